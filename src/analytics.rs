@@ -0,0 +1,267 @@
+//! Derived tables computed from the imported HealthKit data once the insert pass completes
+use crate::identifier::{self, get_valid_sqlite_identifier};
+use crate::options::ImportOptions;
+use crate::schema;
+use sqlx::{Sqlite, Transaction};
+use std::borrow::Cow;
+
+mod daily_metrics;
+mod daily_views;
+mod device_timeline;
+mod diabetes;
+mod heart_rate_zones;
+mod highlights;
+mod interpolate;
+mod measured_views;
+mod outliers;
+mod respiratory;
+mod route_heatmap;
+mod search_index;
+mod source_coverage;
+mod tidy_view;
+mod workout_link;
+mod workout_segments;
+
+/// The tables created by the analytics passes, rebuilt by every import
+pub const DERIVED_TABLES: &[&str] = &[
+    "highlights",
+    "source_coverage",
+    "device_timeline",
+    "insulin_delivery",
+    "blood_glucose",
+    "workout_segments",
+    "daily_metrics_wide",
+    "route_heatmap",
+    "interpolated_daily",
+    "WorkoutHeartRateZone",
+    "search",
+];
+
+/// Prefix of the quantity tables
+const QUANTITY_TABLE_PREFIX: &str = "HKQuantityTypeIdentifier";
+
+/// Runs every post-import analytics pass against the database
+pub async fn run(tx: &mut Transaction<'_, Sqlite>, options: &ImportOptions) -> anyhow::Result<()> {
+    highlights::create_highlights_table(tx, options, options.clock()).await?;
+    source_coverage::create_source_coverage_table(tx).await?;
+    device_timeline::create_device_timeline_table(tx).await?;
+    measured_views::create_measured_views(tx, options.naming).await?;
+    diabetes::create_insulin_delivery_table(tx, options).await?;
+    diabetes::create_blood_glucose_table(tx, options).await?;
+    respiratory::create_respiratory_views(tx, options).await?;
+    workout_segments::create_workout_segments_table(tx, options).await?;
+    if options.flag_outliers {
+        outliers::flag_outliers(tx, options).await?;
+    }
+    if options.link_workouts {
+        workout_link::link_samples_to_workouts(tx, options).await?;
+    }
+    if options.heart_rate_zones {
+        heart_rate_zones::create_heart_rate_zone_table(tx, options, options.max_heart_rate).await?;
+    }
+    if options.daily_metrics {
+        daily_metrics::create_daily_metrics_wide_table(tx, options).await?;
+    }
+    if let Some(precision) = options.route_heatmap {
+        route_heatmap::create_route_heatmap_table(tx, options, precision).await?;
+    }
+    if options.daily_views {
+        daily_views::create_daily_views(tx, options).await?;
+    }
+    if let Some(person) = &options.tidy_view {
+        tidy_view::create_samples_long_view(tx, options, person).await?;
+    }
+    if !options.interpolate.is_empty() {
+        interpolate::create_interpolated_daily_table(
+            tx,
+            options,
+            &options.interpolate,
+            options.clock(),
+        )
+        .await?;
+    }
+    // Last, so the index covers the rows the other passes flag or add
+    if options.search_index {
+        search_index::create_search_table(tx, options.naming, options.no_destructive).await?;
+    }
+    Ok(())
+}
+
+/// Returns the name the table of the export has in the database, or None if there is no such
+/// table. The names are looked up as the import writes them: with or without the sample type
+/// prefix, as --short-names drops it, and made into identifiers by --identifier-map and
+/// --identifiers. The analytics run before the tables are renamed by --naming.
+async fn resolve_table(
+    tx: &mut Transaction<'_, Sqlite>,
+    options: &ImportOptions,
+    table_name: &str,
+) -> anyhow::Result<Option<String>> {
+    for name in output_table_names(options, table_name) {
+        if table_exists(tx, &name).await? {
+            return Ok(Some(name.into_owned()));
+        }
+    }
+    Ok(None)
+}
+
+/// Returns the names the import may write a table of the export with, without and with
+/// --short-names
+fn output_table_names<'a>(options: &ImportOptions, table_name: &'a str) -> [Cow<'a, str>; 2] {
+    [table_name, schema::short_table_name(table_name)]
+        .map(|name| identifier::convert(options, name))
+}
+
+/// Returns true if the database contains a table with the given name
+async fn table_exists(tx: &mut Transaction<'_, Sqlite>, table_name: &str) -> anyhow::Result<bool> {
+    let count: i64 =
+        sqlx::query_scalar("SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = ?")
+            .bind(table_name)
+            .fetch_one(&mut *tx)
+            .await?;
+    Ok(count > 0)
+}
+
+/// Returns the imported tables with a row per sample, i.e. the tables with `sourceName` and
+/// `startDate` columns, in alphabetical order, leaving out the internal and derived tables
+async fn sample_tables(tx: &mut Transaction<'_, Sqlite>) -> anyhow::Result<Vec<String>> {
+    let table_names = sqlx::query_scalar(
+        r#"SELECT m.name FROM sqlite_master m
+        WHERE m.type = 'table'
+            AND m.name NOT LIKE '\_%' ESCAPE '\'
+            AND m.name NOT IN (SELECT value FROM json_each(?))
+            AND EXISTS (SELECT 1 FROM pragma_table_info(m.name) WHERE name = 'sourceName')
+            AND EXISTS (SELECT 1 FROM pragma_table_info(m.name) WHERE name = 'startDate')
+        ORDER BY m.name"#,
+    )
+    .bind(serde_json::to_string(DERIVED_TABLES)?)
+    .fetch_all(&mut *tx)
+    .await?;
+    Ok(table_names)
+}
+
+/// Returns the quantity tables of the database, i.e. the tables with `type` and `value` columns
+/// holding quantity samples, with the type identifier of their samples, in alphabetical order
+async fn quantity_tables(
+    tx: &mut Transaction<'_, Sqlite>,
+    options: &ImportOptions,
+) -> anyhow::Result<Vec<(String, String)>> {
+    let candidates: Vec<String> = sqlx::query_scalar(
+        r#"SELECT m.name FROM sqlite_master m
+        WHERE m.type = 'table'
+            AND EXISTS (SELECT 1 FROM pragma_table_info(m.name) WHERE name = 'value')
+            AND EXISTS (SELECT 1 FROM pragma_table_info(m.name) WHERE name = 'type')
+        ORDER BY m.name"#,
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+    let mut tables = Vec::new();
+    for table_name in candidates {
+        if table_name.starts_with(QUANTITY_TABLE_PREFIX) {
+            let type_name = table_name.clone();
+            tables.push((table_name, type_name));
+        } else if let Some(type_name) = renamed_quantity_type(tx, options, &table_name).await? {
+            tables.push((table_name, type_name));
+        }
+    }
+    Ok(tables)
+}
+
+/// Returns the type identifier of a quantity table written under another name, by --short-names,
+/// --identifier-map, or --identifiers, read from its rows, or None if it isn't such a table
+async fn renamed_quantity_type(
+    tx: &mut Transaction<'_, Sqlite>,
+    options: &ImportOptions,
+    table_name: &str,
+) -> anyhow::Result<Option<String>> {
+    let qs = format!(
+        "SELECT CAST(type AS TEXT) FROM {} LIMIT 1",
+        get_valid_sqlite_identifier(table_name)
+    );
+    let type_name: Option<Option<String>> =
+        sqlx::query_scalar(&qs).fetch_optional(&mut *tx).await?;
+    Ok(type_name.flatten().filter(|type_name| {
+        type_name.starts_with(QUANTITY_TABLE_PREFIX)
+            && output_table_names(options, type_name).contains(&Cow::Borrowed(table_name))
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conformance::EXPORT_FILES;
+    use crate::identifier::IdentifierMap;
+    use crate::testing::{self, TestDir};
+    use sqlx::SqlitePool;
+
+    /// A heart rate sample recorded during the workout of the conformance export
+    const WORKOUT_HEART_RATE: &str = r#"<Record type="HKQuantityTypeIdentifierHeartRate" sourceName="Watch" sourceVersion="10.2" unit="count/min" creationDate="2024-01-01 09:10:05 -0800" startDate="2024-01-01 09:10:00 -0800" endDate="2024-01-01 09:10:00 -0800" value="150"/>
+</HealthData>"#;
+
+    #[tokio::test]
+    async fn analytics_find_mapped_tables() {
+        let dir = TestDir::new("analytics-mapped-tables");
+        let files: Vec<(&str, Vec<u8>)> = EXPORT_FILES
+            .iter()
+            .map(|(path, contents)| match *path {
+                "export.xml" => (
+                    *path,
+                    String::from_utf8(contents.to_vec())
+                        .unwrap()
+                        .replace("</HealthData>", WORKOUT_HEART_RATE)
+                        .into_bytes(),
+                ),
+                _ => (*path, contents.to_vec()),
+            })
+            .collect();
+        let files: Vec<(&str, &[u8])> = files
+            .iter()
+            .map(|(path, contents)| (*path, contents.as_slice()))
+            .collect();
+        let export_dir = dir.write_export("export", &files);
+        let map_path = dir.path().join("identifiers.json");
+        std::fs::write(
+            &map_path,
+            r#"{ "HKQuantityTypeIdentifierHeartRate": "heart rate", "Workout": "workouts" }"#,
+        )
+        .unwrap();
+        let db_url = dir.db_url("health.db");
+        let options = ImportOptions {
+            identifier_map: Some(IdentifierMap::from_path(map_path.to_str().unwrap()).unwrap()),
+            link_workouts: true,
+            heart_rate_zones: true,
+            max_heart_rate: Some(190),
+            daily_views: true,
+            tidy_view: Some("me".to_string()),
+            ..ImportOptions::defaults()
+        };
+        testing::import(&export_dir, &db_url, &options)
+            .await
+            .unwrap();
+        let db = SqlitePool::connect(&db_url).await.unwrap();
+
+        let linked: Vec<(f64, Option<i64>)> =
+            sqlx::query_as("SELECT value, workout_id FROM `heart rate` ORDER BY startDate")
+                .fetch_all(&db)
+                .await
+                .unwrap();
+        assert_eq!(linked, [(62.0, None), (71.5, None), (150.0, Some(1))]);
+        let zone_seconds: f64 = sqlx::query_scalar(
+            "SELECT SUM(duration_seconds) FROM WorkoutHeartRateZone WHERE workout_id = 1",
+        )
+        .fetch_one(&db)
+        .await
+        .unwrap();
+        assert!(zone_seconds > 0.0);
+        let daily: Vec<(String, i64)> = sqlx::query_as("SELECT date, samples FROM daily_HeartRate")
+            .fetch_all(&db)
+            .await
+            .unwrap();
+        assert_eq!(daily, [("2024-01-01".to_string(), 3)]);
+        let metrics: Vec<String> =
+            sqlx::query_scalar("SELECT DISTINCT metric FROM samples_long ORDER BY metric")
+                .fetch_all(&db)
+                .await
+                .unwrap();
+        assert!(metrics.contains(&"HeartRate".to_string()));
+    }
+}
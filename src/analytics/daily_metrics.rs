@@ -0,0 +1,225 @@
+//! A wide table with one row per day and one column per metric, the shape needed to correlate
+//! metrics such as sleep duration against the next day's resting heart rate
+use super::resolve_table;
+use crate::identifier::get_valid_sqlite_identifier;
+use crate::options::ImportOptions;
+use sqlx::{Sqlite, Transaction};
+
+/// Sleep analysis values that count as time asleep, across export versions
+const ASLEEP_VALUES: &str = "'HKCategoryValueSleepAnalysisAsleep', 'HKCategoryValueSleepAnalysisAsleepUnspecified', 'HKCategoryValueSleepAnalysisAsleepCore', 'HKCategoryValueSleepAnalysisAsleepDeep', 'HKCategoryValueSleepAnalysisAsleepREM'";
+
+/// A column of the wide table
+struct DailyMetric {
+    column_name: &'static str,
+    table_name: &'static str,
+    /// Aggregate expression over the table's rows for a single day
+    aggregate: &'static str,
+    /// Also add a next_day_<column_name> column holding the following day's value
+    next_day: bool,
+}
+
+const DAILY_METRICS: &[DailyMetric] = &[
+    DailyMetric {
+        column_name: "resting_heart_rate",
+        table_name: "HKQuantityTypeIdentifierRestingHeartRate",
+        aggregate: "AVG(value)",
+        next_day: true,
+    },
+    DailyMetric {
+        column_name: "hrv_sdnn",
+        table_name: "HKQuantityTypeIdentifierHeartRateVariabilitySDNN",
+        aggregate: "AVG(value)",
+        next_day: true,
+    },
+    DailyMetric {
+        column_name: "steps",
+        table_name: "HKQuantityTypeIdentifierStepCount",
+        aggregate: "SUM(value)",
+        next_day: false,
+    },
+    DailyMetric {
+        column_name: "active_energy",
+        table_name: "HKQuantityTypeIdentifierActiveEnergyBurned",
+        aggregate: "SUM(value)",
+        next_day: false,
+    },
+    DailyMetric {
+        column_name: "exercise_minutes",
+        table_name: "HKQuantityTypeIdentifierAppleExerciseTime",
+        aggregate: "SUM(value)",
+        next_day: false,
+    },
+];
+
+const SLEEP_TABLE_NAME: &str = "HKCategoryTypeIdentifierSleepAnalysis";
+
+/// Creates and populates the daily_metrics_wide table.
+///
+/// Days are local calendar days. Sleep is attributed to the day it ended on, so each row pairs
+/// the night before with that day's activity, and the next_day_ columns with the following day.
+pub async fn create_daily_metrics_wide_table(
+    tx: &mut Transaction<'_, Sqlite>,
+    options: &ImportOptions,
+) -> anyhow::Result<()> {
+    let mut column_definitions = vec![
+        "`date` DATE PRIMARY KEY".to_string(),
+        "`sleep_hours` REAL".to_string(),
+    ];
+    for metric in DAILY_METRICS {
+        column_definitions.push(format!(
+            "{} REAL",
+            get_valid_sqlite_identifier(metric.column_name)
+        ));
+        if metric.next_day {
+            column_definitions.push(format!(
+                "{} REAL",
+                get_valid_sqlite_identifier(&format!("next_day_{}", metric.column_name))
+            ));
+        }
+    }
+    sqlx::query(&format!(
+        "CREATE TABLE IF NOT EXISTS daily_metrics_wide ({})",
+        column_definitions.join(", ")
+    ))
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query("DELETE FROM daily_metrics_wide")
+        .execute(&mut *tx)
+        .await?;
+
+    // One common table expression of (date, value) rows per metric present in the database
+    let mut ctes = Vec::new();
+    let mut columns = Vec::new();
+    let mut joins = Vec::new();
+    if let Some(sleep_table_name) = resolve_table(tx, options, SLEEP_TABLE_NAME).await? {
+        ctes.push(format!(
+            r#"sleep_hours AS (
+                SELECT substr(endDate, 1, 10) AS date, SUM((julianday(endDate) - julianday(startDate)) * 24) AS value
+                FROM {} WHERE value IN ({}) GROUP BY 1
+            )"#,
+            get_valid_sqlite_identifier(&sleep_table_name),
+            ASLEEP_VALUES
+        ));
+        columns.push(("sleep_hours".to_string(), "sleep_hours.value".to_string()));
+        joins.push("LEFT JOIN sleep_hours ON sleep_hours.date = dates.date".to_string());
+    }
+    for metric in DAILY_METRICS {
+        let table_name = match resolve_table(tx, options, metric.table_name).await? {
+            Some(table_name) => table_name,
+            None => continue,
+        };
+        ctes.push(format!(
+            "{} AS (SELECT substr(startDate, 1, 10) AS date, {} AS value FROM {} GROUP BY 1)",
+            metric.column_name,
+            metric.aggregate,
+            get_valid_sqlite_identifier(&table_name)
+        ));
+        columns.push((
+            metric.column_name.to_string(),
+            format!("{}.value", metric.column_name),
+        ));
+        joins.push(format!(
+            "LEFT JOIN {0} ON {0}.date = dates.date",
+            metric.column_name
+        ));
+        if metric.next_day {
+            columns.push((
+                format!("next_day_{}", metric.column_name),
+                format!("next_day_{}.value", metric.column_name),
+            ));
+            joins.push(format!(
+                "LEFT JOIN {0} AS next_day_{0} ON next_day_{0}.date = date(dates.date, '+1 day')",
+                metric.column_name
+            ));
+        }
+    }
+    if ctes.is_empty() {
+        return Ok(());
+    }
+    let dates = columns
+        .iter()
+        .filter(|(name, _)| !name.starts_with("next_day_"))
+        .map(|(name, _)| format!("SELECT date FROM {}", name))
+        .collect::<Vec<_>>()
+        .join(" UNION ");
+    let qs = format!(
+        r#"WITH {}, dates AS ({})
+        INSERT INTO daily_metrics_wide (`date`, {})
+        SELECT dates.date, {} FROM dates {} ORDER BY dates.date"#,
+        ctes.join(", "),
+        dates,
+        columns
+            .iter()
+            .map(|(name, _)| get_valid_sqlite_identifier(name))
+            .collect::<Vec<_>>()
+            .join(", "),
+        columns
+            .iter()
+            .map(|(_, expression)| expression.as_str())
+            .collect::<Vec<_>>()
+            .join(", "),
+        joins.join(" ")
+    );
+    sqlx::query(&qs).execute(&mut *tx).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::SqlitePool;
+
+    #[tokio::test]
+    async fn days_pair_the_night_before_with_the_next_day() {
+        let pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        let mut tx = pool.begin().await.unwrap();
+        sqlx::query(
+            r#"CREATE TABLE HKCategoryTypeIdentifierSleepAnalysis (startDate DATE, endDate DATE, value TEXT);
+            INSERT INTO HKCategoryTypeIdentifierSleepAnalysis VALUES
+                ('2023-01-01 23:00:00', '2023-01-02 03:00:00', 'HKCategoryValueSleepAnalysisAsleepCore'),
+                ('2023-01-02 03:00:00', '2023-01-02 04:30:00', 'HKCategoryValueSleepAnalysisAwake'),
+                ('2023-01-02 04:30:00', '2023-01-02 07:00:00', 'HKCategoryValueSleepAnalysisAsleepREM');
+            CREATE TABLE HKQuantityTypeIdentifierRestingHeartRate (startDate DATE, value REAL);
+            INSERT INTO HKQuantityTypeIdentifierRestingHeartRate VALUES
+                ('2023-01-02 08:00:00', 60), ('2023-01-02 20:00:00', 64), ('2023-01-03 08:00:00', 58);
+            CREATE TABLE HKQuantityTypeIdentifierStepCount (startDate DATE, value REAL);
+            INSERT INTO HKQuantityTypeIdentifierStepCount VALUES
+                ('2023-01-02 09:00:00', 1000), ('2023-01-02 17:00:00', 2500);"#,
+        )
+        .execute(&mut *tx)
+        .await
+        .unwrap();
+        create_daily_metrics_wide_table(&mut tx, &ImportOptions::defaults())
+            .await
+            .unwrap();
+        type Day = (
+            String,
+            Option<f64>,
+            Option<f64>,
+            Option<f64>,
+            Option<f64>,
+            Option<f64>,
+        );
+        let days: Vec<Day> = sqlx::query_as(
+            r#"SELECT date, ROUND(sleep_hours, 6), resting_heart_rate, next_day_resting_heart_rate, steps, hrv_sdnn
+            FROM daily_metrics_wide ORDER BY date"#,
+        )
+        .fetch_all(&mut *tx)
+        .await
+        .unwrap();
+        assert_eq!(
+            days,
+            [
+                (
+                    "2023-01-02".to_string(),
+                    Some(6.5),
+                    Some(62.0),
+                    Some(58.0),
+                    Some(3500.0),
+                    None
+                ),
+                ("2023-01-03".to_string(), None, Some(58.0), None, None, None),
+            ]
+        );
+    }
+}
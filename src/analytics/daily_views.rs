@@ -0,0 +1,146 @@
+//! Per-day aggregates of each quantity table, so the usual GROUP BY over steps, heart rate, or
+//! weight doesn't have to be rewritten for every query
+use super::{quantity_tables, QUANTITY_TABLE_PREFIX};
+use crate::identifier::get_valid_sqlite_identifier;
+use crate::options::ImportOptions;
+use log::*;
+use sqlx::{Sqlite, Transaction};
+
+/// Prefix of the view names, e.g. daily_StepCount
+const VIEW_PREFIX: &str = "daily_";
+
+/// Creates a `daily_<type>` view of (date, unit, samples, min, max, avg, sum) rows for every
+/// quantity table, named after the type of its samples whatever the name of the table.
+///
+/// Days are the local calendar days of startDate. Rows are also grouped by unit so that samples
+/// written in different units, e.g. lb and kg, are never aggregated together.
+pub async fn create_daily_views(
+    tx: &mut Transaction<'_, Sqlite>,
+    options: &ImportOptions,
+) -> anyhow::Result<()> {
+    for (table_name, type_name) in quantity_tables(tx, options).await? {
+        let view_name = format!(
+            "{}{}",
+            VIEW_PREFIX,
+            type_name.trim_start_matches(QUANTITY_TABLE_PREFIX)
+        );
+        debug!("Creating view {}", view_name);
+        // Recreated in case the table gained a unit since the last import
+        sqlx::query(&format!(
+            "DROP VIEW IF EXISTS {}",
+            get_valid_sqlite_identifier(&view_name)
+        ))
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query(&format!(
+            r#"CREATE VIEW {} AS
+            SELECT substr(startDate, 1, 10) AS date, unit, COUNT(*) AS samples,
+                MIN(value) AS min, MAX(value) AS max, AVG(value) AS avg, SUM(value) AS sum
+            FROM {} GROUP BY 1, 2"#,
+            get_valid_sqlite_identifier(&view_name),
+            get_valid_sqlite_identifier(&table_name)
+        ))
+        .execute(&mut *tx)
+        .await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::conformance::EXPORT_FILES;
+    use crate::options::ImportOptions;
+    use crate::testing::{self, TestDir};
+    use sqlx::SqlitePool;
+
+    /// Weights in two units on the same day, and steps late in the evening, which is the next day in
+    /// UTC
+    const RECORDS: &str = r#" <Record type="HKQuantityTypeIdentifierBodyMass" sourceName="Scale" unit="lb" creationDate="2024-01-01 19:00:00 -0800" startDate="2024-01-01 19:00:00 -0800" endDate="2024-01-01 19:00:00 -0800" value="160"/>
+ <Record type="HKQuantityTypeIdentifierBodyMass" sourceName="Scale" unit="kg" creationDate="2024-01-01 20:00:00 -0800" startDate="2024-01-01 20:00:00 -0800" endDate="2024-01-01 20:00:00 -0800" value="72.5"/>
+ <Record type="HKQuantityTypeIdentifierStepCount" sourceName="Phone" unit="count" creationDate="2024-01-02 23:59:00 -0800" startDate="2024-01-02 00:00:00 -0800" endDate="2024-01-02 23:59:00 -0800" value="6000"/>
+ <Record type="HKQuantityTypeIdentifierStepCount" sourceName="Watch" unit="count" creationDate="2024-01-02 23:59:00 -0800" startDate="2024-01-01 22:00:00 -0800" endDate="2024-01-01 23:00:00 -0800" value="2000"/>
+</HealthData>"#;
+
+    #[tokio::test]
+    async fn quantities_are_aggregated_per_local_day_and_unit() {
+        let dir = TestDir::new("daily-views");
+        let export_xml = std::str::from_utf8(EXPORT_FILES[0].1)
+            .unwrap()
+            .replace("</HealthData>", RECORDS);
+        let mut files = EXPORT_FILES.to_vec();
+        files[0] = ("export.xml", export_xml.as_bytes());
+        let export_dir = dir.write_export("export", &files);
+        let db_url = dir.db_url("health.db");
+        let options = ImportOptions {
+            daily_views: true,
+            ..ImportOptions::defaults()
+        };
+        testing::import(&export_dir, &db_url, &options)
+            .await
+            .unwrap();
+        let db = SqlitePool::connect(&db_url).await.unwrap();
+
+        let weights: Vec<(String, String, i64, f64, f64)> = sqlx::query_as(
+            "SELECT date, unit, samples, min, max FROM daily_BodyMass ORDER BY 1, 2",
+        )
+        .fetch_all(&db)
+        .await
+        .unwrap();
+        assert_eq!(
+            weights,
+            [
+                (
+                    "2024-01-01".to_string(),
+                    "kg".to_string(),
+                    2,
+                    72.123456789,
+                    72.5
+                ),
+                ("2024-01-01".to_string(), "lb".to_string(), 1, 160.0, 160.0),
+            ]
+        );
+        let steps: Vec<(String, i64, i64)> =
+            sqlx::query_as("SELECT date, samples, sum FROM daily_StepCount ORDER BY date")
+                .fetch_all(&db)
+                .await
+                .unwrap();
+        assert_eq!(
+            steps,
+            [
+                ("2024-01-01".to_string(), 2, 4000002000),
+                ("2024-01-02".to_string(), 1, 6000),
+            ]
+        );
+        let average: f64 = sqlx::query_scalar("SELECT avg FROM daily_HeartRate")
+            .fetch_one(&db)
+            .await
+            .unwrap();
+        assert_eq!(average, 66.75);
+        // Only quantity tables get a view
+        let views: Vec<String> = sqlx::query_scalar(
+            "SELECT name FROM sqlite_master WHERE type = 'view' AND name LIKE 'daily\\_%' ESCAPE '\\' ORDER BY name",
+        )
+        .fetch_all(&db)
+        .await
+        .unwrap();
+        assert_eq!(
+            views,
+            [
+                "daily_BodyMass",
+                "daily_HeartRate",
+                "daily_HeartRateVariabilitySDNN",
+                "daily_StepCount"
+            ]
+        );
+        db.close().await;
+
+        // Appending recreates the views rather than failing because they exist
+        let appended = ImportOptions {
+            append: true,
+            ..options
+        };
+        testing::import(&export_dir, &db_url, &appended)
+            .await
+            .unwrap();
+    }
+}
@@ -0,0 +1,153 @@
+//! Which OS and app versions each device ran over which dates, for explaining shifts in metrics
+//! caused by algorithm changes in an update
+use super::sample_tables;
+use crate::device::{device_fields, DEVICE_ID_COLUMN, DEVICE_TABLE_NAME};
+use crate::identifier::get_valid_sqlite_identifier;
+use sqlx::{Sqlite, Transaction};
+use std::collections::BTreeMap;
+
+/// The source, device name, hardware model, and OS or app version a timeline row covers
+type TimelineKey = (String, Option<String>, Option<String>, Option<String>);
+
+/// The first and last dates, as julian days, and the row count of a timeline row
+type TimelineSpan = (f64, f64, i64);
+
+/// The source, device description, and source version of a group of rows of a table, with its
+/// first and last dates and row count
+type TimelineRow = (String, Option<String>, Option<String>, f64, f64, i64);
+
+/// Creates and populates the device_timeline table
+pub async fn create_device_timeline_table(tx: &mut Transaction<'_, Sqlite>) -> anyhow::Result<()> {
+    sqlx::query(
+        r#"CREATE TABLE IF NOT EXISTS device_timeline (
+            `sourceName` TEXT,
+            `device` TEXT,
+            `hardware` TEXT,
+            `version` TEXT,
+            `firstDate` DATE,
+            `lastDate` DATE,
+            `rowCount` INTEGER
+        )"#,
+    )
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query("DELETE FROM device_timeline")
+        .execute(&mut *tx)
+        .await?;
+
+    let device_description = format!(
+        "(SELECT description FROM {} WHERE id = {})",
+        get_valid_sqlite_identifier(DEVICE_TABLE_NAME),
+        DEVICE_ID_COLUMN
+    );
+    let mut timeline: BTreeMap<TimelineKey, TimelineSpan> = BTreeMap::new();
+    for table_name in sample_tables(tx).await? {
+        // Whether the table has each version column, or the device_id of --device-table in place
+        // of the device
+        let (has_device, has_device_id, has_source_version): (bool, bool, bool) = sqlx::query_as(
+            r#"SELECT
+                EXISTS (SELECT 1 FROM pragma_table_info(?1) WHERE name = 'device'),
+                EXISTS (SELECT 1 FROM pragma_table_info(?1) WHERE name = ?2),
+                EXISTS (SELECT 1 FROM pragma_table_info(?1) WHERE name = 'sourceVersion')"#,
+        )
+        .bind(&table_name)
+        .bind(DEVICE_ID_COLUMN)
+        .fetch_one(&mut *tx)
+        .await?;
+        if !has_device && !has_device_id && !has_source_version {
+            continue;
+        }
+        // Cast, as the schema inference types versions such as 10.2 as REAL
+        let rows: Vec<TimelineRow> = sqlx::query_as(&format!(
+            r#"SELECT CAST(sourceName AS TEXT), CAST({} AS TEXT), CAST({} AS TEXT),
+                MIN(julianday(startDate)), MAX(julianday(startDate)), COUNT(*)
+            FROM {} WHERE sourceName IS NOT NULL AND julianday(startDate) IS NOT NULL
+            GROUP BY 1, 2, 3"#,
+            if has_device {
+                "device"
+            } else if has_device_id {
+                device_description.as_str()
+            } else {
+                "NULL"
+            },
+            if has_source_version {
+                "sourceVersion"
+            } else {
+                "NULL"
+            },
+            get_valid_sqlite_identifier(&table_name)
+        ))
+        .fetch_all(&mut *tx)
+        .await?;
+        for (source_name, device, source_version, first, last, count) in rows {
+            let fields = device.as_deref().map(device_fields).unwrap_or_default();
+            let field = |key: &str| {
+                fields
+                    .iter()
+                    .find(|(name, _)| *name == key)
+                    .map(|(_, value)| value.to_string())
+            };
+            // The OS version of the device, or else the version of the app that wrote the sample
+            let version = field("software").or(source_version);
+            let key = (source_name, field("name"), field("hardware"), version);
+            let span = timeline.entry(key).or_insert((first, last, 0));
+            span.0 = span.0.min(first);
+            span.1 = span.1.max(last);
+            span.2 += count;
+        }
+    }
+    for ((source_name, device, hardware, version), (first, last, count)) in timeline {
+        sqlx::query(
+            r#"INSERT INTO device_timeline (`sourceName`, `device`, `hardware`, `version`, `firstDate`, `lastDate`, `rowCount`)
+            VALUES (?, ?, ?, ?, datetime(?), datetime(?), ?)"#,
+        )
+        .bind(source_name)
+        .bind(device)
+        .bind(hardware)
+        .bind(version)
+        .bind(first)
+        .bind(last)
+        .bind(count)
+        .execute(&mut *tx)
+        .await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::SqlitePool;
+
+    #[tokio::test]
+    async fn numeric_source_version() {
+        let pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        let mut tx = pool.begin().await.unwrap();
+        sqlx::query(
+            r#"CREATE TABLE HKQuantityTypeIdentifierHeartRate (
+                sourceName TEXT, sourceVersion REAL, startDate DATE, value REAL
+            )"#,
+        )
+        .execute(&mut *tx)
+        .await
+        .unwrap();
+        sqlx::query(
+            r#"INSERT INTO HKQuantityTypeIdentifierHeartRate VALUES
+                ('Health', 10.2, '2023-01-01 08:00:00', 60),
+                ('Health', 10.2, '2023-01-02 08:00:00', 62);
+            -- A derived table, left out of the timeline
+            CREATE TABLE blood_glucose (sourceName TEXT, sourceVersion REAL, startDate DATE);
+            INSERT INTO blood_glucose VALUES ('Health', 10.2, '2023-01-01 08:00:00')"#,
+        )
+        .execute(&mut *tx)
+        .await
+        .unwrap();
+        create_device_timeline_table(&mut tx).await.unwrap();
+        let rows: Vec<(String, Option<String>, i64)> =
+            sqlx::query_as("SELECT sourceName, version, rowCount FROM device_timeline")
+                .fetch_all(&mut *tx)
+                .await
+                .unwrap();
+        assert_eq!(rows, [("Health".to_string(), Some("10.2".to_string()), 2)]);
+    }
+}
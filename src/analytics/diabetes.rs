@@ -0,0 +1,217 @@
+//! Typed insulin delivery and blood glucose tables for diabetes management
+use super::resolve_table;
+use crate::core::METADATA_COLUMN_PREFIX;
+use crate::identifier::get_valid_sqlite_identifier;
+use crate::metadata::{self, BLOOD_GLUCOSE_MEAL_TIMES, INSULIN_DELIVERY_REASONS};
+use crate::options::{ImportOptions, Naming};
+use crate::record_metadata;
+use sqlx::{Sqlite, Transaction};
+
+const INSULIN_DELIVERY_TABLE: &str = "HKQuantityTypeIdentifierInsulinDelivery";
+const BLOOD_GLUCOSE_TABLE: &str = "HKQuantityTypeIdentifierBloodGlucose";
+
+/// Milligrams per deciliter of blood glucose in one millimole per liter, from the molar mass of
+/// glucose of 180.156 g/mol
+const MG_DL_PER_MMOL_L: f64 = 18.0156;
+
+/// Creates the insulin_delivery table of insulin doses with their delivery reason
+pub async fn create_insulin_delivery_table(
+    tx: &mut Transaction<'_, Sqlite>,
+    options: &ImportOptions,
+) -> anyhow::Result<()> {
+    let table_name = match resolve_table(tx, options, INSULIN_DELIVERY_TABLE).await? {
+        Some(table_name) => table_name,
+        None => return Ok(()),
+    };
+    let reason = metadata_expression(
+        tx,
+        options.naming,
+        &table_name,
+        "HKInsulinDeliveryReason",
+        INSULIN_DELIVERY_REASONS,
+    )
+    .await?;
+    sqlx::query(
+        r#"CREATE TABLE IF NOT EXISTS insulin_delivery (
+            `record_id` INTEGER,
+            `startDate` DATE,
+            `endDate` DATE,
+            `units` REAL,
+            `reason` TEXT,
+            `sourceName` TEXT
+        )"#,
+    )
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query("DELETE FROM insulin_delivery")
+        .execute(&mut *tx)
+        .await?;
+    // Insulin is always recorded in international units (IU)
+    sqlx::query(&format!(
+        r#"INSERT INTO insulin_delivery
+        SELECT rowid, startDate, endDate, value, {}, sourceName FROM {}"#,
+        reason,
+        get_valid_sqlite_identifier(&table_name)
+    ))
+    .execute(&mut *tx)
+    .await?;
+    Ok(())
+}
+
+/// Creates the blood_glucose table of readings in both mg/dL and mmol/L with their meal time
+pub async fn create_blood_glucose_table(
+    tx: &mut Transaction<'_, Sqlite>,
+    options: &ImportOptions,
+) -> anyhow::Result<()> {
+    let table_name = match resolve_table(tx, options, BLOOD_GLUCOSE_TABLE).await? {
+        Some(table_name) => table_name,
+        None => return Ok(()),
+    };
+    let meal_time = metadata_expression(
+        tx,
+        options.naming,
+        &table_name,
+        "HKBloodGlucoseMealTime",
+        BLOOD_GLUCOSE_MEAL_TIMES,
+    )
+    .await?;
+    sqlx::query(
+        r#"CREATE TABLE IF NOT EXISTS blood_glucose (
+            `record_id` INTEGER,
+            `startDate` DATE,
+            `mg_dl` REAL,
+            `mmol_l` REAL,
+            `meal_time` TEXT,
+            `sourceName` TEXT
+        )"#,
+    )
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query("DELETE FROM blood_glucose")
+        .execute(&mut *tx)
+        .await?;
+    // mmol/L readings have a unit of mmol<180.1558800000541>/L
+    sqlx::query(&format!(
+        r#"INSERT INTO blood_glucose
+        SELECT rowid, startDate,
+            CASE WHEN unit LIKE 'mmol%' THEN value * ?1 ELSE value END,
+            CASE WHEN unit LIKE 'mmol%' THEN value ELSE value / ?1 END,
+            {}, sourceName
+        FROM {}"#,
+        meal_time,
+        get_valid_sqlite_identifier(&table_name)
+    ))
+    .bind(MG_DL_PER_MMOL_L)
+    .execute(&mut *tx)
+    .await?;
+    Ok(())
+}
+
+/// Returns an SQL expression for the name of a metadata enum value, reading either the raw
+/// metadata column, the column decoded by --friendly-names, or the RecordMetadata table of
+/// --metadata-table, whichever the table has
+async fn metadata_expression(
+    tx: &mut Transaction<'_, Sqlite>,
+    naming: Naming,
+    table_name: &str,
+    key: &str,
+    names: &[(i32, &str)],
+) -> anyhow::Result<String> {
+    let columns: Vec<String> = sqlx::query_scalar("SELECT name FROM pragma_table_info(?)")
+        .bind(table_name)
+        .fetch_all(&mut *tx)
+        .await?;
+    let cases = |value: String| {
+        let cases = names
+            .iter()
+            .map(|(raw, name)| format!("WHEN {} THEN '{}'", raw, name))
+            .collect::<Vec<_>>()
+            .join(" ");
+        format!("CASE {} {} END", value, cases)
+    };
+    let raw_column = format!("{}{}", METADATA_COLUMN_PREFIX, key);
+    if columns.contains(&raw_column) {
+        return Ok(cases(get_valid_sqlite_identifier(&raw_column)));
+    }
+    match metadata::friendly_column(key) {
+        Some(column) if columns.iter().any(|c| c == column) => {
+            return Ok(get_valid_sqlite_identifier(column));
+        }
+        _ => {}
+    }
+    match record_metadata::value_expression(tx, naming, table_name, key, true).await? {
+        Some(value) => Ok(cases(value)),
+        None => Ok("NULL".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::SqlitePool;
+
+    #[tokio::test]
+    async fn doses_are_stored_with_their_delivery_reason() {
+        let pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        let mut tx = pool.begin().await.unwrap();
+        sqlx::query(
+            r#"CREATE TABLE HKQuantityTypeIdentifierInsulinDelivery (startDate DATE, endDate DATE, value REAL, unit TEXT, sourceName TEXT, metadata_HKInsulinDeliveryReason INTEGER);
+            INSERT INTO HKQuantityTypeIdentifierInsulinDelivery VALUES
+                ('2024-01-01 08:00:00', '2024-01-01 08:00:00', 4.5, 'IU', 'Pump', 2),
+                ('2024-01-01 00:00:00', '2024-01-01 01:00:00', 0.8, 'IU', 'Pump', 1),
+                ('2024-01-01 09:00:00', '2024-01-01 09:00:00', 1.0, 'IU', 'Pen', NULL);"#,
+        )
+        .execute(&mut *tx)
+        .await
+        .unwrap();
+        create_insulin_delivery_table(&mut tx, &ImportOptions::defaults())
+            .await
+            .unwrap();
+        let doses: Vec<(i64, f64, Option<String>, String)> = sqlx::query_as(
+            "SELECT record_id, units, reason, sourceName FROM insulin_delivery ORDER BY record_id",
+        )
+        .fetch_all(&mut *tx)
+        .await
+        .unwrap();
+        assert_eq!(
+            doses,
+            [
+                (1, 4.5, Some("bolus".to_string()), "Pump".to_string()),
+                (2, 0.8, Some("basal".to_string()), "Pump".to_string()),
+                (3, 1.0, None, "Pen".to_string()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn readings_are_stored_in_both_units_with_their_meal_time() {
+        let pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        let mut tx = pool.begin().await.unwrap();
+        // The meal time decoded by --friendly-names is used as is
+        sqlx::query(
+            r#"CREATE TABLE HKQuantityTypeIdentifierBloodGlucose (startDate DATE, value REAL, unit TEXT, sourceName TEXT, blood_glucose_meal_time TEXT);
+            INSERT INTO HKQuantityTypeIdentifierBloodGlucose VALUES
+                ('2024-01-01 08:00:00', 90.078, 'mg/dL', 'Meter', 'preprandial'),
+                ('2024-01-01 13:00:00', 7.5, 'mmol<180.1558800000541>/L', 'Meter', 'postprandial');"#,
+        )
+        .execute(&mut *tx)
+        .await
+        .unwrap();
+        create_blood_glucose_table(&mut tx, &ImportOptions::defaults())
+            .await
+            .unwrap();
+        let readings: Vec<(f64, f64, String)> = sqlx::query_as(
+            "SELECT ROUND(mg_dl, 6), ROUND(mmol_l, 6), meal_time FROM blood_glucose ORDER BY record_id",
+        )
+        .fetch_all(&mut *tx)
+        .await
+        .unwrap();
+        assert_eq!(
+            readings,
+            [
+                (90.078, 5.0, "preprandial".to_string()),
+                (135.117, 7.5, "postprandial".to_string()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,268 @@
+//! The time spent in each heart rate zone during each workout, from the heart rate samples
+//! within the workout's time range
+use super::resolve_table;
+use crate::identifier::get_valid_sqlite_identifier;
+use crate::options::ImportOptions;
+use log::*;
+use sqlx::{Sqlite, Transaction};
+
+const WORKOUT_TABLE_NAME: &str = "Workout";
+const HEART_RATE_TABLE_NAME: &str = "HKQuantityTypeIdentifierHeartRate";
+const ME_TABLE_NAME: &str = "Me";
+const DATE_OF_BIRTH_COLUMN: &str = "HKCharacteristicTypeIdentifierDateOfBirth";
+
+/// The zones as (zone, lower bound, upper bound) in fractions of the maximum heart rate. Samples
+/// below zone 1 aren't counted, and samples above the maximum are counted in zone 5.
+const ZONES: &[(i64, f64, f64)] = &[
+    (1, 0.5, 0.6),
+    (2, 0.6, 0.7),
+    (3, 0.7, 0.8),
+    (4, 0.8, 0.9),
+    (5, 0.9, 1.0),
+];
+
+/// Longest time a sample is counted for, so a gap in the samples isn't counted in the zone of
+/// the sample before it
+const MAX_SAMPLE_SECONDS: f64 = 60.0;
+
+/// Creates and populates the WorkoutHeartRateZone table with a row per zone of each workout with
+/// heart rate samples, holding the seconds spent in the zone. Each sample counts until the next
+/// sample or the end of the workout.
+///
+/// The maximum heart rate is the given one, or else 220 minus the age on the day of the workout,
+/// from the date of birth of the Me profile.
+pub async fn create_heart_rate_zone_table(
+    tx: &mut Transaction<'_, Sqlite>,
+    options: &ImportOptions,
+    max_heart_rate: Option<u32>,
+) -> anyhow::Result<()> {
+    let (heart_rate_table, workout_table) = match (
+        resolve_table(tx, options, HEART_RATE_TABLE_NAME).await?,
+        resolve_table(tx, options, WORKOUT_TABLE_NAME).await?,
+    ) {
+        (Some(heart_rate_table), Some(workout_table)) => (heart_rate_table, workout_table),
+        _ => return Ok(()),
+    };
+    let me_table = match resolve_table(tx, options, ME_TABLE_NAME).await? {
+        Some(me_table) if has_date_of_birth(tx, &me_table).await? => Some(me_table),
+        _ => None,
+    };
+    let max_heart_rate = match (max_heart_rate, me_table) {
+        (Some(max_heart_rate), _) => max_heart_rate.to_string(),
+        (None, Some(me_table)) => format!(
+            r#"(SELECT 220 - (CAST(strftime('%Y', w.startDate) AS INTEGER) - CAST(strftime('%Y', {0}) AS INTEGER)
+                - (strftime('%m-%d', w.startDate) < strftime('%m-%d', {0})))
+            FROM {1} WHERE {0} != '' LIMIT 1)"#,
+            get_valid_sqlite_identifier(DATE_OF_BIRTH_COLUMN),
+            get_valid_sqlite_identifier(&me_table)
+        ),
+        (None, None) => {
+            warn!(
+                "The heart rate zones need a maximum heart rate, but the export has no date of birth. Give one with --max-heart-rate."
+            );
+            return Ok(());
+        }
+    };
+    sqlx::query(
+        r#"CREATE TABLE IF NOT EXISTS WorkoutHeartRateZone (
+            `workout_id` INTEGER,
+            `zone` INTEGER,
+            `min_bpm` REAL,
+            `max_bpm` REAL,
+            `max_heart_rate` REAL,
+            `duration_seconds` REAL
+        )"#,
+    )
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query("DELETE FROM WorkoutHeartRateZone")
+        .execute(&mut *tx)
+        .await?;
+    // Indexing the sample times keeps each workout's lookup a range scan
+    sqlx::query("CREATE TEMP TABLE zone_samples (jd REAL, bpm REAL)")
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query(&format!(
+        "INSERT INTO zone_samples SELECT julianday(startDate), value FROM {} WHERE value > 0",
+        get_valid_sqlite_identifier(&heart_rate_table)
+    ))
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query("CREATE INDEX temp.zone_samples_jd ON zone_samples (jd)")
+        .execute(&mut *tx)
+        .await?;
+    let zones = ZONES
+        .iter()
+        .map(|(zone, lower, upper)| {
+            format!(
+                "SELECT {} AS zone, {} AS lower, {} AS upper",
+                zone, lower, upper
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(" UNION ALL ");
+    sqlx::query(&format!(
+        r#"INSERT INTO WorkoutHeartRateZone (workout_id, zone, min_bpm, max_bpm, max_heart_rate, duration_seconds)
+        WITH workout_spans AS (
+            SELECT w.rowid AS workout_id, julianday(w.startDate) AS start_jd, julianday(w.endDate) AS end_jd,
+                {0} AS max_heart_rate
+            FROM {1} w
+        ),
+        samples AS (
+            SELECT w.workout_id, w.max_heart_rate, s.bpm,
+                (MIN(
+                    COALESCE(LEAD(s.jd) OVER (PARTITION BY w.workout_id ORDER BY s.jd), w.end_jd),
+                    w.end_jd,
+                    s.jd + {2} / 86400.0
+                ) - s.jd) * 86400 AS seconds
+            FROM workout_spans w JOIN zone_samples s ON s.jd >= w.start_jd AND s.jd < w.end_jd
+            WHERE w.max_heart_rate > 0
+        ),
+        zones AS ({3})
+        SELECT s.workout_id, z.zone, z.lower * s.max_heart_rate, z.upper * s.max_heart_rate, s.max_heart_rate,
+            SUM(CASE WHEN s.bpm >= z.lower * s.max_heart_rate AND (z.zone = 5 OR s.bpm < z.upper * s.max_heart_rate)
+                THEN s.seconds ELSE 0 END)
+        FROM samples s CROSS JOIN zones z
+        GROUP BY s.workout_id, z.zone
+        ORDER BY s.workout_id, z.zone"#,
+        max_heart_rate,
+        get_valid_sqlite_identifier(&workout_table),
+        MAX_SAMPLE_SECONDS,
+        zones
+    ))
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query("DROP TABLE temp.zone_samples")
+        .execute(&mut *tx)
+        .await?;
+    Ok(())
+}
+
+/// Returns true if the Me profile table has a date of birth column
+async fn has_date_of_birth(
+    tx: &mut Transaction<'_, Sqlite>,
+    me_table: &str,
+) -> anyhow::Result<bool> {
+    let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM pragma_table_info(?) WHERE name = ?")
+        .bind(me_table)
+        .bind(DATE_OF_BIRTH_COLUMN)
+        .fetch_one(&mut *tx)
+        .await?;
+    Ok(count > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::SqlitePool;
+
+    /// Creates a workout from 08:00 to 08:03 of the day with a heart rate sample at each of the
+    /// given seconds into it, returning the seconds counted in each zone, from 1 to 5
+    async fn zone_seconds(
+        day: &str,
+        date_of_birth: Option<&str>,
+        max_heart_rate: Option<u32>,
+        samples: &[(u32, f64)],
+    ) -> (f64, Vec<f64>) {
+        let pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        let mut tx = pool.begin().await.unwrap();
+        sqlx::query("CREATE TABLE Workout (startDate DATE, endDate DATE)")
+            .execute(&mut *tx)
+            .await
+            .unwrap();
+        sqlx::query("INSERT INTO Workout VALUES (? || ' 08:00:00', ? || ' 08:03:00')")
+            .bind(day)
+            .bind(day)
+            .execute(&mut *tx)
+            .await
+            .unwrap();
+        sqlx::query("CREATE TABLE HKQuantityTypeIdentifierHeartRate (startDate DATE, value REAL)")
+            .execute(&mut *tx)
+            .await
+            .unwrap();
+        for (seconds, bpm) in samples {
+            sqlx::query(
+                "INSERT INTO HKQuantityTypeIdentifierHeartRate VALUES (datetime(? || ' 08:00:00', ? || ' seconds'), ?)",
+            )
+            .bind(day)
+            .bind(seconds)
+            .bind(bpm)
+            .execute(&mut *tx)
+            .await
+            .unwrap();
+        }
+        if let Some(date_of_birth) = date_of_birth {
+            sqlx::query(&format!("CREATE TABLE Me ({} DATE)", DATE_OF_BIRTH_COLUMN))
+                .execute(&mut *tx)
+                .await
+                .unwrap();
+            sqlx::query("INSERT INTO Me VALUES (?)")
+                .bind(date_of_birth)
+                .execute(&mut *tx)
+                .await
+                .unwrap();
+        }
+        create_heart_rate_zone_table(&mut tx, &ImportOptions::defaults(), max_heart_rate)
+            .await
+            .unwrap();
+        let zones: Vec<(i64, f64, f64)> = sqlx::query_as(
+            "SELECT zone, max_heart_rate, ROUND(duration_seconds, 3) FROM WorkoutHeartRateZone ORDER BY zone",
+        )
+        .fetch_all(&mut *tx)
+        .await
+        .unwrap();
+        assert_eq!(
+            zones.iter().map(|(zone, _, _)| *zone).collect::<Vec<_>>(),
+            [1, 2, 3, 4, 5]
+        );
+        (
+            zones[0].1,
+            zones.iter().map(|(_, _, seconds)| *seconds).collect(),
+        )
+    }
+
+    #[tokio::test]
+    async fn samples_on_a_zone_edge_count_in_the_zone_above() {
+        let samples = [
+            (0, 99.9),
+            (10, 100.0),
+            (20, 119.9),
+            (30, 120.0),
+            (40, 140.0),
+            (50, 160.0),
+            (60, 179.9),
+            (70, 180.0),
+            // Above the maximum, and counted for at most a minute of the 100 seconds to the end
+            (80, 210.0),
+            // At the end of the workout, so not within it
+            (180, 150.0),
+        ];
+        assert_eq!(
+            zone_seconds("2023-06-14", None, Some(200), &samples).await,
+            (200.0, vec![20.0, 10.0, 10.0, 20.0, 70.0])
+        );
+    }
+
+    #[tokio::test]
+    async fn edges_of_a_maximum_from_the_date_of_birth() {
+        // 220 - 33 = 187 on the birthday, whose zones start at 93.5, 112.2, 130.9, 149.6, and
+        // 168.3 bpm
+        let samples = [
+            (0, 93.5),
+            (10, 112.2),
+            (20, 130.9),
+            (30, 149.6),
+            (40, 168.3),
+            (50, 168.2),
+            (60, 93.4),
+        ];
+        assert_eq!(
+            zone_seconds("2023-06-15", Some("1990-06-15"), None, &samples).await,
+            (187.0, vec![10.0, 10.0, 10.0, 20.0, 10.0])
+        );
+        // A day before the birthday, the maximum is a beat higher
+        let (max_heart_rate, _) =
+            zone_seconds("2023-06-14", Some("1990-06-15"), None, &samples).await;
+        assert_eq!(max_heart_rate, 188.0);
+    }
+}
@@ -0,0 +1,331 @@
+//! Apple Health style "trends": the last 90 days of a metric compared against the 365 days before
+use super::resolve_table;
+use crate::clock::Clock;
+use crate::identifier::get_valid_sqlite_identifier;
+use crate::options::ImportOptions;
+use log::*;
+use sqlx::{Sqlite, Transaction};
+use time::{Duration, OffsetDateTime};
+
+/// How a metric is summarized over a window
+#[derive(Clone, Copy)]
+enum Aggregation {
+    /// The average of the per-day totals, e.g. steps per day, by the local day of startDate
+    DailySum,
+    /// The average of all samples, e.g. resting heart rate
+    Average,
+}
+
+impl Aggregation {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Aggregation::DailySum => "daily_sum",
+            Aggregation::Average => "average",
+        }
+    }
+}
+
+/// The metrics summarized in the highlights table
+const HIGHLIGHT_METRICS: &[(&str, Aggregation)] = &[
+    ("HKQuantityTypeIdentifierStepCount", Aggregation::DailySum),
+    (
+        "HKQuantityTypeIdentifierDistanceWalkingRunning",
+        Aggregation::DailySum,
+    ),
+    (
+        "HKQuantityTypeIdentifierActiveEnergyBurned",
+        Aggregation::DailySum,
+    ),
+    (
+        "HKQuantityTypeIdentifierAppleExerciseTime",
+        Aggregation::DailySum,
+    ),
+    (
+        "HKQuantityTypeIdentifierFlightsClimbed",
+        Aggregation::DailySum,
+    ),
+    (
+        "HKQuantityTypeIdentifierRestingHeartRate",
+        Aggregation::Average,
+    ),
+    (
+        "HKQuantityTypeIdentifierWalkingHeartRateAverage",
+        Aggregation::Average,
+    ),
+    (
+        "HKQuantityTypeIdentifierHeartRateVariabilitySDNN",
+        Aggregation::Average,
+    ),
+    ("HKQuantityTypeIdentifierVO2Max", Aggregation::Average),
+    (
+        "HKQuantityTypeIdentifierRespiratoryRate",
+        Aggregation::Average,
+    ),
+    ("HKQuantityTypeIdentifierWalkingSpeed", Aggregation::Average),
+    ("HKQuantityTypeIdentifierBodyMass", Aggregation::Average),
+];
+
+/// Length of the recent window
+const RECENT_DAYS: i64 = 90;
+/// Length of the baseline window immediately preceding the recent window
+const BASELINE_DAYS: i64 = 365;
+/// Changes smaller than this percentage are reported as "flat"
+const FLAT_THRESHOLD_PERCENT: f64 = 1.0;
+
+/// Creates and populates the highlights table
+pub async fn create_highlights_table(
+    tx: &mut Transaction<'_, Sqlite>,
+    options: &ImportOptions,
+    clock: Clock,
+) -> anyhow::Result<()> {
+    sqlx::query(
+        r#"CREATE TABLE IF NOT EXISTS highlights (
+            `type` TEXT PRIMARY KEY,
+            `aggregation` TEXT,
+            `unit` TEXT,
+            `recentValue` REAL,
+            `baselineValue` REAL,
+            `change` REAL,
+            `changePercent` REAL,
+            `trend` TEXT,
+            `recentStartDate` DATE,
+            `baselineStartDate` DATE,
+            `asOfDate` DATE
+        )"#,
+    )
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query("DELETE FROM highlights")
+        .execute(&mut *tx)
+        .await?;
+
+    let as_of = clock.now();
+    let recent_start = as_of - Duration::days(RECENT_DAYS);
+    let baseline_start = recent_start - Duration::days(BASELINE_DAYS);
+    for (table_name, aggregation) in HIGHLIGHT_METRICS {
+        let table_name = match resolve_table(tx, options, table_name).await? {
+            Some(table_name) => table_name,
+            None => continue,
+        };
+        let recent = aggregate(tx, &table_name, *aggregation, recent_start, as_of).await?;
+        let baseline =
+            aggregate(tx, &table_name, *aggregation, baseline_start, recent_start).await?;
+        let (change, change_percent) = match (recent, baseline) {
+            (Some(recent), Some(baseline)) if baseline != 0.0 => (
+                Some(recent - baseline),
+                Some((recent - baseline) / baseline.abs() * 100.0),
+            ),
+            (Some(recent), Some(baseline)) => (Some(recent - baseline), None),
+            _ => (None, None),
+        };
+        let trend = change_percent.map(|p| {
+            if p.abs() < FLAT_THRESHOLD_PERCENT {
+                "flat"
+            } else if p > 0.0 {
+                "up"
+            } else {
+                "down"
+            }
+        });
+        let unit = sqlx::query_scalar::<_, Option<String>>(&format!(
+            "SELECT unit FROM {} GROUP BY unit ORDER BY COUNT(*) DESC LIMIT 1",
+            get_valid_sqlite_identifier(&table_name)
+        ))
+        .fetch_optional(&mut *tx)
+        .await?
+        .flatten();
+        debug!(
+            "Highlight for {}: recent={:?} baseline={:?}",
+            table_name, recent, baseline
+        );
+        sqlx::query(
+            r#"INSERT INTO highlights (`type`, `aggregation`, `unit`, `recentValue`, `baselineValue`, `change`, `changePercent`, `trend`, `recentStartDate`, `baselineStartDate`, `asOfDate`)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)"#,
+        )
+        .bind(&table_name)
+        .bind(aggregation.as_str())
+        .bind(unit)
+        .bind(recent)
+        .bind(baseline)
+        .bind(change)
+        .bind(change_percent)
+        .bind(trend)
+        .bind(recent_start)
+        .bind(baseline_start)
+        .bind(as_of)
+        .execute(&mut *tx)
+        .await?;
+    }
+    Ok(())
+}
+
+/// Summarizes the metric over samples with a startDate in [start, end)
+async fn aggregate(
+    tx: &mut Transaction<'_, Sqlite>,
+    table_name: &str,
+    aggregation: Aggregation,
+    start: OffsetDateTime,
+    end: OffsetDateTime,
+) -> anyhow::Result<Option<f64>> {
+    let qs = match aggregation {
+        Aggregation::DailySum => format!(
+            r#"SELECT AVG(total) FROM (
+                SELECT SUM(value) AS total FROM {}
+                WHERE julianday(startDate) >= julianday(?) AND julianday(startDate) < julianday(?)
+                GROUP BY substr(startDate, 1, 10)
+            )"#,
+            get_valid_sqlite_identifier(table_name)
+        ),
+        Aggregation::Average => format!(
+            r#"SELECT AVG(value) FROM {}
+            WHERE julianday(startDate) >= julianday(?) AND julianday(startDate) < julianday(?)"#,
+            get_valid_sqlite_identifier(table_name)
+        ),
+    };
+    let value: Option<f64> = sqlx::query_scalar(&qs)
+        .bind(start)
+        .bind(end)
+        .fetch_one(&mut *tx)
+        .await?;
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::SqlitePool;
+    use time::macros::datetime;
+
+    /// Samples of metrics as (type, startDate, value), around the windows of 2024-01-01, whose
+    /// recent window starts 2023-10-03 and baseline window 2022-10-03
+    const SAMPLES: &[(&str, &str, f64)] = &[
+        // Samples on the start of a window are within it, and those on its end aren't
+        ("RestingHeartRate", "2022-10-02 23:59:59", 1000.0),
+        ("RestingHeartRate", "2022-10-03 00:00:00", 50.0),
+        ("RestingHeartRate", "2023-10-02 23:59:59", 50.0),
+        ("RestingHeartRate", "2023-10-03 00:00:00", 60.0),
+        ("RestingHeartRate", "2024-01-01 00:00:00", 1000.0),
+        // Daily sums are averaged over the days with samples
+        ("StepCount", "2023-01-01 08:00:00", 5000.0),
+        ("StepCount", "2023-01-01 18:00:00", 5000.0),
+        ("StepCount", "2023-12-01 08:00:00", 4000.0),
+        ("StepCount", "2023-12-01 18:00:00", 4000.0),
+        // Days are local, so an evening sample west of UTC counts toward the day it was taken
+        ("StepCount", "2023-12-02 08:00:00-08:00", 4000.0),
+        ("StepCount", "2023-12-02 20:00:00-08:00", 4000.0),
+        // Changes under a percent are flat
+        ("BodyMass", "2023-01-01 08:00:00", 70.0),
+        ("BodyMass", "2023-12-01 08:00:00", 70.5),
+        // A zero baseline has a change, but no percentage or trend
+        ("WalkingSpeed", "2023-01-01 08:00:00", 0.0),
+        ("WalkingSpeed", "2023-12-01 08:00:00", 1.2),
+        // A metric without recent samples has no change
+        ("VO2Max", "2023-01-01 08:00:00", 40.0),
+    ];
+
+    type Highlight = (
+        String,
+        Option<f64>,
+        Option<f64>,
+        Option<f64>,
+        Option<f64>,
+        Option<String>,
+    );
+
+    fn highlight(
+        type_name: &str,
+        recent: Option<f64>,
+        baseline: Option<f64>,
+        change: Option<f64>,
+        change_percent: Option<f64>,
+        trend: Option<&str>,
+    ) -> Highlight {
+        (
+            format!("HKQuantityTypeIdentifier{}", type_name),
+            recent,
+            baseline,
+            change,
+            change_percent,
+            trend.map(str::to_string),
+        )
+    }
+
+    #[tokio::test]
+    async fn windows_and_trends() {
+        let pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        let mut tx = pool.begin().await.unwrap();
+        for (type_name, start_date, value) in SAMPLES {
+            let table_name = format!("HKQuantityTypeIdentifier{}", type_name);
+            sqlx::query(&format!(
+                "CREATE TABLE IF NOT EXISTS {} (startDate DATE, value REAL, unit TEXT)",
+                table_name
+            ))
+            .execute(&mut *tx)
+            .await
+            .unwrap();
+            sqlx::query(&format!("INSERT INTO {} VALUES (?, ?, 'u')", table_name))
+                .bind(start_date)
+                .bind(value)
+                .execute(&mut *tx)
+                .await
+                .unwrap();
+        }
+        let clock = Clock::Fixed(datetime!(2024-01-01 00:00:00 UTC));
+        create_highlights_table(&mut tx, &ImportOptions::defaults(), clock)
+            .await
+            .unwrap();
+
+        let windows: Vec<(String, String, String)> = sqlx::query_as(
+            "SELECT DISTINCT date(recentStartDate), date(baselineStartDate), date(asOfDate) FROM highlights",
+        )
+        .fetch_all(&mut *tx)
+        .await
+        .unwrap();
+        assert_eq!(
+            windows,
+            [(
+                "2023-10-03".to_string(),
+                "2022-10-03".to_string(),
+                "2024-01-01".to_string()
+            )]
+        );
+        let highlights: Vec<Highlight> = sqlx::query_as(
+            r#"SELECT type, recentValue, baselineValue, ROUND(change, 6), ROUND(changePercent, 6), trend
+            FROM highlights ORDER BY type"#,
+        )
+        .fetch_all(&mut *tx)
+        .await
+        .unwrap();
+        assert_eq!(
+            highlights,
+            [
+                highlight(
+                    "BodyMass",
+                    Some(70.5),
+                    Some(70.0),
+                    Some(0.5),
+                    Some(0.714286),
+                    Some("flat")
+                ),
+                highlight(
+                    "RestingHeartRate",
+                    Some(60.0),
+                    Some(50.0),
+                    Some(10.0),
+                    Some(20.0),
+                    Some("up")
+                ),
+                highlight(
+                    "StepCount",
+                    Some(8000.0),
+                    Some(10000.0),
+                    Some(-2000.0),
+                    Some(-20.0),
+                    Some("down")
+                ),
+                highlight("VO2Max", None, Some(40.0), None, None, None),
+                highlight("WalkingSpeed", Some(1.2), Some(0.0), Some(1.2), None, None),
+            ]
+        );
+    }
+}
@@ -0,0 +1,204 @@
+//! Daily series for sparse, continuous metrics such as body mass, with the gaps between
+//! measurements filled in
+use super::resolve_table;
+use crate::clock::Clock;
+use crate::core::HEALTHKIT_DATE_FORMAT;
+use crate::identifier::get_valid_sqlite_identifier;
+use crate::options::ImportOptions;
+use log::*;
+use sqlx::{Sqlite, Transaction};
+use time::Date;
+
+/// Short names accepted by --interpolate in addition to full type identifiers
+const METRIC_ALIASES: &[(&str, &str)] = &[
+    ("weight", "HKQuantityTypeIdentifierBodyMass"),
+    ("body_fat", "HKQuantityTypeIdentifierBodyFatPercentage"),
+    ("bmi", "HKQuantityTypeIdentifierBodyMassIndex"),
+    ("lean_body_mass", "HKQuantityTypeIdentifierLeanBodyMass"),
+    ("height", "HKQuantityTypeIdentifierHeight"),
+    ("waist", "HKQuantityTypeIdentifierWaistCircumference"),
+    ("vo2max", "HKQuantityTypeIdentifierVO2Max"),
+    (
+        "resting_heart_rate",
+        "HKQuantityTypeIdentifierRestingHeartRate",
+    ),
+];
+
+/// Creates the interpolated_daily table for the given metrics.
+///
+/// Days with measurements hold the average of that day's samples. Days between two measurements
+/// are linearly interpolated, and days after the last measurement are forward-filled up to today,
+/// or the --as-of date.
+/// Every row records how it was derived in the method column.
+pub async fn create_interpolated_daily_table(
+    tx: &mut Transaction<'_, Sqlite>,
+    options: &ImportOptions,
+    metrics: &[String],
+    clock: Clock,
+) -> anyhow::Result<()> {
+    sqlx::query(
+        r#"CREATE TABLE IF NOT EXISTS interpolated_daily (
+            `type` TEXT,
+            `date` DATE,
+            `value` REAL,
+            `unit` TEXT,
+            `method` TEXT,
+            `derived` INTEGER
+        )"#,
+    )
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query("DELETE FROM interpolated_daily")
+        .execute(&mut *tx)
+        .await?;
+
+    let today = clock.today();
+    for metric in metrics {
+        let type_name = METRIC_ALIASES
+            .iter()
+            .find(|(alias, _)| *alias == metric.as_str())
+            .map(|(_, table_name)| *table_name)
+            .unwrap_or(metric.as_str());
+        let table_name = match resolve_table(tx, options, type_name).await? {
+            Some(table_name) => table_name,
+            None => {
+                warn!("Cannot interpolate {}: no {} table", metric, type_name);
+                continue;
+            }
+        };
+        // Only interpolate between samples recorded in the most common unit
+        let unit: Option<String> = sqlx::query_scalar::<_, Option<String>>(&format!(
+            "SELECT unit FROM {} GROUP BY unit ORDER BY COUNT(*) DESC LIMIT 1",
+            get_valid_sqlite_identifier(&table_name)
+        ))
+        .fetch_optional(&mut *tx)
+        .await?
+        .flatten();
+        // The first 10 characters of the stored startDate are the local date of the sample
+        let daily: Vec<(String, f64)> = sqlx::query_as(&format!(
+            r#"SELECT substr(startDate, 1, 10) AS day, AVG(value) FROM {}
+            WHERE unit IS ?
+            GROUP BY day ORDER BY day"#,
+            get_valid_sqlite_identifier(&table_name)
+        ))
+        .bind(&unit)
+        .fetch_all(&mut *tx)
+        .await?;
+        let mut measurements = Vec::with_capacity(daily.len());
+        for (day, value) in daily {
+            measurements.push((Date::parse(&day, &HEALTHKIT_DATE_FORMAT)?, value));
+        }
+        for (date, value, method) in interpolate_daily(&measurements, today) {
+            sqlx::query(
+                r#"INSERT INTO interpolated_daily (`type`, `date`, `value`, `unit`, `method`, `derived`)
+                VALUES (?, ?, ?, ?, ?, ?)"#,
+            )
+            .bind(type_name)
+            .bind(date)
+            .bind(value)
+            .bind(&unit)
+            .bind(method)
+            .bind(method != "measured")
+            .execute(&mut *tx)
+            .await?;
+        }
+    }
+    Ok(())
+}
+
+/// Expands sorted daily measurements into one value per day through the given end date
+fn interpolate_daily(measurements: &[(Date, f64)], end: Date) -> Vec<(Date, f64, &'static str)> {
+    let mut series = Vec::new();
+    for window in measurements.windows(2) {
+        let (start_date, start_value) = window[0];
+        let (end_date, end_value) = window[1];
+        let days = (end_date - start_date).whole_days() as f64;
+        series.push((start_date, start_value, "measured"));
+        let mut date = start_date;
+        while let Some(next) = date.next_day() {
+            if next >= end_date {
+                break;
+            }
+            let t = (next - start_date).whole_days() as f64 / days;
+            series.push((next, start_value + (end_value - start_value) * t, "linear"));
+            date = next;
+        }
+    }
+    if let Some(&(last_date, last_value)) = measurements.last() {
+        series.push((last_date, last_value, "measured"));
+        let mut date = last_date;
+        while let Some(next) = date.next_day() {
+            if next > end {
+                break;
+            }
+            series.push((next, last_value, "forward_fill"));
+            date = next;
+        }
+    }
+    series
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time::macros::date;
+
+    #[test]
+    fn gaps_are_interpolated_between_their_measured_days() {
+        let measurements = [
+            (date!(2023 - 01 - 01), 70.0),
+            (date!(2023 - 01 - 02), 71.0),
+            (date!(2023 - 01 - 05), 68.0),
+        ];
+        assert_eq!(
+            interpolate_daily(&measurements, date!(2023 - 01 - 05)),
+            [
+                (date!(2023 - 01 - 01), 70.0, "measured"),
+                // Consecutive days have no gap to fill
+                (date!(2023 - 01 - 02), 71.0, "measured"),
+                (date!(2023 - 01 - 03), 70.0, "linear"),
+                (date!(2023 - 01 - 04), 69.0, "linear"),
+                // The last measurement falls on the end date, so nothing is forward-filled
+                (date!(2023 - 01 - 05), 68.0, "measured"),
+            ]
+        );
+    }
+
+    #[test]
+    fn days_after_the_last_measurement_are_forward_filled_through_the_end() {
+        let measurements = [(date!(2023 - 01 - 01), 70.0), (date!(2023 - 01 - 03), 72.0)];
+        assert_eq!(
+            interpolate_daily(&measurements, date!(2023 - 01 - 05)),
+            [
+                (date!(2023 - 01 - 01), 70.0, "measured"),
+                (date!(2023 - 01 - 02), 71.0, "linear"),
+                (date!(2023 - 01 - 03), 72.0, "measured"),
+                (date!(2023 - 01 - 04), 72.0, "forward_fill"),
+                (date!(2023 - 01 - 05), 72.0, "forward_fill"),
+            ]
+        );
+    }
+
+    #[test]
+    fn single_samples_are_only_forward_filled() {
+        let measurements = [(date!(2023 - 01 - 01), 70.0)];
+        assert_eq!(
+            interpolate_daily(&measurements, date!(2023 - 01 - 03)),
+            [
+                (date!(2023 - 01 - 01), 70.0, "measured"),
+                (date!(2023 - 01 - 02), 70.0, "forward_fill"),
+                (date!(2023 - 01 - 03), 70.0, "forward_fill"),
+            ]
+        );
+        assert_eq!(
+            interpolate_daily(&measurements, date!(2023 - 01 - 01)),
+            [(date!(2023 - 01 - 01), 70.0, "measured")]
+        );
+        // A sample after the end date is kept, but not filled backwards
+        assert_eq!(
+            interpolate_daily(&measurements, date!(2022 - 12 - 31)),
+            [(date!(2023 - 01 - 01), 70.0, "measured")]
+        );
+        assert_eq!(interpolate_daily(&[], date!(2023 - 01 - 01)), []);
+    }
+}
@@ -0,0 +1,112 @@
+//! Views of each table without the samples entered by hand
+use crate::core::METADATA_COLUMN_PREFIX;
+use crate::identifier::get_valid_sqlite_identifier;
+use crate::metadata::{FRIENDLY_USER_ENTERED_COLUMN, USER_ENTERED_COLUMN};
+use crate::options::Naming;
+use crate::record_metadata;
+use log::*;
+use sqlx::{Sqlite, Transaction};
+
+/// Suffix of the view names, e.g. HKQuantityTypeIdentifierBodyMass_measured
+const VIEW_SUFFIX: &str = "_measured";
+
+/// Creates a `<table>_measured` view for every table with an HKWasUserEntered column, or with
+/// HKWasUserEntered entries in the RecordMetadata table of --metadata-table, holding only the
+/// device-measured rows
+pub async fn create_measured_views(
+    tx: &mut Transaction<'_, Sqlite>,
+    naming: Naming,
+) -> anyhow::Result<()> {
+    let tables: Vec<(String, String)> = sqlx::query_as(
+        r#"SELECT m.name, p.name FROM sqlite_master m, pragma_table_info(m.name) p
+        WHERE m.type = 'table' AND p.name IN (?, ?)"#,
+    )
+    .bind(USER_ENTERED_COLUMN)
+    .bind(FRIENDLY_USER_ENTERED_COLUMN)
+    .fetch_all(&mut *tx)
+    .await?;
+    let mut tables: Vec<(String, String)> = tables
+        .into_iter()
+        .map(|(table_name, column_name)| (table_name, get_valid_sqlite_identifier(&column_name)))
+        .collect();
+    let key = USER_ENTERED_COLUMN
+        .strip_prefix(METADATA_COLUMN_PREFIX)
+        .expect("a metadata column");
+    for table_name in record_metadata::tables_with_key(tx, naming, key).await? {
+        if let Some(value) =
+            record_metadata::value_expression(tx, naming, &table_name, key, true).await?
+        {
+            tables.push((table_name, value));
+        }
+    }
+    for (table_name, user_entered) in tables {
+        let view_name = format!("{}{}", table_name, VIEW_SUFFIX);
+        debug!("Creating view {}", view_name);
+        // Recreated in case the column was renamed by --friendly-names since the last import
+        sqlx::query(&format!(
+            "DROP VIEW IF EXISTS {}",
+            get_valid_sqlite_identifier(&view_name)
+        ))
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query(&format!(
+            "CREATE VIEW {} AS SELECT * FROM {} WHERE COALESCE({}, 0) = 0",
+            get_valid_sqlite_identifier(&view_name),
+            get_valid_sqlite_identifier(&table_name),
+            user_entered
+        ))
+        .execute(&mut *tx)
+        .await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::SqlitePool;
+
+    #[tokio::test]
+    async fn views_leave_out_the_rows_entered_by_hand() {
+        let pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        let mut tx = pool.begin().await.unwrap();
+        sqlx::query(
+            r#"CREATE TABLE HKQuantityTypeIdentifierBodyMass (value REAL, metadata_HKWasUserEntered INTEGER);
+            INSERT INTO HKQuantityTypeIdentifierBodyMass VALUES (70, 0), (71, 1), (72, NULL);
+            CREATE TABLE HKQuantityTypeIdentifierHeight (value REAL, user_entered INTEGER);
+            INSERT INTO HKQuantityTypeIdentifierHeight VALUES (1.8, 1), (1.81, 0);
+            CREATE TABLE HKQuantityTypeIdentifierHeartRate (value REAL);"#,
+        )
+        .execute(&mut *tx)
+        .await
+        .unwrap();
+        create_measured_views(&mut tx, Naming::Original)
+            .await
+            .unwrap();
+        let body_mass: Vec<f64> =
+            sqlx::query_scalar("SELECT value FROM HKQuantityTypeIdentifierBodyMass_measured")
+                .fetch_all(&mut *tx)
+                .await
+                .unwrap();
+        assert_eq!(body_mass, [70.0, 72.0]);
+        // Also with the column of --friendly-names
+        let height: Vec<f64> =
+            sqlx::query_scalar("SELECT value FROM HKQuantityTypeIdentifierHeight_measured")
+                .fetch_all(&mut *tx)
+                .await
+                .unwrap();
+        assert_eq!(height, [1.81]);
+        let views: Vec<String> =
+            sqlx::query_scalar("SELECT name FROM sqlite_master WHERE type = 'view' ORDER BY name")
+                .fetch_all(&mut *tx)
+                .await
+                .unwrap();
+        assert_eq!(
+            views,
+            [
+                "HKQuantityTypeIdentifierBodyMass_measured",
+                "HKQuantityTypeIdentifierHeight_measured"
+            ]
+        );
+    }
+}
@@ -0,0 +1,110 @@
+//! Flags physiologically implausible samples without deleting them
+use super::resolve_table;
+use crate::identifier::get_valid_sqlite_identifier;
+use crate::options::ImportOptions;
+use sqlx::{Sqlite, Transaction};
+
+/// Adds an is_outlier column to every table with bounds in the configuration file
+pub async fn flag_outliers(
+    tx: &mut Transaction<'_, Sqlite>,
+    options: &ImportOptions,
+) -> anyhow::Result<()> {
+    for (table_name, bounds) in &options.config().outlier_bounds {
+        let table_name = match resolve_table(tx, options, table_name).await? {
+            Some(table_name) => table_name,
+            None => continue,
+        };
+        let columns: Vec<String> = sqlx::query_scalar("SELECT name FROM pragma_table_info(?)")
+            .bind(&table_name)
+            .fetch_all(&mut *tx)
+            .await?;
+        let has_unit = columns.iter().any(|c| c == "unit");
+        if !columns.iter().any(|c| c == "value") || (bounds.unit.is_some() && !has_unit) {
+            continue;
+        }
+        if !columns.iter().any(|c| c == "is_outlier") {
+            sqlx::query(&format!(
+                "ALTER TABLE {} ADD COLUMN `is_outlier` INTEGER",
+                get_valid_sqlite_identifier(&table_name)
+            ))
+            .execute(&mut *tx)
+            .await?;
+        }
+        let qs =
+            format!(
+            "UPDATE {} SET `is_outlier` = CASE WHEN {}(value < ? OR value > ?) THEN 1 ELSE 0 END",
+            get_valid_sqlite_identifier(&table_name),
+            if bounds.unit.is_some() { "unit = ? AND " } else { "" }
+        );
+        let mut query = sqlx::query(&qs);
+        if let Some(unit) = &bounds.unit {
+            query = query.bind(unit);
+        }
+        query
+            .bind(bounds.min)
+            .bind(bounds.max)
+            .execute(&mut *tx)
+            .await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::SqlitePool;
+
+    async fn flags(tx: &mut Transaction<'_, Sqlite>, table_name: &str) -> Vec<(f64, i64)> {
+        sqlx::query_as(&format!(
+            "SELECT value, is_outlier FROM {} ORDER BY rowid",
+            table_name
+        ))
+        .fetch_all(&mut **tx)
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn samples_outside_the_bounds_are_flagged() {
+        let pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        let mut tx = pool.begin().await.unwrap();
+        sqlx::query(
+            r#"CREATE TABLE HKQuantityTypeIdentifierHeartRate (value REAL, unit TEXT);
+            INSERT INTO HKQuantityTypeIdentifierHeartRate VALUES
+                (24.9, 'count/min'), (25, 'count/min'), (250, 'count/min'), (250.1, 'count/min'),
+                (1000, 'count/s');
+            CREATE TABLE HKQuantityTypeIdentifierBodyMass (value REAL);
+            INSERT INTO HKQuantityTypeIdentifierBodyMass VALUES (19.9), (150), (700.1);
+            CREATE TABLE HKQuantityTypeIdentifierStepCount (count REAL, unit TEXT);"#,
+        )
+        .execute(&mut *tx)
+        .await
+        .unwrap();
+        let options = ImportOptions::defaults();
+        flag_outliers(&mut tx, &options).await.unwrap();
+        // Bounds are inclusive, and only apply to samples in their unit
+        assert_eq!(
+            flags(&mut tx, "HKQuantityTypeIdentifierHeartRate").await,
+            [(24.9, 1), (25.0, 0), (250.0, 0), (250.1, 1), (1000.0, 0)]
+        );
+        // Bounds without a unit apply to every sample
+        assert_eq!(
+            flags(&mut tx, "HKQuantityTypeIdentifierBodyMass").await,
+            [(19.9, 1), (150.0, 0), (700.1, 1)]
+        );
+        // Tables without values are left as they are
+        let columns: i64 = sqlx::query_scalar(
+            "SELECT COUNT(*) FROM pragma_table_info('HKQuantityTypeIdentifierStepCount')",
+        )
+        .fetch_one(&mut *tx)
+        .await
+        .unwrap();
+        assert_eq!(columns, 2);
+        // Flagging again updates the existing column
+        flag_outliers(&mut tx, &options).await.unwrap();
+        assert_eq!(
+            flags(&mut tx, "HKQuantityTypeIdentifierBodyMass").await,
+            [(19.9, 1), (150.0, 0), (700.1, 1)]
+        );
+    }
+}
@@ -0,0 +1,208 @@
+//! Views consolidating blood oxygen, respiratory rate, and breathing disturbance samples, with
+//! per-night summaries for spotting possible sleep apnea
+use super::resolve_table;
+use crate::identifier::get_valid_sqlite_identifier;
+use crate::options::ImportOptions;
+use log::*;
+use sqlx::{Sqlite, Transaction};
+
+const SAMPLES_VIEW_NAME: &str = "respiratory_samples";
+const NIGHTLY_VIEW_NAME: &str = "respiratory_nightly";
+
+/// Blood oxygen saturation below this percentage counts towards spo2_minutes_below_threshold
+const SPO2_THRESHOLD_PERCENT: f64 = 90.0;
+
+/// The longest time in minutes a blood oxygen reading is assumed to last, so a gap between
+/// readings, such as the watch being off the wrist, doesn't count as time below the threshold
+const MAX_READING_MINUTES: f64 = 10.0;
+
+/// A metric of the respiratory_samples view
+struct RespiratoryMetric {
+    metric: &'static str,
+    table_name: &'static str,
+    /// Expression converting the value column to the unit of the view
+    value: &'static str,
+}
+
+const RESPIRATORY_METRICS: &[RespiratoryMetric] = &[
+    // Stored as a fraction with a unit of %
+    RespiratoryMetric {
+        metric: "oxygen_saturation",
+        table_name: "HKQuantityTypeIdentifierOxygenSaturation",
+        value: "value * 100",
+    },
+    RespiratoryMetric {
+        metric: "respiratory_rate",
+        table_name: "HKQuantityTypeIdentifierRespiratoryRate",
+        value: "value",
+    },
+    RespiratoryMetric {
+        metric: "breathing_disturbances",
+        table_name: "HKQuantityTypeIdentifierAppleSleepingBreathingDisturbances",
+        value: "value",
+    },
+];
+
+/// Creates the respiratory_samples and respiratory_nightly views.
+///
+/// A night runs from noon to noon and is named by the date it starts on, so readings taken
+/// after midnight count towards the evening before.
+pub async fn create_respiratory_views(
+    tx: &mut Transaction<'_, Sqlite>,
+    options: &ImportOptions,
+) -> anyhow::Result<()> {
+    // Recreated in case an append imported metrics the previous import didn't have
+    for view_name in [NIGHTLY_VIEW_NAME, SAMPLES_VIEW_NAME] {
+        sqlx::query(&format!(
+            "DROP VIEW IF EXISTS {}",
+            get_valid_sqlite_identifier(view_name)
+        ))
+        .execute(&mut *tx)
+        .await?;
+    }
+    let mut selects = Vec::new();
+    for metric in RESPIRATORY_METRICS {
+        let table_name = match resolve_table(tx, options, metric.table_name).await? {
+            Some(table_name) => table_name,
+            None => continue,
+        };
+        selects.push(format!(
+            r#"SELECT '{}' AS metric, rowid AS record_id, startDate, endDate,
+                date(substr(startDate, 1, 19), '-12 hours') AS night, {} AS value, sourceName
+            FROM {}"#,
+            metric.metric,
+            metric.value,
+            get_valid_sqlite_identifier(&table_name)
+        ));
+    }
+    if selects.is_empty() {
+        return Ok(());
+    }
+    debug!(
+        "Creating views {} and {}",
+        SAMPLES_VIEW_NAME, NIGHTLY_VIEW_NAME
+    );
+    sqlx::query(&format!(
+        "CREATE VIEW {} AS {}",
+        get_valid_sqlite_identifier(SAMPLES_VIEW_NAME),
+        selects.join(" UNION ALL ")
+    ))
+    .execute(&mut *tx)
+    .await?;
+    // Each reading lasts until the next reading of the same metric that night
+    sqlx::query(&format!(
+        r#"CREATE VIEW {nightly} AS
+        WITH readings AS (
+            SELECT metric, night, value,
+                COALESCE(MIN((julianday(LEAD(startDate) OVER (PARTITION BY metric, night ORDER BY startDate)) - julianday(startDate)) * 1440, {max_minutes}), {max_minutes}) AS minutes
+            FROM {samples}
+        )
+        SELECT night,
+            MIN(CASE WHEN metric = 'oxygen_saturation' THEN value END) AS spo2_min,
+            AVG(CASE WHEN metric = 'oxygen_saturation' THEN value END) AS spo2_avg,
+            COUNT(CASE WHEN metric = 'oxygen_saturation' THEN value END) AS spo2_readings,
+            SUM(CASE WHEN metric = 'oxygen_saturation' AND value < {threshold} THEN minutes ELSE 0 END) AS spo2_minutes_below_threshold,
+            MIN(CASE WHEN metric = 'respiratory_rate' THEN value END) AS respiratory_rate_min,
+            AVG(CASE WHEN metric = 'respiratory_rate' THEN value END) AS respiratory_rate_avg,
+            MAX(CASE WHEN metric = 'respiratory_rate' THEN value END) AS respiratory_rate_max,
+            MAX(CASE WHEN metric = 'breathing_disturbances' THEN value END) AS breathing_disturbances
+        FROM readings GROUP BY night"#,
+        nightly = get_valid_sqlite_identifier(NIGHTLY_VIEW_NAME),
+        samples = get_valid_sqlite_identifier(SAMPLES_VIEW_NAME),
+        max_minutes = MAX_READING_MINUTES,
+        threshold = SPO2_THRESHOLD_PERCENT,
+    ))
+    .execute(&mut *tx)
+    .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::SqlitePool;
+
+    #[tokio::test]
+    async fn readings_are_summarized_per_night() {
+        let pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        let mut tx = pool.begin().await.unwrap();
+        sqlx::query(
+            r#"CREATE TABLE HKQuantityTypeIdentifierOxygenSaturation (startDate DATE, endDate DATE, value REAL, sourceName TEXT);
+            INSERT INTO HKQuantityTypeIdentifierOxygenSaturation VALUES
+                ('2024-01-01 23:00:00', '2024-01-01 23:00:00', 0.95, 'Watch'),
+                ('2024-01-02 02:00:00', '2024-01-02 02:00:00', 0.88, 'Watch'),
+                ('2024-01-02 02:04:00', '2024-01-02 02:04:00', 0.89, 'Watch'),
+                ('2024-01-02 03:00:00', '2024-01-02 03:00:00', 0.97, 'Watch'),
+                ('2024-01-02 13:00:00', '2024-01-02 13:00:00', 0.99, 'Watch');
+            CREATE TABLE HKQuantityTypeIdentifierRespiratoryRate (startDate DATE, endDate DATE, value REAL, sourceName TEXT);
+            INSERT INTO HKQuantityTypeIdentifierRespiratoryRate VALUES
+                ('2024-01-02 01:00:00', '2024-01-02 01:00:00', 14, 'Watch'),
+                ('2024-01-02 04:00:00', '2024-01-02 04:00:00', 16, 'Watch');"#,
+        )
+        .execute(&mut *tx)
+        .await
+        .unwrap();
+        create_respiratory_views(&mut tx, &ImportOptions::defaults())
+            .await
+            .unwrap();
+        let samples: Vec<(String, i64)> =
+            sqlx::query_as("SELECT metric, COUNT(*) FROM respiratory_samples GROUP BY metric")
+                .fetch_all(&mut *tx)
+                .await
+                .unwrap();
+        assert_eq!(
+            samples,
+            [
+                ("oxygen_saturation".to_string(), 5),
+                ("respiratory_rate".to_string(), 2)
+            ]
+        );
+        type Night = (
+            String,
+            f64,
+            f64,
+            i64,
+            f64,
+            Option<f64>,
+            Option<f64>,
+            Option<f64>,
+        );
+        let nights: Vec<Night> = sqlx::query_as(
+            r#"SELECT night, ROUND(spo2_min, 6), ROUND(spo2_avg, 6), spo2_readings,
+                ROUND(spo2_minutes_below_threshold, 6), respiratory_rate_min, respiratory_rate_avg,
+                breathing_disturbances
+            FROM respiratory_nightly ORDER BY night"#,
+        )
+        .fetch_all(&mut *tx)
+        .await
+        .unwrap();
+        assert_eq!(
+            nights,
+            [
+                // Readings after midnight count towards the evening before. The 88% reading
+                // lasts until the next 4 minutes later, and the 89% reading is capped at 10
+                // minutes rather than lasting until the next an hour later.
+                (
+                    "2024-01-01".to_string(),
+                    88.0,
+                    92.25,
+                    4,
+                    14.0,
+                    Some(14.0),
+                    Some(15.0),
+                    None
+                ),
+                (
+                    "2024-01-02".to_string(),
+                    99.0,
+                    99.0,
+                    1,
+                    0.0,
+                    None,
+                    None,
+                    None
+                ),
+            ]
+        );
+    }
+}
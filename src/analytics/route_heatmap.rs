@@ -0,0 +1,214 @@
+//! Counts of the workout route points falling in each geohash cell, for drawing personal heatmaps
+use super::resolve_table;
+use crate::identifier::get_valid_sqlite_identifier;
+use crate::options::ImportOptions;
+use log::*;
+use sqlx::{Sqlite, Transaction};
+use std::collections::{BTreeMap, BTreeSet};
+
+const WORKOUT_TABLE_NAME: &str = "Workout";
+
+const GEOHASH_ALPHABET: &[u8] = b"0123456789bcdefghjkmnpqrstuvwxyz";
+
+#[derive(Default)]
+struct Cell {
+    points: i32,
+    workouts: BTreeSet<i64>,
+}
+
+/// Creates and populates the route_heatmap table, binning points into geohashes of the given
+/// number of characters
+pub async fn create_route_heatmap_table(
+    tx: &mut Transaction<'_, Sqlite>,
+    options: &ImportOptions,
+    precision: u8,
+) -> anyhow::Result<()> {
+    sqlx::query(
+        r#"CREATE TABLE IF NOT EXISTS route_heatmap (
+            `geohash` TEXT PRIMARY KEY,
+            `precision` INTEGER,
+            `latitude` REAL,
+            `longitude` REAL,
+            `south` REAL,
+            `west` REAL,
+            `north` REAL,
+            `east` REAL,
+            `points` INTEGER,
+            `workouts` INTEGER
+        )"#,
+    )
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query("DELETE FROM route_heatmap")
+        .execute(&mut *tx)
+        .await?;
+    let workout_table = match resolve_table(tx, options, WORKOUT_TABLE_NAME).await? {
+        Some(workout_table) => workout_table,
+        None => return Ok(()),
+    };
+    let columns: Vec<String> = sqlx::query_scalar("SELECT name FROM pragma_table_info(?)")
+        .bind(&workout_table)
+        .fetch_all(&mut *tx)
+        .await?;
+    if !columns.iter().any(|c| c == "geometry") {
+        return Ok(());
+    }
+
+    // Read one route at a time to bound memory use on exports with many long routes
+    let workout_table = get_valid_sqlite_identifier(&workout_table);
+    let workout_ids: Vec<i64> = sqlx::query_scalar(&format!(
+        "SELECT rowid FROM {} WHERE geometry IS NOT NULL",
+        workout_table
+    ))
+    .fetch_all(&mut *tx)
+    .await?;
+    let mut cells: BTreeMap<String, Cell> = BTreeMap::new();
+    for workout_id in workout_ids {
+        let points: Vec<(Option<f64>, Option<f64>)> = sqlx::query_as(&format!(
+            r#"SELECT json_extract(p.value, '$[0]'), json_extract(p.value, '$[1]')
+            FROM {} w, json_each(w.geometry, '$.coordinates') p
+            WHERE w.rowid = ?"#,
+            workout_table
+        ))
+        .bind(workout_id)
+        .fetch_all(&mut *tx)
+        .await?;
+        for (lon, lat) in points {
+            if let (Some(lon), Some(lat)) = (lon, lat) {
+                let cell = cells
+                    .entry(encode_geohash(lat, lon, precision as usize))
+                    .or_default();
+                cell.points += 1;
+                cell.workouts.insert(workout_id);
+            }
+        }
+    }
+    debug!("Binned route points into {} cells", cells.len());
+    for (geohash, cell) in cells {
+        let (south, west, north, east) = decode_geohash_bounds(&geohash);
+        sqlx::query(
+            r#"INSERT INTO route_heatmap (`geohash`, `precision`, `latitude`, `longitude`, `south`, `west`, `north`, `east`, `points`, `workouts`)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)"#,
+        )
+        .bind(&geohash)
+        .bind(precision as i32)
+        .bind((south + north) / 2.0)
+        .bind((west + east) / 2.0)
+        .bind(south)
+        .bind(west)
+        .bind(north)
+        .bind(east)
+        .bind(cell.points)
+        .bind(cell.workouts.len() as i32)
+        .execute(&mut *tx)
+        .await?;
+    }
+    Ok(())
+}
+
+/// Encodes a coordinate as a geohash with the given number of characters
+fn encode_geohash(lat: f64, lon: f64, precision: usize) -> String {
+    let mut lat_range = (-90.0, 90.0);
+    let mut lon_range = (-180.0, 180.0);
+    let mut geohash = String::with_capacity(precision);
+    let mut bits = 0;
+    let mut index = 0;
+    // Bits alternate between longitude and latitude, starting with longitude
+    let mut even = true;
+    while geohash.len() < precision {
+        let (range, value) = if even {
+            (&mut lon_range, lon)
+        } else {
+            (&mut lat_range, lat)
+        };
+        let mid = (range.0 + range.1) / 2.0;
+        index <<= 1;
+        if value >= mid {
+            index |= 1;
+            range.0 = mid;
+        } else {
+            range.1 = mid;
+        }
+        even = !even;
+        bits += 1;
+        if bits == 5 {
+            geohash.push(GEOHASH_ALPHABET[index] as char);
+            bits = 0;
+            index = 0;
+        }
+    }
+    geohash
+}
+
+/// Returns the (south, west, north, east) bounds of a geohash cell
+fn decode_geohash_bounds(geohash: &str) -> (f64, f64, f64, f64) {
+    let mut lat_range = (-90.0, 90.0);
+    let mut lon_range = (-180.0, 180.0);
+    let mut even = true;
+    for c in geohash.bytes() {
+        let index = GEOHASH_ALPHABET
+            .iter()
+            .position(|a| *a == c)
+            .expect("geohash characters come from the alphabet");
+        for bit in (0..5).rev() {
+            let range = if even { &mut lon_range } else { &mut lat_range };
+            let mid = (range.0 + range.1) / 2.0;
+            if index & (1 << bit) != 0 {
+                range.0 = mid;
+            } else {
+                range.1 = mid;
+            }
+            even = !even;
+        }
+    }
+    (lat_range.0, lon_range.0, lat_range.1, lon_range.1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::SqlitePool;
+
+    #[test]
+    fn geohashes_encode_and_bound_their_points() {
+        assert_eq!(encode_geohash(57.64911, 10.40744, 11), "u4pruydqqvj");
+        assert_eq!(encode_geohash(-90.0, -180.0, 3), "000");
+        let (south, west, north, east) = decode_geohash_bounds("u4pruydqqvj");
+        assert!(south <= 57.64911 && 57.64911 < north);
+        assert!(west <= 10.40744 && 10.40744 < east);
+        let (south, west, north, east) = decode_geohash_bounds("u");
+        assert_eq!((south, west, north, east), (45.0, 0.0, 90.0, 45.0));
+    }
+
+    #[tokio::test]
+    async fn points_and_workouts_are_counted_per_cell() {
+        let pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        let mut tx = pool.begin().await.unwrap();
+        sqlx::query(
+            r#"CREATE TABLE Workout (geometry JSON);
+            INSERT INTO Workout VALUES
+                ('{"type":"LineString","coordinates":[[10.40744,57.64911],[10.40745,57.64912],[-122.4194,37.7749]]}'),
+                (NULL),
+                ('{"type":"LineString","coordinates":[[10.40746,57.64913]]}');"#,
+        )
+        .execute(&mut *tx)
+        .await
+        .unwrap();
+        create_route_heatmap_table(&mut tx, &ImportOptions::defaults(), 5)
+            .await
+            .unwrap();
+        let cells: Vec<(String, i64, i64, i64)> = sqlx::query_as(
+            "SELECT geohash, precision, points, workouts FROM route_heatmap ORDER BY geohash",
+        )
+        .fetch_all(&mut *tx)
+        .await
+        .unwrap();
+        assert_eq!(
+            cells,
+            [
+                ("9q8yy".to_string(), 5, 1, 1),
+                ("u4pru".to_string(), 5, 3, 2)
+            ]
+        );
+    }
+}
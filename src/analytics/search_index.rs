@@ -0,0 +1,243 @@
+//! A full-text index of the source names, devices, and text metadata of every table, for
+//! finding e.g. the samples that came from Withings or a medication note
+use super::{table_exists, DERIVED_TABLES};
+use crate::core::METADATA_COLUMN_PREFIX;
+use crate::device::{DEVICE_ID_COLUMN, DEVICE_TABLE_NAME};
+use crate::identifier::get_valid_sqlite_identifier;
+use crate::naming;
+use crate::options::{ensure_destructive_allowed, Naming};
+use crate::record_metadata::RECORD_METADATA_TABLE_NAME;
+use crate::single_table::METADATA_COLUMN;
+use sqlx::{Sqlite, Transaction};
+
+/// Name of the FTS5 table, a single word so the --naming casings leave it unchanged
+const SEARCH_TABLE_NAME: &str = "search";
+
+/// Creates and populates the search table with a row for each row of the other tables with a
+/// source name, device, or text metadata value, referring to it by table_name and record_id. The
+/// metadata is indexed as "key: value" pairs, for the metadata columns of each table and the
+/// metadata JSON object of --single-table. The entries of the RecordMetadata table of
+/// --metadata-table are indexed as rows of their own referring to the same records.
+///
+/// The virtual table isn't renamed once the import finishes, so its columns and the table names
+/// it holds are written in the casing of --naming. An existing search table is dropped and
+/// rebuilt, which --no-destructive refuses.
+pub async fn create_search_table(
+    tx: &mut Transaction<'_, Sqlite>,
+    naming: Naming,
+    no_destructive: bool,
+) -> anyhow::Result<()> {
+    let column = |name: &str| get_valid_sqlite_identifier(&naming::convert(naming, name));
+    if table_exists(tx, SEARCH_TABLE_NAME).await? {
+        ensure_destructive_allowed(
+            no_destructive,
+            format_args!("drop the {} table", SEARCH_TABLE_NAME),
+        )?;
+    }
+    sqlx::query(&format!("DROP TABLE IF EXISTS {}", SEARCH_TABLE_NAME))
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query(&format!(
+        "CREATE VIRTUAL TABLE {} USING fts5({}, {}, {}, {} UNINDEXED, {} UNINDEXED)",
+        SEARCH_TABLE_NAME,
+        column("sourceName"),
+        column("device"),
+        column("metadata"),
+        column("table_name"),
+        column("record_id")
+    ))
+    .execute(&mut *tx)
+    .await?;
+
+    // The virtual table's shadow tables have the shadow type, so they aren't indexed
+    let tables: Vec<String> = sqlx::query_scalar(
+        r#"SELECT name FROM pragma_table_list
+        WHERE schema = 'main' AND type = 'table'
+            AND name NOT LIKE 'sqlite\_%' ESCAPE '\'
+            AND name NOT LIKE '\_%' ESCAPE '\'
+            AND name NOT IN (SELECT value FROM json_each(?))
+        ORDER BY name"#,
+    )
+    .bind(serde_json::to_string(DERIVED_TABLES)?)
+    .fetch_all(&mut *tx)
+    .await?;
+    for table_name in tables {
+        let column_names: Vec<String> = sqlx::query_scalar("SELECT name FROM pragma_table_info(?)")
+            .bind(&table_name)
+            .fetch_all(&mut *tx)
+            .await?;
+        let has_column = |name: &str| column_names.iter().any(|column| column == name);
+        let text = |name: &str| {
+            if has_column(name) {
+                format!(
+                    "CASE WHEN typeof({0}) = 'text' AND {0} != '' THEN {0} END",
+                    get_valid_sqlite_identifier(name)
+                )
+            } else {
+                "NULL".to_string()
+            }
+        };
+        let mut metadata: Vec<String> = column_names
+            .iter()
+            .filter_map(|name| Some((name, name.strip_prefix(METADATA_COLUMN_PREFIX)?)))
+            .map(|(name, key)| {
+                format!(
+                    "COALESCE(CASE WHEN typeof({0}) = 'text' AND {0} != '' THEN ' ' || '{1}: ' || {0} END, '')",
+                    get_valid_sqlite_identifier(name),
+                    key.replace('\'', "''")
+                )
+            })
+            .collect();
+        if has_column(METADATA_COLUMN) {
+            // Compressed with --compress-json, the object is a BLOB that isn't indexed
+            metadata.push(format!(
+                r#"COALESCE((SELECT ' ' || group_concat(j.key || ': ' || j.value, ' ')
+                    FROM json_each(CASE WHEN typeof(t.{0}) = 'text' THEN t.{0} END) j
+                    WHERE j.type = 'text'), '')"#,
+                get_valid_sqlite_identifier(METADATA_COLUMN)
+            ));
+        }
+        // The description of the device_id of --device-table
+        let device = if has_column(DEVICE_ID_COLUMN) {
+            format!(
+                "(SELECT description FROM {} WHERE id = t.{})",
+                get_valid_sqlite_identifier(DEVICE_TABLE_NAME),
+                get_valid_sqlite_identifier(DEVICE_ID_COLUMN)
+            )
+        } else {
+            text("device")
+        };
+        if !has_column("sourceName") && device == "NULL" && metadata.is_empty() {
+            continue;
+        }
+        let metadata = if metadata.is_empty() {
+            "NULL".to_string()
+        } else {
+            format!("NULLIF(TRIM({}), '')", metadata.join(" || "))
+        };
+        sqlx::query(&format!(
+            r#"INSERT INTO {0} ({1}, {2}, {3}, {4}, {5})
+            SELECT source_name, device, metadata, ?, row_id FROM (
+                SELECT {6} AS source_name, {7} AS device, {8} AS metadata, t.rowid AS row_id
+                FROM {9} t
+            )
+            WHERE source_name IS NOT NULL OR device IS NOT NULL OR metadata IS NOT NULL"#,
+            SEARCH_TABLE_NAME,
+            column("sourceName"),
+            column("device"),
+            column("metadata"),
+            column("table_name"),
+            column("record_id"),
+            text("sourceName"),
+            device,
+            metadata,
+            get_valid_sqlite_identifier(&table_name)
+        ))
+        .bind(naming::convert(naming, &table_name))
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    // The table names of its rows are already the names of --naming
+    if table_exists(tx, RECORD_METADATA_TABLE_NAME).await? {
+        sqlx::query(&format!(
+            r#"INSERT INTO {0} ({1}, {2}, {3})
+            SELECT group_concat(key || ': ' || value, ' '), table_name, row_id
+            FROM {4}
+            WHERE value != ''
+            GROUP BY table_name, row_id"#,
+            SEARCH_TABLE_NAME,
+            column("metadata"),
+            column("table_name"),
+            column("record_id"),
+            get_valid_sqlite_identifier(RECORD_METADATA_TABLE_NAME)
+        ))
+        .execute(&mut *tx)
+        .await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conformance::EXPORT_FILES;
+    use crate::options::ImportOptions;
+    use crate::testing::{self, TestDir};
+    use sqlx::SqlitePool;
+
+    #[tokio::test]
+    async fn the_search_table_finds_rows_by_source_device_and_metadata() {
+        let dir = TestDir::new("search-index");
+        let export_dir = dir.write_export("export", EXPORT_FILES);
+        let db_url = dir.db_url("health.db");
+        let options = ImportOptions {
+            search_index: true,
+            ..ImportOptions::defaults()
+        };
+        testing::import(&export_dir, &db_url, &options)
+            .await
+            .unwrap();
+        let db = SqlitePool::connect(&db_url).await.unwrap();
+        let search = |query: &'static str| {
+            let db = db.clone();
+            async move {
+                let rows: Vec<(String, i64)> = sqlx::query_as(
+                    "SELECT table_name, record_id FROM search WHERE search MATCH ? ORDER BY table_name, record_id",
+                )
+                .bind(query)
+                .fetch_all(&db)
+                .await
+                .unwrap();
+                rows
+            }
+        };
+        let phone = [("HKQuantityTypeIdentifierStepCount".to_string(), 1)];
+        assert_eq!(search("sourceName:phone").await, phone);
+        // The metadata is indexed as key: value pairs
+        assert_eq!(
+            search("metadata:HKTimeZone AND metadata:Angeles").await,
+            [("Workout".to_string(), 1)]
+        );
+        // Rows without a text source, device, or metadata, and the internal tables, aren't indexed
+        let indexed: i64 = sqlx::query_scalar(
+            "SELECT COUNT(*) FROM search WHERE table_name LIKE '\\_%' ESCAPE '\\' OR table_name = 'Me'",
+        )
+        .fetch_one(&db)
+        .await
+        .unwrap();
+        assert_eq!(indexed, 0);
+    }
+
+    #[tokio::test]
+    async fn rebuilding_the_search_table_is_refused_with_no_destructive() {
+        let pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        let mut tx = pool.begin().await.unwrap();
+        sqlx::query(
+            "CREATE TABLE Workout (sourceName TEXT); INSERT INTO Workout VALUES ('Watch');",
+        )
+        .execute(&mut *tx)
+        .await
+        .unwrap();
+        // Creating the table isn't destructive, rebuilding it is
+        create_search_table(&mut tx, Naming::Original, true)
+            .await
+            .unwrap();
+        let error = create_search_table(&mut tx, Naming::Original, true)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Refusing to drop the search table because --no-destructive is set"
+        );
+        create_search_table(&mut tx, Naming::Original, false)
+            .await
+            .unwrap();
+        let rows: i64 =
+            sqlx::query_scalar("SELECT COUNT(*) FROM search WHERE search MATCH 'watch'")
+                .fetch_one(&mut *tx)
+                .await
+                .unwrap();
+        assert_eq!(rows, 1);
+    }
+}
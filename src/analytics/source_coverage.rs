@@ -0,0 +1,120 @@
+//! Which apps and devices contributed the rows of each record type, and over which dates
+use super::sample_tables;
+use crate::identifier::get_valid_sqlite_identifier;
+use sqlx::{Sqlite, Transaction};
+
+/// Creates and populates the source_coverage table
+pub async fn create_source_coverage_table(tx: &mut Transaction<'_, Sqlite>) -> anyhow::Result<()> {
+    sqlx::query(
+        r#"CREATE TABLE IF NOT EXISTS source_coverage (
+            `type` TEXT,
+            `sourceName` TEXT,
+            `rank` INTEGER,
+            `rowCount` INTEGER,
+            `sharePercent` REAL,
+            `activeDays` INTEGER,
+            `firstDate` DATE,
+            `lastDate` DATE
+        )"#,
+    )
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query("DELETE FROM source_coverage")
+        .execute(&mut *tx)
+        .await?;
+
+    for table_name in sample_tables(tx).await? {
+        let qs = format!(
+            r#"INSERT INTO source_coverage (`type`, `sourceName`, `rank`, `rowCount`, `sharePercent`, `activeDays`, `firstDate`, `lastDate`)
+            SELECT ?, sourceName,
+                RANK() OVER (ORDER BY rowCount DESC),
+                rowCount,
+                100.0 * rowCount / SUM(rowCount) OVER (),
+                activeDays, firstDate, lastDate
+            FROM (
+                SELECT sourceName,
+                    COUNT(*) AS rowCount,
+                    COUNT(DISTINCT substr(startDate, 1, 10)) AS activeDays,
+                    datetime(MIN(julianday(startDate))) AS firstDate,
+                    datetime(MAX(julianday(startDate))) AS lastDate
+                FROM {}
+                GROUP BY sourceName
+            )"#,
+            get_valid_sqlite_identifier(&table_name)
+        );
+        sqlx::query(&qs).bind(&table_name).execute(&mut *tx).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::SqlitePool;
+
+    #[tokio::test]
+    async fn sources_are_ranked_by_their_share_of_rows() {
+        let pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        let mut tx = pool.begin().await.unwrap();
+        sqlx::query(
+            r#"CREATE TABLE HKQuantityTypeIdentifierHeartRate (sourceName TEXT, startDate DATE);
+            INSERT INTO HKQuantityTypeIdentifierHeartRate VALUES
+                ('Watch', '2023-01-01 08:00:00'), ('Watch', '2023-01-01 09:00:00'),
+                ('Watch', '2023-01-03 08:00:00'), ('Phone', '2023-01-02 08:00:00'),
+                ('Phone', '2023-01-02 20:00:00-08:00');
+            CREATE TABLE _internal (sourceName TEXT, startDate DATE);
+            INSERT INTO _internal VALUES ('Watch', '2023-01-01 08:00:00');
+            CREATE TABLE interpolated_daily (sourceName TEXT, startDate DATE);
+            INSERT INTO interpolated_daily VALUES ('Watch', '2023-01-01 08:00:00');
+            CREATE TABLE ActivitySummary (dateComponents DATE, sourceName TEXT);
+            INSERT INTO ActivitySummary VALUES ('2023-01-01', 'Watch');"#,
+        )
+        .execute(&mut *tx)
+        .await
+        .unwrap();
+        create_source_coverage_table(&mut tx).await.unwrap();
+        type Coverage = (String, String, i64, i64, f64, i64, String, String);
+        let coverage: Vec<Coverage> =
+            sqlx::query_as("SELECT * FROM source_coverage ORDER BY type, rank")
+                .fetch_all(&mut *tx)
+                .await
+                .unwrap();
+        let row = |source: &str, rank, rows, share, days, first: &str, last: &str| {
+            (
+                "HKQuantityTypeIdentifierHeartRate".to_string(),
+                source.to_string(),
+                rank,
+                rows,
+                share,
+                days,
+                first.to_string(),
+                last.to_string(),
+            )
+        };
+        assert_eq!(
+            coverage,
+            [
+                // Neither the internal nor the derived tables are counted
+                row(
+                    "Watch",
+                    1,
+                    3,
+                    60.0,
+                    2,
+                    "2023-01-01 08:00:00",
+                    "2023-01-03 08:00:00"
+                ),
+                // Active on one local day, although the evening sample is the next day in UTC
+                row(
+                    "Phone",
+                    2,
+                    2,
+                    40.0,
+                    1,
+                    "2023-01-02 08:00:00",
+                    "2023-01-03 04:00:00"
+                ),
+            ]
+        );
+    }
+}
@@ -0,0 +1,119 @@
+//! A single long-format view of every quantity sample, the shape tidyverse and pandas tooling
+//! expects
+use super::{quantity_tables, QUANTITY_TABLE_PREFIX};
+use crate::identifier::get_valid_sqlite_identifier;
+use crate::options::ImportOptions;
+use crate::registry;
+use log::*;
+use sqlx::{Sqlite, Transaction};
+
+const VIEW_NAME: &str = "samples_long";
+
+/// Creates the samples_long view of (person, metric, timestamp, value, unit, source) rows with a
+/// UNION ALL over the quantity tables, labelling every row with the given person. The metrics
+/// are the types of the samples, or their readable names of the registry with --friendly-names,
+/// e.g. "Heart Rate" rather than "HeartRate".
+pub async fn create_samples_long_view(
+    tx: &mut Transaction<'_, Sqlite>,
+    options: &ImportOptions,
+    person: &str,
+) -> anyhow::Result<()> {
+    // Recreated so it covers the tables added since the last import
+    sqlx::query(&format!(
+        "DROP VIEW IF EXISTS {}",
+        get_valid_sqlite_identifier(VIEW_NAME)
+    ))
+    .execute(&mut *tx)
+    .await?;
+    let tables = quantity_tables(tx, options).await?;
+    if tables.is_empty() {
+        return Ok(());
+    }
+    debug!("Creating view {} over {} tables", VIEW_NAME, tables.len());
+    let person = person.replace('\'', "''");
+    let selects = tables
+        .iter()
+        .map(|(table_name, type_name)| {
+            let metric = if options.friendly_names {
+                registry::friendly_name(type_name)
+            } else {
+                type_name.trim_start_matches(QUANTITY_TABLE_PREFIX).to_string()
+            };
+            format!(
+                "SELECT '{}' AS person, '{}' AS metric, startDate AS timestamp, value, unit, sourceName AS source FROM {}",
+                person,
+                metric.replace('\'', "''"),
+                get_valid_sqlite_identifier(table_name)
+            )
+        })
+        .collect::<Vec<_>>();
+    sqlx::query(&format!(
+        "CREATE VIEW {} AS {}",
+        get_valid_sqlite_identifier(VIEW_NAME),
+        selects.join(" UNION ALL ")
+    ))
+    .execute(&mut *tx)
+    .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::SqlitePool;
+
+    async fn samples(options: &ImportOptions) -> Vec<(String, String, String, f64)> {
+        let pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        let mut tx = pool.begin().await.unwrap();
+        sqlx::query(
+            r#"CREATE TABLE HKQuantityTypeIdentifierHeartRate (type TEXT, startDate DATE, value REAL, unit TEXT, sourceName TEXT);
+            INSERT INTO HKQuantityTypeIdentifierHeartRate VALUES
+                ('HKQuantityTypeIdentifierHeartRate', '2024-01-01 08:00:00', 62, 'count/min', 'Watch');
+            CREATE TABLE HKQuantityTypeIdentifierBodyMass (type TEXT, startDate DATE, value REAL, unit TEXT, sourceName TEXT);
+            INSERT INTO HKQuantityTypeIdentifierBodyMass VALUES
+                ('HKQuantityTypeIdentifierBodyMass', '2024-01-01 07:00:00', 72, 'kg', 'Scale');
+            CREATE TABLE HKCategoryTypeIdentifierSleepAnalysis (type TEXT, startDate DATE, value TEXT);"#,
+        )
+        .execute(&mut *tx)
+        .await
+        .unwrap();
+        create_samples_long_view(&mut tx, options, "O'Brien")
+            .await
+            .unwrap();
+        sqlx::query_as("SELECT person, metric, source, value FROM samples_long ORDER BY metric")
+            .fetch_all(&mut *tx)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn quantity_samples_are_labelled_with_the_person_and_metric() {
+        assert_eq!(
+            samples(&ImportOptions::defaults()).await,
+            [
+                (
+                    "O'Brien".to_string(),
+                    "BodyMass".to_string(),
+                    "Scale".to_string(),
+                    72.0
+                ),
+                (
+                    "O'Brien".to_string(),
+                    "HeartRate".to_string(),
+                    "Watch".to_string(),
+                    62.0
+                ),
+            ]
+        );
+        let options = ImportOptions {
+            friendly_names: true,
+            ..ImportOptions::defaults()
+        };
+        let metrics: Vec<String> = samples(&options)
+            .await
+            .into_iter()
+            .map(|(_, metric, _, _)| metric)
+            .collect();
+        assert_eq!(metrics, ["Heart Rate", "Weight"]);
+    }
+}
@@ -0,0 +1,243 @@
+//! Links the samples recorded during a workout to it, so per-workout queries are simple joins
+use super::{resolve_table, DERIVED_TABLES};
+use crate::identifier::{self, get_valid_sqlite_identifier};
+use crate::options::ImportOptions;
+use sqlx::{Sqlite, Transaction};
+
+const WORKOUT_TABLE_NAME: &str = "Workout";
+
+/// The tables of the workouts and their children, which aren't linked even though some have the
+/// columns of a record table
+const WORKOUT_TABLE_NAMES: &[&str] = &[
+    WORKOUT_TABLE_NAME,
+    "WorkoutActivity",
+    "WorkoutEvent",
+    "WorkoutRoute",
+    "WorkoutRoutePoint",
+    "WorkoutStatistics",
+];
+
+/// Column of the sample tables holding the rowid of the workout of each sample
+const WORKOUT_ID_COLUMN: &str = "workout_id";
+
+/// Adds a workout_id column to every record table, holding the rowid of the workout each sample
+/// overlaps, and indexes it. A sample overlapping several workouts is linked to the one of them
+/// that started last.
+pub async fn link_samples_to_workouts(
+    tx: &mut Transaction<'_, Sqlite>,
+    options: &ImportOptions,
+) -> anyhow::Result<()> {
+    let workout_table = match resolve_table(tx, options, WORKOUT_TABLE_NAME).await? {
+        Some(workout_table) => workout_table,
+        None => return Ok(()),
+    };
+    // Indexing the workout start times keeps each sample's lookup a range scan
+    sqlx::query(&format!(
+        r#"CREATE TEMP TABLE link_workouts AS
+        SELECT rowid AS workout_id, julianday(startDate) AS start_jd, julianday(endDate) AS end_jd
+        FROM {}
+        WHERE julianday(startDate) IS NOT NULL AND julianday(endDate) IS NOT NULL"#,
+        get_valid_sqlite_identifier(&workout_table)
+    ))
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query("CREATE INDEX temp.link_workouts_start ON link_workouts (start_jd)")
+        .execute(&mut *tx)
+        .await?;
+    // No workout overlapping a sample starts earlier than the longest workout before the sample
+    let longest_workout: f64 =
+        sqlx::query_scalar("SELECT COALESCE(MAX(end_jd - start_jd), 0.0) FROM link_workouts")
+            .fetch_one(&mut *tx)
+            .await?;
+
+    // The records, one table per type or the samples table of --single-table, rather than the
+    // workouts and their children
+    let workout_tables: Vec<_> = WORKOUT_TABLE_NAMES
+        .iter()
+        .map(|table_name| identifier::convert(options, table_name))
+        .collect();
+    let tables: Vec<(String, bool)> = sqlx::query_as(
+        r#"SELECT m.name,
+            EXISTS (SELECT 1 FROM pragma_table_info(m.name) WHERE name = ?)
+        FROM sqlite_master m
+        WHERE m.type = 'table'
+            AND m.name NOT LIKE '\_%' ESCAPE '\'
+            AND m.name NOT IN (SELECT value FROM json_each(?))
+            AND m.name NOT IN (SELECT value FROM json_each(?))
+            AND EXISTS (SELECT 1 FROM pragma_table_info(m.name) WHERE name = 'type')
+            AND EXISTS (SELECT 1 FROM pragma_table_info(m.name) WHERE name = 'sourceName')
+            AND EXISTS (SELECT 1 FROM pragma_table_info(m.name) WHERE name = 'startDate')
+            AND EXISTS (SELECT 1 FROM pragma_table_info(m.name) WHERE name = 'endDate')
+        ORDER BY m.name"#,
+    )
+    .bind(WORKOUT_ID_COLUMN)
+    .bind(serde_json::to_string(DERIVED_TABLES)?)
+    .bind(serde_json::to_string(&workout_tables)?)
+    .fetch_all(&mut *tx)
+    .await?;
+    let workout_id = get_valid_sqlite_identifier(WORKOUT_ID_COLUMN);
+    for (table_name, has_workout_id) in tables {
+        let table = get_valid_sqlite_identifier(&table_name);
+        if !has_workout_id {
+            sqlx::query(&format!(
+                "ALTER TABLE {} ADD COLUMN {} INTEGER",
+                table, workout_id
+            ))
+            .execute(&mut *tx)
+            .await?;
+        }
+        // The latest workout starting before the sample ends of those ending after it starts,
+        // which started at most the longest workout before it
+        sqlx::query(&format!(
+            r#"UPDATE {0} SET {1} = (
+                SELECT w.workout_id FROM link_workouts w
+                WHERE w.start_jd <= julianday(COALESCE({0}.endDate, {0}.startDate))
+                    AND w.start_jd >= julianday({0}.startDate) - ?
+                    AND w.end_jd >= julianday({0}.startDate)
+                ORDER BY w.start_jd DESC LIMIT 1
+            )"#,
+            table, workout_id
+        ))
+        .bind(longest_workout)
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query(&format!(
+            "CREATE INDEX IF NOT EXISTS {} ON {} ({})",
+            get_valid_sqlite_identifier(&format!("{}_{}", table_name, WORKOUT_ID_COLUMN)),
+            table,
+            workout_id
+        ))
+        .execute(&mut *tx)
+        .await?;
+    }
+    sqlx::query("DROP TABLE temp.link_workouts")
+        .execute(&mut *tx)
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::SqlitePool;
+
+    /// Creates the workouts, and the samples of the table with their startDate and endDate,
+    /// returning the workout_id each sample is linked to
+    async fn link(
+        options: &ImportOptions,
+        table_name: &str,
+        workouts: &[(&str, &str)],
+        samples: &[(&str, &str)],
+    ) -> Vec<Option<i64>> {
+        let pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        let mut tx = pool.begin().await.unwrap();
+        sqlx::query("CREATE TABLE Workout (startDate DATE, endDate DATE)")
+            .execute(&mut *tx)
+            .await
+            .unwrap();
+        for (start, end) in workouts {
+            sqlx::query("INSERT INTO Workout VALUES (?, ?)")
+                .bind(start)
+                .bind(end)
+                .execute(&mut *tx)
+                .await
+                .unwrap();
+        }
+        let table = get_valid_sqlite_identifier(table_name);
+        sqlx::query(&format!(
+            "CREATE TABLE {} (type TEXT, sourceName TEXT, startDate DATE, endDate DATE, value REAL)",
+            table
+        ))
+        .execute(&mut *tx)
+        .await
+        .unwrap();
+        for (start, end) in samples {
+            sqlx::query(&format!(
+                "INSERT INTO {} VALUES ('HKQuantityTypeIdentifierHeartRate', 'Watch', ?, ?, 60)",
+                table
+            ))
+            .bind(start)
+            .bind(end)
+            .execute(&mut *tx)
+            .await
+            .unwrap();
+        }
+        link_samples_to_workouts(&mut tx, options).await.unwrap();
+        sqlx::query_scalar(&format!("SELECT workout_id FROM {} ORDER BY rowid", table))
+            .fetch_all(&mut *tx)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn samples_are_linked_to_the_workout_they_overlap() {
+        let workouts = [
+            ("2024-01-01T09:00:00-08:00", "2024-01-01T09:30:00-08:00"),
+            ("2024-01-01T10:00:00-08:00", "2024-01-01T10:30:00-08:00"),
+        ];
+        let samples = [
+            ("2024-01-01T09:10:00-08:00", "2024-01-01T09:10:00-08:00"),
+            // Overlapping the end and the start of the workouts
+            ("2024-01-01T08:59:00-08:00", "2024-01-01T09:01:00-08:00"),
+            ("2024-01-01T10:29:00-08:00", "2024-01-01T10:31:00-08:00"),
+            // Outside every workout
+            ("2024-01-01T09:45:00-08:00", "2024-01-01T09:45:00-08:00"),
+            ("2024-01-01T11:00:00-08:00", "2024-01-01T11:00:00-08:00"),
+            ("2024-01-01T08:00:00-08:00", "2024-01-01T08:00:00-08:00"),
+        ];
+        assert_eq!(
+            link(
+                &ImportOptions::defaults(),
+                "HKQuantityTypeIdentifierHeartRate",
+                &workouts,
+                &samples
+            )
+            .await,
+            [Some(1), Some(1), Some(2), None, None, None]
+        );
+    }
+
+    #[tokio::test]
+    async fn samples_overlapping_several_workouts_are_linked_to_the_latest() {
+        // A long walk with a short run during it, and a ride overlapping the end of the walk
+        let workouts = [
+            ("2024-01-01T09:00:00-08:00", "2024-01-01T12:00:00-08:00"),
+            ("2024-01-01T09:30:00-08:00", "2024-01-01T09:45:00-08:00"),
+            ("2024-01-01T11:50:00-08:00", "2024-01-01T13:00:00-08:00"),
+        ];
+        let samples = [
+            ("2024-01-01T09:40:00-08:00", "2024-01-01T09:40:00-08:00"),
+            // After the run, still during the walk that started before it
+            ("2024-01-01T10:00:00-08:00", "2024-01-01T10:00:00-08:00"),
+            ("2024-01-01T11:55:00-08:00", "2024-01-01T11:55:00-08:00"),
+            ("2024-01-01T12:30:00-08:00", "2024-01-01T12:30:00-08:00"),
+        ];
+        assert_eq!(
+            link(
+                &ImportOptions::defaults(),
+                "HKQuantityTypeIdentifierHeartRate",
+                &workouts,
+                &samples
+            )
+            .await,
+            [Some(2), Some(1), Some(3), Some(3)]
+        );
+    }
+
+    #[tokio::test]
+    async fn the_samples_table_of_single_table_is_linked() {
+        let options = ImportOptions {
+            single_table: true,
+            ..ImportOptions::defaults()
+        };
+        let workouts = [("2024-01-01T09:00:00-08:00", "2024-01-01T09:30:00-08:00")];
+        let samples = [
+            ("2024-01-01T09:10:00-08:00", "2024-01-01T09:10:00-08:00"),
+            ("2024-01-01T10:10:00-08:00", "2024-01-01T10:10:00-08:00"),
+        ];
+        assert_eq!(
+            link(&options, "samples", &workouts, &samples).await,
+            [Some(1), None]
+        );
+    }
+}
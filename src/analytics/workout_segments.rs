@@ -0,0 +1,334 @@
+//! Interval workout segments reconstructed from segment and lap events and workout activities,
+//! with the heart rate, distance, and energy of each segment
+use super::resolve_table;
+use crate::identifier::get_valid_sqlite_identifier;
+use crate::options::ImportOptions;
+use sqlx::{Sqlite, Transaction};
+
+const WORKOUT_EVENT_TABLE_NAME: &str = "WorkoutEvent";
+const WORKOUT_ACTIVITY_TABLE_NAME: &str = "WorkoutActivity";
+
+/// A statistic of each segment, aggregated over the samples that started within it
+struct SegmentStatistic {
+    column_name: &'static str,
+    table_names: &'static [&'static str],
+    aggregate: &'static str,
+}
+
+const SEGMENT_STATISTICS: &[SegmentStatistic] = &[
+    SegmentStatistic {
+        column_name: "heart_rate_avg",
+        table_names: &["HKQuantityTypeIdentifierHeartRate"],
+        aggregate: "AVG(value)",
+    },
+    SegmentStatistic {
+        column_name: "heart_rate_max",
+        table_names: &["HKQuantityTypeIdentifierHeartRate"],
+        aggregate: "MAX(value)",
+    },
+    SegmentStatistic {
+        column_name: "distance",
+        table_names: &[
+            "HKQuantityTypeIdentifierDistanceWalkingRunning",
+            "HKQuantityTypeIdentifierDistanceCycling",
+            "HKQuantityTypeIdentifierDistanceSwimming",
+        ],
+        aggregate: "SUM(value)",
+    },
+    SegmentStatistic {
+        column_name: "active_energy",
+        table_names: &["HKQuantityTypeIdentifierActiveEnergyBurned"],
+        aggregate: "SUM(value)",
+    },
+];
+
+/// Creates and populates the workout_segments table.
+///
+/// Segment and lap WorkoutEvents and the WorkoutActivity elements of multisport workouts are
+/// numbered in order within each workout. Dates are RFC 3339 in UTC.
+///
+/// Apple writes some segments cumulatively, each starting with the workout and ending at a later
+/// split, which would make the rows overlap. Each row instead starts where the previous one of
+/// its kind ended, if that is later than its own start, so the rows never overlap.
+pub async fn create_workout_segments_table(
+    tx: &mut Transaction<'_, Sqlite>,
+    options: &ImportOptions,
+) -> anyhow::Result<()> {
+    let mut selects = Vec::new();
+    let event_table = match resolve_table(tx, options, WORKOUT_EVENT_TABLE_NAME).await? {
+        Some(table_name) if has_columns(tx, &table_name, &["duration", "durationUnit"]).await? => {
+            Some(table_name)
+        }
+        _ => None,
+    };
+    if let Some(event_table) = event_table {
+        selects.push(format!(
+            r#"SELECT workout_id,
+                CASE type WHEN 'HKWorkoutEventTypeSegment' THEN 'segment' ELSE 'lap' END AS kind,
+                julianday(date) AS start_jd,
+                julianday(date) + CASE durationUnit WHEN 's' THEN duration / 86400.0 WHEN 'hr' THEN duration / 24.0 ELSE duration / 1440.0 END AS end_jd
+            FROM {} WHERE type IN ('HKWorkoutEventTypeSegment', 'HKWorkoutEventTypeLap') AND duration > 0"#,
+            get_valid_sqlite_identifier(&event_table)
+        ));
+    }
+    if let Some(activity_table) = resolve_table(tx, options, WORKOUT_ACTIVITY_TABLE_NAME).await? {
+        selects.push(format!(
+            "SELECT workout_id, 'activity' AS kind, julianday(startDate) AS start_jd, julianday(endDate) AS end_jd FROM {}",
+            get_valid_sqlite_identifier(&activity_table)
+        ));
+    }
+    if selects.is_empty() {
+        return Ok(());
+    }
+    let mut column_definitions = vec![
+        "`workout_id` INTEGER".to_string(),
+        "`kind` TEXT".to_string(),
+        "`segment` INTEGER".to_string(),
+        "`startDate` DATE".to_string(),
+        "`endDate` DATE".to_string(),
+        "`duration_seconds` REAL".to_string(),
+        "`intensity` TEXT".to_string(),
+    ];
+    for statistic in SEGMENT_STATISTICS {
+        column_definitions.push(format!(
+            "{} REAL",
+            get_valid_sqlite_identifier(statistic.column_name)
+        ));
+    }
+    column_definitions.push("`speed` REAL".to_string());
+    sqlx::query(&format!(
+        "CREATE TABLE IF NOT EXISTS workout_segments ({})",
+        column_definitions.join(", ")
+    ))
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query("DELETE FROM workout_segments")
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query(&format!(
+        r#"INSERT INTO workout_segments (workout_id, kind, segment, startDate, endDate, duration_seconds)
+        SELECT workout_id, kind, ROW_NUMBER() OVER (PARTITION BY workout_id, kind ORDER BY start_jd),
+            strftime('%Y-%m-%dT%H:%M:%SZ', start_jd), strftime('%Y-%m-%dT%H:%M:%SZ', end_jd),
+            (end_jd - start_jd) * 86400
+        FROM (
+            SELECT workout_id, kind, end_jd, MAX(start_jd, COALESCE(LAG(end_jd) OVER (
+                PARTITION BY workout_id, kind ORDER BY end_jd, start_jd
+            ), start_jd)) AS start_jd
+            FROM ({})
+        )
+        WHERE end_jd > start_jd ORDER BY workout_id, kind, start_jd"#,
+        selects.join(" UNION ALL ")
+    ))
+    .execute(&mut *tx)
+    .await?;
+
+    for statistic in SEGMENT_STATISTICS {
+        let mut samples = Vec::new();
+        for table_name in statistic.table_names {
+            if let Some(table_name) = resolve_table(tx, options, table_name).await? {
+                samples.push(format!(
+                    "SELECT julianday(startDate), value FROM {}",
+                    get_valid_sqlite_identifier(&table_name)
+                ));
+            }
+        }
+        if samples.is_empty() {
+            continue;
+        }
+        // Indexing the sample times keeps each segment's lookup a range scan
+        sqlx::query("CREATE TEMP TABLE segment_samples (start_jd REAL, value REAL)")
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query(&format!(
+            "INSERT INTO segment_samples {}",
+            samples.join(" UNION ALL ")
+        ))
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query("CREATE INDEX temp.segment_samples_start ON segment_samples (start_jd)")
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query(&format!(
+            r#"UPDATE workout_segments SET {} = (
+                SELECT {} FROM segment_samples
+                WHERE start_jd >= julianday(workout_segments.startDate) AND start_jd < julianday(workout_segments.endDate)
+            )"#,
+            get_valid_sqlite_identifier(statistic.column_name),
+            statistic.aggregate
+        ))
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query("DROP TABLE temp.segment_samples")
+            .execute(&mut *tx)
+            .await?;
+    }
+
+    // Distance per hour, in the distance unit of the export
+    sqlx::query(
+        "UPDATE workout_segments SET speed = distance / (duration_seconds / 3600) WHERE duration_seconds > 0",
+    )
+    .execute(&mut *tx)
+    .await?;
+    // Segments harder than the average segment of the same kind in their workout are work
+    // intervals, the rest are recovery. Heart rate is preferred, falling back to speed.
+    for column_name in ["speed", "heart_rate_avg"] {
+        sqlx::query(&format!(
+            r#"UPDATE workout_segments SET intensity = CASE
+                WHEN {0} >= (
+                    SELECT AVG(s.{0}) FROM workout_segments s
+                    WHERE s.workout_id = workout_segments.workout_id AND s.kind = workout_segments.kind
+                ) THEN 'work' ELSE 'rest' END
+            WHERE {0} IS NOT NULL AND (
+                SELECT COUNT(*) FROM workout_segments s
+                WHERE s.workout_id = workout_segments.workout_id AND s.kind = workout_segments.kind
+            ) > 1"#,
+            get_valid_sqlite_identifier(column_name)
+        ))
+        .execute(&mut *tx)
+        .await?;
+    }
+    Ok(())
+}
+
+/// Returns true if the table has all of the columns
+async fn has_columns(
+    tx: &mut Transaction<'_, Sqlite>,
+    table_name: &str,
+    column_names: &[&str],
+) -> anyhow::Result<bool> {
+    let columns: Vec<String> = sqlx::query_scalar("SELECT name FROM pragma_table_info(?)")
+        .bind(table_name)
+        .fetch_all(&mut *tx)
+        .await?;
+    Ok(column_names
+        .iter()
+        .all(|name| columns.iter().any(|column| column == name)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::SqlitePool;
+
+    #[tokio::test]
+    async fn segments_are_numbered_and_summarized() {
+        let pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        let mut tx = pool.begin().await.unwrap();
+        sqlx::query(
+            r#"CREATE TABLE WorkoutEvent (workout_id INTEGER, type TEXT, date DATE, duration REAL, durationUnit TEXT);
+            INSERT INTO WorkoutEvent VALUES
+                (1, 'HKWorkoutEventTypeSegment', '2024-01-01 10:05:00', 120, 's'),
+                (1, 'HKWorkoutEventTypeSegment', '2024-01-01 10:00:00', 5, 'min'),
+                (1, 'HKWorkoutEventTypePause', '2024-01-01 10:02:00', 1, 'min'),
+                (1, 'HKWorkoutEventTypeLap', '2024-01-01 10:00:00', 0, 'min'),
+                (3, 'HKWorkoutEventTypeSegment', '2024-01-03 08:00:00', 5, 'min'),
+                (3, 'HKWorkoutEventTypeSegment', '2024-01-03 08:00:00', 11, 'min'),
+                (3, 'HKWorkoutEventTypeSegment', '2024-01-03 08:00:00', 11, 'min');
+            CREATE TABLE WorkoutActivity (workout_id INTEGER, startDate DATE, endDate DATE);
+            INSERT INTO WorkoutActivity VALUES (2, '2024-01-02 09:00:00', '2024-01-02 09:30:00');
+            CREATE TABLE HKQuantityTypeIdentifierHeartRate (startDate DATE, value REAL);
+            INSERT INTO HKQuantityTypeIdentifierHeartRate VALUES
+                ('2024-01-01 10:01:00', 150), ('2024-01-01 10:03:00', 160), ('2024-01-01 10:06:00', 120);
+            CREATE TABLE HKQuantityTypeIdentifierDistanceWalkingRunning (startDate DATE, value REAL);
+            INSERT INTO HKQuantityTypeIdentifierDistanceWalkingRunning VALUES
+                ('2024-01-01 10:01:00', 1.0), ('2024-01-01 10:06:00', 0.2);"#,
+        )
+        .execute(&mut *tx)
+        .await
+        .unwrap();
+        create_workout_segments_table(&mut tx, &ImportOptions::defaults())
+            .await
+            .unwrap();
+        // Rounded, as the dates are compared and subtracted as Julian days
+        type Segment = (
+            i64,
+            String,
+            i64,
+            String,
+            f64,
+            Option<f64>,
+            Option<f64>,
+            Option<f64>,
+            Option<String>,
+        );
+        let segments: Vec<Segment> = sqlx::query_as(
+            r#"SELECT workout_id, kind, segment, startDate, ROUND(duration_seconds, 3), heart_rate_avg,
+                heart_rate_max, ROUND(speed, 3), intensity
+            FROM workout_segments ORDER BY workout_id, kind, segment"#,
+        )
+        .fetch_all(&mut *tx)
+        .await
+        .unwrap();
+        assert_eq!(
+            segments,
+            [
+                // Only segments and laps with a duration, numbered by their start
+                (
+                    1,
+                    "segment".to_string(),
+                    1,
+                    "2024-01-01T10:00:00Z".to_string(),
+                    300.0,
+                    Some(155.0),
+                    Some(160.0),
+                    Some(12.0),
+                    Some("work".to_string())
+                ),
+                (
+                    1,
+                    "segment".to_string(),
+                    2,
+                    "2024-01-01T10:05:00Z".to_string(),
+                    120.0,
+                    Some(120.0),
+                    Some(120.0),
+                    Some(6.0),
+                    Some("rest".to_string())
+                ),
+                // A segment alone of its kind has no intensity
+                (
+                    2,
+                    "activity".to_string(),
+                    1,
+                    "2024-01-02T09:00:00Z".to_string(),
+                    1800.0,
+                    None,
+                    None,
+                    None,
+                    None
+                ),
+                // Cumulative segments start where the previous one ended, and a repeated one is
+                // left out as it would be empty
+                (
+                    3,
+                    "segment".to_string(),
+                    1,
+                    "2024-01-03T08:00:00Z".to_string(),
+                    300.0,
+                    None,
+                    None,
+                    None,
+                    None
+                ),
+                (
+                    3,
+                    "segment".to_string(),
+                    2,
+                    "2024-01-03T08:05:00Z".to_string(),
+                    360.0,
+                    None,
+                    None,
+                    None,
+                    None
+                ),
+            ]
+        );
+        let end_dates: Vec<String> = sqlx::query_scalar(
+            "SELECT endDate FROM workout_segments WHERE workout_id = 3 ORDER BY segment",
+        )
+        .fetch_all(&mut *tx)
+        .await
+        .unwrap();
+        assert_eq!(end_dates, ["2024-01-03T08:05:00Z", "2024-01-03T08:11:00Z"]);
+    }
+}
@@ -0,0 +1,284 @@
+//! Removes or pseudonymizes the fields that identify the person or devices behind an export,
+//! so the output can be shared for research or bug reports
+use crate::clinical::CLINICAL_TABLE_PREFIX;
+use crate::core::{DatabaseRow, DatabaseValue, HKTables, METADATA_COLUMN_PREFIX};
+use sha2::{Digest, Sha256};
+
+/// Columns naming the app or device that wrote a sample, replaced by a pseudonym
+const PSEUDONYMIZED_COLUMNS: &[&str] = &["sourceName"];
+
+/// Columns removed from every row
+const REMOVED_COLUMNS: &[&str] = &["sourceVersion", "HKCharacteristicTypeIdentifierDateOfBirth"];
+
+/// Metadata keys removed from every row, as they identify the user, their devices, or where
+/// they were
+const REMOVED_METADATA_KEYS: &[&str] = &[
+    "HKExternalUUID",
+    "HKMetadataKeySyncIdentifier",
+    "HKMetadataKeySyncVersion",
+    "HKMetadataKeyUserMotionContext",
+    "HKTimeZone",
+    "HKDeviceName",
+    "HKDeviceSerialNumber",
+];
+
+/// Fields of the device description kept, e.g. `model:Watch, hardware:Watch6,1, software:9.1`.
+/// The name, local identifier, and UDI device identifier are dropped.
+const KEPT_DEVICE_FIELDS: &[&str] = &["manufacturer", "model", "hardware", "software"];
+
+/// Anonymizes the rows of a single import.
+///
+/// Pseudonyms are salted hashes with a salt chosen for each import, so the same source has the
+/// same pseudonym throughout an import but can't be matched against a list of likely names. As
+/// the pseudonyms of the same source differ between imports, --anonymize can't be combined with
+/// --append or --resume.
+pub struct Anonymizer {
+    salt: [u8; 32],
+}
+
+impl Anonymizer {
+    pub fn with_random_salt() -> Self {
+        Self {
+            salt: rand::random(),
+        }
+    }
+
+    /// Returns the anonymized row, or None if rows of the table can't be anonymized and are
+    /// skipped instead, as with the free-form FHIR clinical records
+    pub fn anonymize_row(&self, table_name: &str, row: DatabaseRow) -> Option<DatabaseRow> {
+        if table_name.starts_with(CLINICAL_TABLE_PREFIX) {
+            return None;
+        }
+        Some(
+            row.into_iter()
+                .filter(|(name, _)| !self.is_removed(name))
+                .map(|(name, value)| match value {
+                    DatabaseValue::Text(text) if PSEUDONYMIZED_COLUMNS.contains(&name.as_str()) => {
+                        let pseudonym = format!("source-{}", self.hash(&text));
+                        (name, DatabaseValue::Text(pseudonym))
+                    }
+                    DatabaseValue::Text(text) if name == "device" => {
+                        (name, DatabaseValue::Text(anonymize_device(&text)))
+                    }
+                    value => (name, value),
+                })
+                .collect(),
+        )
+    }
+
+    /// Removes the columns removed from every row from the tables, so the output has no empty
+    /// columns for them
+    pub fn remove_columns(&self, tables: &mut HKTables) {
+        for columns in tables.values_mut() {
+            columns.retain(|name, _| !self.is_removed(name));
+        }
+    }
+
+    fn is_removed(&self, column_name: &str) -> bool {
+        if REMOVED_COLUMNS.contains(&column_name) {
+            return true;
+        }
+        column_name
+            .strip_prefix(METADATA_COLUMN_PREFIX)
+            .is_some_and(|key| REMOVED_METADATA_KEYS.contains(&key))
+    }
+
+    /// Returns the first 8 bytes of the salted SHA-256 of the text as hex
+    fn hash(&self, text: &str) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(self.salt);
+        hasher.update(text.as_bytes());
+        hasher.finalize()[..8]
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+}
+
+/// Keeps only the kind of device from a device description such as
+/// `<<HKDevice: 0x283a4c4b0>, name:Apple Watch, manufacturer:Apple Inc., model:Watch, hardware:Watch6,1, software:9.1>`
+fn anonymize_device(device: &str) -> String {
+    // Other device columns, such as that of the electrocardiograms, only hold the hardware model
+    if !device.starts_with("<<HKDevice") {
+        return device.to_string();
+    }
+    let fields = device
+        .trim_start_matches('<')
+        .trim_end_matches('>')
+        .split(", ")
+        .filter(|field| {
+            field
+                .split_once(':')
+                .is_some_and(|(key, _)| KEPT_DEVICE_FIELDS.contains(&key))
+        })
+        .collect::<Vec<_>>();
+    fields.join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conformance::EXPORT_FILES;
+    use crate::identifier::get_valid_sqlite_identifier;
+    use crate::options::ImportOptions;
+    use crate::testing::{self, TestDir};
+    use sqlx::SqlitePool;
+    use std::collections::BTreeSet;
+
+    #[test]
+    fn anonymize_device_keeps_the_kind_of_device() {
+        assert_eq!(
+            anonymize_device("<<HKDevice: 0x283a4c4b0>, name:Apple Watch, manufacturer:Apple Inc., model:Watch, hardware:Watch6,1, software:9.1>"),
+            "manufacturer:Apple Inc., model:Watch, hardware:Watch6,1, software:9.1"
+        );
+    }
+
+    #[test]
+    fn anonymize_device_keeps_other_descriptions() {
+        assert_eq!(anonymize_device("Watch6,1"), "Watch6,1");
+        assert_eq!(anonymize_device(""), "");
+        assert_eq!(
+            anonymize_device("name:Apple Watch, model:Watch"),
+            "name:Apple Watch, model:Watch"
+        );
+    }
+
+    #[test]
+    fn pseudonyms_are_consistent_within_an_import() {
+        let anonymizer = Anonymizer::with_random_salt();
+        let row = |source: &str| {
+            vec![
+                (
+                    "sourceName".to_string(),
+                    DatabaseValue::Text(source.to_string()),
+                ),
+                (
+                    "sourceVersion".to_string(),
+                    DatabaseValue::Text("9.1".to_string()),
+                ),
+                (
+                    "metadata_HKExternalUUID".to_string(),
+                    DatabaseValue::Text("F1E2".to_string()),
+                ),
+                ("value".to_string(), DatabaseValue::Real(72.0)),
+            ]
+        };
+        let first = anonymizer
+            .anonymize_row("HeartRate", row("Apple Watch"))
+            .unwrap();
+        let second = anonymizer
+            .anonymize_row("HeartRate", row("Apple Watch"))
+            .unwrap();
+        let other = anonymizer
+            .anonymize_row("HeartRate", row("Withings"))
+            .unwrap();
+        let names =
+            |row: &DatabaseRow| row.iter().map(|(name, _)| name.clone()).collect::<Vec<_>>();
+        assert_eq!(names(&first), ["sourceName", "value"]);
+        assert_eq!(first[0].1.to_text(), second[0].1.to_text());
+        assert_ne!(first[0].1.to_text(), other[0].1.to_text());
+        assert!(first[0].1.to_text().starts_with("source-"));
+    }
+
+    #[test]
+    fn clinical_records_are_skipped() {
+        let anonymizer = Anonymizer::with_random_salt();
+        let table_name = format!("{}Observation", CLINICAL_TABLE_PREFIX);
+        assert!(anonymizer.anonymize_row(&table_name, Vec::new()).is_none());
+    }
+
+    #[tokio::test]
+    async fn imports_are_anonymized_consistently() {
+        let dir = TestDir::new("anonymize");
+        let export_dir = dir.write_export("export", EXPORT_FILES);
+        let db_url = dir.db_url("health.db");
+        let geojson_dir = dir.path().join("geojson");
+        let options = ImportOptions {
+            anonymize: true,
+            geojson_dir: Some(geojson_dir.clone()),
+            ..ImportOptions::defaults()
+        };
+        testing::import(&export_dir, &db_url, &options)
+            .await
+            .unwrap();
+        let db = SqlitePool::connect(&db_url).await.unwrap();
+
+        // The tables inferred by the schema pass have no columns for the removed fields
+        let columns: Vec<(String, String)> = sqlx::query_as(
+            r#"SELECT m.name, p.name FROM sqlite_master m, pragma_table_info(m.name) p
+            WHERE m.type = 'table'"#,
+        )
+        .fetch_all(&db)
+        .await
+        .unwrap();
+        let anonymizer = Anonymizer::with_random_salt();
+        for (table_name, column_name) in &columns {
+            assert!(
+                !anonymizer.is_removed(column_name),
+                "{}.{}",
+                table_name,
+                column_name
+            );
+        }
+        assert!(columns
+            .iter()
+            .any(|(_, name)| name == "metadata_HKIndoorWorkout"));
+
+        // The rows written by the insert pass have the same pseudonym for each source across
+        // the tables, which isn't the name of the source
+        let mut sources = BTreeSet::new();
+        for (table_name, column_name) in columns.iter().filter(|(_, name)| name == "sourceName") {
+            let names: Vec<Option<String>> = sqlx::query_scalar(&format!(
+                "SELECT DISTINCT {} FROM {}",
+                get_valid_sqlite_identifier(column_name),
+                get_valid_sqlite_identifier(table_name)
+            ))
+            .fetch_all(&db)
+            .await
+            .unwrap();
+            sources.extend(names.into_iter().flatten());
+        }
+        assert_eq!(sources.len(), 3, "{:?}", sources);
+        assert!(sources.iter().all(|source| source.starts_with("source-")));
+        let watch: Vec<String> = sqlx::query_scalar(
+            r#"SELECT sourceName FROM HKQuantityTypeIdentifierHeartRate
+            UNION SELECT sourceName FROM Workout
+            UNION SELECT sourceName FROM HKCategoryTypeIdentifierSleepAnalysis"#,
+        )
+        .fetch_all(&db)
+        .await
+        .unwrap();
+        assert_eq!(watch.len(), 1);
+
+        // The route files are anonymized with the pseudonyms of the database
+        let geojson_path = std::fs::read_dir(&geojson_dir)
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap()
+            .path();
+        let geojson = std::fs::read_to_string(geojson_path).unwrap();
+        assert!(geojson.contains(&watch[0]));
+        assert!(!geojson.contains("\"Watch\""));
+        assert!(!geojson.contains("HKTimeZone"));
+        assert!(!geojson.contains("sourceVersion"));
+
+        // Clinical records are free-form, so they're skipped
+        let clinical_tables: BTreeSet<&String> = columns
+            .iter()
+            .map(|(table_name, _)| table_name)
+            .filter(|table_name| table_name.starts_with(CLINICAL_TABLE_PREFIX))
+            .collect();
+        assert!(!clinical_tables.is_empty());
+        for table_name in clinical_tables {
+            let rows: i64 = sqlx::query_scalar(&format!(
+                "SELECT COUNT(*) FROM {}",
+                get_valid_sqlite_identifier(table_name)
+            ))
+            .fetch_one(&db)
+            .await
+            .unwrap();
+            assert_eq!(rows, 0, "{}", table_name);
+        }
+    }
+}
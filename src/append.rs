@@ -0,0 +1,207 @@
+//! Support for appending a newer HealthKit export to an existing database
+use crate::core::{DatabaseRow, DatabaseValue};
+use crate::identifier::get_valid_sqlite_identifier;
+use crate::sink::bind_database_value;
+use sqlx::{Row, Sqlite, Transaction};
+use std::collections::HashMap;
+
+/// Columns that together identify a row when it has no HKExternalUUID metadata
+const IDENTITY_COLUMNS: &[&str] = &[
+    "type",
+    "workoutActivityType",
+    "startDate",
+    "sourceName",
+    "dateComponents",
+    "workout_id",
+];
+
+/// Metadata column holding the identifier third-party apps attach to their samples
+const EXTERNAL_UUID_COLUMN: &str = "metadata_HKExternalUUID";
+
+/// Decides which rows of a new export are already present in the database.
+///
+/// Rows with an HKExternalUUID, which third-party apps may sync long after the samples were taken,
+/// are skipped if a row with the same HKExternalUUID already exists. Other rows with a startDate
+/// older than the newest startDate already in their table are assumed to be imported, and those
+/// at or after that watermark are only skipped if a row with the same type, startDate, and
+/// sourceName already exists.
+#[derive(Default)]
+pub struct Deduplicator {
+    /// The newest startDate, as a unix timestamp, of each table before the import began
+    watermarks: HashMap<String, Option<i64>>,
+}
+
+impl Deduplicator {
+    /// Returns true if the row should not be inserted because it was imported by a previous run
+    pub async fn is_duplicate(
+        &mut self,
+        tx: &mut Transaction<'_, Sqlite>,
+        table_name: &str,
+        row: &DatabaseRow,
+    ) -> anyhow::Result<bool> {
+        let external_uuid = row.iter().find(|(name, _)| name == EXTERNAL_UUID_COLUMN);
+        if let (None, Some(DatabaseValue::OffsetDateTime(start_date))) =
+            (external_uuid, row_value(row, "startDate"))
+        {
+            match self.watermark(tx, table_name).await? {
+                None => return Ok(false),
+                Some(watermark) if start_date.unix_timestamp() > watermark => return Ok(false),
+                Some(watermark) if start_date.unix_timestamp() < watermark => return Ok(true),
+                Some(_) => {}
+            }
+        }
+        let key: Vec<&(String, DatabaseValue)> = match external_uuid {
+            Some(column) => vec![column],
+            None => row
+                .iter()
+                .filter(|(name, _)| IDENTITY_COLUMNS.contains(&name.as_str()))
+                .collect(),
+        };
+        if key.is_empty() {
+            return Ok(false);
+        }
+        let qs = format!(
+            "SELECT COUNT(*) FROM {} WHERE {}",
+            get_valid_sqlite_identifier(table_name),
+            key.iter()
+                .map(|(name, _)| format!("{} = ?", get_valid_sqlite_identifier(name)))
+                .collect::<Vec<_>>()
+                .join(" AND ")
+        );
+        let mut query = sqlx::query(&qs);
+        for (_, value) in key {
+            query = bind_database_value(query, value.clone());
+        }
+        let count: i64 = query.fetch_one(&mut *tx).await?.get(0);
+        Ok(count > 0)
+    }
+
+    async fn watermark(
+        &mut self,
+        tx: &mut Transaction<'_, Sqlite>,
+        table_name: &str,
+    ) -> anyhow::Result<Option<i64>> {
+        if let Some(watermark) = self.watermarks.get(table_name) {
+            return Ok(*watermark);
+        }
+        let watermark: Option<i64> = sqlx::query_scalar(&format!(
+            "SELECT MAX(CAST(strftime('%s', startDate) AS INTEGER)) FROM {}",
+            get_valid_sqlite_identifier(table_name)
+        ))
+        .fetch_one(&mut *tx)
+        .await?;
+        self.watermarks.insert(table_name.to_string(), watermark);
+        Ok(watermark)
+    }
+}
+
+fn row_value<'a>(row: &'a DatabaseRow, column_name: &str) -> Option<&'a DatabaseValue> {
+    row.iter()
+        .find(|(name, _)| name == column_name)
+        .map(|(_, value)| value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::SqlitePool;
+    use time::macros::datetime;
+    use time::OffsetDateTime;
+
+    const TABLE_NAME: &str = "HKQuantityTypeIdentifierHeartRate";
+
+    fn sample(start_date: OffsetDateTime, external_uuid: Option<&str>) -> DatabaseRow {
+        let mut row = vec![
+            (
+                "type".to_string(),
+                DatabaseValue::Text(TABLE_NAME.to_string()),
+            ),
+            (
+                "startDate".to_string(),
+                DatabaseValue::OffsetDateTime(start_date),
+            ),
+            (
+                "sourceName".to_string(),
+                DatabaseValue::Text("Watch".to_string()),
+            ),
+        ];
+        if let Some(external_uuid) = external_uuid {
+            row.push((
+                EXTERNAL_UUID_COLUMN.to_string(),
+                DatabaseValue::Text(external_uuid.to_string()),
+            ));
+        }
+        row
+    }
+
+    /// Returns a transaction on a database with an imported sample at the watermark
+    async fn imported() -> Transaction<'static, Sqlite> {
+        let pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        let mut tx = pool.begin().await.unwrap();
+        sqlx::query(&format!(
+            "CREATE TABLE {} (type TEXT, startDate DATE, sourceName TEXT, {} TEXT)",
+            TABLE_NAME, EXTERNAL_UUID_COLUMN
+        ))
+        .execute(&mut *tx)
+        .await
+        .unwrap();
+        sqlx::query(&format!("INSERT INTO {} VALUES (?, ?, ?, ?)", TABLE_NAME))
+            .bind(TABLE_NAME)
+            .bind(datetime!(2024-01-02 08:00 UTC))
+            .bind("Watch")
+            .bind("A1")
+            .execute(&mut *tx)
+            .await
+            .unwrap();
+        tx
+    }
+
+    #[tokio::test]
+    async fn rows_before_the_watermark_are_duplicates() {
+        let mut tx = imported().await;
+        let mut dedup = Deduplicator::default();
+        let row = sample(datetime!(2024-01-01 08:00 UTC), None);
+        assert!(dedup.is_duplicate(&mut tx, TABLE_NAME, &row).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn rows_at_the_watermark_are_compared() {
+        let mut tx = imported().await;
+        let mut dedup = Deduplicator::default();
+        let row = sample(datetime!(2024-01-02 08:00 UTC), None);
+        assert!(dedup.is_duplicate(&mut tx, TABLE_NAME, &row).await.unwrap());
+        let later = sample(datetime!(2024-01-03 08:00 UTC), None);
+        assert!(!dedup
+            .is_duplicate(&mut tx, TABLE_NAME, &later)
+            .await
+            .unwrap());
+    }
+
+    #[tokio::test]
+    async fn rows_with_an_external_uuid_ignore_the_watermark() {
+        let mut tx = imported().await;
+        let mut dedup = Deduplicator::default();
+        let backfilled = sample(datetime!(2023-12-01 08:00 UTC), Some("B2"));
+        assert!(!dedup
+            .is_duplicate(&mut tx, TABLE_NAME, &backfilled)
+            .await
+            .unwrap());
+        let imported = sample(datetime!(2023-12-01 08:00 UTC), Some("A1"));
+        assert!(dedup
+            .is_duplicate(&mut tx, TABLE_NAME, &imported)
+            .await
+            .unwrap());
+    }
+
+    #[tokio::test]
+    async fn rows_of_empty_tables_are_new() {
+        let mut tx = imported().await;
+        sqlx::query(&format!("DELETE FROM {}", TABLE_NAME))
+            .execute(&mut *tx)
+            .await
+            .unwrap();
+        let mut dedup = Deduplicator::default();
+        let row = sample(datetime!(2024-01-01 08:00 UTC), None);
+        assert!(!dedup.is_duplicate(&mut tx, TABLE_NAME, &row).await.unwrap());
+    }
+}
@@ -0,0 +1,181 @@
+//! The `_blobs` table holding the files of the export as they were exported, with --blobs
+use crate::core::{DatabaseRow, DatabaseValue, HKTables};
+use crate::ecg::ECG_TABLE_NAME;
+use crate::meta::EXPORT_METADATA_TABLE_NAME;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Name of the table holding one row per distinct file
+pub const BLOB_TABLE_NAME: &str = "_blobs";
+
+/// Column of the Ecg and clinical record tables holding the hash of the file the row was read from
+pub const BLOB_COLUMN: &str = "blob";
+
+/// Workout column holding the hash of the route GPX file
+pub const ROUTE_BLOB_COLUMN: &str = "routeBlob";
+
+/// export_metadata column holding the hash of export_cda.xml
+pub const CDA_BLOB_COLUMN: &str = "cdaBlob";
+
+pub const ECG_MIME_TYPE: &str = "text/csv";
+pub const GPX_MIME_TYPE: &str = "application/gpx+xml";
+pub const FHIR_MIME_TYPE: &str = "application/fhir+json";
+pub const CDA_MIME_TYPE: &str = "application/xml";
+
+/// The contents of a file, addressed by the hex SHA-256 of its bytes
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Blob {
+    pub hash: String,
+    pub mime: String,
+    pub bytes: Vec<u8>,
+}
+
+impl Blob {
+    pub fn new(mime: &str, bytes: Vec<u8>) -> Self {
+        Self {
+            hash: format!("{:x}", Sha256::digest(&bytes)),
+            mime: mime.to_string(),
+            bytes,
+        }
+    }
+
+    /// The column referring to the blob from the row of the file
+    pub fn reference(&self, column_name: &str) -> (String, DatabaseValue) {
+        (
+            column_name.to_string(),
+            DatabaseValue::Text(self.hash.clone()),
+        )
+    }
+
+    /// Converts the blob to a row of the `_blobs` table
+    pub fn into_row(self) -> DatabaseRow {
+        vec![
+            ("hash".to_string(), DatabaseValue::Text(self.hash)),
+            ("mime".to_string(), DatabaseValue::Text(self.mime)),
+            ("bytes".to_string(), DatabaseValue::Blob(self.bytes)),
+        ]
+    }
+}
+
+/// Adds the `_blobs` table, and the columns referring to it from the tables whose files are
+/// stored. The clinical record tables are given their column as their records are flattened.
+pub fn extend_tables(tables: &mut HKTables, workout_table_name: &str) {
+    let columns = [("hash", "TEXT"), ("mime", "TEXT"), ("bytes", "BLOB")];
+    tables.insert(
+        BLOB_TABLE_NAME.to_string(),
+        columns
+            .iter()
+            .map(|(name, ty)| (name.to_string(), *ty))
+            .collect(),
+    );
+    let references = [
+        (ECG_TABLE_NAME, BLOB_COLUMN),
+        (workout_table_name, ROUTE_BLOB_COLUMN),
+        (EXPORT_METADATA_TABLE_NAME, CDA_BLOB_COLUMN),
+    ];
+    for (table_name, column_name) in references {
+        if let Some(columns) = tables.get_mut(table_name) {
+            columns.insert(column_name.to_string(), "TEXT");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conformance::EXPORT_FILES;
+    use crate::options::ImportOptions;
+    use crate::testing::{self, TestDir};
+    use sqlx::SqlitePool;
+
+    #[test]
+    fn blobs_are_addressed_by_the_sha256_of_their_bytes() {
+        let blob = Blob::new(ECG_MIME_TYPE, b"abc".to_vec());
+        assert_eq!(
+            blob.hash,
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(blob.reference(BLOB_COLUMN).1.to_text(), blob.hash);
+        let row = blob.into_row();
+        assert_eq!(
+            row.iter()
+                .map(|(name, _)| name.as_str())
+                .collect::<Vec<_>>(),
+            ["hash", "mime", "bytes"]
+        );
+        assert_eq!(row[1].1.to_text(), "text/csv");
+    }
+
+    #[test]
+    fn only_the_tables_of_the_export_get_a_reference_column() {
+        let mut tables = HKTables::from([
+            ("Workouts".to_string(), Default::default()),
+            (ECG_TABLE_NAME.to_string(), Default::default()),
+        ]);
+        extend_tables(&mut tables, "Workouts");
+        assert_eq!(tables[BLOB_TABLE_NAME]["bytes"], "BLOB");
+        assert_eq!(tables["Workouts"][ROUTE_BLOB_COLUMN], "TEXT");
+        assert_eq!(tables[ECG_TABLE_NAME][BLOB_COLUMN], "TEXT");
+        assert!(!tables.contains_key(EXPORT_METADATA_TABLE_NAME));
+    }
+
+    #[tokio::test]
+    async fn each_distinct_file_is_stored_once_and_referenced_by_its_rows() {
+        let dir = TestDir::new("blobs");
+        // A second workout with the same route file
+        let export_xml = std::str::from_utf8(EXPORT_FILES[0].1).unwrap().replace(
+            " <ActivitySummary dateComponents=\"2024-01-01\"",
+            r#" <Workout workoutActivityType="HKWorkoutActivityTypeRunning" duration="30.5" durationUnit="min" sourceName="Watch" creationDate="2024-01-02 09:31:00 -0800" startDate="2024-01-02 09:00:00 -0800" endDate="2024-01-02 09:30:30 -0800">
+  <WorkoutRoute sourceName="Watch" creationDate="2024-01-02 09:31:00 -0800" startDate="2024-01-02 09:00:00 -0800" endDate="2024-01-02 09:30:30 -0800">
+   <FileReference path="/workout-routes/route_2024-01-01_9.30am.gpx"/>
+  </WorkoutRoute>
+ </Workout>
+ <ActivitySummary dateComponents="2024-01-01""#,
+        );
+        let mut files = EXPORT_FILES.to_vec();
+        files[0] = ("export.xml", export_xml.as_bytes());
+        let export_dir = dir.write_export("export", &files);
+        let db_url = dir.db_url("health.db");
+        let options = ImportOptions {
+            blobs: true,
+            ..ImportOptions::defaults()
+        };
+        testing::import(&export_dir, &db_url, &options)
+            .await
+            .unwrap();
+        let db = SqlitePool::connect(&db_url).await.unwrap();
+        let mimes: Vec<String> = sqlx::query_scalar("SELECT mime FROM _blobs ORDER BY mime")
+            .fetch_all(&db)
+            .await
+            .unwrap();
+        assert_eq!(
+            mimes,
+            [FHIR_MIME_TYPE, GPX_MIME_TYPE, CDA_MIME_TYPE, ECG_MIME_TYPE]
+        );
+        let referencing = [
+            ("Workout", ROUTE_BLOB_COLUMN, 2),
+            (ECG_TABLE_NAME, BLOB_COLUMN, 1),
+            ("ClinicalObservation", BLOB_COLUMN, 1),
+            (EXPORT_METADATA_TABLE_NAME, CDA_BLOB_COLUMN, 1),
+        ];
+        for (table_name, column_name, rows) in referencing {
+            let joined: i64 = sqlx::query_scalar(&format!(
+                "SELECT COUNT(*) FROM {} JOIN _blobs ON hash = {}",
+                table_name, column_name
+            ))
+            .fetch_one(&db)
+            .await
+            .unwrap();
+            assert_eq!(joined, rows, "{}", table_name);
+        }
+        let (route_path, route) = EXPORT_FILES[2];
+        assert!(route_path.ends_with(".gpx"));
+        let bytes: Vec<u8> = sqlx::query_scalar("SELECT bytes FROM _blobs WHERE mime = ?")
+            .bind(GPX_MIME_TYPE)
+            .fetch_one(&db)
+            .await
+            .unwrap();
+        assert_eq!(bytes, route);
+        db.close().await;
+    }
+}
@@ -0,0 +1,195 @@
+//! A compact binary copy of the parsed export, reused by --cache to skip parsing the XML
+use crate::core::{HKTables, ParsedElement};
+use log::*;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/// Incremented whenever the parsed representation changes, invalidating older caches
+const CACHE_VERSION: u32 = 5;
+
+/// Returns the path of the cached parse of the export with the given content hash
+pub fn cache_path(dir: &Path, content_hash: &str) -> PathBuf {
+    dir.join(format!("{}-v{}.bin", content_hash, CACHE_VERSION))
+}
+
+/// Writes the parsed export to a temporary file, renamed into place once the parse completes
+/// so that an interrupted import never leaves a truncated cache behind
+pub struct CacheWriter {
+    path: PathBuf,
+    temp_path: PathBuf,
+    writer: BufWriter<File>,
+}
+
+impl CacheWriter {
+    pub fn create(path: &Path) -> anyhow::Result<Self> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let temp_path = path.with_extension("partial");
+        let writer = BufWriter::new(File::create(&temp_path)?);
+        Ok(Self {
+            path: path.to_path_buf(),
+            temp_path,
+            writer,
+        })
+    }
+
+    pub fn write_tables(&mut self, tables: &HKTables) -> anyhow::Result<()> {
+        bincode::serialize_into(&mut self.writer, &CACHE_VERSION)?;
+        bincode::serialize_into(&mut self.writer, tables)?;
+        Ok(())
+    }
+
+    pub fn write_element(&mut self, element: &ParsedElement) -> anyhow::Result<()> {
+        bincode::serialize_into(&mut self.writer, &Some(element))?;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> anyhow::Result<()> {
+        bincode::serialize_into(&mut self.writer, &None::<&ParsedElement>)?;
+        self.writer.flush()?;
+        drop(self.writer);
+        std::fs::rename(&self.temp_path, &self.path)?;
+        info!("Cached the parsed export to {}", self.path.display());
+        Ok(())
+    }
+}
+
+/// Reads the parsed export written by a CacheWriter
+pub struct CacheReader {
+    reader: BufReader<File>,
+}
+
+impl CacheReader {
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        Ok(Self {
+            reader: BufReader::new(File::open(path)?),
+        })
+    }
+
+    pub fn read_tables(&mut self) -> anyhow::Result<HKTables> {
+        let version: u32 = bincode::deserialize_from(&mut self.reader)?;
+        if version != CACHE_VERSION {
+            anyhow::bail!(
+                "The cache was written by an incompatible version ({} != {})",
+                version,
+                CACHE_VERSION
+            );
+        }
+        // The SQL types are deserialized as owned strings and mapped back to the static names
+        let tables: BTreeMap<String, BTreeMap<String, String>> =
+            bincode::deserialize_from(&mut self.reader)?;
+        Ok(tables
+            .into_iter()
+            .map(|(table_name, columns)| {
+                let columns = columns
+                    .into_iter()
+                    .map(|(column_name, ty)| (column_name, static_sql_type(&ty)))
+                    .collect();
+                (table_name, columns)
+            })
+            .collect())
+    }
+
+    /// Returns the next element, or None once every element has been read
+    pub fn read_element(&mut self) -> anyhow::Result<Option<ParsedElement>> {
+        Ok(bincode::deserialize_from(&mut self.reader)?)
+    }
+}
+
+fn static_sql_type(ty: &str) -> &'static str {
+    match ty {
+        "INTEGER" => "INTEGER",
+        "REAL" => "REAL",
+        "DATE" => "DATE",
+        "JSON" => "JSON",
+        _ => "TEXT",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conformance::EXPORT_FILES;
+    use crate::core::DatabaseValue;
+    use crate::options::ImportOptions;
+    use crate::testing::{self, TestDir};
+
+    #[test]
+    fn parsed_exports_are_read_back_as_written() {
+        let dir = TestDir::new("cache-round-trip");
+        let path = cache_path(dir.path(), "hash");
+        let tables = HKTables::from([(
+            "Heart Rate".to_string(),
+            [("value", "REAL"), ("unit", "TEXT"), ("startDate", "DATE")]
+                .into_iter()
+                .map(|(name, ty)| (name.to_string(), ty))
+                .collect(),
+        )]);
+        let mut writer = CacheWriter::create(&path).unwrap();
+        writer.write_tables(&tables).unwrap();
+        writer
+            .write_element(&ParsedElement::Row {
+                table_name: "Heart Rate".to_string(),
+                row: vec![("value".to_string(), DatabaseValue::Real(62.0))],
+            })
+            .unwrap();
+        // The cache only appears once it is complete
+        assert!(!path.exists());
+        writer.finish().unwrap();
+        assert!(path.exists());
+        assert!(!path.with_extension("partial").exists());
+
+        let mut reader = CacheReader::open(&path).unwrap();
+        assert_eq!(reader.read_tables().unwrap(), tables);
+        match reader.read_element().unwrap() {
+            Some(ParsedElement::Row { table_name, row }) => {
+                assert_eq!(table_name, "Heart Rate");
+                assert!(
+                    matches!(row.as_slice(), [(name, DatabaseValue::Real(v))] if name == "value" && *v == 62.0)
+                );
+            }
+            element => panic!("unexpected element {:?}", element),
+        }
+        assert!(reader.read_element().unwrap().is_none());
+    }
+
+    #[test]
+    fn caches_of_other_versions_are_rejected() {
+        let dir = TestDir::new("cache-version");
+        let path = cache_path(dir.path(), "hash");
+        std::fs::write(&path, bincode::serialize(&(CACHE_VERSION + 1)).unwrap()).unwrap();
+        let error = CacheReader::open(&path).unwrap().read_tables().unwrap_err();
+        assert!(error.to_string().contains("incompatible version"));
+    }
+
+    #[tokio::test]
+    async fn cached_imports_write_the_same_rows() {
+        let dir = TestDir::new("cache-import");
+        let export_dir = dir.write_export("export", EXPORT_FILES);
+        let options = ImportOptions {
+            cache: Some(dir.path().join("cache")),
+            ..ImportOptions::defaults()
+        };
+        let parsed_url = dir.db_url("parsed.db");
+        testing::import(&export_dir, &parsed_url, &options)
+            .await
+            .unwrap();
+        let cached = std::fs::read_dir(dir.path().join("cache"))
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect::<Vec<_>>();
+        assert_eq!(cached.len(), 1);
+        assert_eq!(cached[0].extension().unwrap(), "bin");
+        let cached_url = dir.db_url("cached.db");
+        testing::import(&export_dir, &cached_url, &options)
+            .await
+            .unwrap();
+        let parsed = testing::row_counts(&parsed_url).await;
+        let cached = testing::row_counts(&cached_url).await;
+        assert_eq!(parsed, cached);
+        assert!(parsed["HKQuantityTypeIdentifierHeartRate"] > 0);
+    }
+}
@@ -0,0 +1,204 @@
+//! The optional features of the build, so commands needing one that wasn't built in fail with
+//! the feature to rebuild with, or are skipped with --skip-unavailable
+use std::fmt;
+
+/// Exit code of a command that needs a feature the binary was built without
+pub const MISSING_CAPABILITY_EXIT_CODE: i32 = 4;
+
+/// A part of the tool behind a cargo feature
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Capability {
+    Parquet,
+    DuckDb,
+    Postgres,
+    RenderRoutes,
+    SqlCipher,
+}
+
+impl Capability {
+    pub const ALL: &'static [Capability] = &[
+        Capability::Parquet,
+        Capability::DuckDb,
+        Capability::Postgres,
+        Capability::RenderRoutes,
+        Capability::SqlCipher,
+    ];
+
+    /// The cargo feature that builds the capability in
+    pub fn feature(&self) -> &'static str {
+        match self {
+            Capability::Parquet => "parquet",
+            Capability::DuckDb => "duckdb",
+            Capability::Postgres => "postgres",
+            Capability::RenderRoutes => "render-routes",
+            Capability::SqlCipher => "sqlcipher",
+        }
+    }
+
+    /// What the capability provides, e.g. for the "requires building with" error
+    pub fn description(&self) -> &'static str {
+        match self {
+            Capability::Parquet => "Parquet output",
+            Capability::DuckDb => "DuckDB output",
+            Capability::Postgres => "PostgreSQL output",
+            Capability::RenderRoutes => "render-routes",
+            Capability::SqlCipher => "Encrypted SQLite output",
+        }
+    }
+
+    /// Returns true if the binary was built with the feature
+    pub fn is_available(&self) -> bool {
+        match self {
+            Capability::Parquet => cfg!(feature = "parquet"),
+            Capability::DuckDb => cfg!(feature = "duckdb"),
+            Capability::Postgres => cfg!(feature = "postgres"),
+            Capability::RenderRoutes => cfg!(feature = "render-routes"),
+            Capability::SqlCipher => cfg!(feature = "sqlcipher"),
+        }
+    }
+
+    /// The error of a command needing the capability when it isn't available
+    pub fn missing(self) -> anyhow::Error {
+        MissingCapability { capability: self }.into()
+    }
+}
+
+/// The error of a command that needs a feature the binary was built without, which the program
+/// exits with MISSING_CAPABILITY_EXIT_CODE for
+#[derive(Debug)]
+pub struct MissingCapability {
+    pub capability: Capability,
+}
+
+impl fmt::Display for MissingCapability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} requires building with `--features {}`, e.g. `cargo install healthkit-to-sqlite-cli --features {}`",
+            self.capability.description(),
+            self.capability.feature(),
+            self.capability.feature()
+        )
+    }
+}
+
+impl std::error::Error for MissingCapability {}
+
+/// Returns the missing capability if it is the error or one of its causes
+pub fn missing(error: &anyhow::Error) -> Option<&MissingCapability> {
+    error.chain().find_map(|cause| cause.downcast_ref())
+}
+
+/// Formats a line per capability saying whether it is built in, and the feature to rebuild with
+/// if it isn't
+pub fn report() -> String {
+    let mut report = String::new();
+    for capability in Capability::ALL {
+        let status = if capability.is_available() {
+            "available".to_string()
+        } else {
+            format!(
+                "unavailable, rebuild with `--features {}`",
+                capability.feature()
+            )
+        };
+        report.push_str(&format!("{:<20} {}\n", capability.description(), status));
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sink::Output;
+    use anyhow::Context;
+
+    #[test]
+    fn every_capability_is_a_feature_of_the_manifest() {
+        let manifest = include_str!("../Cargo.toml");
+        let features = manifest
+            .split("[features]")
+            .nth(1)
+            .and_then(|rest| rest.split("\n[").next())
+            .unwrap();
+        for capability in Capability::ALL {
+            assert!(
+                features
+                    .lines()
+                    .any(|line| line.starts_with(&format!("{} = ", capability.feature()))),
+                "{} is not a feature",
+                capability.feature()
+            );
+        }
+    }
+
+    #[test]
+    fn the_report_has_a_line_per_capability() {
+        let report = report();
+        assert_eq!(report.lines().count(), Capability::ALL.len());
+        for (line, capability) in report.lines().zip(Capability::ALL) {
+            assert!(line.starts_with(capability.description()), "{}", line);
+            assert_eq!(
+                line.ends_with("available"),
+                capability.is_available(),
+                "{}",
+                line
+            );
+        }
+    }
+
+    #[test]
+    fn missing_capabilities_are_found_among_the_causes_of_an_error() {
+        let error = Err::<(), _>(Capability::DuckDb.missing())
+            .context("Failed to open the output")
+            .unwrap_err();
+        let missing = missing(&error).unwrap();
+        assert_eq!(missing.capability, Capability::DuckDb);
+        assert_eq!(
+            missing.to_string(),
+            "DuckDB output requires building with `--features duckdb`, e.g. `cargo install healthkit-to-sqlite-cli --features duckdb`"
+        );
+        assert!(super::missing(&anyhow::anyhow!("Failed to open the output")).is_none());
+    }
+
+    #[test]
+    fn outputs_need_the_capability_of_their_backend() {
+        let database = |url: &str| Output::Database(url.to_string()).capability();
+        assert_eq!(database("sqlite://health.db"), None);
+        assert_eq!(
+            database("sqlite://health.db?key=secret"),
+            Some(Capability::SqlCipher)
+        );
+        assert_eq!(database("duckdb://health.duckdb"), Some(Capability::DuckDb));
+        assert_eq!(
+            database("postgresql://localhost/health"),
+            Some(Capability::Postgres)
+        );
+        assert_eq!(
+            Output::Parquet("out".into()).capability(),
+            Some(Capability::Parquet)
+        );
+        assert_eq!(Output::Csv("out".into()).capability(), None);
+    }
+
+    #[cfg(not(feature = "duckdb"))]
+    #[tokio::test]
+    async fn importing_into_a_backend_that_isnt_built_in_fails_with_its_capability() {
+        use crate::conformance::EXPORT_FILES;
+        use crate::options::ImportOptions;
+        use crate::testing::{self, TestDir};
+
+        let dir = TestDir::new("capability-missing");
+        let export_dir = dir.write_export("export", EXPORT_FILES);
+        let error = testing::import(
+            &export_dir,
+            &format!("duckdb://{}", dir.path().join("health.duckdb").display()),
+            &ImportOptions::defaults(),
+        )
+        .await
+        .err()
+        .unwrap();
+        assert_eq!(missing(&error).unwrap().capability, Capability::DuckDb);
+        assert!(!dir.path().join("health.duckdb").exists());
+    }
+}
@@ -0,0 +1,121 @@
+//! The `_changes` table of the rows each incremental import inserted or deleted, for downstream
+//! systems to consume the deltas instead of re-scanning the tables
+use crate::identifier::get_valid_sqlite_identifier;
+use sqlx::{Sqlite, Transaction};
+
+/// Name of the change log table
+pub const CHANGES_TABLE_NAME: &str = "_changes";
+
+/// Records the changes of one import under its export_id
+pub struct ChangeLog {
+    /// Numbers the imports that logged changes, starting at 1
+    export_id: i64,
+}
+
+impl ChangeLog {
+    /// Creates the _changes table if needed and assigns the import the next export_id
+    pub async fn open(tx: &mut Transaction<'_, Sqlite>) -> anyhow::Result<Self> {
+        sqlx::query(&format!(
+            r#"CREATE TABLE IF NOT EXISTS {} (
+                `export_id` INTEGER,
+                `table` TEXT,
+                `pk` INTEGER,
+                `op` TEXT
+            )"#,
+            CHANGES_TABLE_NAME
+        ))
+        .execute(&mut *tx)
+        .await?;
+        let export_id: i64 = sqlx::query_scalar(&format!(
+            "SELECT COALESCE(MAX(export_id), 0) + 1 FROM {}",
+            CHANGES_TABLE_NAME
+        ))
+        .fetch_one(&mut *tx)
+        .await?;
+        Ok(Self { export_id })
+    }
+
+    /// Records that the row with the rowid was inserted to the table
+    pub async fn record_insert(
+        &self,
+        tx: &mut Transaction<'_, Sqlite>,
+        table_name: &str,
+        rowid: i64,
+    ) -> anyhow::Result<()> {
+        if !is_logged(table_name) {
+            return Ok(());
+        }
+        sqlx::query(&format!(
+            "INSERT INTO {} VALUES (?, ?, ?, 'insert')",
+            CHANGES_TABLE_NAME
+        ))
+        .bind(self.export_id)
+        .bind(table_name)
+        .bind(rowid)
+        .execute(&mut *tx)
+        .await?;
+        Ok(())
+    }
+
+    /// Records that every row of the table is about to be deleted
+    pub async fn record_clear(
+        &self,
+        tx: &mut Transaction<'_, Sqlite>,
+        table_name: &str,
+    ) -> anyhow::Result<()> {
+        if !is_logged(table_name) {
+            return Ok(());
+        }
+        sqlx::query(&format!(
+            "INSERT INTO {} SELECT ?, ?, rowid, 'delete' FROM {}",
+            CHANGES_TABLE_NAME,
+            get_valid_sqlite_identifier(table_name)
+        ))
+        .bind(self.export_id)
+        .bind(table_name)
+        .execute(&mut *tx)
+        .await?;
+        Ok(())
+    }
+}
+
+/// The internal tables, such as `_meta` and `_schema_provenance`, are rewritten by every import
+/// and aren't logged
+fn is_logged(table_name: &str) -> bool {
+    !table_name.starts_with('_')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::SqlitePool;
+
+    #[tokio::test]
+    async fn changes_are_logged_under_the_import_that_made_them() {
+        let pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        let mut tx = pool.begin().await.unwrap();
+        sqlx::query("CREATE TABLE Me (dateOfBirth TEXT); INSERT INTO Me VALUES ('1990-01-01');")
+            .execute(&mut *tx)
+            .await
+            .unwrap();
+        let first = ChangeLog::open(&mut tx).await.unwrap();
+        first.record_insert(&mut tx, "Workout", 1).await.unwrap();
+        first.record_insert(&mut tx, "_meta", 1).await.unwrap();
+        let second = ChangeLog::open(&mut tx).await.unwrap();
+        second.record_clear(&mut tx, "Me").await.unwrap();
+        second.record_insert(&mut tx, "Me", 2).await.unwrap();
+        let changes: Vec<(i64, String, i64, String)> =
+            sqlx::query_as("SELECT export_id, `table`, pk, op FROM _changes ORDER BY rowid")
+                .fetch_all(&mut *tx)
+                .await
+                .unwrap();
+        assert_eq!(
+            changes,
+            [
+                (1, "Workout".to_string(), 1, "insert".to_string()),
+                (2, "Me".to_string(), 1, "delete".to_string()),
+                (2, "Me".to_string(), 2, "insert".to_string()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,335 @@
+//! The `_import_checkpoint` table recording how far into export.xml an import has committed, so
+//! a failed or interrupted import can be resumed with --resume instead of started over
+use sqlx::{Row, Sqlite, Transaction};
+use time::OffsetDateTime;
+
+/// Name of the table holding the checkpoint of the unfinished import
+pub const CHECKPOINT_TABLE_NAME: &str = "_import_checkpoint";
+
+/// Number of top-level elements of export.xml imported between checkpoints
+#[cfg(not(test))]
+pub const CHECKPOINT_ELEMENTS: u64 = 100_000;
+
+/// Checkpointed every few elements in the tests, so the small exports of the tests have several
+#[cfg(test)]
+pub const CHECKPOINT_ELEMENTS: u64 = 3;
+
+/// How far an import got, as of its last commit
+#[derive(Debug, Clone, Copy)]
+pub struct Checkpoint {
+    /// Uncompressed size of export.xml in bytes, to tell whether a resume is of the same export
+    pub export_xml_size: u64,
+    /// Byte offset in export.xml of the end of the last element committed
+    pub export_xml_position: usize,
+    /// Number of top-level elements of export.xml committed
+    pub element_index: u64,
+    /// Number of rows written before the checkpoint, excluding rows skipped as duplicates
+    pub rows_imported: u64,
+    /// Number of attachments imported after export.xml, i.e. electrocardiogram files, clinical
+    /// records, and clinical record files, in the order they are imported
+    pub attachments_imported: u64,
+}
+
+/// Replaces the checkpoint in the database
+pub async fn write(
+    tx: &mut Transaction<'_, Sqlite>,
+    checkpoint: &Checkpoint,
+) -> anyhow::Result<()> {
+    // Emptied rather than recreated, so --no-destructive never drops it
+    sqlx::query(&format!(
+        r#"CREATE TABLE IF NOT EXISTS {} (
+            `export_xml_size` INTEGER,
+            `export_xml_position` INTEGER,
+            `element_index` INTEGER,
+            `rows_imported` INTEGER,
+            `attachments_imported` INTEGER,
+            `checkpointed_at` DATE
+        )"#,
+        CHECKPOINT_TABLE_NAME
+    ))
+    .execute(&mut *tx)
+    .await?;
+    // Missing from the tables created by earlier versions
+    let has_attachments_imported: bool = sqlx::query_scalar(
+        "SELECT COUNT(*) > 0 FROM pragma_table_info(?) WHERE name = 'attachments_imported'",
+    )
+    .bind(CHECKPOINT_TABLE_NAME)
+    .fetch_one(&mut *tx)
+    .await?;
+    if !has_attachments_imported {
+        sqlx::query(&format!(
+            "ALTER TABLE {} ADD COLUMN `attachments_imported` INTEGER",
+            CHECKPOINT_TABLE_NAME
+        ))
+        .execute(&mut *tx)
+        .await?;
+    }
+    sqlx::query(&format!("DELETE FROM {}", CHECKPOINT_TABLE_NAME))
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query(&format!(
+        r#"INSERT INTO {} (
+            `export_xml_size`,
+            `export_xml_position`,
+            `element_index`,
+            `rows_imported`,
+            `attachments_imported`,
+            `checkpointed_at`
+        ) VALUES (?, ?, ?, ?, ?, ?)"#,
+        CHECKPOINT_TABLE_NAME
+    ))
+    .bind(i64::try_from(checkpoint.export_xml_size)?)
+    .bind(i64::try_from(checkpoint.export_xml_position)?)
+    .bind(i64::try_from(checkpoint.element_index)?)
+    .bind(i64::try_from(checkpoint.rows_imported)?)
+    .bind(i64::try_from(checkpoint.attachments_imported)?)
+    .bind(OffsetDateTime::now_utc())
+    .execute(&mut *tx)
+    .await?;
+    Ok(())
+}
+
+/// Returns the checkpoint of the unfinished import, if there is one
+pub async fn read(tx: &mut Transaction<'_, Sqlite>) -> anyhow::Result<Option<Checkpoint>> {
+    if !table_exists(tx).await? {
+        return Ok(None);
+    }
+    let row = sqlx::query(&format!("SELECT * FROM {}", CHECKPOINT_TABLE_NAME))
+        .fetch_optional(&mut *tx)
+        .await?;
+    match row {
+        Some(row) => Ok(Some(Checkpoint {
+            export_xml_size: u64::try_from(row.try_get::<i64, _>("export_xml_size")?)?,
+            export_xml_position: usize::try_from(row.try_get::<i64, _>("export_xml_position")?)?,
+            element_index: u64::try_from(row.try_get::<i64, _>("element_index")?)?,
+            rows_imported: u64::try_from(row.try_get::<i64, _>("rows_imported")?)?,
+            // Missing from the checkpoints of earlier versions, which never reached attachments
+            attachments_imported: match row.try_get::<i64, _>("attachments_imported") {
+                Ok(attachments_imported) => u64::try_from(attachments_imported)?,
+                Err(_) => 0,
+            },
+        })),
+        None => Ok(None),
+    }
+}
+
+/// Removes the checkpoint once the import is complete, leaving nothing to resume. With
+/// --no-destructive the table is kept and only its row is deleted.
+pub async fn clear(tx: &mut Transaction<'_, Sqlite>, no_destructive: bool) -> anyhow::Result<()> {
+    if !no_destructive {
+        sqlx::query(&format!("DROP TABLE IF EXISTS {}", CHECKPOINT_TABLE_NAME))
+            .execute(&mut *tx)
+            .await?;
+    } else if table_exists(tx).await? {
+        sqlx::query(&format!("DELETE FROM {}", CHECKPOINT_TABLE_NAME))
+            .execute(&mut *tx)
+            .await?;
+    }
+    Ok(())
+}
+
+async fn table_exists(tx: &mut Transaction<'_, Sqlite>) -> anyhow::Result<bool> {
+    Ok(sqlx::query_scalar(
+        "SELECT COUNT(*) > 0 FROM sqlite_master WHERE type = 'table' AND name = ?",
+    )
+    .bind(CHECKPOINT_TABLE_NAME)
+    .fetch_one(&mut *tx)
+    .await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conformance::EXPORT_FILES;
+    use crate::core::ImportStatus;
+    use crate::interrupt;
+    use crate::options::ImportOptions;
+    use crate::testing::{self, TestDir};
+    use sqlx::SqlitePool;
+    use std::collections::BTreeMap;
+    use std::path::Path;
+
+    /// Returns the checkpoint of the database, if it has one
+    async fn read_checkpoint(db_url: &str) -> Option<Checkpoint> {
+        let db = SqlitePool::connect(db_url).await.unwrap();
+        let mut tx = db.begin().await.unwrap();
+        let checkpoint = read(&mut tx).await.unwrap();
+        tx.commit().await.unwrap();
+        db.close().await;
+        checkpoint
+    }
+
+    /// Resumes the import of the export into the database, returning the row counts of the
+    /// tables once it completes
+    async fn resume(export_dir: &Path, db_url: &str) -> BTreeMap<String, i64> {
+        let options = ImportOptions {
+            resume: true,
+            ..ImportOptions::defaults()
+        };
+        let status = testing::import(export_dir, db_url, &options).await.unwrap();
+        assert!(matches!(status, ImportStatus::Complete));
+        assert!(read_checkpoint(db_url).await.is_none());
+        testing::row_counts(db_url).await
+    }
+
+    /// Returns the number of workouts of the database, and how many of them have a route
+    async fn workouts(db_url: &str) -> (i64, i64) {
+        let db = SqlitePool::connect(db_url).await.unwrap();
+        let workouts = sqlx::query_as("SELECT COUNT(*), COUNT(geometry) FROM Workout")
+            .fetch_one(&db)
+            .await
+            .unwrap();
+        db.close().await;
+        workouts
+    }
+
+    /// Byte offset in the export.xml of the conformance export of the end of its workout
+    fn workout_end() -> usize {
+        let export_xml = EXPORT_FILES
+            .iter()
+            .find(|(path, _)| *path == "export.xml")
+            .unwrap()
+            .1;
+        let end_tag = b"</Workout>";
+        export_xml
+            .windows(end_tag.len())
+            .position(|window| window == end_tag)
+            .unwrap()
+            + end_tag.len()
+    }
+
+    #[tokio::test]
+    async fn interrupted_imports_resume_to_a_clean_import() {
+        let dir = TestDir::new("checkpoint-interrupted");
+        let export_dir = dir.write_export("export", EXPORT_FILES);
+        let clean_url = dir.db_url("clean.db");
+        testing::import(&export_dir, &clean_url, &ImportOptions::defaults())
+            .await
+            .unwrap();
+        let clean = testing::row_counts(&clean_url).await;
+        // Stops the import at every point it checks for an interrupt in turn, until it completes
+        let mut checkpoints = Vec::new();
+        for check in 0.. {
+            let db_url = dir.db_url(&format!("interrupted_{}.db", check));
+            interrupt::interrupt_at_check(Some(check));
+            let status = testing::import(&export_dir, &db_url, &ImportOptions::defaults()).await;
+            interrupt::interrupt_at_check(None);
+            match status {
+                Ok(ImportStatus::Complete) => break,
+                Ok(ImportStatus::Interrupted(_)) => {}
+                // Interrupted during the schema pass, leaving nothing to resume
+                Err(e) if e.to_string().ends_with("before any rows were written") => continue,
+                Err(e) => panic!("{}", e),
+            }
+            let checkpoint = read_checkpoint(&db_url).await.unwrap();
+            checkpoints.push(checkpoint);
+            assert_eq!(
+                resume(&export_dir, &db_url).await,
+                clean,
+                "interrupted at {:?}",
+                checkpoint
+            );
+        }
+        // Interrupted within the insert pass, and after some of the attachments
+        assert!(checkpoints
+            .iter()
+            .any(|c| c.element_index > 0 && c.attachments_imported == 0));
+        assert!(checkpoints.iter().any(|c| c.attachments_imported > 0));
+    }
+
+    #[tokio::test]
+    async fn failed_imports_resume_from_their_last_checkpoint() {
+        let dir = TestDir::new("checkpoint-failed");
+        let export_dir = dir.write_export("export", EXPORT_FILES);
+        let clean_url = dir.db_url("clean.db");
+        testing::import(&export_dir, &clean_url, &ImportOptions::defaults())
+            .await
+            .unwrap();
+        let clean = testing::row_counts(&clean_url).await;
+        // Fails the import at each row in turn with --max-rows, losing the rows written since
+        // the last checkpoint
+        let mut resumed_after_workout = false;
+        for max_rows in 1.. {
+            let db_url = dir.db_url(&format!("failed_{}.db", max_rows));
+            let options = ImportOptions {
+                max_rows: Some(max_rows),
+                ..ImportOptions::defaults()
+            };
+            if testing::import(&export_dir, &db_url, &options)
+                .await
+                .is_ok()
+            {
+                break;
+            }
+            let checkpoint = match read_checkpoint(&db_url).await {
+                Some(checkpoint) => checkpoint,
+                None => continue,
+            };
+            assert!(checkpoint.rows_imported <= max_rows);
+            // The workout waits for its route on another thread, and is written before the
+            // checkpoint after it rather than left to the rows the failure rolled back
+            if checkpoint.export_xml_position >= workout_end() {
+                assert_eq!(workouts(&db_url).await, (1, 1));
+                resumed_after_workout = true;
+            }
+            assert_eq!(
+                resume(&export_dir, &db_url).await,
+                clean,
+                "failed after {} rows at {:?}",
+                max_rows,
+                checkpoint
+            );
+        }
+        assert!(resumed_after_workout);
+    }
+
+    #[tokio::test]
+    async fn checkpoints_of_another_export_are_refused() {
+        let dir = TestDir::new("checkpoint-another-export");
+        let export_dir = dir.write_export("export", EXPORT_FILES);
+        let db_url = dir.db_url("health.db");
+        // Fails the import at its first row after a checkpoint
+        let mut max_rows = 0;
+        let checkpoint = loop {
+            max_rows += 1;
+            let options = ImportOptions {
+                max_rows: Some(max_rows),
+                ..ImportOptions::defaults()
+            };
+            assert!(testing::import(&export_dir, &db_url, &options)
+                .await
+                .is_err());
+            if let Some(checkpoint) = read_checkpoint(&db_url).await {
+                break checkpoint;
+            }
+        };
+        let export_xml = export_dir.join("export.xml");
+        let contents = std::fs::read_to_string(&export_xml).unwrap();
+        std::fs::write(
+            &export_xml,
+            contents.replace("</HealthData>", "\n</HealthData>"),
+        )
+        .unwrap();
+        let options = ImportOptions {
+            resume: true,
+            ..ImportOptions::defaults()
+        };
+        let error = testing::import(&export_dir, &db_url, &options)
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "The checkpoint is of a different export, whose export.xml is {} bytes rather than {}",
+                checkpoint.export_xml_size,
+                checkpoint.export_xml_size + 1
+            )
+        );
+        // The checkpoint is kept for a resume of the export it is of
+        assert_eq!(
+            read_checkpoint(&db_url).await.unwrap().export_xml_position,
+            checkpoint.export_xml_position
+        );
+    }
+}
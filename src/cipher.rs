@@ -0,0 +1,194 @@
+//! Encrypted SQLite databases, written with SQLCipher when the database URL carries a key
+//! parameter, e.g. sqlite://healthkit.db?key=passphrase
+use crate::capability::Capability;
+use percent_encoding::percent_decode_str;
+use sqlx::sqlite::SqliteConnectOptions;
+use sqlx::SqlitePool;
+use std::borrow::Cow;
+use std::str::FromStr;
+
+/// Query parameter of a sqlite:// URL holding the key of the database
+const KEY_PARAMETER: &str = "key";
+
+/// Splits the key parameter off a SQLite URL, returning the URL sqlx understands and the key,
+/// percent-decoded, so keys can hold characters such as &, #, and % written as %26, %23, and %25
+pub fn split_key(url: &str) -> anyhow::Result<(String, Option<String>)> {
+    let (url, key) = split_key_parameter(url);
+    let key = key
+        .map(|key| match percent_decode_str(key).decode_utf8() {
+            Ok(key) => Ok(key.into_owned()),
+            Err(_) => Err(anyhow::anyhow!(
+                "The database key isn't UTF-8 once percent-decoded"
+            )),
+        })
+        .transpose()?;
+    Ok((url, key))
+}
+
+/// Returns true if the SQLite URL has a key parameter
+pub fn has_key(url: &str) -> bool {
+    split_key_parameter(url).1.is_some()
+}
+
+/// Splits the key parameter off a SQLite URL, returning the URL and the key as it is in the URL
+fn split_key_parameter(url: &str) -> (String, Option<&str>) {
+    let (base, query) = match url.split_once('?') {
+        Some(split) => split,
+        None => return (url.to_string(), None),
+    };
+    let mut key = None;
+    let mut params = Vec::new();
+    for param in query.split('&') {
+        match param.split_once('=') {
+            Some((KEY_PARAMETER, value)) => key = Some(value),
+            _ => params.push(param),
+        }
+    }
+    if params.is_empty() {
+        (base.to_string(), key)
+    } else {
+        (format!("{}?{}", base, params.join("&")), key)
+    }
+}
+
+/// Returns the URL with the value of its key parameter hidden, for messages
+pub fn redact_key(url: &str) -> Cow<'_, str> {
+    match split_key_parameter(url) {
+        (_, None) => Cow::Borrowed(url),
+        (base, Some(_)) => {
+            let separator = if base.contains('?') { '&' } else { '?' };
+            Cow::Owned(format!("{}{}{}=***", base, separator, KEY_PARAMETER))
+        }
+    }
+}
+
+/// Returns the connection options of a SQLite URL, keying each connection with the key
+/// parameter of the URL. A key is either a passphrase, or a raw key of 64 hex digits, or 96 with
+/// the salt, written as x'...'.
+pub fn connect_options(url: &str) -> anyhow::Result<SqliteConnectOptions> {
+    let (url, key) = split_key(url)?;
+    let connect_options = SqliteConnectOptions::from_str(&url)?;
+    let key = match key {
+        Some(key) => key,
+        None => return Ok(connect_options),
+    };
+    if !Capability::SqlCipher.is_available() {
+        return Err(Capability::SqlCipher.missing());
+    }
+    Ok(connect_options.pragma(KEY_PARAMETER, key_pragma_value(&key)?))
+}
+
+/// Returns the value of the key PRAGMA for a key, as a quoted passphrase or the raw key
+fn key_pragma_value(key: &str) -> anyhow::Result<String> {
+    if key.is_empty() {
+        anyhow::bail!("The database key is empty");
+    }
+    let raw_key = key
+        .strip_prefix("x'")
+        .or_else(|| key.strip_prefix("X'"))
+        .and_then(|key| key.strip_suffix('\''));
+    match raw_key {
+        Some(hex) if matches!(hex.len(), 64 | 96) && hex.chars().all(|c| c.is_ascii_hexdigit()) => {
+            Ok(format!("\"x'{}'\"", hex))
+        }
+        Some(_) => anyhow::bail!(
+            "A raw database key must be x'...' with 64 hex digits, or 96 with the salt"
+        ),
+        None => Ok(format!("'{}'", key.replace('\'', "''"))),
+    }
+}
+
+/// Checks that the keyed database is encrypted with SQLCipher and that the key opens it, so a
+/// SQLite without cipher support never writes the health data unencrypted
+pub async fn check_key(db: &SqlitePool) -> anyhow::Result<()> {
+    let cipher_version: Option<String> = sqlx::query_scalar("PRAGMA cipher_version")
+        .fetch_optional(db)
+        .await?;
+    if cipher_version.is_none() {
+        anyhow::bail!(
+            "The database URL has a key, but this SQLite library lacks SQLCipher support, so the database can't be encrypted. Rebuild with `--features {}`",
+            Capability::SqlCipher.feature()
+        );
+    }
+    if let Err(e) = sqlx::query("SELECT COUNT(*) FROM sqlite_master")
+        .fetch_one(db)
+        .await
+    {
+        anyhow::bail!(
+            "The key doesn't open the database, or it isn't an encrypted database: {}",
+            e
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RAW_KEY: &str = "2DD29CA851E7B56E4697B0E1F08507293D761A05CE4D1B628663F411A8086D99";
+
+    #[test]
+    fn urls_without_a_key_are_unchanged() {
+        let (url, key) = split_key("sqlite://health.db?mode=rwc").unwrap();
+        assert_eq!(url, "sqlite://health.db?mode=rwc");
+        assert_eq!(key, None);
+        assert!(!has_key("sqlite://health.db"));
+    }
+
+    #[test]
+    fn key_is_split_off_the_other_parameters() {
+        let (url, key) = split_key("sqlite://health.db?mode=rwc&key=secret").unwrap();
+        assert_eq!(url, "sqlite://health.db?mode=rwc");
+        assert_eq!(key.as_deref(), Some("secret"));
+        let (url, key) = split_key("sqlite://health.db?key=secret").unwrap();
+        assert_eq!(url, "sqlite://health.db");
+        assert_eq!(key.as_deref(), Some("secret"));
+    }
+
+    #[test]
+    fn key_is_percent_decoded() {
+        let (_, key) = split_key("sqlite://health.db?key=a%26b%3Dc%23d%25e").unwrap();
+        assert_eq!(key.as_deref(), Some("a&b=c#d%e"));
+        let (_, key) = split_key("sqlite://health.db?key=caf%C3%A9").unwrap();
+        assert_eq!(key.as_deref(), Some("café"));
+        assert!(split_key("sqlite://health.db?key=%FF").is_err());
+    }
+
+    #[test]
+    fn key_is_redacted() {
+        assert_eq!(
+            redact_key("sqlite://health.db?key=secret&mode=rwc"),
+            "sqlite://health.db?mode=rwc&key=***"
+        );
+        assert_eq!(
+            redact_key("sqlite://health.db?key=%FF"),
+            "sqlite://health.db?key=***"
+        );
+        assert_eq!(redact_key("sqlite://health.db"), "sqlite://health.db");
+    }
+
+    #[test]
+    fn passphrases_are_quoted() {
+        assert_eq!(key_pragma_value("secret").unwrap(), "'secret'");
+        assert_eq!(key_pragma_value("it's").unwrap(), "'it''s'");
+        assert_eq!(key_pragma_value("x'ab").unwrap(), "'x''ab'");
+        assert!(key_pragma_value("").is_err());
+    }
+
+    #[test]
+    fn raw_keys_are_checked() {
+        assert_eq!(
+            key_pragma_value(&format!("x'{}'", RAW_KEY)).unwrap(),
+            format!("\"x'{}'\"", RAW_KEY)
+        );
+        let salted = format!("X'{}{}'", RAW_KEY, &RAW_KEY[..32]);
+        assert_eq!(
+            key_pragma_value(&salted).unwrap(),
+            format!("\"x'{}{}'\"", RAW_KEY, &RAW_KEY[..32])
+        );
+        assert!(key_pragma_value(&format!("x'{}'", &RAW_KEY[..63])).is_err());
+        assert!(key_pragma_value(&format!("x'{}0'", RAW_KEY)).is_err());
+        assert!(key_pragma_value(&format!("x'{}'", RAW_KEY.replace('D', "G"))).is_err());
+    }
+}
@@ -0,0 +1,255 @@
+//! Flattening the FHIR resources exported to the clinical-records directory into tables
+use crate::blob::{Blob, BLOB_COLUMN, FHIR_MIME_TYPE};
+use crate::core::{DatabaseRow, DatabaseValue, HKTables, HEALTHKIT_DATE_FORMAT};
+use crate::schema::widen_type;
+use crate::source::ExportArchive;
+use log::*;
+use sqlx::types::JsonValue;
+use std::io::{BufReader, Read};
+use time::format_description::well_known::Rfc3339;
+use time::{Date, OffsetDateTime};
+
+/// Prefix of the table names, followed by the FHIR resource type, e.g. ClinicalObservation
+pub const CLINICAL_TABLE_PREFIX: &str = "Clinical";
+
+/// Separates the keys of nested objects in column names, e.g. code_text
+const KEY_SEPARATOR: &str = "_";
+
+/// A FHIR resource flattened to a row of its resource type table
+pub struct ClinicalRecord {
+    pub table_name: String,
+    pub row: DatabaseRow,
+}
+
+/// Reads and flattens the FHIR JSON files at the given paths. Bundle files contribute a row for
+/// each resource they contain. If keep_files is set, the files are returned as blobs, and each
+/// row refers to the blob of its file.
+pub fn read_clinical_records(
+    archive: &mut ExportArchive,
+    paths: &[String],
+    keep_files: bool,
+) -> anyhow::Result<(Vec<ClinicalRecord>, Vec<Blob>)> {
+    let mut records = Vec::new();
+    let mut blobs = Vec::new();
+    for path in paths {
+        debug!("Reading clinical record file: {}", path);
+        let resource: JsonValue = {
+            let (mut file, size) = archive.open_file(path)?;
+            if keep_files {
+                let mut bytes = Vec::with_capacity(size as usize);
+                file.read_to_end(&mut bytes)?;
+                let resource = serde_json::from_slice(&bytes)?;
+                blobs.push(Blob::new(FHIR_MIME_TYPE, bytes));
+                resource
+            } else {
+                serde_json::from_reader(BufReader::with_capacity(size as usize, file))?
+            }
+        };
+        let blob = keep_files.then(|| blobs.last().expect("pushed above").reference(BLOB_COLUMN));
+        let resources = match resource["resourceType"].as_str() {
+            Some("Bundle") => resource["entry"]
+                .as_array()
+                .map(|entries| entries.iter().map(|entry| &entry["resource"]).collect())
+                .unwrap_or_default(),
+            _ => vec![&resource],
+        };
+        for resource in resources {
+            let resource_type = match resource["resourceType"].as_str() {
+                Some(resource_type) => resource_type,
+                None => {
+                    warn!("Skipping FHIR resource without a resourceType in {}", path);
+                    continue;
+                }
+            };
+            let mut row = vec![("file".to_string(), DatabaseValue::Text(path.clone()))];
+            flatten(None, resource, &mut row);
+            row.extend(blob.clone());
+            records.push(ClinicalRecord {
+                table_name: format!("{}{}", CLINICAL_TABLE_PREFIX, resource_type),
+                row,
+            });
+        }
+    }
+    Ok((records, blobs))
+}
+
+/// Adds a table for each resource type, with a column for each flattened key
+pub fn extend_tables(tables: &mut HKTables, records: &[ClinicalRecord]) {
+    for record in records {
+        let columns = tables.entry(record.table_name.clone()).or_default();
+        for (name, value) in &record.row {
+            let ty = column_type(value);
+            columns
+                .entry(name.clone())
+                .and_modify(|existing| *existing = widen_type(existing, ty))
+                .or_insert(ty);
+        }
+    }
+}
+
+/// Flattens nested objects into columns named by their key path. Arrays, such as the codings of
+/// a concept or the components of an observation, are kept as JSON.
+fn flatten(prefix: Option<&str>, value: &JsonValue, row: &mut DatabaseRow) {
+    let name = || prefix.unwrap_or_default().to_string();
+    match value {
+        JsonValue::Object(object) => {
+            for (key, value) in object {
+                let key = match prefix {
+                    Some(prefix) => format!("{}{}{}", prefix, KEY_SEPARATOR, key),
+                    None => key.clone(),
+                };
+                flatten(Some(&key), value, row);
+            }
+        }
+        JsonValue::Null => {}
+        JsonValue::Bool(b) => row.push((name(), DatabaseValue::Integer(*b as i64))),
+        JsonValue::Number(n) => {
+            let value = match n.as_i64() {
+                Some(i) => DatabaseValue::Integer(i),
+                _ => DatabaseValue::Real(n.as_f64().unwrap_or_default()),
+            };
+            row.push((name(), value));
+        }
+        JsonValue::String(s) => row.push((name(), database_value_from_fhir_str(s))),
+        JsonValue::Array(_) => row.push((name(), DatabaseValue::Json(value.clone()))),
+    }
+}
+
+/// FHIR dateTime values are RFC 3339 and dates are ISO 8601 dates. Partial dates, such as a year
+/// and month, are kept as text.
+fn database_value_from_fhir_str(s: &str) -> DatabaseValue {
+    if let Ok(date_time) = OffsetDateTime::parse(s, &Rfc3339) {
+        DatabaseValue::OffsetDateTime(date_time)
+    } else if let Ok(date) = Date::parse(s, &HEALTHKIT_DATE_FORMAT) {
+        DatabaseValue::Date(date)
+    } else {
+        DatabaseValue::Text(s.to_string())
+    }
+}
+
+fn column_type(value: &DatabaseValue) -> &'static str {
+    match value {
+        DatabaseValue::Integer(_) => "INTEGER",
+        DatabaseValue::Real(_) => "REAL",
+        DatabaseValue::OffsetDateTime(_) | DatabaseValue::Date(_) => "DATE",
+        DatabaseValue::Text(_) => "TEXT",
+        DatabaseValue::Json(_) => "JSON",
+        DatabaseValue::Blob(_) => "BLOB",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::ExportSource;
+    use crate::testing::TestDir;
+
+    const OBSERVATION: &str = r#"{
+        "resourceType": "Observation",
+        "id": "1",
+        "status": "final",
+        "code": {"text": "Hemoglobin A1c", "coding": [{"code": "4548-4"}]},
+        "valueQuantity": {"value": 5.4, "unit": "%"},
+        "effectiveDateTime": "2024-01-01T09:00:00-08:00",
+        "issued": "2024-01-02",
+        "note": null
+    }"#;
+
+    const BUNDLE: &str = r#"{
+        "resourceType": "Bundle",
+        "entry": [
+            {"resource": {"resourceType": "Observation", "id": "2", "valueQuantity": {"value": 6}}},
+            {"resource": {"resourceType": "Condition", "id": "3", "onsetDateTime": "2023-06", "active": true}},
+            {"resource": {"id": "4"}}
+        ]
+    }"#;
+
+    fn texts(row: &DatabaseRow) -> Vec<(&str, String)> {
+        row.iter()
+            .map(|(name, value)| (name.as_str(), value.to_text()))
+            .collect()
+    }
+
+    #[test]
+    fn resources_are_flattened_into_rows_of_their_type() {
+        let dir = TestDir::new("clinical-records");
+        let export_dir = dir.write_export(
+            "export",
+            &[
+                (
+                    "clinical-records/Observation-1.json",
+                    OBSERVATION.as_bytes(),
+                ),
+                ("clinical-records/Bundle-1.json", BUNDLE.as_bytes()),
+            ],
+        );
+        let mut archive = ExportSource::Directory(export_dir).open().unwrap();
+        let paths = archive.list_files("clinical-records").unwrap();
+        let (records, blobs) = read_clinical_records(&mut archive, &paths, false).unwrap();
+        assert!(blobs.is_empty());
+        let table_names: Vec<&str> = records.iter().map(|r| r.table_name.as_str()).collect();
+        // The resource without a resourceType is skipped
+        assert_eq!(
+            table_names,
+            [
+                "ClinicalObservation",
+                "ClinicalCondition",
+                "ClinicalObservation"
+            ]
+        );
+        assert_eq!(
+            texts(&records[1].row),
+            [
+                ("file", "/clinical-records/Bundle-1.json".to_string()),
+                ("active", "1".to_string()),
+                ("id", "3".to_string()),
+                // Partial dates are kept as text
+                ("onsetDateTime", "2023-06".to_string()),
+                ("resourceType", "Condition".to_string()),
+            ]
+        );
+        let observation: Vec<_> = records[2]
+            .row
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.database_type()))
+            .collect();
+        assert_eq!(
+            observation,
+            [
+                ("file", "TEXT"),
+                ("code_coding", "JSON"),
+                ("code_text", "TEXT"),
+                ("effectiveDateTime", "DATE"),
+                ("id", "TEXT"),
+                ("issued", "DATE"),
+                ("resourceType", "TEXT"),
+                ("status", "TEXT"),
+                ("valueQuantity_unit", "TEXT"),
+                ("valueQuantity_value", "REAL"),
+            ]
+        );
+
+        let mut tables = HKTables::new();
+        extend_tables(&mut tables, &records);
+        // The integer and real values of the observations are widened to REAL
+        assert_eq!(tables["ClinicalObservation"]["valueQuantity_value"], "REAL");
+        assert_eq!(tables["ClinicalCondition"]["active"], "INTEGER");
+    }
+
+    #[test]
+    fn files_are_kept_as_blobs_referenced_by_their_rows() {
+        let dir = TestDir::new("clinical-records-blobs");
+        let export_dir = dir.write_export(
+            "export",
+            &[("clinical-records/Bundle-1.json", BUNDLE.as_bytes())],
+        );
+        let mut archive = ExportSource::Directory(export_dir).open().unwrap();
+        let paths = archive.list_files("clinical-records").unwrap();
+        let (records, blobs) = read_clinical_records(&mut archive, &paths, true).unwrap();
+        assert_eq!(blobs.len(), 1);
+        assert_eq!(blobs[0].mime, FHIR_MIME_TYPE);
+        for record in &records {
+            assert!(texts(&record.row).contains(&(BLOB_COLUMN, blobs[0].hash.clone())));
+        }
+    }
+}
@@ -0,0 +1,108 @@
+//! The reference time of an import, used for the import timestamp and the windows of derived
+//! tables such as highlights and interpolated_daily
+use time::format_description::well_known::Rfc3339;
+use time::macros::format_description;
+use time::{Date, OffsetDateTime};
+
+/// Where the import gets the current time from
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Clock {
+    /// The system clock
+    #[default]
+    System,
+    /// A fixed time, so repeated imports of the same export produce the same output
+    Fixed(OffsetDateTime),
+}
+
+impl Clock {
+    pub fn now(&self) -> OffsetDateTime {
+        match self {
+            Clock::System => OffsetDateTime::now_utc(),
+            Clock::Fixed(now) => *now,
+        }
+    }
+
+    pub fn today(&self) -> Date {
+        self.now().date()
+    }
+}
+
+/// Parses the --as-of argument, either an RFC 3339 date and time or a date, which is taken as
+/// midnight UTC
+pub fn parse_as_of(s: &str) -> Result<OffsetDateTime, String> {
+    if let Ok(date_time) = OffsetDateTime::parse(s, &Rfc3339) {
+        return Ok(date_time);
+    }
+    Date::parse(s, format_description!("[year]-[month]-[day]"))
+        .map(|date| date.midnight().assume_utc())
+        .map_err(|_| {
+            format!(
+                "\"{}\" is neither a date such as 2024-01-31 nor an RFC 3339 date and time such as 2024-01-31T08:00:00Z",
+                s
+            )
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conformance::EXPORT_FILES;
+    use crate::options::ImportOptions;
+    use crate::testing::{self, TestDir};
+    use sqlx::SqlitePool;
+    use time::macros::{date, datetime};
+
+    #[test]
+    fn as_of_is_a_date_and_time_or_a_date_at_midnight_utc() {
+        assert_eq!(
+            parse_as_of("2024-01-31T08:00:00-08:00"),
+            Ok(datetime!(2024-01-31 08:00:00 -8))
+        );
+        assert_eq!(
+            parse_as_of("2024-01-31"),
+            Ok(datetime!(2024-01-31 00:00:00 UTC))
+        );
+        for invalid in ["2024-01-31 08:00:00", "31/01/2024", "2024-02-30", ""] {
+            let error = parse_as_of(invalid).unwrap_err();
+            assert!(error.contains("is neither a date"), "{}", error);
+        }
+    }
+
+    #[test]
+    fn a_fixed_clock_always_tells_the_same_time() {
+        let clock = Clock::Fixed(datetime!(2024-01-31 23:30:00 -8));
+        assert_eq!(clock.now(), datetime!(2024-01-31 23:30:00 -8));
+        assert_eq!(clock.today(), date!(2024 - 01 - 31));
+        assert_eq!(Clock::default(), Clock::System);
+    }
+
+    #[tokio::test]
+    async fn imports_as_of_the_same_time_record_the_same_import_time() {
+        let dir = TestDir::new("clock-as-of");
+        let export_dir = dir.write_export("export", EXPORT_FILES);
+        let options = ImportOptions {
+            as_of: Some(parse_as_of("2024-01-31T08:00:00Z").unwrap()),
+            ..ImportOptions::defaults()
+        };
+        let mut imported_at = Vec::new();
+        for file_name in ["first.db", "second.db"] {
+            let db_url = dir.db_url(file_name);
+            testing::import(&export_dir, &db_url, &options)
+                .await
+                .unwrap();
+            let db = SqlitePool::connect(&db_url).await.unwrap();
+            let as_of: String = sqlx::query_scalar("SELECT value FROM _meta WHERE key = 'as_of'")
+                .fetch_one(&db)
+                .await
+                .unwrap();
+            assert_eq!(as_of, "2024-01-31T08:00:00Z");
+            let time: OffsetDateTime = sqlx::query_scalar("SELECT importedAt FROM export_metadata")
+                .fetch_one(&db)
+                .await
+                .unwrap();
+            imported_at.push(time);
+            db.close().await;
+        }
+        assert_eq!(imported_at, [datetime!(2024-01-31 08:00:00 UTC); 2]);
+    }
+}
@@ -0,0 +1,238 @@
+//! Storing the JSON columns of SQLite databases as zstd-compressed BLOBs with --compress-json,
+//! as the route geometries of long workouts are megabytes of JSON text
+use crate::core::{DatabaseRow, DatabaseValue, HKTables};
+use crate::identifier::get_valid_sqlite_identifier;
+use crate::naming;
+use crate::options::Naming;
+use log::*;
+use sqlx::{Sqlite, Transaction};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// zstd compression level, the library's default, which compresses JSON well at a fast speed
+const COMPRESSION_LEVEL: i32 = 3;
+
+/// The first bytes of every zstd frame
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// Suffix of the view of each table with compressed columns that decompresses them
+const JSON_VIEW_SUFFIX: &str = "_json";
+
+/// The function of the sqlite-zstd extension the views decompress the columns with. It isn't
+/// part of SQLite, so the views can only be queried once the extension is loaded.
+const DECOMPRESS_FUNCTION: &str = "zstd_decompress";
+
+/// The JSON columns of each table, which hold compressed BLOBs in the database
+#[derive(Default)]
+pub struct CompressedColumns(BTreeMap<String, BTreeSet<String>>);
+
+impl CompressedColumns {
+    /// Turns the JSON columns of the tables into BLOB columns, remembering them
+    pub fn extend_tables(&mut self, tables: &HKTables) -> HKTables {
+        let mut tables = tables.clone();
+        for (table_name, columns) in tables.iter_mut() {
+            for (column_name, ty) in columns.iter_mut() {
+                if *ty == "JSON" {
+                    *ty = "BLOB";
+                    self.insert(table_name, column_name);
+                }
+            }
+        }
+        tables
+    }
+
+    /// Replaces the JSON values of the row with their compressed text
+    pub fn compress_row(&mut self, table_name: &str, row: &mut DatabaseRow) -> anyhow::Result<()> {
+        for (column_name, value) in row.iter_mut() {
+            if let DatabaseValue::Json(json) = value {
+                let compressed = zstd::encode_all(json.to_string().as_bytes(), COMPRESSION_LEVEL)?;
+                *value = DatabaseValue::Blob(compressed);
+                self.insert(table_name, column_name);
+            }
+        }
+        Ok(())
+    }
+
+    fn insert(&mut self, table_name: &str, column_name: &str) {
+        self.0
+            .entry(table_name.to_string())
+            .or_default()
+            .insert(column_name.to_string());
+    }
+
+    /// Creates a view of each table with compressed columns, named with the JSON_VIEW_SUFFIX,
+    /// with the columns decompressed by the zstd_decompress function of the sqlite-zstd
+    /// extension. The tables have been renamed to the casing by the time the views are created.
+    /// Warns if the extension wasn't loaded with --load-extension, as the views can't be queried
+    /// without it.
+    pub async fn create_views(
+        &self,
+        tx: &mut Transaction<'_, Sqlite>,
+        naming: Naming,
+    ) -> anyhow::Result<()> {
+        for (table_name, compressed) in &self.0 {
+            let table_name = naming::convert(naming, table_name);
+            let compressed: BTreeSet<String> = compressed
+                .iter()
+                .map(|column_name| naming::convert(naming, column_name))
+                .collect();
+            let column_names: Vec<String> =
+                sqlx::query_scalar("SELECT name FROM pragma_table_info(?) ORDER BY cid")
+                    .bind(&table_name)
+                    .fetch_all(&mut *tx)
+                    .await?;
+            let columns = column_names
+                .iter()
+                .map(|column_name| {
+                    let column = get_valid_sqlite_identifier(column_name);
+                    if compressed.contains(column_name) {
+                        format!("{0}({1}, 1) AS {1}", DECOMPRESS_FUNCTION, column)
+                    } else {
+                        column
+                    }
+                })
+                .collect::<Vec<_>>();
+            let view_name =
+                get_valid_sqlite_identifier(&format!("{}{}", table_name, JSON_VIEW_SUFFIX));
+            // Recreated, as an appended export can add columns
+            sqlx::query(&format!("DROP VIEW IF EXISTS {}", view_name))
+                .execute(&mut *tx)
+                .await?;
+            sqlx::query(&format!(
+                "CREATE VIEW {} AS SELECT rowid AS rowid, {} FROM {}",
+                view_name,
+                columns.join(", "),
+                get_valid_sqlite_identifier(&table_name)
+            ))
+            .execute(&mut *tx)
+            .await?;
+        }
+        if !self.0.is_empty() && !decompress_function_loaded(tx).await? {
+            warn!(
+                "The {} views need the {} function of the sqlite-zstd extension (https://github.com/phiresky/sqlite-zstd), load it to query them",
+                JSON_VIEW_SUFFIX, DECOMPRESS_FUNCTION
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Returns true if the connection has the zstd_decompress function, i.e. sqlite-zstd was loaded
+async fn decompress_function_loaded(tx: &mut Transaction<'_, Sqlite>) -> anyhow::Result<bool> {
+    Ok(
+        sqlx::query_scalar("SELECT EXISTS (SELECT 1 FROM pragma_function_list WHERE name = ?)")
+            .bind(DECOMPRESS_FUNCTION)
+            .fetch_one(&mut *tx)
+            .await?,
+    )
+}
+
+/// Returns the JSON text of a column value, decompressing it if it was compressed
+pub fn json_text(bytes: &[u8]) -> anyhow::Result<Vec<u8>> {
+    if is_compressed(bytes) {
+        Ok(zstd::decode_all(bytes)?)
+    } else {
+        Ok(bytes.to_vec())
+    }
+}
+
+/// Returns true if the BLOB holds a zstd frame
+pub fn is_compressed(bytes: &[u8]) -> bool {
+    bytes.starts_with(&ZSTD_MAGIC)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use sqlx::SqlitePool;
+
+    const TABLE_NAME: &str = "Workout";
+
+    fn workout() -> DatabaseRow {
+        vec![
+            (
+                "workoutActivityType".to_string(),
+                DatabaseValue::Text("HKWorkoutActivityTypeRunning".to_string()),
+            ),
+            (
+                "geometry".to_string(),
+                DatabaseValue::Json(json!({"type": "LineString", "coordinates": [[1.5, 2.5]]})),
+            ),
+        ]
+    }
+
+    #[test]
+    fn compressed_rows_round_trip() {
+        let mut compressed = CompressedColumns::default();
+        let mut row = workout();
+        compressed.compress_row(TABLE_NAME, &mut row).unwrap();
+        assert!(matches!(&row[0].1, DatabaseValue::Text(_)));
+        let DatabaseValue::Blob(bytes) = &row[1].1 else {
+            panic!("geometry wasn't compressed");
+        };
+        assert!(is_compressed(bytes));
+        let text = json_text(bytes).unwrap();
+        let value: serde_json::Value = serde_json::from_slice(&text).unwrap();
+        assert_eq!(value["coordinates"], json!([[1.5, 2.5]]));
+        assert_eq!(
+            compressed.0[TABLE_NAME],
+            BTreeSet::from(["geometry".to_string()])
+        );
+    }
+
+    #[test]
+    fn uncompressed_json_is_returned_as_is() {
+        let text = br#"{"type":"LineString"}"#;
+        assert!(!is_compressed(text));
+        assert_eq!(json_text(text).unwrap(), text);
+    }
+
+    #[test]
+    fn json_columns_become_blobs() {
+        let mut compressed = CompressedColumns::default();
+        let tables = HKTables::from([(
+            TABLE_NAME.to_string(),
+            BTreeMap::from([
+                ("geometry".to_string(), "JSON"),
+                ("title".to_string(), "TEXT"),
+            ]),
+        )]);
+        let tables = compressed.extend_tables(&tables);
+        assert_eq!(tables[TABLE_NAME]["geometry"], "BLOB");
+        assert_eq!(tables[TABLE_NAME]["title"], "TEXT");
+    }
+
+    #[tokio::test]
+    async fn views_need_sqlite_zstd() {
+        let pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        let mut tx = pool.begin().await.unwrap();
+        sqlx::query("CREATE TABLE Workout (workoutActivityType TEXT, geometry BLOB)")
+            .execute(&mut *tx)
+            .await
+            .unwrap();
+        let mut compressed = CompressedColumns::default();
+        let mut row = workout();
+        compressed.compress_row(TABLE_NAME, &mut row).unwrap();
+        compressed
+            .create_views(&mut tx, Naming::Original)
+            .await
+            .unwrap();
+        assert!(!decompress_function_loaded(&mut tx).await.unwrap());
+        let sql: String =
+            sqlx::query_scalar("SELECT sql FROM sqlite_master WHERE name = 'Workout_json'")
+                .fetch_one(&mut *tx)
+                .await
+                .unwrap();
+        assert!(
+            sql.contains("zstd_decompress(`geometry`, 1) AS `geometry`"),
+            "{}",
+            sql
+        );
+        let error = sqlx::query("SELECT * FROM Workout_json")
+            .fetch_all(&mut *tx)
+            .await
+            .err()
+            .unwrap();
+        assert!(error.to_string().contains("no such function"), "{}", error);
+    }
+}
@@ -0,0 +1,145 @@
+//! User configuration loaded from the JSON file passed with --config
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// Settings that are too detailed to express as command-line flags
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Plausible value ranges per record type, used by --flag-outliers
+    pub outlier_bounds: BTreeMap<String, Bounds>,
+    /// Number of decimal places to round real values to, per table and column
+    pub rounding: BTreeMap<String, BTreeMap<String, i32>>,
+    /// Areas whose workout route points are removed before they are stored
+    pub privacy: PrivacyConfig,
+}
+
+/// Areas, such as around home or work, that workout routes shouldn't reveal
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PrivacyConfig {
+    pub zones: Vec<PrivacyZone>,
+    pub action: ZoneAction,
+}
+
+/// An area given as a circle or a polygon
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "shape", rename_all = "snake_case", deny_unknown_fields)]
+pub enum PrivacyZone {
+    Circle {
+        latitude: f64,
+        longitude: f64,
+        radius_meters: f64,
+    },
+    /// The vertices as [longitude, latitude] pairs, matching GeoJSON
+    Polygon { coordinates: Vec<[f64; 2]> },
+}
+
+/// What happens to the route points inside a privacy zone
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ZoneAction {
+    /// Remove every point inside a zone
+    #[default]
+    Drop,
+    /// Remove the points inside a zone at the start and end of the route, keeping the
+    /// points of routes that only pass through a zone
+    Truncate,
+}
+
+/// An inclusive range of plausible values
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Bounds {
+    pub min: f64,
+    pub max: f64,
+    /// Only apply the bounds to samples recorded in this unit
+    pub unit: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            outlier_bounds: default_outlier_bounds(),
+            rounding: Default::default(),
+            privacy: Default::default(),
+        }
+    }
+}
+
+impl Config {
+    /// Parses the configuration file at the given path
+    pub fn from_path(path: &str) -> anyhow::Result<Self> {
+        let file = std::fs::File::open(Path::new(path))?;
+        Ok(serde_json::from_reader(std::io::BufReader::new(file))?)
+    }
+}
+
+fn default_outlier_bounds() -> BTreeMap<String, Bounds> {
+    [
+        (
+            "HKQuantityTypeIdentifierHeartRate",
+            25.0,
+            250.0,
+            Some("count/min"),
+        ),
+        (
+            "HKQuantityTypeIdentifierRestingHeartRate",
+            25.0,
+            150.0,
+            Some("count/min"),
+        ),
+        (
+            "HKQuantityTypeIdentifierWalkingHeartRateAverage",
+            30.0,
+            200.0,
+            Some("count/min"),
+        ),
+        (
+            "HKQuantityTypeIdentifierHeartRateVariabilitySDNN",
+            1.0,
+            300.0,
+            Some("ms"),
+        ),
+        (
+            "HKQuantityTypeIdentifierOxygenSaturation",
+            0.5,
+            1.0,
+            Some("%"),
+        ),
+        (
+            "HKQuantityTypeIdentifierRespiratoryRate",
+            4.0,
+            60.0,
+            Some("count/min"),
+        ),
+        ("HKQuantityTypeIdentifierVO2Max", 10.0, 100.0, None),
+        // Wide enough to cover both kg and lb
+        ("HKQuantityTypeIdentifierBodyMass", 20.0, 700.0, None),
+        (
+            "HKQuantityTypeIdentifierBodyFatPercentage",
+            0.02,
+            0.7,
+            Some("%"),
+        ),
+        (
+            "HKQuantityTypeIdentifierStepCount",
+            0.0,
+            50000.0,
+            Some("count"),
+        ),
+    ]
+    .into_iter()
+    .map(|(type_name, min, max, unit)| {
+        (
+            type_name.to_string(),
+            Bounds {
+                min,
+                max,
+                unit: unit.map(str::to_string),
+            },
+        )
+    })
+    .collect()
+}
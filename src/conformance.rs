@@ -0,0 +1,153 @@
+//! The conformance command, which imports a miniature export bundled with the binary and checks
+//! the database against the expected results, to confirm a build works on its platform
+use crate::core::{self, ImportStatus};
+use crate::identifier::get_valid_sqlite_identifier;
+use crate::options::ImportOptions;
+use crate::sink::Output;
+use anyhow::Context;
+use console::Term;
+use indicatif::ProgressBar;
+use sqlx::SqlitePool;
+use std::path::Path;
+
+/// The files of the miniature export, by their path in the export directory
+pub(crate) const EXPORT_FILES: &[(&str, &[u8])] = &[
+    (
+        "export.xml",
+        include_bytes!("../conformance/apple_health_export/export.xml"),
+    ),
+    (
+        "export_cda.xml",
+        include_bytes!("../conformance/apple_health_export/export_cda.xml"),
+    ),
+    (
+        "workout-routes/route_2024-01-01_9.30am.gpx",
+        include_bytes!(
+            "../conformance/apple_health_export/workout-routes/route_2024-01-01_9.30am.gpx"
+        ),
+    ),
+    (
+        "electrocardiograms/ecg_2024-01-01.csv",
+        include_bytes!("../conformance/apple_health_export/electrocardiograms/ecg_2024-01-01.csv"),
+    ),
+    (
+        "clinical-records/Observation-1.json",
+        include_bytes!("../conformance/apple_health_export/clinical-records/Observation-1.json"),
+    ),
+];
+
+/// The checks of the imported database, in the format described at the top of the file
+const EXPECTED: &str = include_str!("../conformance/expected.txt");
+
+/// Imports the bundled export with the default options into a temporary SQLite database and runs
+/// each check of the expected results against it, failing if any check fails
+pub async fn conformance() -> anyhow::Result<()> {
+    let dir = std::env::temp_dir().join(format!(
+        "healthkit-to-sqlite-conformance-{}",
+        std::process::id()
+    ));
+    let result = run(&dir).await;
+    // The files are only useful to the run that wrote them
+    let _ = std::fs::remove_dir_all(&dir);
+    result
+}
+
+async fn run(dir: &Path) -> anyhow::Result<()> {
+    let export_dir = dir.join("apple_health_export");
+    for (path, contents) in EXPORT_FILES {
+        let path = export_dir.join(path);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(&path, contents)?;
+    }
+    let db_url = format!("sqlite://{}", dir.join("conformance.db").display());
+    let options = ImportOptions::defaults();
+    let status = core::healthkit_to_sqlite(
+        &Output::Database(db_url.clone()),
+        &export_dir,
+        &options,
+        &ProgressBar::hidden(),
+    )
+    .await
+    .context("Failed to import the conformance export")?;
+    if let ImportStatus::Interrupted(reason) = status {
+        anyhow::bail!("The conformance import {}", reason);
+    }
+    let db = SqlitePool::connect(&db_url).await?;
+    let term = Term::stdout();
+    let mut checks = 0;
+    let mut failures = 0;
+    for line in EXPECTED.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        checks += 1;
+        let (expected, actual) = check(&db, line).await?;
+        if expected == actual {
+            term.write_line(&format!("ok      {}", line))?;
+        } else {
+            failures += 1;
+            term.write_line(&format!("FAILED  {} (found {})", line, actual))?;
+        }
+    }
+    db.close().await;
+    if failures > 0 {
+        anyhow::bail!(
+            "{} of {} conformance checks failed, so this build doesn't import exports as expected on this platform",
+            failures,
+            checks
+        );
+    }
+    term.write_line(&format!("All {} conformance checks passed", checks))?;
+    Ok(())
+}
+
+/// Runs a check, returning the expected and actual results, with "-" for a missing result
+async fn check<'a>(db: &SqlitePool, line: &'a str) -> anyhow::Result<(&'a str, String)> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    let (expected, actual): (&str, Option<String>) = match fields.as_slice() {
+        ["rows", table_name, expected] => {
+            // A missing table is reported as a failed check rather than an error
+            let count: Option<i64> = sqlx::query_scalar(&format!(
+                "SELECT COUNT(*) FROM {}",
+                get_valid_sqlite_identifier(table_name)
+            ))
+            .fetch_one(db)
+            .await
+            .ok();
+            (expected, count.map(|count| count.to_string()))
+        }
+        ["type", table_name, column_name, expected] => {
+            let ty = sqlx::query_scalar("SELECT type FROM pragma_table_info(?) WHERE name = ?")
+                .bind(table_name)
+                .bind(column_name)
+                .fetch_optional(db)
+                .await?;
+            (expected, ty)
+        }
+        ["value", table_name, expression, rowid, expected] => {
+            let value: Option<Option<String>> = sqlx::query_scalar(&format!(
+                "SELECT CAST({} AS TEXT) FROM {} WHERE rowid = ?",
+                expression,
+                get_valid_sqlite_identifier(table_name)
+            ))
+            .bind(rowid.parse::<i64>()?)
+            .fetch_optional(db)
+            .await
+            .with_context(|| format!("Failed to run the conformance check \"{}\"", line))?;
+            (expected, value.flatten())
+        }
+        _ => anyhow::bail!("Unrecognized conformance check \"{}\"", line),
+    };
+    Ok((expected, actual.unwrap_or_else(|| "-".to_string())))
+}
+
+#[cfg(test)]
+mod tests {
+    #[tokio::test]
+    async fn bundled_export_conforms() {
+        super::conformance().await.unwrap();
+    }
+}
@@ -0,0 +1,219 @@
+//! Serializing imports into the same database, for services that convert uploaded exports as
+//! they arrive. Imports are queued in the order they ask for the database, and a lock file next
+//! to the database keeps out imports run by other processes.
+use fs2::FileExt;
+use log::*;
+use std::collections::{HashMap, VecDeque};
+use std::fs::{File, OpenOptions};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+
+/// How often the lock file is tried while another process holds it
+const LOCK_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Where an import waiting for the database stands
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueuePosition {
+    /// Waiting for the given number of imports of this process to finish, the first of which may
+    /// be running. The estimate of the time until the import starts is the average duration of
+    /// the earlier imports into the database, once one has finished.
+    Queued { ahead: usize, eta: Option<Duration> },
+    /// Waiting for an import run by another process to release the lock file
+    Locked,
+}
+
+/// Serializes the imports into each database. Clones share the queues.
+#[derive(Clone, Default)]
+pub struct ImportCoordinator {
+    queues: Arc<Mutex<HashMap<PathBuf, Queue>>>,
+    /// Woken whenever an import leaves a queue
+    changed: Arc<Notify>,
+}
+
+/// The imports of a database, removed once none is waiting or running, which also resets the
+/// durations the estimates are based on
+#[derive(Default)]
+struct Queue {
+    /// Ids of the imports waiting for the database, in the order they asked for it
+    waiting: VecDeque<u64>,
+    /// When the running import started, if one is running
+    running: Option<Instant>,
+    next_id: u64,
+    finished: u32,
+    total_duration: Duration,
+}
+
+impl Queue {
+    fn position(&self, id: u64) -> QueuePosition {
+        let waiting_ahead = self
+            .waiting
+            .iter()
+            .position(|waiting| *waiting == id)
+            .unwrap_or(0);
+        let ahead = waiting_ahead + usize::from(self.running.is_some());
+        let eta = (self.finished > 0).then(|| {
+            let average = self.total_duration / self.finished;
+            let running_elapsed = self
+                .running
+                .map_or(Duration::ZERO, |started| started.elapsed());
+            (average * ahead as u32).saturating_sub(running_elapsed)
+        });
+        QueuePosition::Queued { ahead, eta }
+    }
+}
+
+impl ImportCoordinator {
+    /// Waits until the import may write to the database at the path, calling on_wait whenever
+    /// its position changes while it waits. The database is the import's until the returned slot
+    /// is dropped.
+    pub async fn acquire(
+        &self,
+        database_path: &Path,
+        mut on_wait: impl FnMut(QueuePosition),
+    ) -> anyhow::Result<ImportSlot> {
+        let key = database_path.to_path_buf();
+        let id = {
+            let mut queues = self.queues.lock().expect("not poisoned");
+            let queue = queues.entry(key.clone()).or_default();
+            queue.next_id += 1;
+            queue.waiting.push_back(queue.next_id);
+            queue.next_id
+        };
+        // Leaves the queue if the caller stops waiting or the lock file can't be taken
+        let mut slot = ImportSlot {
+            coordinator: self.clone(),
+            key,
+            id,
+            started: None,
+            _lock_file: None,
+        };
+        let mut last_position = None;
+        loop {
+            // Created before the queue is checked, so a change made after the check isn't missed
+            let changed = self.changed.notified();
+            {
+                let mut queues = self.queues.lock().expect("not poisoned");
+                let queue = queues.get_mut(&slot.key).expect("queued above");
+                if queue.running.is_none() && queue.waiting.front() == Some(&id) {
+                    queue.waiting.pop_front();
+                    queue.running = Some(Instant::now());
+                    slot.started = queue.running;
+                    break;
+                }
+                let position = queue.position(id);
+                if last_position != Some(position) {
+                    last_position = Some(position);
+                    on_wait(position);
+                }
+            }
+            changed.await;
+        }
+        slot._lock_file = Some(lock_file(database_path, &mut on_wait).await?);
+        Ok(slot)
+    }
+}
+
+/// Takes the lock file of the database, waiting for any other process holding it
+async fn lock_file(
+    database_path: &Path,
+    on_wait: &mut impl FnMut(QueuePosition),
+) -> anyhow::Result<File> {
+    let mut path = database_path.as_os_str().to_owned();
+    path.push(".lock");
+    let file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(false)
+        .open(&path)?;
+    let mut waiting = false;
+    while let Err(e) = file.try_lock_exclusive() {
+        if e.kind() != fs2::lock_contended_error().kind() {
+            return Err(e.into());
+        }
+        if !waiting {
+            debug!("Waiting for the lock on {:?}", path);
+            waiting = true;
+            on_wait(QueuePosition::Locked);
+        }
+        tokio::time::sleep(LOCK_POLL_INTERVAL).await;
+    }
+    Ok(file)
+}
+
+/// The turn of an import to write to a database, which ends when it is dropped
+pub struct ImportSlot {
+    coordinator: ImportCoordinator,
+    key: PathBuf,
+    id: u64,
+    /// When the import's turn began, or None while it waits
+    started: Option<Instant>,
+    /// Unlocked when closed
+    _lock_file: Option<File>,
+}
+
+impl Drop for ImportSlot {
+    fn drop(&mut self) {
+        let mut queues = self.coordinator.queues.lock().expect("not poisoned");
+        if let Some(queue) = queues.get_mut(&self.key) {
+            match self.started {
+                Some(started) => {
+                    queue.running = None;
+                    queue.finished += 1;
+                    queue.total_duration += started.elapsed();
+                }
+                None => queue.waiting.retain(|id| *id != self.id),
+            }
+            // Otherwise a long-running service would keep a queue for every database it imported
+            if queue.running.is_none() && queue.waiting.is_empty() {
+                queues.remove(&self.key);
+            }
+        }
+        self.coordinator.changed.notify_waiters();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn queues_are_removed_once_idle() {
+        let dir = std::env::temp_dir().join(format!(
+            "healthkit-to-sqlite-coordinator-{}",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        let database_path = dir.join("healthkit.db");
+        let coordinator = ImportCoordinator::default();
+        let first = coordinator.acquire(&database_path, |_| {}).await.unwrap();
+        let mut positions = Vec::new();
+        let second = {
+            let coordinator = coordinator.clone();
+            let database_path = database_path.clone();
+            tokio::spawn(async move {
+                coordinator
+                    .acquire(&database_path, |position| positions.push(position))
+                    .await
+                    .map(|slot| (slot, positions))
+            })
+        };
+        // Lets the second import queue behind the first
+        tokio::task::yield_now().await;
+        assert_eq!(coordinator.queues.lock().unwrap().len(), 1);
+        drop(first);
+        let (second, positions) = second.await.unwrap().unwrap();
+        assert_eq!(
+            positions,
+            [QueuePosition::Queued {
+                ahead: 1,
+                eta: None
+            }]
+        );
+        assert_eq!(coordinator.queues.lock().unwrap().len(), 1);
+        drop(second);
+        assert!(coordinator.queues.lock().unwrap().is_empty());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
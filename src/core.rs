@@ -1,106 +1,1175 @@
+use crate::anonymize::Anonymizer;
+use crate::blob::{
+    self, Blob, BLOB_COLUMN, BLOB_TABLE_NAME, CDA_BLOB_COLUMN, CDA_MIME_TYPE, ECG_MIME_TYPE,
+    ROUTE_BLOB_COLUMN,
+};
+use crate::cache::{self, CacheReader, CacheWriter};
+use crate::checkpoint::{Checkpoint, CHECKPOINT_ELEMENTS};
+use crate::clinical;
+use crate::clock::Clock;
+use crate::device::{DeviceTable, DEVICE_TABLE_NAME};
+use crate::ecg::{self, ECG_ID_COLUMN, ECG_SAMPLE_TABLE_NAME, ECG_TABLE_NAME};
+use crate::expectations::ExpectationsWriter;
+use crate::geojson::GeoJsonWriter;
+use crate::heartbeat::{self, HEARTBEAT_TABLE_NAME};
+use crate::identifier;
+use crate::interrupt::Interrupt;
+use crate::meta::{self, EXPORT_METADATA_TABLE_NAME, META_TABLE_NAME};
+use crate::metadata;
+use crate::naming::{self, Renames};
+use crate::options::{ImportOptions, KeepRaw, Naming};
+use crate::perf_history::{PerfHistory, PERF_HISTORY_TABLE_NAME};
+use crate::provenance::{SchemaProvenance, PROVENANCE_TABLE_NAME};
+use crate::quota::{self, Quota};
+use crate::record_metadata::{self, RECORD_METADATA_TABLE_NAME};
+use crate::route::{self, Route, RouteReaderPool, RouteResult};
+use crate::row_key::{self, ROW_KEY_COLUMN};
+use crate::schema::{self, ColumnAliases};
+use crate::single_table::{self, SAMPLES_TABLE_NAME};
+use crate::sink::{Output, Sink, REPLICATION_TRANSACTION_ROWS};
+use crate::source::{
+    ExportArchive, ExportSource, CLINICAL_RECORDS_DIRECTORY_NAME, ELECTROCARDIOGRAMS_DIRECTORY_NAME,
+};
+use crate::transform::{self, DateColumns};
+use crate::units::{self, UnitCheck, UnitNormalizer, UNITS_TABLE_NAME};
+use crate::unknown_types::UnknownTypes;
+use crate::workout_pace;
+use crate::workout_title::{self, TITLE_COLUMN};
+use indicatif::{ProgressBar, ProgressStyle};
 use log::*;
 use quick_xml::events::{BytesStart, Event};
-use sqlx::migrate::MigrateDatabase;
+use serde::{Deserialize, Serialize};
 use sqlx::types::JsonValue;
-use sqlx::{Sqlite, SqlitePool, Transaction};
 use std::borrow::Cow;
-use std::collections::BTreeMap;
-use std::fs::File;
-use std::io::{BufRead, BufReader, Seek};
-use std::path::PathBuf;
-use time::{format_description, Date, OffsetDateTime};
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::io::{BufRead, BufReader, Read};
+use std::path::Path;
+use std::time::{Duration, Instant};
+use time::format_description::well_known::Rfc3339;
+use time::format_description::FormatItem;
+use time::macros::format_description;
+use time::{Date, OffsetDateTime};
+use tokio::sync::oneshot::{self, error::TryRecvError};
 
-lazy_static::lazy_static! {
-    static ref HEALTHKIT_OFFSET_DATE_FORMAT_STR: &'static str =
-        "[year]-[month]-[day] [hour]:[minute]:[second] [offset_hour sign:mandatory][offset_minute]";
-    static ref HEALTHKIT_OFFSET_DATE_FORMAT : Vec<format_description::FormatItem<'static>> = format_description::parse(&HEALTHKIT_OFFSET_DATE_FORMAT_STR).expect("format parse");
-    static ref HEALTHKIT_DATE_FORMAT_STR: &'static str =
-        "[year]-[month]-[day]";
-    static ref HEALTHKIT_DATE_FORMAT : Vec<format_description::FormatItem<'static>> = format_description::parse(&HEALTHKIT_DATE_FORMAT_STR).expect("format parse");
+pub(crate) const HEALTHKIT_OFFSET_DATE_FORMAT: &[FormatItem<'static>] = format_description!(
+    "[year]-[month]-[day] [hour]:[minute]:[second] [offset_hour sign:mandatory][offset_minute]"
+);
+pub(crate) const HEALTHKIT_DATE_FORMAT: &[FormatItem<'static>] =
+    format_description!("[year]-[month]-[day]");
 
+lazy_static::lazy_static! {
     // Static table names
-    static ref WORKOUT_TABLE_NAME: &'static str = "Workout";
+    pub(crate) static ref WORKOUT_TABLE_NAME: &'static str = "Workout";
+    static ref WORKOUT_EVENT_TABLE_NAME: &'static str = "WorkoutEvent";
+    static ref WORKOUT_STATISTICS_TABLE_NAME: &'static str = "WorkoutStatistics";
+    static ref WORKOUT_ACTIVITY_TABLE_NAME: &'static str = "WorkoutActivity";
     static ref ACTIVITY_SUMMARY_TABLE_NAME: &'static str = "ActivitySummary";
+    static ref ME_TABLE_NAME: &'static str = "Me";
 }
 
+/// Column of the tables normalized from Workout child elements referring to the parent Workout row
+pub(crate) const WORKOUT_ID_COLUMN: &str = "workout_id";
+
+/// Primary key column of the Workout table of a SQLite database, an alias of its rowid, which
+/// the workout_id columns of its child tables reference
+pub(crate) const WORKOUT_KEY_COLUMN: &str = "id";
+
+/// Column of the tables normalized from Record child elements referring to the parent record row
+pub(crate) const RECORD_ID_COLUMN: &str = "record_id";
+
+/// Child elements of Record elements and the tables their rows are inserted to
+const RECORD_CHILD_TABLES: &[(&[u8], &str)] = &[
+    (b"SensitivityPoint", "AudiogramSensitivityPoint"),
+    (b"InstantaneousBeatsPerMinute", HEARTBEAT_TABLE_NAME),
+];
+
+/// Prefix of the columns holding the value of a MetadataEntry element
+pub(crate) const METADATA_COLUMN_PREFIX: &str = "metadata_";
+
+/// Column of the export.xml tables holding, with --keep-raw=json, a JSON object of the attribute
+/// and metadata strings that were parsed to numbers or dates, keyed by column name
+pub(crate) const RAW_VALUES_COLUMN: &str = "_raw";
+
 /// A map of table names to a map of column names to SQL types
-type HKTables = BTreeMap<String, BTreeMap<String, &'static str>>;
+pub(crate) type HKTables = BTreeMap<String, BTreeMap<String, &'static str>>;
 /// A list of (column name, value) tuples for insertion into a database table
-type DatabaseRow = Vec<(String, DatabaseValue)>;
+pub(crate) type DatabaseRow = Vec<(String, DatabaseValue)>;
 
 /// A typed value for insertion into the database
-#[derive(Debug)]
-enum DatabaseValue {
-    Integer(i32),
-    Real(f32),
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) enum DatabaseValue {
+    Integer(i64),
+    Real(f64),
     OffsetDateTime(OffsetDateTime),
     Date(Date),
     Text(String),
-    Json(JsonValue),
+    Json(#[serde(with = "json_text")] JsonValue),
+    Blob(Vec<u8>),
+}
+
+/// Serializes JSON values as text, as the binary cache format can't represent arbitrary JSON
+mod json_text {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use sqlx::types::JsonValue;
+
+    pub fn serialize<S: Serializer>(value: &JsonValue, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&value.to_string())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<JsonValue, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        serde_json::from_str(&text).map_err(serde::de::Error::custom)
+    }
+}
+
+/// A top-level element of the export, parsed into the rows it is written as.
+/// Options that only change how elements are written, such as the privacy zones, are applied
+/// when the element is written so a cached parse can be reused with different options.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) enum ParsedElement {
+    /// A single row, e.g. from a Record or ActivitySummary element
+    Row {
+        table_name: String,
+        row: DatabaseRow,
+    },
+    /// A Workout row with the rows of its child elements and its route
+    Workout {
+        row: DatabaseRow,
+        children: Vec<(String, DatabaseRow)>,
+        route: Option<Route>,
+    },
+    /// An Ecg row with the voltage samples of the recording, and the CSV file with --blobs
+    Ecg {
+        row: DatabaseRow,
+        samples: Vec<f64>,
+        blob: Option<Blob>,
+    },
+    /// A Record row with the rows of its child elements, e.g. the sensitivity points of an
+    /// audiogram. Records without child elements are written as a Row.
+    Record {
+        table_name: String,
+        row: DatabaseRow,
+        children: Vec<(String, DatabaseRow)>,
+    },
+}
+
+impl DatabaseValue {
+    /// Returns the SQLite type of a column holding the value
+    pub(crate) fn database_type(&self) -> &'static str {
+        match self {
+            DatabaseValue::Integer(_) => "INTEGER",
+            DatabaseValue::Real(_) => "REAL",
+            DatabaseValue::OffsetDateTime(_) | DatabaseValue::Date(_) => "DATE",
+            DatabaseValue::Text(_) => "TEXT",
+            DatabaseValue::Json(_) => "JSON",
+            DatabaseValue::Blob(_) => "BLOB",
+        }
+    }
+
+    /// Converts the value to JSON, with numbers as JSON numbers and everything else as text
+    pub(crate) fn to_json(&self) -> JsonValue {
+        match self {
+            DatabaseValue::Integer(i) => JsonValue::from(*i),
+            DatabaseValue::Real(r) => JsonValue::from(*r),
+            DatabaseValue::Json(j) => j.clone(),
+            other => JsonValue::from(other.to_text()),
+        }
+    }
+
+    /// Formats the value as text, matching the representation stored in SQLite
+    pub(crate) fn to_text(&self) -> String {
+        match self {
+            DatabaseValue::Integer(i) => i.to_string(),
+            DatabaseValue::Real(i) => i.to_string(),
+            DatabaseValue::OffsetDateTime(i) => {
+                i.format(&Rfc3339).unwrap_or_else(|_| i.to_string())
+            }
+            DatabaseValue::Date(i) => i.to_string(),
+            DatabaseValue::Text(i) => i.clone(),
+            DatabaseValue::Json(i) => i.to_string(),
+            // Hex, as in the X'...' literals of SQL
+            DatabaseValue::Blob(bytes) => bytes.iter().map(|b| format!("{:02x}", b)).collect(),
+        }
+    }
+}
+
+/// Number of parsed workouts held while waiting for their routes before parsing waits for the
+/// oldest route to be read
+const MAX_PENDING_WORKOUTS: usize = 256;
+
+/// A parsed Workout whose route is still being read by the route reader pool
+struct PendingWorkout {
+    row: DatabaseRow,
+    children: Vec<(String, DatabaseRow)>,
+    route: Option<oneshot::Receiver<RouteResult>>,
+}
+
+/// Where export.xml stops being readable, found by the schema pass. Exports copied through
+/// iCloud are commonly cut short, leaving a zip or XML file that ends partway through.
+pub(crate) struct Truncation {
+    /// Byte offset in export.xml of the end of the last element known to be complete
+    complete_until: usize,
+    /// Uncompressed size of export.xml in bytes
+    size: u64,
+    /// Why the rest of export.xml couldn't be read
+    reason: String,
+}
+
+/// Byte offsets in export.xml of the top-level elements most recently read by the schema pass
+#[derive(Default)]
+struct ElementOffsets {
+    current: usize,
+    previous: usize,
+}
+
+/// State shared by the passes over a single export
+pub(crate) struct ImportContext {
+    sink: Sink,
+    options: ImportOptions,
+    column_aliases: ColumnAliases,
+    /// The date columns given a raw column when --keep-raw=dates is set
+    date_columns: DateColumns,
+    /// Tables of samples, whose rows are given a row_key
+    keyed_tables: HashSet<String>,
+    /// Set when --anonymize is given
+    anonymizer: Option<Anonymizer>,
+    /// Collects the data contract when --expectations is given
+    expectations: Option<ExpectationsWriter>,
+    provenance: SchemaProvenance,
+    /// Records the parsed export when --cache is set and no cached parse exists
+    cache: Option<CacheWriter>,
+    /// Writes each route to a file when --geojson-dir is set
+    geojson: Option<GeoJsonWriter>,
+    /// Workouts waiting for their route, in the order they appear in the export
+    pending_workouts: VecDeque<PendingWorkout>,
+    /// Set when export.xml is truncated or corrupt, in which case only its readable part is imported
+    truncation: Option<Truncation>,
+    /// Number of rows written, excluding rows skipped as duplicates
+    rows_written: u64,
+    interrupt: Interrupt,
+    /// Set when the import stopped early because of the interrupt
+    interruption: Option<Interruption>,
+    /// The export format version, locale, and ExportDate, written to export_metadata once the
+    /// HealthData elements are read
+    export_header: DatabaseRow,
+    /// Shows the bytes of export.xml read by both passes
+    progress: ProgressBar,
+    /// How far the insert pass has got through export.xml, once it has begun
+    checkpoint: Option<Checkpoint>,
+    /// The checkpoint the import continues from when --resume is given
+    resume_from: Option<Checkpoint>,
+    /// Times the phases and inserts when --perf-history is given
+    perf_history: Option<PerfHistory>,
+    unknown_types: UnknownTypes,
+    /// Counts the units of each quantity table, to warn about tables with mixed units
+    unit_check: UnitCheck,
+    /// Converts the values of quantity tables to their canonical units with --normalize-units
+    unit_normalizer: Option<UnitNormalizer>,
+    /// Numbers the distinct devices with --device-table
+    devices: Option<DeviceTable>,
+    /// The table and column names of --naming
+    renames: Renames,
+    /// The record table the insert pass is writing and the rows written to it so far, logged
+    /// when the pass moves on to the next table
+    current_table: Option<(String, u64)>,
+    /// The hashes of the files written to the `_blobs` table by this import, with --blobs
+    blob_hashes: HashSet<String>,
+    /// export_cda.xml, written with the export_metadata row when --blobs is set
+    cda_blob: Option<Blob>,
+}
+
+/// Where an import stopped after being interrupted
+struct Interruption {
+    reason: String,
+    /// Byte offset in export.xml of the end of the last element imported, or None if every
+    /// element of export.xml was imported
+    export_xml_position: Option<usize>,
+}
+
+/// How an import ended
+pub enum ImportStatus {
+    Complete,
+    /// Stopped early by Ctrl-C, SIGTERM, or the timeout, with the reason. The rows written before
+    /// it stopped are kept.
+    Interrupted(String),
+}
+
+impl ImportContext {
+    /// Returns true if the import has been asked to stop, recording where it stopped
+    fn interrupted(&mut self, export_xml_position: Option<usize>) -> bool {
+        if self.interruption.is_none() {
+            if let Some(reason) = self.interrupt.reason() {
+                self.interruption = Some(Interruption {
+                    reason,
+                    export_xml_position,
+                });
+            }
+        }
+        self.interruption.is_some()
+    }
+
+    /// Records the time since the phase started when --perf-history is given
+    fn record_phase(&mut self, phase: &'static str, started: Instant) {
+        if let Some(perf_history) = &mut self.perf_history {
+            perf_history.record_phase(phase, started.elapsed());
+        }
+    }
+
+    /// Counts a top-level element of export.xml as imported, committing the rows written so far
+    /// along with a checkpoint every CHECKPOINT_ELEMENTS elements
+    async fn element_imported(&mut self, export_xml_position: usize) -> anyhow::Result<()> {
+        let due = match &mut self.checkpoint {
+            Some(checkpoint) => {
+                checkpoint.element_index += 1;
+                checkpoint.export_xml_position = export_xml_position;
+                checkpoint.element_index % CHECKPOINT_ELEMENTS == 0
+            }
+            None => false,
+        };
+        if due {
+            // Workouts still waiting for their route are before the checkpoint
+            self.write_ready_workouts(0).await?;
+            self.write_checkpoint().await?;
+        }
+        Ok(())
+    }
+
+    /// Counts an attachment imported after export.xml, returning true if the import being
+    /// resumed already committed it, in which case it's skipped
+    fn attachment_committed(&mut self) -> bool {
+        let index = match &mut self.checkpoint {
+            Some(checkpoint) => {
+                checkpoint.attachments_imported += 1;
+                checkpoint.attachments_imported
+            }
+            None => return false,
+        };
+        self.resume_from
+            .is_some_and(|resume_from| index <= resume_from.attachments_imported)
+    }
+
+    async fn write_checkpoint(&mut self) -> anyhow::Result<()> {
+        if let Some(mut checkpoint) = self.checkpoint {
+            checkpoint.rows_imported = self.rows_written;
+            debug!("Checkpointing the import at {:?}", checkpoint);
+            self.sink.checkpoint(&checkpoint).await?;
+        }
+        Ok(())
+    }
+
+    /// Creates the output tables, including any columns added by the row transforms
+    async fn create_tables(&mut self, mut tables: HKTables) -> anyhow::Result<()> {
+        if let Some(cache) = &mut self.cache {
+            cache.write_tables(&tables)?;
+        }
+        self.unknown_types.check_tables(&mut tables)?;
+        if let Some(anonymizer) = &self.anonymizer {
+            anonymizer.remove_columns(&mut tables);
+        }
+        let has_routes = tables
+            .get(*WORKOUT_TABLE_NAME)
+            .is_some_and(|columns| columns.contains_key("geometry"));
+        if has_routes {
+            let columns = tables.get_mut(*WORKOUT_TABLE_NAME).expect("cant fail");
+            columns.insert(route::ROUTE_PROPERTIES_COLUMN.to_string(), "JSON");
+            if self.options.route_points {
+                route::extend_tables(&mut tables);
+            }
+        }
+        tables.extend(meta::export_metadata_schema());
+        if self.options.blobs {
+            blob::extend_tables(&mut tables, &WORKOUT_TABLE_NAME);
+        }
+        if self.options.normalize_units {
+            units::extend_tables(&mut tables);
+        }
+        self.column_aliases = schema::merge_case_insensitive_columns(&mut tables);
+        identifier::rename_columns(&self.options, &mut tables, &mut self.column_aliases)?;
+        self.provenance = SchemaProvenance::from_tables(&tables);
+        self.date_columns = transform::extend_tables(&mut tables, &self.options);
+        workout_title::extend_tables(&mut tables, &WORKOUT_TABLE_NAME);
+        workout_pace::extend_tables(&mut tables, &WORKOUT_TABLE_NAME);
+        row_key::extend_tables(&mut tables);
+        self.keyed_tables = tables
+            .iter()
+            .filter(|(_, columns)| columns.contains_key(ROW_KEY_COLUMN))
+            .map(|(table_name, _)| table_name.clone())
+            .collect();
+        if self.options.device_table {
+            DeviceTable::extend_tables(&mut tables);
+        }
+        self.provenance.add_synthesized(&tables);
+        if self.options.device_table {
+            tables.extend(DeviceTable::table_schema());
+        }
+        if self.options.metadata_table {
+            record_metadata::extend_tables(&mut tables);
+            tables.extend(record_metadata::table_schema());
+        }
+        if self.options.single_table {
+            tables = single_table::merge_tables(tables);
+        }
+        // Everything but the output keeps the full names, e.g. to match the configuration
+        if self.options.short_names {
+            tables = schema::shorten_table_names(tables)?;
+        }
+        tables = identifier::rename_tables(&self.options, tables)?;
+        if let Some(expectations) = &mut self.expectations {
+            expectations.add_tables(&tables);
+        }
+        self.create_output_tables(tables).await
+    }
+
+    /// Creates the tables in the output, prefixed with the --tenant ID and with the names of
+    /// --naming, unless the sink renames the tables once the import finishes
+    async fn create_output_tables(&mut self, tables: HKTables) -> anyhow::Result<()> {
+        self.renames.add_tables(self.options.naming, &tables)?;
+        let tables = if self.sink.renames_on_finish() {
+            tables
+        } else {
+            self.renames.rename_tables(tables)
+        };
+        let tables: HKTables = tables
+            .into_iter()
+            .map(|(table_name, columns)| {
+                let table_name = self.options.tenant_table_name(&table_name).into_owned();
+                (table_name, columns)
+            })
+            .collect();
+        self.sink.create_tables(&tables).await
+    }
+
+    /// Deletes every row of the output table, see create_output_tables
+    async fn clear_output_table(&mut self, table_name: &str) -> anyhow::Result<()> {
+        let table_name = if self.sink.renames_on_finish() {
+            table_name
+        } else {
+            self.renames.table_name(table_name)
+        };
+        let table_name = self.options.tenant_table_name(table_name);
+        self.sink.clear_table(&table_name).await
+    }
+
+    /// Writes a row to the output table, see create_output_tables
+    async fn insert_output_row(
+        &mut self,
+        table_name: &str,
+        row: DatabaseRow,
+    ) -> anyhow::Result<Option<i64>> {
+        let (table_name, row) = if self.sink.renames_on_finish() {
+            (table_name, row)
+        } else {
+            let row = self.renames.rename_row(table_name, row);
+            (self.renames.table_name(table_name), row)
+        };
+        let table_name = self.options.tenant_table_name(table_name);
+        self.sink.insert(&table_name, row).await
+    }
+
+    /// Writes the rows of a parsed element
+    async fn write(&mut self, element: ParsedElement) -> anyhow::Result<()> {
+        if let Some(cache) = &mut self.cache {
+            cache.write_element(&element)?;
+        }
+        match element {
+            ParsedElement::Row {
+                table_name,
+                mut row,
+            } => {
+                // The Me table holds the single profile of the latest export
+                if table_name == *ME_TABLE_NAME {
+                    let me_table_name = output_table_name(&self.options, &table_name).into_owned();
+                    self.clear_output_table(&me_table_name).await?;
+                }
+                if table_name == EXPORT_METADATA_TABLE_NAME {
+                    row.extend([
+                        (
+                            "toolVersion".to_string(),
+                            DatabaseValue::Text(env!("CARGO_PKG_VERSION").to_string()),
+                        ),
+                        (
+                            "importedAt".to_string(),
+                            DatabaseValue::OffsetDateTime(self.options.clock().now()),
+                        ),
+                    ]);
+                    if let Some(cda) = self.cda_blob.take() {
+                        row.push(cda.reference(CDA_BLOB_COLUMN));
+                        if self.insert(&table_name, row).await?.is_some() {
+                            self.write_blob(cda).await?;
+                        }
+                        return Ok(());
+                    }
+                }
+                self.insert(&table_name, row).await?;
+            }
+            ParsedElement::Workout {
+                mut row,
+                mut children,
+                route,
+            } => {
+                let mut gpx = None;
+                if let Some(Route { points, gpx: blob }) = route {
+                    if let Some(blob) = blob {
+                        row.push(blob.reference(ROUTE_BLOB_COLUMN));
+                        gpx = Some(blob);
+                    }
+                    let mut points =
+                        route::apply_privacy_zones(points, &self.options.config().privacy);
+                    if let Some(max_meters) = self.options.fuzz_routes {
+                        route::fuzz_route(&mut points, max_meters);
+                    }
+                    let geometry = route::geometry(&points);
+                    row.push((
+                        route::ROUTE_PROPERTIES_COLUMN.to_string(),
+                        DatabaseValue::Json(route::properties(&points)?),
+                    ));
+                    if let Some(geojson) = &mut self.geojson {
+                        match &self.anonymizer {
+                            Some(anonymizer) => {
+                                if let Some(row) =
+                                    anonymizer.anonymize_row(&WORKOUT_TABLE_NAME, row.clone())
+                                {
+                                    geojson.write(&row, &geometry)?;
+                                }
+                            }
+                            None => geojson.write(&row, &geometry)?,
+                        }
+                    }
+                    row.push(("geometry".to_string(), DatabaseValue::Json(geometry)));
+                    if self.options.route_points {
+                        children.extend(points.iter().map(|point| {
+                            (route::ROUTE_POINT_TABLE_NAME.to_string(), point.to_row())
+                        }));
+                    }
+                }
+                if let Some(title) = workout_title::title(&row) {
+                    row.push((TITLE_COLUMN.to_string(), DatabaseValue::Text(title)));
+                }
+                let statistics = children
+                    .iter()
+                    .filter(|(table_name, _)| table_name == *WORKOUT_STATISTICS_TABLE_NAME)
+                    .map(|(_, statistics)| statistics);
+                let derived = workout_pace::derived_columns(&row, statistics);
+                row.extend(derived);
+                // Children of a workout skipped as a duplicate were imported along with it
+                if let Some(workout_id) = self.insert(&WORKOUT_TABLE_NAME, row).await? {
+                    if let Some(gpx) = gpx {
+                        self.write_blob(gpx).await?;
+                    }
+                    let workout_id = DatabaseValue::Integer(workout_id);
+                    for (table_name, mut child) in children {
+                        child.push((WORKOUT_ID_COLUMN.to_string(), workout_id.clone()));
+                        self.insert(&table_name, child).await?;
+                    }
+                }
+            }
+            ParsedElement::Ecg {
+                mut row,
+                samples,
+                blob,
+            } => {
+                if let Some(blob) = &blob {
+                    row.push(blob.reference(BLOB_COLUMN));
+                }
+                if let Some(ecg_id) = self.insert(ECG_TABLE_NAME, row).await? {
+                    if let Some(blob) = blob {
+                        self.write_blob(blob).await?;
+                    }
+                    let ecg_id = DatabaseValue::Integer(ecg_id);
+                    for (index, microvolts) in samples.into_iter().enumerate() {
+                        let mut sample = ecg::sample_row(index, microvolts)?;
+                        sample.push((ECG_ID_COLUMN.to_string(), ecg_id.clone()));
+                        self.insert(ECG_SAMPLE_TABLE_NAME, sample).await?;
+                    }
+                }
+            }
+            ParsedElement::Record {
+                table_name,
+                row,
+                children,
+            } => {
+                if let Some(record_id) = self.insert(&table_name, row).await? {
+                    let record_id = DatabaseValue::Integer(record_id);
+                    for (child_table_name, mut child) in children {
+                        child.push((RECORD_ID_COLUMN.to_string(), record_id.clone()));
+                        self.insert(&child_table_name, child).await?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes a file to the `_blobs` table, unless this import already wrote a file with the same
+    /// contents
+    async fn write_blob(&mut self, blob: Blob) -> anyhow::Result<()> {
+        if self.blob_hashes.insert(blob.hash.clone()) {
+            self.insert(BLOB_TABLE_NAME, blob.into_row()).await?;
+        }
+        Ok(())
+    }
+
+    /// Queues a workout until its route has been read, writing any workouts that are ready
+    async fn write_workout(&mut self, workout: PendingWorkout) -> anyhow::Result<()> {
+        self.pending_workouts.push_back(workout);
+        self.write_ready_workouts(MAX_PENDING_WORKOUTS).await
+    }
+
+    /// Writes the queued workouts whose route has been read, in order, waiting for the oldest
+    /// routes until no more than max_pending workouts remain queued
+    async fn write_ready_workouts(&mut self, max_pending: usize) -> anyhow::Result<()> {
+        while let Some(mut workout) = self.pending_workouts.pop_front() {
+            let route = match workout.route.take() {
+                None => None,
+                Some(mut receiver) => match receiver.try_recv() {
+                    Ok(route) => Some(route?),
+                    Err(TryRecvError::Empty) if self.pending_workouts.len() >= max_pending => {
+                        Some(receiver.await??)
+                    }
+                    Err(TryRecvError::Empty) => {
+                        workout.route = Some(receiver);
+                        self.pending_workouts.push_front(workout);
+                        break;
+                    }
+                    Err(TryRecvError::Closed) => {
+                        anyhow::bail!("The route reader stopped before reading a workout route")
+                    }
+                },
+            };
+            self.write(ParsedElement::Workout {
+                row: workout.row,
+                children: workout.children,
+                route,
+            })
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// Transforms and writes a single row to the specified table, returning the id of the row
+    async fn insert(&mut self, table_name: &str, row: DatabaseRow) -> anyhow::Result<Option<i64>> {
+        if !self.unknown_types.observe(table_name) {
+            return Ok(None);
+        }
+        let mut row = match &self.anonymizer {
+            Some(anonymizer) => match anonymizer.anonymize_row(table_name, row) {
+                Some(row) => row,
+                None => return Ok(None),
+            },
+            None => row,
+        };
+        // Keyed on the values of the export, before any transforms other than --anonymize change
+        // them, so the keys of anonymized rows are hashes of their pseudonyms
+        if self.keyed_tables.contains(table_name) {
+            let key = row_key::row_key(table_name, &row);
+            row.push((ROW_KEY_COLUMN.to_string(), DatabaseValue::Text(key)));
+        }
+        let row = schema::rename_row(table_name, row, &self.column_aliases);
+        if self.options.exclude_user_entered && metadata::is_user_entered(&row) {
+            // Skipped like a duplicate, so the children of the row are skipped too
+            return Ok(None);
+        }
+        // Before rounding, so the converted values are rounded
+        let row = match &mut self.unit_normalizer {
+            Some(unit_normalizer) => unit_normalizer.normalize_row(table_name, row),
+            None => row,
+        };
+        let row = transform::transform_row(table_name, row, &self.options, &self.date_columns);
+        self.provenance.observe(table_name, &row);
+        self.unit_check.observe(table_name, &row);
+        let row = match &mut self.devices {
+            Some(devices) => {
+                let (row, device_row) = devices.replace_device(row);
+                if let Some(device_row) = device_row {
+                    let device_table_name = output_table_name(&self.options, DEVICE_TABLE_NAME);
+                    self.insert_output_row(&device_table_name, device_row)
+                        .await?;
+                }
+                row
+            }
+            None => row,
+        };
+        let (row, metadata) = if self.options.metadata_table {
+            record_metadata::split_row(row)
+        } else {
+            (row, Vec::new())
+        };
+        let record_table_name = single_table::is_record_table(table_name).then_some(table_name);
+        let row = if self.options.single_table && record_table_name.is_some() {
+            single_table::samples_row(row)
+        } else {
+            row
+        };
+        let table_name = output_table_name(&self.options, table_name).into_owned();
+        if let Some(expectations) = &mut self.expectations {
+            expectations.observe(&table_name, &row);
+        }
+        let started = Instant::now();
+        let id = self.insert_output_row(&table_name, row).await?;
+        if let Some(perf_history) = &mut self.perf_history {
+            perf_history.record_insert(&table_name, started.elapsed(), id.is_some());
+        }
+        if let Some(row_id) = id.filter(|_| !metadata.is_empty()) {
+            // Referring to the table by its name in the finished database
+            let row_table_name = self
+                .options
+                .tenant_table_name(self.renames.table_name(&table_name))
+                .into_owned();
+            let metadata_table_name =
+                output_table_name(&self.options, RECORD_METADATA_TABLE_NAME).into_owned();
+            for metadata_row in record_metadata::metadata_rows(&row_table_name, row_id, metadata) {
+                self.insert_output_row(&metadata_table_name, metadata_row)
+                    .await?;
+            }
+        }
+        if id.is_some() {
+            self.rows_written += 1;
+            quota::check(Quota::Rows, self.options.max_rows, self.rows_written)?;
+            if quota::database_size_check_due(self.rows_written) {
+                self.check_database_size().await?;
+            }
+        }
+        if let Some(record_table_name) = record_table_name {
+            self.count_table_row(record_table_name, id.is_some());
+        }
+        Ok(id)
+    }
+
+    /// Fails if the SQLite database is larger than --max-db-size
+    async fn check_database_size(&mut self) -> anyhow::Result<()> {
+        if self.options.max_db_size.is_some() {
+            if let Some(size) = self.sink.database_size().await? {
+                quota::check(Quota::DatabaseSize, self.options.max_db_size, size)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Counts a row of a record table, logging the rows written to the previous record table
+    /// when the row is the first of another table. The records of a type are together in
+    /// export.xml, so each table is logged once.
+    fn count_table_row(&mut self, table_name: &str, written: bool) {
+        match &mut self.current_table {
+            Some((current, rows)) if current == table_name => *rows += u64::from(written),
+            _ => {
+                self.log_current_table();
+                self.current_table = Some((table_name.to_string(), u64::from(written)));
+            }
+        }
+    }
+
+    fn log_current_table(&mut self) {
+        if let Some((table_name, rows)) = self.current_table.take() {
+            info!("Imported {} rows into {}", rows, table_name);
+        }
+    }
+
+    /// Writes the _schema_provenance and _meta tables and finishes writing the output
+    async fn finish(mut self) -> anyhow::Result<ImportStatus> {
+        self.log_current_table();
+        self.create_output_tables(SchemaProvenance::table_schema())
+            .await?;
+        // The provenance describes the whole export, so replace any rows from a previous import
+        self.clear_output_table(PROVENANCE_TABLE_NAME).await?;
+        let provenance = std::mem::take(&mut self.provenance);
+        let options = &self.options;
+        let rows = provenance.into_rows(
+            |table_name| output_table_name(options, table_name).into_owned(),
+            options.naming,
+        );
+        for row in rows {
+            self.insert_output_row(PROVENANCE_TABLE_NAME, row).await?;
+        }
+        if let Some(unit_normalizer) = self.unit_normalizer.take() {
+            self.create_output_tables(UnitNormalizer::table_schema())
+                .await?;
+            self.clear_output_table(UNITS_TABLE_NAME).await?;
+            let options = &self.options;
+            let rows = unit_normalizer
+                .into_rows(|table_name| output_table_name(options, table_name).into_owned());
+            for row in rows {
+                self.insert_output_row(UNITS_TABLE_NAME, row).await?;
+            }
+        }
+        // Appended to rather than replaced, so the runs can be compared
+        if let Some(perf_history) = self.perf_history.take() {
+            self.create_output_tables(PerfHistory::table_schema())
+                .await?;
+            for row in perf_history.into_rows(&self.options)? {
+                self.insert_output_row(PERF_HISTORY_TABLE_NAME, row).await?;
+            }
+        }
+        let complete = self.truncation.is_none() && self.interruption.is_none();
+        let mut entries = vec![("complete", complete.to_string())];
+        if !complete {
+            entries.push(("rows_imported", self.rows_written.to_string()));
+        }
+        if let Some(interruption) = &self.interruption {
+            entries.push(("interrupted", interruption.reason.clone()));
+            if let Some(position) = interruption.export_xml_position {
+                entries.push(("export_xml_bytes_imported", position.to_string()));
+            }
+        }
+        if let Some(truncation) = &self.truncation {
+            warn!(
+                "The export is truncated or corrupt: {}. Imported {} rows from the first {} of {} bytes ({:.1}%) of export.xml",
+                truncation.reason,
+                self.rows_written,
+                truncation.complete_until,
+                truncation.size,
+                100.0 * truncation.complete_until as f64 / truncation.size.max(1) as f64
+            );
+            entries.extend([
+                ("truncation_reason", truncation.reason.clone()),
+                (
+                    "export_xml_bytes_imported",
+                    truncation.complete_until.to_string(),
+                ),
+                ("export_xml_bytes", truncation.size.to_string()),
+            ]);
+        }
+        entries.extend(self.unknown_types.report());
+        if let Clock::Fixed(as_of) = self.options.clock() {
+            entries.push(("as_of", as_of.format(&Rfc3339)?));
+        }
+        let mixed_unit_tables = self.unit_check.report(|table_name| {
+            let table_name = naming::convert(
+                self.options.naming,
+                &output_table_name(&self.options, table_name),
+            );
+            self.options.tenant_table_name(&table_name).into_owned()
+        });
+        if !mixed_unit_tables.is_empty() {
+            entries.push(("mixed_unit_tables", mixed_unit_tables.join(",")));
+        }
+        if self.options.naming != Naming::Original {
+            entries.push(("naming", self.options.naming.as_str().to_string()));
+        }
+        if self.options.replication_friendly {
+            // The import never checkpoints or vacuums, leaving checkpoints to SQLite's passive
+            // auto-checkpoint or the replication tool
+            entries.extend([
+                ("transaction_rows", REPLICATION_TRANSACTION_ROWS.to_string()),
+                ("wal_checkpoint", "passive auto-checkpoint".to_string()),
+            ]);
+        }
+        self.create_output_tables(meta::table_schema()).await?;
+        self.clear_output_table(META_TABLE_NAME).await?;
+        if self.interruption.is_some() {
+            // Resuming continues after the last element imported before the interruption
+            self.write_checkpoint().await?;
+        } else {
+            self.sink.clear_checkpoint().await?;
+        }
+        for row in meta::rows(entries) {
+            self.insert_output_row(META_TABLE_NAME, row).await?;
+        }
+        if let Some(expectations) = self.expectations.take() {
+            expectations.finish(self.options.naming)?;
+        }
+        self.check_database_size().await?;
+        self.sink.finish().await?;
+        Ok(match self.interruption {
+            Some(interruption) => ImportStatus::Interrupted(interruption.reason),
+            None => ImportStatus::Complete,
+        })
+    }
 }
 
-/// Creates an SQLite database at the given URI containing all exported HealthKit data
+/// Writes all exported HealthKit data to the given output
 pub async fn healthkit_to_sqlite(
-    database_uri: &str,
-    healthkit_zip_archive_path: &PathBuf,
-) -> anyhow::Result<()> {
-    let db = create_db(database_uri).await?;
-    let exported_zip_archive_reader_0 = BufReader::new(File::open(healthkit_zip_archive_path)?);
-    let exported_zip_archive_reader_1 = BufReader::new(File::open(healthkit_zip_archive_path)?);
-    let mut data_archive = zip::ZipArchive::new(exported_zip_archive_reader_0)?;
-    let mut routes_archive = zip::ZipArchive::new(exported_zip_archive_reader_1)?;
+    output: &Output,
+    healthkit_export_path: &Path,
+    options: &ImportOptions,
+    progress: &ProgressBar,
+) -> anyhow::Result<ImportStatus> {
+    if options.anonymize && options.keeps_raw(KeepRaw::Json) {
+        anyhow::bail!("--keep-raw=json can't be combined with --anonymize, as the exported strings would reveal the values it changes");
+    }
+    let mut ctx = ImportContext {
+        sink: Sink::open(output, options).await?,
+        options: options.clone(),
+        column_aliases: Default::default(),
+        date_columns: Default::default(),
+        keyed_tables: Default::default(),
+        anonymizer: options.anonymize.then(Anonymizer::with_random_salt),
+        expectations: match &options.expectations {
+            Some(path) if !options.dry_run => {
+                Some(ExpectationsWriter::create(path, options.no_destructive)?)
+            }
+            _ => None,
+        },
+        provenance: Default::default(),
+        cache: None,
+        geojson: match &options.geojson_dir {
+            Some(dir) if !options.dry_run => {
+                Some(GeoJsonWriter::create(dir, options.no_destructive)?)
+            }
+            _ => None,
+        },
+        pending_workouts: Default::default(),
+        truncation: None,
+        rows_written: 0,
+        interrupt: Interrupt::listen(options.timeout.map(Duration::from_secs))?,
+        interruption: None,
+        export_header: Vec::new(),
+        progress: progress.clone(),
+        checkpoint: None,
+        resume_from: None,
+        perf_history: options.perf_history.then(PerfHistory::start),
+        unknown_types: UnknownTypes::new(options.unknown_types),
+        unit_check: Default::default(),
+        unit_normalizer: options.normalize_units.then(UnitNormalizer::default),
+        devices: None,
+        renames: Default::default(),
+        current_table: None,
+        blob_hashes: Default::default(),
+        cda_blob: None,
+    };
+    if options.resume {
+        let checkpoint = ctx.sink.read_checkpoint().await?.ok_or_else(|| {
+            anyhow::anyhow!("The database has no checkpoint to resume from, so its last import either finished or failed before its first checkpoint")
+        })?;
+        info!(
+            "Resuming the import after element {} of export.xml, at byte {}",
+            checkpoint.element_index, checkpoint.export_xml_position
+        );
+        ctx.rows_written = checkpoint.rows_imported;
+        ctx.resume_from = Some(checkpoint);
+    }
+    // Continues the numbering of the devices of an appended or resumed import
+    if options.device_table {
+        let device_table_name = output_table_name(options, DEVICE_TABLE_NAME);
+        let device_table_name = options.tenant_table_name(&device_table_name);
+        let devices = ctx.sink.read_devices(&device_table_name).await?;
+        ctx.devices = Some(DeviceTable::with_devices(devices));
+    }
+    let source = ExportSource::detect(healthkit_export_path)?;
+    debug!("Reading HealthKit export from {:?}", source);
+    let mut data_archive = source.open()?;
+    data_archive.check_limits(&options.zip_limits())?;
+    // Checked before the export is hashed or parsed, as both read all of export.xml
+    if options.max_export_size.is_some() {
+        let size = data_archive.uncompressed_size()?;
+        quota::check(Quota::ExportSize, options.max_export_size, size)?;
+    }
+    // The cache holds whole exports, so it isn't used when only part of the export is imported.
+    // Nor does it hold the files of the export or the raw strings of its values, so it isn't used
+    // with --blobs or --keep-raw=json.
+    let cache_dir = options.cache.as_ref().filter(|_| {
+        !options.routes_only
+            && !options.records_only
+            && !options.blobs
+            && !options.keeps_raw(KeepRaw::Json)
+    });
+    if let Some(cache_dir) = cache_dir {
+        let path = cache::cache_path(cache_dir, &source.content_hash()?);
+        if path.exists() {
+            info!("Reading the parsed export from {}", path.display());
+            let started = Instant::now();
+            let mut reader = CacheReader::open(&path)?;
+            ctx.create_tables(reader.read_tables()?).await?;
+            while let Some(element) = reader.read_element()? {
+                if ctx.interrupted(None) {
+                    break;
+                }
+                ctx.write(element).await?;
+            }
+            ctx.record_phase("cache", started);
+            return ctx.finish().await;
+        }
+        ctx.cache = Some(CacheWriter::create(&path)?);
+    }
+    // The route files hold the locations privacy zones remove, so they're only kept without zones
+    let keep_gpx = options.blobs && options.config().privacy.zones.is_empty();
+    if options.blobs && !keep_gpx {
+        warn!(
+            "The route GPX files aren't stored in {} as privacy zones are configured",
+            BLOB_TABLE_NAME
+        );
+    }
+    // Each route reader opens its own handle on the export
+    let routes = if options.records_only {
+        RouteReaderPool::disabled()
+    } else {
+        RouteReaderPool::new(&source, keep_gpx)?
+    };
+    let mut ecg_files = data_archive.list_files(ELECTROCARDIOGRAMS_DIRECTORY_NAME)?;
+    ecg_files.retain(|path| path.ends_with(".csv") && !options.routes_only);
+    let mut clinical_files = data_archive.list_files(CLINICAL_RECORDS_DIRECTORY_NAME)?;
+    clinical_files.retain(|path| path.ends_with(".json") && !options.routes_only);
+    // Clinical records are few and small, so they are parsed once and kept for the insert pass
+    let (clinical_records, clinical_blobs) =
+        clinical::read_clinical_records(&mut data_archive, &clinical_files, options.blobs)?;
+    if options.blobs && !options.routes_only {
+        ctx.cda_blob = data_archive
+            .read_export_cda_xml()?
+            .map(|bytes| Blob::new(CDA_MIME_TYPE, bytes));
+    }
     // Pass 1: Create the database tables
+    let started = Instant::now();
     {
-        let export_zip = data_archive.by_name("apple_health_export/export.xml")?;
-        let reader = BufReader::with_capacity(export_zip.size() as usize, export_zip);
+        let (export_xml, size) = data_archive.open_export_xml()?;
+        let reader = BufReader::with_capacity(size as usize, export_xml);
         let mut xml_reader = quick_xml::Reader::from_reader(reader);
         xml_reader.trim_text(true);
+        // export.xml is read once by each pass
+        progress.set_style(byte_progress_style());
+        progress.set_length(2 * size);
 
-        let mut tx = db.begin().await?;
-        sqlite_create_healthkit_tables(&mut tx, &mut xml_reader).await?;
-        tx.commit().await?;
+        let mut tables = create_healthkit_tables(&mut ctx, &mut xml_reader, size).await?;
+        if !ecg_files.is_empty() {
+            ecg::extend_tables(&mut tables);
+        }
+        clinical::extend_tables(&mut tables, &clinical_records);
+        ctx.create_tables(tables).await?;
     }
+    ctx.record_phase("schema_pass", started);
     // Pass 2: Insert data into the database tables
+    let started = Instant::now();
     {
-        let export_zip = data_archive.by_name("apple_health_export/export.xml")?;
-        let reader = BufReader::with_capacity(export_zip.size() as usize, export_zip);
+        let (export_xml, size) = data_archive.open_export_xml()?;
+        let reader = BufReader::with_capacity(size as usize, export_xml);
         let mut xml_reader = quick_xml::Reader::from_reader(reader);
         xml_reader.trim_text(true);
+        progress.set_position(size);
+        ctx.checkpoint = match ctx.resume_from {
+            Some(checkpoint) if checkpoint.export_xml_size != size => anyhow::bail!(
+                "The checkpoint is of a different export, whose export.xml is {} bytes rather than {}",
+                checkpoint.export_xml_size,
+                size
+            ),
+            Some(checkpoint) => Some(checkpoint),
+            None => Some(Checkpoint {
+                export_xml_size: size,
+                export_xml_position: 0,
+                element_index: 0,
+                rows_imported: 0,
+                attachments_imported: 0,
+            }),
+        };
 
-        let mut tx = db.begin().await?;
-        sqlite_insert_healthkit_tables(&mut tx, &mut xml_reader, &mut routes_archive).await?;
-        tx.commit().await?;
+        insert_healthkit_tables(&mut ctx, &mut xml_reader, &routes).await?;
+        ctx.write_ready_workouts(0).await?;
+    }
+    ctx.record_phase("insert_pass", started);
+    let started = Instant::now();
+    // Counted from the first attachment, as a resumed checkpoint counts those it committed
+    if let Some(checkpoint) = &mut ctx.checkpoint {
+        checkpoint.attachments_imported = 0;
     }
+    insert_ecgs(&mut ctx, &mut data_archive, &ecg_files).await?;
+    for record in clinical_records {
+        if ctx.interrupted(None) {
+            break;
+        }
+        if ctx.attachment_committed() {
+            continue;
+        }
+        ctx.write(ParsedElement::Row {
+            table_name: record.table_name,
+            row: record.row,
+        })
+        .await?;
+    }
+    for blob in clinical_blobs {
+        if ctx.interrupted(None) {
+            break;
+        }
+        if ctx.attachment_committed() {
+            continue;
+        }
+        ctx.write_blob(blob).await?;
+    }
+    ctx.record_phase("attachments", started);
+    if let Some(cache) = ctx.cache.take() {
+        // A later import of a fixed copy of the export has a different hash, so only complete
+        // exports are worth caching
+        if ctx.truncation.is_none() && ctx.interruption.is_none() {
+            cache.finish()?;
+        }
+    }
+    ctx.finish().await
+}
 
-    Ok(())
+/// Returns the name of the table in the output: the samples table for records with
+/// --single-table, and without the sample type prefix with --short-names, made into an identifier
+/// by --identifiers and --identifier-map
+fn output_table_name<'a>(options: &ImportOptions, table_name: &'a str) -> Cow<'a, str> {
+    let table_name = if options.single_table && single_table::is_record_table(table_name) {
+        SAMPLES_TABLE_NAME
+    } else if options.short_names {
+        schema::short_table_name(table_name)
+    } else {
+        table_name
+    };
+    identifier::convert(options, table_name)
 }
 
-/// Converts an arbitrary string to a valid SQLite identifier
-/// This currently isn't robust enough to handle all possible strings
-/// But it's good enough for now. See https://stackoverflow.com/a/6701665
-fn get_valid_sqlite_identifier(s: &str) -> String {
-    format!("`{}`", s)
+/// Progress bar style showing the bytes of export.xml read, with the percentage and ETA
+fn byte_progress_style() -> ProgressStyle {
+    ProgressStyle::with_template(
+        "[{elapsed_precise}] {msg} [{wide_bar:.blue}] {percent}% {bytes}/{total_bytes} (ETA {eta})",
+    )
+    .expect("valid template")
+    .progress_chars("=> ")
 }
 
-/// Derives and creates the SQLite tables from the exported HealthKit XML
-async fn sqlite_create_healthkit_tables<R: BufRead>(
-    tx: &mut Transaction<'_, Sqlite>,
+/// Derives the output tables from the exported HealthKit XML
+async fn create_healthkit_tables<R: BufRead>(
+    ctx: &mut ImportContext,
     xml_reader: &mut quick_xml::Reader<R>,
-) -> anyhow::Result<()> {
+    size: u64,
+) -> anyhow::Result<HKTables> {
     let mut buf = Vec::new();
     let mut tables: HKTables = HKTables::new();
     // Top-level parsing
     loop {
         match xml_reader.read_event_into(&mut buf) {
-            Err(e) => panic!(
-                "Error reading top-level HealthKit XML data at position {}: {:?}",
-                xml_reader.buffer_position(),
-                e
-            ),
+            Err(e) => {
+                return Err(anyhow::Error::new(e).context(format!(
+                    "Failed to read export.xml at byte {}",
+                    xml_reader.buffer_position()
+                )))
+            }
             Ok(Event::Start(e)) => {
                 if let b"HealthData" = e.name().as_ref() {
                     debug!("HealthData: {:?}", e.attributes());
-                    hk_create_health_data_tables(xml_reader, &mut tables, &mut buf).await?;
+                    let mut offsets = ElementOffsets::default();
+                    let result = hk_create_health_data_tables(
+                        xml_reader,
+                        &mut tables,
+                        &mut buf,
+                        &mut offsets,
+                        &ctx.interrupt,
+                        &ctx.progress,
+                        &ctx.options,
+                    )
+                    .await;
+                    // No rows are written until the tables are created, so there's nothing to keep
+                    if let Some(reason) = ctx.interrupt.reason() {
+                        anyhow::bail!("The import was {} before any rows were written", reason);
+                    }
+                    ctx.truncation = match result {
+                        Ok(true) => check_remaining_bytes(xml_reader, size),
+                        // The last element may have been cut short, so it isn't imported
+                        Ok(false) => Some(Truncation {
+                            complete_until: offsets.previous,
+                            size,
+                            reason: "export.xml ends before the closing </HealthData> tag"
+                                .to_string(),
+                        }),
+                        Err(e) if quota::exceeded(&e).is_some() => return Err(e),
+                        Err(e) => Some(Truncation {
+                            complete_until: offsets.current,
+                            size,
+                            reason: format!(
+                                "export.xml could not be read past byte {}: {}",
+                                offsets.current, e
+                            ),
+                        }),
+                    };
+                    break;
                 }
             }
             Ok(Event::Eof) => break, // exits the loop when reaching end of file
@@ -115,51 +1184,74 @@ async fn sqlite_create_healthkit_tables<R: BufRead>(
         }
         buf.clear();
     }
-    for (name, columns) in tables {
-        let qs = format!(
-            r#"CREATE TABLE IF NOT EXISTS `{}` ({});
-        "#,
-            name,
-            columns
-                .iter()
-                .map(|(name, ty)| format!("{} {}", get_valid_sqlite_identifier(name), ty))
-                .collect::<Vec<_>>()
-                .join(", ")
-        );
-        sqlx::query(&qs).execute(&mut *tx).await?;
+    if ctx.options.keeps_raw(KeepRaw::Json) {
+        for columns in tables.values_mut() {
+            columns.insert(RAW_VALUES_COLUMN.to_string(), "JSON");
+        }
+    }
+    Ok(tables)
+}
+
+/// Reads the rest of export.xml after the closing </HealthData> tag. Zip entries only verify
+/// their CRC once they are read to the end.
+fn check_remaining_bytes<R: BufRead>(
+    xml_reader: &mut quick_xml::Reader<R>,
+    size: u64,
+) -> Option<Truncation> {
+    let complete_until = xml_reader.buffer_position();
+    match std::io::copy(xml_reader.get_mut(), &mut std::io::sink()) {
+        Ok(_) => None,
+        Err(e) => Some(Truncation {
+            complete_until,
+            size,
+            reason: format!("export.xml is corrupt: {}", e),
+        }),
     }
-    Ok(())
 }
 
-// Inserts the HealthKit data into the SQLite tables
-async fn sqlite_insert_healthkit_tables<S: BufRead + Seek, R: BufRead>(
-    tx: &mut Transaction<'_, Sqlite>,
+// Inserts the HealthKit data into the output tables
+async fn insert_healthkit_tables<R: BufRead>(
+    ctx: &mut ImportContext,
     xml_reader: &mut quick_xml::Reader<R>,
-    zip_archive: &mut zip::ZipArchive<S>,
+    routes: &RouteReaderPool,
 ) -> anyhow::Result<()> {
     let mut buf = Vec::new();
     // Top-level parsing
     loop {
         match xml_reader.read_event_into(&mut buf) {
-            Err(e) => panic!(
-                "Error reading top-level HealthKit XML data at position {}: {:?}",
-                xml_reader.buffer_position(),
-                e
-            ),
+            Err(e) => {
+                return Err(anyhow::Error::new(e).context(format!(
+                    "Failed to read export.xml at byte {}",
+                    xml_reader.buffer_position()
+                )))
+            }
             Ok(Event::Start(e)) => {
                 if let b"HealthData" = e.name().as_ref() {
                     debug!("HealthData: {:?}", e.attributes());
-                    insert_hk_health_data_elements(tx, xml_reader, zip_archive).await?;
+                    // The locale attribute
+                    let header = database_row_from_element(xml_reader, e, false)?;
+                    ctx.export_header.extend(header);
+                    insert_hk_health_data_elements(ctx, xml_reader, routes).await?;
+                    if ctx.truncation.is_some() || ctx.interruption.is_some() {
+                        break;
+                    }
                 }
             }
             Ok(Event::Eof) => break, // exits the loop when reaching end of file
             Ok(Event::Decl(_)) => continue, // continue loop on Decl event. We can use this to get encoding information (UTF8)
-            Ok(Event::DocType(_)) => continue, // continue loop on DocType event. We can use this to get the top-level SCHEMA and HealthKit export version
-            Ok(Event::End(_)) => continue,     // continue loop on End event
-            Ok(Event::Empty(_)) => continue,   // continue loop on Empty event
+            Ok(Event::DocType(e)) => {
+                if let Some(version) =
+                    meta::export_version_from_doctype(&String::from_utf8_lossy(&e))
+                {
+                    ctx.export_header
+                        .push(("exportVersion".to_string(), DatabaseValue::Text(version)));
+                }
+            }
+            Ok(Event::End(_)) => continue, // continue loop on End event
+            Ok(Event::Empty(_)) => continue, // continue loop on Empty event
             Ok(Event::Comment(_)) => continue, // continue loop on Comment event
-            Ok(Event::CData(_)) => continue,   // continue loop on CData event
-            Ok(Event::PI(_)) => continue,      // continue loop on PI event
+            Ok(Event::CData(_)) => continue, // continue loop on CData event
+            Ok(Event::PI(_)) => continue,  // continue loop on PI event
             Ok(Event::Text(_)) => continue, // continue loop on Text event, don't care about text at the top level
         }
         buf.clear();
@@ -167,6 +1259,42 @@ async fn sqlite_insert_healthkit_tables<S: BufRead + Seek, R: BufRead>(
     Ok(())
 }
 
+/// Inserts the electrocardiogram recordings exported as CSV files next to export.xml
+async fn insert_ecgs(
+    ctx: &mut ImportContext,
+    archive: &mut ExportArchive,
+    paths: &[String],
+) -> anyhow::Result<()> {
+    for path in paths {
+        if ctx.interrupted(None) {
+            break;
+        }
+        if ctx.attachment_committed() {
+            continue;
+        }
+        debug!("Reading electrocardiogram file: {}", path);
+        let (ecg, blob) = {
+            let (mut file, size) = archive.open_file(path)?;
+            if ctx.options.blobs {
+                let mut bytes = Vec::with_capacity(size as usize);
+                file.read_to_end(&mut bytes)?;
+                let ecg = ecg::read_ecg(path, bytes.as_slice())?;
+                (ecg, Some(Blob::new(ECG_MIME_TYPE, bytes)))
+            } else {
+                let ecg = ecg::read_ecg(path, BufReader::with_capacity(size as usize, file))?;
+                (ecg, None)
+            }
+        };
+        ctx.write(ParsedElement::Ecg {
+            row: ecg.row,
+            samples: ecg.samples,
+            blob,
+        })
+        .await?;
+    }
+    Ok(())
+}
+
 /// Derive the SQL type from a HealthKit value str
 fn database_type_str_from_hk_value_str(value: &str) -> &'static str {
     lazy_static::lazy_static! {
@@ -175,9 +1303,9 @@ fn database_type_str_from_hk_value_str(value: &str) -> &'static str {
         static ref DATE: &'static str = "DATE";
         static ref TEXT: &'static str = "TEXT";
     }
-    if value.parse::<i32>().is_ok() {
+    if value.parse::<i64>().is_ok() {
         &INTEGER
-    } else if value.parse::<f32>().is_ok() {
+    } else if value.parse::<f64>().is_ok() {
         &REAL
     } else if OffsetDateTime::parse(value, &HEALTHKIT_OFFSET_DATE_FORMAT).is_ok()
         || Date::parse(value, &HEALTHKIT_DATE_FORMAT).is_ok()
@@ -189,10 +1317,10 @@ fn database_type_str_from_hk_value_str(value: &str) -> &'static str {
 }
 
 /// Returns a typed HKValue from a HealthKit value str
-fn database_value_from_hk_value_str(value: &str) -> DatabaseValue {
-    if let Ok(i) = value.parse::<i32>() {
+pub(crate) fn database_value_from_hk_value_str(value: &str) -> DatabaseValue {
+    if let Ok(i) = value.parse::<i64>() {
         DatabaseValue::Integer(i)
-    } else if let Ok(i) = value.parse::<f32>() {
+    } else if let Ok(i) = value.parse::<f64>() {
         DatabaseValue::Real(i)
     } else if let Ok(i) = Date::parse(value, &HEALTHKIT_DATE_FORMAT) {
         DatabaseValue::Date(i)
@@ -216,18 +1344,28 @@ fn hk_create_table_from_element<'a, R: BufRead>(
     for attribute in element.attributes() {
         let attribute = attribute?;
         let column_name_str = std::str::from_utf8(attribute.key.as_ref())?;
-        if !columns.contains_key(column_name_str) {
-            columns.insert(
-                column_name_str.to_string(),
-                database_type_str_from_hk_value_str(
-                    attribute.decode_and_unescape_value(reader)?.as_ref(),
-                ),
-            );
+        if columns.get(column_name_str) != Some(&"TEXT") {
+            let value = attribute.decode_and_unescape_value(reader)?;
+            observe_column_type(columns, column_name_str, &value);
         }
     }
     Ok(())
 }
 
+/// Widens the type of a column to hold the value, e.g. from INTEGER to REAL for a value with a
+/// fraction, or to TEXT for values of differing types, adding the column if it is new. Every
+/// value of the export is observed by the schema pass, so a column is typed by all of its values
+/// rather than the first.
+fn observe_column_type(columns: &mut BTreeMap<String, &'static str>, name: &str, value: &str) {
+    let ty = database_type_str_from_hk_value_str(value);
+    match columns.get_mut(name) {
+        Some(existing) => *existing = schema::widen_type(existing, ty),
+        None => {
+            columns.insert(name.to_string(), ty);
+        }
+    }
+}
+
 fn hk_table_append_metadata_entry_column<R: BufRead>(
     reader: &mut quick_xml::Reader<R>,
     element: BytesStart,
@@ -245,51 +1383,149 @@ fn hk_table_append_metadata_entry_column<R: BufRead>(
             _ => (),
         }
     }
-    let column_name_str = key.as_ref();
-    if !columns.contains_key(column_name_str) {
-        columns.insert(
-            // TODO
-            format!("metadata_{}", column_name_str),
-            database_type_str_from_hk_value_str(value.as_ref()),
-        );
+    let column_name = format!("{}{}", METADATA_COLUMN_PREFIX, key);
+    observe_column_type(columns, &column_name, &value);
+    Ok(())
+}
+
+/// Adds the attributes of a Workout child element to its table, along with the workout_id column
+fn hk_create_workout_child_table<R: BufRead>(
+    reader: &mut quick_xml::Reader<R>,
+    element: BytesStart,
+    tables: &mut HKTables,
+    table_name: &str,
+) -> anyhow::Result<()> {
+    hk_create_table_from_element(reader, element, tables, table_name)?;
+    let columns = tables.get_mut(table_name).expect("cant fail");
+    columns.insert(WORKOUT_ID_COLUMN.to_string(), "INTEGER");
+    Ok(())
+}
+
+/// Adds the table of a Record child element, with a column for each attribute and a record_id column
+fn hk_create_record_child_table<R: BufRead>(
+    reader: &mut quick_xml::Reader<R>,
+    element: BytesStart,
+    tables: &mut HKTables,
+    table_name: &str,
+) -> anyhow::Result<()> {
+    hk_create_table_from_element(reader, element, tables, table_name)?;
+    let columns = tables.get_mut(table_name).expect("cant fail");
+    columns.insert(RECORD_ID_COLUMN.to_string(), "INTEGER");
+    if table_name == HEARTBEAT_TABLE_NAME {
+        // The time of day attribute is resolved to a date on insert
+        columns.insert("time".to_string(), "DATE");
     }
     Ok(())
 }
 
+/// Returns the table of a Record child element, if it is one that is imported
+fn record_child_table_name(element_name: &[u8]) -> Option<&'static str> {
+    RECORD_CHILD_TABLES
+        .iter()
+        .find(|(name, _)| *name == element_name)
+        .map(|(_, table_name)| *table_name)
+}
+
+/// Adds a column for each MetadataEntry nested inside a child element, up to its end tag
+fn hk_create_child_metadata_columns<R: BufRead>(
+    reader: &mut quick_xml::Reader<R>,
+    tables: &mut HKTables,
+    table_name: &str,
+    end: &[u8],
+) -> anyhow::Result<()> {
+    let mut buf = Vec::new();
+    loop {
+        match reader.read_event_into(&mut buf)? {
+            Event::Eof => break,
+            Event::End(element) if end == element.name().as_ref() => break,
+            Event::Empty(element) if b"MetadataEntry" == element.name().as_ref() => {
+                hk_table_append_metadata_entry_column(reader, element, tables, table_name)?;
+            }
+            _ => {}
+        }
+        buf.clear();
+    }
+    Ok(())
+}
+
+/// Infers the tables of the elements inside HealthData, returning false if export.xml ends before
+/// the closing </HealthData> tag
 async fn hk_create_health_data_tables<R: BufRead>(
     reader: &mut quick_xml::Reader<R>,
     tables: &mut HKTables,
     buf: &mut Vec<u8>,
-) -> anyhow::Result<()> {
+    offsets: &mut ElementOffsets,
+    interrupt: &Interrupt,
+    progress: &ProgressBar,
+    options: &ImportOptions,
+) -> anyhow::Result<bool> {
     loop {
+        if let Some(reason) = interrupt.reason() {
+            anyhow::bail!("{}", reason);
+        }
+        offsets.previous = offsets.current;
+        offsets.current = reader.buffer_position();
+        progress.set_position(offsets.current as u64);
+        // The sizes recorded in a zip archive can understate what its files decompress to
+        quota::check(
+            Quota::ExportSize,
+            options.max_export_size,
+            offsets.current as u64,
+        )?;
         match reader.read_event_into(buf)? {
-            Event::Eof => break, // exits the loop when reaching end of file
+            Event::Eof => return Ok(false), // export.xml is truncated
             Event::Start(element) => match element.name().as_ref() {
+                b"Workout" if options.records_only => {
+                    reader.read_to_end_into(element.name(), &mut Vec::new())?;
+                }
                 b"Workout" => {
                     let table_name = "Workout";
                     hk_create_table_from_element(reader, element, tables, table_name)?;
                     let mut inner_buf = Vec::new();
+                    // MetadataEntry elements inside a WorkoutActivity belong to the activity
+                    let mut in_activity = false;
                     loop {
                         match reader.read_event_into(&mut inner_buf)? {
                             Event::Eof => break, // exits the loop when reaching end of file
-                            Event::End(element) => {
-                                if let b"Workout" = element.name().as_ref() {
-                                    break;
-                                }
-                            }
+                            Event::End(element) => match element.name().as_ref() {
+                                b"Workout" => break,
+                                b"WorkoutActivity" => in_activity = false,
+                                _ => {}
+                            },
                             Event::Empty(element) => match element.name().as_ref() {
                                 b"MetadataEntry" => {
+                                    let table_name: &str = if in_activity {
+                                        &WORKOUT_ACTIVITY_TABLE_NAME
+                                    } else {
+                                        table_name
+                                    };
                                     hk_table_append_metadata_entry_column(
                                         reader, element, tables, table_name,
                                     )?;
                                 }
+                                b"WorkoutActivity" => {
+                                    hk_create_workout_child_table(
+                                        reader,
+                                        element,
+                                        tables,
+                                        &WORKOUT_ACTIVITY_TABLE_NAME,
+                                    )?;
+                                }
                                 b"WorkoutEvent" => {
-                                    let columns = tables.get_mut(table_name).expect("cant fail");
-                                    columns.insert("workoutEvents".to_string(), "JSON");
+                                    hk_create_workout_child_table(
+                                        reader,
+                                        element,
+                                        tables,
+                                        &WORKOUT_EVENT_TABLE_NAME,
+                                    )?;
                                 }
                                 b"WorkoutStatistics" => {
-                                    let columns = tables.get_mut(table_name).expect("cant fail");
-                                    columns.insert("workoutStatistics".to_string(), "JSON");
+                                    hk_create_workout_child_table(
+                                        reader,
+                                        element,
+                                        tables,
+                                        &WORKOUT_STATISTICS_TABLE_NAME,
+                                    )?;
                                 }
                                 other => {
                                     debug!(
@@ -298,16 +1534,57 @@ async fn hk_create_health_data_tables<R: BufRead>(
                                     );
                                 }
                             },
-                            Event::Start(element) => {
-                                if b"WorkoutRoute" == element.name().as_ref() {
+                            Event::Start(element) => match element.name().as_ref() {
+                                b"WorkoutRoute" => {
                                     let columns = tables.get_mut(table_name).expect("cant fail");
                                     columns.insert("geometry".to_string(), "JSON");
                                 }
-                            }
+                                b"WorkoutActivity" => {
+                                    hk_create_workout_child_table(
+                                        reader,
+                                        element,
+                                        tables,
+                                        &WORKOUT_ACTIVITY_TABLE_NAME,
+                                    )?;
+                                    in_activity = true;
+                                }
+                                b"WorkoutEvent" => {
+                                    hk_create_workout_child_table(
+                                        reader,
+                                        element,
+                                        tables,
+                                        &WORKOUT_EVENT_TABLE_NAME,
+                                    )?;
+                                    hk_create_child_metadata_columns(
+                                        reader,
+                                        tables,
+                                        &WORKOUT_EVENT_TABLE_NAME,
+                                        b"WorkoutEvent",
+                                    )?;
+                                }
+                                b"WorkoutStatistics" => {
+                                    hk_create_workout_child_table(
+                                        reader,
+                                        element,
+                                        tables,
+                                        &WORKOUT_STATISTICS_TABLE_NAME,
+                                    )?;
+                                    hk_create_child_metadata_columns(
+                                        reader,
+                                        tables,
+                                        &WORKOUT_STATISTICS_TABLE_NAME,
+                                        b"WorkoutStatistics",
+                                    )?;
+                                }
+                                _ => {}
+                            },
                             _ => continue,
                         }
                     }
                 }
+                b"Record" if options.routes_only => {
+                    reader.read_to_end_into(element.name(), &mut Vec::new())?;
+                }
                 b"Record" => {
                     let table_name = attribute_value_from_element(reader, &element, b"type")?;
                     hk_create_table_from_element(reader, element, tables, &table_name)?;
@@ -320,17 +1597,26 @@ async fn hk_create_health_data_tables<R: BufRead>(
                                     break;
                                 }
                             }
-                            Event::Empty(element) => {
-                                if b"MetadataEntry" == element.name().as_ref() {
+                            Event::Empty(element) | Event::Start(element) => {
+                                let name = element.name();
+                                if b"MetadataEntry" == name.as_ref() {
                                     hk_table_append_metadata_entry_column(
                                         reader,
                                         element,
                                         tables,
                                         &table_name,
                                     )?;
+                                } else if let Some(child_table_name) =
+                                    record_child_table_name(name.as_ref())
+                                {
+                                    hk_create_record_child_table(
+                                        reader,
+                                        element,
+                                        tables,
+                                        child_table_name,
+                                    )?;
                                 }
                             }
-                            Event::Start(_) => {}
                             _ => continue,
                         }
                     }
@@ -347,9 +1633,10 @@ async fn hk_create_health_data_tables<R: BufRead>(
                     // TODO
                     //debug!("ExportDate: {:?}", element.attributes());
                 }
+                // Only the workouts are imported with routes_only
+                b"Me" | b"Record" | b"ActivitySummary" if options.routes_only => {}
                 b"Me" => {
-                    // TODO
-                    //debug!("Me: {:?}", element.attributes());
+                    hk_create_table_from_element(reader, element, tables, &ME_TABLE_NAME)?;
                 }
                 b"Record" => {
                     let table_name = attribute_value_from_element(reader, &element, b"type")?;
@@ -367,7 +1654,11 @@ async fn hk_create_health_data_tables<R: BufRead>(
             },
             Event::Decl(_) => continue, // continue loop on Decl event. We can use this to get encoding information (UTF8)
             Event::DocType(_) => continue, // continue loop on DocType event. We can use this to get the top-level SCHEMA and HealthKit export version
-            Event::End(_) => continue,     // continue loop on End event
+            Event::End(element) => {
+                if let b"HealthData" = element.name().as_ref() {
+                    return Ok(true);
+                }
+            }
             Event::Comment(_) => continue, // continue loop on Comment event
             Event::CData(_) => continue,   // continue loop on CData event
             Event::PI(_) => continue,      // continue loop on PI event
@@ -375,24 +1666,58 @@ async fn hk_create_health_data_tables<R: BufRead>(
         }
         buf.clear();
     }
-    Ok(())
 }
 
-async fn insert_hk_health_data_elements<S: BufRead + Seek, R: BufRead>(
-    db: &mut Transaction<'_, Sqlite>,
+async fn insert_hk_health_data_elements<R: BufRead>(
+    ctx: &mut ImportContext,
     reader: &mut quick_xml::Reader<R>,
-    zip_archive: &mut zip::ZipArchive<S>,
+    routes: &RouteReaderPool,
 ) -> anyhow::Result<()> {
     let mut buf = Vec::new();
+    // The first pass read the whole export.xml
+    let first_pass_bytes = ctx.progress.length().unwrap_or_default() / 2;
     loop {
-        match reader.read_event_into(&mut buf)? {
+        ctx.progress
+            .set_position(first_pass_bytes + reader.buffer_position() as u64);
+        // Stop after the last complete element of a truncated export
+        if let Some(truncation) = &ctx.truncation {
+            if reader.buffer_position() >= truncation.complete_until {
+                write_export_metadata(ctx).await?;
+                break;
+            }
+        }
+        if ctx.interrupted(Some(reader.buffer_position())) {
+            break;
+        }
+        // The elements before the checkpoint being resumed were committed by the failed import
+        if let Some(resume_from) = &ctx.resume_from {
+            if reader.buffer_position() < resume_from.export_xml_position {
+                // The ExportDate is still read, as export_metadata is written at the end
+                if let Event::Empty(element) = reader.read_event_into(&mut buf)? {
+                    if element.name().as_ref() == b"ExportDate" {
+                        read_export_date(ctx, reader, element)?;
+                    }
+                }
+                buf.clear();
+                continue;
+            }
+        }
+        let event = reader.read_event_into(&mut buf)?;
+        let is_element = matches!(event, Event::Start(_) | Event::Empty(_));
+        match event {
             Event::Eof => break, // exits the loop when reaching end of file
             Event::Start(element) => match element.name().as_ref() {
+                b"Workout" if ctx.options.records_only => {
+                    reader.read_to_end_into(element.name(), &mut Vec::new())?;
+                }
                 b"Workout" => {
-                    insert_hk_workout_element(db, reader, element, zip_archive).await?;
+                    insert_hk_workout_element(ctx, reader, element, routes).await?;
+                }
+                b"Record" if ctx.options.routes_only => {
+                    reader.read_to_end_into(element.name(), &mut Vec::new())?;
                 }
                 b"Record" => {
-                    insert_hk_record_element(db, reader, element).await?;
+                    insert_hk_record_element(ctx, reader, element).await?;
                 }
                 other => {
                     debug!(
@@ -403,22 +1728,51 @@ async fn insert_hk_health_data_elements<S: BufRead + Seek, R: BufRead>(
             },
             Event::Decl(_) => continue, // continue loop on Decl event. We can use this to get encoding information (UTF8)
             Event::DocType(_) => continue, // continue loop on DocType event. We can use this to get the top-level SCHEMA and HealthKit export version
-            Event::End(_) => continue,     // continue loop on End event
+            Event::End(element) => {
+                // Counted as an element so a resumed import doesn't write export_metadata again
+                if element.name().as_ref() == b"HealthData" {
+                    write_export_metadata(ctx).await?;
+                    ctx.element_imported(reader.buffer_position()).await?;
+                    break;
+                }
+            }
             Event::Empty(element) => match element.name().as_ref() {
                 b"ExportDate" => {
-                    debug!("ExportDate: {:?}", element.attributes());
+                    read_export_date(ctx, reader, element)?;
                 }
+                b"Me" | b"Record" | b"ActivitySummary" if ctx.options.routes_only => {}
                 b"Me" => {
-                    debug!("Me: {:?}", element.attributes());
+                    let row = database_row_from_element(
+                        reader,
+                        element,
+                        ctx.options.keeps_raw(KeepRaw::Json),
+                    )?;
+                    ctx.write(ParsedElement::Row {
+                        table_name: ME_TABLE_NAME.to_string(),
+                        row,
+                    })
+                    .await?;
                 }
                 b"Record" => {
                     let table_name = attribute_value_from_element(reader, &element, b"type")?;
-                    let row = database_row_from_element(reader, element)?;
-                    insert_database_row(db, &table_name, row).await?;
+                    let row = database_row_from_element(
+                        reader,
+                        element,
+                        ctx.options.keeps_raw(KeepRaw::Json),
+                    )?;
+                    ctx.write(ParsedElement::Row { table_name, row }).await?;
                 }
                 b"ActivitySummary" => {
-                    let row = database_row_from_element(reader, element)?;
-                    insert_database_row(db, &ACTIVITY_SUMMARY_TABLE_NAME, row).await?;
+                    let row = database_row_from_element(
+                        reader,
+                        element,
+                        ctx.options.keeps_raw(KeepRaw::Json),
+                    )?;
+                    ctx.write(ParsedElement::Row {
+                        table_name: ACTIVITY_SUMMARY_TABLE_NAME.to_string(),
+                        row,
+                    })
+                    .await?;
                 }
                 _ => {}
             },
@@ -427,32 +1781,85 @@ async fn insert_hk_health_data_elements<S: BufRead + Seek, R: BufRead>(
             Event::PI(_) => continue,      // continue loop on PI event
             Event::Text(_) => continue, // continue loop on Text event, don't care about text at the top level
         }
+        if is_element {
+            ctx.element_imported(reader.buffer_position()).await?;
+        }
         buf.clear();
     }
     Ok(())
 }
 
+/// Adds the value of the ExportDate element to the export header
+fn read_export_date<R: BufRead>(
+    ctx: &mut ImportContext,
+    reader: &mut quick_xml::Reader<R>,
+    element: BytesStart,
+) -> anyhow::Result<()> {
+    for (name, value) in database_row_from_element(reader, element, false)? {
+        if name == "value" {
+            ctx.export_header.push(("exportDate".to_string(), value));
+        }
+    }
+    Ok(())
+}
+
+/// Writes the export_metadata row once the HealthData elements are read, even without an
+/// ExportDate, so the database still records which export and tool version built it
+async fn write_export_metadata(ctx: &mut ImportContext) -> anyhow::Result<()> {
+    let row = std::mem::take(&mut ctx.export_header);
+    ctx.write(ParsedElement::Row {
+        table_name: EXPORT_METADATA_TABLE_NAME.to_string(),
+        row,
+    })
+    .await
+}
+
+/// Returns a row with a column for each attribute of the element, keeping the strings of the
+/// values parsed to numbers or dates in the raw values column if keep_raw is set
 fn database_row_from_element<R: BufRead>(
     reader: &mut quick_xml::Reader<R>,
     element: BytesStart,
+    keep_raw: bool,
 ) -> anyhow::Result<DatabaseRow> {
     let mut column = DatabaseRow::with_capacity(element.attributes().count());
     for attribute in element.attributes() {
         let attribute = attribute?;
         let column_name_str = std::str::from_utf8(attribute.key.as_ref())?;
         let column_value_str = attribute.decode_and_unescape_value(reader)?;
-        column.push((
-            column_name_str.to_string(),
-            database_value_from_hk_value_str(&column_value_str),
-        ));
+        let value = database_value_from_hk_value_str(&column_value_str);
+        if keep_raw {
+            keep_raw_value(&mut column, column_name_str, &column_value_str, &value);
+        }
+        column.push((column_name_str.to_string(), value));
     }
     Ok(column)
 }
 
+/// Adds the string of a value parsed to a number or date to the raw values column of the row
+fn keep_raw_value(row: &mut DatabaseRow, name: &str, raw: &str, value: &DatabaseValue) {
+    if matches!(value, DatabaseValue::Text(_)) {
+        return;
+    }
+    let index = match row.iter().position(|(name, _)| name == RAW_VALUES_COLUMN) {
+        Some(index) => index,
+        None => {
+            row.push((
+                RAW_VALUES_COLUMN.to_string(),
+                DatabaseValue::Json(JsonValue::Object(Default::default())),
+            ));
+            row.len() - 1
+        }
+    };
+    if let (_, DatabaseValue::Json(JsonValue::Object(raw_values))) = &mut row[index] {
+        raw_values.insert(name.to_string(), JsonValue::String(raw.to_string()));
+    }
+}
+
 fn append_hk_metadata_entry_to_database_row<R: BufRead>(
     reader: &mut quick_xml::Reader<R>,
     element: BytesStart,
     mut record: DatabaseRow,
+    keep_raw: bool,
 ) -> anyhow::Result<DatabaseRow> {
     let mut key = Cow::Borrowed("");
     let mut value = Cow::Borrowed("");
@@ -464,34 +1871,13 @@ fn append_hk_metadata_entry_to_database_row<R: BufRead>(
             _ => (),
         }
     }
-    let column_name_str = key.as_ref();
-    record.push((
-        // TODO
-        format!("metadata_{}", column_name_str),
-        database_value_from_hk_value_str(value.as_ref()),
-    ));
-    Ok(record)
-}
-
-/// Converts a HealthKit XML element into a JSON object
-fn json_value_from_hk_element<'a, R: BufRead>(
-    reader: &mut quick_xml::Reader<R>,
-    element: &BytesStart<'a>,
-) -> anyhow::Result<JsonValue> {
-    let mut json: BTreeMap<String, JsonValue> = Default::default();
-    for attribute in element.attributes() {
-        let attribute = attribute?;
-        let attr_name_str = std::str::from_utf8(attribute.key.as_ref())?;
-        let attr_value_str = attribute.decode_and_unescape_value(reader)?;
-        let key = attr_name_str.to_string();
-        let value = if let Ok(val) = attr_value_str.parse::<f32>() {
-            val.into()
-        } else {
-            attr_value_str.into()
-        };
-        json.insert(key, value);
+    let column_name = format!("{}{}", METADATA_COLUMN_PREFIX, key);
+    let column_value = database_value_from_hk_value_str(value.as_ref());
+    if keep_raw {
+        keep_raw_value(&mut record, &column_name, &value, &column_value);
     }
-    Ok(serde_json::to_value(json)?)
+    record.push((column_name, column_value));
+    Ok(record)
 }
 
 /// Returns the element attribute value as a string
@@ -508,43 +1894,74 @@ fn attribute_value_from_element<'a, R: BufRead>(
             break;
         }
     }
+    // Fails the pass, which imports the elements before it as from a truncated export
     if table_name.is_empty() {
-        unreachable!("Workout element without workoutActivityType attribute");
+        anyhow::bail!(
+            "{} element without a {} attribute",
+            String::from_utf8_lossy(element.name().as_ref()),
+            String::from_utf8_lossy(key)
+        );
     }
     Ok(table_name.to_string())
 }
 
-/// Inserts a single HealthKit Workout element into the Workout table
-async fn insert_hk_workout_element<'a, S: BufRead + Seek, R: BufRead>(
-    db: &mut Transaction<'_, Sqlite>,
+/// Parses a single HealthKit Workout element, queueing it for insertion once its route is read
+async fn insert_hk_workout_element<'a, R: BufRead>(
+    ctx: &mut ImportContext,
     reader: &mut quick_xml::Reader<R>,
     element: BytesStart<'a>,
-    zip_archive: &mut zip::ZipArchive<S>,
+    routes: &RouteReaderPool,
 ) -> anyhow::Result<()> {
-    let mut row = database_row_from_element(reader, element)?;
+    let keep_raw = ctx.options.keeps_raw(KeepRaw::Json);
+    let mut row = database_row_from_element(reader, element, keep_raw)?;
     let mut buf = Vec::new();
-    let mut workout_events = Vec::new();
-    let mut workout_stats = BTreeMap::new();
-    let mut workout_route = BTreeMap::new();
+    // Rows of the child tables, inserted once the id of the workout row is known
+    let mut children: Vec<(String, DatabaseRow)> = Vec::new();
+    let mut route = None;
+    // Index into children of the WorkoutActivity being read, whose MetadataEntry elements belong to it
+    let mut activity = None;
     loop {
         match reader.read_event_into(&mut buf)? {
             Event::Eof => break, // exits the loop when reaching end of file
-            Event::End(element) => {
-                if b"Workout" == element.name().as_ref() {
-                    break;
-                }
-            }
+            Event::End(element) => match element.name().as_ref() {
+                b"Workout" => break,
+                b"WorkoutActivity" => activity = None,
+                _ => {}
+            },
             Event::Empty(element) => match element.name().as_ref() {
-                b"MetadataEntry" => {
-                    row = append_hk_metadata_entry_to_database_row(reader, element, row)?;
+                b"MetadataEntry" => match activity {
+                    Some(index) => {
+                        let (_, child) = &mut children[index];
+                        *child = append_hk_metadata_entry_to_database_row(
+                            reader,
+                            element,
+                            std::mem::take(child),
+                            keep_raw,
+                        )?;
+                    }
+                    None => {
+                        row = append_hk_metadata_entry_to_database_row(
+                            reader, element, row, keep_raw,
+                        )?;
+                    }
+                },
+                b"WorkoutActivity" => {
+                    children.push((
+                        WORKOUT_ACTIVITY_TABLE_NAME.to_string(),
+                        database_row_from_element(reader, element, keep_raw)?,
+                    ));
                 }
                 b"WorkoutEvent" => {
-                    workout_events.push(json_value_from_hk_element(reader, &element)?);
+                    children.push((
+                        WORKOUT_EVENT_TABLE_NAME.to_string(),
+                        database_row_from_element(reader, element, keep_raw)?,
+                    ));
                 }
                 b"WorkoutStatistics" => {
-                    let key = attribute_value_from_element(reader, &element, b"type")?;
-                    let val = json_value_from_hk_element(reader, &element)?;
-                    workout_stats.insert(key, val);
+                    children.push((
+                        WORKOUT_STATISTICS_TABLE_NAME.to_string(),
+                        database_row_from_element(reader, element, keep_raw)?,
+                    ));
                 }
                 other => {
                     debug!(
@@ -553,79 +1970,48 @@ async fn insert_hk_workout_element<'a, S: BufRead + Seek, R: BufRead>(
                     );
                 }
             },
+            Event::Start(element) if b"WorkoutEvent" == element.name().as_ref() => {
+                let child = database_row_from_element(reader, element, keep_raw)?;
+                let child =
+                    append_child_metadata_entries(reader, child, b"WorkoutEvent", keep_raw)?;
+                children.push((WORKOUT_EVENT_TABLE_NAME.to_string(), child));
+            }
+            Event::Start(element) if b"WorkoutStatistics" == element.name().as_ref() => {
+                let child = database_row_from_element(reader, element, keep_raw)?;
+                let child =
+                    append_child_metadata_entries(reader, child, b"WorkoutStatistics", keep_raw)?;
+                children.push((WORKOUT_STATISTICS_TABLE_NAME.to_string(), child));
+            }
+            // The nested WorkoutEvent and WorkoutStatistics elements are read as the workout's own
+            Event::Start(element) if b"WorkoutActivity" == element.name().as_ref() => {
+                children.push((
+                    WORKOUT_ACTIVITY_TABLE_NAME.to_string(),
+                    database_row_from_element(reader, element, keep_raw)?,
+                ));
+                activity = Some(children.len() - 1);
+            }
             // Handle the WorkoutRoute element
             Event::Start(element) => {
                 if b"WorkoutRoute" == element.name().as_ref() {
                     let mut inner_buf = Vec::new();
                     loop {
                         match reader.read_event_into(&mut inner_buf)? {
-                            Event::End(element) => {
-                                if b"WorkoutRoute" == element.name().as_ref() {
-                                    break;
-                                }
+                            Event::End(element) if b"WorkoutRoute" == element.name().as_ref() => {
+                                break
                             }
-                            Event::Empty(element) => {
-                                if b"FileReference" == element.name().as_ref() {
-                                    let mut path_value = Default::default();
-                                    for attribute in element.attributes() {
-                                        let attribute = attribute?;
-                                        if b"path" == attribute.key.as_ref() {
-                                            path_value =
-                                                attribute.decode_and_unescape_value(reader)?;
-                                            break;
-                                        }
+                            Event::Empty(element)
+                                if b"FileReference" == element.name().as_ref() =>
+                            {
+                                let mut path_value = Default::default();
+                                for attribute in element.attributes() {
+                                    let attribute = attribute?;
+                                    if b"path" == attribute.key.as_ref() {
+                                        path_value = attribute.decode_and_unescape_value(reader)?;
+                                        break;
                                     }
-                                    // Read the route gpx file at path_value
-                                    debug!("Reading route gpx file: {}", path_value);
-                                    let route_gpx_zip = zip_archive
-                                        .by_name(&format!("apple_health_export{}", path_value))?;
-                                    let route_reader = BufReader::with_capacity(
-                                        route_gpx_zip.size() as usize,
-                                        route_gpx_zip,
-                                    );
-                                    let mut route_xml =
-                                        quick_xml::Reader::from_reader(route_reader);
-                                    let mut coordinates = Vec::new();
-                                    let mut route_buf = Vec::new();
-                                    loop {
-                                        match route_xml.read_event_into(&mut route_buf)? {
-                                            // For now, we only care about extracting the lat/lon coordinates
-                                            // from the trkpt elements
-                                            Event::Start(element) => {
-                                                if b"trkpt" == element.name().as_ref() {
-                                                    let mut lat = Default::default();
-                                                    let mut lon = Default::default();
-                                                    for attribute in element.attributes() {
-                                                        let attribute = attribute?;
-                                                        if b"lat" == attribute.key.as_ref() {
-                                                            lat = attribute
-                                                                .decode_and_unescape_value(
-                                                                    &route_xml,
-                                                                )?;
-                                                        } else if b"lon" == attribute.key.as_ref() {
-                                                            lon = attribute
-                                                                .decode_and_unescape_value(
-                                                                    &route_xml,
-                                                                )?;
-                                                        }
-                                                    }
-                                                    coordinates.push(JsonValue::Array(vec![
-                                                        lon.parse::<f32>()?.into(),
-                                                        lat.parse::<f32>()?.into(),
-                                                    ]));
-                                                }
-                                            }
-                                            Event::Eof => break,
-                                            _ => {}
-                                        }
-                                    }
-                                    workout_route.insert(
-                                        "type",
-                                        JsonValue::String("LineString".to_string()),
-                                    );
-                                    workout_route
-                                        .insert("coordinates", JsonValue::Array(coordinates));
                                 }
+                                // Read the route gpx file at path_value on the pool
+                                route = Some(routes.read(path_value.into_owned()));
                             }
                             _ => {}
                         }
@@ -636,31 +2022,47 @@ async fn insert_hk_workout_element<'a, S: BufRead + Seek, R: BufRead>(
         }
         buf.clear();
     }
-    row.push((
-        "workoutEvents".to_string(),
-        DatabaseValue::Json(serde_json::to_value(workout_events)?),
-    ));
-    row.push((
-        "workoutStatistics".to_string(),
-        DatabaseValue::Json(serde_json::to_value(workout_stats)?),
-    ));
-    row.push((
-        "geometry".to_string(),
-        DatabaseValue::Json(serde_json::to_value(workout_route)?),
-    ));
-    insert_database_row(db, &WORKOUT_TABLE_NAME, row).await?;
-    Ok(())
+    ctx.write_workout(PendingWorkout {
+        row,
+        children,
+        route,
+    })
+    .await
+}
+
+/// Appends each MetadataEntry nested inside a child element to its row, up to its end tag
+fn append_child_metadata_entries<R: BufRead>(
+    reader: &mut quick_xml::Reader<R>,
+    mut row: DatabaseRow,
+    end: &[u8],
+    keep_raw: bool,
+) -> anyhow::Result<DatabaseRow> {
+    let mut buf = Vec::new();
+    loop {
+        match reader.read_event_into(&mut buf)? {
+            Event::Eof => break,
+            Event::End(element) if end == element.name().as_ref() => break,
+            Event::Empty(element) if b"MetadataEntry" == element.name().as_ref() => {
+                row = append_hk_metadata_entry_to_database_row(reader, element, row, keep_raw)?;
+            }
+            _ => {}
+        }
+        buf.clear();
+    }
+    Ok(row)
 }
 
 /// Inserts a single HealthKit Record element into the appropriate database table
 async fn insert_hk_record_element<'a, R: BufRead>(
-    db: &mut Transaction<'_, Sqlite>,
+    ctx: &mut ImportContext,
     reader: &mut quick_xml::Reader<R>,
     element: BytesStart<'a>,
 ) -> anyhow::Result<()> {
     // The name of the record table comes from the type attribute
     let table_name = attribute_value_from_element(reader, &element, b"type")?;
-    let mut row = database_row_from_element(reader, element)?;
+    let keep_raw = ctx.options.keeps_raw(KeepRaw::Json);
+    let mut row = database_row_from_element(reader, element, keep_raw)?;
+    let mut children = Vec::new();
     let mut buf = Vec::new();
     loop {
         match reader.read_event_into(&mut buf)? {
@@ -670,62 +2072,570 @@ async fn insert_hk_record_element<'a, R: BufRead>(
                     break;
                 }
             }
-            Event::Empty(element) => {
-                if b"MetadataEntry" == element.name().as_ref() {
-                    row = append_hk_metadata_entry_to_database_row(reader, element, row)?;
+            Event::Empty(element) | Event::Start(element) => {
+                let name = element.name();
+                if b"MetadataEntry" == name.as_ref() {
+                    row = append_hk_metadata_entry_to_database_row(reader, element, row, keep_raw)?;
+                } else if let Some(child_table_name) = record_child_table_name(name.as_ref()) {
+                    let child = database_row_from_element(reader, element, keep_raw)?;
+                    children.push((child_table_name.to_string(), child));
                 }
             }
-            Event::Start(_) => {}
             _ => continue,
         }
         buf.clear();
     }
-    insert_database_row(db, &table_name, row).await?;
-    Ok(())
-}
-
-/// Inserts a single database row into the specified table
-async fn insert_database_row(
-    db: &mut Transaction<'_, Sqlite>,
-    table_name: &str,
-    row: DatabaseRow,
-) -> anyhow::Result<()> {
-    let qs = format!(
-        r#"INSERT INTO {} ({}) VALUES ({})"#,
-        table_name,
-        row.iter()
-            .map(|(name, _)| get_valid_sqlite_identifier(name))
-            .collect::<Vec<_>>()
-            .join(", "),
-        row.iter()
-            .map(|(_, _)| "?")
-            .collect::<Vec<&str>>()
-            .join(", ")
+    let start_date = row.iter().find_map(|(name, value)| match value {
+        DatabaseValue::OffsetDateTime(date) if name == "startDate" => Some(*date),
+        _ => None,
+    });
+    heartbeat::resolve_times(
+        start_date,
+        children
+            .iter_mut()
+            .filter(|(child_table_name, _)| child_table_name == HEARTBEAT_TABLE_NAME)
+            .map(|(_, beat)| beat),
     );
-    let mut query = sqlx::query(&qs);
-    for (_, value) in row {
-        match value {
-            DatabaseValue::Integer(i) => query = query.bind(i),
-            DatabaseValue::Real(i) => query = query.bind(i),
-            DatabaseValue::OffsetDateTime(i) => query = query.bind(i),
-            DatabaseValue::Date(i) => query = query.bind(i),
-            DatabaseValue::Text(i) => query = query.bind(i),
-            DatabaseValue::Json(i) => query = query.bind(i),
-        }
-    }
-    query.execute(&mut *db).await?;
-    Ok(())
+    if children.is_empty() {
+        ctx.write(ParsedElement::Row { table_name, row }).await
+    } else {
+        ctx.write(ParsedElement::Record {
+            table_name,
+            row,
+            children,
+        })
+        .await
+    }
 }
 
-async fn create_db(db_url: &str) -> anyhow::Result<SqlitePool> {
-    // Create the database
-    if !sqlx::Sqlite::database_exists(db_url).await? {
-        sqlx::Sqlite::create_database(db_url).await?;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conformance::EXPORT_FILES;
+    use crate::testing::{self, TestDir};
+    use sqlx::SqlitePool;
+
+    /// Imports the conformance export with the options, returning the database
+    async fn import_export(dir: &TestDir, options: &ImportOptions) -> SqlitePool {
+        let export_dir = dir.write_export("export", EXPORT_FILES);
+        let db_url = dir.db_url("health.db");
+        testing::import(&export_dir, &db_url, options)
+            .await
+            .unwrap();
+        SqlitePool::connect(&db_url).await.unwrap()
+    }
+
+    /// Imports the conformance export with its export.xml cut short right after the first
+    /// occurrence of the text, returning the row counts of the tables and the value of the
+    /// truncation_reason in _meta
+    async fn import_cut_after(
+        dir: &TestDir,
+        name: &str,
+        text: &str,
+    ) -> (BTreeMap<String, i64>, Option<String>) {
+        let files: Vec<(&str, Vec<u8>)> = EXPORT_FILES
+            .iter()
+            .map(|(path, contents)| match *path {
+                "export.xml" => {
+                    let contents = std::str::from_utf8(contents).unwrap();
+                    let end = contents.find(text).unwrap() + text.len();
+                    (*path, contents.as_bytes()[..end].to_vec())
+                }
+                _ => (*path, contents.to_vec()),
+            })
+            .collect();
+        let files: Vec<(&str, &[u8])> = files
+            .iter()
+            .map(|(path, contents)| (*path, contents.as_slice()))
+            .collect();
+        let export_dir = dir.write_export(name, &files);
+        let db_url = dir.db_url(&format!("{}.db", name));
+        testing::import(&export_dir, &db_url, &ImportOptions::defaults())
+            .await
+            .unwrap();
+        let db = SqlitePool::connect(&db_url).await.unwrap();
+        let reason: Option<String> =
+            sqlx::query_scalar("SELECT value FROM _meta WHERE key = 'truncation_reason'")
+                .fetch_optional(&db)
+                .await
+                .unwrap();
+        db.close().await;
+        (testing::row_counts(&db_url).await, reason)
+    }
+
+    #[tokio::test]
+    async fn truncated_exports_import_only_complete_elements() {
+        let dir = TestDir::new("truncated-export");
+        // The text export.xml is cut after, and the rows of the record and workout tables
+        // expected from the elements before it
+        let cases: &[(&str, &[(&str, i64)])] = &[
+            // Within the attributes of the second heart rate sample
+            (
+                r#"startDate="2024-01-01 08:05:00 -0800" endDate="2024-01-01 08:05:00 -0800" value="71"#,
+                &[
+                    ("HKQuantityTypeIdentifierHeartRate", 1),
+                    ("HKQuantityTypeIdentifierStepCount", 0),
+                ],
+            ),
+            // Within the children of the HRV sample
+            (
+                r#"<InstantaneousBeatsPerMinute bpm="63""#,
+                &[
+                    ("HKQuantityTypeIdentifierHeartRate", 2),
+                    ("HKCategoryTypeIdentifierSleepAnalysis", 1),
+                    ("HKQuantityTypeIdentifierHeartRateVariabilitySDNN", 0),
+                    ("InstantaneousBeatsPerMinute", 0),
+                ],
+            ),
+            // Within the workout, after some of its children
+            (
+                r#"<WorkoutEvent type="HKWorkoutEventTypePause""#,
+                &[
+                    ("HKQuantityTypeIdentifierHeartRateVariabilitySDNN", 1),
+                    ("InstantaneousBeatsPerMinute", 3),
+                    ("HKDataTypeIdentifierAudiogram", 1),
+                    ("Workout", 0),
+                    ("WorkoutEvent", 0),
+                ],
+            ),
+            // Within the workout, right after one of its children ends
+            (
+                "</WorkoutActivity>",
+                &[
+                    ("Workout", 0),
+                    ("WorkoutActivity", 0),
+                    ("ActivitySummary", 0),
+                ],
+            ),
+            // After the last activity summary, without the closing </HealthData> tag. The last
+            // element read can't be told apart from one cut among its children, so it is left out.
+            (
+                r#"activeEnergyBurned="366.827" activeEnergyBurnedGoal="690" activeEnergyBurnedUnit="Cal" appleMoveTime="0" appleMoveTimeGoal="0" appleExerciseTime="11" appleExerciseTimeGoal="30" appleStandHours="10" appleStandHoursGoal="12"/>"#,
+                &[("Workout", 1), ("WorkoutEvent", 2), ("ActivitySummary", 1)],
+            ),
+        ];
+        for (i, (text, expected)) in cases.iter().enumerate() {
+            let (counts, reason) = import_cut_after(&dir, &format!("cut_{}", i), text).await;
+            assert!(reason.is_some(), "cut after {}", text);
+            for (table_name, rows) in *expected {
+                assert_eq!(
+                    counts.get(*table_name).copied().unwrap_or(0),
+                    *rows,
+                    "{} rows when cut after {}",
+                    table_name,
+                    text
+                );
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn workout_events_reference_their_workout() {
+        let dir = TestDir::new("workout-events");
+        let db = import_export(&dir, &ImportOptions::defaults()).await;
+        let events: Vec<(i64, String, String)> =
+            sqlx::query_as("SELECT workout_id, date, type FROM WorkoutEvent ORDER BY rowid")
+                .fetch_all(&db)
+                .await
+                .unwrap();
+        assert_eq!(
+            events,
+            [
+                (
+                    1,
+                    "2024-01-01T09:10:00-08:00".to_string(),
+                    "HKWorkoutEventTypePause".to_string()
+                ),
+                (
+                    1,
+                    "2024-01-01T09:11:00-08:00".to_string(),
+                    "HKWorkoutEventTypeResume".to_string()
+                ),
+            ]
+        );
+        let workout_id: i64 = sqlx::query_scalar("SELECT rowid FROM Workout")
+            .fetch_one(&db)
+            .await
+            .unwrap();
+        assert_eq!(workout_id, 1);
+        // The events are only stored in their own table
+        let event_columns: i64 = sqlx::query_scalar(
+            "SELECT COUNT(*) FROM pragma_table_info('Workout') WHERE name LIKE '%event%'",
+        )
+        .fetch_one(&db)
+        .await
+        .unwrap();
+        assert_eq!(event_columns, 0);
+    }
+
+    #[tokio::test]
+    async fn workout_child_tables_declare_a_foreign_key_to_their_workout() {
+        let dir = TestDir::new("workout-foreign-keys");
+        let options = ImportOptions {
+            route_points: true,
+            ..ImportOptions::defaults()
+        };
+        let db = import_export(&dir, &options).await;
+        for table_name in [
+            "WorkoutEvent",
+            "WorkoutStatistics",
+            "WorkoutActivity",
+            "WorkoutRoutePoint",
+        ] {
+            let foreign_keys: Vec<(String, String, String)> = sqlx::query_as(
+                r#"SELECT "from", "table", "to" FROM pragma_foreign_key_list(?)"#,
+            )
+            .bind(table_name)
+            .fetch_all(&db)
+            .await
+            .unwrap();
+            assert_eq!(
+                foreign_keys,
+                [(
+                    "workout_id".to_string(),
+                    "Workout".to_string(),
+                    "id".to_string()
+                )],
+                "{}",
+                table_name
+            );
+        }
+        let violations: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM pragma_foreign_key_check")
+            .fetch_one(&db)
+            .await
+            .unwrap();
+        assert_eq!(violations, 0);
+        // The key is the rowid the child rows were written with
+        let workout_id: i64 = sqlx::query_scalar("SELECT id FROM Workout WHERE id = rowid")
+            .fetch_one(&db)
+            .await
+            .unwrap();
+        assert_eq!(workout_id, 1);
+    }
+
+    #[tokio::test]
+    async fn workout_statistics_are_stored_in_their_own_table() {
+        let dir = TestDir::new("workout-statistics");
+        let db = import_export(&dir, &ImportOptions::defaults()).await;
+        let statistics: Vec<(i64, String, f64, String)> =
+            sqlx::query_as("SELECT workout_id, type, sum, unit FROM WorkoutStatistics")
+                .fetch_all(&db)
+                .await
+                .unwrap();
+        assert_eq!(
+            statistics,
+            [(
+                1,
+                "HKQuantityTypeIdentifierDistanceWalkingRunning".to_string(),
+                5.0125,
+                "km".to_string()
+            )]
+        );
+        let statistics_columns: i64 = sqlx::query_scalar(
+            "SELECT COUNT(*) FROM pragma_table_info('Workout') WHERE name LIKE '%statistic%' OR name LIKE '%sum%'",
+        )
+        .fetch_one(&db)
+        .await
+        .unwrap();
+        assert_eq!(statistics_columns, 0);
+    }
+
+    #[tokio::test]
+    async fn route_points_are_stored_with_their_workout() {
+        let dir = TestDir::new("route-points");
+        let options = ImportOptions {
+            route_points: true,
+            ..ImportOptions::defaults()
+        };
+        let db = import_export(&dir, &options).await;
+        type Point = (i64, f64, f64, f64, String, f64, f64, f64, f64);
+        let points: Vec<Point> = sqlx::query_as(
+            "SELECT workout_id, lat, lon, ele, time, speed, course, hAcc, vAcc FROM WorkoutRoutePoint ORDER BY rowid",
+        )
+        .fetch_all(&db)
+        .await
+        .unwrap();
+        assert_eq!(points.len(), 3);
+        assert_eq!(
+            points[0],
+            (
+                1,
+                34.045088,
+                -118.232524,
+                85.651855,
+                "2024-01-01T17:00:45Z".to_string(),
+                1.154731,
+                169.804688,
+                2.270945,
+                1.579882
+            )
+        );
+        // Without --route-points the table isn't created
+        let dir = TestDir::new("route-points-off");
+        let db = import_export(&dir, &ImportOptions::defaults()).await;
+        let tables: i64 = sqlx::query_scalar(
+            "SELECT COUNT(*) FROM sqlite_master WHERE name = 'WorkoutRoutePoint'",
+        )
+        .fetch_one(&db)
+        .await
+        .unwrap();
+        assert_eq!(tables, 0);
     }
 
-    // Connect to the database
-    let db = SqlitePool::connect(db_url).await?;
-    // Run migrations
-    sqlx::migrate!().run(&db).await?;
-    Ok(db)
+    #[tokio::test]
+    async fn tables_are_created_with_their_columns_in_order() {
+        let dir = TestDir::new("column-order");
+        let db = import_export(&dir, &ImportOptions::defaults()).await;
+        let columns: Vec<(String, String)> =
+            sqlx::query_as("SELECT name, type FROM pragma_table_info('Workout') ORDER BY cid")
+                .fetch_all(&db)
+                .await
+                .unwrap();
+        // Numeric columns first, then DATE, TEXT, and JSON, with the metadata columns last
+        let ranks: Vec<(bool, usize)> = columns
+            .iter()
+            .map(|(name, ty)| {
+                let type_rank = match ty.as_str() {
+                    "INTEGER" | "REAL" => 0,
+                    "DATE" => 1,
+                    "TEXT" => 2,
+                    _ => 3,
+                };
+                (name.starts_with(METADATA_COLUMN_PREFIX), type_rank)
+            })
+            .collect();
+        let mut sorted = ranks.clone();
+        sorted.sort();
+        assert!(ranks.iter().any(|(metadata, _)| *metadata));
+        assert_eq!(ranks, sorted);
+        let column_order: String =
+            sqlx::query_scalar("SELECT value FROM _meta WHERE key = 'column_order'")
+                .fetch_one(&db)
+                .await
+                .unwrap();
+        assert_eq!(column_order, schema::COLUMN_ORDER);
+    }
+
+    #[tokio::test]
+    async fn ecg_samples_reference_their_recording() {
+        let dir = TestDir::new("ecg-samples");
+        let db = import_export(&dir, &ImportOptions::defaults()).await;
+        let recording: (i64, String, i64) =
+            sqlx::query_as("SELECT rowid, classification, sampleCount FROM Ecg")
+                .fetch_one(&db)
+                .await
+                .unwrap();
+        assert_eq!(recording.1, "Sinus Rhythm");
+        let samples: Vec<(i64, i64, f64)> = sqlx::query_as(
+            "SELECT ecg_id, sample, microvolts FROM EcgVoltageSample ORDER BY sample",
+        )
+        .fetch_all(&db)
+        .await
+        .unwrap();
+        assert_eq!(samples.len() as i64, recording.2);
+        assert!(samples.iter().all(|(ecg_id, _, _)| *ecg_id == recording.0));
+        assert_eq!(
+            samples[..2],
+            [(recording.0, 0, -12.5), (recording.0, 1, -10.25)]
+        );
+    }
+
+    #[tokio::test]
+    async fn interrupted_imports_record_why_they_stopped() {
+        let dir = TestDir::new("interrupted-import");
+        let export_dir = dir.write_export("export", EXPORT_FILES);
+        // Interrupted at the first point it checks after the schema pass
+        let mut db_url = String::new();
+        for check in 0.. {
+            db_url = dir.db_url(&format!("interrupted_{}.db", check));
+            crate::interrupt::interrupt_at_check(Some(check));
+            let status = testing::import(&export_dir, &db_url, &ImportOptions::defaults()).await;
+            crate::interrupt::interrupt_at_check(None);
+            match status {
+                Ok(ImportStatus::Interrupted(reason)) => {
+                    assert_eq!(reason, "interrupted by the test");
+                    break;
+                }
+                Err(e) if e.to_string().ends_with("before any rows were written") => {}
+                Ok(ImportStatus::Complete) => panic!("the import was never interrupted"),
+                Err(e) => panic!("{}", e),
+            }
+        }
+        let db = SqlitePool::connect(&db_url).await.unwrap();
+        let meta: Vec<(String, String)> = sqlx::query_as(
+            "SELECT key, value FROM _meta WHERE key IN ('complete', 'interrupted', 'rows_imported') ORDER BY key",
+        )
+        .fetch_all(&db)
+        .await
+        .unwrap();
+        assert_eq!(meta.len(), 3);
+        assert_eq!(meta[0], ("complete".to_string(), "false".to_string()));
+        assert_eq!(
+            meta[1],
+            (
+                "interrupted".to_string(),
+                "interrupted by the test".to_string()
+            )
+        );
+        assert_eq!(meta[2], ("rows_imported".to_string(), "0".to_string()));
+    }
+
+    #[tokio::test]
+    async fn audiogram_sensitivity_points_reference_their_record() {
+        let dir = TestDir::new("audiogram");
+        let db = import_export(&dir, &ImportOptions::defaults()).await;
+        type Point = (i64, i64, String, i64, i64, String);
+        let points: Vec<Point> = sqlx::query_as(
+            r#"SELECT record_id, frequencyValue, frequencyUnit, leftEarValue, rightEarValue, leftEarUnit
+            FROM AudiogramSensitivityPoint ORDER BY frequencyValue"#,
+        )
+        .fetch_all(&db)
+        .await
+        .unwrap();
+        let record_id: i64 = sqlx::query_scalar("SELECT rowid FROM HKDataTypeIdentifierAudiogram")
+            .fetch_one(&db)
+            .await
+            .unwrap();
+        let point = |frequency, left, right| {
+            (
+                record_id,
+                frequency,
+                "Hz".to_string(),
+                left,
+                right,
+                "dBHL".to_string(),
+            )
+        };
+        assert_eq!(points, [point(500, 10, 15), point(1000, 5, 20)]);
+    }
+
+    #[tokio::test]
+    async fn rows_entered_by_hand_are_excluded_when_asked() {
+        let dir = TestDir::new("exclude-user-entered");
+        let options = ImportOptions {
+            exclude_user_entered: true,
+            ..ImportOptions::defaults()
+        };
+        let db = import_export(&dir, &options).await;
+        // The only body mass sample was entered by hand
+        let body_mass: i64 =
+            sqlx::query_scalar("SELECT COUNT(*) FROM HKQuantityTypeIdentifierBodyMass")
+                .fetch_one(&db)
+                .await
+                .unwrap();
+        assert_eq!(body_mass, 0);
+        let heart_rate: i64 =
+            sqlx::query_scalar("SELECT COUNT(*) FROM HKQuantityTypeIdentifierHeartRate")
+                .fetch_one(&db)
+                .await
+                .unwrap();
+        assert_eq!(heart_rate, 2);
+    }
+
+    #[tokio::test]
+    async fn the_profile_is_a_single_row_of_the_latest_import() {
+        let dir = TestDir::new("me");
+        let db_url = dir.db_url("health.db");
+        let options = ImportOptions {
+            append: true,
+            ..ImportOptions::defaults()
+        };
+        for date_of_birth in ["1980-01-01", "1990-01-01"] {
+            let files: Vec<(&str, Vec<u8>)> = EXPORT_FILES
+                .iter()
+                .map(|(path, contents)| match *path {
+                    "export.xml" => {
+                        let contents = String::from_utf8_lossy(contents).replace(
+                            "DateOfBirth=\"1990-01-01\"",
+                            &format!("DateOfBirth=\"{}\"", date_of_birth),
+                        );
+                        (*path, contents.into_bytes())
+                    }
+                    _ => (*path, contents.to_vec()),
+                })
+                .collect();
+            let files: Vec<(&str, &[u8])> = files
+                .iter()
+                .map(|(path, contents)| (*path, contents.as_slice()))
+                .collect();
+            let export_dir = dir.write_export(date_of_birth, &files);
+            testing::import(&export_dir, &db_url, &options)
+                .await
+                .unwrap();
+        }
+        let db = SqlitePool::connect(&db_url).await.unwrap();
+        let me: Vec<(String, String)> = sqlx::query_as(
+            "SELECT HKCharacteristicTypeIdentifierDateOfBirth, HKCharacteristicTypeIdentifierBiologicalSex FROM Me",
+        )
+        .fetch_all(&db)
+        .await
+        .unwrap();
+        assert_eq!(
+            me,
+            [(
+                "1990-01-01".to_string(),
+                "HKBiologicalSexNotSet".to_string()
+            )]
+        );
+    }
+
+    #[tokio::test]
+    async fn the_export_date_locale_and_version_are_recorded() {
+        let dir = TestDir::new("export-metadata");
+        let db = import_export(&dir, &ImportOptions::defaults()).await;
+        let export: Vec<(String, String, String, String)> = sqlx::query_as(
+            "SELECT exportDate, locale, exportVersion, toolVersion FROM export_metadata",
+        )
+        .fetch_all(&db)
+        .await
+        .unwrap();
+        assert_eq!(
+            export,
+            [(
+                "2024-01-02T09:00:00-08:00".to_string(),
+                "en_US".to_string(),
+                "14".to_string(),
+                env!("CARGO_PKG_VERSION").to_string()
+            )]
+        );
+    }
+
+    #[tokio::test]
+    async fn the_export_is_recorded_without_an_export_date() {
+        let dir = TestDir::new("export-metadata-without-date");
+        let export_xml = std::str::from_utf8(EXPORT_FILES[0].1).unwrap();
+        let export_date = export_xml.find("<ExportDate").unwrap();
+        let export_date_end = export_date + export_xml[export_date..].find("/>").unwrap() + 2;
+        let export_xml = [&export_xml[..export_date], &export_xml[export_date_end..]].concat();
+        let files: Vec<(&str, &[u8])> = EXPORT_FILES
+            .iter()
+            .map(|(path, contents)| match *path {
+                "export.xml" => (*path, export_xml.as_bytes()),
+                _ => (*path, *contents),
+            })
+            .collect();
+        let export_dir = dir.write_export("export", &files);
+        let db_url = dir.db_url("health.db");
+        testing::import(&export_dir, &db_url, &ImportOptions::defaults())
+            .await
+            .unwrap();
+        let db = SqlitePool::connect(&db_url).await.unwrap();
+        let export: Vec<(Option<String>, String, String, String, bool)> = sqlx::query_as(
+            "SELECT exportDate, locale, exportVersion, toolVersion, importedAt IS NOT NULL
+            FROM export_metadata",
+        )
+        .fetch_all(&db)
+        .await
+        .unwrap();
+        assert_eq!(
+            export,
+            [(
+                None,
+                "en_US".to_string(),
+                "14".to_string(),
+                env!("CARGO_PKG_VERSION").to_string(),
+                true
+            )]
+        );
+    }
 }
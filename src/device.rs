@@ -0,0 +1,377 @@
+//! Parsing the device descriptions of the samples
+use crate::core::{DatabaseRow, DatabaseValue, HKTables};
+use std::collections::{BTreeMap, HashMap};
+
+/// Splits a device description such as
+/// `<<HKDevice: 0x283a4c4b0>, name:Apple Watch, manufacturer:Apple Inc., model:Watch, hardware:Watch6,1, software:9.1>`
+/// into its key and value pairs
+pub(crate) fn device_fields(device: &str) -> Vec<(&str, &str)> {
+    device
+        .trim_start_matches('<')
+        .trim_end_matches('>')
+        .split(", ")
+        .filter_map(|field| field.split_once(':'))
+        .collect()
+}
+
+/// Returns the name and hardware model of a device description, e.g. `Apple Watch (Watch6,1)`,
+/// or the description itself if it has no name
+pub(crate) fn device_label(device: &str) -> String {
+    let fields = device_fields(device);
+    let field = |key: &str| {
+        fields
+            .iter()
+            .find(|(name, _)| *name == key)
+            .map(|(_, value)| *value)
+    };
+    match (field("name"), field("hardware")) {
+        (Some(name), Some(hardware)) => format!("{} ({})", name, hardware),
+        (Some(name), None) => name.to_string(),
+        _ => device.to_string(),
+    }
+}
+
+/// Prefix of the columns holding the fields of the device description with --device-columns
+const DEVICE_FIELD_COLUMN_PREFIX: &str = "device_";
+
+/// Adds a column for each field of the device description to the tables with a device column,
+/// e.g. device_name and device_software
+pub fn extend_tables(tables: &mut HKTables) {
+    for columns in tables.values_mut() {
+        if columns.contains_key(DEVICE_COLUMN) {
+            for field in DEVICE_FIELDS {
+                columns.insert(format!("{}{}", DEVICE_FIELD_COLUMN_PREFIX, field), "TEXT");
+            }
+        }
+    }
+}
+
+/// Adds the fields of the device description of the row to their columns. The versions are kept
+/// as text, as they have several parts, e.g. 16.1.2.
+pub fn parse_row(mut row: DatabaseRow) -> DatabaseRow {
+    let device = row.iter().find_map(|(name, value)| match value {
+        DatabaseValue::Text(device) if name == DEVICE_COLUMN => Some(device.clone()),
+        _ => None,
+    });
+    if let Some(device) = device {
+        for (key, value) in device_fields(&device) {
+            let value = value.trim();
+            if DEVICE_FIELDS.contains(&key) && !value.is_empty() {
+                row.push((
+                    format!("{}{}", DEVICE_FIELD_COLUMN_PREFIX, key),
+                    DatabaseValue::Text(value.to_string()),
+                ));
+            }
+        }
+    }
+    row
+}
+
+/// Name of the table of the distinct devices with --device-table
+pub(crate) const DEVICE_TABLE_NAME: &str = "Device";
+
+/// Column holding the device description of a sample, replaced with --device-table
+const DEVICE_COLUMN: &str = "device";
+
+/// Column referring to the id of the sample's row of the Device table with --device-table
+pub(crate) const DEVICE_ID_COLUMN: &str = "device_id";
+
+/// Fields of the device descriptions stored in the columns of the Device table
+const DEVICE_FIELDS: &[&str] = &["name", "manufacturer", "model", "hardware", "software"];
+
+/// The id of each distinct device description, numbered in the order they are first seen
+#[derive(Default)]
+pub(crate) struct DeviceTable {
+    ids: HashMap<String, i64>,
+}
+
+impl DeviceTable {
+    /// Starts from the devices of an earlier import of the database, as ids and descriptions
+    pub fn with_devices(devices: Vec<(i64, String)>) -> Self {
+        Self {
+            ids: devices
+                .into_iter()
+                .map(|(id, description)| (description, id))
+                .collect(),
+        }
+    }
+
+    pub fn table_schema() -> HKTables {
+        let mut columns: BTreeMap<String, &'static str> = [
+            ("id".to_string(), "INTEGER"),
+            ("description".to_string(), "TEXT"),
+        ]
+        .into_iter()
+        .collect();
+        for field in DEVICE_FIELDS {
+            columns.insert(field.to_string(), "TEXT");
+        }
+        [(DEVICE_TABLE_NAME.to_string(), columns)]
+            .into_iter()
+            .collect()
+    }
+
+    /// Replaces the device column of the tables with the device_id column
+    pub fn extend_tables(tables: &mut HKTables) {
+        for columns in tables.values_mut() {
+            if columns.remove(DEVICE_COLUMN).is_some() {
+                columns.insert(DEVICE_ID_COLUMN.to_string(), "INTEGER");
+            }
+        }
+    }
+
+    /// Replaces the device description of the row with the id of the device, returning the row of
+    /// the Device table to insert too when the device is new
+    pub fn replace_device(&mut self, row: DatabaseRow) -> (DatabaseRow, Option<DatabaseRow>) {
+        let mut device_row = None;
+        let row = row
+            .into_iter()
+            .map(|(name, value)| match value {
+                DatabaseValue::Text(description) if name == DEVICE_COLUMN => {
+                    let id = match self.ids.get(&description) {
+                        Some(id) => *id,
+                        None => {
+                            let id = self.ids.values().max().map_or(1, |id| id + 1);
+                            device_row = Some(Self::device_row(id, &description));
+                            self.ids.insert(description, id);
+                            id
+                        }
+                    };
+                    (DEVICE_ID_COLUMN.to_string(), DatabaseValue::Integer(id))
+                }
+                value => (name, value),
+            })
+            .collect();
+        (row, device_row)
+    }
+
+    fn device_row(id: i64, description: &str) -> DatabaseRow {
+        let mut row = vec![
+            ("id".to_string(), DatabaseValue::Integer(id)),
+            (
+                "description".to_string(),
+                DatabaseValue::Text(description.to_string()),
+            ),
+        ];
+        let fields = device_fields(description);
+        for field in DEVICE_FIELDS {
+            let value = fields
+                .iter()
+                .find(|(name, _)| name == field)
+                .map(|(_, value)| value.trim())
+                .filter(|value| !value.is_empty());
+            if let Some(value) = value {
+                row.push((field.to_string(), DatabaseValue::Text(value.to_string())));
+            }
+        }
+        row
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WATCH: &str = "<<HKDevice: 0x283a4c4b0>, name:Apple Watch, manufacturer:Apple Inc., model:Watch, hardware:Watch6,1, software:9.1>";
+    const PHONE: &str = "<<HKDevice: 0x2816d5a40>, name:iPhone, manufacturer:Apple Inc., model:iPhone, hardware:iPhone14,2, software:16.1.2, creation date:2022-11-20 18:03:51 +0000>";
+    /// A scale without a hardware model, with an empty software version
+    const SCALE: &str = "<<HKDevice: 0x2816d5b80>, name:Body Cardio, manufacturer:Withings, model:WBS04, software:, localIdentifier:00:24:e4:aa:bb:cc>";
+
+    fn texts(row: &DatabaseRow) -> Vec<(&str, &str)> {
+        row.iter()
+            .map(|(name, value)| match value {
+                DatabaseValue::Text(text) => (name.as_str(), text.as_str()),
+                DatabaseValue::Integer(_) => (name.as_str(), "<integer>"),
+                other => panic!("unexpected value {:?}", other),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn device_descriptions_are_split_into_their_fields() {
+        assert_eq!(
+            device_fields(WATCH),
+            [
+                ("HKDevice", " 0x283a4c4b0>"),
+                ("name", "Apple Watch"),
+                ("manufacturer", "Apple Inc."),
+                ("model", "Watch"),
+                ("hardware", "Watch6,1"),
+                ("software", "9.1"),
+            ]
+        );
+        // Only the first colon separates the key, as the values can have colons
+        assert_eq!(
+            device_fields(SCALE).last(),
+            Some(&("localIdentifier", "00:24:e4:aa:bb:cc"))
+        );
+        assert_eq!(device_fields(""), []);
+        assert_eq!(device_fields("Apple Watch"), []);
+    }
+
+    #[test]
+    fn device_labels_are_the_name_and_hardware_model() {
+        assert_eq!(device_label(WATCH), "Apple Watch (Watch6,1)");
+        assert_eq!(device_label(SCALE), "Body Cardio");
+        assert_eq!(device_label("Apple Watch"), "Apple Watch");
+        assert_eq!(
+            device_label("<<HKDevice: 0x1>, model:Watch>"),
+            "<<HKDevice: 0x1>, model:Watch>"
+        );
+    }
+
+    #[test]
+    fn each_distinct_device_is_numbered_once() {
+        let mut devices = DeviceTable::default();
+        let sample = |device: &str| {
+            vec![
+                ("value".to_string(), DatabaseValue::Real(62.0)),
+                (
+                    DEVICE_COLUMN.to_string(),
+                    DatabaseValue::Text(device.to_string()),
+                ),
+            ]
+        };
+        let (row, device_row) = devices.replace_device(sample(WATCH));
+        assert_eq!(row[1].0, DEVICE_ID_COLUMN);
+        assert!(matches!(row[1].1, DatabaseValue::Integer(1)));
+        assert_eq!(
+            texts(&device_row.unwrap()),
+            [
+                ("id", "<integer>"),
+                ("description", WATCH),
+                ("name", "Apple Watch"),
+                ("manufacturer", "Apple Inc."),
+                ("model", "Watch"),
+                ("hardware", "Watch6,1"),
+                ("software", "9.1"),
+            ]
+        );
+        let (row, device_row) = devices.replace_device(sample(PHONE));
+        assert!(matches!(row[1].1, DatabaseValue::Integer(2)));
+        assert!(device_row.is_some());
+        let (row, device_row) = devices.replace_device(sample(WATCH));
+        assert!(matches!(row[1].1, DatabaseValue::Integer(1)));
+        assert!(device_row.is_none());
+        // Rows without a device are left as they are
+        let row = vec![("value".to_string(), DatabaseValue::Real(62.0))];
+        let (row, device_row) = devices.replace_device(row);
+        assert_eq!(row.len(), 1);
+        assert!(device_row.is_none());
+    }
+
+    #[test]
+    fn missing_and_empty_device_fields_are_left_out() {
+        let (_, device_row) = DeviceTable::default().replace_device(vec![(
+            DEVICE_COLUMN.to_string(),
+            DatabaseValue::Text(SCALE.to_string()),
+        )]);
+        assert_eq!(
+            texts(&device_row.unwrap()),
+            [
+                ("id", "<integer>"),
+                ("description", SCALE),
+                ("name", "Body Cardio"),
+                ("manufacturer", "Withings"),
+                ("model", "WBS04"),
+            ]
+        );
+        // A malformed description is kept as the description alone
+        let (_, device_row) = DeviceTable::default().replace_device(vec![(
+            DEVICE_COLUMN.to_string(),
+            DatabaseValue::Text("Apple Watch".to_string()),
+        )]);
+        assert_eq!(
+            texts(&device_row.unwrap()),
+            [("id", "<integer>"), ("description", "Apple Watch")]
+        );
+    }
+
+    #[test]
+    fn devices_of_an_earlier_import_keep_their_ids() {
+        let mut devices =
+            DeviceTable::with_devices(vec![(1, WATCH.to_string()), (5, PHONE.to_string())]);
+        let row = vec![(
+            DEVICE_COLUMN.to_string(),
+            DatabaseValue::Text(PHONE.to_string()),
+        )];
+        let (row, device_row) = devices.replace_device(row);
+        assert!(matches!(row[0].1, DatabaseValue::Integer(5)));
+        assert!(device_row.is_none());
+        let row = vec![(
+            DEVICE_COLUMN.to_string(),
+            DatabaseValue::Text(SCALE.to_string()),
+        )];
+        let (row, _) = devices.replace_device(row);
+        assert!(matches!(row[0].1, DatabaseValue::Integer(6)));
+    }
+
+    #[test]
+    fn device_columns_hold_the_fields_of_the_description() {
+        let mut tables = HKTables::from([
+            (
+                "HKQuantityTypeIdentifierHeartRate".to_string(),
+                BTreeMap::from([(DEVICE_COLUMN.to_string(), "TEXT")]),
+            ),
+            ("Me".to_string(), BTreeMap::new()),
+        ]);
+        extend_tables(&mut tables);
+        let columns: Vec<&str> = tables["HKQuantityTypeIdentifierHeartRate"]
+            .keys()
+            .map(String::as_str)
+            .collect();
+        assert_eq!(
+            columns,
+            [
+                "device",
+                "device_hardware",
+                "device_manufacturer",
+                "device_model",
+                "device_name",
+                "device_software"
+            ]
+        );
+        assert!(tables["Me"].is_empty());
+        let row = parse_row(vec![(
+            DEVICE_COLUMN.to_string(),
+            DatabaseValue::Text(PHONE.to_string()),
+        )]);
+        assert_eq!(
+            texts(&row),
+            [
+                ("device", PHONE),
+                ("device_name", "iPhone"),
+                ("device_manufacturer", "Apple Inc."),
+                ("device_model", "iPhone"),
+                ("device_hardware", "iPhone14,2"),
+                ("device_software", "16.1.2"),
+            ]
+        );
+    }
+
+    #[test]
+    fn device_columns_leave_out_missing_and_unknown_fields() {
+        let row = parse_row(vec![(
+            DEVICE_COLUMN.to_string(),
+            DatabaseValue::Text(SCALE.to_string()),
+        )]);
+        assert_eq!(
+            texts(&row),
+            [
+                ("device", SCALE),
+                ("device_name", "Body Cardio"),
+                ("device_manufacturer", "Withings"),
+                ("device_model", "WBS04"),
+            ]
+        );
+        for device in ["Apple Watch", "", "<<HKDevice: 0x1>, name>"] {
+            let row = parse_row(vec![(
+                DEVICE_COLUMN.to_string(),
+                DatabaseValue::Text(device.to_string()),
+            )]);
+            assert_eq!(row.len(), 1, "{}", device);
+        }
+        let row = parse_row(vec![("value".to_string(), DatabaseValue::Real(62.0))]);
+        assert_eq!(row.len(), 1);
+    }
+}
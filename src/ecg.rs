@@ -0,0 +1,204 @@
+//! Parsing the electrocardiogram recordings exported as CSV files next to export.xml
+use crate::core::{database_value_from_hk_value_str, DatabaseRow, DatabaseValue, HKTables};
+use log::*;
+use std::io::Read;
+
+/// Name of the table holding one row per recording
+pub const ECG_TABLE_NAME: &str = "Ecg";
+/// Name of the table holding one row per voltage sample of a recording
+pub const ECG_SAMPLE_TABLE_NAME: &str = "EcgVoltageSample";
+/// Column of EcgVoltageSample referring to the rowid of the recording in Ecg
+pub const ECG_ID_COLUMN: &str = "ecg_id";
+
+/// Header fields of the CSV file and the Ecg columns they are stored in. The name and date of
+/// birth fields are skipped, as the same values are found in the Me element of export.xml.
+const HEADER_COLUMNS: &[(&str, &str, &str)] = &[
+    ("Recorded Date", "startDate", "DATE"),
+    ("Classification", "classification", "TEXT"),
+    ("Symptoms", "symptoms", "TEXT"),
+    ("Software Version", "softwareVersion", "TEXT"),
+    ("Device", "device", "TEXT"),
+    ("Sample Rate", "sampleRate", "REAL"),
+    ("Lead", "lead", "TEXT"),
+    ("Unit", "unit", "TEXT"),
+];
+const SKIPPED_HEADER_FIELDS: &[&str] = &["Name", "Date of Birth"];
+
+/// The recording attributes and voltage samples of an electrocardiogram CSV file
+pub struct Electrocardiogram {
+    pub row: DatabaseRow,
+    pub samples: Vec<f64>,
+}
+
+/// Adds the Ecg and EcgVoltageSample tables
+pub fn extend_tables(tables: &mut HKTables) {
+    let mut columns: Vec<(&str, &'static str)> = vec![("file", "TEXT"), ("sampleCount", "INTEGER")];
+    columns.extend(HEADER_COLUMNS.iter().map(|(_, name, ty)| (*name, *ty)));
+    tables.insert(
+        ECG_TABLE_NAME.to_string(),
+        columns
+            .iter()
+            .map(|(name, ty)| (name.to_string(), *ty))
+            .collect(),
+    );
+    let sample_columns = [
+        (ECG_ID_COLUMN, "INTEGER"),
+        ("sample", "INTEGER"),
+        ("microvolts", "REAL"),
+    ];
+    tables.insert(
+        ECG_SAMPLE_TABLE_NAME.to_string(),
+        sample_columns
+            .iter()
+            .map(|(name, ty)| (name.to_string(), *ty))
+            .collect(),
+    );
+}
+
+/// Reads an electrocardiogram CSV file. The file starts with "field,value" header lines,
+/// followed by one voltage sample per line.
+pub fn read_ecg<R: Read>(path: &str, reader: R) -> anyhow::Result<Electrocardiogram> {
+    let mut csv_reader = ::csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .from_reader(reader);
+    let mut row = vec![("file".to_string(), DatabaseValue::Text(path.to_string()))];
+    let mut samples = Vec::new();
+    for record in csv_reader.records() {
+        let record = record?;
+        let field = record.get(0).unwrap_or_default().trim();
+        if field.is_empty() {
+            continue;
+        }
+        if let Ok(sample) = field.parse::<f64>() {
+            samples.push(sample);
+            continue;
+        }
+        // Values containing commas, such as a list of symptoms, may span several fields
+        let value = record
+            .iter()
+            .skip(1)
+            .map(str::trim)
+            .filter(|value| !value.is_empty())
+            .collect::<Vec<_>>()
+            .join(", ");
+        match HEADER_COLUMNS
+            .iter()
+            .find(|(header, _, _)| *header == field)
+        {
+            Some(_) if value.is_empty() => {}
+            Some((_, "sampleRate", _)) => {
+                // e.g. "512 hertz"
+                let rate = value.split_whitespace().next().unwrap_or_default();
+                match rate.parse::<f64>() {
+                    Ok(rate) => row.push(("sampleRate".to_string(), DatabaseValue::Real(rate))),
+                    Err(_) => warn!("Unrecognized ECG sample rate \"{}\" in {}", value, path),
+                }
+            }
+            Some((_, "startDate", _)) => match database_value_from_hk_value_str(&value) {
+                date @ DatabaseValue::OffsetDateTime(_) => {
+                    row.push(("startDate".to_string(), date));
+                }
+                _ => warn!("Unrecognized ECG recorded date \"{}\" in {}", value, path),
+            },
+            Some((_, column, _)) => row.push((column.to_string(), DatabaseValue::Text(value))),
+            None if SKIPPED_HEADER_FIELDS.contains(&field) => {}
+            None => debug!("Skipping ECG header field \"{}\" in {}", field, path),
+        }
+    }
+    let unit = row.iter().find(|(name, _)| name == "unit");
+    if let Some((_, DatabaseValue::Text(unit))) = unit {
+        if unit != "µV" {
+            warn!("ECG samples in {} are in {} rather than µV", path, unit);
+        }
+    }
+    row.push((
+        "sampleCount".to_string(),
+        DatabaseValue::Integer(i64::try_from(samples.len())?),
+    ));
+    Ok(Electrocardiogram { row, samples })
+}
+
+/// Converts a voltage sample to a row of the EcgVoltageSample table, without the ecg_id column
+pub fn sample_row(index: usize, microvolts: f64) -> anyhow::Result<DatabaseRow> {
+    Ok(vec![
+        (
+            "sample".to_string(),
+            DatabaseValue::Integer(i64::try_from(index)?),
+        ),
+        ("microvolts".to_string(), DatabaseValue::Real(microvolts)),
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ECG: &str = r#"Name,Jane Appleseed
+Date of Birth,"Jan 1, 1990"
+Recorded Date,2024-01-01 10:00:00 -0800
+Classification,Sinus Rhythm
+Symptoms,Chest tightness or pain,Shortness of breath
+Software Version,2
+Device,"Apple Watch"
+Sample Rate,512 hertz
+Lead,Lead I
+Unit,µV
+Custom Field,1
+
+-12.5
+3.125
+42
+"#;
+
+    fn texts(row: &DatabaseRow) -> Vec<(&str, String)> {
+        row.iter()
+            .map(|(name, value)| (name.as_str(), value.to_text()))
+            .collect()
+    }
+
+    #[test]
+    fn header_fields_and_samples_are_read() {
+        let ecg = read_ecg("/electrocardiograms/ecg.csv", ECG.as_bytes()).unwrap();
+        assert_eq!(
+            texts(&ecg.row),
+            [
+                ("file", "/electrocardiograms/ecg.csv".to_string()),
+                ("startDate", "2024-01-01T10:00:00-08:00".to_string()),
+                ("classification", "Sinus Rhythm".to_string()),
+                // Values with commas are joined back together
+                (
+                    "symptoms",
+                    "Chest tightness or pain, Shortness of breath".to_string()
+                ),
+                ("softwareVersion", "2".to_string()),
+                ("device", "Apple Watch".to_string()),
+                ("sampleRate", "512".to_string()),
+                ("lead", "Lead I".to_string()),
+                ("unit", "µV".to_string()),
+                ("sampleCount", "3".to_string()),
+            ]
+        );
+        assert_eq!(ecg.samples, [-12.5, 3.125, 42.0]);
+        assert_eq!(
+            texts(&sample_row(2, 42.0).unwrap()),
+            [
+                ("sample", "2".to_string()),
+                ("microvolts", "42".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn empty_and_unrecognized_values_are_skipped() {
+        let csv = "Recorded Date,yesterday\nSymptoms,\nSample Rate,fast\n\n1.5\n";
+        let ecg = read_ecg("ecg.csv", csv.as_bytes()).unwrap();
+        assert_eq!(
+            texts(&ecg.row),
+            [
+                ("file", "ecg.csv".to_string()),
+                ("sampleCount", "1".to_string())
+            ]
+        );
+    }
+}
@@ -0,0 +1,257 @@
+//! A machine-readable data contract describing the imported tables, for downstream pipelines to
+//! validate future imports against
+use crate::core::{DatabaseRow, DatabaseValue, HKTables};
+use crate::naming;
+use crate::options::{ensure_destructive_allowed, Naming};
+use log::*;
+use serde_json::json;
+use sqlx::types::JsonValue;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+/// Text columns with at most this many distinct values list them as the allowed values
+const MAX_ENUM_VALUES: usize = 20;
+
+/// The observed shape of a column
+struct ColumnExpectation {
+    ty: &'static str,
+    non_null_count: u64,
+    min: Option<f64>,
+    max: Option<f64>,
+    earliest: Option<OffsetDateTime>,
+    latest: Option<OffsetDateTime>,
+    /// The distinct text values, or None once there are more than MAX_ENUM_VALUES
+    values: Option<BTreeSet<String>>,
+}
+
+impl ColumnExpectation {
+    fn new(ty: &'static str) -> Self {
+        Self {
+            ty,
+            non_null_count: 0,
+            min: None,
+            max: None,
+            earliest: None,
+            latest: None,
+            values: Some(Default::default()),
+        }
+    }
+
+    fn observe(&mut self, value: &DatabaseValue) {
+        self.non_null_count += 1;
+        let number = match value {
+            DatabaseValue::Integer(i) => Some(*i as f64),
+            DatabaseValue::Real(r) => Some(*r),
+            DatabaseValue::OffsetDateTime(d) => {
+                self.earliest = Some(self.earliest.map_or(*d, |e| e.min(*d)));
+                self.latest = Some(self.latest.map_or(*d, |l| l.max(*d)));
+                None
+            }
+            DatabaseValue::Text(text) => {
+                if let Some(values) = &mut self.values {
+                    if !values.contains(text) {
+                        values.insert(text.clone());
+                    }
+                    if values.len() > MAX_ENUM_VALUES {
+                        self.values = None;
+                    }
+                }
+                None
+            }
+            DatabaseValue::Date(_) | DatabaseValue::Json(_) | DatabaseValue::Blob(_) => None,
+        };
+        if let Some(number) = number {
+            self.min = Some(self.min.map_or(number, |m| m.min(number)));
+            self.max = Some(self.max.map_or(number, |m| m.max(number)));
+        }
+    }
+
+    fn to_json(&self, row_count: u64) -> JsonValue {
+        let mut column = json!({
+            "type": self.ty,
+            "nullable": self.non_null_count < row_count,
+        });
+        if let (Some(min), Some(max)) = (self.min, self.max) {
+            column["min"] = json!(min);
+            column["max"] = json!(max);
+        }
+        if let (Some(earliest), Some(latest)) = (self.earliest, self.latest) {
+            column["min"] = json!(earliest.format(&Rfc3339).ok());
+            column["max"] = json!(latest.format(&Rfc3339).ok());
+        }
+        match &self.values {
+            Some(values) if self.ty == "TEXT" && !values.is_empty() => {
+                column["values"] = json!(values);
+            }
+            _ => {}
+        }
+        column
+    }
+}
+
+#[derive(Default)]
+struct TableExpectation {
+    row_count: u64,
+    columns: BTreeMap<String, ColumnExpectation>,
+}
+
+/// Collects the expectations of every table as rows are inserted, writing them to a JSON file
+pub struct ExpectationsWriter {
+    path: PathBuf,
+    tables: BTreeMap<String, TableExpectation>,
+}
+
+impl ExpectationsWriter {
+    /// Fails early, before the import, if the file exists and --no-destructive is set
+    pub fn create(path: &Path, no_destructive: bool) -> anyhow::Result<Self> {
+        if path.exists() {
+            ensure_destructive_allowed(
+                no_destructive,
+                format_args!("overwrite \"{}\"", path.display()),
+            )?;
+        }
+        Ok(Self {
+            path: path.to_path_buf(),
+            tables: Default::default(),
+        })
+    }
+
+    /// Records the type of every column of the output tables
+    pub fn add_tables(&mut self, tables: &HKTables) {
+        for (table_name, columns) in tables {
+            let table = self.tables.entry(table_name.clone()).or_default();
+            for (column_name, ty) in columns {
+                table
+                    .columns
+                    .entry(column_name.clone())
+                    .or_insert_with(|| ColumnExpectation::new(ty));
+            }
+        }
+    }
+
+    /// Records the values of a row inserted to the table
+    pub fn observe(&mut self, table_name: &str, row: &DatabaseRow) {
+        let table = match self.tables.get_mut(table_name) {
+            Some(table) => table,
+            None => return,
+        };
+        table.row_count += 1;
+        for (name, value) in row {
+            if let Some(column) = table.columns.get_mut(name) {
+                column.observe(value);
+            }
+        }
+    }
+
+    /// Writes the expectations of every table with rows
+    pub fn finish(self, naming: Naming) -> anyhow::Result<()> {
+        let tables: serde_json::Map<String, JsonValue> = self
+            .tables
+            .iter()
+            .filter(|(_, table)| table.row_count > 0)
+            .map(|(table_name, table)| {
+                let columns: serde_json::Map<String, JsonValue> = table
+                    .columns
+                    .iter()
+                    .map(|(name, column)| {
+                        (
+                            naming::convert(naming, name),
+                            column.to_json(table.row_count),
+                        )
+                    })
+                    .collect();
+                (
+                    naming::convert(naming, table_name),
+                    json!({ "row_count": table.row_count, "columns": columns }),
+                )
+            })
+            .collect();
+        let expectations = json!({
+            "tool_version": env!("CARGO_PKG_VERSION"),
+            "tables": tables,
+        });
+        debug!("Writing {}", self.path.display());
+        std::fs::write(&self.path, serde_json::to_vec_pretty(&expectations)?)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestDir;
+    use time::macros::datetime;
+
+    #[test]
+    fn tables_are_described_by_the_values_of_their_rows() {
+        let dir = TestDir::new("expectations");
+        let path = dir.path().join("expectations.json");
+        let mut writer = ExpectationsWriter::create(&path, false).unwrap();
+        writer.add_tables(&HKTables::from([
+            (
+                "HKQuantityTypeIdentifierHeartRate".to_string(),
+                BTreeMap::from([
+                    ("startDate".to_string(), "DATE"),
+                    ("value".to_string(), "REAL"),
+                    ("unit".to_string(), "TEXT"),
+                    ("device".to_string(), "TEXT"),
+                ]),
+            ),
+            ("Workout".to_string(), BTreeMap::new()),
+        ]));
+        for (date, value) in [
+            (datetime!(2024-01-01 08:00:00 -8), 62.0),
+            (datetime!(2024-01-01 07:00:00 -8), 71.5),
+        ] {
+            writer.observe(
+                "HKQuantityTypeIdentifierHeartRate",
+                &vec![
+                    ("startDate".to_string(), DatabaseValue::OffsetDateTime(date)),
+                    ("value".to_string(), DatabaseValue::Real(value)),
+                    (
+                        "unit".to_string(),
+                        DatabaseValue::Text("count/min".to_string()),
+                    ),
+                ],
+            );
+        }
+        writer.finish(Naming::Original).unwrap();
+
+        let expectations: JsonValue =
+            serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        // Tables without rows are left out
+        assert_eq!(
+            expectations["tables"],
+            json!({
+                "HKQuantityTypeIdentifierHeartRate": {
+                    "row_count": 2,
+                    "columns": {
+                        "device": {"type": "TEXT", "nullable": true},
+                        "startDate": {
+                            "type": "DATE",
+                            "nullable": false,
+                            "min": "2024-01-01T07:00:00-08:00",
+                            "max": "2024-01-01T08:00:00-08:00",
+                        },
+                        "unit": {"type": "TEXT", "nullable": false, "values": ["count/min"]},
+                        "value": {"type": "REAL", "nullable": false, "min": 62.0, "max": 71.5},
+                    },
+                },
+            })
+        );
+    }
+
+    #[test]
+    fn text_columns_with_many_values_list_none() {
+        let mut column = ColumnExpectation::new("TEXT");
+        for i in 0..=MAX_ENUM_VALUES {
+            column.observe(&DatabaseValue::Text(i.to_string()));
+        }
+        assert_eq!(
+            column.to_json(21),
+            json!({"type": "TEXT", "nullable": false})
+        );
+    }
+}
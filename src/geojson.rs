@@ -0,0 +1,146 @@
+//! Writes each workout route to its own GeoJSON file for use in GIS tools
+use crate::core::{DatabaseRow, DatabaseValue};
+use crate::options::ensure_destructive_allowed;
+use log::*;
+use sqlx::types::JsonValue;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use time::format_description::FormatItem;
+use time::macros::format_description;
+
+/// File name format of the workout start date, avoiding characters invalid in file names
+const FILE_NAME_FORMAT: &[FormatItem<'static>] =
+    format_description!("[year]-[month]-[day]T[hour][minute][second]");
+
+/// Writes `<workout-start-date>.geojson` files into a directory
+pub struct GeoJsonWriter {
+    dir: PathBuf,
+    no_destructive: bool,
+    /// File names written by this import, used to disambiguate workouts starting the same second
+    written: HashSet<String>,
+}
+
+impl GeoJsonWriter {
+    pub fn create(dir: &Path, no_destructive: bool) -> anyhow::Result<Self> {
+        std::fs::create_dir_all(dir)?;
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            no_destructive,
+            written: Default::default(),
+        })
+    }
+
+    /// Writes the route as a Feature with the workout attributes as its properties
+    pub fn write(&mut self, row: &DatabaseRow, geometry: &JsonValue) -> anyhow::Result<()> {
+        let mut properties = serde_json::Map::new();
+        let mut start_date = None;
+        for (name, value) in row {
+            let property = value.to_json();
+            if let ("startDate", DatabaseValue::OffsetDateTime(d)) = (name.as_str(), value) {
+                start_date = Some(d.format(FILE_NAME_FORMAT)?);
+            }
+            properties.insert(name.clone(), property);
+        }
+        let stem = start_date.unwrap_or_else(|| "workout".to_string());
+        let mut file_name = format!("{}.geojson", stem);
+        let mut n = 1;
+        while self.written.contains(&file_name) {
+            n += 1;
+            file_name = format!("{}-{}.geojson", stem, n);
+        }
+        let feature = serde_json::json!({
+            "type": "Feature",
+            "geometry": geometry,
+            "properties": properties,
+        });
+        let path = self.dir.join(&file_name);
+        if path.exists() {
+            ensure_destructive_allowed(
+                self.no_destructive,
+                format_args!("overwrite \"{}\"", path.display()),
+            )?;
+        }
+        debug!("Writing {}", path.display());
+        std::fs::write(path, serde_json::to_vec(&feature)?)?;
+        self.written.insert(file_name);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestDir;
+    use time::macros::datetime;
+
+    fn workout(activity_type: &str) -> DatabaseRow {
+        vec![
+            (
+                "workoutActivityType".to_string(),
+                DatabaseValue::Text(activity_type.to_string()),
+            ),
+            (
+                "startDate".to_string(),
+                DatabaseValue::OffsetDateTime(datetime!(2024-01-01 09:30:00 -8)),
+            ),
+            ("duration".to_string(), DatabaseValue::Real(30.5)),
+        ]
+    }
+
+    fn read(dir: &Path, file_name: &str) -> JsonValue {
+        serde_json::from_slice(&std::fs::read(dir.join(file_name)).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn routes_are_written_as_features_named_after_their_start() {
+        let dir = TestDir::new("geojson-features");
+        let geometry = serde_json::json!({
+            "type": "LineString",
+            "coordinates": [[-118.2, 34.0], [-118.3, 34.1]],
+        });
+        let mut writer = GeoJsonWriter::create(dir.path(), false).unwrap();
+        writer.write(&workout("Running"), &geometry).unwrap();
+        // Workouts starting the same second are numbered
+        writer.write(&workout("Walking"), &geometry).unwrap();
+        let feature = read(dir.path(), "2024-01-01T093000.geojson");
+        assert_eq!(
+            feature,
+            serde_json::json!({
+                "type": "Feature",
+                "geometry": geometry,
+                "properties": {
+                    "workoutActivityType": "Running",
+                    "startDate": "2024-01-01T09:30:00-08:00",
+                    "duration": 30.5,
+                },
+            })
+        );
+        assert_eq!(
+            read(dir.path(), "2024-01-01T093000-2.geojson")["properties"]["workoutActivityType"],
+            "Walking"
+        );
+    }
+
+    #[test]
+    fn files_of_earlier_imports_are_only_overwritten_when_destructive() {
+        let dir = TestDir::new("geojson-no-destructive");
+        let geometry = serde_json::json!({"type": "LineString", "coordinates": []});
+        GeoJsonWriter::create(dir.path(), false)
+            .unwrap()
+            .write(&workout("Running"), &geometry)
+            .unwrap();
+        let error = GeoJsonWriter::create(dir.path(), true)
+            .unwrap()
+            .write(&workout("Walking"), &geometry)
+            .unwrap_err();
+        assert!(error.to_string().contains("--no-destructive"));
+        GeoJsonWriter::create(dir.path(), false)
+            .unwrap()
+            .write(&workout("Walking"), &geometry)
+            .unwrap();
+        assert_eq!(
+            read(dir.path(), "2024-01-01T093000.geojson")["properties"]["workoutActivityType"],
+            "Walking"
+        );
+    }
+}
@@ -0,0 +1,114 @@
+//! Timestamps for the beat-to-beat measurements of heartbeat series records
+use crate::core::{DatabaseRow, DatabaseValue};
+use log::*;
+use time::format_description::FormatItem;
+use time::macros::format_description;
+use time::{Duration, OffsetDateTime, PrimitiveDateTime, Time};
+
+/// Name of the table holding the InstantaneousBeatsPerMinute elements of a record
+pub const HEARTBEAT_TABLE_NAME: &str = "InstantaneousBeatsPerMinute";
+
+/// The beat times are only a time of day, formatted for the locale of the device, e.g.
+/// "7:46:05.84 PM" or "19:46:05.84"
+const TIME_FORMATS: &[&[FormatItem<'static>]] = &[
+    format_description!("[hour repr:12 padding:none]:[minute]:[second].[subsecond] [period]"),
+    format_description!("[hour padding:none]:[minute]:[second].[subsecond]"),
+];
+
+/// Beats may be timestamped slightly before the startDate of their record
+const START_TOLERANCE: Duration = Duration::minutes(5);
+
+/// Replaces the time of day of each beat with a date, taking the day from the startDate of the
+/// record. Beats timed before the startDate are on the next day, as the series crossed midnight.
+/// Times that can't be parsed are dropped rather than stored in the DATE column.
+pub fn resolve_times<'a>(
+    start_date: Option<OffsetDateTime>,
+    beats: impl IntoIterator<Item = &'a mut DatabaseRow>,
+) {
+    for beat in beats {
+        let position = match beat.iter().position(|(name, _)| name == "time") {
+            Some(position) => position,
+            None => continue,
+        };
+        let date = match (&beat[position].1, start_date) {
+            (DatabaseValue::Text(time), Some(start_date)) => beat_date(time, start_date),
+            _ => None,
+        };
+        match date {
+            Some(date) => beat[position].1 = DatabaseValue::OffsetDateTime(date),
+            None => {
+                debug!("Dropping unrecognized beat time {:?}", beat[position].1);
+                beat.remove(position);
+            }
+        }
+    }
+}
+
+fn beat_date(time: &str, start_date: OffsetDateTime) -> Option<OffsetDateTime> {
+    let time = TIME_FORMATS
+        .iter()
+        .find_map(|format| Time::parse(time, format).ok())?;
+    let date = PrimitiveDateTime::new(start_date.date(), time).assume_offset(start_date.offset());
+    if date + START_TOLERANCE < start_date {
+        Some(date + Duration::days(1))
+    } else {
+        Some(date)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time::macros::datetime;
+
+    fn beat(time: &str) -> DatabaseRow {
+        vec![
+            ("bpm".to_string(), DatabaseValue::Integer(61)),
+            ("time".to_string(), DatabaseValue::Text(time.to_string())),
+        ]
+    }
+
+    fn times(beats: &[DatabaseRow]) -> Vec<Option<String>> {
+        beats
+            .iter()
+            .map(|beat| {
+                beat.iter()
+                    .find(|(name, _)| name == "time")
+                    .map(|(_, value)| value.to_text())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn beats_are_dated_from_the_start_of_their_record() {
+        let mut beats = vec![
+            beat("11:59:59.50 PM"),
+            // Past midnight, so on the next day
+            beat("12:00:00.45 AM"),
+            beat("23:59:59.75"),
+            // Within the tolerance before the start, so on the same day
+            beat("11:56:00.00 PM"),
+            beat("noon"),
+        ];
+        resolve_times(Some(datetime!(2024-01-01 23:59:59 -8)), &mut beats);
+        assert_eq!(
+            times(&beats),
+            [
+                Some("2024-01-01T23:59:59.5-08:00".to_string()),
+                Some("2024-01-02T00:00:00.45-08:00".to_string()),
+                Some("2024-01-01T23:59:59.75-08:00".to_string()),
+                Some("2024-01-01T23:56:00-08:00".to_string()),
+                None,
+            ]
+        );
+        // The bpm of a beat whose time is dropped is kept
+        assert_eq!(beats[4].len(), 1);
+    }
+
+    #[test]
+    fn beats_of_records_without_a_start_date_have_no_time() {
+        let mut beats = vec![beat("11:59:59.50 PM")];
+        resolve_times(None, &mut beats);
+        assert_eq!(times(&beats), [None]);
+    }
+}
@@ -0,0 +1,334 @@
+//! Quoting table and column names in SQL, and making the names of the export's tables and
+//! columns into identifiers as chosen with --identifiers and --identifier-map
+use crate::core::HKTables;
+use crate::options::{Identifiers, ImportOptions};
+use crate::schema::ColumnAliases;
+use serde::Deserialize;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Quotes a table or column name for use in SQLite statements, doubling any backticks in it, so
+/// names with spaces, quotes, or other punctuation, or that are keywords, can be used as they are.
+/// Every statement naming a table or column that isn't a fixed name of this crate, including the
+/// export's tables as the analytics find them, quotes it with this function.
+pub(crate) fn get_valid_sqlite_identifier(s: &str) -> String {
+    format!("`{}`", s.replace('`', "``"))
+}
+
+/// Table and column names mapped to the names to use for them instead, read from the JSON object
+/// of the --identifier-map file
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(transparent)]
+pub struct IdentifierMap(HashMap<String, String>);
+
+impl IdentifierMap {
+    /// Parses the identifier map file at the given path
+    pub fn from_path(path: &str) -> anyhow::Result<Self> {
+        let file = std::fs::File::open(Path::new(path))?;
+        let map: Self = serde_json::from_reader(std::io::BufReader::new(file))?;
+        if let Some((name, _)) = map.0.iter().find(|(_, to)| to.is_empty()) {
+            anyhow::bail!("The identifier map gives \"{}\" an empty name", name);
+        }
+        Ok(map)
+    }
+}
+
+/// Returns the identifier of a table or column name: its name in the --identifier-map, or else
+/// the name made into an identifier by --identifiers. The analytics look up the tables of the
+/// export through it, but read the columns they aggregate, such as startDate and value, by their
+/// export names.
+pub fn convert<'a>(options: &ImportOptions, name: &'a str) -> Cow<'a, str> {
+    if let Some(mapped) = options
+        .identifier_map
+        .as_ref()
+        .and_then(|map| map.0.get(name))
+    {
+        return Cow::Owned(mapped.clone());
+    }
+    match options.identifiers {
+        Identifiers::Verbatim => Cow::Borrowed(name),
+        Identifiers::Slugify => slugify(name),
+    }
+}
+
+/// Replaces each run of characters other than ASCII letters, digits, and underscores with an
+/// underscore, and prefixes a name starting with a digit with an underscore, e.g. "Pressure (hPa)"
+/// becomes Pressure_hPa. Names that need no quoting are returned as they are, so slugifying a
+/// slugified name leaves it unchanged.
+pub fn slugify(name: &str) -> Cow<'_, str> {
+    let is_valid = |c: char| c.is_ascii_alphanumeric() || c == '_';
+    let starts_with_digit = name.starts_with(|c: char| c.is_ascii_digit());
+    if !name.is_empty() && !starts_with_digit && name.chars().all(is_valid) {
+        return Cow::Borrowed(name);
+    }
+    let mut slug = String::with_capacity(name.len() + 1);
+    if starts_with_digit {
+        slug.push('_');
+    }
+    for c in name.chars() {
+        if is_valid(c) {
+            slug.push(c);
+        } else if !slug.ends_with('_') {
+            slug.push('_');
+        }
+    }
+    let trimmed = slug.trim_end_matches('_');
+    if trimmed.is_empty() {
+        Cow::Owned("_".to_string())
+    } else {
+        Cow::Owned(trimmed.to_string())
+    }
+}
+
+/// Renames the tables to their identifiers, failing if two tables would have the same name
+pub fn rename_tables(options: &ImportOptions, tables: HKTables) -> anyhow::Result<HKTables> {
+    let mut renamed = HKTables::new();
+    for (table_name, columns) in tables {
+        let identifier = convert(options, &table_name).into_owned();
+        if let Some(other) = renamed
+            .keys()
+            .find(|other| other.eq_ignore_ascii_case(&identifier))
+        {
+            anyhow::bail!(
+                "More than one table would be named \"{}\" with --identifiers and --identifier-map, including \"{}\"",
+                other,
+                table_name
+            );
+        }
+        renamed.insert(identifier, columns);
+    }
+    Ok(renamed)
+}
+
+/// Renames the columns of the tables to their identifiers, adding the renames to the aliases the
+/// rows are renamed with. Fails if two columns of a table would have the same name.
+pub fn rename_columns(
+    options: &ImportOptions,
+    tables: &mut HKTables,
+    aliases: &mut ColumnAliases,
+) -> anyhow::Result<()> {
+    for (table_name, columns) in tables.iter_mut() {
+        let renames: Vec<(String, String)> = columns
+            .keys()
+            .filter_map(|column_name| match convert(options, column_name) {
+                Cow::Owned(identifier) if identifier != *column_name => {
+                    Some((column_name.clone(), identifier))
+                }
+                _ => None,
+            })
+            .collect();
+        for (column_name, identifier) in renames {
+            if let Some(other) = columns
+                .keys()
+                .find(|other| other.eq_ignore_ascii_case(&identifier) && **other != column_name)
+            {
+                anyhow::bail!(
+                    "The columns \"{}\" and \"{}\" of table \"{}\" would both be named \"{}\" with --identifiers and --identifier-map",
+                    other,
+                    column_name,
+                    table_name,
+                    identifier
+                );
+            }
+            let ty = columns.remove(&column_name).expect("listed above");
+            columns.insert(identifier.clone(), ty);
+            // Columns merged into the renamed column are renamed with it
+            let table_aliases = aliases.entry(table_name.clone()).or_default();
+            for merged_into in table_aliases.values_mut() {
+                if *merged_into == column_name {
+                    *merged_into = identifier.clone();
+                }
+            }
+            table_aliases.insert(column_name, identifier);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestDir;
+    use sqlx::SqlitePool;
+    use std::collections::BTreeMap;
+
+    fn identifier_options(identifiers: Identifiers, map: &[(&str, &str)]) -> ImportOptions {
+        let map = map
+            .iter()
+            .map(|(from, to)| (from.to_string(), to.to_string()))
+            .collect();
+        ImportOptions {
+            identifiers,
+            identifier_map: Some(IdentifierMap(map)),
+            ..ImportOptions::defaults()
+        }
+    }
+
+    fn table(columns: &[&str]) -> BTreeMap<String, &'static str> {
+        columns
+            .iter()
+            .map(|name| (name.to_string(), "TEXT"))
+            .collect()
+    }
+
+    #[test]
+    fn identifiers_are_quoted_with_their_backticks_doubled() {
+        assert_eq!(get_valid_sqlite_identifier("value"), "`value`");
+        assert_eq!(get_valid_sqlite_identifier("a`b"), "`a``b`");
+        assert_eq!(get_valid_sqlite_identifier("``"), "``````");
+    }
+
+    #[tokio::test]
+    async fn quoted_identifiers_name_any_table_and_column() {
+        let pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        for name in [
+            "select",
+            "Pressure (hPa)",
+            "it's",
+            "a`b",
+            "\"quoted\"",
+            "x;y",
+        ] {
+            let identifier = get_valid_sqlite_identifier(name);
+            sqlx::query(&format!(
+                "CREATE TABLE {0} ({0} TEXT); INSERT INTO {0} ({0}) VALUES ('ok');",
+                identifier
+            ))
+            .execute(&pool)
+            .await
+            .unwrap();
+            let value: String = sqlx::query_scalar(&format!("SELECT {0} FROM {0}", identifier))
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+            assert_eq!(value, "ok");
+            let column: String = sqlx::query_scalar("SELECT name FROM pragma_table_info(?)")
+                .bind(name)
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+            assert_eq!(column, name);
+        }
+    }
+
+    #[test]
+    fn slugify_replaces_the_characters_that_need_quoting() {
+        assert_eq!(slugify("Pressure (hPa)"), "Pressure_hPa");
+        assert_eq!(
+            slugify("metadata_HKWeather Humidity"),
+            "metadata_HKWeather_Humidity"
+        );
+        assert_eq!(slugify("a -- b"), "a_b");
+        assert_eq!(slugify("3DTouch"), "_3DTouch");
+        assert_eq!(slugify("Höhe"), "H_he");
+        assert_eq!(slugify("%%"), "_");
+        assert_eq!(slugify(""), "_");
+        // Valid names are borrowed, and slugifying is idempotent
+        assert!(matches!(slugify("startDate"), Cow::Borrowed("startDate")));
+        for name in ["Pressure (hPa)", "3DTouch", "%%", "_a_"] {
+            let slug = slugify(name).into_owned();
+            assert_eq!(slugify(&slug), slug);
+        }
+    }
+
+    #[test]
+    fn the_identifier_map_takes_precedence_over_slugifying() {
+        let options = identifier_options(Identifiers::Slugify, &[("Pressure (hPa)", "pressure")]);
+        assert_eq!(convert(&options, "Pressure (hPa)"), "pressure");
+        assert_eq!(convert(&options, "Temperature (degC)"), "Temperature_degC");
+        let options = identifier_options(Identifiers::Verbatim, &[]);
+        assert_eq!(
+            convert(&options, "Temperature (degC)"),
+            "Temperature (degC)"
+        );
+    }
+
+    #[test]
+    fn identifier_maps_are_read_from_json_files() {
+        let dir = TestDir::new("identifier-map");
+        let path = dir.path().join("map.json");
+        std::fs::write(
+            &path,
+            r#"{"HKQuantityTypeIdentifierHeartRate": "heart_rate"}"#,
+        )
+        .unwrap();
+        let map = IdentifierMap::from_path(path.to_str().unwrap()).unwrap();
+        assert_eq!(
+            map.0
+                .get("HKQuantityTypeIdentifierHeartRate")
+                .map(String::as_str),
+            Some("heart_rate")
+        );
+        std::fs::write(&path, r#"{"startDate": ""}"#).unwrap();
+        let error = IdentifierMap::from_path(path.to_str().unwrap()).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "The identifier map gives \"startDate\" an empty name"
+        );
+        std::fs::write(&path, r#"["startDate"]"#).unwrap();
+        assert!(IdentifierMap::from_path(path.to_str().unwrap()).is_err());
+    }
+
+    #[test]
+    fn tables_are_renamed_to_their_identifiers() {
+        let options = identifier_options(Identifiers::Slugify, &[("Workout", "workouts")]);
+        let tables = HKTables::from([
+            ("Workout".to_string(), table(&["startDate"])),
+            ("Blood Pressure".to_string(), table(&["value"])),
+        ]);
+        let renamed = rename_tables(&options, tables).unwrap();
+        let names: Vec<&str> = renamed.keys().map(String::as_str).collect();
+        assert_eq!(names, ["Blood_Pressure", "workouts"]);
+        // Names differing only in case are the same table to SQLite
+        let tables = HKTables::from([
+            ("Blood Pressure".to_string(), table(&["value"])),
+            ("blood_pressure".to_string(), table(&["value"])),
+        ]);
+        let error = rename_tables(&options, tables).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "More than one table would be named \"Blood_Pressure\" with --identifiers and --identifier-map, including \"blood_pressure\""
+        );
+    }
+
+    #[test]
+    fn columns_are_renamed_along_with_the_columns_merged_into_them() {
+        let options = identifier_options(Identifiers::Slugify, &[]);
+        let mut tables = HKTables::from([(
+            "Workout".to_string(),
+            table(&["metadata_Pressure (hPa)", "startDate"]),
+        )]);
+        // A column of another casing was merged into the renamed column
+        let mut aliases = ColumnAliases::from([(
+            "Workout".to_string(),
+            BTreeMap::from([(
+                "metadata_pressure (hPa)".to_string(),
+                "metadata_Pressure (hPa)".to_string(),
+            )]),
+        )]);
+        rename_columns(&options, &mut tables, &mut aliases).unwrap();
+        let columns: Vec<&str> = tables["Workout"].keys().map(String::as_str).collect();
+        assert_eq!(columns, ["metadata_Pressure_hPa", "startDate"]);
+        assert_eq!(
+            aliases["Workout"],
+            BTreeMap::from([
+                (
+                    "metadata_Pressure (hPa)".to_string(),
+                    "metadata_Pressure_hPa".to_string()
+                ),
+                (
+                    "metadata_pressure (hPa)".to_string(),
+                    "metadata_Pressure_hPa".to_string()
+                ),
+            ])
+        );
+        // Columns that would have the same name
+        let mut tables = HKTables::from([("Workout".to_string(), table(&["a b", "a_b"]))]);
+        let error = rename_columns(&options, &mut tables, &mut ColumnAliases::new()).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "The columns \"a_b\" and \"a b\" of table \"Workout\" would both be named \"a_b\" with --identifiers and --identifier-map"
+        );
+    }
+}
@@ -0,0 +1,138 @@
+//! Stopping an import early on Ctrl-C, SIGTERM, or when the --timeout elapses
+use log::*;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::task::JoinHandle;
+
+/// Exit code of a process stopped by SIGINT
+const SIGINT_EXIT_CODE: i32 = 130;
+
+#[cfg(test)]
+thread_local! {
+    /// The number of times the imports of a test check for an interrupt before they are
+    /// interrupted, so a test can stop an import at each point it checks
+    static CHECKS_BEFORE_INTERRUPT: std::cell::Cell<Option<u64>> = const { std::cell::Cell::new(None) };
+}
+
+/// Interrupts the imports of the calling test at their given check, or never with None
+#[cfg(test)]
+pub fn interrupt_at_check(checks: Option<u64>) {
+    CHECKS_BEFORE_INTERRUPT.with(|cell| cell.set(checks));
+}
+
+/// Records the first request to stop the import. The import checks it between elements, so the
+/// rows written so far can be committed rather than rolled back.
+pub struct Interrupt {
+    requested: Arc<AtomicBool>,
+    reason: Arc<Mutex<Option<String>>>,
+    listeners: Vec<JoinHandle<()>>,
+}
+
+impl Interrupt {
+    /// Starts listening for signals and the timeout
+    pub fn listen(timeout: Option<Duration>) -> anyhow::Result<Self> {
+        let mut interrupt = Self {
+            requested: Default::default(),
+            reason: Default::default(),
+            listeners: Vec::new(),
+        };
+        let request = interrupt.requester();
+        interrupt.listeners.push(tokio::spawn(async move {
+            if tokio::signal::ctrl_c().await.is_err() {
+                return;
+            }
+            request("interrupted by Ctrl-C".to_string());
+            // A second Ctrl-C stops without waiting for the rows to be committed
+            if tokio::signal::ctrl_c().await.is_ok() {
+                std::process::exit(SIGINT_EXIT_CODE);
+            }
+        }));
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{signal, SignalKind};
+            let mut terminate = signal(SignalKind::terminate())?;
+            let request = interrupt.requester();
+            interrupt.listeners.push(tokio::spawn(async move {
+                if terminate.recv().await.is_some() {
+                    request("stopped by SIGTERM".to_string());
+                }
+            }));
+        }
+        if let Some(timeout) = timeout {
+            let request = interrupt.requester();
+            interrupt.listeners.push(tokio::spawn(async move {
+                tokio::time::sleep(timeout).await;
+                request(format!(
+                    "stopped after the {} second timeout",
+                    timeout.as_secs()
+                ));
+            }));
+        }
+        Ok(interrupt)
+    }
+
+    fn requester(&self) -> impl Fn(String) + Send + 'static {
+        let requested = self.requested.clone();
+        let reason = self.reason.clone();
+        move |new_reason| {
+            let mut reason = reason.lock().expect("interrupt lock poisoned");
+            if reason.is_none() {
+                info!("Import {}, finishing the current element", new_reason);
+                *reason = Some(new_reason);
+                requested.store(true, Ordering::Release);
+            }
+        }
+    }
+
+    /// Returns why the import should stop, if it has been asked to
+    pub fn reason(&self) -> Option<String> {
+        #[cfg(test)]
+        CHECKS_BEFORE_INTERRUPT.with(|cell| match cell.get() {
+            Some(0) => (self.requester())("interrupted by the test".to_string()),
+            Some(checks) => cell.set(Some(checks - 1)),
+            None => {}
+        });
+        if !self.requested.load(Ordering::Acquire) {
+            return None;
+        }
+        self.reason.lock().expect("interrupt lock poisoned").clone()
+    }
+}
+
+impl Drop for Interrupt {
+    fn drop(&mut self) {
+        for listener in &self.listeners {
+            listener.abort();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn the_timeout_requests_an_interrupt() {
+        let interrupt = Interrupt::listen(Some(Duration::ZERO)).unwrap();
+        for _ in 0..100 {
+            if interrupt.reason().is_some() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(
+            interrupt.reason().as_deref(),
+            Some("stopped after the 0 second timeout")
+        );
+    }
+
+    #[tokio::test]
+    async fn the_first_reason_is_kept() {
+        let interrupt = Interrupt::listen(None).unwrap();
+        assert_eq!(interrupt.reason(), None);
+        (interrupt.requester())("interrupted by Ctrl-C".to_string());
+        (interrupt.requester())("stopped by SIGTERM".to_string());
+        assert_eq!(interrupt.reason().as_deref(), Some("interrupted by Ctrl-C"));
+    }
+}
@@ -0,0 +1,136 @@
+//! Log output as human-readable text or JSON lines, chosen with --log-format
+use crate::capability;
+use crate::quota;
+use clap::ValueEnum;
+use serde_json::{json, Value as JsonValue};
+use std::io::Write;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+/// The format of the log lines written to stderr
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum LogFormat {
+    #[default]
+    Text,
+    /// One JSON object per line with the timestamp, level, target, and message, for automation.
+    /// Logs at the info level unless RUST_LOG says otherwise.
+    Json,
+}
+
+/// Sets up the logger, filtered by RUST_LOG
+pub fn init(format: LogFormat) {
+    match format {
+        LogFormat::Text => env_logger::init(),
+        LogFormat::Json => {
+            env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info"))
+                .format(|buf, record| writeln!(buf, "{}", record_line(record)))
+                .init()
+        }
+    }
+}
+
+/// Writes the error that ended the program as a JSON line, see [`error_line`]
+pub fn log_error(error: &anyhow::Error) {
+    eprintln!("{}", error_line(error));
+}
+
+/// The JSON line of a log record
+fn record_line(record: &log::Record) -> JsonValue {
+    json!({
+        "timestamp": timestamp(),
+        "level": record.level().as_str(),
+        "target": record.target(),
+        "message": record.args().to_string(),
+    })
+}
+
+/// The JSON line of an error, with the message of the error and each of its causes, outermost
+/// first, in `causes`, and the `code` of an exceeded quota or of a missing feature, along with
+/// the `feature` to rebuild with
+fn error_line(error: &anyhow::Error) -> JsonValue {
+    let mut line = json!({
+        "timestamp": timestamp(),
+        "level": "ERROR",
+        "target": env!("CARGO_CRATE_NAME"),
+        "message": format!("{:#}", error),
+        "causes": error.chain().map(|cause| cause.to_string()).collect::<Vec<_>>(),
+    });
+    if let Some(exceeded) = quota::exceeded(error) {
+        line["code"] = exceeded.quota.code().into();
+    }
+    if let Some(missing) = capability::missing(error) {
+        line["code"] = "feature_unavailable".into();
+        line["feature"] = missing.capability.feature().into();
+    }
+    line
+}
+
+fn timestamp() -> String {
+    OffsetDateTime::now_utc()
+        .format(&Rfc3339)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capability::Capability;
+    use crate::quota::{Quota, QuotaExceeded};
+    use anyhow::Context;
+
+    fn assert_recent(line: &JsonValue) {
+        let timestamp =
+            OffsetDateTime::parse(line["timestamp"].as_str().unwrap(), &Rfc3339).unwrap();
+        assert!((OffsetDateTime::now_utc() - timestamp).whole_minutes() < 1);
+    }
+
+    #[test]
+    fn records_are_logged_as_json_objects() {
+        let line = record_line(
+            &log::Record::builder()
+                .level(log::Level::Warn)
+                .target("healthkit_to_sqlite::core")
+                .args(format_args!("Skipping {} records", 2))
+                .build(),
+        );
+        assert_recent(&line);
+        assert_eq!(line["level"], "WARN");
+        assert_eq!(line["target"], "healthkit_to_sqlite::core");
+        assert_eq!(line["message"], "Skipping 2 records");
+    }
+
+    #[test]
+    fn errors_are_logged_with_their_causes() {
+        let error = Err::<(), _>(anyhow::anyhow!("disk I/O error"))
+            .context("Failed to write the Workout table")
+            .unwrap_err();
+        let line = error_line(&error);
+        assert_recent(&line);
+        assert_eq!(line["level"], "ERROR");
+        assert_eq!(line["target"], "healthkit_to_sqlite");
+        assert_eq!(
+            line["message"],
+            "Failed to write the Workout table: disk I/O error"
+        );
+        assert_eq!(
+            line["causes"],
+            json!(["Failed to write the Workout table", "disk I/O error"])
+        );
+        assert!(line.get("code").is_none());
+    }
+
+    #[test]
+    fn exceeded_quotas_and_missing_features_are_logged_with_their_code() {
+        let error = anyhow::Error::from(QuotaExceeded {
+            quota: Quota::Rows,
+            limit: 10,
+        })
+        .context("Failed to import");
+        assert_eq!(error_line(&error)["code"], "rows_exceeded");
+
+        let error = Capability::Parquet.missing();
+        let line = error_line(&error);
+        assert_eq!(line["code"], "feature_unavailable");
+        assert_eq!(line["feature"], "parquet");
+    }
+}
@@ -1,21 +1,230 @@
-use clap::Parser;
+use clap::error::ErrorKind;
+use clap::{Args, CommandFactory, Parser, Subcommand, ValueEnum};
 use console::Term;
 use dialoguer::theme::ColorfulTheme;
 use dialoguer::Confirm;
 use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
-use sqlx::migrate::MigrateDatabase;
 use std::path::PathBuf;
 use std::time::Duration;
 
+mod analytics;
+mod anonymize;
+mod append;
+mod blob;
+mod cache;
+mod capability;
+mod change_log;
+mod checkpoint;
+mod cipher;
+mod clinical;
+mod clock;
+mod compress;
+mod config;
+mod conformance;
+mod coordinator;
 mod core;
+mod device;
+mod ecg;
+mod expectations;
+mod geojson;
+mod heartbeat;
+mod identifier;
+mod interrupt;
+mod logging;
+mod meta;
+mod metadata;
+mod naming;
+mod options;
+mod perf_history;
+mod provenance;
+mod quota;
+mod record_metadata;
+mod registry;
+#[cfg(feature = "render-routes")]
+mod render;
+mod route;
+mod row_key;
+mod schema;
+mod serve;
+mod single_table;
+mod sink;
+mod source;
+mod sql_patch;
+mod stats;
+#[cfg(test)]
+mod testing;
+mod transform;
+mod units;
+mod unknown_types;
+mod verify;
+mod watch;
+mod workout_pace;
+mod workout_title;
+
+/// The kind of output to write the HealthKit data to
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum Format {
+    /// A database at DB_URL
+    Database,
+    /// One CSV file per table in the --out directory
+    Csv,
+    /// One Parquet file per table in the --out directory
+    Parquet,
+    /// One newline-delimited JSON file per table in the --out directory, or a single stream on
+    /// stdout with `--out -`
+    Ndjson,
+    /// An SQL script of CREATE TABLE and INSERT statements at the --out path, or on stdout with
+    /// `--out -`
+    Sql,
+}
+
+/// The image format routes are rendered to
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum ImageFormat {
+    Svg,
+    Png,
+}
+
+impl ImageFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ImageFormat::Svg => "svg",
+            ImageFormat::Png => "png",
+        }
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct RenderRoutesArgs {
+    #[arg(
+        help = "URL to the SQLite database created by a previous import",
+        env = "DATABASE_URL"
+    )]
+    pub db_url: String,
+    #[arg(help = "Directory to write the images to", long)]
+    pub out: PathBuf,
+    #[arg(help = "Image format", long, value_enum, default_value_t = ImageFormat::Svg)]
+    pub format: ImageFormat,
+    #[arg(
+        help = "Width and height of the images in pixels",
+        long,
+        default_value_t = 1024
+    )]
+    pub size: u32,
+}
+
+/// The format the type registry is printed in
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum TypesFormat {
+    /// One aligned line per type
+    Text,
+    /// A Markdown table, for documentation
+    Markdown,
+    Json,
+}
+
+#[derive(Args, Debug)]
+pub struct TypesArgs {
+    #[arg(help = "Output format", long, value_enum, default_value_t = TypesFormat::Text)]
+    pub format: TypesFormat,
+}
+
+#[derive(Args, Debug)]
+pub struct RoutesOnlyArgs {
+    #[arg(help = "Path to the HealthKit export.zip, an extracted export directory, or export.xml")]
+    pub export_path: PathBuf,
+    #[arg(
+        help = "Path or URL to the SQLite database to create",
+        env = "DATABASE_URL"
+    )]
+    pub db_url: String,
+    #[arg(
+        help = "Prompts the user to drop the database if it already exists",
+        short,
+        long
+    )]
+    pub drop: bool,
+    #[arg(help = "Responds yes to all prompts", short, long)]
+    pub yes: bool,
+}
+
+#[derive(Args, Debug)]
+pub struct VerifyArgs {
+    #[arg(help = "Path to the HealthKit export.zip, an extracted export directory, or export.xml")]
+    pub export_path: PathBuf,
+    #[arg(
+        help = "URL to the SQLite database created by importing the export",
+        env = "DATABASE_URL"
+    )]
+    pub db_url: String,
+    #[arg(
+        help = "Lists every table, rather than only those whose row count differs from the export",
+        long
+    )]
+    pub all: bool,
+}
+
+#[derive(Args, Debug)]
+pub struct StatsArgs {
+    #[arg(help = "Path to the HealthKit export.zip, an extracted export directory, or export.xml")]
+    pub export_path: PathBuf,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Renders each workout route, and a heatmap of all routes, to images.
+    /// Requires building with `--features render-routes`.
+    RenderRoutes(RenderRoutesArgs),
+    /// Prints the known HealthKit type identifiers with their kind, canonical unit, the iOS
+    /// version that introduced them, and a readable name.
+    Types(TypesArgs),
+    /// Imports only the workouts and their routes, with a row per route point, skipping every
+    /// record. Finishes in seconds for mapping runs and rides.
+    RoutesOnly(RoutesOnlyArgs),
+    /// Counts the records of each type, workouts, and activity summaries in export.xml and
+    /// compares them with the row counts of a database built from it, reporting any tables or
+    /// rows missing from the database.
+    Verify(VerifyArgs),
+    /// Prints a summary of the export without creating a database: the record types with their
+    /// counts and date ranges, the numbers of workouts and routes, and the sources and devices.
+    Stats(StatsArgs),
+    /// Prints the optional features, such as Parquet output, and whether this binary was built
+    /// with them.
+    Capabilities,
+    /// Imports a miniature export bundled with the binary into a temporary database and checks
+    /// the row counts, column types, and values against the expected ones, to confirm this build
+    /// imports exports correctly on this platform.
+    Conformance,
+    /// Watches a directory, such as a Dropbox or iCloud Drive folder exports are saved to, and
+    /// appends each new export.zip that appears in it to a SQLite database, until Ctrl-C.
+    Watch(Box<watch::WatchArgs>),
+    /// Serves a small read-only JSON HTTP API over a SQLite database, to list its tables, run
+    /// parameterized SELECT statements, and fetch workout routes as GeoJSON, until Ctrl-C.
+    Serve(serve::ServeArgs),
+}
 
 #[derive(Parser)]
-#[command(author, version, about, long_about = None)]
+#[command(
+    author,
+    version,
+    about,
+    long_about = None,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
 struct Cli {
-    #[arg(help = "Path to the HealthKit export.zip data")]
-    export_zip: PathBuf,
-    #[arg(help = "URL to the SQLite database", env = "DATABASE_URL")]
-    db_url: String,
+    #[command(subcommand)]
+    command: Option<Command>,
+    #[arg(
+        help = "Path to the HealthKit export.zip, an extracted export directory, or export.xml",
+        required = true
+    )]
+    export_path: Option<PathBuf>,
+    #[arg(
+        help = "URL to the SQLite (sqlite://), DuckDB (duckdb://), or PostgreSQL (postgres://) database",
+        env = "DATABASE_URL"
+    )]
+    db_url: Option<String>,
     #[arg(
         help = "Prompts the user to drop the database if it already exists",
         short,
@@ -26,43 +235,205 @@ struct Cli {
     yes: bool,
     #[arg(help = "Minimize stdout output", short, long)]
     quiet: bool,
+    #[arg(
+        help = "Format of the log output on stderr. json writes a JSON object per line, including the error that stops the program, and hides the progress spinner",
+        long,
+        value_enum,
+        default_value_t = logging::LogFormat::Text,
+        global = true
+    )]
+    log_format: logging::LogFormat,
+    #[arg(
+        help = "Skips a command that needs an optional feature this binary was built without, logging a warning and exiting successfully, rather than failing",
+        long,
+        global = true
+    )]
+    skip_unavailable: bool,
+    #[arg(help = "Output format", long, value_enum, default_value_t = Format::Database)]
+    format: Format,
+    #[arg(
+        help = "Directory to write the output files to when the format isn't database, the SQL script to write, or - to stream NDJSON or SQL to stdout",
+        long
+    )]
+    out: Option<PathBuf>,
+    #[command(flatten)]
+    import: options::ImportOptions,
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     dotenv::dotenv().ok();
-    env_logger::init();
-
     let cli = Cli::parse();
-    let term = Term::stdout();
+    logging::init(cli.log_format);
+
+    match run(&cli).await {
+        Err(e) if cli.log_format == logging::LogFormat::Json => {
+            logging::log_error(&e);
+            std::process::exit(exit_code(&e));
+        }
+        Err(e) if quota::exceeded(&e).is_some() || capability::missing(&e).is_some() => {
+            eprintln!("Error: {:?}", e);
+            std::process::exit(exit_code(&e));
+        }
+        result => result,
+    }
+}
+
+async fn run(cli: &Cli) -> anyhow::Result<()> {
+    // Checked before anything is done, such as dropping an existing database
+    if let Some(capability) = required_capability(cli).filter(|c| !c.is_available()) {
+        if cli.skip_unavailable {
+            log::warn!("Skipping: {}", capability.missing());
+            return Ok(());
+        }
+        return Err(capability.missing());
+    }
+    match &cli.command {
+        Some(Command::RenderRoutes(args)) => {
+            #[cfg(feature = "render-routes")]
+            return render::render_routes(args).await;
+            #[cfg(not(feature = "render-routes"))]
+            {
+                let _ = args;
+                return Err(capability::Capability::RenderRoutes.missing());
+            }
+        }
+        Some(Command::Types(args)) => {
+            match args.format {
+                TypesFormat::Text => print!("{}", registry::to_text()),
+                TypesFormat::Markdown => print!("{}", registry::to_markdown()),
+                TypesFormat::Json => {
+                    println!("{}", serde_json::to_string_pretty(registry::TYPES)?)
+                }
+            }
+            return Ok(());
+        }
+        Some(Command::Verify(args)) => return verify::verify(args).await,
+        Some(Command::Stats(args)) => return stats::print_stats(args),
+        Some(Command::Capabilities) => {
+            print!("{}", capability::report());
+            return Ok(());
+        }
+        Some(Command::Conformance) => return conformance::conformance().await,
+        Some(Command::Watch(args)) => return watch::watch(args).await,
+        Some(Command::Serve(args)) => return serve::serve(args).await,
+        Some(Command::RoutesOnly(_)) | None => {}
+    }
+    let mut import = cli.import.clone();
+    let (export_path, output, drop_existing, yes) = match &cli.command {
+        Some(Command::RoutesOnly(args)) => {
+            import.routes_only = true;
+            import.route_points = true;
+            (
+                args.export_path.clone(),
+                sink::Output::Database(args.db_url.clone()),
+                args.drop,
+                args.yes,
+            )
+        }
+        _ => (
+            cli.export_path
+                .clone()
+                .expect("required unless a subcommand is given"),
+            output_from_cli(cli),
+            cli.drop,
+            cli.yes,
+        ),
+    };
+    // --drop is an argument of the commands rather than of the import options the watch and
+    // conformance commands share, so its conflicts with them are checked here
+    if drop_existing {
+        let conflicting = [
+            ("--append", import.append),
+            ("--resume", import.resume),
+            ("--tenant", import.tenant.is_some()),
+        ];
+        if let Some((flag, _)) = conflicting.iter().find(|(_, given)| *given) {
+            Cli::command()
+                .error(
+                    ErrorKind::ArgumentConflict,
+                    format!("--drop can't be combined with {}", flag),
+                )
+                .exit();
+        }
+    }
+    // Keeps stdout for the rows when they are streamed to it
+    let term = match &output {
+        sink::Output::Ndjson(path) | sink::Output::Sql(path) if path.as_os_str() == "-" => {
+            Term::stderr()
+        }
+        _ => Term::stdout(),
+    };
+
+    // Concurrent imports into the same SQLite database, including the check for an existing
+    // database below, wait for their turn
+    let _slot = match &output {
+        sink::Output::Database(database_uri)
+            if !import.dry_run
+                && sink::Backend::from_url(database_uri) == sink::Backend::Sqlite =>
+        {
+            match sink::sqlite_path(database_uri) {
+                Some(path) => Some(
+                    coordinator::ImportCoordinator::default()
+                        .acquire(path, |position| {
+                            if !cli.quiet {
+                                let _ = term.write_line(&waiting_message(
+                                    position,
+                                    &cipher::redact_key(database_uri),
+                                ));
+                            }
+                        })
+                        .await?,
+                ),
+                None => None,
+            }
+        }
+        _ => None,
+    };
 
     // Abort the program if the database already exists and the user didn't specify the --force flag
-    let database_uri = &cli.db_url;
-    if sqlx::Sqlite::database_exists(database_uri).await? {
-        let drop_prompt = format!("The database at \"{}\" already exists. Do you want to drop it? This will delete all data in the database.", database_uri);
-        if cli.drop
-            && (cli.yes
-                || Confirm::with_theme(&ColorfulTheme::default())
-                    .with_prompt(drop_prompt)
-                    .default(false)
-                    .interact()
-                    .unwrap())
+    if let sink::Output::Database(database_uri) = &output {
+        // The database of a tenant's import usually holds the imports of other tenants
+        if !import.append
+            && !import.resume
+            && !import.dry_run
+            && import.tenant.is_none()
+            && sink::database_exists(database_uri).await?
         {
-            if !cli.quiet {
-                term.write_line(&format!("Dropping database at \"{}\"...", database_uri))?;
+            // The key of an encrypted database is left out of the messages
+            let shown_uri = cipher::redact_key(database_uri);
+            if drop_existing {
+                options::ensure_destructive_allowed(
+                    import.no_destructive,
+                    format_args!("drop the database at \"{}\"", shown_uri),
+                )?;
+            }
+            let drop_prompt = format!("The database at \"{}\" already exists. Do you want to drop it? This will delete all data in the database.", shown_uri);
+            if drop_existing
+                && (yes
+                    || Confirm::with_theme(&ColorfulTheme::default())
+                        .with_prompt(drop_prompt)
+                        .default(false)
+                        .interact()
+                        .unwrap())
+            {
+                if !cli.quiet {
+                    term.write_line(&format!("Dropping database at \"{}\"...", shown_uri))?;
+                }
+                sink::drop_database(database_uri).await?;
+            } else {
+                term.write_line(&format!(
+                    "The database at \"{}\" already exists. Please delete it or specify a different database URL.",
+                    shown_uri)
+                )?;
+                return Ok(());
             }
-            sqlx::Sqlite::drop_database(database_uri).await?;
-        } else {
-            term.write_line(&format!(
-                "The database at \"{}\" already exists. Please delete it or specify a different database URL.",
-                database_uri)
-            )?;
-            return Ok(());
         }
     }
 
     let pb = ProgressBar::new_spinner();
-    if cli.quiet {
+    // The dry run prints the tables when the import finishes
+    if cli.quiet || import.dry_run || cli.log_format == logging::LogFormat::Json {
         pb.set_draw_target(ProgressDrawTarget::hidden());
     }
     pb.enable_steady_tick(Duration::from_millis(120));
@@ -80,12 +451,145 @@ async fn main() -> anyhow::Result<()> {
             ]),
     );
     pb.set_message(format!(
-        "Creating SQLite database \"{}\" from \"{}\"...",
-        cli.db_url,
-        cli.export_zip.display(),
+        "Creating {} from \"{}\"...",
+        output,
+        export_path.display(),
     ));
 
-    core::healthkit_to_sqlite(database_uri, &cli.export_zip).await?;
-    pb.finish_with_message(format!("Created SQLite database {}", cli.db_url));
+    match core::healthkit_to_sqlite(&output, &export_path, &import, &pb).await? {
+        core::ImportStatus::Complete if import.dry_run => {
+            term.write_line(&format!("Dry run, nothing was written to {}", output))?
+        }
+        core::ImportStatus::Complete => pb.finish_with_message(format!("Created {}", output)),
+        core::ImportStatus::Interrupted(reason) => {
+            pb.finish_with_message(format!(
+                "Import {}, saved the rows imported so far to {}",
+                reason, output
+            ));
+            let resume = match &output {
+                // The rows committed after the last checkpoint would be imported again
+                sink::Output::Database(_) if import.replication_friendly => {
+                    "To import the whole export, run the same command again with --drop."
+                }
+                sink::Output::Database(_) => {
+                    "To import the rest of the export, run the same command again with --resume."
+                }
+                _ => "To import the whole export, run the same command again.",
+            };
+            term.write_line(resume)?;
+        }
+    }
     Ok(())
 }
+
+/// Returns the optional feature the command needs, if any
+fn required_capability(cli: &Cli) -> Option<capability::Capability> {
+    match &cli.command {
+        Some(Command::RenderRoutes(_)) => Some(capability::Capability::RenderRoutes),
+        Some(Command::RoutesOnly(args)) => sink::Output::Database(args.db_url.clone()).capability(),
+        Some(_) => None,
+        None => output_from_cli(cli).capability(),
+    }
+}
+
+/// The exit code of the program when it fails with the error
+fn exit_code(error: &anyhow::Error) -> i32 {
+    if quota::exceeded(error).is_some() {
+        quota::QUOTA_EXCEEDED_EXIT_CODE
+    } else if capability::missing(error).is_some() {
+        capability::MISSING_CAPABILITY_EXIT_CODE
+    } else {
+        1
+    }
+}
+
+/// Describes why the import is waiting for the database
+fn waiting_message(position: coordinator::QueuePosition, database_uri: &str) -> String {
+    match position {
+        coordinator::QueuePosition::Queued { ahead, eta } => format!(
+            "Waiting for the imports into \"{}\" to finish, {} ahead{}...",
+            database_uri,
+            ahead,
+            eta.map_or(String::new(), |eta| format!(", about {}s", eta.as_secs()))
+        ),
+        coordinator::QueuePosition::Locked => format!(
+            "Waiting for another import into \"{}\" to finish...",
+            database_uri
+        ),
+    }
+}
+
+/// The output the import is written to, from the --format and DB_URL or --out arguments
+fn output_from_cli(cli: &Cli) -> sink::Output {
+    match cli.format {
+        Format::Database => match &cli.db_url {
+            Some(database_uri) => sink::Output::Database(database_uri.clone()),
+            None => Cli::command()
+                .error(
+                    ErrorKind::MissingRequiredArgument,
+                    "DB_URL is required when the format is database",
+                )
+                .exit(),
+        },
+        Format::Csv | Format::Parquet | Format::Ndjson | Format::Sql => match &cli.out {
+            Some(dir) if cli.format == Format::Csv => sink::Output::Csv(dir.clone()),
+            Some(dir) if cli.format == Format::Ndjson => sink::Output::Ndjson(dir.clone()),
+            Some(path) if cli.format == Format::Sql => sink::Output::Sql(path.clone()),
+            Some(dir) => sink::Output::Parquet(dir.clone()),
+            None => Cli::command()
+                .error(
+                    ErrorKind::MissingRequiredArgument,
+                    "--out is required when the format isn't database",
+                )
+                .exit(),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cli_is_valid() {
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn import_defaults_match_command_line() {
+        let cli = Cli::try_parse_from(["healthkit-to-sqlite", "export.zip", "sqlite://health.db"])
+            .unwrap();
+        assert_eq!(
+            format!("{:?}", cli.import),
+            format!("{:?}", options::ImportOptions::defaults())
+        );
+    }
+
+    #[test]
+    fn keep_raw_defaults_to_the_rounded_columns() {
+        let cli = Cli::try_parse_from([
+            "healthkit-to-sqlite",
+            "--keep-raw",
+            "export.zip",
+            "sqlite://health.db",
+        ])
+        .unwrap();
+        assert_eq!(cli.import.keep_raw, [options::KeepRaw::Columns]);
+        assert_eq!(cli.export_path.as_deref(), Some(std::path::Path::new("export.zip")));
+    }
+
+    #[test]
+    fn keep_raw_can_be_given_more_than_once() {
+        let cli = Cli::try_parse_from([
+            "healthkit-to-sqlite",
+            "--keep-raw=json",
+            "--keep-raw=dates",
+            "export.zip",
+            "sqlite://health.db",
+        ])
+        .unwrap();
+        assert!(cli.import.keeps_raw(options::KeepRaw::Json));
+        assert!(cli.import.keeps_raw(options::KeepRaw::Dates));
+        assert!(!cli.import.keeps_raw(options::KeepRaw::Columns));
+    }
+}
@@ -0,0 +1,84 @@
+//! The `_meta` table describing how the output was produced
+use crate::core::{DatabaseRow, DatabaseValue, HKTables};
+use crate::schema::COLUMN_ORDER;
+
+/// Name of the key/value table describing the output
+pub const META_TABLE_NAME: &str = "_meta";
+
+/// Name of the table with a row describing the export behind each import
+pub const EXPORT_METADATA_TABLE_NAME: &str = "export_metadata";
+
+/// Schema of the export_metadata table
+pub(crate) fn export_metadata_schema() -> HKTables {
+    let columns = [
+        ("exportDate", "DATE"),
+        ("locale", "TEXT"),
+        ("exportVersion", "TEXT"),
+        ("toolVersion", "TEXT"),
+        ("importedAt", "DATE"),
+    ];
+    HKTables::from([(
+        EXPORT_METADATA_TABLE_NAME.to_string(),
+        columns
+            .iter()
+            .map(|(name, ty)| (name.to_string(), *ty))
+            .collect(),
+    )])
+}
+
+/// Reads the export format version from the comment in the DTD of export.xml, e.g.
+/// `<!-- HealthKit Export Version: 14 -->`
+pub(crate) fn export_version_from_doctype(doctype: &str) -> Option<String> {
+    const MARKER: &str = "HealthKit Export Version:";
+    let start = doctype.find(MARKER)? + MARKER.len();
+    doctype[start..]
+        .split_whitespace()
+        .next()
+        .filter(|version| *version != "-->")
+        .map(str::to_string)
+}
+
+/// Schema of the `_meta` table
+pub(crate) fn table_schema() -> HKTables {
+    let columns = [("key", "TEXT"), ("value", "TEXT")];
+    HKTables::from([(
+        META_TABLE_NAME.to_string(),
+        columns
+            .iter()
+            .map(|(name, ty)| (name.to_string(), *ty))
+            .collect(),
+    )])
+}
+
+/// The rows of the `_meta` table, followed by the given entries describing this import
+pub(crate) fn rows(entries: Vec<(&'static str, String)>) -> Vec<DatabaseRow> {
+    [
+        ("tool_version", env!("CARGO_PKG_VERSION").to_string()),
+        ("column_order", COLUMN_ORDER.to_string()),
+    ]
+    .into_iter()
+    .chain(entries)
+    .map(|(key, value)| {
+        vec![
+            ("key".to_string(), DatabaseValue::Text(key.to_string())),
+            ("value".to_string(), DatabaseValue::Text(value)),
+        ]
+    })
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_export_version_is_read_from_the_doctype() {
+        let doctype = "HealthData [\n<!-- HealthKit Export Version: 14 -->\n<!ELEMENT HealthData (ExportDate,Me)>";
+        assert_eq!(export_version_from_doctype(doctype).as_deref(), Some("14"));
+        assert_eq!(
+            export_version_from_doctype("HealthData [\n<!-- HealthKit Export Version: -->\n]"),
+            None
+        );
+        assert_eq!(export_version_from_doctype("HealthData []"), None);
+    }
+}
@@ -0,0 +1,237 @@
+//! Decoding numeric enum and flag metadata into readable columns when --friendly-names is set
+use crate::core::{DatabaseRow, DatabaseValue, HKTables, METADATA_COLUMN_PREFIX};
+
+/// Metadata column flagging samples entered by hand rather than measured by a device
+pub const USER_ENTERED_COLUMN: &str = "metadata_HKWasUserEntered";
+/// The readable column of HKWasUserEntered
+pub const FRIENDLY_USER_ENTERED_COLUMN: &str = "user_entered";
+
+/// Returns true if the row was entered by hand, judging by its undecoded metadata
+pub fn is_user_entered(row: &DatabaseRow) -> bool {
+    row.iter().any(|(name, value)| {
+        name == USER_ENTERED_COLUMN && !matches!(value, DatabaseValue::Integer(0))
+    })
+}
+
+/// Names of the HKInsulinDeliveryReason values
+pub const INSULIN_DELIVERY_REASONS: &[(i32, &str)] = &[(1, "basal"), (2, "bolus")];
+/// Names of the HKBloodGlucoseMealTime values
+pub const BLOOD_GLUCOSE_MEAL_TIMES: &[(i32, &str)] = &[(1, "preprandial"), (2, "postprandial")];
+
+/// How the numeric value of a metadata key is decoded
+enum Decoding {
+    /// A HealthKit enum, with the name of each raw value
+    Enum(&'static [(i32, &'static str)]),
+    /// A boolean flag stored as 0 or 1
+    Flag,
+}
+
+/// Metadata keys with numeric values, the column their decoded value is stored in, and how
+/// they are decoded
+const DECODED_KEYS: &[(&str, &str, Decoding)] = &[
+    (
+        "HKMetadataKeyHeartRateMotionContext",
+        "motion_context",
+        Decoding::Enum(&[(0, "not_set"), (1, "sedentary"), (2, "active")]),
+    ),
+    (
+        "HKMetadataKeyUserMotionContext",
+        "user_motion_context",
+        Decoding::Enum(&[(0, "not_set"), (1, "stationary"), (2, "active")]),
+    ),
+    (
+        "HKMetadataKeyHeartRateSensorLocation",
+        "heart_rate_sensor_location",
+        Decoding::Enum(&[
+            (0, "other"),
+            (1, "chest"),
+            (2, "wrist"),
+            (3, "finger"),
+            (4, "hand"),
+            (5, "ear_lobe"),
+            (6, "foot"),
+        ]),
+    ),
+    (
+        "HKMetadataKeyDevicePlacementSide",
+        "device_placement_side",
+        Decoding::Enum(&[(0, "unknown"), (1, "left"), (2, "right"), (3, "central")]),
+    ),
+    (
+        "HKSwimmingLocationType",
+        "swimming_location_type",
+        Decoding::Enum(&[(0, "unknown"), (1, "pool"), (2, "open_water")]),
+    ),
+    (
+        "HKSwimmingStrokeStyle",
+        "swimming_stroke_style",
+        Decoding::Enum(&[
+            (0, "unknown"),
+            (1, "mixed"),
+            (2, "freestyle"),
+            (3, "backstroke"),
+            (4, "breaststroke"),
+            (5, "butterfly"),
+            (6, "kickboard"),
+        ]),
+    ),
+    (
+        "HKVO2MaxTestType",
+        "vo2max_test_type",
+        Decoding::Enum(&[
+            (1, "max_exercise"),
+            (2, "predicted_sub_max_exercise"),
+            (3, "predicted_non_exercise"),
+        ]),
+    ),
+    (
+        "HKInsulinDeliveryReason",
+        "insulin_delivery_reason",
+        Decoding::Enum(INSULIN_DELIVERY_REASONS),
+    ),
+    (
+        "HKBloodGlucoseMealTime",
+        "blood_glucose_meal_time",
+        Decoding::Enum(BLOOD_GLUCOSE_MEAL_TIMES),
+    ),
+    (
+        "HKWasUserEntered",
+        FRIENDLY_USER_ENTERED_COLUMN,
+        Decoding::Flag,
+    ),
+    ("HKIndoorWorkout", "indoor_workout", Decoding::Flag),
+    ("HKWasTakenInLab", "taken_in_lab", Decoding::Flag),
+];
+
+/// Returns the readable column of a metadata key, if it is decoded
+pub fn friendly_column(key: &str) -> Option<&'static str> {
+    DECODED_KEYS
+        .iter()
+        .find(|(decoded, _, _)| *decoded == key)
+        .map(|(_, column, _)| *column)
+}
+
+fn decoded_key(column_name: &str) -> Option<&'static (&'static str, &'static str, Decoding)> {
+    let key = column_name.strip_prefix(METADATA_COLUMN_PREFIX)?;
+    DECODED_KEYS.iter().find(|(decoded, _, _)| *decoded == key)
+}
+
+/// Replaces the metadata columns of the decoded keys with their readable column
+pub fn extend_tables(tables: &mut HKTables) {
+    for columns in tables.values_mut() {
+        let decoded: Vec<String> = columns
+            .keys()
+            .filter(|name| decoded_key(name).is_some())
+            .cloned()
+            .collect();
+        for name in decoded {
+            let (_, column, decoding) = decoded_key(&name).expect("filtered above");
+            columns.remove(&name);
+            let ty = match decoding {
+                Decoding::Enum(_) => "TEXT",
+                Decoding::Flag => "INTEGER",
+            };
+            columns.insert(column.to_string(), ty);
+        }
+    }
+}
+
+/// Decodes the values of the decoded keys into their readable column. Enum values without a
+/// known name are kept as their number.
+pub fn decode_row(row: DatabaseRow) -> DatabaseRow {
+    row.into_iter()
+        .map(|(name, value)| match decoded_key(&name) {
+            Some((_, column, decoding)) => {
+                let value = match (decoding, value) {
+                    (Decoding::Enum(names), DatabaseValue::Integer(i)) => {
+                        match names.iter().find(|(raw, _)| i64::from(*raw) == i) {
+                            Some((_, name)) => DatabaseValue::Text(name.to_string()),
+                            None => DatabaseValue::Text(i.to_string()),
+                        }
+                    }
+                    (Decoding::Flag, DatabaseValue::Integer(i)) => {
+                        DatabaseValue::Integer((i != 0) as i64)
+                    }
+                    (_, value) => value,
+                };
+                (column.to_string(), value)
+            }
+            None => (name, value),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn texts(row: &DatabaseRow) -> Vec<(&str, String)> {
+        row.iter()
+            .map(|(name, value)| (name.as_str(), value.to_text()))
+            .collect()
+    }
+
+    #[test]
+    fn enums_and_flags_are_decoded_into_their_readable_column() {
+        let row = vec![
+            ("value".to_string(), DatabaseValue::Integer(62)),
+            (
+                "metadata_HKMetadataKeyHeartRateMotionContext".to_string(),
+                DatabaseValue::Integer(1),
+            ),
+            // Values without a known name are kept as their number
+            (
+                "metadata_HKMetadataKeyDevicePlacementSide".to_string(),
+                DatabaseValue::Integer(9),
+            ),
+            (
+                "metadata_HKIndoorWorkout".to_string(),
+                DatabaseValue::Integer(2),
+            ),
+            (
+                "metadata_HKWeatherHumidity".to_string(),
+                DatabaseValue::Integer(1),
+            ),
+        ];
+        assert_eq!(
+            texts(&decode_row(row)),
+            [
+                ("value", "62".to_string()),
+                ("motion_context", "sedentary".to_string()),
+                ("device_placement_side", "9".to_string()),
+                ("indoor_workout", "1".to_string()),
+                ("metadata_HKWeatherHumidity", "1".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn decoded_columns_replace_their_metadata_columns() {
+        let mut tables = HKTables::from([(
+            "HKQuantityTypeIdentifierHeartRate".to_string(),
+            [
+                ("value", "REAL"),
+                ("metadata_HKMetadataKeyHeartRateMotionContext", "INTEGER"),
+                ("metadata_HKWasUserEntered", "INTEGER"),
+            ]
+            .into_iter()
+            .map(|(name, ty)| (name.to_string(), ty))
+            .collect(),
+        )]);
+        extend_tables(&mut tables);
+        let columns: Vec<_> = tables["HKQuantityTypeIdentifierHeartRate"]
+            .iter()
+            .map(|(name, ty)| (name.as_str(), *ty))
+            .collect();
+        assert_eq!(
+            columns,
+            [
+                ("motion_context", "TEXT"),
+                ("user_entered", "INTEGER"),
+                ("value", "REAL")
+            ]
+        );
+        assert_eq!(friendly_column("HKWasUserEntered"), Some("user_entered"));
+        assert_eq!(friendly_column("HKWeatherHumidity"), None);
+    }
+}
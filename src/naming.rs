@@ -0,0 +1,302 @@
+//! Converting the output table and column names to the casing chosen with --naming
+use crate::core::{DatabaseRow, HKTables};
+use crate::identifier::get_valid_sqlite_identifier;
+use crate::options::Naming;
+use log::*;
+use sqlx::{Sqlite, Transaction};
+use std::collections::HashMap;
+
+/// Tables of the database that belong to the tools rather than the output
+const UNRENAMED_TABLES: &[&str] = &["_sqlx_migrations", crate::checkpoint::CHECKPOINT_TABLE_NAME];
+
+/// Converts a name to the casing, keeping any leading underscores. For example, with snake
+/// casing HKQuantityTypeIdentifierVO2Max becomes hk_quantity_type_identifier_vo2_max and
+/// metadata_HKWasUserEntered becomes metadata_hk_was_user_entered. Converting a converted name
+/// again leaves it unchanged.
+pub fn convert(naming: Naming, name: &str) -> String {
+    let unprefixed = name.trim_start_matches('_');
+    let words = words(unprefixed);
+    if naming == Naming::Original || words.is_empty() {
+        return name.to_string();
+    }
+    let mut converted = name[..name.len() - unprefixed.len()].to_string();
+    for (i, word) in words.iter().enumerate() {
+        match naming {
+            Naming::Snake => {
+                if i > 0 {
+                    converted.push('_');
+                }
+                converted.push_str(word);
+            }
+            Naming::Camel if i > 0 => {
+                let mut chars = word.chars();
+                if let Some(first) = chars.next() {
+                    converted.extend(first.to_uppercase());
+                    converted.push_str(chars.as_str());
+                }
+            }
+            _ => converted.push_str(word),
+        }
+    }
+    converted
+}
+
+/// Splits a name into lowercase words at underscores and other punctuation, and where the case
+/// changes, treating a run of capitals as an acronym, e.g. HK, Quantity for HKQuantity
+fn words(name: &str) -> Vec<String> {
+    let chars: Vec<char> = name.chars().collect();
+    let mut words = Vec::new();
+    let mut word = String::new();
+    for (i, &c) in chars.iter().enumerate() {
+        if !c.is_alphanumeric() {
+            if !word.is_empty() {
+                words.push(std::mem::take(&mut word));
+            }
+            continue;
+        }
+        if c.is_uppercase() && !word.is_empty() {
+            let previous = chars[i - 1];
+            let next_is_lowercase = chars.get(i + 1).is_some_and(|next| next.is_lowercase());
+            if previous.is_lowercase()
+                || previous.is_numeric()
+                || (previous.is_uppercase() && next_is_lowercase)
+            {
+                words.push(std::mem::take(&mut word));
+            }
+        }
+        word.extend(c.to_lowercase());
+    }
+    if !word.is_empty() {
+        words.push(word);
+    }
+    words
+}
+
+/// The converted names of the output tables and columns, keyed by their original names
+#[derive(Default)]
+pub struct Renames {
+    tables: HashMap<String, String>,
+    columns: HashMap<String, HashMap<String, String>>,
+}
+
+impl Renames {
+    /// Adds the converted names of the tables and their columns, failing if two tables, or two
+    /// columns of a table, would get the same name
+    pub fn add_tables(&mut self, naming: Naming, tables: &HKTables) -> anyhow::Result<()> {
+        if naming == Naming::Original {
+            return Ok(());
+        }
+        for (table_name, columns) in tables {
+            let converted = convert(naming, table_name);
+            if let Some((other, _)) = self
+                .tables
+                .iter()
+                .find(|(other, name)| **name == converted && *other != table_name)
+            {
+                anyhow::bail!(
+                    "--naming can't be used with this export, as the tables \"{}\" and \"{}\" would both be named \"{}\"",
+                    other,
+                    table_name,
+                    converted
+                );
+            }
+            self.tables.insert(table_name.clone(), converted);
+            let table_columns = self.columns.entry(table_name.clone()).or_default();
+            for column_name in columns.keys() {
+                let converted = convert(naming, column_name);
+                if let Some((other, _)) = table_columns
+                    .iter()
+                    .find(|(other, name)| **name == converted && *other != column_name)
+                {
+                    anyhow::bail!(
+                        "--naming can't be used with this export, as the columns \"{}\" and \"{}\" of table \"{}\" would both be named \"{}\"",
+                        other,
+                        column_name,
+                        table_name,
+                        converted
+                    );
+                }
+                table_columns.insert(column_name.clone(), converted);
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the converted name of the table, or the name itself if it wasn't added
+    pub fn table_name<'a>(&'a self, table_name: &'a str) -> &'a str {
+        self.tables
+            .get(table_name)
+            .map_or(table_name, String::as_str)
+    }
+
+    /// Returns the tables with their converted table and column names
+    pub fn rename_tables(&self, tables: HKTables) -> HKTables {
+        tables
+            .into_iter()
+            .map(|(table_name, columns)| {
+                let columns = columns
+                    .into_iter()
+                    .map(|(column_name, ty)| (self.column_name(&table_name, column_name), ty))
+                    .collect();
+                (self.table_name(&table_name).to_string(), columns)
+            })
+            .collect()
+    }
+
+    /// Returns the row with the converted column names of the table
+    pub fn rename_row(&self, table_name: &str, row: DatabaseRow) -> DatabaseRow {
+        if !self.columns.contains_key(table_name) {
+            return row;
+        }
+        row.into_iter()
+            .map(|(name, value)| (self.column_name(table_name, name), value))
+            .collect()
+    }
+
+    fn column_name(&self, table_name: &str, column_name: String) -> String {
+        match self
+            .columns
+            .get(table_name)
+            .and_then(|columns| columns.get(&column_name))
+        {
+            Some(converted) => converted.clone(),
+            None => column_name,
+        }
+    }
+}
+
+/// Renames every table and column of the SQLite database to the casing. SQLite only changes the
+/// schema to rename, and rewrites the views and indexes that refer to the renamed names. Virtual
+/// tables, such as the full-text search table, and their shadow tables can't be renamed, so they
+/// are created with the names of the casing.
+pub async fn rename_sqlite_tables(
+    tx: &mut Transaction<'_, Sqlite>,
+    naming: Naming,
+) -> anyhow::Result<()> {
+    if naming == Naming::Original {
+        return Ok(());
+    }
+    let table_names: Vec<String> = sqlx::query_scalar(
+        "SELECT name FROM pragma_table_list WHERE schema = 'main' AND type = 'table' AND name NOT LIKE 'sqlite_%'",
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+    for table_name in table_names {
+        if UNRENAMED_TABLES.contains(&table_name.as_str()) {
+            continue;
+        }
+        debug!(
+            "Renaming the table \"{}\" and its columns to {} case",
+            table_name,
+            naming.as_str()
+        );
+        let column_names: Vec<String> = sqlx::query_scalar("SELECT name FROM pragma_table_info(?)")
+            .bind(&table_name)
+            .fetch_all(&mut *tx)
+            .await?;
+        for column_name in column_names {
+            for (from, to) in rename_steps(&column_name, &convert(naming, &column_name)) {
+                let qs = format!(
+                    "ALTER TABLE {} RENAME COLUMN {} TO {}",
+                    get_valid_sqlite_identifier(&table_name),
+                    get_valid_sqlite_identifier(&from),
+                    get_valid_sqlite_identifier(&to)
+                );
+                sqlx::query(&qs).execute(&mut *tx).await?;
+            }
+        }
+        for (from, to) in rename_steps(&table_name, &convert(naming, &table_name)) {
+            let qs = format!(
+                "ALTER TABLE {} RENAME TO {}",
+                get_valid_sqlite_identifier(&from),
+                get_valid_sqlite_identifier(&to)
+            );
+            sqlx::query(&qs).execute(&mut *tx).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Returns the renames taking a name to its converted name. SQLite names are case-insensitive,
+/// so a name that only changes case is renamed through a temporary name.
+fn rename_steps(from: &str, to: &str) -> Vec<(String, String)> {
+    if from == to {
+        Vec::new()
+    } else if from.eq_ignore_ascii_case(to) {
+        let temporary = format!("{}__renaming", to);
+        vec![
+            (from.to_string(), temporary.clone()),
+            (temporary, to.to_string()),
+        ]
+    } else {
+        vec![(from.to_string(), to.to_string())]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn acronyms_are_single_words() {
+        assert_eq!(
+            convert(Naming::Snake, "HKQuantityTypeIdentifierHeartRate"),
+            "hk_quantity_type_identifier_heart_rate"
+        );
+        assert_eq!(
+            convert(Naming::Camel, "HKQuantityTypeIdentifierHeartRate"),
+            "hkQuantityTypeIdentifierHeartRate"
+        );
+        assert_eq!(
+            convert(Naming::Snake, "HKWasUserEntered"),
+            "hk_was_user_entered"
+        );
+        assert_eq!(convert(Naming::Snake, "sourceURL"), "source_url");
+    }
+
+    #[test]
+    fn digits_stay_with_the_word_before_them() {
+        assert_eq!(
+            convert(Naming::Snake, "HKQuantityTypeIdentifierVO2Max"),
+            "hk_quantity_type_identifier_vo2_max"
+        );
+        assert_eq!(
+            convert(Naming::Camel, "HKQuantityTypeIdentifierVO2Max"),
+            "hkQuantityTypeIdentifierVo2Max"
+        );
+        assert_eq!(convert(Naming::Snake, "Watch6,1"), "watch6_1");
+    }
+
+    #[test]
+    fn leading_underscores_are_kept() {
+        assert_eq!(
+            convert(Naming::Camel, "_schema_provenance"),
+            "_schemaProvenance"
+        );
+        assert_eq!(convert(Naming::Snake, "_meta"), "_meta");
+        assert_eq!(convert(Naming::Snake, "___"), "___");
+    }
+
+    #[test]
+    fn converting_twice_changes_nothing() {
+        for name in [
+            "HKQuantityTypeIdentifierVO2Max",
+            "metadata_HKWasUserEntered",
+            "_schema_provenance",
+            "startDate",
+        ] {
+            for naming in [Naming::Snake, Naming::Camel] {
+                let converted = convert(naming, name);
+                assert_eq!(convert(naming, &converted), converted);
+            }
+        }
+    }
+
+    #[test]
+    fn original_naming_changes_nothing() {
+        assert_eq!(
+            convert(Naming::Original, "metadata_HKWasUserEntered"),
+            "metadata_HKWasUserEntered"
+        );
+    }
+}
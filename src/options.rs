@@ -0,0 +1,610 @@
+use crate::clock::{self, Clock};
+use crate::config::Config;
+use crate::identifier::IdentifierMap;
+use crate::source::{ZipLimits, DEFAULT_MAX_COMPRESSION_RATIO, DEFAULT_MAX_ZIP_ENTRIES};
+use clap::{Args, ValueEnum};
+use sqlx::sqlite::{SqliteJournalMode, SqliteSynchronous};
+use std::borrow::Cow;
+use std::path::PathBuf;
+use time::OffsetDateTime;
+
+/// The SQLite collating sequence given to text columns
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum Collation {
+    /// Compares strings byte by byte
+    Binary,
+    /// Ignores the case of ASCII characters
+    #[default]
+    Nocase,
+    /// Ignores trailing spaces
+    Rtrim,
+}
+
+impl Collation {
+    pub fn as_sql(&self) -> &'static str {
+        match self {
+            Collation::Binary => "BINARY",
+            Collation::Nocase => "NOCASE",
+            Collation::Rtrim => "RTRIM",
+        }
+    }
+}
+
+/// The SQLite journal mode used while importing
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum JournalMode {
+    Delete,
+    Truncate,
+    Persist,
+    Memory,
+    Wal,
+    Off,
+}
+
+impl JournalMode {
+    pub fn to_sqlite(self) -> SqliteJournalMode {
+        match self {
+            JournalMode::Delete => SqliteJournalMode::Delete,
+            JournalMode::Truncate => SqliteJournalMode::Truncate,
+            JournalMode::Persist => SqliteJournalMode::Persist,
+            JournalMode::Memory => SqliteJournalMode::Memory,
+            JournalMode::Wal => SqliteJournalMode::Wal,
+            JournalMode::Off => SqliteJournalMode::Off,
+        }
+    }
+}
+
+/// How often SQLite waits for writes to reach the disk while importing
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Synchronous {
+    /// Never waits, so a crash or power loss during the import can corrupt the database
+    Off,
+    /// Waits at the most critical moments, safe in WAL mode
+    Normal,
+    Full,
+    Extra,
+}
+
+impl Synchronous {
+    pub fn to_sqlite(self) -> SqliteSynchronous {
+        match self {
+            Synchronous::Off => SqliteSynchronous::Off,
+            Synchronous::Normal => SqliteSynchronous::Normal,
+            Synchronous::Full => SqliteSynchronous::Full,
+            Synchronous::Extra => SqliteSynchronous::Extra,
+        }
+    }
+}
+
+/// Where SQLite keeps temporary tables and indexes
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum TempStore {
+    Default,
+    File,
+    Memory,
+}
+
+impl TempStore {
+    pub fn as_sql(&self) -> &'static str {
+        match self {
+            TempStore::Default => "DEFAULT",
+            TempStore::File => "FILE",
+            TempStore::Memory => "MEMORY",
+        }
+    }
+}
+
+/// The casing of the output table and column names
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum Naming {
+    /// lowerCamelCase, e.g. hkQuantityTypeIdentifierHeartRate and startDate
+    Camel,
+    /// snake_case, e.g. hk_quantity_type_identifier_heart_rate and start_date
+    Snake,
+    /// The names of the export, e.g. HKQuantityTypeIdentifierHeartRate and startDate
+    #[default]
+    Original,
+}
+
+impl Naming {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Naming::Camel => "camel",
+            Naming::Snake => "snake",
+            Naming::Original => "original",
+        }
+    }
+}
+
+/// How the names of the export's tables and columns are made into identifiers
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum Identifiers {
+    /// The names as they are, quoted in SQL, e.g. "Pressure (hPa)"
+    #[default]
+    Verbatim,
+    /// The names with each run of characters other than letters, digits, and underscores replaced
+    /// by an underscore, e.g. Pressure_hPa
+    Slugify,
+}
+
+/// The exported strings kept with --keep-raw
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum KeepRaw {
+    /// The exact value of every rounded column, in a parallel <column>_raw column
+    Columns,
+    /// The string of every attribute and metadata value parsed to a number or date, in a _raw
+    /// JSON column of its row
+    Json,
+    /// The date string of every date column, with its UTC offset, in a parallel <column>_raw column
+    Dates,
+}
+
+/// What to do with records whose type isn't in the type registry
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum UnknownTypePolicy {
+    /// Fails before writing anything, for pipelines that must notice new types
+    Fail,
+    /// Skips the records with a warning
+    Warn,
+    /// Imports the records into a table per type like any other
+    #[default]
+    ImportGeneric,
+}
+
+/// Options controlling how a HealthKit export is imported
+#[derive(Args, Debug, Clone, Default)]
+pub struct ImportOptions {
+    #[arg(
+        help = "Append to an existing database, only inserting records newer than those already imported. Older records are skipped without checking, unless they have an HKExternalUUID that isn't in the database yet",
+        short,
+        long
+    )]
+    pub append: bool,
+    #[arg(
+        help = "Logs the rowid of every row the append inserts or deletes to a _changes table, for consuming the deltas downstream",
+        long,
+        requires = "append"
+    )]
+    pub change_log: bool,
+    #[arg(
+        help = "Resumes a failed or interrupted import into the same database from its last checkpoint",
+        long,
+        conflicts_with_all = ["cache", "dry_run"]
+    )]
+    pub resume: bool,
+    #[arg(
+        help = "Writes an SQL patch of the changes the append made to this file, for updating replicas of the database by applying it with sqlite3. The patch is a plain SQL script for `sqlite3 replica.db < PATH`, not a binary changeset of SQLite's session extension",
+        long,
+        value_name = "PATH",
+        requires = "append",
+        conflicts_with = "flag_outliers"
+    )]
+    pub sql_patch: Option<PathBuf>,
+    #[arg(help = "Path to a JSON configuration file", long, value_parser = Config::from_path)]
+    pub config: Option<Config>,
+    #[arg(
+        help = "Adds an is_outlier column flagging samples outside the configured plausible range",
+        long
+    )]
+    pub flag_outliers: bool,
+    #[arg(
+        help = "Creates daily interpolated series for the given metrics, e.g. weight,body_fat",
+        long,
+        value_delimiter = ','
+    )]
+    pub interpolate: Vec<String>,
+    #[arg(
+        help = "Creates a daily_metrics_wide table with one row per day for correlating sleep, heart rate, and activity",
+        long
+    )]
+    pub daily_metrics: bool,
+    #[arg(
+        help = "Adds a workout_id column to every record table holding the rowid of the workout each sample overlaps",
+        long
+    )]
+    pub link_workouts: bool,
+    #[arg(
+        help = "Stores each distinct device description once in a Device table, replacing the device column of the samples with a device_id",
+        long
+    )]
+    pub device_table: bool,
+    #[arg(
+        help = "Creates a WorkoutHeartRateZone table with the seconds of each workout spent in each of the five heart rate zones",
+        long
+    )]
+    pub heart_rate_zones: bool,
+    #[arg(
+        help = "Maximum heart rate the heart rate zones are fractions of, instead of 220 minus the age on the day of each workout",
+        long,
+        value_name = "BPM",
+        requires = "heart_rate_zones"
+    )]
+    pub max_heart_rate: Option<u32>,
+    #[arg(
+        help = "Creates a daily_<type> view of per-day min, max, avg, and sum for every quantity table, e.g. daily_StepCount",
+        long
+    )]
+    pub daily_views: bool,
+    #[arg(
+        help = "Creates a samples_long view of (person, metric, timestamp, value, unit, source) rows over every quantity table, labelling the rows with PERSON",
+        long,
+        value_name = "PERSON",
+        num_args = 0..=1,
+        default_missing_value = "me"
+    )]
+    pub tidy_view: Option<String>,
+    #[arg(
+        help = "Keeps the exported strings of values: the exact value of every rounded column in a parallel <column>_raw column (columns, the default), the string of every value parsed to a number or date in a _raw JSON column of its row (json), or the date string of every date column, with its UTC offset, in a parallel <column>_raw column (dates). Can be given more than once",
+        long,
+        value_name = "WHAT",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "columns"
+    )]
+    pub keep_raw: Vec<KeepRaw>,
+    #[arg(
+        help = "Converts the values of each quantity table in other units to the table's canonical unit, e.g. mi to km, listing the units in a _units table",
+        long
+    )]
+    pub normalize_units: bool,
+    #[arg(
+        help = "Stores every date in UTC, with the UTC offset it was exported with in minutes in a parallel <column>_utc_offset_minutes column",
+        long
+    )]
+    pub utc: bool,
+    #[arg(
+        help = "With --utc, also stores the date in the exported UTC offset of every date in a parallel <column>_local_date column, for grouping by day",
+        long,
+        requires = "utc"
+    )]
+    pub local_dates: bool,
+    #[arg(
+        help = "Collation of text columns in SQLite databases",
+        long,
+        value_enum,
+        default_value_t = Collation::Nocase
+    )]
+    pub collation: Collation,
+    #[arg(
+        help = "Runs ANALYZE and PRAGMA optimize on SQLite databases after the import, so queries are planned with up-to-date statistics",
+        long
+    )]
+    pub analyze: bool,
+    #[arg(
+        help = "SQLite journal mode during the import",
+        long,
+        value_enum,
+        value_name = "MODE"
+    )]
+    pub journal_mode: Option<JournalMode>,
+    #[arg(
+        help = "Commits the import in small transactions in WAL mode, so replication tools such as Litestream can follow along without large WAL spikes",
+        long,
+        conflicts_with = "resume"
+    )]
+    pub replication_friendly: bool,
+    #[arg(
+        help = "Appends the duration of each phase, the rows per second of each table, and the SQLite settings of the import to a local _perf_history table",
+        long
+    )]
+    pub perf_history: bool,
+    #[arg(
+        help = "Strips the HKQuantityTypeIdentifier and HKCategoryTypeIdentifier prefixes from the table names, e.g. HeartRate instead of HKQuantityTypeIdentifierHeartRate",
+        long
+    )]
+    pub short_names: bool,
+    #[arg(
+        help = "Writes every Record element to a single samples table with a metadata JSON column, instead of a table per type",
+        long
+    )]
+    pub single_table: bool,
+    #[arg(
+        help = "Writes the MetadataEntry elements to a RecordMetadata table of table_name, row_id, key, and value rows, instead of a metadata_ column per key",
+        long,
+        conflicts_with = "single_table"
+    )]
+    pub metadata_table: bool,
+    #[arg(
+        help = "What to do with records whose type isn't in the type registry",
+        long,
+        value_enum,
+        default_value_t = UnknownTypePolicy::ImportGeneric
+    )]
+    pub unknown_types: UnknownTypePolicy,
+    #[arg(
+        help = "Skips the workouts and their routes, importing only the records and other elements",
+        long,
+        conflicts_with_all = ["route_points", "route_heatmap", "geojson_dir"]
+    )]
+    pub records_only: bool,
+    /// Set by the routes-only command to import only the workouts and their routes
+    #[arg(skip)]
+    pub routes_only: bool,
+    #[arg(
+        help = "SQLite synchronous setting during the import. off is fastest but a crash can corrupt the database",
+        long,
+        value_enum,
+        value_name = "MODE"
+    )]
+    pub synchronous: Option<Synchronous>,
+    #[arg(
+        help = "SQLite page cache size during the import, in pages, or in KiB when negative as in PRAGMA cache_size",
+        long,
+        value_name = "SIZE",
+        allow_negative_numbers = true
+    )]
+    pub cache_size: Option<i64>,
+    #[arg(
+        help = "Where SQLite keeps temporary tables and indexes during the import",
+        long,
+        value_enum,
+        value_name = "STORE"
+    )]
+    pub temp_store: Option<TempStore>,
+    #[arg(
+        help = "Loads a SQLite extension, e.g. mod_spatialite, on the import's connections before the tables are created. Can be given more than once",
+        long = "load-extension",
+        value_name = "PATH"
+    )]
+    pub load_extensions: Vec<String>,
+    #[arg(
+        help = "Creates a search table, an FTS5 full-text index of the source names, devices, and text metadata of every table",
+        long
+    )]
+    pub search_index: bool,
+    #[arg(
+        help = "Stores the JSON columns, such as the workout route geometries, as zstd-compressed BLOBs, with a <table>_json view of each table decompressing them with the sqlite-zstd extension",
+        long,
+        conflicts_with = "route_heatmap"
+    )]
+    pub compress_json: bool,
+    #[arg(
+        help = "Creates a route_heatmap table counting the route points in each geohash cell of the given precision (1-12 characters)",
+        long,
+        value_name = "PRECISION",
+        num_args = 0..=1,
+        default_missing_value = "7",
+        value_parser = clap::value_parser!(u8).range(1..=12)
+    )]
+    pub route_heatmap: Option<u8>,
+    #[arg(
+        help = "Writes every workout route track point as a row of a WorkoutRoutePoint table",
+        long
+    )]
+    pub route_points: bool,
+    #[arg(
+        help = "Stores the ECG CSV files, route GPX files, clinical record files, and export_cda.xml in a _blobs table of their hash, MIME type, and bytes, referred to by hash from the rows read from them",
+        long,
+        conflicts_with_all = ["anonymize", "fuzz_routes"]
+    )]
+    pub blobs: bool,
+    #[arg(
+        help = "Caches the parsed export in this directory, so later imports of the same export skip parsing the XML",
+        long,
+        value_name = "DIR"
+    )]
+    pub cache: Option<PathBuf>,
+    #[arg(
+        help = "Also writes each workout route to <workout-start-date>.geojson in this directory",
+        long,
+        value_name = "PATH"
+    )]
+    pub geojson_dir: Option<PathBuf>,
+    #[arg(
+        help = "Stops the import after this many seconds, keeping the rows imported so far as with Ctrl-C",
+        long,
+        value_name = "SECONDS"
+    )]
+    pub timeout: Option<u64>,
+    #[arg(
+        help = "Never drops databases or tables or deletes or overwrites files, failing instead, regardless of other flags. The rows of the derived tables and the views are still rebuilt",
+        long
+    )]
+    pub no_destructive: bool,
+    #[arg(
+        help = "Parses the export and prints the tables, columns, and row counts it would create, without writing anything",
+        long
+    )]
+    pub dry_run: bool,
+    #[arg(
+        help = "Writes a JSON data contract of every table's column types, nullability, and observed value ranges to this file",
+        long,
+        value_name = "PATH"
+    )]
+    pub expectations: Option<PathBuf>,
+    #[arg(
+        help = "Decodes numeric metadata such as HKMetadataKeyHeartRateMotionContext and HKWasUserEntered into readable columns, e.g. motion_context = 'active', and uses readable metric names in the samples_long view",
+        long
+    )]
+    pub friendly_names: bool,
+    #[arg(
+        help = "Parses the device description of each sample into device_name, device_manufacturer, device_model, device_hardware, and device_software columns",
+        long
+    )]
+    pub device_columns: bool,
+    #[arg(
+        help = "Skips samples and workouts entered by hand, i.e. with HKWasUserEntered metadata",
+        long
+    )]
+    pub exclude_user_entered: bool,
+    #[arg(
+        help = "Removes or pseudonymizes identifying fields such as sourceName, device names, sourceVersion, and sync identifiers, and skips clinical records, so the output can be shared. The pseudonyms differ between imports, so it can't be combined with --append or --resume",
+        long,
+        conflicts_with_all = ["append", "resume"]
+    )]
+    pub anonymize: bool,
+    #[arg(
+        help = "Moves each workout route by a random offset of up to this many meters, keeping its shape",
+        long,
+        value_name = "METERS",
+        num_args = 0..=1,
+        default_missing_value = "1000"
+    )]
+    pub fuzz_routes: Option<f64>,
+    #[arg(
+        help = "Uses this date, or RFC 3339 date and time, as the current time for importedAt and the windows of derived tables, so the output is reproducible",
+        long,
+        value_name = "DATE",
+        value_parser = clock::parse_as_of
+    )]
+    pub as_of: Option<OffsetDateTime>,
+    #[arg(
+        help = "Converts the table and column names to camelCase or snake_case, recording the original names in _schema_provenance",
+        long,
+        value_enum,
+        default_value_t = Naming::Original,
+        conflicts_with_all = ["append", "resume"]
+    )]
+    pub naming: Naming,
+    #[arg(
+        help = "Prefixes every table with this ID and an underscore, so one database can hold the imports of many users. Letters, digits, and underscores only. The analytics tables aren't scoped to a tenant, so they aren't created",
+        long,
+        value_name = "ID",
+        value_parser = parse_tenant,
+        // The analytics passes are skipped for tenants, so the flags that only configure them
+        // would have no effect
+        conflicts_with_all = [
+            "resume",
+            "naming",
+            "flag_outliers",
+            "interpolate",
+            "daily_metrics",
+            "link_workouts",
+            "heart_rate_zones",
+            "daily_views",
+            "tidy_view",
+            "search_index",
+            "route_heatmap",
+        ]
+    )]
+    pub tenant: Option<String>,
+    #[arg(
+        help = "Fails if the uncompressed export is larger than this many bytes, before anything is written",
+        long,
+        value_name = "BYTES"
+    )]
+    pub max_export_size: Option<u64>,
+    #[arg(
+        help = "Fails if the import writes more than this many rows",
+        long,
+        value_name = "ROWS"
+    )]
+    pub max_rows: Option<u64>,
+    #[arg(
+        help = "Fails if the SQLite database grows larger than this many bytes",
+        long,
+        value_name = "BYTES"
+    )]
+    pub max_db_size: Option<u64>,
+    #[arg(
+        help = "Fails if the zip archive has more files than this",
+        long,
+        value_name = "FILES",
+        default_value_t = DEFAULT_MAX_ZIP_ENTRIES
+    )]
+    pub max_zip_entries: usize,
+    #[arg(
+        help = "Fails if a file of the zip archive decompresses to more than this many times its compressed size",
+        long,
+        value_name = "RATIO",
+        default_value_t = DEFAULT_MAX_COMPRESSION_RATIO
+    )]
+    pub max_compression_ratio: u64,
+    #[arg(
+        help = "How the names of the export's tables and columns are made into identifiers",
+        long,
+        value_enum,
+        default_value_t = Identifiers::Verbatim
+    )]
+    pub identifiers: Identifiers,
+    #[arg(
+        help = "Path to a JSON object of the export's table and column names to the names to use instead, taking precedence over --identifiers",
+        long,
+        value_name = "PATH",
+        value_parser = IdentifierMap::from_path
+    )]
+    pub identifier_map: Option<IdentifierMap>,
+}
+
+/// Fails with an error naming the action if --no-destructive forbids it
+pub fn ensure_destructive_allowed(
+    no_destructive: bool,
+    action: impl std::fmt::Display,
+) -> anyhow::Result<()> {
+    if no_destructive {
+        anyhow::bail!("Refusing to {} because --no-destructive is set", action);
+    }
+    Ok(())
+}
+
+/// Parses the --tenant argument, which is used unquoted in table names
+pub fn parse_tenant(s: &str) -> Result<String, String> {
+    if s.is_empty() || !s.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        return Err(format!(
+            "\"{}\" isn't a tenant ID, which consists of letters, digits, and underscores",
+            s
+        ));
+    }
+    Ok(s.to_string())
+}
+
+impl ImportOptions {
+    /// The options of an import given no flags, as the command line parses them. The derived
+    /// Default leaves the zip limits at zero rather than their defaults.
+    pub fn defaults() -> Self {
+        Self {
+            max_zip_entries: DEFAULT_MAX_ZIP_ENTRIES,
+            max_compression_ratio: DEFAULT_MAX_COMPRESSION_RATIO,
+            ..Default::default()
+        }
+    }
+
+    /// The configuration file contents, or the defaults if no file was given
+    pub fn config(&self) -> &Config {
+        lazy_static::lazy_static! {
+            static ref DEFAULT_CONFIG: Config = Config::default();
+        }
+        self.config.as_ref().unwrap_or(&DEFAULT_CONFIG)
+    }
+
+    /// Returns true if --keep-raw keeps the strings
+    pub fn keeps_raw(&self, keep_raw: KeepRaw) -> bool {
+        self.keep_raw.contains(&keep_raw)
+    }
+
+    /// The name of the table in the output, prefixed with the --tenant ID if one is given
+    pub fn tenant_table_name<'a>(&self, table_name: &'a str) -> Cow<'a, str> {
+        match &self.tenant {
+            Some(tenant) => Cow::Owned(format!("{}_{}", tenant, table_name)),
+            None => Cow::Borrowed(table_name),
+        }
+    }
+
+    /// The limits on the files of a zip archive
+    pub fn zip_limits(&self) -> ZipLimits {
+        ZipLimits {
+            max_entries: self.max_zip_entries,
+            max_compression_ratio: self.max_compression_ratio,
+        }
+    }
+
+    /// The clock the import reads the current time from, fixed by --as-of
+    pub fn clock(&self) -> Clock {
+        self.as_of.map_or(Clock::System, Clock::Fixed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn destructive_actions_are_refused_with_no_destructive() {
+        assert!(ensure_destructive_allowed(false, "drop the database").is_ok());
+        let error = ensure_destructive_allowed(true, "drop the database").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Refusing to drop the database because --no-destructive is set"
+        );
+    }
+}
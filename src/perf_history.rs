@@ -0,0 +1,271 @@
+//! The opt-in `_perf_history` table of how long each import took, for comparing the settings of
+//! several runs. It's only written to the output, nothing is sent anywhere.
+use crate::core::{DatabaseRow, DatabaseValue, HKTables};
+use crate::options::ImportOptions;
+use clap::ValueEnum;
+use std::collections::BTreeMap;
+use std::time::Duration;
+use time::OffsetDateTime;
+
+/// Name of the table every import with --perf-history appends to
+pub const PERF_HISTORY_TABLE_NAME: &str = "_perf_history";
+
+/// Rows written to a table and the time spent writing them
+#[derive(Default)]
+struct TableTiming {
+    rows: u64,
+    time: Duration,
+}
+
+/// Collects the timings of a single import
+pub struct PerfHistory {
+    started_at: OffsetDateTime,
+    phases: Vec<(&'static str, Duration)>,
+    tables: BTreeMap<String, TableTiming>,
+}
+
+impl PerfHistory {
+    pub fn start() -> Self {
+        Self {
+            started_at: OffsetDateTime::now_utc(),
+            phases: Vec::new(),
+            tables: BTreeMap::new(),
+        }
+    }
+
+    /// Records how long a phase of the import took, e.g. the schema or insert pass
+    pub fn record_phase(&mut self, phase: &'static str, elapsed: Duration) {
+        self.phases.push((phase, elapsed));
+    }
+
+    /// Records the time spent writing a row, which counts towards the rows of the table if it
+    /// wasn't skipped as a duplicate
+    pub fn record_insert(&mut self, table_name: &str, elapsed: Duration, written: bool) {
+        if !self.tables.contains_key(table_name) {
+            self.tables
+                .insert(table_name.to_string(), Default::default());
+        }
+        let table = self.tables.get_mut(table_name).expect("key must exist");
+        table.time += elapsed;
+        if written {
+            table.rows += 1;
+        }
+    }
+
+    /// Schema of the `_perf_history` table
+    pub fn table_schema() -> HKTables {
+        let columns = [
+            ("run_started_at", "DATE"),
+            ("kind", "TEXT"),
+            ("name", "TEXT"),
+            ("rows", "INTEGER"),
+            ("seconds", "REAL"),
+            ("rows_per_second", "REAL"),
+            ("value", "TEXT"),
+        ];
+        HKTables::from([(
+            PERF_HISTORY_TABLE_NAME.to_string(),
+            columns
+                .iter()
+                .map(|(name, ty)| (name.to_string(), *ty))
+                .collect(),
+        )])
+    }
+
+    /// Returns a row for each setting, phase, and table of the import, all with the time the
+    /// import started, which identifies the run
+    pub fn into_rows(self, options: &ImportOptions) -> anyhow::Result<Vec<DatabaseRow>> {
+        let row = |kind: &str, name: &str| {
+            vec![
+                (
+                    "run_started_at".to_string(),
+                    DatabaseValue::OffsetDateTime(self.started_at),
+                ),
+                ("kind".to_string(), DatabaseValue::Text(kind.to_string())),
+                ("name".to_string(), DatabaseValue::Text(name.to_string())),
+            ]
+        };
+        let mut rows = Vec::new();
+        for (name, value) in settings(options) {
+            let mut setting = row("setting", name);
+            setting.push(("value".to_string(), DatabaseValue::Text(value)));
+            rows.push(setting);
+        }
+        for (name, elapsed) in &self.phases {
+            let mut phase = row("phase", name);
+            phase.push((
+                "seconds".to_string(),
+                DatabaseValue::Real(elapsed.as_secs_f64()),
+            ));
+            rows.push(phase);
+        }
+        for (name, timing) in &self.tables {
+            let mut table = row("table", name);
+            let seconds = timing.time.as_secs_f64();
+            table.extend([
+                (
+                    "rows".to_string(),
+                    DatabaseValue::Integer(i64::try_from(timing.rows)?),
+                ),
+                ("seconds".to_string(), DatabaseValue::Real(seconds)),
+            ]);
+            if seconds > 0.0 {
+                table.push((
+                    "rows_per_second".to_string(),
+                    DatabaseValue::Real(timing.rows as f64 / seconds),
+                ));
+            }
+            rows.push(table);
+        }
+        Ok(rows)
+    }
+}
+
+/// The settings that affect how fast an import runs, with "default" for those left unset
+fn settings(options: &ImportOptions) -> Vec<(&'static str, String)> {
+    fn value_name<T: ValueEnum>(value: Option<T>) -> String {
+        value
+            .and_then(|value| value.to_possible_value())
+            .map_or("default".to_string(), |value| value.get_name().to_string())
+    }
+    vec![
+        ("journal_mode", value_name(options.journal_mode)),
+        ("synchronous", value_name(options.synchronous)),
+        (
+            "cache_size",
+            options
+                .cache_size
+                .map_or("default".to_string(), |size| size.to_string()),
+        ),
+        ("temp_store", value_name(options.temp_store)),
+        (
+            "replication_friendly",
+            options.replication_friendly.to_string(),
+        ),
+        ("append", options.append.to_string()),
+        ("resume", options.resume.to_string()),
+        ("tool_version", env!("CARGO_PKG_VERSION").to_string()),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conformance::EXPORT_FILES;
+    use crate::options::JournalMode;
+    use crate::testing::{self, TestDir};
+    use sqlx::SqlitePool;
+
+    fn text(row: &DatabaseRow, column: &str) -> Option<String> {
+        row.iter()
+            .find(|(name, _)| name == column)
+            .map(|(_, value)| value.to_text())
+    }
+
+    #[test]
+    fn settings_phases_and_tables_are_each_a_row() {
+        let mut perf_history = PerfHistory::start();
+        perf_history.record_phase("schema_pass", Duration::from_millis(250));
+        perf_history.record_insert("Workout", Duration::from_millis(500), true);
+        perf_history.record_insert("Workout", Duration::from_millis(500), true);
+        perf_history.record_insert("Workout", Duration::from_millis(1000), false);
+        perf_history.record_insert("ActivitySummary", Duration::ZERO, true);
+        let options = ImportOptions {
+            journal_mode: Some(JournalMode::Wal),
+            cache_size: Some(-64000),
+            ..ImportOptions::defaults()
+        };
+        let rows = perf_history.into_rows(&options).unwrap();
+        let settings: Vec<(String, String)> = rows
+            .iter()
+            .filter(|row| text(row, "kind").as_deref() == Some("setting"))
+            .map(|row| (text(row, "name").unwrap(), text(row, "value").unwrap()))
+            .collect();
+        assert_eq!(settings[0], ("journal_mode".to_string(), "wal".to_string()));
+        assert_eq!(
+            settings[1],
+            ("synchronous".to_string(), "default".to_string())
+        );
+        assert_eq!(
+            settings[2],
+            ("cache_size".to_string(), "-64000".to_string())
+        );
+        assert_eq!(settings.len(), 8);
+
+        let phase = &rows[settings.len()];
+        assert_eq!(text(phase, "name").as_deref(), Some("schema_pass"));
+        assert_eq!(text(phase, "seconds").as_deref(), Some("0.25"));
+
+        let tables: Vec<(Option<String>, Option<String>, Option<String>)> = rows
+            [settings.len() + 1..]
+            .iter()
+            .map(|row| {
+                (
+                    text(row, "name"),
+                    text(row, "rows"),
+                    text(row, "rows_per_second"),
+                )
+            })
+            .collect();
+        assert_eq!(
+            tables,
+            [
+                (
+                    Some("ActivitySummary".to_string()),
+                    Some("1".to_string()),
+                    None
+                ),
+                (
+                    Some("Workout".to_string()),
+                    Some("2".to_string()),
+                    Some("1".to_string())
+                ),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn each_import_appends_the_rows_of_its_run() {
+        let dir = TestDir::new("perf-history");
+        let export_dir = dir.write_export("export", EXPORT_FILES);
+        let db_url = dir.db_url("health.db");
+        let options = ImportOptions {
+            perf_history: true,
+            ..ImportOptions::defaults()
+        };
+        testing::import(&export_dir, &db_url, &options)
+            .await
+            .unwrap();
+        let appended = ImportOptions {
+            append: true,
+            ..options
+        };
+        testing::import(&export_dir, &db_url, &appended)
+            .await
+            .unwrap();
+        let db = SqlitePool::connect(&db_url).await.unwrap();
+        let runs: Vec<(i64, String)> = sqlx::query_as(
+            "SELECT COUNT(DISTINCT run_started_at), value FROM _perf_history WHERE name = 'append' GROUP BY value ORDER BY value",
+        )
+        .fetch_all(&db)
+        .await
+        .unwrap();
+        assert_eq!(runs, [(1, "false".to_string()), (1, "true".to_string())]);
+        let phases: Vec<String> = sqlx::query_scalar(
+            "SELECT DISTINCT name FROM _perf_history WHERE kind = 'phase' ORDER BY name",
+        )
+        .fetch_all(&db)
+        .await
+        .unwrap();
+        assert!(phases.contains(&"schema_pass".to_string()), "{:?}", phases);
+        assert!(phases.contains(&"insert_pass".to_string()), "{:?}", phases);
+        let heart_rates: i64 = sqlx::query_scalar(
+            "SELECT rows FROM _perf_history WHERE kind = 'table' AND name = 'HKQuantityTypeIdentifierHeartRate' ORDER BY run_started_at LIMIT 1",
+        )
+        .fetch_one(&db)
+        .await
+        .unwrap();
+        assert_eq!(heart_rates, 2);
+        db.close().await;
+    }
+}
@@ -0,0 +1,366 @@
+//! Tracking where each output column came from, written to the `_schema_provenance` table
+use crate::clinical::CLINICAL_TABLE_PREFIX;
+use crate::core::{
+    DatabaseRow, DatabaseValue, HKTables, METADATA_COLUMN_PREFIX, RAW_VALUES_COLUMN,
+    RECORD_ID_COLUMN, WORKOUT_ID_COLUMN,
+};
+use crate::ecg::ECG_ID_COLUMN;
+use crate::naming;
+use crate::options::Naming;
+use crate::route::ROUTE_PROPERTIES_COLUMN;
+use std::collections::{BTreeMap, BTreeSet};
+use time::OffsetDateTime;
+
+/// Name of the table describing the origin of every column
+pub const PROVENANCE_TABLE_NAME: &str = "_schema_provenance";
+
+/// Columns built from child elements rather than copied from an attribute, and the element
+/// they are built from
+const SYNTHESIZED_COLUMNS: &[(&str, &str)] = &[
+    (WORKOUT_ID_COLUMN, "Workout"),
+    (RECORD_ID_COLUMN, "Record"),
+    ("geometry", "WorkoutRoute"),
+    (ROUTE_PROPERTIES_COLUMN, "WorkoutRoute"),
+    (ECG_ID_COLUMN, "electrocardiogram CSV"),
+];
+
+/// How a column was produced from the export
+#[derive(Clone, Copy, Debug)]
+enum ColumnOrigin {
+    /// An attribute of the element
+    Attribute,
+    /// The value of a MetadataEntry child element
+    Metadata,
+    /// Derived from child elements or added by a transform
+    Synthesized,
+}
+
+impl ColumnOrigin {
+    fn as_str(&self) -> &'static str {
+        match self {
+            ColumnOrigin::Attribute => "attribute",
+            ColumnOrigin::Metadata => "metadata",
+            ColumnOrigin::Synthesized => "synthesized",
+        }
+    }
+}
+
+struct ColumnProvenance {
+    origin: ColumnOrigin,
+    /// Name of the XML element the value is read from
+    element: &'static str,
+    /// Name of the attribute or metadata key the value is read from
+    key: Option<String>,
+    /// sourceName of every row with a value in the column
+    sources: BTreeSet<String>,
+    row_count: i64,
+    first_seen: Option<OffsetDateTime>,
+    last_seen: Option<OffsetDateTime>,
+}
+
+impl ColumnProvenance {
+    fn new(origin: ColumnOrigin, element: &'static str, key: Option<String>) -> Self {
+        Self {
+            origin,
+            element,
+            key,
+            sources: Default::default(),
+            row_count: 0,
+            first_seen: None,
+            last_seen: None,
+        }
+    }
+}
+
+/// The provenance of every column of every table, keyed by table and then column name
+#[derive(Default)]
+pub struct SchemaProvenance {
+    tables: BTreeMap<String, BTreeMap<String, ColumnProvenance>>,
+}
+
+impl SchemaProvenance {
+    /// Classifies the columns inferred from the export
+    pub fn from_tables(tables: &HKTables) -> Self {
+        let mut provenance = Self::default();
+        for (table_name, columns) in tables {
+            let element = element_name(table_name);
+            let table = provenance.tables.entry(table_name.clone()).or_default();
+            for column_name in columns.keys() {
+                let column = match column_name.strip_prefix(METADATA_COLUMN_PREFIX) {
+                    Some(key) => ColumnProvenance::new(
+                        ColumnOrigin::Metadata,
+                        element,
+                        Some(key.to_string()),
+                    ),
+                    None => match SYNTHESIZED_COLUMNS
+                        .iter()
+                        .find(|(name, _)| name == column_name)
+                    {
+                        Some((_, child)) => {
+                            ColumnProvenance::new(ColumnOrigin::Synthesized, child, None)
+                        }
+                        // The strings of the attributes of the table's own element
+                        None if column_name == RAW_VALUES_COLUMN => {
+                            ColumnProvenance::new(ColumnOrigin::Synthesized, element, None)
+                        }
+                        None => ColumnProvenance::new(
+                            ColumnOrigin::Attribute,
+                            element,
+                            Some(column_name.clone()),
+                        ),
+                    },
+                };
+                table.insert(column_name.clone(), column);
+            }
+        }
+        provenance
+    }
+
+    /// Records any columns not seen by from_tables, i.e. those added by the row transforms
+    pub fn add_synthesized(&mut self, tables: &HKTables) {
+        for (table_name, columns) in tables {
+            let element = element_name(table_name);
+            let table = self.tables.entry(table_name.clone()).or_default();
+            for column_name in columns.keys() {
+                table.entry(column_name.clone()).or_insert_with(|| {
+                    ColumnProvenance::new(ColumnOrigin::Synthesized, element, None)
+                });
+            }
+        }
+    }
+
+    /// Records the source and date of a row inserted to the table
+    pub fn observe(&mut self, table_name: &str, row: &DatabaseRow) {
+        let table = match self.tables.get_mut(table_name) {
+            Some(table) => table,
+            None => return,
+        };
+        let mut source = None;
+        let mut date = None;
+        for (name, value) in row {
+            match (name.as_str(), value) {
+                ("sourceName", DatabaseValue::Text(s)) => source = Some(s),
+                ("startDate", DatabaseValue::OffsetDateTime(d)) => date = Some(*d),
+                ("dateComponents", DatabaseValue::Date(d)) => {
+                    date = Some(d.midnight().assume_utc())
+                }
+                _ => {}
+            }
+        }
+        for (name, _) in row {
+            if let Some(column) = table.get_mut(name) {
+                column.row_count += 1;
+                if let Some(source) = source {
+                    if !column.sources.contains(source) {
+                        column.sources.insert(source.clone());
+                    }
+                }
+                if let Some(date) = date {
+                    column.first_seen = Some(column.first_seen.map_or(date, |d| d.min(date)));
+                    column.last_seen = Some(column.last_seen.map_or(date, |d| d.max(date)));
+                }
+            }
+        }
+    }
+
+    /// The schema of the provenance table
+    pub fn table_schema() -> HKTables {
+        let columns = [
+            ("table", "TEXT"),
+            ("column", "TEXT"),
+            ("origin", "TEXT"),
+            ("element", "TEXT"),
+            ("key", "TEXT"),
+            ("originalTable", "TEXT"),
+            ("originalColumn", "TEXT"),
+            ("sources", "JSON"),
+            ("rowCount", "INTEGER"),
+            ("firstSeenDate", "DATE"),
+            ("lastSeenDate", "DATE"),
+        ];
+        HKTables::from([(
+            PROVENANCE_TABLE_NAME.to_string(),
+            columns
+                .iter()
+                .map(|(name, ty)| (name.to_string(), *ty))
+                .collect(),
+        )])
+    }
+
+    /// Returns a row of the provenance table for each column, with the table and column names of
+    /// the output. The names before --naming converted them are kept in originalTable and
+    /// originalColumn.
+    pub fn into_rows(
+        self,
+        output_table_name: impl Fn(&str) -> String,
+        naming: Naming,
+    ) -> Vec<DatabaseRow> {
+        let mut rows = Vec::new();
+        for (table_name, columns) in self.tables {
+            let table_name = output_table_name(&table_name);
+            for (column_name, column) in columns {
+                let mut row: DatabaseRow = vec![
+                    (
+                        "table".to_string(),
+                        DatabaseValue::Text(naming::convert(naming, &table_name)),
+                    ),
+                    (
+                        "column".to_string(),
+                        DatabaseValue::Text(naming::convert(naming, &column_name)),
+                    ),
+                    (
+                        "originalTable".to_string(),
+                        DatabaseValue::Text(table_name.clone()),
+                    ),
+                    (
+                        "originalColumn".to_string(),
+                        DatabaseValue::Text(column_name),
+                    ),
+                    (
+                        "origin".to_string(),
+                        DatabaseValue::Text(column.origin.as_str().to_string()),
+                    ),
+                    (
+                        "element".to_string(),
+                        DatabaseValue::Text(column.element.to_string()),
+                    ),
+                    (
+                        "sources".to_string(),
+                        DatabaseValue::Json(column.sources.into_iter().collect()),
+                    ),
+                    (
+                        "rowCount".to_string(),
+                        DatabaseValue::Integer(column.row_count),
+                    ),
+                ];
+                if let Some(key) = column.key {
+                    row.push(("key".to_string(), DatabaseValue::Text(key)));
+                }
+                if let Some(date) = column.first_seen {
+                    row.push((
+                        "firstSeenDate".to_string(),
+                        DatabaseValue::OffsetDateTime(date),
+                    ));
+                }
+                if let Some(date) = column.last_seen {
+                    row.push((
+                        "lastSeenDate".to_string(),
+                        DatabaseValue::OffsetDateTime(date),
+                    ));
+                }
+                rows.push(row);
+            }
+        }
+        rows
+    }
+}
+
+/// Returns the name of the element the rows of a table are read from
+fn element_name(table_name: &str) -> &'static str {
+    match table_name {
+        "Workout" => "Workout",
+        "ActivitySummary" => "ActivitySummary",
+        "Me" => "Me",
+        "export_metadata" => "ExportDate",
+        "WorkoutEvent" => "WorkoutEvent",
+        "WorkoutStatistics" => "WorkoutStatistics",
+        "WorkoutActivity" => "WorkoutActivity",
+        "WorkoutRoutePoint" => "trkpt",
+        "AudiogramSensitivityPoint" => "SensitivityPoint",
+        "InstantaneousBeatsPerMinute" => "InstantaneousBeatsPerMinute",
+        "Ecg" | "EcgVoltageSample" => "electrocardiogram CSV",
+        "_blobs" => "file of the export",
+        name if name.starts_with(CLINICAL_TABLE_PREFIX) => "FHIR resource",
+        _ => "Record",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::conformance::EXPORT_FILES;
+    use crate::options::ImportOptions;
+    use crate::testing::{self, TestDir};
+    use sqlx::SqlitePool;
+
+    type Provenance = (String, String, String, Option<String>, i64, String);
+
+    #[tokio::test]
+    async fn columns_are_traced_to_their_origin() {
+        let dir = TestDir::new("provenance");
+        let export_dir = dir.write_export("export", EXPORT_FILES);
+        let db_url = dir.db_url("health.db");
+        testing::import(&export_dir, &db_url, &ImportOptions::defaults())
+            .await
+            .unwrap();
+        let db = SqlitePool::connect(&db_url).await.unwrap();
+        let columns: Vec<Provenance> = sqlx::query_as(
+            r#"SELECT "column", origin, element, key, rowCount, CAST(sources AS TEXT)
+            FROM _schema_provenance
+            WHERE "table" = ? AND "column" IN ('value', 'metadata_HKMetadataKeyHeartRateMotionContext', 'row_key')
+                OR "table" = 'Workout' AND "column" = 'geometry'
+                OR "table" = 'WorkoutEvent' AND "column" = 'workout_id'
+            ORDER BY "table", "column""#,
+        )
+        .bind("HKQuantityTypeIdentifierHeartRate")
+        .fetch_all(&db)
+        .await
+        .unwrap();
+        let provenance =
+            |column: &str, origin: &str, element: &str, key: Option<&str>, rows, sources: &str| {
+                (
+                    column.to_string(),
+                    origin.to_string(),
+                    element.to_string(),
+                    key.map(str::to_string),
+                    rows,
+                    sources.to_string(),
+                )
+            };
+        assert_eq!(
+            columns,
+            [
+                provenance(
+                    "metadata_HKMetadataKeyHeartRateMotionContext",
+                    "metadata",
+                    "Record",
+                    Some("HKMetadataKeyHeartRateMotionContext"),
+                    1,
+                    r#"["Watch"]"#
+                ),
+                provenance("row_key", "synthesized", "Record", None, 2, r#"["Watch"]"#),
+                provenance(
+                    "value",
+                    "attribute",
+                    "Record",
+                    Some("value"),
+                    2,
+                    r#"["Watch"]"#
+                ),
+                provenance(
+                    "geometry",
+                    "synthesized",
+                    "WorkoutRoute",
+                    None,
+                    1,
+                    r#"["Watch"]"#
+                ),
+                // Child elements have no source of their own
+                provenance("workout_id", "synthesized", "Workout", None, 2, "[]"),
+            ]
+        );
+        let seen: (String, String) = sqlx::query_as(
+            r#"SELECT firstSeenDate, lastSeenDate FROM _schema_provenance
+            WHERE "table" = 'HKQuantityTypeIdentifierHeartRate' AND "column" = 'value'"#,
+        )
+        .fetch_one(&db)
+        .await
+        .unwrap();
+        assert_eq!(
+            seen,
+            (
+                "2024-01-01T08:00:00-08:00".to_string(),
+                "2024-01-01T08:05:00-08:00".to_string()
+            )
+        );
+    }
+}
@@ -0,0 +1,194 @@
+//! Limits on the size of an import, for services that convert untrusted uploads
+use std::fmt;
+
+/// Exit code of an import stopped because it exceeded a limit
+pub const QUOTA_EXCEEDED_EXIT_CODE: i32 = 3;
+
+/// Number of rows written between checks of the database size against --max-db-size
+pub const DATABASE_SIZE_CHECK_ROWS: u64 = 10_000;
+
+/// A limit set by the --max-* options
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Quota {
+    /// The uncompressed size of the export in bytes
+    ExportSize,
+    /// The number of rows written
+    Rows,
+    /// The size of the SQLite database in bytes
+    DatabaseSize,
+}
+
+impl Quota {
+    fn option(&self) -> &'static str {
+        match self {
+            Quota::ExportSize => "--max-export-size",
+            Quota::Rows => "--max-rows",
+            Quota::DatabaseSize => "--max-db-size",
+        }
+    }
+
+    /// The error code of the quota in the JSON log
+    pub fn code(&self) -> &'static str {
+        match self {
+            Quota::ExportSize => "export_size_exceeded",
+            Quota::Rows => "rows_exceeded",
+            Quota::DatabaseSize => "db_size_exceeded",
+        }
+    }
+}
+
+/// The error of an import that exceeded a limit, which the program exits with
+/// QUOTA_EXCEEDED_EXIT_CODE for
+#[derive(Debug)]
+pub struct QuotaExceeded {
+    pub quota: Quota,
+    pub limit: u64,
+}
+
+impl fmt::Display for QuotaExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let exceeded = match self.quota {
+            Quota::ExportSize => "The uncompressed export is larger than",
+            Quota::Rows => "The import writes more rows than",
+            Quota::DatabaseSize => "The database grew larger than",
+        };
+        let unit = match self.quota {
+            Quota::Rows => "",
+            Quota::ExportSize | Quota::DatabaseSize => " bytes",
+        };
+        write!(
+            f,
+            "{} the {} of {}{}",
+            exceeded,
+            self.quota.option(),
+            self.limit,
+            unit
+        )
+    }
+}
+
+impl std::error::Error for QuotaExceeded {}
+
+/// Fails with QuotaExceeded if the value is over the limit, if one is set
+pub fn check(quota: Quota, limit: Option<u64>, value: u64) -> anyhow::Result<()> {
+    match limit {
+        Some(limit) if value > limit => Err(QuotaExceeded { quota, limit }.into()),
+        _ => Ok(()),
+    }
+}
+
+/// Returns true if the database size is due to be checked after writing the number of rows
+pub fn database_size_check_due(rows_written: u64) -> bool {
+    rows_written.is_multiple_of(DATABASE_SIZE_CHECK_ROWS)
+}
+
+/// Returns the exceeded quota if it is the error or one of its causes
+pub fn exceeded(error: &anyhow::Error) -> Option<&QuotaExceeded> {
+    error.chain().find_map(|cause| cause.downcast_ref())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conformance::EXPORT_FILES;
+    use crate::options::ImportOptions;
+    use crate::testing::{self, TestDir};
+
+    #[test]
+    fn values_over_the_limit_exceed_it() {
+        assert!(check(Quota::Rows, None, u64::MAX).is_ok());
+        assert!(check(Quota::Rows, Some(10), 10).is_ok());
+        let error = check(Quota::Rows, Some(10), 11).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "The import writes more rows than the --max-rows of 10"
+        );
+        // Also when the error has been given context
+        let error = error.context("Failed to insert a row");
+        let quota = exceeded(&error).unwrap();
+        assert_eq!((quota.quota, quota.limit), (Quota::Rows, 10));
+        assert!(exceeded(&anyhow::anyhow!("another error")).is_none());
+    }
+
+    #[test]
+    fn the_database_size_is_checked_every_few_rows() {
+        let due: Vec<u64> = (1..=3 * DATABASE_SIZE_CHECK_ROWS)
+            .filter(|rows| database_size_check_due(*rows))
+            .collect();
+        assert_eq!(
+            due,
+            [
+                DATABASE_SIZE_CHECK_ROWS,
+                2 * DATABASE_SIZE_CHECK_ROWS,
+                3 * DATABASE_SIZE_CHECK_ROWS
+            ]
+        );
+    }
+
+    /// Imports the conformance export with the options, returning the quota it exceeded
+    async fn exceeded_quota(dir: &TestDir, options: ImportOptions) -> (Quota, String) {
+        let export_dir = dir.write_export("export", EXPORT_FILES);
+        let error = match testing::import(&export_dir, &dir.db_url("health.db"), &options).await {
+            Ok(_) => panic!("the import wasn't aborted"),
+            Err(error) => error,
+        };
+        let quota = exceeded(&error).expect("a quota error").quota;
+        (quota, error.to_string())
+    }
+
+    #[tokio::test]
+    async fn imports_over_each_limit_are_aborted() {
+        let dir = TestDir::new("quota-export-size");
+        let options = ImportOptions {
+            max_export_size: Some(1000),
+            ..ImportOptions::defaults()
+        };
+        assert_eq!(
+            exceeded_quota(&dir, options).await,
+            (
+                Quota::ExportSize,
+                "The uncompressed export is larger than the --max-export-size of 1000 bytes"
+                    .to_string()
+            )
+        );
+        let dir = TestDir::new("quota-rows");
+        let options = ImportOptions {
+            max_rows: Some(5),
+            ..ImportOptions::defaults()
+        };
+        assert_eq!(
+            exceeded_quota(&dir, options).await,
+            (
+                Quota::Rows,
+                "The import writes more rows than the --max-rows of 5".to_string()
+            )
+        );
+        let dir = TestDir::new("quota-db-size");
+        let options = ImportOptions {
+            max_db_size: Some(4096),
+            ..ImportOptions::defaults()
+        };
+        assert_eq!(
+            exceeded_quota(&dir, options).await,
+            (
+                Quota::DatabaseSize,
+                "The database grew larger than the --max-db-size of 4096 bytes".to_string()
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn imports_within_the_limits_complete() {
+        let dir = TestDir::new("quota-within");
+        let export_dir = dir.write_export("export", EXPORT_FILES);
+        let options = ImportOptions {
+            max_export_size: Some(10_000_000),
+            max_rows: Some(10_000),
+            max_db_size: Some(100_000_000),
+            ..ImportOptions::defaults()
+        };
+        testing::import(&export_dir, &dir.db_url("health.db"), &options)
+            .await
+            .unwrap();
+    }
+}
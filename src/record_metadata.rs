@@ -0,0 +1,287 @@
+//! Writing the MetadataEntry elements to a RecordMetadata key and value table with
+//! --metadata-table, rather than a sparse metadata_ column per key
+use crate::core::{DatabaseRow, DatabaseValue, HKTables, METADATA_COLUMN_PREFIX};
+use crate::identifier::get_valid_sqlite_identifier;
+use crate::naming;
+use crate::options::Naming;
+use sqlx::{Sqlite, Transaction};
+use std::collections::BTreeMap;
+
+/// Name of the table holding a row per metadata entry with --metadata-table
+pub const RECORD_METADATA_TABLE_NAME: &str = "RecordMetadata";
+
+/// The RecordMetadata table, referring to the row of each entry by its table and rowid
+pub fn table_schema() -> HKTables {
+    let columns: BTreeMap<String, &'static str> = [
+        ("table_name".to_string(), "TEXT"),
+        ("row_id".to_string(), "INTEGER"),
+        ("key".to_string(), "TEXT"),
+        ("value".to_string(), "TEXT"),
+    ]
+    .into_iter()
+    .collect();
+    [(RECORD_METADATA_TABLE_NAME.to_string(), columns)]
+        .into_iter()
+        .collect()
+}
+
+/// Removes the metadata columns from the tables
+pub fn extend_tables(tables: &mut HKTables) {
+    for columns in tables.values_mut() {
+        columns.retain(|column_name, _| !column_name.starts_with(METADATA_COLUMN_PREFIX));
+    }
+}
+
+/// Splits the metadata columns off the row, returning the row and the key and value of each
+pub fn split_row(row: DatabaseRow) -> (DatabaseRow, Vec<(String, DatabaseValue)>) {
+    let mut metadata = Vec::new();
+    let mut columns = DatabaseRow::with_capacity(row.len());
+    for (name, value) in row {
+        match name.strip_prefix(METADATA_COLUMN_PREFIX) {
+            Some(key) => metadata.push((key.to_string(), value)),
+            None => columns.push((name, value)),
+        }
+    }
+    (columns, metadata)
+}
+
+/// Returns the RecordMetadata rows of the metadata of the row with the id in the table. The
+/// values are stored as text, as the types of the values of a key can differ.
+pub fn metadata_rows(
+    table_name: &str,
+    row_id: i64,
+    metadata: Vec<(String, DatabaseValue)>,
+) -> Vec<DatabaseRow> {
+    metadata
+        .into_iter()
+        .map(|(key, value)| {
+            vec![
+                (
+                    "table_name".to_string(),
+                    DatabaseValue::Text(table_name.to_string()),
+                ),
+                ("row_id".to_string(), DatabaseValue::Integer(row_id)),
+                ("key".to_string(), DatabaseValue::Text(key)),
+                ("value".to_string(), DatabaseValue::Text(value.to_text())),
+            ]
+        })
+        .collect()
+}
+
+/// Returns the statement creating the index of the RecordMetadata table by the row of each
+/// entry, which joins to the rows of the other tables use
+pub fn index_statement(table_name: &str) -> String {
+    format!(
+        "CREATE INDEX IF NOT EXISTS {} ON {} (`table_name`, `row_id`)",
+        get_valid_sqlite_identifier(&format!("{}_row", table_name)),
+        get_valid_sqlite_identifier(table_name)
+    )
+}
+
+/// Returns the tables of the database with an entry of the metadata key in the RecordMetadata
+/// table, which refers to them by their names in the casing of --naming
+pub async fn tables_with_key(
+    tx: &mut Transaction<'_, Sqlite>,
+    naming: Naming,
+    key: &str,
+) -> anyhow::Result<Vec<String>> {
+    if !table_exists(tx).await? {
+        return Ok(Vec::new());
+    }
+    let names: Vec<String> = sqlx::query_scalar(&format!(
+        "SELECT DISTINCT table_name FROM {} WHERE key = ?",
+        get_valid_sqlite_identifier(RECORD_METADATA_TABLE_NAME)
+    ))
+    .bind(key)
+    .fetch_all(&mut *tx)
+    .await?;
+    let tables: Vec<String> =
+        sqlx::query_scalar("SELECT name FROM sqlite_master WHERE type = 'table' ORDER BY name")
+            .fetch_all(&mut *tx)
+            .await?;
+    Ok(tables
+        .into_iter()
+        .filter(|table_name| names.contains(&naming::convert(naming, table_name)))
+        .collect())
+}
+
+/// Returns an SQL expression for the value of the metadata key of the row of the table the query
+/// selects from, read from the RecordMetadata table, or None if the database has none. The value
+/// is text, so it's cast to an integer if `integer` is true.
+pub async fn value_expression(
+    tx: &mut Transaction<'_, Sqlite>,
+    naming: Naming,
+    table_name: &str,
+    key: &str,
+    integer: bool,
+) -> anyhow::Result<Option<String>> {
+    if !table_exists(tx).await? {
+        return Ok(None);
+    }
+    let value = if integer {
+        "CAST(m.value AS INTEGER)"
+    } else {
+        "m.value"
+    };
+    Ok(Some(format!(
+        "(SELECT {} FROM {} m WHERE m.table_name = '{}' AND m.row_id = {}.rowid AND m.key = '{}')",
+        value,
+        get_valid_sqlite_identifier(RECORD_METADATA_TABLE_NAME),
+        naming::convert(naming, table_name).replace('\'', "''"),
+        get_valid_sqlite_identifier(table_name),
+        key.replace('\'', "''")
+    )))
+}
+
+async fn table_exists(tx: &mut Transaction<'_, Sqlite>) -> anyhow::Result<bool> {
+    let count: i64 =
+        sqlx::query_scalar("SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = ?")
+            .bind(RECORD_METADATA_TABLE_NAME)
+            .fetch_one(&mut *tx)
+            .await?;
+    Ok(count > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conformance::EXPORT_FILES;
+    use crate::options::ImportOptions;
+    use crate::testing::{self, TestDir};
+    use sqlx::SqlitePool;
+
+    #[test]
+    fn the_metadata_columns_are_split_off_the_row() {
+        let row = vec![
+            ("value".to_string(), DatabaseValue::Real(72.5)),
+            (
+                "metadata_HKWasUserEntered".to_string(),
+                DatabaseValue::Integer(1),
+            ),
+            ("unit".to_string(), DatabaseValue::Text("kg".to_string())),
+            (
+                "metadata_HKTimeZone".to_string(),
+                DatabaseValue::Text("Europe/Paris".to_string()),
+            ),
+        ];
+        let (columns, metadata) = split_row(row);
+        let names: Vec<&str> = columns.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, ["value", "unit"]);
+        let rows = metadata_rows("HKQuantityTypeIdentifierBodyMass", 3, metadata);
+        let rows: Vec<Vec<String>> = rows
+            .iter()
+            .map(|row| row.iter().map(|(_, value)| value.to_text()).collect())
+            .collect();
+        assert_eq!(
+            rows,
+            [
+                [
+                    "HKQuantityTypeIdentifierBodyMass",
+                    "3",
+                    "HKWasUserEntered",
+                    "1"
+                ],
+                [
+                    "HKQuantityTypeIdentifierBodyMass",
+                    "3",
+                    "HKTimeZone",
+                    "Europe/Paris"
+                ],
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn value_expressions_read_the_metadata_of_each_row() {
+        let pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        let mut tx = pool.begin().await.unwrap();
+        assert_eq!(
+            value_expression(&mut tx, Naming::Original, "Samples", "key", false)
+                .await
+                .unwrap(),
+            None
+        );
+        sqlx::query(
+            r#"CREATE TABLE RecordMetadata (table_name TEXT, row_id INTEGER, key TEXT, value TEXT);
+            CREATE TABLE "it's" (value REAL);
+            INSERT INTO "it's" VALUES (1), (2);
+            INSERT INTO RecordMetadata VALUES ('it''s', 1, 'the ''key''', '10'), ('it''s', 2, 'other', 'x');"#,
+        )
+        .execute(&mut *tx)
+        .await
+        .unwrap();
+        for (integer, expected) in [
+            (false, [Some("10".to_string()), None]),
+            (true, [Some("10".to_string()), None]),
+        ] {
+            let value = value_expression(&mut tx, Naming::Original, "it's", "the 'key'", integer)
+                .await
+                .unwrap()
+                .unwrap();
+            let values: Vec<Option<String>> = sqlx::query_scalar(&format!(
+                "SELECT CAST({} AS TEXT) FROM `it's` ORDER BY rowid",
+                value
+            ))
+            .fetch_all(&mut *tx)
+            .await
+            .unwrap();
+            assert_eq!(values, expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn imports_with_the_metadata_table_hold_an_entry_per_metadata_key() {
+        let dir = TestDir::new("metadata-table");
+        let export_dir = dir.write_export("export", EXPORT_FILES);
+        for naming in [Naming::Original, Naming::Snake] {
+            let db_url = dir.db_url(&format!("{}.db", naming.as_str()));
+            let options = ImportOptions {
+                metadata_table: true,
+                naming,
+                ..ImportOptions::defaults()
+            };
+            testing::import(&export_dir, &db_url, &options)
+                .await
+                .unwrap();
+            let db = SqlitePool::connect(&db_url).await.unwrap();
+            let body_mass = naming::convert(naming, "HKQuantityTypeIdentifierBodyMass");
+            let metadata_table = naming::convert(naming, RECORD_METADATA_TABLE_NAME);
+            let entries: Vec<(String, i64, String, String)> = sqlx::query_as(&format!(
+                "SELECT table_name, row_id, key, value FROM {} WHERE table_name = ?",
+                get_valid_sqlite_identifier(&metadata_table)
+            ))
+            .bind(&body_mass)
+            .fetch_all(&db)
+            .await
+            .unwrap();
+            assert_eq!(
+                entries,
+                [(
+                    body_mass.clone(),
+                    1,
+                    "HKWasUserEntered".to_string(),
+                    "1".to_string()
+                )]
+            );
+            // The metadata columns aren't created
+            let metadata_columns: i64 = sqlx::query_scalar(
+                r#"SELECT COUNT(*) FROM sqlite_master m, pragma_table_info(m.name) p
+                WHERE m.type = 'table' AND p.name LIKE 'metadata%'"#,
+            )
+            .fetch_one(&db)
+            .await
+            .unwrap();
+            assert_eq!(metadata_columns, 0);
+            // The body mass entered by hand is left out of the view reading the metadata table,
+            // which is found by the name of --naming but keeps the name of the export
+            let measured: i64 = sqlx::query_scalar(
+                "SELECT COUNT(*) FROM HKQuantityTypeIdentifierBodyMass_measured",
+            )
+            .fetch_one(&db)
+            .await
+            .unwrap();
+            assert_eq!(measured, 0, "{}", naming.as_str());
+            db.close().await;
+        }
+    }
+}
@@ -0,0 +1,1156 @@
+//! The known HealthKit sample type identifiers, with their kind, canonical unit, the iOS
+//! version that introduced them, and a readable name
+use serde::Serialize;
+
+/// The kind of sample a type identifier names
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TypeKind {
+    /// A numeric value with a unit, e.g. a heart rate
+    Quantity,
+    /// A value from a fixed set, e.g. a sleep stage or symptom severity
+    Category,
+    /// Several quantities recorded together, e.g. a blood pressure reading
+    Correlation,
+}
+
+impl TypeKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TypeKind::Quantity => "quantity",
+            TypeKind::Category => "category",
+            TypeKind::Correlation => "correlation",
+        }
+    }
+}
+
+/// A known sample type identifier
+#[derive(Debug, Serialize)]
+pub struct TypeInfo {
+    pub identifier: &'static str,
+    pub kind: TypeKind,
+    /// The unit values are converted to, as an HKUnit string. Only quantities have units.
+    pub unit: Option<&'static str>,
+    /// The iOS version the identifier was introduced in
+    pub introduced: &'static str,
+    pub name: &'static str,
+}
+
+const fn quantity(
+    identifier: &'static str,
+    unit: &'static str,
+    introduced: &'static str,
+    name: &'static str,
+) -> TypeInfo {
+    TypeInfo {
+        identifier,
+        kind: TypeKind::Quantity,
+        unit: Some(unit),
+        introduced,
+        name,
+    }
+}
+
+const fn category(
+    identifier: &'static str,
+    introduced: &'static str,
+    name: &'static str,
+) -> TypeInfo {
+    TypeInfo {
+        identifier,
+        kind: TypeKind::Category,
+        unit: None,
+        introduced,
+        name,
+    }
+}
+
+const fn correlation(
+    identifier: &'static str,
+    introduced: &'static str,
+    name: &'static str,
+) -> TypeInfo {
+    TypeInfo {
+        identifier,
+        kind: TypeKind::Correlation,
+        unit: None,
+        introduced,
+        name,
+    }
+}
+
+/// Every known sample type identifier, quantities first, each kind in alphabetical order
+pub const TYPES: &[TypeInfo] = &[
+    quantity(
+        "HKQuantityTypeIdentifierActiveEnergyBurned",
+        "kcal",
+        "8.0",
+        "Active Energy",
+    ),
+    quantity(
+        "HKQuantityTypeIdentifierAppleExerciseTime",
+        "min",
+        "9.3",
+        "Exercise Minutes",
+    ),
+    quantity(
+        "HKQuantityTypeIdentifierAppleMoveTime",
+        "min",
+        "14.5",
+        "Move Minutes",
+    ),
+    quantity(
+        "HKQuantityTypeIdentifierAppleSleepingBreathingDisturbances",
+        "count",
+        "18.0",
+        "Breathing Disturbances",
+    ),
+    quantity(
+        "HKQuantityTypeIdentifierAppleSleepingWristTemperature",
+        "degC",
+        "16.0",
+        "Sleeping Wrist Temperature",
+    ),
+    quantity(
+        "HKQuantityTypeIdentifierAppleStandTime",
+        "min",
+        "13.0",
+        "Stand Minutes",
+    ),
+    quantity(
+        "HKQuantityTypeIdentifierAppleWalkingSteadiness",
+        "%",
+        "15.0",
+        "Walking Steadiness",
+    ),
+    quantity(
+        "HKQuantityTypeIdentifierAtrialFibrillationBurden",
+        "%",
+        "16.0",
+        "AFib History",
+    ),
+    quantity(
+        "HKQuantityTypeIdentifierBasalBodyTemperature",
+        "degC",
+        "9.0",
+        "Basal Body Temperature",
+    ),
+    quantity(
+        "HKQuantityTypeIdentifierBasalEnergyBurned",
+        "kcal",
+        "8.0",
+        "Resting Energy",
+    ),
+    quantity(
+        "HKQuantityTypeIdentifierBloodAlcoholContent",
+        "%",
+        "8.0",
+        "Blood Alcohol Content",
+    ),
+    quantity(
+        "HKQuantityTypeIdentifierBloodGlucose",
+        "mg/dL",
+        "8.0",
+        "Blood Glucose",
+    ),
+    quantity(
+        "HKQuantityTypeIdentifierBloodPressureDiastolic",
+        "mmHg",
+        "8.0",
+        "Diastolic Blood Pressure",
+    ),
+    quantity(
+        "HKQuantityTypeIdentifierBloodPressureSystolic",
+        "mmHg",
+        "8.0",
+        "Systolic Blood Pressure",
+    ),
+    quantity(
+        "HKQuantityTypeIdentifierBodyFatPercentage",
+        "%",
+        "8.0",
+        "Body Fat Percentage",
+    ),
+    quantity("HKQuantityTypeIdentifierBodyMass", "kg", "8.0", "Weight"),
+    quantity(
+        "HKQuantityTypeIdentifierBodyMassIndex",
+        "count",
+        "8.0",
+        "Body Mass Index",
+    ),
+    quantity(
+        "HKQuantityTypeIdentifierBodyTemperature",
+        "degC",
+        "8.0",
+        "Body Temperature",
+    ),
+    quantity(
+        "HKQuantityTypeIdentifierCrossCountrySkiingSpeed",
+        "m/s",
+        "18.0",
+        "Cross Country Skiing Speed",
+    ),
+    quantity(
+        "HKQuantityTypeIdentifierCyclingCadence",
+        "count/min",
+        "17.0",
+        "Cycling Cadence",
+    ),
+    quantity(
+        "HKQuantityTypeIdentifierCyclingFunctionalThresholdPower",
+        "W",
+        "17.0",
+        "Cycling Functional Threshold Power",
+    ),
+    quantity(
+        "HKQuantityTypeIdentifierCyclingPower",
+        "W",
+        "17.0",
+        "Cycling Power",
+    ),
+    quantity(
+        "HKQuantityTypeIdentifierCyclingSpeed",
+        "m/s",
+        "17.0",
+        "Cycling Speed",
+    ),
+    quantity(
+        "HKQuantityTypeIdentifierDietaryBiotin",
+        "g",
+        "8.0",
+        "Biotin",
+    ),
+    quantity(
+        "HKQuantityTypeIdentifierDietaryCaffeine",
+        "g",
+        "8.0",
+        "Caffeine",
+    ),
+    quantity(
+        "HKQuantityTypeIdentifierDietaryCalcium",
+        "g",
+        "8.0",
+        "Calcium",
+    ),
+    quantity(
+        "HKQuantityTypeIdentifierDietaryCarbohydrates",
+        "g",
+        "8.0",
+        "Carbohydrates",
+    ),
+    quantity(
+        "HKQuantityTypeIdentifierDietaryChloride",
+        "g",
+        "8.0",
+        "Chloride",
+    ),
+    quantity(
+        "HKQuantityTypeIdentifierDietaryCholesterol",
+        "g",
+        "8.0",
+        "Dietary Cholesterol",
+    ),
+    quantity(
+        "HKQuantityTypeIdentifierDietaryChromium",
+        "g",
+        "8.0",
+        "Chromium",
+    ),
+    quantity(
+        "HKQuantityTypeIdentifierDietaryCopper",
+        "g",
+        "8.0",
+        "Copper",
+    ),
+    quantity(
+        "HKQuantityTypeIdentifierDietaryEnergyConsumed",
+        "kcal",
+        "8.0",
+        "Dietary Energy",
+    ),
+    quantity(
+        "HKQuantityTypeIdentifierDietaryFatMonounsaturated",
+        "g",
+        "8.0",
+        "Monounsaturated Fat",
+    ),
+    quantity(
+        "HKQuantityTypeIdentifierDietaryFatPolyunsaturated",
+        "g",
+        "8.0",
+        "Polyunsaturated Fat",
+    ),
+    quantity(
+        "HKQuantityTypeIdentifierDietaryFatSaturated",
+        "g",
+        "8.0",
+        "Saturated Fat",
+    ),
+    quantity(
+        "HKQuantityTypeIdentifierDietaryFatTotal",
+        "g",
+        "8.0",
+        "Total Fat",
+    ),
+    quantity("HKQuantityTypeIdentifierDietaryFiber", "g", "8.0", "Fiber"),
+    quantity(
+        "HKQuantityTypeIdentifierDietaryFolate",
+        "g",
+        "8.0",
+        "Folate",
+    ),
+    quantity(
+        "HKQuantityTypeIdentifierDietaryIodine",
+        "g",
+        "8.0",
+        "Iodine",
+    ),
+    quantity("HKQuantityTypeIdentifierDietaryIron", "g", "8.0", "Iron"),
+    quantity(
+        "HKQuantityTypeIdentifierDietaryMagnesium",
+        "g",
+        "8.0",
+        "Magnesium",
+    ),
+    quantity(
+        "HKQuantityTypeIdentifierDietaryManganese",
+        "g",
+        "8.0",
+        "Manganese",
+    ),
+    quantity(
+        "HKQuantityTypeIdentifierDietaryMolybdenum",
+        "g",
+        "8.0",
+        "Molybdenum",
+    ),
+    quantity(
+        "HKQuantityTypeIdentifierDietaryNiacin",
+        "g",
+        "8.0",
+        "Niacin",
+    ),
+    quantity(
+        "HKQuantityTypeIdentifierDietaryPantothenicAcid",
+        "g",
+        "8.0",
+        "Pantothenic Acid",
+    ),
+    quantity(
+        "HKQuantityTypeIdentifierDietaryPhosphorus",
+        "g",
+        "8.0",
+        "Phosphorus",
+    ),
+    quantity(
+        "HKQuantityTypeIdentifierDietaryPotassium",
+        "g",
+        "8.0",
+        "Potassium",
+    ),
+    quantity(
+        "HKQuantityTypeIdentifierDietaryProtein",
+        "g",
+        "8.0",
+        "Protein",
+    ),
+    quantity(
+        "HKQuantityTypeIdentifierDietaryRiboflavin",
+        "g",
+        "8.0",
+        "Riboflavin",
+    ),
+    quantity(
+        "HKQuantityTypeIdentifierDietarySelenium",
+        "g",
+        "8.0",
+        "Selenium",
+    ),
+    quantity(
+        "HKQuantityTypeIdentifierDietarySodium",
+        "g",
+        "8.0",
+        "Sodium",
+    ),
+    quantity("HKQuantityTypeIdentifierDietarySugar", "g", "8.0", "Sugar"),
+    quantity(
+        "HKQuantityTypeIdentifierDietaryThiamin",
+        "g",
+        "8.0",
+        "Thiamin",
+    ),
+    quantity(
+        "HKQuantityTypeIdentifierDietaryVitaminA",
+        "g",
+        "8.0",
+        "Vitamin A",
+    ),
+    quantity(
+        "HKQuantityTypeIdentifierDietaryVitaminB12",
+        "g",
+        "8.0",
+        "Vitamin B12",
+    ),
+    quantity(
+        "HKQuantityTypeIdentifierDietaryVitaminB6",
+        "g",
+        "8.0",
+        "Vitamin B6",
+    ),
+    quantity(
+        "HKQuantityTypeIdentifierDietaryVitaminC",
+        "g",
+        "8.0",
+        "Vitamin C",
+    ),
+    quantity(
+        "HKQuantityTypeIdentifierDietaryVitaminD",
+        "g",
+        "8.0",
+        "Vitamin D",
+    ),
+    quantity(
+        "HKQuantityTypeIdentifierDietaryVitaminE",
+        "g",
+        "8.0",
+        "Vitamin E",
+    ),
+    quantity(
+        "HKQuantityTypeIdentifierDietaryVitaminK",
+        "g",
+        "8.0",
+        "Vitamin K",
+    ),
+    quantity("HKQuantityTypeIdentifierDietaryWater", "mL", "9.0", "Water"),
+    quantity("HKQuantityTypeIdentifierDietaryZinc", "g", "8.0", "Zinc"),
+    quantity(
+        "HKQuantityTypeIdentifierDistanceCrossCountrySkiing",
+        "m",
+        "18.0",
+        "Cross Country Skiing Distance",
+    ),
+    quantity(
+        "HKQuantityTypeIdentifierDistanceCycling",
+        "m",
+        "8.0",
+        "Cycling Distance",
+    ),
+    quantity(
+        "HKQuantityTypeIdentifierDistanceDownhillSnowSports",
+        "m",
+        "11.2",
+        "Downhill Snow Sports Distance",
+    ),
+    quantity(
+        "HKQuantityTypeIdentifierDistancePaddleSports",
+        "m",
+        "18.0",
+        "Paddle Sports Distance",
+    ),
+    quantity(
+        "HKQuantityTypeIdentifierDistanceRowing",
+        "m",
+        "18.0",
+        "Rowing Distance",
+    ),
+    quantity(
+        "HKQuantityTypeIdentifierDistanceSkatingSports",
+        "m",
+        "18.0",
+        "Skating Sports Distance",
+    ),
+    quantity(
+        "HKQuantityTypeIdentifierDistanceSwimming",
+        "m",
+        "10.0",
+        "Swimming Distance",
+    ),
+    quantity(
+        "HKQuantityTypeIdentifierDistanceWalkingRunning",
+        "m",
+        "8.0",
+        "Walking + Running Distance",
+    ),
+    quantity(
+        "HKQuantityTypeIdentifierDistanceWheelchair",
+        "m",
+        "10.0",
+        "Wheelchair Distance",
+    ),
+    quantity(
+        "HKQuantityTypeIdentifierElectrodermalActivity",
+        "S",
+        "8.0",
+        "Electrodermal Activity",
+    ),
+    quantity(
+        "HKQuantityTypeIdentifierEnvironmentalAudioExposure",
+        "dBASPL",
+        "13.0",
+        "Environmental Sound Levels",
+    ),
+    quantity(
+        "HKQuantityTypeIdentifierEnvironmentalSoundReduction",
+        "dBASPL",
+        "16.0",
+        "Environmental Sound Reduction",
+    ),
+    quantity(
+        "HKQuantityTypeIdentifierEstimatedWorkoutEffortScore",
+        "appleEffortScore",
+        "18.0",
+        "Estimated Workout Effort",
+    ),
+    quantity(
+        "HKQuantityTypeIdentifierFlightsClimbed",
+        "count",
+        "8.0",
+        "Flights Climbed",
+    ),
+    quantity(
+        "HKQuantityTypeIdentifierForcedExpiratoryVolume1",
+        "L",
+        "8.0",
+        "Forced Expiratory Volume, 1 sec",
+    ),
+    quantity(
+        "HKQuantityTypeIdentifierForcedVitalCapacity",
+        "L",
+        "8.0",
+        "Forced Vital Capacity",
+    ),
+    quantity(
+        "HKQuantityTypeIdentifierHeadphoneAudioExposure",
+        "dBASPL",
+        "13.0",
+        "Headphone Audio Levels",
+    ),
+    quantity(
+        "HKQuantityTypeIdentifierHeartRate",
+        "count/min",
+        "8.0",
+        "Heart Rate",
+    ),
+    quantity(
+        "HKQuantityTypeIdentifierHeartRateRecoveryOneMinute",
+        "count/min",
+        "16.0",
+        "Cardio Recovery",
+    ),
+    quantity(
+        "HKQuantityTypeIdentifierHeartRateVariabilitySDNN",
+        "ms",
+        "11.0",
+        "Heart Rate Variability",
+    ),
+    quantity("HKQuantityTypeIdentifierHeight", "m", "8.0", "Height"),
+    quantity(
+        "HKQuantityTypeIdentifierInhalerUsage",
+        "count",
+        "8.0",
+        "Inhaler Usage",
+    ),
+    quantity(
+        "HKQuantityTypeIdentifierInsulinDelivery",
+        "IU",
+        "11.0",
+        "Insulin Delivery",
+    ),
+    quantity(
+        "HKQuantityTypeIdentifierLeanBodyMass",
+        "kg",
+        "8.0",
+        "Lean Body Mass",
+    ),
+    quantity(
+        "HKQuantityTypeIdentifierNikeFuel",
+        "count",
+        "8.0",
+        "NikeFuel",
+    ),
+    quantity(
+        "HKQuantityTypeIdentifierNumberOfAlcoholicBeverages",
+        "count",
+        "15.0",
+        "Alcoholic Beverages",
+    ),
+    quantity(
+        "HKQuantityTypeIdentifierNumberOfTimesFallen",
+        "count",
+        "8.0",
+        "Number of Times Fallen",
+    ),
+    quantity(
+        "HKQuantityTypeIdentifierOxygenSaturation",
+        "%",
+        "8.0",
+        "Blood Oxygen",
+    ),
+    quantity(
+        "HKQuantityTypeIdentifierPaddleSportsSpeed",
+        "m/s",
+        "18.0",
+        "Paddle Sports Speed",
+    ),
+    quantity(
+        "HKQuantityTypeIdentifierPeakExpiratoryFlowRate",
+        "L/min",
+        "8.0",
+        "Peak Expiratory Flow Rate",
+    ),
+    quantity(
+        "HKQuantityTypeIdentifierPeripheralPerfusionIndex",
+        "%",
+        "8.0",
+        "Peripheral Perfusion Index",
+    ),
+    quantity(
+        "HKQuantityTypeIdentifierPhysicalEffort",
+        "kcal/hr·kg",
+        "17.0",
+        "Physical Effort",
+    ),
+    quantity(
+        "HKQuantityTypeIdentifierPushCount",
+        "count",
+        "10.0",
+        "Pushes",
+    ),
+    quantity(
+        "HKQuantityTypeIdentifierRespiratoryRate",
+        "count/min",
+        "8.0",
+        "Respiratory Rate",
+    ),
+    quantity(
+        "HKQuantityTypeIdentifierRestingHeartRate",
+        "count/min",
+        "11.0",
+        "Resting Heart Rate",
+    ),
+    quantity(
+        "HKQuantityTypeIdentifierRowingSpeed",
+        "m/s",
+        "18.0",
+        "Rowing Speed",
+    ),
+    quantity(
+        "HKQuantityTypeIdentifierRunningGroundContactTime",
+        "ms",
+        "16.0",
+        "Running Ground Contact Time",
+    ),
+    quantity(
+        "HKQuantityTypeIdentifierRunningPower",
+        "W",
+        "16.0",
+        "Running Power",
+    ),
+    quantity(
+        "HKQuantityTypeIdentifierRunningSpeed",
+        "m/s",
+        "16.0",
+        "Running Speed",
+    ),
+    quantity(
+        "HKQuantityTypeIdentifierRunningStrideLength",
+        "m",
+        "16.0",
+        "Running Stride Length",
+    ),
+    quantity(
+        "HKQuantityTypeIdentifierRunningVerticalOscillation",
+        "cm",
+        "16.0",
+        "Vertical Oscillation",
+    ),
+    quantity(
+        "HKQuantityTypeIdentifierSixMinuteWalkTestDistance",
+        "m",
+        "14.0",
+        "Six-Minute Walk",
+    ),
+    quantity(
+        "HKQuantityTypeIdentifierStairAscentSpeed",
+        "m/s",
+        "14.0",
+        "Stair Speed: Up",
+    ),
+    quantity(
+        "HKQuantityTypeIdentifierStairDescentSpeed",
+        "m/s",
+        "14.0",
+        "Stair Speed: Down",
+    ),
+    quantity("HKQuantityTypeIdentifierStepCount", "count", "8.0", "Steps"),
+    quantity(
+        "HKQuantityTypeIdentifierSwimmingStrokeCount",
+        "count",
+        "10.0",
+        "Swimming Strokes",
+    ),
+    quantity(
+        "HKQuantityTypeIdentifierTimeInDaylight",
+        "min",
+        "17.0",
+        "Time in Daylight",
+    ),
+    quantity(
+        "HKQuantityTypeIdentifierUnderwaterDepth",
+        "m",
+        "16.0",
+        "Underwater Depth",
+    ),
+    quantity(
+        "HKQuantityTypeIdentifierUVExposure",
+        "count",
+        "9.0",
+        "UV Index",
+    ),
+    quantity(
+        "HKQuantityTypeIdentifierVO2Max",
+        "mL/min·kg",
+        "11.0",
+        "Cardio Fitness",
+    ),
+    quantity(
+        "HKQuantityTypeIdentifierWaistCircumference",
+        "m",
+        "11.0",
+        "Waist Circumference",
+    ),
+    quantity(
+        "HKQuantityTypeIdentifierWalkingAsymmetryPercentage",
+        "%",
+        "14.0",
+        "Walking Asymmetry",
+    ),
+    quantity(
+        "HKQuantityTypeIdentifierWalkingDoubleSupportPercentage",
+        "%",
+        "14.0",
+        "Double Support Time",
+    ),
+    quantity(
+        "HKQuantityTypeIdentifierWalkingHeartRateAverage",
+        "count/min",
+        "11.0",
+        "Walking Heart Rate Average",
+    ),
+    quantity(
+        "HKQuantityTypeIdentifierWalkingSpeed",
+        "m/s",
+        "14.0",
+        "Walking Speed",
+    ),
+    quantity(
+        "HKQuantityTypeIdentifierWalkingStepLength",
+        "m",
+        "14.0",
+        "Walking Step Length",
+    ),
+    quantity(
+        "HKQuantityTypeIdentifierWaterTemperature",
+        "degC",
+        "16.0",
+        "Water Temperature",
+    ),
+    quantity(
+        "HKQuantityTypeIdentifierWorkoutEffortScore",
+        "appleEffortScore",
+        "18.0",
+        "Workout Effort",
+    ),
+    category(
+        "HKCategoryTypeIdentifierAbdominalCramps",
+        "13.6",
+        "Abdominal Cramps",
+    ),
+    category("HKCategoryTypeIdentifierAcne", "13.6", "Acne"),
+    category(
+        "HKCategoryTypeIdentifierAppetiteChanges",
+        "13.6",
+        "Appetite Changes",
+    ),
+    category(
+        "HKCategoryTypeIdentifierAppleStandHour",
+        "9.0",
+        "Stand Hours",
+    ),
+    category(
+        "HKCategoryTypeIdentifierAppleWalkingSteadinessEvent",
+        "15.0",
+        "Walking Steadiness Notification",
+    ),
+    category(
+        "HKCategoryTypeIdentifierAudioExposureEvent",
+        "13.0",
+        "Loud Environment Notification",
+    ),
+    category(
+        "HKCategoryTypeIdentifierBladderIncontinence",
+        "14.0",
+        "Bladder Incontinence",
+    ),
+    category("HKCategoryTypeIdentifierBloating", "13.6", "Bloating"),
+    category("HKCategoryTypeIdentifierBreastPain", "13.6", "Breast Pain"),
+    category(
+        "HKCategoryTypeIdentifierCervicalMucusQuality",
+        "9.0",
+        "Cervical Mucus Quality",
+    ),
+    category(
+        "HKCategoryTypeIdentifierChestTightnessOrPain",
+        "13.6",
+        "Chest Tightness or Pain",
+    ),
+    category("HKCategoryTypeIdentifierChills", "13.6", "Chills"),
+    category(
+        "HKCategoryTypeIdentifierConstipation",
+        "13.6",
+        "Constipation",
+    ),
+    category(
+        "HKCategoryTypeIdentifierContraceptive",
+        "14.3",
+        "Contraceptives",
+    ),
+    category("HKCategoryTypeIdentifierCoughing", "13.6", "Coughing"),
+    category("HKCategoryTypeIdentifierDiarrhea", "13.6", "Diarrhea"),
+    category("HKCategoryTypeIdentifierDizziness", "13.6", "Dizziness"),
+    category("HKCategoryTypeIdentifierDrySkin", "14.0", "Dry Skin"),
+    category(
+        "HKCategoryTypeIdentifierEnvironmentalAudioExposureEvent",
+        "14.0",
+        "Environmental Noise Notification",
+    ),
+    category("HKCategoryTypeIdentifierFainting", "13.6", "Fainting"),
+    category("HKCategoryTypeIdentifierFatigue", "13.6", "Fatigue"),
+    category("HKCategoryTypeIdentifierFever", "13.6", "Fever"),
+    category(
+        "HKCategoryTypeIdentifierGeneralizedBodyAche",
+        "13.6",
+        "Body and Muscle Ache",
+    ),
+    category("HKCategoryTypeIdentifierHairLoss", "14.0", "Hair Loss"),
+    category(
+        "HKCategoryTypeIdentifierHandwashingEvent",
+        "14.0",
+        "Handwashing",
+    ),
+    category("HKCategoryTypeIdentifierHeadache", "13.6", "Headache"),
+    category(
+        "HKCategoryTypeIdentifierHeadphoneAudioExposureEvent",
+        "14.2",
+        "Headphone Notification",
+    ),
+    category("HKCategoryTypeIdentifierHeartburn", "13.6", "Heartburn"),
+    category(
+        "HKCategoryTypeIdentifierHighHeartRateEvent",
+        "12.2",
+        "High Heart Rate Notification",
+    ),
+    category("HKCategoryTypeIdentifierHotFlashes", "13.6", "Hot Flashes"),
+    category(
+        "HKCategoryTypeIdentifierInfrequentMenstrualCycles",
+        "16.0",
+        "Infrequent Periods",
+    ),
+    category(
+        "HKCategoryTypeIdentifierIntermenstrualBleeding",
+        "9.0",
+        "Spotting",
+    ),
+    category(
+        "HKCategoryTypeIdentifierIrregularHeartRhythmEvent",
+        "12.2",
+        "Irregular Rhythm Notification",
+    ),
+    category(
+        "HKCategoryTypeIdentifierIrregularMenstrualCycles",
+        "16.0",
+        "Irregular Cycles",
+    ),
+    category("HKCategoryTypeIdentifierLactation", "14.3", "Lactation"),
+    category(
+        "HKCategoryTypeIdentifierLossOfSmell",
+        "13.6",
+        "Loss of Smell",
+    ),
+    category(
+        "HKCategoryTypeIdentifierLossOfTaste",
+        "13.6",
+        "Loss of Taste",
+    ),
+    category(
+        "HKCategoryTypeIdentifierLowCardioFitnessEvent",
+        "14.3",
+        "Low Cardio Fitness Notification",
+    ),
+    category(
+        "HKCategoryTypeIdentifierLowerBackPain",
+        "13.6",
+        "Lower Back Pain",
+    ),
+    category(
+        "HKCategoryTypeIdentifierLowHeartRateEvent",
+        "12.2",
+        "Low Heart Rate Notification",
+    ),
+    category(
+        "HKCategoryTypeIdentifierMemoryLapse",
+        "14.0",
+        "Memory Lapse",
+    ),
+    category(
+        "HKCategoryTypeIdentifierMenstrualFlow",
+        "9.0",
+        "Menstruation",
+    ),
+    category(
+        "HKCategoryTypeIdentifierMindfulSession",
+        "10.0",
+        "Mindful Minutes",
+    ),
+    category(
+        "HKCategoryTypeIdentifierMoodChanges",
+        "13.6",
+        "Mood Changes",
+    ),
+    category("HKCategoryTypeIdentifierNausea", "13.6", "Nausea"),
+    category(
+        "HKCategoryTypeIdentifierNightSweats",
+        "14.0",
+        "Night Sweats",
+    ),
+    category(
+        "HKCategoryTypeIdentifierOvulationTestResult",
+        "9.0",
+        "Ovulation Test Result",
+    ),
+    category("HKCategoryTypeIdentifierPelvicPain", "13.6", "Pelvic Pain"),
+    category(
+        "HKCategoryTypeIdentifierPersistentIntermenstrualBleeding",
+        "16.0",
+        "Persistent Spotting",
+    ),
+    category("HKCategoryTypeIdentifierPregnancy", "14.3", "Pregnancy"),
+    category(
+        "HKCategoryTypeIdentifierPregnancyTestResult",
+        "15.0",
+        "Pregnancy Test Result",
+    ),
+    category(
+        "HKCategoryTypeIdentifierProgesteroneTestResult",
+        "15.0",
+        "Progesterone Test Result",
+    ),
+    category(
+        "HKCategoryTypeIdentifierProlongedMenstrualPeriods",
+        "16.0",
+        "Prolonged Periods",
+    ),
+    category(
+        "HKCategoryTypeIdentifierRapidPoundingOrFlutteringHeartbeat",
+        "13.6",
+        "Rapid, Pounding, or Fluttering Heartbeat",
+    ),
+    category("HKCategoryTypeIdentifierRunnyNose", "13.6", "Runny Nose"),
+    category(
+        "HKCategoryTypeIdentifierSexualActivity",
+        "9.0",
+        "Sexual Activity",
+    ),
+    category(
+        "HKCategoryTypeIdentifierShortnessOfBreath",
+        "13.6",
+        "Shortness of Breath",
+    ),
+    category(
+        "HKCategoryTypeIdentifierSinusCongestion",
+        "13.6",
+        "Sinus Congestion",
+    ),
+    category(
+        "HKCategoryTypeIdentifierSkippedHeartbeat",
+        "13.6",
+        "Skipped Heartbeat",
+    ),
+    category("HKCategoryTypeIdentifierSleepAnalysis", "8.0", "Sleep"),
+    category(
+        "HKCategoryTypeIdentifierSleepApneaEvent",
+        "18.0",
+        "Breathing Disturbances Notification",
+    ),
+    category(
+        "HKCategoryTypeIdentifierSleepChanges",
+        "13.6",
+        "Sleep Changes",
+    ),
+    category("HKCategoryTypeIdentifierSoreThroat", "13.6", "Sore Throat"),
+    category(
+        "HKCategoryTypeIdentifierToothbrushingEvent",
+        "13.0",
+        "Toothbrushing",
+    ),
+    category(
+        "HKCategoryTypeIdentifierVaginalDryness",
+        "14.0",
+        "Vaginal Dryness",
+    ),
+    category("HKCategoryTypeIdentifierVomiting", "13.6", "Vomiting"),
+    category("HKCategoryTypeIdentifierWheezing", "13.6", "Wheezing"),
+    correlation(
+        "HKCorrelationTypeIdentifierBloodPressure",
+        "8.0",
+        "Blood Pressure",
+    ),
+    correlation("HKCorrelationTypeIdentifierFood", "8.0", "Food"),
+];
+
+/// Returns the registry entry of a type identifier, if it is known
+pub fn lookup(identifier: &str) -> Option<&'static TypeInfo> {
+    TYPES.iter().find(|info| info.identifier == identifier)
+}
+
+/// Returns the readable name of a type identifier, falling back to the identifier without its
+/// HealthKit prefix for types missing from the registry
+pub fn friendly_name(identifier: &str) -> String {
+    match lookup(identifier) {
+        Some(info) => info.name.to_string(),
+        None => crate::schema::short_table_name(identifier).to_string(),
+    }
+}
+
+/// Renders the registry as a table of one line per type
+pub fn to_text() -> String {
+    let width = TYPES
+        .iter()
+        .map(|info| info.identifier.len())
+        .max()
+        .unwrap_or_default();
+    TYPES
+        .iter()
+        .map(|info| {
+            format!(
+                "{:width$}  {:11}  {:5}  {:16}  {}\n",
+                info.identifier,
+                info.kind.as_str(),
+                info.introduced,
+                info.unit.unwrap_or("-"),
+                info.name,
+                width = width
+            )
+        })
+        .collect()
+}
+
+/// Renders the registry as a Markdown table, for the documentation of the imported tables
+pub fn to_markdown() -> String {
+    let mut markdown = String::from(
+        "| Identifier | Kind | Unit | Introduced | Name |\n| --- | --- | --- | --- | --- |\n",
+    );
+    for info in TYPES {
+        markdown.push_str(&format!(
+            "| `{}` | {} | {} | iOS {} | {} |\n",
+            info.identifier,
+            info.kind.as_str(),
+            info.unit
+                .map_or(String::new(), |unit| format!("`{}`", unit)),
+            info.introduced,
+            info.name
+        ));
+    }
+    markdown
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kind_prefix(kind: TypeKind) -> &'static str {
+        match kind {
+            TypeKind::Quantity => "HKQuantityTypeIdentifier",
+            TypeKind::Category => "HKCategoryTypeIdentifier",
+            TypeKind::Correlation => "HKCorrelationTypeIdentifier",
+        }
+    }
+
+    #[test]
+    fn types_are_sorted_by_kind_then_identifier_without_duplicates() {
+        // Alphabetical as a reader would sort them, so UnderwaterDepth comes before UVExposure
+        let key = |info: &TypeInfo| (info.kind as u8, info.identifier.to_ascii_lowercase());
+        for pair in TYPES.windows(2) {
+            assert!(
+                key(&pair[0]) < key(&pair[1]),
+                "{} is listed before {}",
+                pair[0].identifier,
+                pair[1].identifier
+            );
+        }
+    }
+
+    #[test]
+    fn identifiers_match_their_kind_and_only_quantities_have_units() {
+        for info in TYPES {
+            assert!(
+                info.identifier.starts_with(kind_prefix(info.kind)),
+                "{} is not a {}",
+                info.identifier,
+                info.kind.as_str()
+            );
+            assert_eq!(
+                info.unit.is_some(),
+                info.kind == TypeKind::Quantity,
+                "{}",
+                info.identifier
+            );
+            assert!(!info.name.is_empty(), "{}", info.identifier);
+            assert!(
+                info.introduced
+                    .split('.')
+                    .all(|part| part.parse::<u8>().is_ok()),
+                "{} was introduced in {}",
+                info.identifier,
+                info.introduced
+            );
+        }
+    }
+
+    #[test]
+    fn known_types_are_looked_up_by_identifier() {
+        let info = lookup("HKQuantityTypeIdentifierHeartRate").unwrap();
+        assert_eq!(info.kind, TypeKind::Quantity);
+        assert_eq!(info.unit, Some("count/min"));
+        let info = lookup("HKCorrelationTypeIdentifierBloodPressure").unwrap();
+        assert_eq!(info.kind, TypeKind::Correlation);
+        assert!(lookup("HKQuantityTypeIdentifierNotYetInvented").is_none());
+        assert!(lookup("Workout").is_none());
+    }
+
+    #[test]
+    fn unknown_types_are_named_after_their_identifier_without_its_prefix() {
+        assert_eq!(friendly_name("HKQuantityTypeIdentifierBodyMass"), "Weight");
+        assert_eq!(
+            friendly_name("HKQuantityTypeIdentifierNotYetInvented"),
+            "NotYetInvented"
+        );
+    }
+
+    #[test]
+    fn the_registry_is_rendered_one_type_per_line() {
+        let text = to_text();
+        assert_eq!(text.lines().count(), TYPES.len());
+        assert!(text.lines().any(
+            |line| line.starts_with("HKQuantityTypeIdentifierHeartRate ")
+                && line.contains("count/min")
+        ));
+        let markdown = to_markdown();
+        assert_eq!(markdown.lines().count(), TYPES.len() + 2);
+        assert!(markdown
+            .contains("| `HKCorrelationTypeIdentifierFood` | correlation |  | iOS 8.0 | Food |"));
+        let json = serde_json::to_value(lookup("HKCategoryTypeIdentifierSleepAnalysis")).unwrap();
+        assert_eq!(json["kind"], "category");
+        assert_eq!(json["unit"], serde_json::Value::Null);
+    }
+}
@@ -0,0 +1,278 @@
+//! Renders the stored workout routes to images for quick visual inspection
+use crate::cipher;
+use crate::compress;
+use crate::sink::Backend;
+use crate::{ImageFormat, RenderRoutesArgs};
+use log::*;
+use sqlx::SqlitePool;
+use std::fmt::Write;
+use std::path::Path;
+use tiny_skia::{Color, Paint, PathBuilder, Pixmap, Stroke, Transform};
+
+/// Blank space around the routes, as a fraction of the image size
+const PADDING: f64 = 0.05;
+/// Opacity of each route in the heatmap, so overlapping routes render darker
+const HEATMAP_OPACITY: f32 = 0.15;
+/// Color of the route lines
+const ROUTE_COLOR: (u8, u8, u8) = (0xd9, 0x3a, 0x2b);
+
+/// A workout route as a list of (longitude, latitude) coordinates
+struct Route {
+    file_stem: String,
+    coordinates: Vec<(f64, f64)>,
+}
+
+/// Renders each workout route, and all routes overlaid as a heatmap, into the output directory
+pub async fn render_routes(args: &RenderRoutesArgs) -> anyhow::Result<()> {
+    if Backend::from_url(&args.db_url) != Backend::Sqlite {
+        anyhow::bail!("render-routes only supports SQLite databases");
+    }
+    let db = SqlitePool::connect_with(cipher::connect_options(&args.db_url)?).await?;
+    let routes = read_routes(&db).await?;
+    std::fs::create_dir_all(&args.out)?;
+    for route in &routes {
+        let path = args
+            .out
+            .join(format!("{}.{}", route.file_stem, args.format.extension()));
+        debug!("Rendering {}", path.display());
+        render(
+            std::slice::from_ref(route),
+            args.size,
+            1.0,
+            args.format,
+            &path,
+        )?;
+    }
+    if !routes.is_empty() {
+        let path = args
+            .out
+            .join(format!("heatmap.{}", args.format.extension()));
+        render(&routes, args.size, HEATMAP_OPACITY, args.format, &path)?;
+    }
+    info!("Rendered {} routes to {}", routes.len(), args.out.display());
+    Ok(())
+}
+
+/// The rowid, activity type, date, and geometry of a workout with a route
+type RouteRow = (i64, Option<String>, Option<String>, Vec<u8>);
+
+/// Reads the GeoJSON LineString geometry of every workout with a route
+async fn read_routes(db: &SqlitePool) -> anyhow::Result<Vec<Route>> {
+    // Read as bytes, as the geometry is a BLOB when it was compressed with --compress-json
+    let rows: Vec<RouteRow> = sqlx::query_as(
+        "SELECT rowid, workoutActivityType, date(startDate), geometry FROM Workout WHERE geometry IS NOT NULL ORDER BY startDate",
+    )
+    .fetch_all(db)
+    .await?;
+    let mut routes = Vec::new();
+    for (rowid, activity_type, date, geometry) in rows {
+        let geometry: serde_json::Value = serde_json::from_slice(&compress::json_text(&geometry)?)?;
+        let coordinates: Vec<(f64, f64)> = geometry["coordinates"]
+            .as_array()
+            .map(|coordinates| {
+                coordinates
+                    .iter()
+                    .filter_map(|c| Some((c[0].as_f64()?, c[1].as_f64()?)))
+                    .collect()
+            })
+            .unwrap_or_default();
+        if coordinates.len() < 2 {
+            continue;
+        }
+        let activity_type = activity_type.unwrap_or_default();
+        routes.push(Route {
+            file_stem: format!(
+                "{}-{}-{}",
+                date.unwrap_or_default(),
+                activity_type.trim_start_matches("HKWorkoutActivityType"),
+                rowid
+            ),
+            coordinates,
+        });
+    }
+    Ok(routes)
+}
+
+/// Maps coordinates to pixels with a Web Mercator projection fit to the routes
+struct Projection {
+    min: (f64, f64),
+    scale: f64,
+    offset: (f64, f64),
+    size: f64,
+}
+
+impl Projection {
+    fn fit(routes: &[Route], size: u32) -> Self {
+        let mut min = (f64::MAX, f64::MAX);
+        let mut max = (f64::MIN, f64::MIN);
+        for &(lon, lat) in routes.iter().flat_map(|r| &r.coordinates) {
+            let (x, y) = mercator(lon, lat);
+            min = (min.0.min(x), min.1.min(y));
+            max = (max.0.max(x), max.1.max(y));
+        }
+        let size = size as f64;
+        let drawable = size * (1.0 - 2.0 * PADDING);
+        let extent = (max.0 - min.0).max(max.1 - min.1).max(f64::EPSILON);
+        let scale = drawable / extent;
+        // Center the routes along the shorter axis
+        let offset = (
+            (size - (max.0 - min.0) * scale) / 2.0,
+            (size - (max.1 - min.1) * scale) / 2.0,
+        );
+        Self {
+            min,
+            scale,
+            offset,
+            size,
+        }
+    }
+
+    fn project(&self, lon: f64, lat: f64) -> (f32, f32) {
+        let (x, y) = mercator(lon, lat);
+        let px = self.offset.0 + (x - self.min.0) * self.scale;
+        // Image rows grow downwards while latitude grows northwards
+        let py = self.size - (self.offset.1 + (y - self.min.1) * self.scale);
+        (px as f32, py as f32)
+    }
+}
+
+fn mercator(lon: f64, lat: f64) -> (f64, f64) {
+    let lat = lat.clamp(-85.0, 85.0).to_radians();
+    (
+        lon.to_radians(),
+        (std::f64::consts::FRAC_PI_4 + lat / 2.0).tan().ln(),
+    )
+}
+
+fn render(
+    routes: &[Route],
+    size: u32,
+    opacity: f32,
+    format: ImageFormat,
+    path: &Path,
+) -> anyhow::Result<()> {
+    let projection = Projection::fit(routes, size);
+    let stroke_width = (size as f32 / 400.0).max(1.0);
+    match format {
+        ImageFormat::Svg => {
+            let mut svg = String::new();
+            writeln!(
+                svg,
+                r#"<svg xmlns="http://www.w3.org/2000/svg" width="{0}" height="{0}" viewBox="0 0 {0} {0}">"#,
+                size
+            )?;
+            writeln!(svg, r#"<rect width="100%" height="100%" fill="white"/>"#)?;
+            for route in routes {
+                let points = route
+                    .coordinates
+                    .iter()
+                    .map(|&(lon, lat)| {
+                        let (x, y) = projection.project(lon, lat);
+                        format!("{:.1},{:.1}", x, y)
+                    })
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                writeln!(
+                    svg,
+                    r##"<polyline points="{}" fill="none" stroke="#{:02x}{:02x}{:02x}" stroke-opacity="{}" stroke-width="{}" stroke-linejoin="round" stroke-linecap="round"/>"##,
+                    points, ROUTE_COLOR.0, ROUTE_COLOR.1, ROUTE_COLOR.2, opacity, stroke_width
+                )?;
+            }
+            writeln!(svg, "</svg>")?;
+            std::fs::write(path, svg)?;
+        }
+        ImageFormat::Png => {
+            let mut pixmap = match Pixmap::new(size, size) {
+                Some(pixmap) => pixmap,
+                None => anyhow::bail!("Invalid image size {}", size),
+            };
+            pixmap.fill(Color::WHITE);
+            let mut paint = Paint::default();
+            paint.set_color_rgba8(
+                ROUTE_COLOR.0,
+                ROUTE_COLOR.1,
+                ROUTE_COLOR.2,
+                (opacity * 255.0) as u8,
+            );
+            paint.anti_alias = true;
+            let stroke = Stroke {
+                width: stroke_width,
+                ..Default::default()
+            };
+            for route in routes {
+                let mut builder = PathBuilder::new();
+                for (i, &(lon, lat)) in route.coordinates.iter().enumerate() {
+                    let (x, y) = projection.project(lon, lat);
+                    if i == 0 {
+                        builder.move_to(x, y);
+                    } else {
+                        builder.line_to(x, y);
+                    }
+                }
+                if let Some(line) = builder.finish() {
+                    pixmap.stroke_path(&line, &paint, &stroke, Transform::identity(), None);
+                }
+            }
+            pixmap.save_png(path)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conformance::EXPORT_FILES;
+    use crate::options::ImportOptions;
+    use crate::testing::{self, TestDir};
+
+    #[test]
+    fn routes_are_fit_within_the_padding() {
+        let routes = [Route {
+            file_stem: "route".to_string(),
+            // Twice as wide as it is tall near the equator
+            coordinates: vec![(0.0, 0.0), (0.02, 0.01)],
+        }];
+        let projection = Projection::fit(&routes, 1000);
+        let (x0, y0) = projection.project(0.0, 0.0);
+        let (x1, y1) = projection.project(0.02, 0.01);
+        assert!((x0 - 50.0).abs() < 0.01 && (x1 - 950.0).abs() < 0.01);
+        // Centered vertically, with north at the top
+        assert!((y0 - 725.0).abs() < 0.1 && (y1 - 275.0).abs() < 0.1);
+    }
+
+    #[tokio::test]
+    async fn each_route_and_the_heatmap_are_rendered() {
+        let dir = TestDir::new("render-routes");
+        let export_dir = dir.write_export("export", EXPORT_FILES);
+        let db_url = dir.db_url("health.db");
+        testing::import(&export_dir, &db_url, &ImportOptions::defaults())
+            .await
+            .unwrap();
+        for format in [ImageFormat::Svg, ImageFormat::Png] {
+            let out = dir.path().join(format.extension());
+            render_routes(&RenderRoutesArgs {
+                db_url: db_url.clone(),
+                out: out.clone(),
+                format,
+                size: 64,
+            })
+            .await
+            .unwrap();
+            let mut files: Vec<String> = std::fs::read_dir(&out)
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+                .collect();
+            files.sort();
+            assert_eq!(
+                files,
+                [
+                    format!("2024-01-01-Running-1.{}", format.extension()),
+                    format!("heatmap.{}", format.extension())
+                ]
+            );
+        }
+        let svg = std::fs::read_to_string(dir.path().join("svg/heatmap.svg")).unwrap();
+        assert!(svg.contains(r#"stroke-opacity="0.15""#), "{}", svg);
+    }
+}
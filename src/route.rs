@@ -0,0 +1,524 @@
+//! Parsing workout route GPX files and applying the configured privacy zones
+use crate::blob::{Blob, GPX_MIME_TYPE};
+use crate::config::{PrivacyConfig, PrivacyZone, ZoneAction};
+use crate::core::{DatabaseRow, DatabaseValue, HKTables, WORKOUT_ID_COLUMN};
+use crate::source::{ExportArchive, ExportSource};
+use log::*;
+use quick_xml::events::Event;
+use serde::{Deserialize, Serialize};
+use sqlx::types::JsonValue;
+use std::io::{BufRead, BufReader, Read};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread::JoinHandle;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+use tokio::sync::oneshot;
+
+/// Name of the table holding one row per track point when --route-points is set
+pub const ROUTE_POINT_TABLE_NAME: &str = "WorkoutRoutePoint";
+
+/// Workout column holding the per-point times and Apple GPX extensions of the route
+pub const ROUTE_PROPERTIES_COLUMN: &str = "routeProperties";
+
+/// Mean radius of the Earth in meters
+const EARTH_RADIUS_METERS: f64 = 6_371_008.8;
+
+/// A single track point of a workout route
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RoutePoint {
+    pub lon: f64,
+    pub lat: f64,
+    /// Elevation in meters
+    pub ele: Option<f64>,
+    pub time: Option<OffsetDateTime>,
+    /// Speed in meters per second
+    pub speed: Option<f64>,
+    /// Direction of travel in degrees from true north
+    pub course: Option<f64>,
+    /// Horizontal accuracy in meters
+    pub h_acc: Option<f64>,
+    /// Vertical accuracy in meters
+    pub v_acc: Option<f64>,
+}
+
+impl RoutePoint {
+    /// Converts the point to a row of the WorkoutRoutePoint table
+    pub fn to_row(&self) -> DatabaseRow {
+        let mut row = vec![
+            ("lon".to_string(), DatabaseValue::Real(self.lon)),
+            ("lat".to_string(), DatabaseValue::Real(self.lat)),
+        ];
+        let optional_reals = [
+            ("ele", self.ele),
+            ("speed", self.speed),
+            ("course", self.course),
+            ("hAcc", self.h_acc),
+            ("vAcc", self.v_acc),
+        ];
+        for (name, value) in optional_reals {
+            if let Some(value) = value {
+                row.push((name.to_string(), DatabaseValue::Real(value)));
+            }
+        }
+        if let Some(time) = self.time {
+            row.push(("time".to_string(), DatabaseValue::OffsetDateTime(time)));
+        }
+        row
+    }
+}
+
+/// Adds the WorkoutRoutePoint table to the inferred tables
+pub fn extend_tables(tables: &mut HKTables) {
+    let columns = [
+        (WORKOUT_ID_COLUMN, "INTEGER"),
+        ("lat", "REAL"),
+        ("lon", "REAL"),
+        ("ele", "REAL"),
+        ("time", "DATE"),
+        ("speed", "REAL"),
+        ("course", "REAL"),
+        ("hAcc", "REAL"),
+        ("vAcc", "REAL"),
+    ];
+    tables.insert(
+        ROUTE_POINT_TABLE_NAME.to_string(),
+        columns
+            .iter()
+            .map(|(name, ty)| (name.to_string(), *ty))
+            .collect(),
+    );
+}
+
+/// The track points of a route file, with the file itself when it is kept for the `_blobs` table
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Route {
+    pub points: Vec<RoutePoint>,
+    pub gpx: Option<Blob>,
+}
+
+/// Most threads reading route files. Each thread opens its own handle on the export, which
+/// reads the central directory of a zip archive again, and a few threads keep up with the pass
+/// over export.xml.
+const MAX_ROUTE_READERS: usize = 4;
+
+/// The route read from a route file, or the error reading it
+pub type RouteResult = anyhow::Result<Route>;
+type RouteJob = (String, oneshot::Sender<RouteResult>);
+
+/// Reads route files on a pool of threads, concurrently with the pass over export.xml.
+/// Each thread has its own handle to the export, as zip archives can't be shared.
+pub struct RouteReaderPool {
+    jobs: Option<mpsc::Sender<RouteJob>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl RouteReaderPool {
+    /// Creates a pool whose readers keep the route files as blobs if keep_gpx is set
+    pub fn new(source: &ExportSource, keep_gpx: bool) -> anyhow::Result<Self> {
+        let threads = std::thread::available_parallelism()
+            .map_or(MAX_ROUTE_READERS, |n| n.get().min(MAX_ROUTE_READERS));
+        Self::with_threads(source, keep_gpx, threads)
+    }
+
+    /// Creates a pool with the given number of reader threads
+    fn with_threads(source: &ExportSource, keep_gpx: bool, threads: usize) -> anyhow::Result<Self> {
+        let (jobs, receiver) = mpsc::channel::<RouteJob>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let mut workers = Vec::with_capacity(threads);
+        for _ in 0..threads {
+            let mut archive = source.open()?;
+            let receiver = receiver.clone();
+            workers.push(std::thread::spawn(move || loop {
+                // The lock is released before the route is read
+                let job = receiver.lock().expect("route job lock poisoned").recv();
+                match job {
+                    Ok((path, reply)) => {
+                        // The receiver is gone if the import already failed
+                        let _ = reply.send(read_route_file(&mut archive, &path, keep_gpx));
+                    }
+                    Err(_) => break,
+                }
+            }));
+        }
+        Ok(Self {
+            jobs: Some(jobs),
+            workers,
+        })
+    }
+
+    /// Returns a pool without any readers, for imports that skip the workouts
+    pub fn disabled() -> Self {
+        Self {
+            jobs: None,
+            workers: Vec::new(),
+        }
+    }
+
+    /// Queues the route file at the path for reading, returning a receiver for its points
+    pub fn read(&self, path: String) -> oneshot::Receiver<RouteResult> {
+        let (reply, receiver) = oneshot::channel();
+        if let Some(jobs) = &self.jobs {
+            // If the workers have stopped, the reply is dropped and the receiver reports it
+            let _ = jobs.send((path, reply));
+        }
+        receiver
+    }
+}
+
+impl Drop for RouteReaderPool {
+    fn drop(&mut self) {
+        // Closing the channel stops the workers once the queued routes are read
+        self.jobs.take();
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+/// Reads the route file at a path relative to the export directory, e.g. /workout-routes/route.gpx
+fn read_route_file(archive: &mut ExportArchive, path: &str, keep_gpx: bool) -> RouteResult {
+    debug!("Reading route gpx file: {}", path);
+    let (mut route_gpx, size) = archive.open_file(path)?;
+    if keep_gpx {
+        let mut bytes = Vec::with_capacity(size as usize);
+        route_gpx.read_to_end(&mut bytes)?;
+        let points = read_gpx(bytes.as_slice())?;
+        Ok(Route {
+            points,
+            gpx: Some(Blob::new(GPX_MIME_TYPE, bytes)),
+        })
+    } else {
+        let points = read_gpx(BufReader::with_capacity(size as usize, route_gpx))?;
+        Ok(Route { points, gpx: None })
+    }
+}
+
+/// Reads the track points of a GPX file
+pub fn read_gpx<R: BufRead>(reader: R) -> anyhow::Result<Vec<RoutePoint>> {
+    let mut route_xml = quick_xml::Reader::from_reader(reader);
+    route_xml.trim_text(true);
+    let mut points = Vec::new();
+    let mut buf = Vec::new();
+    // The trkpt being read and the name of its child element holding the current text
+    let mut point: Option<RoutePoint> = None;
+    let mut field = Vec::new();
+    loop {
+        match route_xml.read_event_into(&mut buf)? {
+            Event::Start(element) => {
+                if b"trkpt" == element.name().as_ref() {
+                    let mut lat = Default::default();
+                    let mut lon = Default::default();
+                    for attribute in element.attributes() {
+                        let attribute = attribute?;
+                        if b"lat" == attribute.key.as_ref() {
+                            lat = attribute.decode_and_unescape_value(&route_xml)?;
+                        } else if b"lon" == attribute.key.as_ref() {
+                            lon = attribute.decode_and_unescape_value(&route_xml)?;
+                        }
+                    }
+                    point = Some(RoutePoint {
+                        lon: lon.parse()?,
+                        lat: lat.parse()?,
+                        ..Default::default()
+                    });
+                } else {
+                    field = element.name().as_ref().to_vec();
+                }
+            }
+            Event::Text(text) => {
+                if let Some(point) = &mut point {
+                    let text = text.unescape()?;
+                    match field.as_slice() {
+                        b"ele" => point.ele = text.parse().ok(),
+                        b"time" => point.time = OffsetDateTime::parse(&text, &Rfc3339).ok(),
+                        b"speed" => point.speed = text.parse().ok(),
+                        b"course" => point.course = text.parse().ok(),
+                        b"hAcc" => point.h_acc = text.parse().ok(),
+                        b"vAcc" => point.v_acc = text.parse().ok(),
+                        _ => {}
+                    }
+                }
+            }
+            Event::End(element) => {
+                if b"trkpt" == element.name().as_ref() {
+                    points.extend(point.take());
+                }
+                field.clear();
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+        buf.clear();
+    }
+    Ok(points)
+}
+
+/// Converts the route to a GeoJSON LineString
+pub fn geometry(points: &[RoutePoint]) -> JsonValue {
+    let coordinates = points
+        .iter()
+        .map(|p| {
+            let mut position = vec![p.lon.into(), p.lat.into()];
+            // GeoJSON positions carry the elevation as an optional third element
+            if let Some(ele) = p.ele {
+                position.push(ele.into());
+            }
+            JsonValue::Array(position)
+        })
+        .collect();
+    serde_json::json!({
+        "type": "LineString",
+        "coordinates": JsonValue::Array(coordinates),
+    })
+}
+
+/// Builds arrays of the point times and extensions, aligned with the coordinates of the
+/// geometry. The times are named coordTimes, as in the GeoJSON written by togeojson.
+pub fn properties(points: &[RoutePoint]) -> anyhow::Result<JsonValue> {
+    let times = points
+        .iter()
+        .map(|p| p.time.map(|time| time.format(&Rfc3339)).transpose())
+        .collect::<Result<Vec<_>, _>>()?;
+    let reals = |value: fn(&RoutePoint) -> Option<f64>| -> Vec<Option<f64>> {
+        points.iter().map(value).collect()
+    };
+    Ok(serde_json::json!({
+        "coordTimes": times,
+        "speed": reals(|p| p.speed),
+        "course": reals(|p| p.course),
+        "hAcc": reals(|p| p.h_acc),
+        "vAcc": reals(|p| p.v_acc),
+    }))
+}
+
+/// Removes the points inside the configured privacy zones
+pub fn apply_privacy_zones(points: Vec<RoutePoint>, privacy: &PrivacyConfig) -> Vec<RoutePoint> {
+    if privacy.zones.is_empty() {
+        return points;
+    }
+    let inside = |p: &RoutePoint| privacy.zones.iter().any(|zone| zone_contains(zone, p));
+    match privacy.action {
+        ZoneAction::Drop => points.into_iter().filter(|p| !inside(p)).collect(),
+        ZoneAction::Truncate => {
+            let first = points.iter().position(|p| !inside(p));
+            let last = points.iter().rposition(|p| !inside(p));
+            match (first, last) {
+                (Some(first), Some(last)) => points[first..=last].to_vec(),
+                _ => Vec::new(),
+            }
+        }
+    }
+}
+
+fn zone_contains(zone: &PrivacyZone, point: &RoutePoint) -> bool {
+    match zone {
+        PrivacyZone::Circle {
+            latitude,
+            longitude,
+            radius_meters,
+        } => haversine_distance(*latitude, *longitude, point.lat, point.lon) <= *radius_meters,
+        PrivacyZone::Polygon { coordinates } => {
+            // Ray casting, treating longitude and latitude as planar coordinates
+            let mut inside = false;
+            let mut j = coordinates.len().wrapping_sub(1);
+            for i in 0..coordinates.len() {
+                let [xi, yi] = coordinates[i];
+                let [xj, yj] = coordinates[j];
+                if (yi > point.lat) != (yj > point.lat)
+                    && point.lon < (xj - xi) * (point.lat - yi) / (yj - yi) + xi
+                {
+                    inside = !inside;
+                }
+                j = i;
+            }
+            inside
+        }
+    }
+}
+
+/// Moves every point of the route by the same random offset of up to max_meters, hiding where
+/// the route is while keeping its shape, distances, and elevations
+pub fn fuzz_route(points: &mut [RoutePoint], max_meters: f64) {
+    let first = match points.first() {
+        Some(first) => first,
+        None => return,
+    };
+    // Uniformly distributed over the disk around the route's true position
+    let distance = max_meters * rand::random::<f64>().sqrt();
+    let bearing = rand::random::<f64>() * std::f64::consts::TAU;
+    let d_lat = (distance * bearing.cos() / EARTH_RADIUS_METERS).to_degrees();
+    let d_lon = (distance * bearing.sin() / (EARTH_RADIUS_METERS * first.lat.to_radians().cos()))
+        .to_degrees();
+    for point in points {
+        point.lat += d_lat;
+        point.lon += d_lon;
+    }
+}
+
+/// Great-circle distance between two coordinates in meters
+pub fn haversine_distance(lat1: f64, lon1: f64, lat2: f64, lon2: f64) -> f64 {
+    let (lat1, lat2) = (lat1.to_radians(), lat2.to_radians());
+    let d_lat = lat2 - lat1;
+    let d_lon = (lon2 - lon1).to_radians();
+    let a = (d_lat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (d_lon / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS_METERS * a.sqrt().asin()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestDir;
+
+    /// Returns a GPX file with a single track point at the coordinate
+    fn gpx(lon: f64, lat: f64) -> String {
+        format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<gpx version="1.1" creator="Apple Health Export" xmlns="http://www.topografix.com/GPX/1/1">
+  <trk><trkseg><trkpt lon="{}" lat="{}"><ele>10.0</ele><time>2024-01-01T17:00:45Z</time></trkpt></trkseg></trk>
+</gpx>"#,
+            lon, lat
+        )
+    }
+
+    #[test]
+    fn elevations_times_and_extensions_are_read_with_each_point() {
+        let gpx = br#"<gpx><trk><trkseg>
+            <trkpt lon="-118.232524" lat="34.045088"><ele>85.651855</ele><time>2024-01-01T17:00:45Z</time><extensions><speed>1.154731</speed><course>169.804688</course><hAcc>2.270945</hAcc><vAcc>1.579882</vAcc></extensions></trkpt>
+            <trkpt lon="-118.232527" lat="34.045077"></trkpt>
+        </trkseg></trk></gpx>"#;
+        let points = read_gpx(gpx.as_slice()).unwrap();
+        assert_eq!(points.len(), 2);
+        assert_eq!(
+            geometry(&points),
+            serde_json::json!({
+                "type": "LineString",
+                // Points without an elevation have only two coordinates
+                "coordinates": [[-118.232524, 34.045088, 85.651855], [-118.232527, 34.045077]],
+            })
+        );
+        assert_eq!(
+            properties(&points).unwrap(),
+            serde_json::json!({
+                "coordTimes": ["2024-01-01T17:00:45Z", null],
+                "speed": [1.154731, null],
+                "course": [169.804688, null],
+                "hAcc": [2.270945, null],
+                "vAcc": [1.579882, null],
+            })
+        );
+        let columns: Vec<String> = points[1]
+            .to_row()
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect();
+        assert_eq!(columns, ["lon", "lat"]);
+    }
+
+    /// Returns the longitudes of points along the equator
+    fn longitudes(points: &[RoutePoint]) -> Vec<f64> {
+        points.iter().map(|p| p.lon).collect()
+    }
+
+    /// A route along the equator that starts inside a circle and passes through a square
+    fn privacy(action: ZoneAction) -> (Vec<RoutePoint>, PrivacyConfig) {
+        let points = [0.0, 0.001, 0.01, 0.02, 0.03]
+            .into_iter()
+            .map(|lon| RoutePoint {
+                lon,
+                ..Default::default()
+            })
+            .collect();
+        let zones = vec![
+            // About 200 m, so the first two points, 111 m apart, are inside
+            PrivacyZone::Circle {
+                latitude: 0.0,
+                longitude: 0.0,
+                radius_meters: 200.0,
+            },
+            PrivacyZone::Polygon {
+                coordinates: vec![
+                    [0.019, -0.001],
+                    [0.021, -0.001],
+                    [0.021, 0.001],
+                    [0.019, 0.001],
+                ],
+            },
+        ];
+        (points, PrivacyConfig { zones, action })
+    }
+
+    #[test]
+    fn points_inside_privacy_zones_are_dropped() {
+        let (points, privacy) = privacy(ZoneAction::Drop);
+        assert_eq!(
+            longitudes(&apply_privacy_zones(points, &privacy)),
+            [0.01, 0.03]
+        );
+    }
+
+    #[test]
+    fn routes_are_truncated_to_the_points_outside_privacy_zones() {
+        let (points, privacy) = privacy(ZoneAction::Truncate);
+        // The point passing through the square is kept between points outside the zones
+        assert_eq!(
+            longitudes(&apply_privacy_zones(points.clone(), &privacy)),
+            [0.01, 0.02, 0.03]
+        );
+        assert!(apply_privacy_zones(points[..2].to_vec(), &privacy).is_empty());
+        let no_zones = PrivacyConfig::default();
+        assert_eq!(longitudes(&apply_privacy_zones(points, &no_zones)).len(), 5);
+    }
+
+    #[tokio::test]
+    async fn routes_are_read_for_their_workouts_by_several_threads() {
+        let dir = TestDir::new("route-reader-pool");
+        let files: Vec<(String, String)> = (0..16)
+            .map(|i| {
+                (
+                    format!("workout-routes/route_{}.gpx", i),
+                    gpx(i as f64, -(i as f64)),
+                )
+            })
+            .collect();
+        let files: Vec<(&str, &[u8])> = files
+            .iter()
+            .map(|(path, contents)| (path.as_str(), contents.as_bytes()))
+            .collect();
+        let export_dir = dir.write_export("export", &files);
+        let pool =
+            RouteReaderPool::with_threads(&ExportSource::Directory(export_dir), false, 4).unwrap();
+        assert_eq!(pool.workers.len(), 4);
+        // Queued before any is awaited, so the threads read them concurrently and out of order
+        let receivers: Vec<_> = (0..16)
+            .map(|i| pool.read(format!("/workout-routes/route_{}.gpx", i)))
+            .collect();
+        for (i, receiver) in receivers.into_iter().enumerate() {
+            let route = receiver.await.unwrap().unwrap();
+            assert_eq!(route.points.len(), 1);
+            assert_eq!(route.points[0].lon, i as f64);
+            assert_eq!(route.points[0].lat, -(i as f64));
+        }
+    }
+
+    #[tokio::test]
+    async fn missing_routes_fail_their_own_read() {
+        let dir = TestDir::new("route-reader-pool-missing");
+        let gpx = gpx(1.0, 2.0);
+        let export_dir =
+            dir.write_export("export", &[("workout-routes/route_1.gpx", gpx.as_bytes())]);
+        let pool =
+            RouteReaderPool::with_threads(&ExportSource::Directory(export_dir), false, 2).unwrap();
+        let missing = pool.read("/workout-routes/route_0.gpx".to_string());
+        let present = pool.read("/workout-routes/route_1.gpx".to_string());
+        assert!(missing.await.unwrap().is_err());
+        assert_eq!(present.await.unwrap().unwrap().points[0].lon, 1.0);
+    }
+
+    #[test]
+    fn readers_are_capped() {
+        let dir = TestDir::new("route-reader-pool-cap");
+        let export_dir = dir.write_export("export", &[("export.xml", b"<HealthData/>")]);
+        let pool = RouteReaderPool::new(&ExportSource::Directory(export_dir), false).unwrap();
+        assert!((1..=MAX_ROUTE_READERS).contains(&pool.workers.len()));
+    }
+}
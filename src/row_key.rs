@@ -0,0 +1,136 @@
+//! Deterministic keys identifying each sample, so the same sample is never inserted twice
+use crate::core::{DatabaseRow, DatabaseValue, HKTables};
+use sha2::{Digest, Sha256};
+
+/// Name of the column holding the key of each sample
+pub const ROW_KEY_COLUMN: &str = "row_key";
+
+/// Metadata key third-party apps use to identify their samples
+const EXTERNAL_UUID_COLUMN: &str = "metadata_HKExternalUUID";
+
+/// Attributes hashed into the key of a sample without an HKExternalUUID, in order
+const KEY_COLUMNS: &[&str] = &[
+    "type",
+    "workoutActivityType",
+    "startDate",
+    "endDate",
+    "value",
+    "unit",
+    "duration",
+    "sourceName",
+];
+
+/// Adds the row_key column to every table of samples, i.e. those with a startDate and sourceName
+pub fn extend_tables(tables: &mut HKTables) {
+    for columns in tables.values_mut() {
+        if columns.contains_key("startDate") && columns.contains_key("sourceName") {
+            columns.insert(ROW_KEY_COLUMN.to_string(), "TEXT");
+        }
+    }
+}
+
+/// Returns the hex SHA-256 of the table name and the HKExternalUUID of the row, or of its
+/// type, dates, value, and source when it has none
+pub fn row_key(table_name: &str, row: &DatabaseRow) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(table_name.as_bytes());
+    match row.iter().find(|(name, _)| name == EXTERNAL_UUID_COLUMN) {
+        Some((name, value)) => hash_column(&mut hasher, name, value),
+        None => {
+            for key_column in KEY_COLUMNS {
+                if let Some((name, value)) = row.iter().find(|(name, _)| name == key_column) {
+                    hash_column(&mut hasher, name, value);
+                }
+            }
+        }
+    }
+    format!("{:x}", hasher.finalize())
+}
+
+/// Hashes a column, separating it from its neighbours so "ab" + "c" differs from "a" + "bc"
+fn hash_column(hasher: &mut Sha256, name: &str, value: &DatabaseValue) {
+    hasher.update([0x1f]);
+    hasher.update(name.as_bytes());
+    hasher.update([0x1e]);
+    hasher.update(value.to_text().as_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(name: &str, value: &str) -> (String, DatabaseValue) {
+        (name.to_string(), DatabaseValue::Text(value.to_string()))
+    }
+
+    fn sample(source_name: &str) -> DatabaseRow {
+        vec![
+            text("type", "HKQuantityTypeIdentifierHeartRate"),
+            text("sourceName", source_name),
+            text("startDate", "2024-01-01 08:00:00 -0800"),
+            text("endDate", "2024-01-01 08:00:00 -0800"),
+            ("value".to_string(), DatabaseValue::Real(62.0)),
+            text("unit", "count/min"),
+        ]
+    }
+
+    #[test]
+    fn keys_are_deterministic() {
+        let key = row_key("HeartRate", &sample("Watch"));
+        assert_eq!(key, row_key("HeartRate", &sample("Watch")));
+        assert_eq!(key.len(), 64);
+        assert_ne!(key, row_key("HeartRate", &sample("Phone")));
+        assert_ne!(key, row_key("RestingHeartRate", &sample("Watch")));
+    }
+
+    #[test]
+    fn keys_without_an_external_uuid_ignore_other_columns() {
+        let mut row = sample("Watch");
+        row.push(text("creationDate", "2024-01-01 08:00:05 -0800"));
+        row.push(text("sourceVersion", "10.2"));
+        assert_eq!(
+            row_key("HeartRate", &row),
+            row_key("HeartRate", &sample("Watch"))
+        );
+    }
+
+    #[test]
+    fn keys_with_an_external_uuid_only_hash_it() {
+        let mut first = sample("Withings");
+        first.push(text(EXTERNAL_UUID_COLUMN, "2F1C"));
+        let mut second = sample("Withings Health Mate");
+        second.push(text(EXTERNAL_UUID_COLUMN, "2F1C"));
+        assert_eq!(row_key("HeartRate", &first), row_key("HeartRate", &second));
+        assert_ne!(
+            row_key("HeartRate", &first),
+            row_key("HeartRate", &sample("Withings"))
+        );
+    }
+
+    #[test]
+    fn columns_are_separated() {
+        let row = |type_name: &str, unit: &str| vec![text("type", type_name), text("unit", unit)];
+        assert_ne!(row_key("T", &row("ab", "c")), row_key("T", &row("a", "bc")));
+    }
+
+    #[test]
+    fn only_tables_of_samples_are_keyed() {
+        let mut tables = HKTables::new();
+        tables.insert(
+            "HeartRate".to_string(),
+            [("startDate", "DATE"), ("sourceName", "TEXT")]
+                .into_iter()
+                .map(|(name, ty)| (name.to_string(), ty))
+                .collect(),
+        );
+        tables.insert(
+            "ActivitySummary".to_string(),
+            [("dateComponents".to_string(), "DATE")]
+                .into_iter()
+                .collect(),
+        );
+        extend_tables(&mut tables);
+        assert!(tables["HeartRate"].contains_key(ROW_KEY_COLUMN));
+        assert!(!tables["ActivitySummary"].contains_key(ROW_KEY_COLUMN));
+    }
+}
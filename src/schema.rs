@@ -0,0 +1,239 @@
+//! Reconciling the inferred schema with the case-insensitive identifiers of SQL databases
+use crate::core::{DatabaseRow, HKTables, METADATA_COLUMN_PREFIX};
+use log::*;
+use std::collections::BTreeMap;
+
+/// A map of table names to a map of column names to the column they were merged into
+pub(crate) type ColumnAliases = BTreeMap<String, BTreeMap<String, String>>;
+
+/// Merges columns whose names differ only by letter case, e.g. metadata keys that changed case
+/// between app versions. SQL identifiers are case-insensitive, so both can't be created.
+///
+/// The columns are merged into the lexicographically smallest name, so the result only depends
+/// on the set of names in the export and not the order they appear in. The merged column has the
+/// narrowest type able to hold the values of every variant.
+pub(crate) fn merge_case_insensitive_columns(tables: &mut HKTables) -> ColumnAliases {
+    let mut aliases = ColumnAliases::new();
+    for (table_name, columns) in tables.iter_mut() {
+        let mut canonical: BTreeMap<String, String> = BTreeMap::new();
+        let mut merged: BTreeMap<String, &'static str> = BTreeMap::new();
+        // BTreeMap iteration is sorted, so the first name seen for each key is the smallest
+        for (column_name, ty) in std::mem::take(columns) {
+            let key = column_name.to_lowercase();
+            match canonical.get(&key) {
+                Some(canonical_name) => {
+                    warn!(
+                        "Merging column \"{}\" into \"{}\" of table \"{}\" as they differ only by case",
+                        column_name, canonical_name, table_name
+                    );
+                    let merged_ty = merged.get_mut(canonical_name).expect("must exist");
+                    *merged_ty = widen_type(merged_ty, ty);
+                    aliases
+                        .entry(table_name.clone())
+                        .or_default()
+                        .insert(column_name, canonical_name.clone());
+                }
+                None => {
+                    canonical.insert(key, column_name.clone());
+                    merged.insert(column_name, ty);
+                }
+            }
+        }
+        *columns = merged;
+    }
+    aliases
+}
+
+/// Renames the aliased columns of a row to the column they were merged into. When a row has
+/// values for more than one variant, the first value is kept.
+pub(crate) fn rename_row(
+    table_name: &str,
+    row: DatabaseRow,
+    aliases: &ColumnAliases,
+) -> DatabaseRow {
+    let table_aliases = match aliases.get(table_name) {
+        Some(table_aliases) => table_aliases,
+        None => return row,
+    };
+    let mut renamed = DatabaseRow::with_capacity(row.len());
+    for (name, value) in row {
+        let name = table_aliases.get(&name).cloned().unwrap_or(name);
+        if renamed.iter().any(|(n, _)| *n == name) {
+            debug!(
+                "Dropping duplicate value for column \"{}\" of table \"{}\"",
+                name, table_name
+            );
+            continue;
+        }
+        renamed.push((name, value));
+    }
+    renamed
+}
+
+/// Returns the SQL type able to hold values of both types
+pub(crate) fn widen_type(a: &'static str, b: &'static str) -> &'static str {
+    match (a, b) {
+        (a, b) if a == b => a,
+        ("INTEGER", "REAL") | ("REAL", "INTEGER") => "REAL",
+        _ => "TEXT",
+    }
+}
+
+/// How the columns of each SQLite table are ordered, recorded in the `_meta` table
+pub(crate) const COLUMN_ORDER: &str = "INTEGER and REAL columns first, then DATE, TEXT, and JSON \
+columns, then the metadata_ columns in the same type order; columns with the same rank are sorted \
+by name";
+
+/// Orders the columns of a table as described by [`COLUMN_ORDER`]. The fixed-size numeric columns
+/// lead each record and the mostly NULL metadata columns trail it, which keeps the commonly read
+/// values of wide tables such as Workout near the start of the record.
+pub(crate) fn ordered_columns<'a>(
+    columns: &'a BTreeMap<String, &'static str>,
+) -> Vec<(&'a String, &'static str)> {
+    let mut ordered: Vec<_> = columns.iter().map(|(name, ty)| (name, *ty)).collect();
+    // The sort is stable and the map is sorted by name, so ties keep their name order
+    ordered.sort_by_key(|(name, ty)| {
+        let type_rank = match *ty {
+            "INTEGER" | "REAL" => 0,
+            "DATE" => 1,
+            "TEXT" => 2,
+            _ => 3,
+        };
+        (name.starts_with(METADATA_COLUMN_PREFIX), type_rank)
+    });
+    ordered
+}
+
+/// Prefixes of the sample type identifiers stripped from the table names with --short-names
+const SHORT_NAME_PREFIXES: &[&str] = &["HKQuantityTypeIdentifier", "HKCategoryTypeIdentifier"];
+
+/// Returns the table name without its sample type prefix, e.g. HeartRate for
+/// HKQuantityTypeIdentifierHeartRate, or the name itself if it has no such prefix
+pub(crate) fn short_table_name(table_name: &str) -> &str {
+    SHORT_NAME_PREFIXES
+        .iter()
+        .find_map(|prefix| table_name.strip_prefix(prefix))
+        .filter(|name| !name.is_empty())
+        .unwrap_or(table_name)
+}
+
+/// Renames the tables to their short names, failing if two tables would have the same name
+pub(crate) fn shorten_table_names(tables: HKTables) -> anyhow::Result<HKTables> {
+    let mut shortened = HKTables::new();
+    for (table_name, columns) in tables {
+        let short_name = short_table_name(&table_name);
+        if shortened.contains_key(short_name) {
+            anyhow::bail!(
+                "--short-names can't be used with this export, as more than one table would be named \"{}\"",
+                short_name
+            );
+        }
+        shortened.insert(short_name.to_string(), columns);
+    }
+    Ok(shortened)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::DatabaseValue;
+
+    fn table(columns: &[(&str, &'static str)]) -> BTreeMap<String, &'static str> {
+        columns
+            .iter()
+            .map(|(name, ty)| (name.to_string(), *ty))
+            .collect()
+    }
+
+    #[test]
+    fn columns_differing_by_case_are_merged_into_the_smallest_name() {
+        let mut tables = HKTables::from([(
+            "Workout".to_string(),
+            table(&[
+                ("metadata_HKWeather", "INTEGER"),
+                ("metadata_HKweather", "REAL"),
+                ("metadata_hkweather", "INTEGER"),
+                ("metadata_Other", "TEXT"),
+            ]),
+        )]);
+        let aliases = merge_case_insensitive_columns(&mut tables);
+        assert_eq!(
+            tables["Workout"],
+            table(&[("metadata_HKWeather", "REAL"), ("metadata_Other", "TEXT")])
+        );
+        assert_eq!(
+            aliases["Workout"],
+            BTreeMap::from([
+                (
+                    "metadata_HKweather".to_string(),
+                    "metadata_HKWeather".to_string()
+                ),
+                (
+                    "metadata_hkweather".to_string(),
+                    "metadata_HKWeather".to_string()
+                ),
+            ])
+        );
+
+        let row = vec![
+            ("metadata_hkweather".to_string(), DatabaseValue::Integer(1)),
+            ("metadata_HKWeather".to_string(), DatabaseValue::Integer(2)),
+            ("metadata_Other".to_string(), DatabaseValue::Integer(3)),
+        ];
+        let row = rename_row("Workout", row, &aliases);
+        let names: Vec<(&str, String)> = row
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.to_text()))
+            .collect();
+        // The first value of a merged column is kept
+        assert_eq!(
+            names,
+            [
+                ("metadata_HKWeather", "1".to_string()),
+                ("metadata_Other", "3".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn merged_types_are_widened() {
+        assert_eq!(widen_type("INTEGER", "INTEGER"), "INTEGER");
+        assert_eq!(widen_type("INTEGER", "REAL"), "REAL");
+        assert_eq!(widen_type("REAL", "INTEGER"), "REAL");
+        assert_eq!(widen_type("REAL", "DATE"), "TEXT");
+        assert_eq!(widen_type("DATE", "DATE"), "DATE");
+    }
+
+    #[test]
+    fn columns_are_ordered_by_type_with_metadata_last() {
+        let columns = table(&[
+            ("unit", "TEXT"),
+            ("metadata_HKWeatherTemperature", "REAL"),
+            ("startDate", "DATE"),
+            ("metadata_HKIndoorWorkout", "INTEGER"),
+            ("value", "REAL"),
+            ("metadata_HKTimeZone", "TEXT"),
+            ("geometry", "JSON"),
+            ("duration", "REAL"),
+            ("workout_id", "INTEGER"),
+        ]);
+        let names: Vec<&str> = ordered_columns(&columns)
+            .into_iter()
+            .map(|(name, _)| name.as_str())
+            .collect();
+        assert_eq!(
+            names,
+            [
+                "duration",
+                "value",
+                "workout_id",
+                "startDate",
+                "unit",
+                "geometry",
+                "metadata_HKIndoorWorkout",
+                "metadata_HKWeatherTemperature",
+                "metadata_HKTimeZone",
+            ]
+        );
+    }
+}
@@ -0,0 +1,541 @@
+//! The serve command, which exposes a small read-only JSON HTTP API over a generated database for
+//! quick dashboards, without installing Datasette
+use crate::cipher;
+use crate::compress;
+use crate::identifier::get_valid_sqlite_identifier;
+use crate::naming;
+use crate::options::parse_tenant;
+use crate::verify::read_naming;
+use clap::Args;
+use console::Term;
+use log::*;
+use serde_json::{json, Map, Value as JsonValue};
+use sqlx::sqlite::{SqlitePoolOptions, SqliteRow};
+use sqlx::{Column, Row, SqlitePool, TypeInfo, ValueRef};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Largest request head read, as only GET requests without a body are served
+const MAX_REQUEST_BYTES: usize = 64 * 1024;
+
+/// How long a client has to send its request head before the connection is closed
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+const WORKOUT_TABLE_NAME: &str = "Workout";
+const GEOMETRY_COLUMN: &str = "geometry";
+
+#[derive(Args, Debug)]
+pub struct ServeArgs {
+    #[arg(
+        help = "Path or URL to the SQLite database to serve",
+        env = "DATABASE_URL"
+    )]
+    pub db_url: String,
+    #[arg(help = "Address to listen on", long, default_value = "127.0.0.1")]
+    pub host: String,
+    #[arg(help = "Port to listen on", long, short, default_value_t = 8001)]
+    pub port: u16,
+    #[arg(
+        help = "Most rows returned by a query, the response is marked truncated beyond them",
+        long,
+        default_value_t = 1000
+    )]
+    pub max_rows: u64,
+    #[arg(
+        help = "Origin allowed to read the responses from a web page, sent as Access-Control-Allow-Origin. Without it, web pages open in a browser can't read the database",
+        long,
+        value_name = "ORIGIN"
+    )]
+    pub cors_origin: Option<String>,
+    #[arg(
+        help = "Serves the workouts of the tenant with this ID, whose tables were imported with --tenant",
+        long,
+        value_name = "ID",
+        value_parser = parse_tenant
+    )]
+    pub tenant: Option<String>,
+}
+
+/// What every connection is served with
+struct Server {
+    pool: SqlitePool,
+    max_rows: u64,
+    cors_origin: Option<String>,
+    /// The name of the Workout table in the database
+    workout_table_name: String,
+}
+
+/// An error response, as the HTTP status and the message of its {"error": ...} body
+#[derive(Debug)]
+struct HttpError(u16, String);
+
+impl From<sqlx::Error> for HttpError {
+    fn from(e: sqlx::Error) -> Self {
+        // Only the errors SQLite reports for the statement are the client's, such as a syntax
+        // error, the others are failures to read the database
+        let status = match e {
+            sqlx::Error::Database(_) => 400,
+            _ => 500,
+        };
+        HttpError(status, e.to_string())
+    }
+}
+
+/// Serves the API until Ctrl-C:
+///
+/// * `GET /tables` lists the tables and views with their columns and row counts
+/// * `GET /query?sql=SELECT...&param=...` runs a SELECT, binding each param in order
+/// * `GET /workouts/<rowid>/geojson` returns the route of a workout as a GeoJSON Feature
+pub async fn serve(args: &ServeArgs) -> anyhow::Result<()> {
+    // The database is opened read-only, so no query can modify it
+    let connect_options = cipher::connect_options(&args.db_url)?.read_only(true);
+    let pool = SqlitePoolOptions::new()
+        .connect_with(connect_options)
+        .await?;
+    let server = Arc::new(Server {
+        workout_table_name: workout_table_name(&pool, args.tenant.as_deref()).await,
+        pool,
+        max_rows: args.max_rows,
+        cors_origin: args.cors_origin.clone(),
+    });
+    let listener = TcpListener::bind((args.host.as_str(), args.port)).await?;
+    Term::stdout().write_line(&format!(
+        "Serving {} at http://{}",
+        cipher::redact_key(&args.db_url),
+        listener.local_addr()?
+    ))?;
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (stream, _) = accepted?;
+                let server = server.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = handle_connection(stream, &server).await {
+                        debug!("Failed to serve a request: {:#}", e);
+                    }
+                });
+            }
+            _ = tokio::signal::ctrl_c() => {
+                server.pool.close().await;
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Returns the name of the Workout table, prefixed with the tenant ID or converted to the
+/// --naming recorded in _meta. A tenant's tables can't be renamed with --naming.
+async fn workout_table_name(pool: &SqlitePool, tenant: Option<&str>) -> String {
+    match tenant {
+        Some(tenant) => format!("{}_{}", tenant, WORKOUT_TABLE_NAME),
+        // A database holding only the imports of tenants has no _meta of its own
+        None => naming::convert(
+            read_naming(pool).await.unwrap_or_default(),
+            WORKOUT_TABLE_NAME,
+        ),
+    }
+}
+
+async fn handle_connection(mut stream: TcpStream, server: &Server) -> anyhow::Result<()> {
+    let request = match tokio::time::timeout(REQUEST_TIMEOUT, read_request(&mut stream)).await {
+        Ok(request) => request?,
+        Err(_) => {
+            debug!(
+                "Closing a connection that sent no request for {:?}",
+                REQUEST_TIMEOUT
+            );
+            return Ok(());
+        }
+    };
+    let request = match request {
+        Some(request) => request,
+        None => return Ok(()),
+    };
+    let request = String::from_utf8_lossy(&request);
+    let mut request_line = request.lines().next().unwrap_or_default().split(' ');
+    let method = request_line.next().unwrap_or_default();
+    let target = request_line.next().unwrap_or_default();
+    debug!("{} {}", method, target);
+    let (status, body) = match respond(method, target, server).await {
+        Ok(body) => (200, body),
+        Err(HttpError(status, message)) => (status, json!({ "error": message })),
+    };
+    let body = serde_json::to_vec(&body)?;
+    // Without the header, browsers keep web pages from other origins from reading the responses
+    let cors = match &server.cors_origin {
+        Some(origin) => format!("Access-Control-Allow-Origin: {}\r\n", origin),
+        None => String::new(),
+    };
+    let head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n{}Connection: close\r\n\r\n",
+        status,
+        reason_phrase(status),
+        body.len(),
+        cors
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(&body).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// Reads the request head, or returns None if the client closes the connection or sends more
+/// than MAX_REQUEST_BYTES without finishing it
+async fn read_request(stream: &mut TcpStream) -> anyhow::Result<Option<Vec<u8>>> {
+    let mut request = Vec::new();
+    let mut buffer = [0u8; 4096];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+        let read = stream.read(&mut buffer).await?;
+        if read == 0 || request.len() + read > MAX_REQUEST_BYTES {
+            return Ok(None);
+        }
+        request.extend_from_slice(&buffer[..read]);
+    }
+    Ok(Some(request))
+}
+
+async fn respond(method: &str, target: &str, server: &Server) -> Result<JsonValue, HttpError> {
+    if method != "GET" {
+        return Err(HttpError(405, format!("{} is not supported", method)));
+    }
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let params: Vec<(String, String)> = query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (percent_decode(name), percent_decode(value))
+        })
+        .collect();
+    let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+    match segments.as_slice() {
+        [] | ["tables"] => tables(&server.pool).await,
+        ["query"] => {
+            let sql = params
+                .iter()
+                .find(|(name, _)| name == "sql")
+                .map(|(_, value)| value.as_str())
+                .ok_or_else(|| HttpError(400, "Missing the sql parameter".to_string()))?;
+            let binds = params
+                .iter()
+                .filter(|(name, _)| name == "param")
+                .map(|(_, value)| value.as_str());
+            query_rows(&server.pool, sql, binds, server.max_rows).await
+        }
+        ["workouts", rowid, "geojson"] => {
+            let rowid = rowid
+                .parse::<i64>()
+                .map_err(|_| HttpError(400, format!("\"{}\" is not a workout rowid", rowid)))?;
+            workout_geojson(&server.pool, &server.workout_table_name, rowid).await
+        }
+        _ => Err(HttpError(404, format!("No such endpoint {}", path))),
+    }
+}
+
+/// Lists the tables and views with their columns, and the row counts of the tables
+async fn tables(pool: &SqlitePool) -> Result<JsonValue, HttpError> {
+    let names: Vec<(String, String)> = sqlx::query_as(
+        "SELECT name, type FROM sqlite_master WHERE type IN ('table', 'view') AND name NOT LIKE 'sqlite_%' ORDER BY name",
+    )
+    .fetch_all(pool)
+    .await?;
+    let mut tables = Vec::new();
+    for (name, ty) in names {
+        let columns: Vec<(String, String)> =
+            sqlx::query_as("SELECT name, type FROM pragma_table_info(?) ORDER BY cid")
+                .bind(&name)
+                .fetch_all(pool)
+                .await?;
+        let columns: Vec<JsonValue> = columns
+            .into_iter()
+            .map(|(name, ty)| json!({ "name": name, "type": ty }))
+            .collect();
+        let mut table = json!({ "name": name, "type": ty, "columns": columns });
+        if ty == "table" {
+            let count: i64 = sqlx::query_scalar(&format!(
+                "SELECT COUNT(*) FROM {}",
+                get_valid_sqlite_identifier(&name)
+            ))
+            .fetch_one(pool)
+            .await?;
+            table["count"] = json!(count);
+        }
+        tables.push(table);
+    }
+    Ok(json!({ "tables": tables }))
+}
+
+/// Runs a SELECT statement, binding each parameter as a number when it parses as one
+async fn query_rows<'a>(
+    pool: &SqlitePool,
+    sql: &str,
+    binds: impl Iterator<Item = &'a str>,
+    max_rows: u64,
+) -> Result<JsonValue, HttpError> {
+    let sql = single_statement(sql)?;
+    let keyword = sql
+        .split_whitespace()
+        .next()
+        .unwrap_or_default()
+        .to_ascii_uppercase();
+    if keyword != "SELECT" && keyword != "WITH" {
+        return Err(HttpError(
+            400,
+            "Only SELECT statements are allowed".to_string(),
+        ));
+    }
+    // Wrapping the statement as a subquery limits the rows. The newlines end any line comment
+    // the statement ends with, which would otherwise comment out the LIMIT.
+    let limited = format!("SELECT * FROM (\n{}\n) LIMIT {}", sql, max_rows + 1);
+    let mut query = sqlx::query(&limited);
+    for bind in binds {
+        query = if let Ok(i) = bind.parse::<i64>() {
+            query.bind(i)
+        } else if let Ok(f) = bind.parse::<f64>() {
+            query.bind(f)
+        } else {
+            query.bind(bind.to_string())
+        };
+    }
+    let mut rows = query.fetch_all(pool).await?;
+    let truncated = rows.len() as u64 > max_rows;
+    rows.truncate(max_rows as usize);
+    let columns: Vec<&str> = rows
+        .first()
+        .map(|row| row.columns().iter().map(|column| column.name()).collect())
+        .unwrap_or_default();
+    let values = rows
+        .iter()
+        .map(|row| Ok(JsonValue::Array(row_values(row)?)))
+        .collect::<Result<Vec<_>, HttpError>>()?;
+    Ok(json!({ "columns": columns, "rows": values, "truncated": truncated }))
+}
+
+/// Returns the statement without its trailing semicolon, rejecting SQL with another statement
+/// after it. SQLite runs every statement of the string, so the API would otherwise run e.g. an
+/// ATTACH after the SELECT.
+fn single_statement(sql: &str) -> Result<&str, HttpError> {
+    let sql = sql.trim();
+    let sql = sql.strip_suffix(';').unwrap_or(sql).trim_end();
+    let mut chars = sql.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            ';' => {
+                return Err(HttpError(
+                    400,
+                    "Only a single statement is allowed".to_string(),
+                ))
+            }
+            // Quoted strings and identifiers, whose doubled quotes end and restart the quote
+            '\'' | '"' | '`' | '[' => {
+                let end = if c == '[' { ']' } else { c };
+                for c in chars.by_ref() {
+                    if c == end {
+                        break;
+                    }
+                }
+            }
+            '-' if chars.peek() == Some(&'-') => {
+                for c in chars.by_ref() {
+                    if c == '\n' {
+                        break;
+                    }
+                }
+            }
+            '/' if chars.peek() == Some(&'*') => {
+                chars.next();
+                let mut previous = None;
+                for c in chars.by_ref() {
+                    if previous == Some('*') && c == '/' {
+                        break;
+                    }
+                    previous = Some(c);
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(sql)
+}
+
+/// Returns the route of a workout as a GeoJSON Feature, with its other columns as properties
+async fn workout_geojson(
+    pool: &SqlitePool,
+    workout_table_name: &str,
+    rowid: i64,
+) -> Result<JsonValue, HttpError> {
+    let row = sqlx::query(&format!(
+        "SELECT * FROM {} WHERE rowid = ?",
+        get_valid_sqlite_identifier(workout_table_name)
+    ))
+    .bind(rowid)
+    .fetch_optional(pool)
+    .await?
+    .ok_or_else(|| HttpError(404, format!("No workout with rowid {}", rowid)))?;
+    let mut geometry = JsonValue::Null;
+    let mut properties = Map::new();
+    for (column, value) in row.columns().iter().zip(row_values(&row)?) {
+        if column.name() == GEOMETRY_COLUMN {
+            geometry = match value {
+                JsonValue::String(text) => serde_json::from_str(&text)
+                    .map_err(|e| HttpError(500, format!("Invalid geometry: {}", e)))?,
+                value => value,
+            };
+        } else {
+            properties.insert(column.name().to_string(), value);
+        }
+    }
+    if geometry.is_null() {
+        return Err(HttpError(404, format!("Workout {} has no route", rowid)));
+    }
+    Ok(json!({ "type": "Feature", "geometry": geometry, "properties": properties }))
+}
+
+/// Converts the values of a row to JSON by their storage class, with the JSON compressed with
+/// --compress-json as JSON and other blobs as hex strings
+fn row_values(row: &SqliteRow) -> Result<Vec<JsonValue>, HttpError> {
+    let mut values = Vec::with_capacity(row.len());
+    for i in 0..row.len() {
+        let raw = row.try_get_raw(i)?;
+        let value = if raw.is_null() {
+            JsonValue::Null
+        } else {
+            match raw.type_info().name() {
+                "INTEGER" => json!(row.try_get::<i64, _>(i)?),
+                "REAL" => json!(row.try_get::<f64, _>(i)?),
+                "BLOB" => {
+                    let bytes: Vec<u8> = row.try_get(i)?;
+                    let json = compress::is_compressed(&bytes)
+                        .then(|| compress::json_text(&bytes).ok())
+                        .flatten()
+                        .and_then(|text| serde_json::from_slice(&text).ok());
+                    json.unwrap_or_else(|| {
+                        json!(bytes
+                            .iter()
+                            .map(|b| format!("{:02x}", b))
+                            .collect::<String>())
+                    })
+                }
+                _ => json!(row.try_get::<String, _>(i)?),
+            }
+        };
+        values.push(value);
+    }
+    Ok(values)
+}
+
+/// Decodes a percent-encoded query string component, with + as a space
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            // from_str_radix also accepts a sign, as in %+1, so the digits are checked first
+            b'%' if i + 2 < bytes.len()
+                && bytes[i + 1].is_ascii_hexdigit()
+                && bytes[i + 2].is_ascii_hexdigit() =>
+            {
+                let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).unwrap_or_default();
+                decoded.push(u8::from_str_radix(hex, 16).unwrap_or_default());
+                i += 2;
+            }
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        _ => "Internal Server Error",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percent_decode_escapes() {
+        assert_eq!(percent_decode("SELECT+%2A+FROM+x"), "SELECT * FROM x");
+        assert_eq!(percent_decode("%C3%A9t%c3%a9"), "été");
+        assert_eq!(percent_decode("a%3d1%26b"), "a=1&b");
+    }
+
+    #[test]
+    fn percent_decode_keeps_malformed_escapes() {
+        // The + after the % is a space as anywhere else, rather than the sign of a number
+        assert_eq!(percent_decode("%+1"), "% 1");
+        assert_eq!(percent_decode("%-1"), "%-1");
+        assert_eq!(percent_decode("%zz"), "%zz");
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_decode("%4"), "%4");
+        assert_eq!(percent_decode("%"), "%");
+    }
+
+    #[test]
+    fn single_statement_strips_the_trailing_semicolon() {
+        assert_eq!(single_statement(" SELECT 1; ").ok(), Some("SELECT 1"));
+        assert_eq!(single_statement("SELECT 1").ok(), Some("SELECT 1"));
+    }
+
+    #[test]
+    fn single_statement_rejects_a_second_statement() {
+        assert!(single_statement("SELECT 1; ATTACH 'x.db' AS x").is_err());
+        assert!(single_statement("SELECT 1;;").is_err());
+    }
+
+    #[test]
+    fn single_statement_ignores_semicolons_in_quotes_and_comments() {
+        for sql in [
+            "SELECT ';' FROM x",
+            "SELECT 'it''s; fine'",
+            "SELECT \"a;b\" FROM x",
+            "SELECT [a;b] FROM x",
+            "SELECT `a;b` FROM x",
+            "SELECT 1 -- a; comment\n",
+            "SELECT /* a; comment */ 1",
+        ] {
+            assert!(single_statement(sql).is_ok(), "{}", sql);
+        }
+    }
+
+    #[test]
+    fn only_statement_errors_are_bad_requests() {
+        assert_eq!(HttpError::from(sqlx::Error::PoolTimedOut).0, 500);
+        assert_eq!(HttpError::from(sqlx::Error::RowNotFound).0, 500);
+    }
+
+    #[tokio::test]
+    async fn query_rows_limits_statements_ending_in_a_line_comment() {
+        let pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        let rows = query_rows(
+            &pool,
+            "SELECT value FROM (SELECT 1 AS value UNION ALL SELECT 2) ORDER BY value -- note",
+            std::iter::empty(),
+            1,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            rows,
+            json!({ "columns": ["value"], "rows": [[1]], "truncated": true })
+        );
+        let rows = query_rows(&pool, "SELECT ? AS value -- note;", ["2"].into_iter(), 10)
+            .await
+            .unwrap();
+        assert_eq!(
+            rows,
+            json!({ "columns": ["value"], "rows": [[2]], "truncated": false })
+        );
+    }
+}
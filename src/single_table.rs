@@ -0,0 +1,183 @@
+//! The --single-table layout, writing every Record element to one long `samples` table rather
+//! than a table per type, which suits faceting and analyses across types
+use crate::core::{DatabaseRow, DatabaseValue, HKTables, METADATA_COLUMN_PREFIX};
+use crate::schema;
+use std::collections::BTreeMap;
+
+/// Name of the table of every Record element
+pub const SAMPLES_TABLE_NAME: &str = "samples";
+
+/// Column of the samples table holding the metadata entries of the record as a JSON object
+pub(crate) const METADATA_COLUMN: &str = "metadata";
+
+/// The record tables are named after the HealthKit type identifier of their records, such as
+/// HKQuantityTypeIdentifierHeartRate, which no other table starts with
+const RECORD_TABLE_PREFIX: &str = "HK";
+
+/// Returns true if the table holds Record elements
+pub fn is_record_table(table_name: &str) -> bool {
+    table_name.starts_with(RECORD_TABLE_PREFIX)
+}
+
+/// Replaces the record tables with the samples table, which has every column of the record
+/// tables except their metadata columns, which are combined into a single JSON column
+pub fn merge_tables(tables: HKTables) -> HKTables {
+    let mut merged = HKTables::new();
+    let mut samples = BTreeMap::new();
+    for (table_name, columns) in tables {
+        if !is_record_table(&table_name) {
+            merged.insert(table_name, columns);
+            continue;
+        }
+        for (column_name, ty) in columns {
+            if column_name.starts_with(METADATA_COLUMN_PREFIX) {
+                samples.insert(METADATA_COLUMN.to_string(), "JSON");
+                continue;
+            }
+            let ty = match samples.get(&column_name) {
+                Some(existing) => schema::widen_type(existing, ty),
+                None => ty,
+            };
+            samples.insert(column_name, ty);
+        }
+    }
+    if !samples.is_empty() {
+        merged.insert(SAMPLES_TABLE_NAME.to_string(), samples);
+    }
+    merged
+}
+
+/// Converts a row of a record table to a row of the samples table
+pub fn samples_row(row: DatabaseRow) -> DatabaseRow {
+    let mut metadata = serde_json::Map::new();
+    let mut samples_row = DatabaseRow::with_capacity(row.len());
+    for (name, value) in row {
+        match name.strip_prefix(METADATA_COLUMN_PREFIX) {
+            Some(key) => {
+                metadata.insert(key.to_string(), value.to_json());
+            }
+            None => samples_row.push((name, value)),
+        }
+    }
+    if !metadata.is_empty() {
+        samples_row.push((
+            METADATA_COLUMN.to_string(),
+            DatabaseValue::Json(metadata.into()),
+        ));
+    }
+    samples_row
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conformance::EXPORT_FILES;
+    use crate::options::ImportOptions;
+    use crate::testing::{self, TestDir};
+    use sqlx::SqlitePool;
+
+    fn columns(columns: &[(&str, &'static str)]) -> BTreeMap<String, &'static str> {
+        columns
+            .iter()
+            .map(|(name, ty)| (name.to_string(), *ty))
+            .collect()
+    }
+
+    #[test]
+    fn record_tables_are_merged_into_the_samples_table() {
+        let tables: HKTables = [
+            (
+                "HKQuantityTypeIdentifierHeartRate",
+                columns(&[
+                    ("value", "REAL"),
+                    ("unit", "TEXT"),
+                    ("metadata_HKMetadataKeyHeartRateMotionContext", "INTEGER"),
+                ]),
+            ),
+            (
+                "HKQuantityTypeIdentifierStepCount",
+                columns(&[("value", "INTEGER"), ("unit", "TEXT")]),
+            ),
+            (
+                "HKCategoryTypeIdentifierSleepAnalysis",
+                columns(&[("value", "TEXT")]),
+            ),
+            ("Workout", columns(&[("duration", "REAL")])),
+        ]
+        .into_iter()
+        .map(|(table_name, columns)| (table_name.to_string(), columns))
+        .collect();
+        let merged = merge_tables(tables);
+        assert_eq!(
+            merged.keys().collect::<Vec<_>>(),
+            ["Workout", SAMPLES_TABLE_NAME]
+        );
+        assert_eq!(
+            merged[SAMPLES_TABLE_NAME],
+            columns(&[("metadata", "JSON"), ("unit", "TEXT"), ("value", "TEXT")])
+        );
+        assert_eq!(merged["Workout"], columns(&[("duration", "REAL")]));
+    }
+
+    #[test]
+    fn there_is_no_samples_table_without_record_tables() {
+        let tables: HKTables = [("Workout".to_string(), columns(&[("duration", "REAL")]))]
+            .into_iter()
+            .collect();
+        assert!(!merge_tables(tables).contains_key(SAMPLES_TABLE_NAME));
+    }
+
+    #[test]
+    fn metadata_columns_are_folded_into_a_json_object() {
+        let row = samples_row(vec![
+            ("value".to_string(), DatabaseValue::Real(62.0)),
+            (
+                "metadata_HKMetadataKeyHeartRateMotionContext".to_string(),
+                DatabaseValue::Integer(1),
+            ),
+            (
+                "metadata_HKTimeZone".to_string(),
+                DatabaseValue::Text("America/Los_Angeles".to_string()),
+            ),
+        ]);
+        assert_eq!(row.len(), 2);
+        assert_eq!(row[0].0, "value");
+        assert_eq!(row[1].0, METADATA_COLUMN);
+        assert_eq!(
+            row[1].1.to_json(),
+            serde_json::json!({
+                "HKMetadataKeyHeartRateMotionContext": 1,
+                "HKTimeZone": "America/Los_Angeles",
+            })
+        );
+        let row = samples_row(vec![("value".to_string(), DatabaseValue::Real(62.0))]);
+        assert_eq!(row.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn every_record_is_imported_into_the_samples_table() {
+        let dir = TestDir::new("single-table");
+        let export_dir = dir.write_export("export", EXPORT_FILES);
+        let db_url = dir.db_url("health.db");
+        let options = ImportOptions {
+            single_table: true,
+            ..ImportOptions::defaults()
+        };
+        testing::import(&export_dir, &db_url, &options)
+            .await
+            .unwrap();
+        let counts = testing::row_counts(&db_url).await;
+        assert_eq!(counts[SAMPLES_TABLE_NAME], 7);
+        assert!(!counts.keys().any(|table_name| is_record_table(table_name)));
+        assert_eq!(counts["Workout"], 1);
+        let db = SqlitePool::connect(&db_url).await.unwrap();
+        let metadata: String = sqlx::query_scalar(
+            "SELECT metadata FROM samples WHERE type = 'HKQuantityTypeIdentifierBodyMass'",
+        )
+        .fetch_one(&db)
+        .await
+        .unwrap();
+        assert_eq!(metadata, r#"{"HKWasUserEntered":1}"#);
+        db.close().await;
+    }
+}
@@ -0,0 +1,953 @@
+//! Destinations the imported HealthKit data can be written to
+use self::csv::CsvSink;
+use self::dry_run::DryRunSink;
+#[cfg(feature = "duckdb")]
+use self::duckdb::DuckDbSink;
+use self::ndjson::NdjsonSink;
+#[cfg(feature = "parquet")]
+use self::parquet::ParquetSink;
+#[cfg(feature = "postgres")]
+use self::postgres::PostgresSink;
+use self::sql_dump::SqlDumpSink;
+use crate::analytics;
+use crate::append::Deduplicator;
+use crate::capability::Capability;
+use crate::change_log::ChangeLog;
+use crate::checkpoint::{self, Checkpoint};
+use crate::cipher;
+use crate::compress::CompressedColumns;
+use crate::core::{
+    DatabaseRow, DatabaseValue, HKTables, WORKOUT_ID_COLUMN, WORKOUT_KEY_COLUMN, WORKOUT_TABLE_NAME,
+};
+use crate::identifier::{self, get_valid_sqlite_identifier};
+use crate::meta::META_TABLE_NAME;
+use crate::naming;
+use crate::options::{Collation, ImportOptions, JournalMode};
+use crate::record_metadata::{self, RECORD_METADATA_TABLE_NAME};
+use crate::row_key::ROW_KEY_COLUMN;
+use crate::schema;
+use crate::sql_patch::SqlPatch;
+use anyhow::Context;
+use log::*;
+use sqlx::migrate::MigrateDatabase;
+use sqlx::query::Query;
+use sqlx::sqlite::{SqliteArguments, SqliteConnectOptions, SqliteJournalMode};
+use sqlx::{Sqlite, SqlitePool, Transaction};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+mod csv;
+mod dry_run;
+#[cfg(feature = "duckdb")]
+mod duckdb;
+mod ndjson;
+#[cfg(feature = "parquet")]
+mod parquet;
+#[cfg(feature = "postgres")]
+mod postgres;
+mod sql_dump;
+
+/// Where the imported data is written
+#[derive(Debug, Clone)]
+pub enum Output {
+    /// A database at the given URL
+    Database(String),
+    /// A directory containing one CSV file per table
+    Csv(PathBuf),
+    /// A directory containing one Parquet file per table
+    Parquet(PathBuf),
+    /// A directory containing one newline-delimited JSON file per table, or `-` for a single
+    /// stream of every row on stdout
+    Ndjson(PathBuf),
+    /// An SQL script of the statements creating and filling the tables, or `-` for stdout
+    Sql(PathBuf),
+}
+
+impl Output {
+    /// The optional feature the output needs, if any
+    pub fn capability(&self) -> Option<Capability> {
+        match self {
+            Output::Database(url) => match Backend::from_url(url) {
+                Backend::Sqlite if cipher::has_key(url) => Some(Capability::SqlCipher),
+                Backend::Sqlite => None,
+                Backend::DuckDb => Some(Capability::DuckDb),
+                Backend::Postgres => Some(Capability::Postgres),
+            },
+            Output::Parquet(_) => Some(Capability::Parquet),
+            Output::Csv(_) | Output::Ndjson(_) | Output::Sql(_) => None,
+        }
+    }
+}
+
+/// The database engine a database URL refers to.
+/// The tables are inferred with SQLite types and identifiers, which each backend translates.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    Sqlite,
+    DuckDb,
+    Postgres,
+}
+
+impl Backend {
+    /// Determines the backend from the URL scheme, defaulting to SQLite
+    pub fn from_url(url: &str) -> Self {
+        if url.starts_with("duckdb:") {
+            Backend::DuckDb
+        } else if url.starts_with("postgres:") || url.starts_with("postgresql:") {
+            Backend::Postgres
+        } else {
+            Backend::Sqlite
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Backend::Sqlite => "SQLite",
+            Backend::DuckDb => "DuckDB",
+            Backend::Postgres => "PostgreSQL",
+        }
+    }
+
+    /// Quotes an identifier for use in this backend's SQL
+    #[cfg(any(feature = "duckdb", feature = "postgres"))]
+    pub fn quote_identifier(&self, s: &str) -> String {
+        match self {
+            Backend::Sqlite => get_valid_sqlite_identifier(s),
+            Backend::DuckDb | Backend::Postgres => format!("\"{}\"", s.replace('"', "\"\"")),
+        }
+    }
+
+    /// Translates an inferred SQLite column type to this backend's type
+    #[cfg(any(feature = "duckdb", feature = "postgres"))]
+    pub fn column_type<'a>(&self, ty: &'a str) -> &'a str {
+        match (self, ty) {
+            (Backend::Sqlite, ty) => ty,
+            (_, "DATE") => "TIMESTAMPTZ",
+            (Backend::DuckDb, "TEXT" | "JSON") => "VARCHAR",
+            (Backend::Postgres, "JSON") => "JSONB",
+            (Backend::Postgres, "BLOB") => "BYTEA",
+            (_, "INTEGER") => "BIGINT",
+            (Backend::DuckDb, "REAL") => "DOUBLE",
+            (Backend::Postgres, "REAL") => "DOUBLE PRECISION",
+            (_, ty) => ty,
+        }
+    }
+}
+
+/// Returns the file path of a duckdb:// URL
+fn duckdb_path(url: &str) -> &Path {
+    Path::new(url.trim_start_matches("duckdb:").trim_start_matches("//"))
+}
+
+/// Returns the path of the file of a table in an output directory. The table names come from the
+/// type attributes of the export, so they are slugified to keep e.g. `../x` inside the directory.
+/// A table whose file name is already in file_names, such as `A_B` after `A B`, or one differing
+/// from it only in case, is given a numbered suffix instead of truncating the other table's file.
+fn table_file_path(
+    dir: &Path,
+    table_name: &str,
+    extension: &str,
+    file_names: &mut HashSet<String>,
+) -> PathBuf {
+    let slug = identifier::slugify(table_name);
+    let mut file_name = format!("{}.{}", slug, extension);
+    let mut n = 1;
+    // Compared in lowercase for case-insensitive file systems
+    while !file_names.insert(file_name.to_lowercase()) {
+        n += 1;
+        file_name = format!("{}_{}.{}", slug, n, extension);
+    }
+    if n > 1 {
+        warn!(
+            "Writing table {} to {}, as another table has the same file name",
+            table_name, file_name
+        );
+    }
+    dir.join(file_name)
+}
+
+/// Returns the file path of a sqlite:// URL, or None for an in-memory database
+pub fn sqlite_path(url: &str) -> Option<&Path> {
+    let path = url.trim_start_matches("sqlite:").trim_start_matches("//");
+    let path = path.split('?').next().unwrap_or(path);
+    (!path.is_empty() && path != ":memory:").then(|| Path::new(path))
+}
+
+/// Returns true if the database at the URL already exists
+pub async fn database_exists(url: &str) -> anyhow::Result<bool> {
+    match Backend::from_url(url) {
+        Backend::Sqlite => Ok(sqlx::Sqlite::database_exists(&cipher::split_key(url)?.0).await?),
+        Backend::DuckDb => Ok(duckdb_path(url).exists()),
+        #[cfg(feature = "postgres")]
+        Backend::Postgres => Ok(sqlx::Postgres::database_exists(url).await?),
+        #[cfg(not(feature = "postgres"))]
+        Backend::Postgres => Ok(false),
+    }
+}
+
+/// Deletes the database at the URL
+pub async fn drop_database(url: &str) -> anyhow::Result<()> {
+    match Backend::from_url(url) {
+        Backend::Sqlite => Ok(sqlx::Sqlite::drop_database(&cipher::split_key(url)?.0).await?),
+        Backend::DuckDb => {
+            let path = duckdb_path(url);
+            std::fs::remove_file(path)?;
+            let wal_path = PathBuf::from(format!("{}.wal", path.display()));
+            if wal_path.exists() {
+                std::fs::remove_file(wal_path)?;
+            }
+            Ok(())
+        }
+        #[cfg(feature = "postgres")]
+        Backend::Postgres => Ok(sqlx::Postgres::drop_database(url).await?),
+        #[cfg(not(feature = "postgres"))]
+        Backend::Postgres => Err(Capability::Postgres.missing()),
+    }
+}
+
+impl std::fmt::Display for Output {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Output::Database(url) => {
+                write!(
+                    f,
+                    "{} database \"{}\"",
+                    Backend::from_url(url).name(),
+                    cipher::redact_key(url)
+                )
+            }
+            Output::Csv(dir) => write!(f, "CSV files in \"{}\"", dir.display()),
+            Output::Parquet(dir) => write!(f, "Parquet files in \"{}\"", dir.display()),
+            Output::Ndjson(path) if path == Path::new("-") => write!(f, "NDJSON on stdout"),
+            Output::Ndjson(dir) => write!(f, "NDJSON files in \"{}\"", dir.display()),
+            Output::Sql(path) if path == Path::new("-") => write!(f, "SQL on stdout"),
+            Output::Sql(path) => write!(f, "SQL script \"{}\"", path.display()),
+        }
+    }
+}
+
+/// An open destination for the imported data
+pub enum Sink {
+    Sqlite(Box<SqliteSink>),
+    Csv(CsvSink),
+    Ndjson(NdjsonSink),
+    Sql(SqlDumpSink),
+    DryRun(DryRunSink),
+    #[cfg(feature = "parquet")]
+    Parquet(ParquetSink),
+    #[cfg(feature = "duckdb")]
+    DuckDb(DuckDbSink),
+    #[cfg(feature = "postgres")]
+    Postgres(Box<PostgresSink>),
+}
+
+impl Sink {
+    /// Opens the output for writing
+    pub async fn open(output: &Output, options: &ImportOptions) -> anyhow::Result<Self> {
+        if options.dry_run {
+            return Ok(Sink::DryRun(DryRunSink::default()));
+        }
+        if options.append && !matches!(output, Output::Database(_)) {
+            anyhow::bail!("--append is only supported when writing to a database");
+        }
+        if options.resume && !matches!(output, Output::Database(_)) {
+            anyhow::bail!("--resume is only supported when writing to a database");
+        }
+        if options.max_db_size.is_some() && !matches!(output, Output::Database(_)) {
+            anyhow::bail!("--max-db-size is only supported when writing to a database");
+        }
+        if options.compress_json && !matches!(output, Output::Database(_)) {
+            anyhow::bail!("--compress-json is only supported when writing to a database");
+        }
+        match output {
+            Output::Database(url) => {
+                let backend = Backend::from_url(url);
+                if options.append && backend != Backend::Sqlite {
+                    anyhow::bail!("--append is only supported for SQLite databases");
+                }
+                if options.resume && backend != Backend::Sqlite {
+                    anyhow::bail!("--resume is only supported for SQLite databases");
+                }
+                if options.max_db_size.is_some() && backend != Backend::Sqlite {
+                    anyhow::bail!("--max-db-size is only supported for SQLite databases");
+                }
+                if options.compress_json && backend != Backend::Sqlite {
+                    anyhow::bail!("--compress-json is only supported for SQLite databases");
+                }
+                match backend {
+                    Backend::Sqlite => Ok(Sink::Sqlite(Box::new(
+                        SqliteSink::open(url, options).await?,
+                    ))),
+                    #[cfg(feature = "duckdb")]
+                    Backend::DuckDb => Ok(Sink::DuckDb(DuckDbSink::open(duckdb_path(url))?)),
+                    #[cfg(not(feature = "duckdb"))]
+                    Backend::DuckDb => Err(Capability::DuckDb.missing()),
+                    #[cfg(feature = "postgres")]
+                    Backend::Postgres => {
+                        Ok(Sink::Postgres(Box::new(PostgresSink::open(url).await?)))
+                    }
+                    #[cfg(not(feature = "postgres"))]
+                    Backend::Postgres => Err(Capability::Postgres.missing()),
+                }
+            }
+            Output::Csv(dir) => Ok(Sink::Csv(CsvSink::create(dir, options.no_destructive)?)),
+            Output::Ndjson(path) => Ok(Sink::Ndjson(NdjsonSink::create(
+                path,
+                options.no_destructive,
+            )?)),
+            Output::Sql(path) => Ok(Sink::Sql(SqlDumpSink::create(
+                path,
+                options.no_destructive,
+                options.collation,
+            )?)),
+            #[cfg(feature = "parquet")]
+            Output::Parquet(dir) => Ok(Sink::Parquet(ParquetSink::create(
+                dir,
+                options.no_destructive,
+            )?)),
+            #[cfg(not(feature = "parquet"))]
+            Output::Parquet(_) => Err(Capability::Parquet.missing()),
+        }
+    }
+
+    /// Creates the tables derived from the export
+    pub async fn create_tables(&mut self, tables: &HKTables) -> anyhow::Result<()> {
+        match self {
+            Sink::Sqlite(sink) => sink.create_tables(tables).await,
+            Sink::Csv(sink) => sink.create_tables(tables),
+            Sink::Ndjson(sink) => sink.create_tables(tables),
+            Sink::Sql(sink) => sink.create_tables(tables),
+            Sink::DryRun(sink) => sink.create_tables(tables),
+            #[cfg(feature = "parquet")]
+            Sink::Parquet(sink) => sink.create_tables(tables),
+            #[cfg(feature = "duckdb")]
+            Sink::DuckDb(sink) => sink.create_tables(tables),
+            #[cfg(feature = "postgres")]
+            Sink::Postgres(sink) => sink.create_tables(tables).await,
+        }
+    }
+
+    /// Deletes every row of a table, e.g. one left in the database by a previous import.
+    /// The other file outputs are always written from scratch, so there is nothing to delete,
+    /// but the SQL script may be run against a database of a previous import.
+    pub async fn clear_table(&mut self, table_name: &str) -> anyhow::Result<()> {
+        match self {
+            Sink::Sqlite(sink) => sink.clear_table(table_name).await,
+            Sink::Csv(_) | Sink::Ndjson(_) => Ok(()),
+            Sink::Sql(sink) => sink.clear_table(table_name),
+            Sink::DryRun(sink) => {
+                sink.clear_table(table_name);
+                Ok(())
+            }
+            #[cfg(feature = "parquet")]
+            Sink::Parquet(_) => Ok(()),
+            #[cfg(feature = "duckdb")]
+            Sink::DuckDb(sink) => sink.clear_table(table_name),
+            #[cfg(feature = "postgres")]
+            Sink::Postgres(sink) => sink.clear_table(table_name).await,
+        }
+    }
+
+    /// Writes a single row to the specified table, returning its id for child rows to refer to.
+    /// The id is the rowid in SQLite and the 1-based row number of the table otherwise.
+    /// Returns None if the row was skipped as a duplicate when appending, or in SQLite because a
+    /// row with the same row_key exists.
+    pub async fn insert(
+        &mut self,
+        table_name: &str,
+        row: DatabaseRow,
+    ) -> anyhow::Result<Option<i64>> {
+        match self {
+            Sink::Sqlite(sink) => sink.insert(table_name, row).await,
+            Sink::Csv(sink) => sink.insert(table_name, row),
+            Sink::Ndjson(sink) => sink.insert(table_name, row),
+            Sink::Sql(sink) => sink.insert(table_name, row),
+            Sink::DryRun(sink) => sink.insert(table_name, row),
+            #[cfg(feature = "parquet")]
+            Sink::Parquet(sink) => sink.insert(table_name, row),
+            #[cfg(feature = "duckdb")]
+            Sink::DuckDb(sink) => sink.insert(table_name, row),
+            #[cfg(feature = "postgres")]
+            Sink::Postgres(sink) => sink.insert(table_name, row).await,
+        }
+    }
+
+    /// Returns the size in bytes of the SQLite database with the rows written so far, or None for
+    /// the other outputs
+    pub async fn database_size(&mut self) -> anyhow::Result<Option<u64>> {
+        match self {
+            Sink::Sqlite(sink) => {
+                let size: i64 = sqlx::query_scalar(
+                    "SELECT page_count * page_size FROM pragma_page_count(), pragma_page_size()",
+                )
+                .fetch_one(&mut sink.tx)
+                .await?;
+                Ok(Some(size as u64))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Returns true if the output is renamed to the names of --naming once the import finishes,
+    /// rather than written with them. The analytics passes of SQLite databases query the tables
+    /// by the names of the export.
+    pub fn renames_on_finish(&self) -> bool {
+        matches!(self, Sink::Sqlite(_))
+    }
+
+    /// Commits the rows written so far along with the checkpoint they reach. Only SQLite databases
+    /// are checkpointed, the other outputs are written in one go.
+    pub async fn checkpoint(&mut self, checkpoint: &Checkpoint) -> anyhow::Result<()> {
+        match self {
+            Sink::Sqlite(sink) => sink.checkpoint(checkpoint).await,
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns the checkpoint of an unfinished import of the output, if there is one
+    pub async fn read_checkpoint(&mut self) -> anyhow::Result<Option<Checkpoint>> {
+        match self {
+            Sink::Sqlite(sink) => checkpoint::read(&mut sink.tx).await,
+            _ => Ok(None),
+        }
+    }
+
+    /// Returns the ids and descriptions of the Device table of an earlier import of the output,
+    /// which is named in the casing of --naming once that import finished
+    pub async fn read_devices(&mut self, table_name: &str) -> anyhow::Result<Vec<(i64, String)>> {
+        let sink = match self {
+            Sink::Sqlite(sink) => sink,
+            _ => return Ok(Vec::new()),
+        };
+        for table_name in [
+            table_name.to_string(),
+            naming::convert(sink.options.naming, table_name),
+        ] {
+            let exists: bool = sqlx::query_scalar(
+                "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?)",
+            )
+            .bind(&table_name)
+            .fetch_one(&mut sink.tx)
+            .await?;
+            if exists {
+                let qs = format!(
+                    "SELECT id, description FROM {}",
+                    get_valid_sqlite_identifier(&table_name)
+                );
+                return Ok(sqlx::query_as(&qs).fetch_all(&mut sink.tx).await?);
+            }
+        }
+        Ok(Vec::new())
+    }
+
+    /// Removes the checkpoint once every element of export.xml is imported
+    pub async fn clear_checkpoint(&mut self) -> anyhow::Result<()> {
+        match self {
+            Sink::Sqlite(sink) => {
+                checkpoint::clear(&mut sink.tx, sink.options.no_destructive).await
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Flushes all written rows and runs any post-import passes the output supports
+    pub async fn finish(self) -> anyhow::Result<()> {
+        match self {
+            Sink::Sqlite(sink) => sink.finish().await,
+            Sink::Csv(sink) => sink.finish(),
+            Sink::Ndjson(sink) => sink.finish(),
+            Sink::Sql(sink) => sink.finish(),
+            Sink::DryRun(sink) => sink.finish(),
+            #[cfg(feature = "parquet")]
+            Sink::Parquet(sink) => sink.finish(),
+            #[cfg(feature = "duckdb")]
+            Sink::DuckDb(sink) => sink.finish(),
+            #[cfg(feature = "postgres")]
+            Sink::Postgres(sink) => sink.finish().await,
+        }
+    }
+}
+
+/// Number of rows committed in each transaction with --replication-friendly
+pub const REPLICATION_TRANSACTION_ROWS: usize = 10_000;
+
+/// Writes the data to a SQLite database inside a single transaction, or in transactions of
+/// REPLICATION_TRANSACTION_ROWS rows with --replication-friendly
+pub struct SqliteSink {
+    db: SqlitePool,
+    tx: Transaction<'static, Sqlite>,
+    dedup: Option<Deduplicator>,
+    /// Logs the inserted and deleted rows when --change-log is set
+    change_log: Option<ChangeLog>,
+    /// Records the changes for the --sql-patch script
+    sql_patch: Option<SqlPatch>,
+    /// Rows inserted since the transaction began
+    transaction_rows: usize,
+    /// Columns of each table created, to add the columns of rows the schema pass didn't find
+    columns: HashMap<String, HashSet<String>>,
+    /// The JSON columns stored compressed with --compress-json
+    compressed: Option<CompressedColumns>,
+    options: ImportOptions,
+}
+
+impl SqliteSink {
+    async fn open(db_url: &str, options: &ImportOptions) -> anyhow::Result<Self> {
+        let db = create_db(db_url, options).await?;
+        if let Some(tenant) = options.tenant.as_deref().filter(|_| !options.append) {
+            let meta_table_name = options.tenant_table_name(META_TABLE_NAME);
+            let imported: i64 = sqlx::query_scalar(
+                "SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = ?",
+            )
+            .bind(meta_table_name.as_ref())
+            .fetch_one(&db)
+            .await?;
+            if imported > 0 {
+                anyhow::bail!(
+                    "The database already holds an import of the tenant \"{}\". Import with --append to add the export to it",
+                    tenant
+                );
+            }
+        }
+        let mut tx = db.begin().await?;
+        let change_log = if options.change_log {
+            Some(ChangeLog::open(&mut tx).await?)
+        } else {
+            None
+        };
+        let sql_patch = match options.sql_patch.as_deref() {
+            Some(path) => Some(SqlPatch::create(path, options.no_destructive, &mut tx).await?),
+            None => None,
+        };
+        Ok(Self {
+            db,
+            tx,
+            dedup: options.append.then(Deduplicator::default),
+            change_log,
+            sql_patch,
+            transaction_rows: 0,
+            columns: Default::default(),
+            compressed: options.compress_json.then(CompressedColumns::default),
+            options: options.clone(),
+        })
+    }
+
+    async fn create_tables(&mut self, tables: &HKTables) -> anyhow::Result<()> {
+        let compressed_tables;
+        let tables = match &mut self.compressed {
+            Some(compressed) => {
+                compressed_tables = compressed.extend_tables(tables);
+                &compressed_tables
+            }
+            None => tables,
+        };
+        let record_metadata_table_name = self
+            .options
+            .tenant_table_name(&identifier::convert(
+                &self.options,
+                RECORD_METADATA_TABLE_NAME,
+            ))
+            .into_owned();
+        let workout_table_name = self
+            .options
+            .tenant_table_name(&identifier::convert(&self.options, &WORKOUT_TABLE_NAME))
+            .into_owned();
+        let workout_id_column = identifier::convert(&self.options, WORKOUT_ID_COLUMN);
+        // The Workout table of a database imported by an older version has no key to reference
+        let workout_keyed: bool = sqlx::query_scalar(
+            "SELECT NOT EXISTS (SELECT 1 FROM pragma_table_info(?1))
+                OR EXISTS (SELECT 1 FROM pragma_table_info(?1) WHERE name = ?2 AND pk = 1)",
+        )
+        .bind(&workout_table_name)
+        .bind(WORKOUT_KEY_COLUMN)
+        .fetch_one(&mut self.tx)
+        .await?;
+        for (name, columns) in tables {
+            let mut definitions = Vec::new();
+            if *name == workout_table_name {
+                definitions.push(format!("{} INTEGER PRIMARY KEY", WORKOUT_KEY_COLUMN));
+            }
+            for (column_name, ty) in schema::ordered_columns(columns) {
+                let definition = column_definition(column_name, ty, self.options.collation);
+                definitions.push(
+                    if workout_keyed
+                        && *name != workout_table_name
+                        && column_name == &workout_id_column
+                    {
+                        format!(
+                            "{} REFERENCES {}({})",
+                            definition,
+                            get_valid_sqlite_identifier(&workout_table_name),
+                            WORKOUT_KEY_COLUMN
+                        )
+                    } else {
+                        definition
+                    },
+                );
+            }
+            let qs = format!(
+                r#"CREATE TABLE IF NOT EXISTS {} ({});
+            "#,
+                get_valid_sqlite_identifier(name),
+                definitions.join(", ")
+            );
+            sqlx::query(&qs)
+                .execute(&mut self.tx)
+                .await
+                .with_context(|| format!("Failed to create the table \"{}\"", name))?;
+            if let Some(sql_patch) = &mut self.sql_patch {
+                sql_patch.record_schema(&qs);
+            }
+            // The table may already exist when appending to a database created from an older export,
+            // in which case any columns that are new in this export need to be added
+            let existing_columns: Vec<String> =
+                sqlx::query_scalar("SELECT name FROM pragma_table_info(?)")
+                    .bind(name)
+                    .fetch_all(&mut self.tx)
+                    .await?;
+            for (column_name, ty) in schema::ordered_columns(columns) {
+                if !existing_columns.contains(column_name) {
+                    self.add_column(name, column_name, ty).await?;
+                }
+            }
+            self.columns
+                .entry(name.clone())
+                .or_default()
+                .extend(existing_columns.into_iter().chain(columns.keys().cloned()));
+            if columns.contains_key(ROW_KEY_COLUMN) {
+                let qs = format!(
+                    "CREATE UNIQUE INDEX IF NOT EXISTS {} ON {} ({})",
+                    get_valid_sqlite_identifier(&format!("{}_{}", name, ROW_KEY_COLUMN)),
+                    get_valid_sqlite_identifier(name),
+                    ROW_KEY_COLUMN
+                );
+                sqlx::query(&qs).execute(&mut self.tx).await?;
+                if let Some(sql_patch) = &mut self.sql_patch {
+                    sql_patch.record_schema(&qs);
+                }
+            }
+            if self.options.metadata_table && *name == record_metadata_table_name {
+                let qs = record_metadata::index_statement(name);
+                sqlx::query(&qs).execute(&mut self.tx).await?;
+                if let Some(sql_patch) = &mut self.sql_patch {
+                    sql_patch.record_schema(&qs);
+                }
+            }
+        }
+        // Commit the schema separately from the data, matching the two passes over the export
+        let tx = std::mem::replace(&mut self.tx, self.db.begin().await?);
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn add_column(
+        &mut self,
+        table_name: &str,
+        column_name: &str,
+        ty: &str,
+    ) -> anyhow::Result<()> {
+        let qs = format!(
+            "ALTER TABLE {} ADD COLUMN {}",
+            get_valid_sqlite_identifier(table_name),
+            column_definition(column_name, ty, self.options.collation)
+        );
+        sqlx::query(&qs).execute(&mut self.tx).await?;
+        if let Some(sql_patch) = &mut self.sql_patch {
+            sql_patch.record_schema(&qs);
+        }
+        Ok(())
+    }
+
+    async fn checkpoint(&mut self, checkpoint: &Checkpoint) -> anyhow::Result<()> {
+        checkpoint::write(&mut self.tx, checkpoint).await?;
+        let tx = std::mem::replace(&mut self.tx, self.db.begin().await?);
+        tx.commit().await?;
+        self.transaction_rows = 0;
+        Ok(())
+    }
+
+    async fn clear_table(&mut self, table_name: &str) -> anyhow::Result<()> {
+        if let Some(change_log) = &self.change_log {
+            change_log.record_clear(&mut self.tx, table_name).await?;
+        }
+        if let Some(sql_patch) = &mut self.sql_patch {
+            sql_patch.record_clear(table_name);
+        }
+        sqlx::query(&format!(
+            "DELETE FROM {}",
+            get_valid_sqlite_identifier(table_name)
+        ))
+        .execute(&mut self.tx)
+        .await?;
+        Ok(())
+    }
+
+    async fn insert(
+        &mut self,
+        table_name: &str,
+        mut row: DatabaseRow,
+    ) -> anyhow::Result<Option<i64>> {
+        if let Some(compressed) = &mut self.compressed {
+            compressed.compress_row(table_name, &mut row)?;
+        }
+        if let Some(columns) = self.columns.get_mut(table_name) {
+            for (column_name, ty) in missing_columns(table_name, columns, &row) {
+                self.add_column(table_name, &column_name, ty).await?;
+            }
+        }
+        if let Some(dedup) = &mut self.dedup {
+            if dedup.is_duplicate(&mut self.tx, table_name, &row).await? {
+                return Ok(None);
+            }
+        }
+        // A row whose key is already in the table is the same sample, imported before
+        let keyed = row.iter().any(|(name, _)| name == ROW_KEY_COLUMN);
+        let qs = format!(
+            r#"INSERT {}INTO {} ({}) VALUES ({})"#,
+            if keyed { "OR IGNORE " } else { "" },
+            get_valid_sqlite_identifier(table_name),
+            row.iter()
+                .map(|(name, _)| get_valid_sqlite_identifier(name))
+                .collect::<Vec<_>>()
+                .join(", "),
+            row.iter()
+                .map(|(_, _)| "?")
+                .collect::<Vec<&str>>()
+                .join(", ")
+        );
+        let mut query = sqlx::query(&qs);
+        for (_, value) in row {
+            query = bind_database_value(query, value);
+        }
+        let result = query
+            .execute(&mut self.tx)
+            .await
+            .with_context(|| format!("Failed to insert a row with {}", qs))?;
+        if result.rows_affected() == 0 {
+            return Ok(None);
+        }
+        let rowid = result.last_insert_rowid();
+        if let Some(change_log) = &self.change_log {
+            change_log
+                .record_insert(&mut self.tx, table_name, rowid)
+                .await?;
+        }
+        if let Some(sql_patch) = &mut self.sql_patch {
+            sql_patch.record_insert(table_name, rowid);
+        }
+        self.transaction_rows += 1;
+        if self.options.replication_friendly
+            && self.transaction_rows >= REPLICATION_TRANSACTION_ROWS
+        {
+            // Each commit appends a bounded number of pages to the WAL for replicas to ship
+            let tx = std::mem::replace(&mut self.tx, self.db.begin().await?);
+            tx.commit().await?;
+            self.transaction_rows = 0;
+        }
+        Ok(Some(rowid))
+    }
+
+    async fn finish(self) -> anyhow::Result<()> {
+        self.tx.commit().await?;
+        // Derive the analytics tables from the inserted data. They aren't scoped to a tenant, so
+        // they are only derived from databases holding a single import.
+        let mut tx = self.db.begin().await?;
+        if self.options.tenant.is_none() {
+            analytics::run(&mut tx, &self.options).await?;
+        }
+        naming::rename_sqlite_tables(&mut tx, self.options.naming).await?;
+        if let Some(compressed) = &self.compressed {
+            compressed
+                .create_views(&mut tx, self.options.naming)
+                .await?;
+        }
+        tx.commit().await?;
+        if self.options.analyze {
+            // Gathers the statistics the query planner uses to pick indexes
+            debug!("Analyzing the database");
+            sqlx::query("ANALYZE").execute(&self.db).await?;
+            sqlx::query("PRAGMA optimize").execute(&self.db).await?;
+        }
+        if let Some(sql_patch) = self.sql_patch {
+            sql_patch.write(&self.db).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns the options of the database connections, with the PRAGMAs given for the import
+fn connect_options(db_url: &str, options: &ImportOptions) -> anyhow::Result<SqliteConnectOptions> {
+    let mut connect_options = cipher::connect_options(db_url)?;
+    if let Some(journal_mode) = options.journal_mode {
+        connect_options = connect_options.journal_mode(journal_mode.to_sqlite());
+    }
+    if options.replication_friendly {
+        // Replication tools follow the WAL, so the database must stay in WAL mode
+        if !matches!(options.journal_mode, None | Some(JournalMode::Wal)) {
+            anyhow::bail!("--replication-friendly requires the WAL journal mode");
+        }
+        connect_options = connect_options.journal_mode(SqliteJournalMode::Wal);
+    }
+    if let Some(synchronous) = options.synchronous {
+        connect_options = connect_options.synchronous(synchronous.to_sqlite());
+    }
+    if let Some(cache_size) = options.cache_size {
+        connect_options = connect_options.pragma("cache_size", cache_size.to_string());
+    }
+    if let Some(temp_store) = options.temp_store {
+        connect_options = connect_options.pragma("temp_store", temp_store.as_sql());
+    }
+    for extension in &options.load_extensions {
+        connect_options = connect_options.extension(extension.clone());
+    }
+    Ok(connect_options)
+}
+
+/// Returns the columns of the row that the table doesn't have, with the type of their values,
+/// adding them to the columns of the table. Rows can have columns the schema pass didn't find, and
+/// the databases add these columns as they're written rather than fail the import. Column names
+/// are case-insensitive, so a column differing only by case from one of the table isn't missing.
+pub(crate) fn missing_columns(
+    table_name: &str,
+    columns: &mut HashSet<String>,
+    row: &DatabaseRow,
+) -> Vec<(String, &'static str)> {
+    let mut missing = Vec::new();
+    for (name, value) in row {
+        if columns.contains(name) {
+            continue;
+        }
+        if !columns
+            .iter()
+            .any(|column| column.eq_ignore_ascii_case(name))
+        {
+            warn!(
+                "Adding the column \"{}\" to \"{}\", as it wasn't found when the tables were created",
+                name, table_name
+            );
+            missing.push((name.clone(), value.database_type()));
+        }
+        columns.insert(name.clone());
+    }
+    missing
+}
+
+/// Returns the column name and type, with the given collation for text columns
+fn column_definition(name: &str, ty: &str, collation: Collation) -> String {
+    match ty {
+        "TEXT" => format!(
+            "{} {} COLLATE {}",
+            get_valid_sqlite_identifier(name),
+            ty,
+            collation.as_sql()
+        ),
+        _ => format!("{} {}", get_valid_sqlite_identifier(name), ty),
+    }
+}
+
+/// Binds a typed value to the next parameter of the query
+pub(crate) fn bind_database_value<'q>(
+    query: Query<'q, Sqlite, SqliteArguments<'q>>,
+    value: DatabaseValue,
+) -> Query<'q, Sqlite, SqliteArguments<'q>> {
+    match value {
+        DatabaseValue::Integer(i) => query.bind(i),
+        DatabaseValue::Real(i) => query.bind(i),
+        DatabaseValue::OffsetDateTime(i) => query.bind(i),
+        DatabaseValue::Date(i) => query.bind(i),
+        DatabaseValue::Text(i) => query.bind(i),
+        DatabaseValue::Json(i) => query.bind(i),
+        DatabaseValue::Blob(i) => query.bind(i),
+    }
+}
+
+async fn create_db(db_url: &str, options: &ImportOptions) -> anyhow::Result<SqlitePool> {
+    // Create and connect to the database. Created by the pool's connections, so with a key the
+    // first write to the file is encrypted.
+    let (_, key) = cipher::split_key(db_url)?;
+    let db =
+        SqlitePool::connect_with(connect_options(db_url, options)?.create_if_missing(true)).await?;
+    if key.is_some() {
+        cipher::check_key(&db).await?;
+    }
+    // Run migrations
+    sqlx::migrate!().run(&db).await?;
+    Ok(db)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conformance::EXPORT_FILES;
+    use crate::testing::{self, TestDir};
+
+    #[test]
+    fn table_file_paths_dont_collide() {
+        let dir = Path::new("out");
+        let mut file_names = HashSet::new();
+        let mut path = |table_name| table_file_path(dir, table_name, "csv", &mut file_names);
+        assert_eq!(path("A B"), dir.join("A_B.csv"));
+        assert_eq!(path("A_B"), dir.join("A_B_2.csv"));
+        assert_eq!(path("a/b"), dir.join("a_b_3.csv"));
+        assert_eq!(path("A_B_2"), dir.join("A_B_2_2.csv"));
+        assert_eq!(path("../x"), dir.join("_x.csv"));
+    }
+
+    #[tokio::test]
+    async fn text_columns_compare_with_the_collation() {
+        let dir = TestDir::new("sink-collation");
+        let export_dir = dir.write_export("export", EXPORT_FILES);
+        for (collation, matches) in [
+            (Collation::Nocase, 2),
+            (Collation::Binary, 0),
+            (Collation::Rtrim, 0),
+        ] {
+            let db_url = dir.db_url(&format!("{}.db", collation.as_sql()));
+            let options = ImportOptions {
+                collation,
+                ..ImportOptions::defaults()
+            };
+            testing::import(&export_dir, &db_url, &options)
+                .await
+                .unwrap();
+            let db = SqlitePool::connect(&db_url).await.unwrap();
+            let count: i64 = sqlx::query_scalar(
+                "SELECT COUNT(*) FROM HKQuantityTypeIdentifierHeartRate WHERE sourceName = 'WATCH'",
+            )
+            .fetch_one(&db)
+            .await
+            .unwrap();
+            assert_eq!(count, matches, "{:?}", collation);
+            let count: i64 = sqlx::query_scalar(
+                "SELECT COUNT(*) FROM HKQuantityTypeIdentifierHeartRate WHERE sourceName = 'Watch  '",
+            )
+            .fetch_one(&db)
+            .await
+            .unwrap();
+            assert_eq!(count, if collation == Collation::Rtrim { 2 } else { 0 });
+        }
+    }
+
+    #[tokio::test]
+    async fn databases_are_analyzed_when_asked() {
+        let dir = TestDir::new("analyze");
+        let export_dir = dir.write_export("export", EXPORT_FILES);
+        for analyze in [false, true] {
+            let db_url = dir.db_url(&format!("analyze_{}.db", analyze));
+            let options = ImportOptions {
+                analyze,
+                ..ImportOptions::defaults()
+            };
+            testing::import(&export_dir, &db_url, &options)
+                .await
+                .unwrap();
+            let db = SqlitePool::connect(&db_url).await.unwrap();
+            let statistics: i64 = sqlx::query_scalar(
+                "SELECT COUNT(*) FROM sqlite_master WHERE name = 'sqlite_stat1'",
+            )
+            .fetch_one(&db)
+            .await
+            .unwrap();
+            assert_eq!(statistics == 1, analyze);
+        }
+    }
+}
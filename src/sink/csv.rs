@@ -0,0 +1,155 @@
+use super::table_file_path;
+use crate::core::{DatabaseRow, HKTables};
+use crate::options::ensure_destructive_allowed;
+use log::*;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+/// Writes each table to `<table>.csv` inside a directory
+pub struct CsvSink {
+    dir: PathBuf,
+    no_destructive: bool,
+    tables: HashMap<String, CsvTable>,
+    /// The file names of the tables, see table_file_path
+    file_names: HashSet<String>,
+}
+
+struct CsvTable {
+    writer: ::csv::Writer<File>,
+    /// Maps each column name to its position in the header
+    columns: HashMap<String, usize>,
+    row_count: i64,
+    /// Columns whose values were dropped, warned about once each
+    dropped_columns: HashSet<String>,
+}
+
+impl CsvSink {
+    pub fn create(dir: &Path, no_destructive: bool) -> anyhow::Result<Self> {
+        std::fs::create_dir_all(dir)?;
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            no_destructive,
+            tables: Default::default(),
+            file_names: Default::default(),
+        })
+    }
+
+    pub fn create_tables(&mut self, tables: &HKTables) -> anyhow::Result<()> {
+        for (name, columns) in tables {
+            let path = table_file_path(&self.dir, name, "csv", &mut self.file_names);
+            if path.exists() {
+                ensure_destructive_allowed(
+                    self.no_destructive,
+                    format_args!("overwrite \"{}\"", path.display()),
+                )?;
+            }
+            let mut writer = ::csv::Writer::from_path(path)?;
+            writer.write_record(columns.keys())?;
+            self.tables.insert(
+                name.clone(),
+                CsvTable {
+                    writer,
+                    columns: columns
+                        .keys()
+                        .enumerate()
+                        .map(|(i, name)| (name.clone(), i))
+                        .collect(),
+                    row_count: 0,
+                    dropped_columns: Default::default(),
+                },
+            );
+        }
+        Ok(())
+    }
+
+    pub fn insert(&mut self, table_name: &str, row: DatabaseRow) -> anyhow::Result<Option<i64>> {
+        let table = match self.tables.get_mut(table_name) {
+            Some(table) => table,
+            None => anyhow::bail!("No CSV file was created for table {}", table_name),
+        };
+        let mut record = vec![String::new(); table.columns.len()];
+        for (name, value) in row {
+            match table.columns.get(&name) {
+                Some(i) => record[*i] = value.to_text(),
+                None => {
+                    if table.dropped_columns.insert(name.clone()) {
+                        warn!(
+                            "Dropping the values of column {} missing from {}.csv",
+                            name, table_name
+                        );
+                    }
+                }
+            }
+        }
+        table.writer.write_record(&record)?;
+        table.row_count += 1;
+        Ok(Some(table.row_count))
+    }
+
+    pub fn finish(self) -> anyhow::Result<()> {
+        for (_, mut table) in self.tables {
+            table.writer.flush()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::DatabaseValue;
+    use crate::testing::TestDir;
+
+    fn tables() -> HKTables {
+        HKTables::from([(
+            "Heart Rate".to_string(),
+            [("startDate", "DATE"), ("value", "REAL")]
+                .into_iter()
+                .map(|(name, ty)| (name.to_string(), ty))
+                .collect(),
+        )])
+    }
+
+    #[test]
+    fn rows_are_written_in_the_order_of_the_header() {
+        let dir = TestDir::new("csv-rows");
+        let mut sink = CsvSink::create(dir.path(), false).unwrap();
+        sink.create_tables(&tables()).unwrap();
+        let row = vec![
+            ("value".to_string(), DatabaseValue::Real(62.5)),
+            ("unknown".to_string(), DatabaseValue::Integer(1)),
+            (
+                "startDate".to_string(),
+                DatabaseValue::Text("2023-01-01 08:00:00".to_string()),
+            ),
+        ];
+        assert_eq!(sink.insert("Heart Rate", row).unwrap(), Some(1));
+        let row = vec![("value".to_string(), DatabaseValue::Integer(70))];
+        assert_eq!(sink.insert("Heart Rate", row).unwrap(), Some(2));
+        assert!(sink.insert("Workout", vec![]).is_err());
+        sink.finish().unwrap();
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join("Heart_Rate.csv")).unwrap(),
+            "startDate,value\n2023-01-01 08:00:00,62.5\n,70\n"
+        );
+    }
+
+    #[test]
+    fn files_are_only_overwritten_when_destructive() {
+        let dir = TestDir::new("csv-overwrite");
+        CsvSink::create(dir.path(), false)
+            .unwrap()
+            .create_tables(&tables())
+            .unwrap();
+        let error = CsvSink::create(dir.path(), true)
+            .unwrap()
+            .create_tables(&tables())
+            .unwrap_err();
+        assert!(error.to_string().contains("--no-destructive"), "{}", error);
+        CsvSink::create(dir.path(), false)
+            .unwrap()
+            .create_tables(&tables())
+            .unwrap();
+    }
+}
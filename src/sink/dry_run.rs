@@ -0,0 +1,94 @@
+use crate::core::{DatabaseRow, HKTables};
+use crate::schema;
+use console::Term;
+use std::collections::BTreeMap;
+
+/// Writes nothing, printing the tables and row counts an import would create instead
+#[derive(Default)]
+pub struct DryRunSink {
+    tables: BTreeMap<String, DryRunTable>,
+}
+
+#[derive(Default)]
+struct DryRunTable {
+    columns: BTreeMap<String, &'static str>,
+    row_count: i64,
+}
+
+impl DryRunSink {
+    pub fn create_tables(&mut self, tables: &HKTables) -> anyhow::Result<()> {
+        for (name, columns) in tables {
+            let table = self.tables.entry(name.clone()).or_default();
+            table
+                .columns
+                .extend(columns.iter().map(|(name, ty)| (name.clone(), *ty)));
+        }
+        Ok(())
+    }
+
+    pub fn clear_table(&mut self, table_name: &str) {
+        if let Some(table) = self.tables.get_mut(table_name) {
+            table.row_count = 0;
+        }
+    }
+
+    pub fn insert(&mut self, table_name: &str, _row: DatabaseRow) -> anyhow::Result<Option<i64>> {
+        let table = match self.tables.get_mut(table_name) {
+            Some(table) => table,
+            None => anyhow::bail!("No table was created for {}", table_name),
+        };
+        table.row_count += 1;
+        Ok(Some(table.row_count))
+    }
+
+    /// Prints each table with its row count and its columns in the order SQLite would have them
+    pub fn finish(self) -> anyhow::Result<()> {
+        Term::stdout().write_str(&self.to_text())?;
+        Ok(())
+    }
+
+    /// Lists each table with its row count and its columns, followed by the totals
+    fn to_text(&self) -> String {
+        let mut text = String::new();
+        for (name, table) in &self.tables {
+            text.push_str(&format!("{} ({} rows)\n", name, table.row_count));
+            for (column_name, ty) in schema::ordered_columns(&table.columns) {
+                text.push_str(&format!("  {} {}\n", column_name, ty));
+            }
+        }
+        let row_count: i64 = self.tables.values().map(|table| table.row_count).sum();
+        text.push_str(&format!(
+            "{} tables, {} rows\n",
+            self.tables.len(),
+            row_count
+        ));
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rows_are_counted_per_table() {
+        let mut sink = DryRunSink::default();
+        sink.create_tables(&HKTables::from([(
+            "Workout".to_string(),
+            BTreeMap::from([
+                ("startDate".to_string(), "DATE"),
+                ("duration".to_string(), "REAL"),
+            ]),
+        )]))
+        .unwrap();
+        assert_eq!(sink.insert("Workout", Vec::new()).unwrap(), Some(1));
+        assert_eq!(sink.insert("Workout", Vec::new()).unwrap(), Some(2));
+        sink.clear_table("Workout");
+        assert_eq!(sink.insert("Workout", Vec::new()).unwrap(), Some(1));
+        assert!(sink.insert("Record", Vec::new()).is_err());
+        assert_eq!(
+            sink.to_text(),
+            "Workout (1 rows)\n  duration REAL\n  startDate DATE\n1 tables, 1 rows\n"
+        );
+    }
+}
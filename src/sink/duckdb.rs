@@ -0,0 +1,165 @@
+use super::{missing_columns, Backend};
+use crate::core::{DatabaseRow, DatabaseValue, HKTables};
+use duckdb::types::Value;
+use duckdb::Connection;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+/// Writes the data to a DuckDB database inside a single transaction
+pub struct DuckDbSink {
+    conn: Connection,
+    /// Number of rows inserted to each table, used as the id of the rows
+    row_counts: HashMap<String, i64>,
+    /// Columns of each table created, to add the columns of rows the schema pass didn't find
+    columns: HashMap<String, HashSet<String>>,
+}
+
+impl DuckDbSink {
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch("BEGIN TRANSACTION")?;
+        Ok(Self {
+            conn,
+            row_counts: Default::default(),
+            columns: Default::default(),
+        })
+    }
+
+    pub fn create_tables(&mut self, tables: &HKTables) -> anyhow::Result<()> {
+        let backend = Backend::DuckDb;
+        for (name, columns) in tables {
+            let qs = format!(
+                "CREATE TABLE IF NOT EXISTS {} ({})",
+                backend.quote_identifier(name),
+                columns
+                    .iter()
+                    .map(|(name, ty)| format!(
+                        "{} {}",
+                        backend.quote_identifier(name),
+                        backend.column_type(ty)
+                    ))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+            self.conn.execute_batch(&qs)?;
+            self.columns
+                .entry(name.clone())
+                .or_default()
+                .extend(columns.keys().cloned());
+        }
+        Ok(())
+    }
+
+    pub fn clear_table(&mut self, table_name: &str) -> anyhow::Result<()> {
+        let qs = format!(
+            "DELETE FROM {}",
+            Backend::DuckDb.quote_identifier(table_name)
+        );
+        self.conn.execute_batch(&qs)?;
+        Ok(())
+    }
+
+    pub fn insert(&mut self, table_name: &str, row: DatabaseRow) -> anyhow::Result<Option<i64>> {
+        let backend = Backend::DuckDb;
+        if let Some(columns) = self.columns.get_mut(table_name) {
+            for (column_name, ty) in missing_columns(table_name, columns, &row) {
+                self.conn.execute_batch(&format!(
+                    "ALTER TABLE {} ADD COLUMN {} {}",
+                    backend.quote_identifier(table_name),
+                    backend.quote_identifier(&column_name),
+                    backend.column_type(ty)
+                ))?;
+            }
+        }
+        let qs = format!(
+            "INSERT INTO {} ({}) VALUES ({})",
+            backend.quote_identifier(table_name),
+            row.iter()
+                .map(|(name, _)| backend.quote_identifier(name))
+                .collect::<Vec<_>>()
+                .join(", "),
+            row.iter().map(|_| "?").collect::<Vec<_>>().join(", ")
+        );
+        let mut statement = self.conn.prepare_cached(&qs)?;
+        statement.execute(duckdb::params_from_iter(
+            row.into_iter()
+                .map(|(_, value)| duckdb_value_from_database_value(value)),
+        ))?;
+        let row_count = self.row_counts.entry(table_name.to_string()).or_default();
+        *row_count += 1;
+        Ok(Some(*row_count))
+    }
+
+    pub fn finish(self) -> anyhow::Result<()> {
+        self.conn.execute_batch("COMMIT")?;
+        Ok(())
+    }
+}
+
+/// Dates are passed as RFC 3339 text, which DuckDB casts to TIMESTAMPTZ while keeping the offset
+fn duckdb_value_from_database_value(value: DatabaseValue) -> Value {
+    match value {
+        DatabaseValue::Integer(i) => Value::BigInt(i),
+        DatabaseValue::Real(i) => Value::Double(i),
+        DatabaseValue::Blob(b) => Value::Blob(b),
+        other => Value::Text(other.to_text()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestDir;
+    use time::macros::datetime;
+
+    #[test]
+    fn rows_are_committed_with_the_columns_found_while_writing() {
+        let dir = TestDir::new("duckdb-rows");
+        let path = dir.path().join("health.duckdb");
+        let mut sink = DuckDbSink::open(&path).unwrap();
+        sink.create_tables(&HKTables::from([(
+            "Heart Rate".to_string(),
+            [("startDate", "DATE"), ("value", "REAL")]
+                .into_iter()
+                .map(|(name, ty)| (name.to_string(), ty))
+                .collect(),
+        )]))
+        .unwrap();
+        let row = vec![
+            (
+                "startDate".to_string(),
+                DatabaseValue::OffsetDateTime(datetime!(2023-01-01 08:00:00 -8)),
+            ),
+            ("value".to_string(), DatabaseValue::Integer(62)),
+        ];
+        assert_eq!(sink.insert("Heart Rate", row).unwrap(), Some(1));
+        let row = vec![
+            ("value".to_string(), DatabaseValue::Real(71.5)),
+            (
+                "unit".to_string(),
+                DatabaseValue::Text("count/min".to_string()),
+            ),
+        ];
+        assert_eq!(sink.insert("Heart Rate", row).unwrap(), Some(2));
+        sink.finish().unwrap();
+
+        let conn = Connection::open(&path).unwrap();
+        let (count, total, unit): (i64, f64, String) = conn
+            .query_row(
+                r#"SELECT COUNT(*), SUM(value), MAX(unit) FROM "Heart Rate""#,
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .unwrap();
+        assert_eq!((count, total, unit.as_str()), (2, 133.5, "count/min"));
+        // The offset of the date is kept, so it is 16:00 UTC
+        let start: i64 = conn
+            .query_row(
+                r#"SELECT CAST(epoch(startDate) AS BIGINT) FROM "Heart Rate" WHERE startDate IS NOT NULL"#,
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(start, 1672588800);
+    }
+}
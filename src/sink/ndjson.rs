@@ -0,0 +1,191 @@
+use super::table_file_path;
+use crate::core::{DatabaseRow, HKTables};
+use crate::options::ensure_destructive_allowed;
+use serde_json::{Map, Value as JsonValue};
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufWriter, Stdout, Write};
+use std::path::{Path, PathBuf};
+
+/// Field naming the table of each row written to the single stream
+const TABLE_FIELD: &str = "table";
+
+/// Writes each row as a line of JSON, either to `<table>.ndjson` inside a directory or to a
+/// single stream on stdout with the table name in a `table` field
+pub struct NdjsonSink {
+    target: NdjsonTarget,
+    no_destructive: bool,
+    /// Number of rows written to each table, the ids of the rows
+    row_counts: HashMap<String, i64>,
+}
+
+enum NdjsonTarget {
+    Directory {
+        dir: PathBuf,
+        writers: HashMap<String, BufWriter<File>>,
+        /// The file names of the tables, see table_file_path
+        file_names: HashSet<String>,
+    },
+    Stdout(BufWriter<Stdout>),
+}
+
+impl NdjsonSink {
+    /// Opens the directory, or stdout if the path is `-`
+    pub fn create(path: &Path, no_destructive: bool) -> anyhow::Result<Self> {
+        let target = if path == Path::new("-") {
+            NdjsonTarget::Stdout(BufWriter::new(std::io::stdout()))
+        } else {
+            std::fs::create_dir_all(path)?;
+            NdjsonTarget::Directory {
+                dir: path.to_path_buf(),
+                writers: Default::default(),
+                file_names: Default::default(),
+            }
+        };
+        Ok(Self {
+            target,
+            no_destructive,
+            row_counts: Default::default(),
+        })
+    }
+
+    pub fn create_tables(&mut self, tables: &HKTables) -> anyhow::Result<()> {
+        for name in tables.keys() {
+            if let NdjsonTarget::Directory {
+                dir,
+                writers,
+                file_names,
+            } = &mut self.target
+            {
+                if writers.contains_key(name) {
+                    continue;
+                }
+                let path = table_file_path(dir, name, "ndjson", file_names);
+                if path.exists() {
+                    ensure_destructive_allowed(
+                        self.no_destructive,
+                        format_args!("overwrite \"{}\"", path.display()),
+                    )?;
+                }
+                writers.insert(name.clone(), BufWriter::new(File::create(path)?));
+            }
+            self.row_counts.entry(name.clone()).or_default();
+        }
+        Ok(())
+    }
+
+    pub fn insert(&mut self, table_name: &str, row: DatabaseRow) -> anyhow::Result<Option<i64>> {
+        let row_count = match self.row_counts.get_mut(table_name) {
+            Some(row_count) => row_count,
+            None => anyhow::bail!("No NDJSON output was created for table {}", table_name),
+        };
+        let mut object: Map<String, JsonValue> = row
+            .into_iter()
+            .map(|(name, value)| (name, value.to_json()))
+            .collect();
+        let writer: &mut dyn Write = match &mut self.target {
+            NdjsonTarget::Directory { writers, .. } => writers
+                .get_mut(table_name)
+                .expect("created with the row count"),
+            NdjsonTarget::Stdout(writer) => {
+                object.insert(
+                    TABLE_FIELD.to_string(),
+                    JsonValue::String(table_name.to_string()),
+                );
+                writer
+            }
+        };
+        serde_json::to_writer(&mut *writer, &object)?;
+        writer.write_all(b"\n")?;
+        *row_count += 1;
+        Ok(Some(*row_count))
+    }
+
+    pub fn finish(self) -> anyhow::Result<()> {
+        match self.target {
+            NdjsonTarget::Directory { writers, .. } => {
+                for (_, mut writer) in writers {
+                    writer.flush()?;
+                }
+            }
+            NdjsonTarget::Stdout(mut writer) => writer.flush()?,
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::DatabaseValue;
+    use crate::testing::TestDir;
+
+    fn tables() -> HKTables {
+        HKTables::from([
+            (
+                "Heart Rate".to_string(),
+                [("startDate", "DATE"), ("value", "REAL")]
+                    .into_iter()
+                    .map(|(name, ty)| (name.to_string(), ty))
+                    .collect(),
+            ),
+            ("Heart_Rate".to_string(), Default::default()),
+        ])
+    }
+
+    #[test]
+    fn rows_are_written_as_a_json_object_per_line() {
+        let dir = TestDir::new("ndjson-rows");
+        let mut sink = NdjsonSink::create(dir.path(), false).unwrap();
+        sink.create_tables(&tables()).unwrap();
+        let row = vec![
+            (
+                "startDate".to_string(),
+                DatabaseValue::Text("2023-01-01 08:00:00".to_string()),
+            ),
+            ("value".to_string(), DatabaseValue::Real(62.5)),
+            (
+                "metadata".to_string(),
+                DatabaseValue::Json(serde_json::json!({ "HKTimeZone": "Europe/Paris" })),
+            ),
+        ];
+        assert_eq!(sink.insert("Heart Rate", row).unwrap(), Some(1));
+        let row = vec![("value".to_string(), DatabaseValue::Integer(70))];
+        assert_eq!(sink.insert("Heart Rate", row).unwrap(), Some(2));
+        assert_eq!(sink.insert("Heart_Rate", vec![]).unwrap(), Some(1));
+        assert!(sink.insert("Workout", vec![]).is_err());
+        sink.finish().unwrap();
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join("Heart_Rate.ndjson")).unwrap(),
+            concat!(
+                r#"{"metadata":{"HKTimeZone":"Europe/Paris"},"startDate":"2023-01-01 08:00:00","value":62.5}"#,
+                "\n",
+                r#"{"value":70}"#,
+                "\n"
+            )
+        );
+        // The table whose file name collides with the first one's gets a numbered file
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join("Heart_Rate_2.ndjson")).unwrap(),
+            "{}\n"
+        );
+    }
+
+    #[test]
+    fn files_are_only_overwritten_when_destructive() {
+        let dir = TestDir::new("ndjson-overwrite");
+        NdjsonSink::create(dir.path(), false)
+            .unwrap()
+            .create_tables(&tables())
+            .unwrap();
+        let error = NdjsonSink::create(dir.path(), true)
+            .unwrap()
+            .create_tables(&tables())
+            .unwrap_err();
+        assert!(error.to_string().contains("--no-destructive"), "{}", error);
+        NdjsonSink::create(dir.path(), false)
+            .unwrap()
+            .create_tables(&tables())
+            .unwrap();
+    }
+}
@@ -0,0 +1,261 @@
+use super::table_file_path;
+use crate::core::{DatabaseRow, DatabaseValue, HKTables};
+use crate::options::ensure_destructive_allowed;
+use arrow::array::{ArrayRef, Float64Array, Int64Array, StringArray, TimestampMicrosecondArray};
+use arrow::datatypes::{DataType, Field, Schema, SchemaRef, TimeUnit};
+use arrow::record_batch::RecordBatch;
+use log::*;
+use parquet::arrow::ArrowWriter;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use time::{OffsetDateTime, Time};
+
+/// Number of rows buffered per table before they are written as a record batch
+const BATCH_SIZE: usize = 8192;
+
+/// Writes each table to `<table>.parquet` inside a directory
+pub struct ParquetSink {
+    dir: PathBuf,
+    no_destructive: bool,
+    tables: HashMap<String, ParquetTable>,
+    /// The file names of the tables, see table_file_path
+    file_names: HashSet<String>,
+}
+
+struct ParquetTable {
+    writer: ArrowWriter<File>,
+    schema: SchemaRef,
+    /// Maps each column name to its position in the schema
+    columns: HashMap<String, usize>,
+    /// Buffered rows, with one slot per schema column
+    rows: Vec<Vec<Option<DatabaseValue>>>,
+    row_count: i64,
+    /// Columns whose values were dropped, warned about once each
+    dropped_columns: HashSet<String>,
+}
+
+impl ParquetSink {
+    pub fn create(dir: &Path, no_destructive: bool) -> anyhow::Result<Self> {
+        std::fs::create_dir_all(dir)?;
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            no_destructive,
+            tables: Default::default(),
+            file_names: Default::default(),
+        })
+    }
+
+    pub fn create_tables(&mut self, tables: &HKTables) -> anyhow::Result<()> {
+        for (name, columns) in tables {
+            let fields = columns
+                .iter()
+                .map(|(name, ty)| Field::new(name, arrow_type_from_sql_type(ty), true))
+                .collect::<Vec<_>>();
+            let schema = Arc::new(Schema::new(fields));
+            let path = table_file_path(&self.dir, name, "parquet", &mut self.file_names);
+            if path.exists() {
+                ensure_destructive_allowed(
+                    self.no_destructive,
+                    format_args!("overwrite \"{}\"", path.display()),
+                )?;
+            }
+            let file = File::create(path)?;
+            let writer = ArrowWriter::try_new(file, schema.clone(), None)?;
+            self.tables.insert(
+                name.clone(),
+                ParquetTable {
+                    writer,
+                    schema,
+                    columns: columns
+                        .keys()
+                        .enumerate()
+                        .map(|(i, name)| (name.clone(), i))
+                        .collect(),
+                    rows: Vec::with_capacity(BATCH_SIZE),
+                    row_count: 0,
+                    dropped_columns: Default::default(),
+                },
+            );
+        }
+        Ok(())
+    }
+
+    pub fn insert(&mut self, table_name: &str, row: DatabaseRow) -> anyhow::Result<Option<i64>> {
+        let table = match self.tables.get_mut(table_name) {
+            Some(table) => table,
+            None => anyhow::bail!("No Parquet file was created for table {}", table_name),
+        };
+        let mut record = vec![None; table.columns.len()];
+        for (name, value) in row {
+            match table.columns.get(&name) {
+                Some(i) => record[*i] = Some(value),
+                None => {
+                    if table.dropped_columns.insert(name.clone()) {
+                        warn!(
+                            "Dropping the values of column {} missing from {}.parquet",
+                            name, table_name
+                        );
+                    }
+                }
+            }
+        }
+        table.rows.push(record);
+        table.row_count += 1;
+        if table.rows.len() >= BATCH_SIZE {
+            table.flush()?;
+        }
+        Ok(Some(table.row_count))
+    }
+
+    pub fn finish(self) -> anyhow::Result<()> {
+        for (_, mut table) in self.tables {
+            table.flush()?;
+            table.writer.close()?;
+        }
+        Ok(())
+    }
+}
+
+impl ParquetTable {
+    /// Writes the buffered rows as a single record batch
+    fn flush(&mut self) -> anyhow::Result<()> {
+        if self.rows.is_empty() {
+            return Ok(());
+        }
+        let rows = std::mem::take(&mut self.rows);
+        let arrays = self
+            .schema
+            .fields()
+            .iter()
+            .enumerate()
+            .map(|(i, field)| -> ArrayRef {
+                let values = rows.iter().map(|row| row[i].as_ref());
+                match field.data_type() {
+                    DataType::Int64 => Arc::new(
+                        values
+                            .map(|value| value.and_then(integer_from_database_value))
+                            .collect::<Int64Array>(),
+                    ),
+                    DataType::Float64 => Arc::new(
+                        values
+                            .map(|value| value.and_then(real_from_database_value))
+                            .collect::<Float64Array>(),
+                    ),
+                    DataType::Timestamp(_, _) => Arc::new(
+                        values
+                            .map(|value| value.and_then(timestamp_from_database_value))
+                            .collect::<TimestampMicrosecondArray>(),
+                    ),
+                    _ => Arc::new(
+                        values
+                            .map(|value| value.map(DatabaseValue::to_text))
+                            .collect::<StringArray>(),
+                    ),
+                }
+            })
+            .collect::<Vec<_>>();
+        let batch = RecordBatch::try_new(self.schema.clone(), arrays)?;
+        self.writer.write(&batch)?;
+        self.rows = Vec::with_capacity(BATCH_SIZE);
+        Ok(())
+    }
+}
+
+/// Maps the SQL column types inferred from the export onto Arrow types.
+/// Dates are stored as UTC timestamps.
+fn arrow_type_from_sql_type(ty: &str) -> DataType {
+    match ty {
+        "INTEGER" => DataType::Int64,
+        "REAL" => DataType::Float64,
+        "DATE" => DataType::Timestamp(TimeUnit::Microsecond, None),
+        _ => DataType::Utf8,
+    }
+}
+
+fn integer_from_database_value(value: &DatabaseValue) -> Option<i64> {
+    match value {
+        DatabaseValue::Integer(i) => Some(*i),
+        _ => None,
+    }
+}
+
+fn real_from_database_value(value: &DatabaseValue) -> Option<f64> {
+    match value {
+        DatabaseValue::Integer(i) => Some(*i as f64),
+        DatabaseValue::Real(r) => Some(*r),
+        _ => None,
+    }
+}
+
+fn timestamp_from_database_value(value: &DatabaseValue) -> Option<i64> {
+    let date_time = match value {
+        DatabaseValue::OffsetDateTime(d) => *d,
+        DatabaseValue::Date(d) => d.with_time(Time::MIDNIGHT).assume_utc(),
+        _ => return None,
+    };
+    Some((date_time - OffsetDateTime::UNIX_EPOCH).whole_microseconds() as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestDir;
+    use time::macros::{date, datetime};
+
+    #[test]
+    fn values_are_converted_to_their_column_type() {
+        assert_eq!(arrow_type_from_sql_type("INTEGER"), DataType::Int64);
+        assert_eq!(arrow_type_from_sql_type("REAL"), DataType::Float64);
+        assert_eq!(arrow_type_from_sql_type("JSON"), DataType::Utf8);
+        assert_eq!(
+            real_from_database_value(&DatabaseValue::Integer(62)),
+            Some(62.0)
+        );
+        assert_eq!(
+            integer_from_database_value(&DatabaseValue::Real(62.5)),
+            None
+        );
+        assert_eq!(
+            timestamp_from_database_value(&DatabaseValue::OffsetDateTime(
+                datetime!(1970-01-01 00:00:01 -1)
+            )),
+            Some(3_601_000_000)
+        );
+        assert_eq!(
+            timestamp_from_database_value(&DatabaseValue::Date(date!(1970 - 01 - 02))),
+            Some(86_400_000_000)
+        );
+        assert_eq!(
+            timestamp_from_database_value(&DatabaseValue::Text("yesterday".to_string())),
+            None
+        );
+    }
+
+    #[test]
+    fn tables_are_written_as_parquet_files() {
+        let dir = TestDir::new("parquet-tables");
+        let tables = HKTables::from([(
+            "Heart Rate".to_string(),
+            [("startDate", "DATE"), ("value", "REAL")]
+                .into_iter()
+                .map(|(name, ty)| (name.to_string(), ty))
+                .collect(),
+        )]);
+        let mut sink = ParquetSink::create(dir.path(), false).unwrap();
+        sink.create_tables(&tables).unwrap();
+        for value in 0..BATCH_SIZE + 1 {
+            let row = vec![("value".to_string(), DatabaseValue::Integer(value as i64))];
+            sink.insert("Heart Rate", row).unwrap();
+        }
+        assert!(sink.insert("Workout", vec![]).is_err());
+        sink.finish().unwrap();
+        let contents = std::fs::read(dir.path().join("Heart_Rate.parquet")).unwrap();
+        assert!(contents.starts_with(b"PAR1") && contents.ends_with(b"PAR1"));
+        assert!(ParquetSink::create(dir.path(), true)
+            .unwrap()
+            .create_tables(&tables)
+            .is_err());
+    }
+}
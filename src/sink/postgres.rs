@@ -0,0 +1,226 @@
+use super::{missing_columns, Backend};
+use crate::core::{DatabaseRow, DatabaseValue, HKTables};
+use log::*;
+use sqlx::migrate::MigrateDatabase;
+use sqlx::postgres::{PgArguments, PgPool};
+use sqlx::query::Query;
+use sqlx::{Postgres, Transaction};
+use std::collections::{HashMap, HashSet};
+use time::Time;
+
+/// Writes the data to a PostgreSQL database inside a single transaction
+pub struct PostgresSink {
+    db: PgPool,
+    tx: Transaction<'static, Postgres>,
+    /// Column types of each created table, used to coerce values Postgres won't cast implicitly
+    tables: HKTables,
+    /// Number of rows inserted to each table, used as the id of the rows
+    row_counts: HashMap<String, i64>,
+}
+
+impl PostgresSink {
+    pub async fn open(db_url: &str) -> anyhow::Result<Self> {
+        if !Postgres::database_exists(db_url).await? {
+            Postgres::create_database(db_url).await?;
+        }
+        let db = PgPool::connect(db_url).await?;
+        let tx = db.begin().await?;
+        Ok(Self {
+            db,
+            tx,
+            tables: Default::default(),
+            row_counts: Default::default(),
+        })
+    }
+
+    pub async fn create_tables(&mut self, tables: &HKTables) -> anyhow::Result<()> {
+        let backend = Backend::Postgres;
+        for (name, columns) in tables {
+            let qs = format!(
+                "CREATE TABLE IF NOT EXISTS {} ({})",
+                backend.quote_identifier(name),
+                columns
+                    .iter()
+                    .map(|(name, ty)| format!(
+                        "{} {}",
+                        backend.quote_identifier(name),
+                        backend.column_type(ty)
+                    ))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+            sqlx::query(&qs).execute(&mut self.tx).await?;
+        }
+        self.tables.extend(tables.clone());
+        let tx = std::mem::replace(&mut self.tx, self.db.begin().await?);
+        tx.commit().await?;
+        Ok(())
+    }
+
+    pub async fn clear_table(&mut self, table_name: &str) -> anyhow::Result<()> {
+        let qs = format!(
+            "DELETE FROM {}",
+            Backend::Postgres.quote_identifier(table_name)
+        );
+        sqlx::query(&qs).execute(&mut self.tx).await?;
+        Ok(())
+    }
+
+    pub async fn insert(
+        &mut self,
+        table_name: &str,
+        row: DatabaseRow,
+    ) -> anyhow::Result<Option<i64>> {
+        let backend = Backend::Postgres;
+        let columns = self
+            .tables
+            .get_mut(table_name)
+            .filter(|columns| row.iter().any(|(name, _)| !columns.contains_key(name)));
+        if let Some(columns) = columns {
+            let mut column_names: HashSet<String> = columns.keys().cloned().collect();
+            for (column_name, ty) in missing_columns(table_name, &mut column_names, &row) {
+                let qs = format!(
+                    "ALTER TABLE {} ADD COLUMN {} {}",
+                    backend.quote_identifier(table_name),
+                    backend.quote_identifier(&column_name),
+                    backend.column_type(ty)
+                );
+                sqlx::query(&qs).execute(&mut self.tx).await?;
+                columns.insert(column_name, ty);
+            }
+        }
+        let qs = format!(
+            "INSERT INTO {} ({}) VALUES ({})",
+            backend.quote_identifier(table_name),
+            row.iter()
+                .map(|(name, _)| backend.quote_identifier(name))
+                .collect::<Vec<_>>()
+                .join(", "),
+            (1..=row.len())
+                .map(|i| format!("${}", i))
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        let columns = self.tables.get(table_name);
+        let mut query = sqlx::query(&qs);
+        for (name, value) in row {
+            let ty = columns
+                .and_then(|columns| columns.get(&name))
+                .copied()
+                .unwrap_or("TEXT");
+            query = bind_postgres_value(query, ty, value);
+        }
+        query.execute(&mut self.tx).await?;
+        let row_count = self.row_counts.entry(table_name.to_string()).or_default();
+        *row_count += 1;
+        Ok(Some(*row_count))
+    }
+
+    pub async fn finish(self) -> anyhow::Result<()> {
+        self.tx.commit().await?;
+        Ok(())
+    }
+}
+
+/// Binds a value as the type of the column it is inserted into.
+/// SQLite accepts any value in any column, but Postgres rejects parameters that don't match the
+/// column type, so values that can't be represented are inserted as NULL.
+fn bind_postgres_value<'q>(
+    query: Query<'q, Postgres, PgArguments>,
+    ty: &str,
+    value: DatabaseValue,
+) -> Query<'q, Postgres, PgArguments> {
+    match (ty, value) {
+        ("INTEGER", DatabaseValue::Integer(i)) => query.bind(i),
+        ("INTEGER", DatabaseValue::Real(r)) => query.bind(r.round() as i64),
+        ("REAL", DatabaseValue::Integer(i)) => query.bind(i as f64),
+        ("REAL", DatabaseValue::Real(r)) => query.bind(r),
+        ("DATE", DatabaseValue::OffsetDateTime(d)) => query.bind(d),
+        ("DATE", DatabaseValue::Date(d)) => query.bind(d.with_time(Time::MIDNIGHT).assume_utc()),
+        ("JSON", DatabaseValue::Json(j)) => query.bind(j),
+        ("BLOB", DatabaseValue::Blob(b)) => query.bind(b),
+        ("TEXT", value) => query.bind(value.to_text()),
+        (ty, value) => {
+            debug!("Inserting NULL for {:?} in a {} column", value, ty);
+            match ty {
+                "INTEGER" => query.bind(None::<i64>),
+                "REAL" => query.bind(None::<f64>),
+                "DATE" => query.bind(None::<time::OffsetDateTime>),
+                "JSON" => query.bind(None::<sqlx::types::JsonValue>),
+                "BLOB" => query.bind(None::<Vec<u8>>),
+                _ => query.bind(None::<String>),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn columns_are_translated_to_postgres_types() {
+        let backend = Backend::Postgres;
+        assert_eq!(backend.column_type("DATE"), "TIMESTAMPTZ");
+        assert_eq!(backend.column_type("JSON"), "JSONB");
+        assert_eq!(backend.column_type("BLOB"), "BYTEA");
+        assert_eq!(backend.column_type("INTEGER"), "BIGINT");
+        assert_eq!(backend.column_type("REAL"), "DOUBLE PRECISION");
+        assert_eq!(backend.column_type("TEXT"), "TEXT");
+        assert_eq!(backend.quote_identifier(r#"a "b""#), r#""a ""b""""#);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a PostgreSQL server, given by HEALTHKIT_TO_SQLITE_TEST_POSTGRES_URL"]
+    async fn rows_are_coerced_to_their_column_types() {
+        let db_url = std::env::var("HEALTHKIT_TO_SQLITE_TEST_POSTGRES_URL").unwrap();
+        let table_name = format!("Heart Rate {}", std::process::id());
+        let mut sink = PostgresSink::open(&db_url).await.unwrap();
+        sink.create_tables(&HKTables::from([(
+            table_name.clone(),
+            [("count", "INTEGER"), ("value", "REAL"), ("unit", "TEXT")]
+                .into_iter()
+                .map(|(name, ty)| (name.to_string(), ty))
+                .collect(),
+        )]))
+        .await
+        .unwrap();
+        let row = vec![
+            ("count".to_string(), DatabaseValue::Real(2.6)),
+            ("value".to_string(), DatabaseValue::Integer(62)),
+            ("unit".to_string(), DatabaseValue::Integer(1)),
+            ("note".to_string(), DatabaseValue::Text("added".to_string())),
+        ];
+        assert_eq!(sink.insert(&table_name, row).await.unwrap(), Some(1));
+        let row = vec![("count".to_string(), DatabaseValue::Text("many".to_string()))];
+        assert_eq!(sink.insert(&table_name, row).await.unwrap(), Some(2));
+        sink.finish().await.unwrap();
+
+        let db = PgPool::connect(&db_url).await.unwrap();
+        let quoted = Backend::Postgres.quote_identifier(&table_name);
+        type Values = (Option<i64>, Option<f64>, Option<String>, Option<String>);
+        let values: Vec<Values> = sqlx::query_as(&format!(
+            "SELECT count, value, unit, note FROM {} ORDER BY count NULLS LAST",
+            quoted
+        ))
+        .fetch_all(&db)
+        .await
+        .unwrap();
+        sqlx::query(&format!("DROP TABLE {}", quoted))
+            .execute(&db)
+            .await
+            .unwrap();
+        assert_eq!(
+            values,
+            [
+                (
+                    Some(3),
+                    Some(62.0),
+                    Some("1".to_string()),
+                    Some("added".to_string())
+                ),
+                (None, None, None, None),
+            ]
+        );
+    }
+}
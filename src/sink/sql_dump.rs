@@ -0,0 +1,277 @@
+use super::{column_definition, missing_columns};
+use crate::core::{DatabaseRow, DatabaseValue, HKTables};
+use crate::identifier::get_valid_sqlite_identifier;
+use crate::options::{ensure_destructive_allowed, Collation};
+use crate::row_key::ROW_KEY_COLUMN;
+use crate::schema;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+/// Writes an SQL script of the CREATE TABLE and INSERT statements an import into SQLite would
+/// execute, to a file or to stdout if the path is `-`
+pub struct SqlDumpSink {
+    writer: BufWriter<Box<dyn Write>>,
+    collation: Collation,
+    /// Columns of each table created so far
+    tables: HashMap<String, HashSet<String>>,
+    /// Number of rows written to each table, the ids of the rows
+    row_counts: HashMap<String, i64>,
+}
+
+impl SqlDumpSink {
+    pub fn create(path: &Path, no_destructive: bool, collation: Collation) -> anyhow::Result<Self> {
+        let output: Box<dyn Write> = if path == Path::new("-") {
+            Box::new(std::io::stdout())
+        } else {
+            if path.exists() {
+                ensure_destructive_allowed(
+                    no_destructive,
+                    format_args!("overwrite \"{}\"", path.display()),
+                )?;
+            }
+            Box::new(File::create(path)?)
+        };
+        let mut writer = BufWriter::new(output);
+        writeln!(writer, "BEGIN TRANSACTION;")?;
+        Ok(Self {
+            writer,
+            collation,
+            tables: Default::default(),
+            row_counts: Default::default(),
+        })
+    }
+
+    pub fn create_tables(&mut self, tables: &HKTables) -> anyhow::Result<()> {
+        for (name, columns) in tables {
+            match self.tables.get_mut(name) {
+                // Tables are created again by later parts of the import, e.g. with new columns
+                Some(existing_columns) => {
+                    for (column_name, ty) in schema::ordered_columns(columns) {
+                        if existing_columns.insert(column_name.clone()) {
+                            writeln!(
+                                self.writer,
+                                "ALTER TABLE {} ADD COLUMN {};",
+                                get_valid_sqlite_identifier(name),
+                                column_definition(column_name, ty, self.collation)
+                            )?;
+                        }
+                    }
+                }
+                None => {
+                    let definitions = schema::ordered_columns(columns)
+                        .into_iter()
+                        .map(|(column_name, ty)| column_definition(column_name, ty, self.collation))
+                        .collect::<Vec<_>>();
+                    writeln!(
+                        self.writer,
+                        "CREATE TABLE IF NOT EXISTS {} ({});",
+                        get_valid_sqlite_identifier(name),
+                        definitions.join(", ")
+                    )?;
+                    self.tables
+                        .insert(name.clone(), columns.keys().cloned().collect());
+                    self.row_counts.insert(name.clone(), 0);
+                }
+            }
+            if columns.contains_key(ROW_KEY_COLUMN) {
+                writeln!(
+                    self.writer,
+                    "CREATE UNIQUE INDEX IF NOT EXISTS {} ON {} ({});",
+                    get_valid_sqlite_identifier(&format!("{}_{}", name, ROW_KEY_COLUMN)),
+                    get_valid_sqlite_identifier(name),
+                    ROW_KEY_COLUMN
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn clear_table(&mut self, table_name: &str) -> anyhow::Result<()> {
+        writeln!(
+            self.writer,
+            "DELETE FROM {};",
+            get_valid_sqlite_identifier(table_name)
+        )?;
+        Ok(())
+    }
+
+    pub fn insert(&mut self, table_name: &str, row: DatabaseRow) -> anyhow::Result<Option<i64>> {
+        if let Some(columns) = self.tables.get_mut(table_name) {
+            for (column_name, ty) in missing_columns(table_name, columns, &row) {
+                writeln!(
+                    self.writer,
+                    "ALTER TABLE {} ADD COLUMN {};",
+                    get_valid_sqlite_identifier(table_name),
+                    column_definition(&column_name, ty, self.collation)
+                )?;
+            }
+        }
+        let row_count = match self.row_counts.get_mut(table_name) {
+            Some(row_count) => row_count,
+            None => anyhow::bail!("No table was created for {} in the SQL dump", table_name),
+        };
+        // A row whose key is already in the table is the same sample, imported before
+        let keyed = row.iter().any(|(name, _)| name == ROW_KEY_COLUMN);
+        writeln!(
+            self.writer,
+            "INSERT {}INTO {} ({}) VALUES ({});",
+            if keyed { "OR IGNORE " } else { "" },
+            get_valid_sqlite_identifier(table_name),
+            row.iter()
+                .map(|(name, _)| get_valid_sqlite_identifier(name))
+                .collect::<Vec<_>>()
+                .join(", "),
+            row.iter()
+                .map(|(_, value)| sql_literal(value))
+                .collect::<Vec<_>>()
+                .join(", ")
+        )?;
+        *row_count += 1;
+        Ok(Some(*row_count))
+    }
+
+    pub fn finish(mut self) -> anyhow::Result<()> {
+        writeln!(self.writer, "COMMIT;")?;
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Returns the value as an SQL literal, with the same representation as when bound in SQLite
+fn sql_literal(value: &DatabaseValue) -> String {
+    match value {
+        DatabaseValue::Integer(i) => i.to_string(),
+        // Debug formatting keeps enough digits to read back the exact value SQLite would store
+        DatabaseValue::Real(r) if r.is_finite() => format!("{:?}", r),
+        DatabaseValue::Real(_) => "NULL".to_string(),
+        DatabaseValue::Blob(_) => format!("X'{}'", value.to_text()),
+        other => format!("'{}'", other.to_text().replace('\'', "''")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conformance::EXPORT_FILES;
+    use crate::core;
+    use crate::options::ImportOptions;
+    use crate::sink::Output;
+    use crate::testing::{self, TestDir};
+    use indicatif::ProgressBar;
+    use sqlx::{Executor, SqlitePool};
+
+    fn tables(columns: &[(&str, &'static str)]) -> HKTables {
+        HKTables::from([(
+            "Heart Rate".to_string(),
+            columns
+                .iter()
+                .map(|(name, ty)| (name.to_string(), *ty))
+                .collect(),
+        )])
+    }
+
+    #[test]
+    fn values_are_written_as_sqlite_literals() {
+        assert_eq!(
+            sql_literal(&DatabaseValue::Integer(-4000000000)),
+            "-4000000000"
+        );
+        assert_eq!(sql_literal(&DatabaseValue::Real(0.1)), "0.1");
+        assert_eq!(sql_literal(&DatabaseValue::Real(62.0)), "62.0");
+        assert_eq!(sql_literal(&DatabaseValue::Real(f64::NAN)), "NULL");
+        assert_eq!(
+            sql_literal(&DatabaseValue::Text("Jo's Watch".to_string())),
+            "'Jo''s Watch'"
+        );
+        assert_eq!(
+            sql_literal(&DatabaseValue::Blob(vec![0x00, 0xff])),
+            "X'00ff'"
+        );
+    }
+
+    #[test]
+    fn tables_are_created_and_altered_as_columns_appear() {
+        let dir = TestDir::new("sql-dump-statements");
+        let path = dir.path().join("health.sql");
+        let mut sink = SqlDumpSink::create(&path, false, Collation::Binary).unwrap();
+        sink.create_tables(&tables(&[("value", "REAL")])).unwrap();
+        sink.create_tables(&tables(&[
+            ("value", "REAL"),
+            ("unit", "TEXT"),
+            (ROW_KEY_COLUMN, "TEXT"),
+        ]))
+        .unwrap();
+        let row = vec![
+            ("value".to_string(), DatabaseValue::Real(62.5)),
+            (
+                ROW_KEY_COLUMN.to_string(),
+                DatabaseValue::Text("a".to_string()),
+            ),
+        ];
+        assert_eq!(sink.insert("Heart Rate", row).unwrap(), Some(1));
+        let row = vec![(
+            "sourceName".to_string(),
+            DatabaseValue::Text("Watch".to_string()),
+        )];
+        assert_eq!(sink.insert("Heart Rate", row).unwrap(), Some(2));
+        assert!(sink.insert("Workout", vec![]).is_err());
+        sink.finish().unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            concat!(
+                "BEGIN TRANSACTION;\n",
+                "CREATE TABLE IF NOT EXISTS `Heart Rate` (`value` REAL);\n",
+                "ALTER TABLE `Heart Rate` ADD COLUMN `row_key` TEXT COLLATE BINARY;\n",
+                "ALTER TABLE `Heart Rate` ADD COLUMN `unit` TEXT COLLATE BINARY;\n",
+                "CREATE UNIQUE INDEX IF NOT EXISTS `Heart Rate_row_key` ON `Heart Rate` (row_key);\n",
+                "INSERT OR IGNORE INTO `Heart Rate` (`value`, `row_key`) VALUES (62.5, 'a');\n",
+                "ALTER TABLE `Heart Rate` ADD COLUMN `sourceName` TEXT COLLATE BINARY;\n",
+                "INSERT INTO `Heart Rate` (`sourceName`) VALUES ('Watch');\n",
+                "COMMIT;\n",
+            )
+        );
+        let error = SqlDumpSink::create(&path, true, Collation::Binary)
+            .err()
+            .unwrap();
+        assert!(error.to_string().contains("--no-destructive"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn the_dump_replays_into_the_tables_of_an_import() {
+        let dir = TestDir::new("sql-dump-replay");
+        let export_dir = dir.write_export("export", EXPORT_FILES);
+        let path = dir.path().join("health.sql");
+        core::healthkit_to_sqlite(
+            &Output::Sql(path.clone()),
+            &export_dir,
+            &ImportOptions::defaults(),
+            &ProgressBar::hidden(),
+        )
+        .await
+        .unwrap();
+        let replayed_url = dir.db_url("replayed.db");
+        let db = SqlitePool::connect(&format!("{}?mode=rwc", replayed_url))
+            .await
+            .unwrap();
+        db.execute(std::fs::read_to_string(&path).unwrap().as_str())
+            .await
+            .unwrap();
+        let value: f64 = sqlx::query_scalar("SELECT value FROM HKQuantityTypeIdentifierBodyMass")
+            .fetch_one(&db)
+            .await
+            .unwrap();
+        assert_eq!(value, 72.123456789);
+        db.close().await;
+
+        let imported_url = dir.db_url("imported.db");
+        testing::import(&export_dir, &imported_url, &ImportOptions::defaults())
+            .await
+            .unwrap();
+        let imported = testing::row_counts(&imported_url).await;
+        for (table_name, rows) in testing::row_counts(&replayed_url).await {
+            assert_eq!(imported.get(&table_name), Some(&rows), "{}", table_name);
+        }
+    }
+}
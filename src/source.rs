@@ -0,0 +1,662 @@
+//! Locating and reading the files of a HealthKit export
+use log::*;
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::{BufReader, ErrorKind, Read, Seek, SeekFrom};
+use std::path::{Component, Path, PathBuf};
+use std::time::Duration;
+use tokio::runtime::RuntimeFlavor;
+
+/// Name of the directory the Health app places the export in, both inside the zip archive and
+/// when the archive is extracted
+const EXPORT_DIRECTORY_NAME: &str = "apple_health_export";
+const EXPORT_XML_NAME: &str = "export.xml";
+/// Name of the HL7 CDA document exported next to export.xml
+const EXPORT_CDA_XML_NAME: &str = "export_cda.xml";
+pub(crate) const WORKOUT_ROUTES_DIRECTORY_NAME: &str = "workout-routes";
+pub(crate) const ELECTROCARDIOGRAMS_DIRECTORY_NAME: &str = "electrocardiograms";
+pub(crate) const CLINICAL_RECORDS_DIRECTORY_NAME: &str = "clinical-records";
+
+/// Number of times a read failing with a transient I/O error is attempted
+const READ_ATTEMPTS: u32 = 5;
+
+/// Wait before the first retry of a failed read, doubled for each further retry
+const READ_RETRY_BACKOFF: Duration = Duration::from_millis(200);
+
+/// Number of files a zip archive may have unless --max-zip-entries says otherwise. Exports have a
+/// file per workout route and electrocardiogram, which rarely add up to more than a few thousand.
+pub const DEFAULT_MAX_ZIP_ENTRIES: usize = 100_000;
+
+/// How many times its compressed size a file of a zip archive may decompress to unless
+/// --max-compression-ratio says otherwise. export.xml typically compresses 15 to 30 times.
+pub const DEFAULT_MAX_COMPRESSION_RATIO: u64 = 200;
+
+/// Files of a zip archive smaller than this when decompressed aren't held to the compression
+/// ratio, as small files of repeated bytes compress well without being a threat
+const COMPRESSION_RATIO_MIN_SIZE: u64 = 1 << 20;
+
+/// Limits on the files of a zip archive, against archives crafted to exhaust memory or disk when
+/// they're decompressed
+#[derive(Debug, Clone, Copy)]
+pub struct ZipLimits {
+    pub max_entries: usize,
+    pub max_compression_ratio: u64,
+}
+
+impl Default for ZipLimits {
+    fn default() -> Self {
+        Self {
+            max_entries: DEFAULT_MAX_ZIP_ENTRIES,
+            max_compression_ratio: DEFAULT_MAX_COMPRESSION_RATIO,
+        }
+    }
+}
+
+/// The location of a HealthKit export on disk
+#[derive(Debug, Clone)]
+pub enum ExportSource {
+    /// The export.zip archive shared from the Health app
+    Zip(PathBuf),
+    /// A directory containing export.xml and the other exported files
+    Directory(PathBuf),
+    /// A bare export.xml file. Any other exported files are read relative to its directory.
+    Xml(PathBuf),
+}
+
+impl ExportSource {
+    /// Determines whether the path is a zip archive, an extracted export directory, or an XML file
+    pub fn detect(path: &Path) -> anyhow::Result<Self> {
+        if path.is_dir() {
+            let nested = path.join(EXPORT_DIRECTORY_NAME);
+            if nested.join(EXPORT_XML_NAME).is_file() {
+                return Ok(ExportSource::Directory(nested));
+            }
+            if path.join(EXPORT_XML_NAME).is_file() {
+                return Ok(ExportSource::Directory(path.to_path_buf()));
+            }
+            anyhow::bail!(
+                "The directory \"{}\" does not contain {} or {}/{}",
+                path.display(),
+                EXPORT_XML_NAME,
+                EXPORT_DIRECTORY_NAME,
+                EXPORT_XML_NAME
+            );
+        }
+        // Sniff the file contents rather than trusting the extension
+        let mut magic = [0u8; 4];
+        let read = File::open(path)?.read(&mut magic)?;
+        if read == magic.len() && magic == *b"PK\x03\x04" {
+            Ok(ExportSource::Zip(path.to_path_buf()))
+        } else {
+            Ok(ExportSource::Xml(path.to_path_buf()))
+        }
+    }
+
+    /// Returns a hash of the export contents as a hex string.
+    /// The zip or XML file is hashed in full. For directories, export.xml is hashed along with the
+    /// names and sizes of the route, electrocardiogram, and clinical record files, which are never
+    /// modified once written.
+    pub fn content_hash(&self) -> anyhow::Result<String> {
+        let mut hasher = Sha256::new();
+        match self {
+            ExportSource::Zip(path) => {
+                std::io::copy(&mut File::open(path)?, &mut hasher)?;
+            }
+            ExportSource::Directory(_) | ExportSource::Xml(_) => {
+                let (root, export_xml) = match self.open()? {
+                    ExportArchive::Directory { root, export_xml } => (root, export_xml),
+                    ExportArchive::Zip(_) => unreachable!("not a zip archive"),
+                };
+                std::io::copy(&mut File::open(export_xml)?, &mut hasher)?;
+                for dir_name in [
+                    WORKOUT_ROUTES_DIRECTORY_NAME,
+                    ELECTROCARDIOGRAMS_DIRECTORY_NAME,
+                    CLINICAL_RECORDS_DIRECTORY_NAME,
+                ] {
+                    let dir = root.join(dir_name);
+                    if !dir.is_dir() {
+                        continue;
+                    }
+                    let mut files = std::fs::read_dir(dir)?
+                        .map(|entry| {
+                            let entry = entry?;
+                            Ok((entry.file_name(), entry.metadata()?.len()))
+                        })
+                        .collect::<anyhow::Result<Vec<_>>>()?;
+                    files.sort();
+                    for (name, size) in files {
+                        hasher.update(name.to_string_lossy().as_bytes());
+                        hasher.update(size.to_le_bytes());
+                    }
+                }
+            }
+        }
+        Ok(format!("{:x}", hasher.finalize()))
+    }
+
+    /// Opens a new, independent handle for reading files from the export
+    pub fn open(&self) -> anyhow::Result<ExportArchive> {
+        match self {
+            ExportSource::Zip(path) => {
+                let file = BufReader::new(RetryingFile::open(path)?);
+                match zip::ZipArchive::new(file) {
+                    Ok(archive) => Ok(ExportArchive::Zip(archive)),
+                    Err(zip::result::ZipError::Io(e)) => Err(e.into()),
+                    Err(e) => Err(corrupt_archive_error(path, e)),
+                }
+            }
+            ExportSource::Directory(root) => Ok(ExportArchive::Directory {
+                root: root.clone(),
+                export_xml: root.join(EXPORT_XML_NAME),
+            }),
+            ExportSource::Xml(path) => Ok(ExportArchive::Directory {
+                root: path.parent().map(Path::to_path_buf).unwrap_or_default(),
+                export_xml: path.clone(),
+            }),
+        }
+    }
+}
+
+/// An open HealthKit export
+pub enum ExportArchive {
+    Zip(zip::ZipArchive<BufReader<RetryingFile>>),
+    Directory { root: PathBuf, export_xml: PathBuf },
+}
+
+impl ExportArchive {
+    /// Fails if the zip archive has more files than the limit, a file whose name is an absolute
+    /// path or leads out of the archive with .., or a file that decompresses to more than the
+    /// ratio allows. Extracted exports are on disk already, so only zip archives are checked.
+    pub fn check_limits(&mut self, limits: &ZipLimits) -> anyhow::Result<()> {
+        let archive = match self {
+            ExportArchive::Zip(archive) => archive,
+            ExportArchive::Directory { .. } => return Ok(()),
+        };
+        if archive.len() > limits.max_entries {
+            anyhow::bail!(
+                "The zip archive has {} files, more than the --max-zip-entries of {}",
+                archive.len(),
+                limits.max_entries
+            );
+        }
+        for i in 0..archive.len() {
+            let file = archive.by_index_raw(i)?;
+            if file.enclosed_name().is_none() {
+                anyhow::bail!(
+                    "The zip archive has a file named \"{}\", which leads outside the archive",
+                    file.name()
+                );
+            }
+            let size = file.size();
+            if size >= COMPRESSION_RATIO_MIN_SIZE
+                && size / file.compressed_size().max(1) > limits.max_compression_ratio
+            {
+                anyhow::bail!(
+                    "\"{}\" of the zip archive decompresses to {} bytes from {}, more than the --max-compression-ratio of {}",
+                    file.name(),
+                    size,
+                    file.compressed_size(),
+                    limits.max_compression_ratio
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Opens export.xml, returning the reader and the uncompressed size of the file
+    pub fn open_export_xml(&mut self) -> anyhow::Result<(Box<dyn Read + '_>, u64)> {
+        match self {
+            ExportArchive::Zip(_) => self.open_file(EXPORT_XML_NAME),
+            ExportArchive::Directory { export_xml, .. } => {
+                let file = RetryingFile::open(export_xml)?;
+                let size = file.file.metadata()?.len();
+                Ok((Box::new(file), size))
+            }
+        }
+    }
+
+    /// Reads export_cda.xml, or returns None if the export has none
+    pub fn read_export_cda_xml(&mut self) -> anyhow::Result<Option<Vec<u8>>> {
+        let exists = match self {
+            ExportArchive::Zip(archive) => {
+                let name = format!("{}/{}", EXPORT_DIRECTORY_NAME, EXPORT_CDA_XML_NAME);
+                archive.file_names().any(|file_name| file_name == name)
+            }
+            ExportArchive::Directory { root, .. } => root.join(EXPORT_CDA_XML_NAME).is_file(),
+        };
+        if !exists {
+            return Ok(None);
+        }
+        let (mut file, size) = self.open_file(EXPORT_CDA_XML_NAME)?;
+        let mut bytes = Vec::with_capacity(size as usize);
+        file.read_to_end(&mut bytes)?;
+        Ok(Some(bytes))
+    }
+
+    /// Returns the uncompressed size in bytes of every file of a zip archive, or of export.xml for
+    /// an extracted export, as recorded by the archive or file system
+    pub fn uncompressed_size(&mut self) -> anyhow::Result<u64> {
+        match self {
+            ExportArchive::Zip(archive) => {
+                let mut size = 0u64;
+                for i in 0..archive.len() {
+                    size = size.saturating_add(archive.by_index_raw(i)?.size());
+                }
+                Ok(size)
+            }
+            ExportArchive::Directory { export_xml, .. } => Ok(std::fs::metadata(export_xml)?.len()),
+        }
+    }
+
+    /// Lists the files in a directory of the export, returning sorted paths relative to the export
+    /// directory in the form accepted by open_file, e.g. /electrocardiograms/ecg_2020-01-01.csv
+    pub fn list_files(&self, dir_name: &str) -> anyhow::Result<Vec<String>> {
+        let mut paths = match self {
+            ExportArchive::Zip(archive) => {
+                let prefix = format!("{}/{}/", EXPORT_DIRECTORY_NAME, dir_name);
+                archive
+                    .file_names()
+                    .filter_map(|name| name.strip_prefix(&prefix))
+                    .filter(|name| !name.is_empty() && !name.ends_with('/'))
+                    .map(|name| format!("/{}/{}", dir_name, name))
+                    .collect::<Vec<_>>()
+            }
+            ExportArchive::Directory { root, .. } => {
+                let dir = root.join(dir_name);
+                if !dir.is_dir() {
+                    return Ok(Vec::new());
+                }
+                let mut paths = Vec::new();
+                for entry in std::fs::read_dir(dir)? {
+                    let entry = entry?;
+                    if entry.file_type()?.is_file() {
+                        paths.push(format!(
+                            "/{}/{}",
+                            dir_name,
+                            entry.file_name().to_string_lossy()
+                        ));
+                    }
+                }
+                paths
+            }
+        };
+        paths.sort();
+        Ok(paths)
+    }
+
+    /// Opens a file by its path relative to the export directory, as found in the path
+    /// attribute of FileReference elements, e.g. /workout-routes/route.gpx
+    pub fn open_file(&mut self, path: &str) -> anyhow::Result<(Box<dyn Read + '_>, u64)> {
+        let path = path.trim_start_matches('/');
+        // The paths come from export.xml, so they mustn't reach files outside the export
+        if !Path::new(path)
+            .components()
+            .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+        {
+            anyhow::bail!(
+                "The export refers to the file \"{}\", which is outside the export",
+                path
+            );
+        }
+        match self {
+            ExportArchive::Zip(archive) => {
+                let name = format!("{}/{}", EXPORT_DIRECTORY_NAME, path);
+                let file = match archive.by_name(&name) {
+                    Ok(file) => file,
+                    Err(zip::result::ZipError::Io(e)) => return Err(e.into()),
+                    Err(e @ zip::result::ZipError::FileNotFound) => return Err(e.into()),
+                    Err(e) => return Err(corrupt_archive_error(Path::new(&name), e)),
+                };
+                let size = file.size();
+                let file = DeclaredSizeReader {
+                    inner: file,
+                    remaining: size,
+                    name,
+                };
+                Ok((Box::new(file), size))
+            }
+            ExportArchive::Directory { root, .. } => {
+                let path = root.join(path);
+                debug!("Opening {}", path.display());
+                let file = RetryingFile::open(&path)?;
+                let size = file.file.metadata()?.len();
+                Ok((Box::new(file), size))
+            }
+        }
+    }
+}
+
+/// Reads a file of a zip archive, failing if it decompresses to more bytes than the archive
+/// records for it. The recorded size is checked against the limits, but the data can be crafted
+/// to decompress to far more.
+struct DeclaredSizeReader<R> {
+    inner: R,
+    remaining: u64,
+    name: String,
+}
+
+impl<R: Read> Read for DeclaredSizeReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        match self.remaining.checked_sub(read as u64) {
+            Some(remaining) => {
+                self.remaining = remaining;
+                Ok(read)
+            }
+            None => Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "\"{}\" of the zip archive decompresses to more than its recorded size",
+                    self.name
+                ),
+            )),
+        }
+    }
+}
+
+/// Returns the error for a zip archive that can't be read because its contents are invalid, as
+/// opposed to an I/O error reading it
+fn corrupt_archive_error(path: &Path, error: zip::result::ZipError) -> anyhow::Error {
+    anyhow::anyhow!(
+        "\"{}\" is corrupt or incompletely copied ({}). Export the data from the Health app again",
+        path.display(),
+        error
+    )
+}
+
+/// A file whose reads are retried with backoff when they fail with a transient I/O error, as
+/// happens on network shares and cloud-synced folders. The file is reopened before each retry,
+/// since a dropped connection can leave the handle unusable.
+pub struct RetryingFile<R = File> {
+    file: R,
+    path: PathBuf,
+    /// Offset of the next read, restored after reopening the file
+    position: u64,
+    /// Opens the file again after a failed read
+    open: fn(&Path) -> std::io::Result<R>,
+}
+
+impl RetryingFile {
+    fn open(path: &Path) -> anyhow::Result<Self> {
+        Self::open_with(path, |path| File::open(path))
+    }
+}
+
+impl<R: Read + Seek> RetryingFile<R> {
+    fn open_with(path: &Path, open: fn(&Path) -> std::io::Result<R>) -> anyhow::Result<Self> {
+        let path = path.to_path_buf();
+        let file = retry(&path, || open(&path))?;
+        Ok(Self {
+            file,
+            path,
+            position: 0,
+            open,
+        })
+    }
+
+    fn reopen(&mut self) -> std::io::Result<()> {
+        let mut file = (self.open)(&self.path)?;
+        file.seek(SeekFrom::Start(self.position))?;
+        self.file = file;
+        Ok(())
+    }
+}
+
+impl<R: Read + Seek> Read for RetryingFile<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let mut attempt = 1;
+        loop {
+            match self.file.read(buf) {
+                Ok(read) => {
+                    self.position += read as u64;
+                    return Ok(read);
+                }
+                // A signal arrived before anything was read, so the read is repeated at once
+                // without counting as an attempt, as std's read_exact does
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) if is_transient(&e) && attempt < READ_ATTEMPTS => {
+                    let backoff = READ_RETRY_BACKOFF * 2u32.pow(attempt - 1);
+                    warn!(
+                        "Reading \"{}\" failed ({}), retrying in {:?}",
+                        self.path.display(),
+                        e,
+                        backoff
+                    );
+                    sleep(backoff);
+                    attempt += 1;
+                    // A failure to reopen is retried by the next attempt to read
+                    if let Err(e) = self.reopen() {
+                        debug!("Reopening \"{}\" failed: {}", self.path.display(), e);
+                    }
+                }
+                Err(e) if is_transient(&e) => return Err(transient_error(&self.path, e)),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+impl<R: Seek> Seek for RetryingFile<R> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.position = self.file.seek(pos)?;
+        Ok(self.position)
+    }
+}
+
+/// Runs the operation until it succeeds, fails with an error that isn't transient, or has been
+/// attempted READ_ATTEMPTS times
+fn retry<T>(path: &Path, mut operation: impl FnMut() -> std::io::Result<T>) -> std::io::Result<T> {
+    let mut attempt = 1;
+    loop {
+        match operation() {
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) if is_transient(&e) && attempt < READ_ATTEMPTS => {
+                let backoff = READ_RETRY_BACKOFF * 2u32.pow(attempt - 1);
+                warn!(
+                    "Opening \"{}\" failed ({}), retrying in {:?}",
+                    path.display(),
+                    e,
+                    backoff
+                );
+                sleep(backoff);
+                attempt += 1;
+            }
+            Err(e) if is_transient(&e) => return Err(transient_error(path, e)),
+            result => return result,
+        }
+    }
+}
+
+/// Waits before a retry. The export is read from async tasks, so on the multi-threaded runtime
+/// the worker thread hands its other tasks to the rest of the pool while it sleeps. A
+/// current-thread runtime can't do that, so there it blocks as it does outside a runtime.
+fn sleep(duration: Duration) {
+    match tokio::runtime::Handle::try_current() {
+        Ok(handle) if handle.runtime_flavor() == RuntimeFlavor::MultiThread => {
+            tokio::task::block_in_place(|| std::thread::sleep(duration))
+        }
+        _ => std::thread::sleep(duration),
+    }
+}
+
+/// Returns the error for a read that kept failing with a transient error, which points at the
+/// storage rather than the export
+fn transient_error(path: &Path, error: std::io::Error) -> std::io::Error {
+    std::io::Error::new(
+        error.kind(),
+        format!(
+            "reading \"{}\" failed {} times with a transient I/O error, the last being: {}. The export itself is not necessarily corrupt; if it is on a network share or in a cloud-synced folder, copy it to a local disk and try again",
+            path.display(),
+            READ_ATTEMPTS,
+            error
+        ),
+    )
+}
+
+/// Returns true if the error may go away when the read is retried after a wait, e.g. a timeout or
+/// dropped connection of a network file system
+fn is_transient(error: &std::io::Error) -> bool {
+    if matches!(
+        error.kind(),
+        ErrorKind::TimedOut
+            | ErrorKind::WouldBlock
+            | ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionAborted
+            | ErrorKind::NotConnected
+    ) {
+        return true;
+    }
+    // EIO, which network file systems return for a lost connection
+    #[cfg(unix)]
+    const TRANSIENT_OS_ERRORS: &[i32] = &[5];
+    // ERROR_UNEXP_NET_ERR, ERROR_NETNAME_DELETED, and ERROR_SEM_TIMEOUT
+    #[cfg(windows)]
+    const TRANSIENT_OS_ERRORS: &[i32] = &[59, 64, 121];
+    #[cfg(not(any(unix, windows)))]
+    const TRANSIENT_OS_ERRORS: &[i32] = &[];
+    error
+        .raw_os_error()
+        .is_some_and(|code| TRANSIENT_OS_ERRORS.contains(&code))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conformance::EXPORT_FILES;
+    use crate::options::ImportOptions;
+    use crate::testing::{self, TestDir};
+    use std::cell::Cell;
+    use std::io::Cursor;
+
+    const CONTENTS: &[u8] = b"<HealthData/>";
+
+    thread_local! {
+        /// The error the reads of the flaky file fail with, and how many more times they fail
+        static FAILURES: Cell<(ErrorKind, usize)> = const { Cell::new((ErrorKind::Other, 0)) };
+        static OPENS: Cell<usize> = const { Cell::new(0) };
+    }
+
+    /// A file whose reads fail as set in FAILURES before they succeed
+    struct FlakyFile(Cursor<&'static [u8]>);
+
+    impl FlakyFile {
+        fn open(_: &Path) -> std::io::Result<Self> {
+            OPENS.with(|opens| opens.set(opens.get() + 1));
+            Ok(Self(Cursor::new(CONTENTS)))
+        }
+    }
+
+    impl Read for FlakyFile {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let (kind, failures) = FAILURES.with(Cell::get);
+            if failures > 0 {
+                FAILURES.with(|f| f.set((kind, failures - 1)));
+                return Err(std::io::Error::from(kind));
+            }
+            self.0.read(buf)
+        }
+    }
+
+    impl Seek for FlakyFile {
+        fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+            self.0.seek(pos)
+        }
+    }
+
+    /// Reads the whole flaky file after its reads fail with the error the number of times
+    fn read_flaky_file(kind: ErrorKind, failures: usize) -> std::io::Result<Vec<u8>> {
+        FAILURES.with(|f| f.set((kind, failures)));
+        OPENS.with(|opens| opens.set(0));
+        let mut file = RetryingFile::open_with(Path::new("export.xml"), FlakyFile::open).unwrap();
+        let mut contents = Vec::new();
+        file.read_to_end(&mut contents)?;
+        Ok(contents)
+    }
+
+    #[test]
+    fn interrupted_reads_are_repeated_without_using_up_attempts() {
+        let failures = READ_ATTEMPTS as usize * 2;
+        assert_eq!(
+            read_flaky_file(ErrorKind::Interrupted, failures).unwrap(),
+            CONTENTS
+        );
+        // Interrupted reads aren't retried by reopening the file
+        assert_eq!(OPENS.with(Cell::get), 1);
+    }
+
+    #[test]
+    fn transient_errors_are_retried() {
+        assert_eq!(read_flaky_file(ErrorKind::TimedOut, 2).unwrap(), CONTENTS);
+        assert_eq!(OPENS.with(Cell::get), 3);
+    }
+
+    #[test]
+    fn other_errors_fail_the_read() {
+        let error = read_flaky_file(ErrorKind::InvalidData, 1).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+        assert_eq!(OPENS.with(Cell::get), 1);
+    }
+
+    #[test]
+    fn exports_are_detected_from_their_path() {
+        let dir = TestDir::new("source-detect");
+        let files = [("export.xml", CONTENTS)];
+        let flat = dir.write_export("flat", &files);
+        let nested = dir.write_export("nested/apple_health_export", &files);
+        let zip = dir.write_zip(
+            "export.zip",
+            &[("apple_health_export/export.xml", CONTENTS)],
+        );
+        assert!(
+            matches!(ExportSource::detect(&flat), Ok(ExportSource::Directory(root)) if root == flat)
+        );
+        assert!(
+            matches!(ExportSource::detect(&dir.path().join("nested")), Ok(ExportSource::Directory(root)) if root == nested)
+        );
+        let xml = flat.join("export.xml");
+        assert!(matches!(ExportSource::detect(&xml), Ok(ExportSource::Xml(path)) if path == xml));
+        assert!(matches!(ExportSource::detect(&zip), Ok(ExportSource::Zip(path)) if path == zip));
+        let empty = dir.write_export("empty", &[]);
+        std::fs::create_dir_all(&empty).unwrap();
+        assert!(ExportSource::detect(&empty).is_err());
+    }
+
+    #[tokio::test]
+    async fn every_kind_of_export_imports_the_same_rows() {
+        let dir = TestDir::new("source-kinds");
+        let export_dir = dir.write_export("export/apple_health_export", EXPORT_FILES);
+        let zipped_files: Vec<(String, &[u8])> = EXPORT_FILES
+            .iter()
+            .map(|(path, contents)| (format!("apple_health_export/{}", path), *contents))
+            .collect();
+        let zipped_files: Vec<(&str, &[u8])> = zipped_files
+            .iter()
+            .map(|(path, contents)| (path.as_str(), *contents))
+            .collect();
+        let sources = [
+            ("directory", dir.path().join("export")),
+            ("extracted", export_dir.clone()),
+            ("xml", export_dir.join("export.xml")),
+            ("zip", dir.write_zip("export.zip", &zipped_files)),
+        ];
+        let options = ImportOptions {
+            route_points: true,
+            ..ImportOptions::defaults()
+        };
+        let mut counts = Vec::new();
+        for (name, path) in &sources {
+            let db_url = dir.db_url(&format!("{}.db", name));
+            testing::import(path, &db_url, &options)
+                .await
+                .unwrap();
+            counts.push(testing::row_counts(&db_url).await);
+        }
+        // The routes are read relative to the XML file
+        assert!(counts[2]["WorkoutRoutePoint"] > 0);
+        assert!(
+            counts.iter().all(|other| *other == counts[0]),
+            "{:#?}",
+            counts
+        );
+    }
+}
@@ -0,0 +1,330 @@
+//! An SQL patch of the changes an incremental import made to a SQLite database, for updating
+//! replicas of the database without copying it.
+//!
+//! This is a plain SQL script rather than a changeset of SQLite's session extension, which only
+//! records changes to tables with a declared PRIMARY KEY. Workout has one, but the record tables
+//! and most of the others are keyed by their rowid alone, so a changeset would leave them out.
+//! The script holds the schema changes, the deleted and inserted rows of the imported tables by
+//! rowid, the analytics tables, which every import rebuilds, in full, and the views the import
+//! created, changed, or dropped. Rows are written with INSERT OR REPLACE, so applying the patch
+//! again leaves the replica as it is.
+use crate::analytics;
+use crate::identifier::get_valid_sqlite_identifier;
+use crate::options::ensure_destructive_allowed;
+use log::*;
+use sqlx::{Sqlite, SqlitePool, Transaction};
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Number of rows selected at a time when writing the inserted rows
+const ROWID_CHUNK_SIZE: usize = 500;
+
+/// The changes to a single table of the import, in the order the table was first changed
+#[derive(Default)]
+struct TableChanges {
+    name: String,
+    cleared: bool,
+    inserted: Vec<i64>,
+}
+
+/// Records the changes of an import, writing them to an SQL script once it is committed
+pub struct SqlPatch {
+    path: PathBuf,
+    /// The CREATE TABLE, ALTER TABLE, and CREATE INDEX statements run by the import
+    schema: Vec<String>,
+    tables: Vec<TableChanges>,
+    /// The name and SQL of each view before the import, to find the views it changed
+    views: BTreeMap<String, String>,
+}
+
+impl SqlPatch {
+    /// Reads the views of the database before the import. Fails early, before the import, if the
+    /// file exists and --no-destructive is set.
+    pub async fn create(
+        path: &Path,
+        no_destructive: bool,
+        tx: &mut Transaction<'_, Sqlite>,
+    ) -> anyhow::Result<Self> {
+        if path.exists() {
+            ensure_destructive_allowed(
+                no_destructive,
+                format_args!("overwrite \"{}\"", path.display()),
+            )?;
+        }
+        let views = sqlx::query_as("SELECT name, sql FROM sqlite_master WHERE type = 'view'")
+            .fetch_all(&mut *tx)
+            .await?
+            .into_iter()
+            .collect();
+        Ok(Self {
+            path: path.to_path_buf(),
+            schema: Vec::new(),
+            tables: Vec::new(),
+            views,
+        })
+    }
+
+    /// Records a statement changing the schema of an imported table
+    pub fn record_schema(&mut self, statement: &str) {
+        self.schema.push(statement.trim().to_string());
+    }
+
+    /// Records that every row of the table was deleted
+    pub fn record_clear(&mut self, table_name: &str) {
+        let table = self.table(table_name);
+        // Rows inserted before the table was cleared no longer exist
+        table.inserted.clear();
+        table.cleared = true;
+    }
+
+    /// Records that the row with the rowid was inserted to the table
+    pub fn record_insert(&mut self, table_name: &str, rowid: i64) {
+        self.table(table_name).inserted.push(rowid);
+    }
+
+    fn table(&mut self, table_name: &str) -> &mut TableChanges {
+        // The last changed table is almost always the one changed next
+        match self
+            .tables
+            .iter()
+            .rposition(|table| table.name == table_name)
+        {
+            Some(index) => &mut self.tables[index],
+            None => {
+                self.tables.push(TableChanges {
+                    name: table_name.to_string(),
+                    ..Default::default()
+                });
+                self.tables.last_mut().expect("cant fail")
+            }
+        }
+    }
+
+    /// Writes the script, reading the inserted rows and derived tables from the committed database
+    pub async fn write(self, db: &SqlitePool) -> anyhow::Result<()> {
+        debug!("Writing the SQL patch to {}", self.path.display());
+        let mut file = std::io::BufWriter::new(std::fs::File::create(&self.path)?);
+        writeln!(file, "BEGIN;")?;
+        for statement in &self.schema {
+            writeln!(file, "{};", statement.trim_end_matches(';'))?;
+        }
+        for table in &self.tables {
+            if table.cleared {
+                writeln!(
+                    file,
+                    "DELETE FROM {};",
+                    get_valid_sqlite_identifier(&table.name)
+                )?;
+            }
+            for rowids in table.inserted.chunks(ROWID_CHUNK_SIZE) {
+                let rowids = rowids
+                    .iter()
+                    .map(i64::to_string)
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                let condition = format!("rowid IN ({})", rowids);
+                for statement in insert_statements(db, &table.name, &condition).await? {
+                    writeln!(file, "{}", statement)?;
+                }
+            }
+        }
+        // The derived tables are replaced in full
+        let derived: Vec<(String, String)> = sqlx::query_as(
+            "SELECT name, sql FROM sqlite_master WHERE type = 'table' AND name IN (SELECT value FROM json_each(?)) ORDER BY name",
+        )
+        .bind(serde_json::to_string(analytics::DERIVED_TABLES)?)
+        .fetch_all(db)
+        .await?;
+        for (name, sql) in derived {
+            writeln!(
+                file,
+                "DROP TABLE IF EXISTS {};",
+                get_valid_sqlite_identifier(&name)
+            )?;
+            writeln!(file, "{};", sql)?;
+            for statement in insert_statements(db, &name, "1").await? {
+                writeln!(file, "{}", statement)?;
+            }
+        }
+        // Only the views the import dropped, created, or changed are replaced, leaving the
+        // database's other views, such as those of its users, as they are
+        let views: BTreeMap<String, String> =
+            sqlx::query_as("SELECT name, sql FROM sqlite_master WHERE type = 'view'")
+                .fetch_all(db)
+                .await?
+                .into_iter()
+                .collect();
+        for (name, sql) in &self.views {
+            if views.get(name) != Some(sql) {
+                writeln!(
+                    file,
+                    "DROP VIEW IF EXISTS {};",
+                    get_valid_sqlite_identifier(name)
+                )?;
+            }
+        }
+        for (name, sql) in &views {
+            if self.views.get(name) != Some(sql) {
+                writeln!(file, "{};", sql)?;
+            }
+        }
+        writeln!(file, "COMMIT;")?;
+        file.flush()?;
+        Ok(())
+    }
+}
+
+/// Returns an INSERT OR REPLACE statement for each row of the table matching the condition,
+/// keeping the rowid so the workout_id and record_id references of child rows stay valid, and
+/// so a row that was already applied is replaced by itself
+async fn insert_statements(
+    db: &SqlitePool,
+    table_name: &str,
+    condition: &str,
+) -> anyhow::Result<Vec<String>> {
+    let columns: Vec<String> = sqlx::query_scalar("SELECT name FROM pragma_table_info(?)")
+        .bind(table_name)
+        .fetch_all(db)
+        .await?;
+    let column_list = std::iter::once("rowid".to_string())
+        .chain(columns.iter().map(|name| get_valid_sqlite_identifier(name)))
+        .collect::<Vec<_>>();
+    // SQLite's quote() writes each value as a literal of its exact type
+    let values = column_list
+        .iter()
+        .map(|column| format!("quote({})", column))
+        .collect::<Vec<_>>()
+        .join(" || ', ' || ");
+    let qs = format!(
+        "SELECT 'INSERT OR REPLACE INTO {} ({}) VALUES (' || {} || ');' FROM {} WHERE {} ORDER BY rowid",
+        get_valid_sqlite_identifier(table_name).replace('\'', "''"),
+        column_list.join(", ").replace('\'', "''"),
+        values,
+        get_valid_sqlite_identifier(table_name),
+        condition
+    );
+    Ok(sqlx::query_scalar(&qs).fetch_all(db).await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conformance::EXPORT_FILES;
+    use crate::options::ImportOptions;
+    use crate::testing::{self, TestDir};
+    use sqlx::Executor;
+
+    /// A heart rate sample newer than those of the conformance export, with a new metadata key
+    const NEWER_RECORD: &str = r#"<Record type="HKQuantityTypeIdentifierHeartRate" sourceName="Watch" sourceVersion="10.2" unit="count/min" creationDate="2024-01-03 08:00:05 -0800" startDate="2024-01-03 08:00:00 -0800" endDate="2024-01-03 08:00:00 -0800" value="64">
+  <MetadataEntry key="HKMetadataKeySessionEstimate" value="1"/>
+ </Record>
+</HealthData>"#;
+
+    /// Returns the export files of the conformance export with the newer record added
+    fn newer_export_files() -> Vec<(&'static str, Vec<u8>)> {
+        EXPORT_FILES
+            .iter()
+            .map(|(path, contents)| {
+                let contents = if *path == "export.xml" {
+                    String::from_utf8(contents.to_vec())
+                        .unwrap()
+                        .replace("</HealthData>", NEWER_RECORD)
+                        .into_bytes()
+                } else {
+                    contents.to_vec()
+                };
+                (*path, contents)
+            })
+            .collect()
+    }
+
+    /// Returns the INSERT statements of every row of every table of the database
+    async fn contents(db: &SqlitePool) -> BTreeMap<String, Vec<String>> {
+        let table_names: Vec<String> =
+            sqlx::query_scalar("SELECT name FROM sqlite_master WHERE type = 'table'")
+                .fetch_all(db)
+                .await
+                .unwrap();
+        let mut contents = BTreeMap::new();
+        for table_name in table_names {
+            let statements = insert_statements(db, &table_name, "1").await.unwrap();
+            contents.insert(table_name, statements);
+        }
+        contents
+    }
+
+    /// Imports the conformance export, copies the database to a replica, and appends the newer
+    /// export to the database with --sql-patch, returning the database, replica, and patch
+    async fn patched(dir: &TestDir) -> (SqlitePool, SqlitePool, String) {
+        let export_dir = dir.write_export("export", EXPORT_FILES);
+        let db_url = dir.db_url("health.db");
+        testing::import(&export_dir, &db_url, &ImportOptions::defaults())
+            .await
+            .unwrap();
+        let db = SqlitePool::connect(&db_url).await.unwrap();
+        db.execute("CREATE VIEW heart_rate_count AS SELECT COUNT(*) FROM HKQuantityTypeIdentifierHeartRate")
+            .await
+            .unwrap();
+        db.close().await;
+        std::fs::copy(dir.path().join("health.db"), dir.path().join("replica.db")).unwrap();
+        let files = newer_export_files();
+        let files: Vec<(&str, &[u8])> = files
+            .iter()
+            .map(|(path, contents)| (*path, contents.as_slice()))
+            .collect();
+        let export_dir = dir.write_export("newer_export", &files);
+        let patch_path = dir.path().join("patch.sql");
+        let options = ImportOptions {
+            append: true,
+            sql_patch: Some(patch_path.clone()),
+            ..ImportOptions::defaults()
+        };
+        testing::import(&export_dir, &db_url, &options)
+            .await
+            .unwrap();
+        let db = SqlitePool::connect(&db_url).await.unwrap();
+        let replica = SqlitePool::connect(&dir.db_url("replica.db"))
+            .await
+            .unwrap();
+        (db, replica, std::fs::read_to_string(patch_path).unwrap())
+    }
+
+    #[tokio::test]
+    async fn patched_replica_matches_the_database() {
+        let dir = TestDir::new("sql-patch");
+        let (db, replica, patch) = patched(&dir).await;
+        assert!(patch.contains("ALTER TABLE `HKQuantityTypeIdentifierHeartRate` ADD COLUMN"));
+        replica.execute(patch.as_str()).await.unwrap();
+        assert_eq!(contents(&db).await, contents(&replica).await);
+    }
+
+    #[tokio::test]
+    async fn patch_keeps_other_views() {
+        let dir = TestDir::new("sql-patch-views");
+        let (_, replica, patch) = patched(&dir).await;
+        assert!(!patch.contains("heart_rate_count"), "{}", patch);
+        replica.execute(patch.as_str()).await.unwrap();
+        let count: i64 = sqlx::query_scalar("SELECT * FROM heart_rate_count")
+            .fetch_one(&replica)
+            .await
+            .unwrap();
+        assert_eq!(count, 3);
+    }
+
+    #[tokio::test]
+    async fn reapplied_rows_are_replaced() {
+        let dir = TestDir::new("sql-patch-reapplied");
+        let (db, replica, patch) = patched(&dir).await;
+        replica.execute(patch.as_str()).await.unwrap();
+        // The rows and derived tables of the patch, without its schema changes, which the
+        // replica already has
+        let rows = patch
+            .lines()
+            .filter(|line| !line.starts_with("ALTER TABLE"))
+            .collect::<Vec<_>>()
+            .join("\n");
+        replica.execute(rows.as_str()).await.unwrap();
+        assert_eq!(contents(&db).await, contents(&replica).await);
+    }
+}
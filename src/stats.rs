@@ -0,0 +1,309 @@
+//! A summary of what an export holds, read without creating a database
+use crate::device::device_label;
+use crate::source::{
+    ExportSource, ZipLimits, CLINICAL_RECORDS_DIRECTORY_NAME, ELECTROCARDIOGRAMS_DIRECTORY_NAME,
+    WORKOUT_ROUTES_DIRECTORY_NAME,
+};
+use crate::StatsArgs;
+use console::Term;
+use quick_xml::events::{BytesStart, Event};
+use std::collections::{BTreeMap, HashMap};
+use std::io::{BufRead, BufReader};
+
+/// Number of sources and devices listed, the ones with the most records first
+const LISTED_SOURCES: usize = 20;
+
+/// The records of a type, or of a source or device
+#[derive(Default)]
+struct Count {
+    records: u64,
+    /// The earliest and latest dates, as the day the sample started in its local time
+    first_date: Option<String>,
+    last_date: Option<String>,
+}
+
+impl Count {
+    fn observe(&mut self, start_date: Option<&str>) {
+        self.records += 1;
+        if let Some(date) = start_date.and_then(|date| date.get(..10)) {
+            if self.first_date.as_deref().is_none_or(|first| date < first) {
+                self.first_date = Some(date.to_string());
+            }
+            if self.last_date.as_deref().is_none_or(|last| date > last) {
+                self.last_date = Some(date.to_string());
+            }
+        }
+    }
+
+    fn date_range(&self) -> String {
+        match (&self.first_date, &self.last_date) {
+            (Some(first), Some(last)) => format!("{} to {}", first, last),
+            _ => "-".to_string(),
+        }
+    }
+}
+
+#[derive(Default)]
+struct ExportStats {
+    types: BTreeMap<String, Count>,
+    sources: HashMap<String, u64>,
+    /// Keyed by the device description, which is repeated by every record of the device
+    devices: HashMap<String, u64>,
+    workouts: Count,
+    workouts_with_route: u64,
+    activity_summaries: u64,
+}
+
+/// Reads export.xml once and prints the record types with their counts and date ranges, the
+/// numbers of workouts and routes, and the sources and devices that wrote the records
+pub fn print_stats(args: &StatsArgs) -> anyhow::Result<()> {
+    let source = ExportSource::detect(&args.export_path)?;
+    let mut archive = source.open()?;
+    archive.check_limits(&ZipLimits::default())?;
+    let route_files = archive.list_files(WORKOUT_ROUTES_DIRECTORY_NAME)?.len();
+    let ecg_files = archive.list_files(ELECTROCARDIOGRAMS_DIRECTORY_NAME)?.len();
+    let clinical_files = archive.list_files(CLINICAL_RECORDS_DIRECTORY_NAME)?.len();
+    let (export_xml, _) = archive.open_export_xml()?;
+    let stats = read_stats(BufReader::new(export_xml))?;
+
+    let term = Term::stdout();
+    let records: u64 = stats.types.values().map(|count| count.records).sum();
+    let all_records = Count {
+        records,
+        first_date: stats
+            .types
+            .values()
+            .filter_map(|c| c.first_date.clone())
+            .min(),
+        last_date: stats
+            .types
+            .values()
+            .filter_map(|c| c.last_date.clone())
+            .max(),
+    };
+    term.write_line(&format!(
+        "{} records of {} types, {}",
+        records,
+        stats.types.len(),
+        all_records.date_range()
+    ))?;
+    term.write_line(&format!(
+        "{} workouts, {}, {} with a route, {} route files",
+        stats.workouts.records,
+        stats.workouts.date_range(),
+        stats.workouts_with_route,
+        route_files
+    ))?;
+    term.write_line(&format!(
+        "{} activity summaries, {} electrocardiograms, {} clinical records",
+        stats.activity_summaries, ecg_files, clinical_files
+    ))?;
+    term.write_line("")?;
+    let width = stats.types.keys().map(String::len).max().unwrap_or(0);
+    for (type_name, count) in &stats.types {
+        term.write_line(&format!(
+            "{:<width$}  {:>10}  {}",
+            type_name,
+            count.records,
+            count.date_range(),
+            width = width
+        ))?;
+    }
+    let mut devices: HashMap<String, u64> = HashMap::new();
+    for (device, records) in &stats.devices {
+        *devices.entry(device_label(device)).or_default() += records;
+    }
+    for (title, counts) in [("Sources", &stats.sources), ("Devices", &devices)] {
+        term.write_line("")?;
+        term.write_line(&format!("{} ({})", title, counts.len()))?;
+        let mut counts: Vec<_> = counts.iter().collect();
+        counts.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+        for (name, records) in counts.iter().take(LISTED_SOURCES) {
+            term.write_line(&format!("  {:>10}  {}", records, name))?;
+        }
+        if counts.len() > LISTED_SOURCES {
+            term.write_line(&format!("  and {} more", counts.len() - LISTED_SOURCES))?;
+        }
+    }
+    Ok(())
+}
+
+/// Reads export.xml, counting the children of HealthData
+fn read_stats<R: BufRead>(export_xml: R) -> anyhow::Result<ExportStats> {
+    let mut reader = quick_xml::Reader::from_reader(export_xml);
+    reader.trim_text(true);
+    let mut stats = ExportStats::default();
+    let mut buf = Vec::new();
+    let mut depth = 0;
+    loop {
+        match reader.read_event_into(&mut buf)? {
+            Event::Eof => break,
+            Event::Start(element) => {
+                depth += 1;
+                match (depth, element.name().as_ref()) {
+                    (2, _) => stats.observe(&reader, &element)?,
+                    // Counted once per workout, as the route element comes before the end tag
+                    (3, b"WorkoutRoute") => stats.workouts_with_route += 1,
+                    _ => {}
+                }
+            }
+            Event::Empty(element) if depth == 1 => stats.observe(&reader, &element)?,
+            Event::End(_) => depth -= 1,
+            _ => {}
+        }
+        buf.clear();
+    }
+    Ok(stats)
+}
+
+impl ExportStats {
+    /// Counts a child element of HealthData
+    fn observe<R>(
+        &mut self,
+        reader: &quick_xml::Reader<R>,
+        element: &BytesStart,
+    ) -> anyhow::Result<()> {
+        let name = element.name();
+        if name.as_ref() == b"ActivitySummary" {
+            self.activity_summaries += 1;
+            return Ok(());
+        }
+        if name.as_ref() != b"Record" && name.as_ref() != b"Workout" {
+            return Ok(());
+        }
+        let mut type_name = None;
+        let mut start_date = None;
+        for attribute in element.attributes() {
+            let attribute = attribute?;
+            match attribute.key.as_ref() {
+                b"type" => type_name = Some(attribute.decode_and_unescape_value(reader)?),
+                b"startDate" => start_date = Some(attribute.decode_and_unescape_value(reader)?),
+                b"sourceName" => increment(
+                    &mut self.sources,
+                    &attribute.decode_and_unescape_value(reader)?,
+                ),
+                b"device" => increment(
+                    &mut self.devices,
+                    &attribute.decode_and_unescape_value(reader)?,
+                ),
+                _ => {}
+            }
+        }
+        let count = match (name.as_ref(), type_name) {
+            (b"Record", Some(type_name)) => {
+                if !self.types.contains_key(type_name.as_ref()) {
+                    self.types.insert(type_name.to_string(), Count::default());
+                }
+                self.types
+                    .get_mut(type_name.as_ref())
+                    .expect("inserted above")
+            }
+            (b"Record", None) => return Ok(()),
+            _ => &mut self.workouts,
+        };
+        count.observe(start_date.as_deref());
+        Ok(())
+    }
+}
+
+/// Adds one to the count of the key, only allocating the key the first time it is seen
+fn increment(counts: &mut HashMap<String, u64>, key: &str) {
+    match counts.get_mut(key) {
+        Some(count) => *count += 1,
+        None => {
+            counts.insert(key.to_string(), 1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conformance::EXPORT_FILES;
+
+    const EXPORT_XML: &str = r#"<HealthData locale="en_US">
+ <ExportDate value="2024-03-01 09:00:00 -0800"/>
+ <Record type="HKQuantityTypeIdentifierHeartRate" sourceName="Watch" device="&lt;&lt;HKDevice: 0x1&gt;, name:Apple Watch, model:Watch&gt;" startDate="2024-01-05 08:00:00 -0800" value="62"/>
+ <Record type="HKQuantityTypeIdentifierHeartRate" sourceName="Watch" startDate="2023-12-31 23:00:00 -0800" value="64">
+  <MetadataEntry key="HKMetadataKeyHeartRateMotionContext" value="1"/>
+ </Record>
+ <Record type="HKQuantityTypeIdentifierStepCount" sourceName="Phone" startDate="2024-02-01 00:00:00 -0800" value="100"/>
+ <Record sourceName="Phone" startDate="2024-02-01 00:00:00 -0800"/>
+ <Record type="HKQuantityTypeIdentifierBodyMass" sourceName="Scale"/>
+ <Workout workoutActivityType="HKWorkoutActivityTypeRunning" sourceName="Watch" startDate="2024-01-02 09:00:00 -0800">
+  <WorkoutEvent type="HKWorkoutEventTypePause" date="2024-01-02 09:10:00 -0800"/>
+  <WorkoutRoute sourceName="Watch" startDate="2024-01-02 09:00:00 -0800">
+   <FileReference path="/workout-routes/route.gpx"/>
+  </WorkoutRoute>
+ </Workout>
+ <Workout workoutActivityType="HKWorkoutActivityTypeWalking" sourceName="Phone" startDate="2024-01-03 09:00:00 -0800"/>
+ <ActivitySummary dateComponents="2024-01-01"/>
+</HealthData>"#;
+
+    #[test]
+    fn records_are_counted_by_type_with_their_date_range() {
+        let stats = read_stats(EXPORT_XML.as_bytes()).unwrap();
+        let types: Vec<(&str, u64, String)> = stats
+            .types
+            .iter()
+            .map(|(name, count)| (name.as_str(), count.records, count.date_range()))
+            .collect();
+        assert_eq!(
+            types,
+            [
+                ("HKQuantityTypeIdentifierBodyMass", 1, "-".to_string()),
+                (
+                    "HKQuantityTypeIdentifierHeartRate",
+                    2,
+                    "2023-12-31 to 2024-01-05".to_string()
+                ),
+                (
+                    "HKQuantityTypeIdentifierStepCount",
+                    1,
+                    "2024-02-01 to 2024-02-01".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn workouts_routes_and_activity_summaries_are_counted() {
+        let stats = read_stats(EXPORT_XML.as_bytes()).unwrap();
+        assert_eq!(stats.workouts.records, 2);
+        assert_eq!(stats.workouts.date_range(), "2024-01-02 to 2024-01-03");
+        assert_eq!(stats.workouts_with_route, 1);
+        assert_eq!(stats.activity_summaries, 1);
+    }
+
+    #[test]
+    fn sources_and_devices_are_counted_over_records_and_workouts() {
+        let stats = read_stats(EXPORT_XML.as_bytes()).unwrap();
+        let sources: BTreeMap<&str, u64> = stats
+            .sources
+            .iter()
+            .map(|(name, count)| (name.as_str(), *count))
+            .collect();
+        assert_eq!(
+            sources,
+            BTreeMap::from([("Phone", 3), ("Scale", 1), ("Watch", 3)])
+        );
+        assert_eq!(stats.devices.len(), 1);
+        assert_eq!(
+            stats.devices["<<HKDevice: 0x1>, name:Apple Watch, model:Watch>"],
+            1
+        );
+    }
+
+    #[test]
+    fn the_conformance_export_is_summarized() {
+        let stats = read_stats(EXPORT_FILES[0].1).unwrap();
+        assert_eq!(stats.types.len(), 6);
+        assert_eq!(
+            stats.types.values().map(|count| count.records).sum::<u64>(),
+            7
+        );
+        assert_eq!(stats.workouts.records, 1);
+        assert_eq!(stats.workouts_with_route, 1);
+        assert_eq!(stats.activity_summaries, 2);
+    }
+}
@@ -0,0 +1,107 @@
+//! Helpers for the tests that import a miniature export written to a temporary directory
+use crate::core::{self, ImportStatus};
+use crate::identifier::get_valid_sqlite_identifier;
+use crate::options::ImportOptions;
+use crate::sink::Output;
+use indicatif::ProgressBar;
+use sqlx::SqlitePool;
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// A temporary directory of a test, removed when it is dropped
+pub struct TestDir(PathBuf);
+
+impl TestDir {
+    /// Creates an empty directory, named after the test as the tests of a run share the process
+    pub fn new(test_name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!(
+            "healthkit-to-sqlite-{}-{}",
+            test_name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        Self(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+
+    /// The URL of a SQLite database in the directory
+    pub fn db_url(&self, file_name: &str) -> String {
+        format!("sqlite://{}", self.0.join(file_name).display())
+    }
+
+    /// Writes an extracted export with the files, by their path in the export, to the directory
+    /// with the name, returning its path
+    pub fn write_export(&self, dir_name: &str, files: &[(&str, &[u8])]) -> PathBuf {
+        let export_dir = self.0.join(dir_name);
+        let _ = std::fs::remove_dir_all(&export_dir);
+        for (path, contents) in files {
+            let path = export_dir.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(&path, contents).unwrap();
+        }
+        export_dir
+    }
+
+    /// Writes a zip archive with the files, by their name in the archive, to the directory with
+    /// the file name, returning its path
+    pub fn write_zip(&self, file_name: &str, files: &[(&str, &[u8])]) -> PathBuf {
+        let path = self.0.join(file_name);
+        let mut zip = zip::ZipWriter::new(std::fs::File::create(&path).unwrap());
+        for (name, contents) in files {
+            zip.start_file(*name, zip::write::FileOptions::default())
+                .unwrap();
+            zip.write_all(contents).unwrap();
+        }
+        zip.finish().unwrap();
+        path
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Imports the export into the SQLite database
+pub async fn import(
+    export_dir: &Path,
+    db_url: &str,
+    options: &ImportOptions,
+) -> anyhow::Result<ImportStatus> {
+    core::healthkit_to_sqlite(
+        &Output::Database(db_url.to_string()),
+        export_dir,
+        options,
+        &ProgressBar::hidden(),
+    )
+    .await
+}
+
+/// Returns the number of rows of each table of the SQLite database
+pub async fn row_counts(db_url: &str) -> BTreeMap<String, i64> {
+    let db = SqlitePool::connect(db_url).await.unwrap();
+    let table_names: Vec<String> =
+        sqlx::query_scalar("SELECT name FROM sqlite_master WHERE type = 'table' ORDER BY name")
+            .fetch_all(&db)
+            .await
+            .unwrap();
+    let mut counts = BTreeMap::new();
+    for table_name in table_names {
+        let count: i64 = sqlx::query_scalar(&format!(
+            "SELECT COUNT(*) FROM {}",
+            get_valid_sqlite_identifier(&table_name)
+        ))
+        .fetch_one(&db)
+        .await
+        .unwrap();
+        counts.insert(table_name, count);
+    }
+    db.close().await;
+    counts
+}
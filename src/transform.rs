@@ -0,0 +1,330 @@
+//! Adjustments applied to every row between parsing the export and writing it to the output
+use crate::core::{
+    DatabaseRow, DatabaseValue, HKTables, HEALTHKIT_DATE_FORMAT, HEALTHKIT_OFFSET_DATE_FORMAT,
+};
+use crate::device;
+use crate::metadata;
+use crate::options::{ImportOptions, KeepRaw};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use time::UtcOffset;
+use unicode_normalization::{is_nfc, UnicodeNormalization};
+
+/// Suffix of the columns holding the unmodified value of a transformed column
+const RAW_COLUMN_SUFFIX: &str = "_raw";
+
+/// Suffix of the columns holding the UTC offset of a date stored in UTC with --utc
+const UTC_OFFSET_COLUMN_SUFFIX: &str = "_utc_offset_minutes";
+
+/// Suffix of the columns holding the local date of a date stored in UTC with --local-dates
+const LOCAL_DATE_COLUMN_SUFFIX: &str = "_local_date";
+
+/// Columns naming the source of a sample. Different iOS versions write the same app or device
+/// name with different Unicode encodings (e.g. the apostrophe in "John’s iPhone"), so these are
+/// normalized to NFC to keep grouping by source from splitting on invisible differences.
+const NORMALIZED_COLUMNS: &[&str] = &["sourceName", "device"];
+
+/// The DATE columns of each table, which are given parallel columns with --keep-raw=dates and
+/// --utc
+pub type DateColumns = HashMap<String, HashSet<String>>;
+
+/// Adds the columns that transform_row may produce to the inferred tables, returning the date
+/// columns given parallel columns
+pub fn extend_tables(tables: &mut HKTables, options: &ImportOptions) -> DateColumns {
+    if options.friendly_names {
+        metadata::extend_tables(tables);
+    }
+    if options.device_columns {
+        device::extend_tables(tables);
+    }
+    let mut parallel_date_columns = DateColumns::new();
+    if options.keeps_raw(KeepRaw::Dates) || options.utc {
+        for (table_name, columns) in tables.iter_mut() {
+            let date_columns: HashSet<String> = columns
+                .iter()
+                .filter(|(_, ty)| **ty == "DATE")
+                .map(|(column_name, _)| column_name.clone())
+                .collect();
+            for column_name in &date_columns {
+                if options.keeps_raw(KeepRaw::Dates) {
+                    columns.insert(format!("{}{}", column_name, RAW_COLUMN_SUFFIX), "TEXT");
+                }
+                if options.utc {
+                    columns.insert(
+                        format!("{}{}", column_name, UTC_OFFSET_COLUMN_SUFFIX),
+                        "INTEGER",
+                    );
+                }
+                if options.local_dates {
+                    columns.insert(
+                        format!("{}{}", column_name, LOCAL_DATE_COLUMN_SUFFIX),
+                        "DATE",
+                    );
+                }
+            }
+            if !date_columns.is_empty() {
+                parallel_date_columns.insert(table_name.clone(), date_columns);
+            }
+        }
+    }
+    if !options.keeps_raw(KeepRaw::Columns) {
+        return parallel_date_columns;
+    }
+    for (table_name, rounded_columns) in &options.config().rounding {
+        if let Some(columns) = tables.get_mut(table_name) {
+            for column_name in rounded_columns.keys() {
+                if let Some(ty) = columns.get(column_name).copied() {
+                    columns.insert(format!("{}{}", column_name, RAW_COLUMN_SUFFIX), ty);
+                }
+            }
+        }
+    }
+    parallel_date_columns
+}
+
+/// Applies the configured transforms to a row of the given table
+pub fn transform_row(
+    table_name: &str,
+    row: DatabaseRow,
+    options: &ImportOptions,
+    date_columns: &DateColumns,
+) -> DatabaseRow {
+    let table_date_columns = date_columns.get(table_name);
+    // Before any other transform, so the raw columns hold the dates exactly as exported
+    let row = match table_date_columns {
+        Some(date_columns) if options.keeps_raw(KeepRaw::Dates) => {
+            keep_raw_dates(row, date_columns)
+        }
+        _ => row,
+    };
+    let row = match table_date_columns {
+        Some(date_columns) if options.utc => to_utc(row, date_columns, options.local_dates),
+        _ => row,
+    };
+    let row = normalize_row(row);
+    let row = if options.friendly_names {
+        metadata::decode_row(row)
+    } else {
+        row
+    };
+    let row = if options.device_columns {
+        device::parse_row(row)
+    } else {
+        row
+    };
+    match options.config().rounding.get(table_name) {
+        Some(rounded_columns) => {
+            round_row(row, rounded_columns, options.keeps_raw(KeepRaw::Columns))
+        }
+        None => row,
+    }
+}
+
+/// Rounds the real values of the configured columns to a number of decimal places
+fn round_row(
+    row: DatabaseRow,
+    rounded_columns: &BTreeMap<String, i32>,
+    keep_raw: bool,
+) -> DatabaseRow {
+    let mut rounded = DatabaseRow::with_capacity(row.len());
+    for (name, value) in row {
+        match (rounded_columns.get(&name), value) {
+            (Some(decimals), DatabaseValue::Real(r)) => {
+                let scale = 10f64.powi(*decimals);
+                rounded.push((
+                    name.clone(),
+                    DatabaseValue::Real((r * scale).round() / scale),
+                ));
+                if keep_raw {
+                    rounded.push((
+                        format!("{}{}", name, RAW_COLUMN_SUFFIX),
+                        DatabaseValue::Real(r),
+                    ));
+                }
+            }
+            (_, value) => rounded.push((name, value)),
+        }
+    }
+    rounded
+}
+
+/// Adds a raw column to each date, holding the date string as it appears in export.xml, e.g.
+/// `2023-01-01 08:00:00 -0800`
+fn keep_raw_dates(mut row: DatabaseRow, date_columns: &HashSet<String>) -> DatabaseRow {
+    let mut raw_dates = Vec::new();
+    for (name, value) in &row {
+        if !date_columns.contains(name) {
+            continue;
+        }
+        // The export's formats are fixed width, so formatting the parsed date restores its string
+        let raw = match value {
+            DatabaseValue::OffsetDateTime(date) => date.format(&HEALTHKIT_OFFSET_DATE_FORMAT),
+            DatabaseValue::Date(date) => date.format(&HEALTHKIT_DATE_FORMAT),
+            _ => continue,
+        };
+        if let Ok(raw) = raw {
+            raw_dates.push((
+                format!("{}{}", name, RAW_COLUMN_SUFFIX),
+                DatabaseValue::Text(raw),
+            ));
+        }
+    }
+    row.extend(raw_dates);
+    row
+}
+
+/// Converts each date with a UTC offset to UTC, adding a column holding the offset in minutes,
+/// e.g. `startDate_utc_offset_minutes = -480`, and with local_dates the date in that offset, so
+/// rows can be grouped by the day they happened on wherever they happened
+fn to_utc(row: DatabaseRow, date_columns: &HashSet<String>, local_dates: bool) -> DatabaseRow {
+    let mut utc_row = DatabaseRow::with_capacity(row.len());
+    let mut parallel_columns = Vec::new();
+    for (name, value) in row {
+        match value {
+            DatabaseValue::OffsetDateTime(date) if date_columns.contains(&name) => {
+                parallel_columns.push((
+                    format!("{}{}", name, UTC_OFFSET_COLUMN_SUFFIX),
+                    DatabaseValue::Integer(date.offset().whole_minutes().into()),
+                ));
+                if local_dates {
+                    parallel_columns.push((
+                        format!("{}{}", name, LOCAL_DATE_COLUMN_SUFFIX),
+                        DatabaseValue::Date(date.date()),
+                    ));
+                }
+                utc_row.push((
+                    name,
+                    DatabaseValue::OffsetDateTime(date.to_offset(UtcOffset::UTC)),
+                ));
+            }
+            value => utc_row.push((name, value)),
+        }
+    }
+    utc_row.extend(parallel_columns);
+    utc_row
+}
+
+/// Normalizes the text of the source columns to Unicode NFC
+fn normalize_row(row: DatabaseRow) -> DatabaseRow {
+    row.into_iter()
+        .map(|(name, value)| match value {
+            DatabaseValue::Text(text)
+                if NORMALIZED_COLUMNS.contains(&name.as_str()) && !is_nfc(&text) =>
+            {
+                (name, DatabaseValue::Text(text.nfc().collect()))
+            }
+            value => (name, value),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    const BODY_MASS: &str = "HKQuantityTypeIdentifierBodyMass";
+
+    /// Returns the columns of the row with their values as text
+    fn texts(row: &DatabaseRow) -> Vec<(&str, String)> {
+        row.iter()
+            .map(|(name, value)| (name.as_str(), value.to_text()))
+            .collect()
+    }
+
+    fn rounding_options(keep_raw: Vec<KeepRaw>) -> ImportOptions {
+        let config: Config = serde_json::from_str(
+            r#"{ "rounding": { "HKQuantityTypeIdentifierBodyMass": { "value": 1, "count": -1 } } }"#,
+        )
+        .unwrap();
+        ImportOptions {
+            config: Some(config),
+            keep_raw,
+            ..ImportOptions::defaults()
+        }
+    }
+
+    #[test]
+    fn configured_columns_are_rounded() {
+        let options = rounding_options(vec![]);
+        let row = vec![
+            ("value".to_string(), DatabaseValue::Real(72.46)),
+            ("count".to_string(), DatabaseValue::Real(1234.5)),
+            ("other".to_string(), DatabaseValue::Real(0.123)),
+        ];
+        let row = transform_row(BODY_MASS, row, &options, &DateColumns::new());
+        assert_eq!(
+            texts(&row),
+            [
+                ("value", "72.5".to_string()),
+                ("count", "1230".to_string()),
+                ("other", "0.123".to_string())
+            ]
+        );
+        // Integers and the columns of other tables are left as they are
+        let row = vec![("value".to_string(), DatabaseValue::Integer(72))];
+        let row = transform_row(BODY_MASS, row, &options, &DateColumns::new());
+        assert_eq!(texts(&row), [("value", "72".to_string())]);
+        let row = vec![("value".to_string(), DatabaseValue::Real(72.46))];
+        let row = transform_row(
+            "HKQuantityTypeIdentifierHeight",
+            row,
+            &options,
+            &DateColumns::new(),
+        );
+        assert_eq!(texts(&row), [("value", "72.46".to_string())]);
+    }
+
+    #[test]
+    fn rounded_columns_can_keep_their_exact_values() {
+        let options = rounding_options(vec![KeepRaw::Columns]);
+        let mut tables = HKTables::from([(
+            BODY_MASS.to_string(),
+            BTreeMap::from([("value".to_string(), "REAL")]),
+        )]);
+        extend_tables(&mut tables, &options);
+        assert_eq!(
+            tables[BODY_MASS],
+            BTreeMap::from([
+                ("value".to_string(), "REAL"),
+                ("value_raw".to_string(), "REAL")
+            ])
+        );
+        let row = vec![("value".to_string(), DatabaseValue::Real(72.46))];
+        let row = transform_row(BODY_MASS, row, &options, &DateColumns::new());
+        assert_eq!(
+            texts(&row),
+            [
+                ("value", "72.5".to_string()),
+                ("value_raw", "72.46".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn source_names_are_normalized_to_nfc() {
+        let decomposed = "Rene\u{301}e\u{2019}s iPhone";
+        let row = vec![
+            (
+                "sourceName".to_string(),
+                DatabaseValue::Text(decomposed.to_string()),
+            ),
+            (
+                "value".to_string(),
+                DatabaseValue::Text(decomposed.to_string()),
+            ),
+        ];
+        let row = transform_row(
+            "Workout",
+            row,
+            &ImportOptions::defaults(),
+            &DateColumns::new(),
+        );
+        assert_eq!(
+            texts(&row),
+            [
+                ("sourceName", "Ren\u{e9}e\u{2019}s iPhone".to_string()),
+                ("value", decomposed.to_string())
+            ]
+        );
+    }
+}
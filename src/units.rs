@@ -0,0 +1,424 @@
+//! Checks that every quantity table has a single unit. Apps can write the same type in
+//! different units, e.g. a scale in lb and a workout app in kg, which skews any aggregate over
+//! the table.
+use crate::core::{DatabaseRow, DatabaseValue, HKTables};
+use crate::identifier::get_valid_sqlite_identifier;
+use crate::registry;
+use log::*;
+use serde_json::json;
+use std::collections::BTreeMap;
+
+/// Name of the table listing the unit of each quantity table with --normalize-units
+pub const UNITS_TABLE_NAME: &str = "_units";
+
+/// Prefix of the quantity tables, the only tables with units
+const QUANTITY_TABLE_PREFIX: &str = "HKQuantityTypeIdentifier";
+
+/// Linear units as (unit, dimension, factor to the base unit of the dimension). Units that
+/// aren't linear, such as degF, or that depend on a molar mass, such as mmol/L, are left out.
+const UNITS: &[(&str, &str, f64)] = &[
+    ("m", "length", 1.0),
+    ("km", "length", 1000.0),
+    ("cm", "length", 0.01),
+    ("mm", "length", 0.001),
+    ("mi", "length", 1609.344),
+    ("yd", "length", 0.9144),
+    ("ft", "length", 0.3048),
+    ("in", "length", 0.0254),
+    ("kg", "mass", 1.0),
+    ("g", "mass", 0.001),
+    ("mg", "mass", 1e-6),
+    ("mcg", "mass", 1e-9),
+    ("lb", "mass", 0.45359237),
+    ("oz", "mass", 0.028349523125),
+    ("st", "mass", 6.35029318),
+    ("L", "volume", 1.0),
+    ("mL", "volume", 0.001),
+    ("fl_oz_us", "volume", 0.0295735295625),
+    ("fl_oz_imp", "volume", 0.0284130625),
+    ("cup_us", "volume", 0.2365882365),
+    ("kcal", "energy", 1.0),
+    ("Cal", "energy", 1.0),
+    ("cal", "energy", 0.001),
+    ("kJ", "energy", 0.239005736),
+    ("s", "time", 1.0),
+    ("ms", "time", 0.001),
+    ("min", "time", 60.0),
+    ("hr", "time", 3600.0),
+    ("d", "time", 86400.0),
+    ("m/s", "speed", 1.0),
+    ("km/hr", "speed", 1.0 / 3.6),
+    ("mi/hr", "speed", 0.44704),
+];
+
+/// Returns the factor converting values in one unit to another, if both are known linear units
+/// of the same dimension
+pub fn conversion_factor(from: &str, to: &str) -> Option<f64> {
+    let unit = |name: &str| UNITS.iter().find(|(unit, _, _)| *unit == name);
+    let (_, from_dimension, from_factor) = unit(from)?;
+    let (_, to_dimension, to_factor) = unit(to)?;
+    (from_dimension == to_dimension).then(|| from_factor / to_factor)
+}
+
+/// Counts the samples of each unit in every quantity table
+#[derive(Default)]
+pub struct UnitCheck {
+    counts: BTreeMap<String, BTreeMap<String, u64>>,
+}
+
+impl UnitCheck {
+    pub fn observe(&mut self, table_name: &str, row: &DatabaseRow) {
+        if !table_name.starts_with(QUANTITY_TABLE_PREFIX) {
+            return;
+        }
+        let unit = row.iter().find_map(|(name, value)| match value {
+            DatabaseValue::Text(unit) if name == "unit" => Some(unit.as_str()),
+            _ => None,
+        });
+        if let Some(unit) = unit {
+            let units = self.counts.entry(table_name.to_string()).or_default();
+            match units.get_mut(unit) {
+                Some(count) => *count += 1,
+                None => {
+                    units.insert(unit.to_string(), 1);
+                }
+            }
+        }
+    }
+
+    /// Warns about every table with more than one unit, suggesting how to convert its values to
+    /// a single unit, and returns the names of those tables. `output_table_name` maps the names
+    /// to those of the tables written.
+    pub fn report(&self, output_table_name: impl Fn(&str) -> String) -> Vec<String> {
+        let mut mixed = Vec::new();
+        for (table_name, units) in &self.counts {
+            if units.len() < 2 {
+                continue;
+            }
+            let output_table = output_table_name(table_name);
+            warn!(
+                "{} has samples in {} units: {}",
+                output_table,
+                units.len(),
+                units
+                    .iter()
+                    .map(|(unit, count)| format!("{} ({} samples)", unit, count))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+            // The registry's canonical unit, or else the most common unit of the table
+            let canonical = registry::lookup(table_name)
+                .and_then(|info| info.unit)
+                .filter(|unit| {
+                    units
+                        .keys()
+                        .any(|other| conversion_factor(other, unit).is_some())
+                });
+            let target: &str = match canonical {
+                Some(unit) => unit,
+                None => units
+                    .iter()
+                    .max_by_key(|(_, count)| **count)
+                    .map(|(unit, _)| unit.as_str())
+                    .expect("the table has units"),
+            };
+            for unit in units.keys().filter(|unit| unit.as_str() != target) {
+                match conversion_factor(unit, target) {
+                    Some(factor) => warn!(
+                        "To convert its {} samples to {}: UPDATE {} SET value = value * {}, unit = '{}' WHERE unit = '{}'",
+                        unit, target, get_valid_sqlite_identifier(&output_table), factor, target, unit
+                    ),
+                    None => warn!(
+                        "Its {} samples can't be converted to {} automatically",
+                        unit, target
+                    ),
+                }
+            }
+            mixed.push(output_table);
+        }
+        mixed
+    }
+}
+
+/// Returns the registry's canonical unit of a quantity table, if values in other units can be
+/// converted to it
+fn canonical_unit(table_name: &str) -> Option<&'static str> {
+    if !table_name.starts_with(QUANTITY_TABLE_PREFIX) {
+        return None;
+    }
+    registry::lookup(table_name)
+        .and_then(|info| info.unit)
+        .filter(|unit| conversion_factor(unit, unit).is_some())
+}
+
+/// Makes the value column of each quantity table that is converted REAL, as converted values
+/// have fractions
+pub fn extend_tables(tables: &mut HKTables) {
+    for (table_name, columns) in tables.iter_mut() {
+        if canonical_unit(table_name).is_none() {
+            continue;
+        }
+        if let Some(ty) = columns.get_mut("value") {
+            if *ty == "INTEGER" {
+                *ty = "REAL";
+            }
+        }
+    }
+}
+
+/// Converts the values of quantity tables to the registry's canonical unit of the table, e.g.
+/// distances in mi to km, counting the samples converted from each unit
+#[derive(Default)]
+pub struct UnitNormalizer {
+    converted: BTreeMap<String, (&'static str, BTreeMap<String, u64>)>,
+}
+
+impl UnitNormalizer {
+    /// Converts the value and unit of a row of the table to the canonical unit. Values in units
+    /// that can't be converted are left as they are, and reported by UnitCheck.
+    pub fn normalize_row(&mut self, table_name: &str, mut row: DatabaseRow) -> DatabaseRow {
+        let canonical = match canonical_unit(table_name) {
+            Some(canonical) => canonical,
+            None => return row,
+        };
+        let (_, converted) = self
+            .converted
+            .entry(table_name.to_string())
+            .or_insert_with(|| (canonical, BTreeMap::new()));
+        let conversion = row.iter().find_map(|(name, value)| match value {
+            DatabaseValue::Text(unit) if name == "unit" && unit != canonical => {
+                conversion_factor(unit, canonical).map(|factor| (unit.clone(), factor))
+            }
+            _ => None,
+        });
+        let (unit, factor) = match conversion {
+            Some(conversion) => conversion,
+            None => return row,
+        };
+        for (name, value) in row.iter_mut() {
+            if name == "unit" {
+                *value = DatabaseValue::Text(canonical.to_string());
+            } else if name == "value" {
+                let converted = match value {
+                    DatabaseValue::Integer(i) => *i as f64 * factor,
+                    DatabaseValue::Real(r) => *r * factor,
+                    _ => continue,
+                };
+                *value = DatabaseValue::Real(converted);
+            }
+        }
+        *converted.entry(unit).or_default() += 1;
+        row
+    }
+
+    /// The schema of the units table
+    pub fn table_schema() -> HKTables {
+        let columns = [
+            ("table", "TEXT"),
+            ("unit", "TEXT"),
+            ("convertedFrom", "JSON"),
+        ];
+        HKTables::from([(
+            UNITS_TABLE_NAME.to_string(),
+            columns
+                .iter()
+                .map(|(name, ty)| (name.to_string(), *ty))
+                .collect(),
+        )])
+    }
+
+    /// Returns a row for each quantity table with the unit its values are in, and the number of
+    /// samples converted from each other unit. `output_table_name` maps the names to those of the
+    /// tables written.
+    pub fn into_rows(self, output_table_name: impl Fn(&str) -> String) -> Vec<DatabaseRow> {
+        self.converted
+            .into_iter()
+            .map(|(table_name, (unit, converted))| {
+                vec![
+                    (
+                        "table".to_string(),
+                        DatabaseValue::Text(output_table_name(&table_name)),
+                    ),
+                    ("unit".to_string(), DatabaseValue::Text(unit.to_string())),
+                    (
+                        "convertedFrom".to_string(),
+                        DatabaseValue::Json(json!(converted)),
+                    ),
+                ]
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(unit: &str, value: DatabaseValue) -> DatabaseRow {
+        vec![
+            ("unit".to_string(), DatabaseValue::Text(unit.to_string())),
+            ("value".to_string(), value),
+        ]
+    }
+
+    fn real(row: &DatabaseRow, column: &str) -> f64 {
+        match row.iter().find(|(name, _)| name == column) {
+            Some((_, DatabaseValue::Real(r))) => *r,
+            _ => panic!("{} is not REAL", column),
+        }
+    }
+
+    #[test]
+    fn conversion_factors() {
+        assert_eq!(conversion_factor("km", "m"), Some(1000.0));
+        assert_eq!(conversion_factor("kg", "kg"), Some(1.0));
+        assert!((conversion_factor("lb", "kg").unwrap() - 0.45359237).abs() < 1e-12);
+        assert!((conversion_factor("kg", "lb").unwrap() - 2.20462262).abs() < 1e-8);
+        // Different dimensions, and units that aren't linear or known
+        assert_eq!(conversion_factor("kg", "m"), None);
+        assert_eq!(conversion_factor("degF", "degC"), None);
+        assert_eq!(conversion_factor("count/min", "count/min"), None);
+    }
+
+    #[test]
+    fn conversion_pairs() {
+        let pairs: &[(&str, &str, f64)] = &[
+            ("mi", "km", 1.609344),
+            ("km", "mi", 0.621371192),
+            ("ft", "m", 0.3048),
+            ("in", "cm", 2.54),
+            ("yd", "ft", 3.0),
+            ("mm", "m", 0.001),
+            ("lb", "kg", 0.45359237),
+            ("oz", "g", 28.349523125),
+            ("st", "lb", 14.0),
+            ("mcg", "mg", 0.001),
+            ("fl_oz_us", "mL", 29.5735295625),
+            ("fl_oz_imp", "mL", 28.4130625),
+            ("cup_us", "fl_oz_us", 8.0),
+            ("L", "mL", 1000.0),
+            ("Cal", "kcal", 1.0),
+            ("cal", "kcal", 0.001),
+            ("kJ", "kcal", 0.239005736),
+            ("hr", "min", 60.0),
+            ("d", "hr", 24.0),
+            ("ms", "s", 0.001),
+            ("km/hr", "m/s", 1.0 / 3.6),
+            ("mi/hr", "km/hr", 1.609344),
+        ];
+        for (from, to, factor) in pairs {
+            let converted = conversion_factor(from, to).unwrap();
+            assert!(
+                (converted - factor).abs() < 1e-9 * factor,
+                "{} to {} is {}, not {}",
+                from,
+                to,
+                converted,
+                factor
+            );
+        }
+        // Every pair of the same dimension converts both ways, and no other pair converts
+        for (from, from_dimension, _) in UNITS {
+            for (to, to_dimension, _) in UNITS {
+                match (conversion_factor(from, to), conversion_factor(to, from)) {
+                    (Some(there), Some(back)) => {
+                        assert_eq!(from_dimension, to_dimension);
+                        assert!((there * back - 1.0).abs() < 1e-12, "{} and {}", from, to);
+                    }
+                    (None, None) => assert_ne!(from_dimension, to_dimension),
+                    _ => panic!("{} and {} convert one way only", from, to),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn samples_are_normalized_per_unit() {
+        let samples: &[(&str, &str, f64, &str, f64)] = &[
+            ("BodyMass", "lb", 100.0, "kg", 45.359237),
+            ("BodyMass", "st", 10.0, "kg", 63.5029318),
+            ("BodyMass", "kg", 70.0, "kg", 70.0),
+            ("Height", "ft", 6.0, "m", 1.8288),
+            ("Height", "cm", 180.0, "m", 1.8),
+            ("DistanceWalkingRunning", "mi", 1.0, "m", 1609.344),
+            ("DistanceWalkingRunning", "km", 5.0, "m", 5000.0),
+            ("DietaryWater", "fl_oz_us", 8.0, "mL", 236.5882365),
+            ("DietaryWater", "L", 1.5, "mL", 1500.0),
+            ("ActiveEnergyBurned", "kJ", 100.0, "kcal", 23.9005736),
+            ("ActiveEnergyBurned", "Cal", 250.0, "kcal", 250.0),
+            ("AppleExerciseTime", "hr", 1.5, "min", 90.0),
+            ("AppleExerciseTime", "s", 30.0, "min", 0.5),
+            ("WalkingSpeed", "km/hr", 3.6, "m/s", 1.0),
+            ("WalkingSpeed", "mi/hr", 1.0, "m/s", 0.44704),
+            // Unknown units, and units of another dimension, pass through unchanged
+            ("BodyMass", "furlong", 3.0, "furlong", 3.0),
+            ("BodyMass", "m", 1.0, "m", 1.0),
+            ("DietaryWater", "cup_imp", 2.0, "cup_imp", 2.0),
+            ("WalkingSpeed", "ft/s", 4.0, "ft/s", 4.0),
+            // Tables whose canonical unit isn't linear, or that aren't known
+            ("HeartRate", "count/s", 1.0, "count/s", 1.0),
+            ("BodyTemperature", "degF", 98.6, "degF", 98.6),
+            ("SomethingNew", "lb", 1.0, "lb", 1.0),
+        ];
+        let mut normalizer = UnitNormalizer::default();
+        for (table, unit, value, expected_unit, expected_value) in samples {
+            let table_name = format!("{}{}", QUANTITY_TABLE_PREFIX, table);
+            let row =
+                normalizer.normalize_row(&table_name, sample(unit, DatabaseValue::Real(*value)));
+            assert_eq!(row[0].1.to_text(), *expected_unit, "{} in {}", table, unit);
+            let converted = real(&row, "value");
+            assert!(
+                (converted - expected_value).abs() < 1e-9 * expected_value,
+                "{} {} in {} is {}, not {}",
+                value,
+                unit,
+                table,
+                converted,
+                expected_value
+            );
+        }
+    }
+
+    #[test]
+    fn values_are_converted_to_the_canonical_unit() {
+        let mut normalizer = UnitNormalizer::default();
+        let table_name = "HKQuantityTypeIdentifierBodyMass";
+        let row = normalizer.normalize_row(table_name, sample("lb", DatabaseValue::Integer(150)));
+        assert!((real(&row, "value") - 68.0388555).abs() < 1e-6);
+        assert_eq!(row[0].1.to_text(), "kg");
+        let row = normalizer.normalize_row(table_name, sample("kg", DatabaseValue::Real(70.5)));
+        assert_eq!(real(&row, "value"), 70.5);
+        let rows = normalizer.into_rows(str::to_string);
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0][2].1.to_text(), r#"{"lb":1}"#);
+    }
+
+    #[test]
+    fn values_in_other_dimensions_are_left_as_they_are() {
+        let mut normalizer = UnitNormalizer::default();
+        let row = normalizer.normalize_row(
+            "HKQuantityTypeIdentifierBodyTemperature",
+            sample("degF", DatabaseValue::Real(98.6)),
+        );
+        assert_eq!(row[0].1.to_text(), "degF");
+        assert_eq!(real(&row, "value"), 98.6);
+        let row = normalizer.normalize_row(
+            "HKQuantityTypeIdentifierBodyMass",
+            sample("m", DatabaseValue::Real(1.0)),
+        );
+        assert_eq!(row[0].1.to_text(), "m");
+    }
+
+    #[test]
+    fn mixed_units_are_reported() {
+        let mut check = UnitCheck::default();
+        let table_name = "HKQuantityTypeIdentifierBodyMass";
+        check.observe(table_name, &sample("kg", DatabaseValue::Real(70.0)));
+        check.observe(table_name, &sample("lb", DatabaseValue::Real(150.0)));
+        check.observe(
+            "HKQuantityTypeIdentifierStepCount",
+            &sample("count", DatabaseValue::Integer(10)),
+        );
+        assert_eq!(check.report(str::to_string), [table_name]);
+    }
+}
@@ -0,0 +1,217 @@
+//! What to do with records whose type isn't in the registry, which happens when a newer iOS
+//! version adds a type or an app writes a private one
+use crate::core::HKTables;
+use crate::options::UnknownTypePolicy;
+use crate::registry;
+use crate::single_table::is_record_table;
+use clap::ValueEnum;
+use log::*;
+use std::collections::BTreeMap;
+
+/// Tracks the record types missing from the registry and the number of records of each
+pub struct UnknownTypes {
+    policy: UnknownTypePolicy,
+    counts: BTreeMap<String, u64>,
+}
+
+impl UnknownTypes {
+    pub fn new(policy: UnknownTypePolicy) -> Self {
+        Self {
+            policy,
+            counts: BTreeMap::new(),
+        }
+    }
+
+    /// Finds the record tables of unknown types, failing if the policy is to fail and removing
+    /// them if it is to warn
+    pub fn check_tables(&mut self, tables: &mut HKTables) -> anyhow::Result<()> {
+        let unknown: Vec<String> = tables
+            .keys()
+            .filter(|table_name| {
+                is_record_table(table_name) && registry::lookup(table_name).is_none()
+            })
+            .cloned()
+            .collect();
+        if unknown.is_empty() {
+            return Ok(());
+        }
+        match self.policy {
+            UnknownTypePolicy::Fail => anyhow::bail!(
+                "The export has record types missing from the type registry: {}. Import with --unknown-types warn to skip them or import-generic to import them",
+                unknown.join(", ")
+            ),
+            UnknownTypePolicy::Warn => {
+                for table_name in &unknown {
+                    warn!("Skipping the records of unknown type {}", table_name);
+                    tables.remove(table_name);
+                }
+            }
+            UnknownTypePolicy::ImportGeneric => {
+                debug!("Importing the unknown record types {}", unknown.join(", "));
+            }
+        }
+        self.counts = unknown
+            .into_iter()
+            .map(|table_name| (table_name, 0))
+            .collect();
+        Ok(())
+    }
+
+    /// Counts a row of the table if it holds records of an unknown type, returning false if the
+    /// row should be skipped
+    pub fn observe(&mut self, table_name: &str) -> bool {
+        match self.counts.get_mut(table_name) {
+            Some(count) => {
+                *count += 1;
+                self.policy != UnknownTypePolicy::Warn
+            }
+            None => true,
+        }
+    }
+
+    /// Logs the number of records of each unknown type, returning the `_meta` entries
+    /// summarizing them
+    pub fn report(&self) -> Vec<(&'static str, String)> {
+        if self.counts.is_empty() {
+            return Vec::new();
+        }
+        let action = match self.policy {
+            UnknownTypePolicy::Warn => "Skipped",
+            _ => "Imported",
+        };
+        for (table_name, count) in &self.counts {
+            info!(
+                "{} {} records of unknown type {}",
+                action, count, table_name
+            );
+        }
+        vec![
+            ("unknown_types", self.counts.len().to_string()),
+            (
+                "unknown_type_records",
+                self.counts.values().sum::<u64>().to_string(),
+            ),
+            (
+                "unknown_type_policy",
+                self.policy
+                    .to_possible_value()
+                    .expect("no skipped values")
+                    .get_name()
+                    .to_string(),
+            ),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conformance::EXPORT_FILES;
+    use crate::options::ImportOptions;
+    use crate::testing::{self, TestDir};
+
+    const UNKNOWN_TYPE: &str = "HKQuantityTypeIdentifierNotYetInvented";
+
+    fn tables() -> HKTables {
+        [
+            "HKQuantityTypeIdentifierHeartRate",
+            UNKNOWN_TYPE,
+            "Workout",
+            "WorkoutEvent",
+        ]
+        .into_iter()
+        .map(|table_name| (table_name.to_string(), BTreeMap::new()))
+        .collect()
+    }
+
+    #[test]
+    fn unknown_record_types_fail_the_import_under_fail() {
+        let mut unknown_types = UnknownTypes::new(UnknownTypePolicy::Fail);
+        let error = unknown_types.check_tables(&mut tables()).unwrap_err();
+        assert!(error.to_string().contains(UNKNOWN_TYPE), "{}", error);
+    }
+
+    #[test]
+    fn unknown_record_types_are_skipped_and_counted_under_warn() {
+        let mut unknown_types = UnknownTypes::new(UnknownTypePolicy::Warn);
+        let mut tables = tables();
+        unknown_types.check_tables(&mut tables).unwrap();
+        assert_eq!(
+            tables.keys().collect::<Vec<_>>(),
+            [
+                "HKQuantityTypeIdentifierHeartRate",
+                "Workout",
+                "WorkoutEvent"
+            ]
+        );
+        assert!(!unknown_types.observe(UNKNOWN_TYPE));
+        assert!(!unknown_types.observe(UNKNOWN_TYPE));
+        assert!(unknown_types.observe("HKQuantityTypeIdentifierHeartRate"));
+        assert_eq!(
+            unknown_types.report(),
+            [
+                ("unknown_types", "1".to_string()),
+                ("unknown_type_records", "2".to_string()),
+                ("unknown_type_policy", "warn".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn unknown_record_types_are_kept_and_counted_under_import_generic() {
+        let mut unknown_types = UnknownTypes::new(UnknownTypePolicy::ImportGeneric);
+        let mut tables = tables();
+        unknown_types.check_tables(&mut tables).unwrap();
+        assert!(tables.contains_key(UNKNOWN_TYPE));
+        assert!(unknown_types.observe(UNKNOWN_TYPE));
+        assert_eq!(
+            unknown_types.report(),
+            [
+                ("unknown_types", "1".to_string()),
+                ("unknown_type_records", "1".to_string()),
+                ("unknown_type_policy", "import-generic".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn nothing_is_reported_without_unknown_record_types() {
+        let mut unknown_types = UnknownTypes::new(UnknownTypePolicy::Fail);
+        let mut tables = tables();
+        tables.remove(UNKNOWN_TYPE);
+        unknown_types.check_tables(&mut tables).unwrap();
+        assert_eq!(tables.len(), 3);
+        assert!(unknown_types.report().is_empty());
+    }
+
+    #[tokio::test]
+    async fn records_of_unknown_types_are_imported_or_left_out_by_policy() {
+        let dir = TestDir::new("unknown-types-warn");
+        let export_xml = std::str::from_utf8(EXPORT_FILES[0].1).unwrap().replace(
+            "<ActivitySummary dateComponents=\"2024-01-01\"",
+            &format!(
+                "<Record type=\"{}\" sourceName=\"Watch\" startDate=\"2024-01-01 08:00:00 -0800\" endDate=\"2024-01-01 08:00:00 -0800\" value=\"1\"/>\n <ActivitySummary dateComponents=\"2024-01-01\"",
+                UNKNOWN_TYPE
+            ),
+        );
+        let mut files = EXPORT_FILES.to_vec();
+        files[0] = ("export.xml", export_xml.as_bytes());
+        let export_dir = dir.write_export("export", &files);
+        for (policy, rows) in [
+            (UnknownTypePolicy::Warn, None),
+            (UnknownTypePolicy::ImportGeneric, Some(1)),
+        ] {
+            let db_url = dir.db_url(&format!("{:?}.db", policy));
+            let options = ImportOptions {
+                unknown_types: policy,
+                ..ImportOptions::defaults()
+            };
+            testing::import(&export_dir, &db_url, &options)
+                .await
+                .unwrap();
+            let counts = testing::row_counts(&db_url).await;
+            assert_eq!(counts.get(UNKNOWN_TYPE).copied(), rows, "{:?}", policy);
+            assert_eq!(counts["HKQuantityTypeIdentifierHeartRate"], 2);
+        }
+    }
+}
@@ -0,0 +1,297 @@
+//! Reconciling a database built by a previous import with the export it was built from
+use crate::cipher;
+use crate::identifier::get_valid_sqlite_identifier;
+use crate::naming;
+use crate::options::Naming;
+use crate::schema;
+use crate::single_table::SAMPLES_TABLE_NAME;
+use crate::source::{ExportSource, ZipLimits};
+use crate::VerifyArgs;
+use anyhow::Context;
+use clap::ValueEnum;
+use console::Term;
+use log::*;
+use quick_xml::events::{BytesStart, Event};
+use sqlx::SqlitePool;
+use std::collections::BTreeMap;
+use std::io::BufReader;
+
+/// The elements inside HealthData counted in addition to the records, which are counted by type
+const COUNTED_ELEMENTS: &[&str] = &["Workout", "ActivitySummary"];
+
+/// Counts the elements of export.xml by type and compares them with the row counts of their
+/// tables, failing if any table is missing or has fewer rows than its elements
+pub async fn verify(args: &VerifyArgs) -> anyhow::Result<()> {
+    let expected = count_elements(&ExportSource::detect(&args.export_path)?)?;
+    let db = SqlitePool::connect_with(cipher::connect_options(&args.db_url)?)
+        .await
+        .with_context(|| {
+            format!(
+                "Failed to open the database at \"{}\"",
+                cipher::redact_key(&args.db_url)
+            )
+        })?;
+    let naming = read_naming(&db).await?;
+    let term = Term::stdout();
+    let mut mismatches = 0;
+    for (table_name, elements) in &expected {
+        let rows = count_rows(&db, table_name, naming).await?;
+        let status = match rows {
+            None => "missing table".to_string(),
+            Some(rows) if rows < *elements => format!("{} rows missing", elements - rows),
+            Some(rows) if rows > *elements => {
+                format!("{} rows more than the export", rows - elements)
+            }
+            Some(_) => "ok".to_string(),
+        };
+        if rows.is_none_or(|rows| rows < *elements) {
+            mismatches += 1;
+        }
+        if rows != Some(*elements) || args.all {
+            term.write_line(&format!(
+                "{:<60} {:>10} {:>10}  {}",
+                table_name,
+                elements,
+                rows.map_or("-".to_string(), |rows| rows.to_string()),
+                status
+            ))?;
+        }
+    }
+    if mismatches > 0 {
+        anyhow::bail!(
+            "{} of {} tables are missing or have fewer rows than export.xml has elements. Rows are also missing when the import was run with --exclude-user-entered, --unknown-types warn, or --records-only, or when the export has duplicate samples",
+            mismatches,
+            expected.len()
+        );
+    }
+    term.write_line(&format!(
+        "All {} tables have a row for every element of export.xml",
+        expected.len()
+    ))?;
+    Ok(())
+}
+
+/// Counts the Record elements of export.xml by type, and the other counted elements by name
+fn count_elements(source: &ExportSource) -> anyhow::Result<BTreeMap<String, i64>> {
+    let mut archive = source.open()?;
+    archive.check_limits(&ZipLimits::default())?;
+    let (export_xml, size) = archive.open_export_xml()?;
+    let mut reader = quick_xml::Reader::from_reader(BufReader::with_capacity(
+        (size as usize).min(1 << 20),
+        export_xml,
+    ));
+    reader.trim_text(true);
+    let mut counts = BTreeMap::new();
+    let mut buf = Vec::new();
+    // Only the children of HealthData are counted, not the elements nested in them
+    let mut depth = 0;
+    loop {
+        match reader.read_event_into(&mut buf)? {
+            Event::Eof => break,
+            Event::Start(element) => {
+                depth += 1;
+                if depth == 2 {
+                    count_element(&reader, &element, &mut counts)?;
+                }
+            }
+            Event::Empty(element) if depth == 1 => {
+                count_element(&reader, &element, &mut counts)?;
+            }
+            Event::End(_) => depth -= 1,
+            _ => {}
+        }
+        buf.clear();
+    }
+    debug!(
+        "Counted the elements of {} types in export.xml",
+        counts.len()
+    );
+    Ok(counts)
+}
+
+/// Counts a child element of HealthData if it is a record or one of the counted elements
+fn count_element<R>(
+    reader: &quick_xml::Reader<R>,
+    element: &BytesStart,
+    counts: &mut BTreeMap<String, i64>,
+) -> anyhow::Result<()> {
+    let table_name = match element.name().as_ref() {
+        b"Record" => match element.try_get_attribute(b"type")? {
+            Some(attribute) => attribute.decode_and_unescape_value(reader)?.to_string(),
+            None => return Ok(()),
+        },
+        name => match COUNTED_ELEMENTS
+            .iter()
+            .find(|counted| counted.as_bytes() == name)
+        {
+            Some(counted) => counted.to_string(),
+            None => return Ok(()),
+        },
+    };
+    *counts.entry(table_name).or_insert(0) += 1;
+    Ok(())
+}
+
+/// Returns the --naming the database was imported with, recorded in _meta
+pub async fn read_naming(db: &SqlitePool) -> anyhow::Result<Naming> {
+    let naming: Option<String> = sqlx::query_scalar("SELECT value FROM _meta WHERE key = 'naming'")
+        .fetch_optional(db)
+        .await
+        .context("The database has no _meta table, so it wasn't created by an import")?;
+    Ok(naming
+        .and_then(|naming| Naming::from_str(&naming, false).ok())
+        .unwrap_or_default())
+}
+
+/// Returns the number of rows of the elements of the table, looking for the table under its short
+/// name and in the samples table of --single-table too, or None if there is no such table
+async fn count_rows(
+    db: &SqlitePool,
+    table_name: &str,
+    naming: Naming,
+) -> anyhow::Result<Option<i64>> {
+    for name in [table_name, schema::short_table_name(table_name)] {
+        let name = naming::convert(naming, name);
+        if table_exists(db, &name).await? {
+            let count = sqlx::query_scalar(&format!(
+                "SELECT COUNT(*) FROM {}",
+                get_valid_sqlite_identifier(&name)
+            ))
+            .fetch_one(db)
+            .await?;
+            return Ok(Some(count));
+        }
+    }
+    let samples_table_name = naming::convert(naming, SAMPLES_TABLE_NAME);
+    if table_exists(db, &samples_table_name).await? {
+        let count = sqlx::query_scalar(&format!(
+            "SELECT COUNT(*) FROM {} WHERE type = ?",
+            get_valid_sqlite_identifier(&samples_table_name)
+        ))
+        .bind(table_name)
+        .fetch_one(db)
+        .await?;
+        if count > 0 {
+            return Ok(Some(count));
+        }
+    }
+    Ok(None)
+}
+
+async fn table_exists(db: &SqlitePool, table_name: &str) -> anyhow::Result<bool> {
+    let count: i64 =
+        sqlx::query_scalar("SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = ?")
+            .bind(table_name)
+            .fetch_one(db)
+            .await?;
+    Ok(count > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conformance::EXPORT_FILES;
+    use crate::options::ImportOptions;
+    use crate::testing::{self, TestDir};
+
+    fn verify_args(export_path: &std::path::Path, db_url: &str) -> VerifyArgs {
+        VerifyArgs {
+            export_path: export_path.to_path_buf(),
+            db_url: db_url.to_string(),
+            all: false,
+        }
+    }
+
+    #[test]
+    fn records_are_counted_by_type_and_nested_elements_are_not() {
+        let dir = TestDir::new("verify-count");
+        let export_dir = dir.write_export("export", EXPORT_FILES);
+        let counts = count_elements(&ExportSource::detect(&export_dir).unwrap()).unwrap();
+        let expected: BTreeMap<String, i64> = [
+            ("ActivitySummary", 2),
+            ("HKCategoryTypeIdentifierSleepAnalysis", 1),
+            ("HKDataTypeIdentifierAudiogram", 1),
+            ("HKQuantityTypeIdentifierBodyMass", 1),
+            ("HKQuantityTypeIdentifierHeartRate", 2),
+            ("HKQuantityTypeIdentifierHeartRateVariabilitySDNN", 1),
+            ("HKQuantityTypeIdentifierStepCount", 1),
+            ("Workout", 1),
+        ]
+        .into_iter()
+        .map(|(name, count)| (name.to_string(), count))
+        .collect();
+        assert_eq!(counts, expected);
+    }
+
+    #[tokio::test]
+    async fn the_database_imported_from_the_export_is_verified() {
+        let dir = TestDir::new("verify-ok");
+        let export_dir = dir.write_export("export", EXPORT_FILES);
+        let db_url = dir.db_url("health.db");
+        testing::import(&export_dir, &db_url, &ImportOptions::defaults())
+            .await
+            .unwrap();
+        verify(&verify_args(&export_dir, &db_url)).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn missing_rows_and_tables_fail_verification() {
+        let dir = TestDir::new("verify-missing");
+        let export_dir = dir.write_export("export", EXPORT_FILES);
+        let db_url = dir.db_url("health.db");
+        testing::import(&export_dir, &db_url, &ImportOptions::defaults())
+            .await
+            .unwrap();
+        let db = SqlitePool::connect(&db_url).await.unwrap();
+        sqlx::query("DELETE FROM HKQuantityTypeIdentifierHeartRate WHERE rowid = 1")
+            .execute(&db)
+            .await
+            .unwrap();
+        sqlx::query("DROP TABLE ActivitySummary")
+            .execute(&db)
+            .await
+            .unwrap();
+        assert_eq!(
+            count_rows(&db, "HKQuantityTypeIdentifierHeartRate", Naming::default())
+                .await
+                .unwrap(),
+            Some(1)
+        );
+        assert_eq!(
+            count_rows(&db, "ActivitySummary", Naming::default())
+                .await
+                .unwrap(),
+            None
+        );
+        db.close().await;
+        let error = verify(&verify_args(&export_dir, &db_url))
+            .await
+            .unwrap_err();
+        assert!(error.to_string().starts_with("2 of 8 tables"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn records_are_counted_in_the_samples_table_under_the_recorded_naming() {
+        let dir = TestDir::new("verify-single-table");
+        let export_dir = dir.write_export("export", EXPORT_FILES);
+        let db_url = dir.db_url("health.db");
+        let options = ImportOptions {
+            single_table: true,
+            naming: Naming::Snake,
+            ..ImportOptions::defaults()
+        };
+        testing::import(&export_dir, &db_url, &options)
+            .await
+            .unwrap();
+        let db = SqlitePool::connect(&db_url).await.unwrap();
+        assert_eq!(read_naming(&db).await.unwrap(), Naming::Snake);
+        assert_eq!(
+            count_rows(&db, "HKQuantityTypeIdentifierHeartRate", Naming::Snake)
+                .await
+                .unwrap(),
+            Some(2)
+        );
+        db.close().await;
+        verify(&verify_args(&export_dir, &db_url)).await.unwrap();
+    }
+}
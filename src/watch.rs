@@ -0,0 +1,311 @@
+//! The watch command, which imports each new export.zip that appears in a directory, e.g. a
+//! Dropbox or iCloud Drive folder exports are saved to, into a database
+use crate::core::{self, ImportStatus};
+use crate::options::ImportOptions;
+use crate::sink::Output;
+use crate::source::ExportSource;
+use clap::Args;
+use console::Term;
+use indicatif::ProgressBar;
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// Name of the file in the watched directory listing the exports already imported, unless
+/// --state is given
+const DEFAULT_STATE_FILE_NAME: &str = ".healthkit-to-sqlite-watch.json";
+
+#[derive(Args, Debug)]
+pub struct WatchArgs {
+    #[arg(help = "Directory to watch for new export.zip files")]
+    pub dir: PathBuf,
+    #[arg(
+        help = "Path or URL to the SQLite database to append the exports to",
+        env = "DATABASE_URL"
+    )]
+    pub db_url: String,
+    #[arg(
+        help = "Seconds between scans of the directory",
+        long,
+        default_value_t = 10
+    )]
+    pub interval: u64,
+    #[arg(
+        help = "File recording the exports already imported, by the hash of their contents [default: DIR/.healthkit-to-sqlite-watch.json]",
+        long,
+        value_name = "PATH"
+    )]
+    pub state: Option<PathBuf>,
+    #[command(flatten)]
+    pub import: ImportOptions,
+}
+
+/// The hashes of the exports imported by earlier scans, kept across runs
+#[derive(Default, Serialize, Deserialize)]
+struct WatchState {
+    imported: BTreeSet<String>,
+}
+
+impl WatchState {
+    fn read(path: &Path) -> anyhow::Result<Self> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let file = std::fs::File::open(path)?;
+        Ok(serde_json::from_reader(std::io::BufReader::new(file))?)
+    }
+
+    fn write(&self, path: &Path) -> anyhow::Result<()> {
+        // Written to a temporary file first, so a crash never leaves a partial state file
+        let temp_path = path.with_extension("json.tmp");
+        std::fs::write(&temp_path, serde_json::to_vec_pretty(self)?)?;
+        std::fs::rename(&temp_path, path)?;
+        Ok(())
+    }
+}
+
+/// The size and modification time of a zip file, which stop changing once it has been copied
+type FileVersion = (u64, Option<SystemTime>);
+
+/// Scans the directory every interval and appends each zip file that has stopped changing since
+/// the previous scan and hasn't been imported before to the database, until Ctrl-C
+pub async fn watch(args: &WatchArgs) -> anyhow::Result<()> {
+    if !args.dir.is_dir() {
+        anyhow::bail!("\"{}\" is not a directory", args.dir.display());
+    }
+    let state_path = args
+        .state
+        .clone()
+        .unwrap_or_else(|| args.dir.join(DEFAULT_STATE_FILE_NAME));
+    if args.import.anonymize {
+        // The pseudonyms differ between imports, so the samples of each export would be appended
+        // again under new ones
+        anyhow::bail!("--anonymize can't be combined with watch, which appends every export");
+    }
+    let mut watcher = Watcher::new(
+        &args.dir,
+        state_path,
+        &args.import,
+        Output::Database(args.db_url.clone()),
+    )?;
+    let interval = Duration::from_secs(args.interval.max(1));
+    let term = Term::stdout();
+    term.write_line(&format!(
+        "Watching \"{}\" for new exports to import into {}...",
+        args.dir.display(),
+        watcher.output
+    ))?;
+    loop {
+        if watcher.scan(&term).await?.is_none() {
+            return Ok(());
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(interval) => {}
+            _ = tokio::signal::ctrl_c() => {
+                term.write_line(&format!("Stopped watching \"{}\"", args.dir.display()))?;
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// The exports imported so far, and the zip files of the watched directory seen by the previous
+/// scan
+struct Watcher {
+    dir: PathBuf,
+    state_path: PathBuf,
+    state: WatchState,
+    options: ImportOptions,
+    output: Output,
+    /// The version of each zip file seen by the previous scan
+    seen: HashMap<PathBuf, FileVersion>,
+    /// The version of each zip file that failed to import
+    failed: HashMap<PathBuf, FileVersion>,
+}
+
+impl Watcher {
+    fn new(
+        dir: &Path,
+        state_path: PathBuf,
+        options: &ImportOptions,
+        output: Output,
+    ) -> anyhow::Result<Self> {
+        let mut options = options.clone();
+        // Each export holds everything the previous ones did, so only newer rows are inserted
+        options.append = true;
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            state: WatchState::read(&state_path)?,
+            state_path,
+            options,
+            output,
+            seen: HashMap::new(),
+            failed: HashMap::new(),
+        })
+    }
+
+    /// Imports the zip files that are unchanged since the previous scan and haven't been
+    /// imported, returning their paths, or None if an import was interrupted
+    async fn scan(&mut self, term: &Term) -> anyhow::Result<Option<Vec<PathBuf>>> {
+        let versions = zip_files(&self.dir)?;
+        // Oldest first, so the newest export is imported last
+        let mut ready: Vec<(&PathBuf, &FileVersion)> = versions
+            .iter()
+            .filter(|(path, version)| self.seen.get(*path) == Some(*version))
+            .filter(|(path, version)| self.failed.get(*path) != Some(*version))
+            .collect();
+        ready.sort_by_key(|(path, (_, modified))| (*modified, path.to_path_buf()));
+        let mut imported = Vec::new();
+        for (path, version) in ready {
+            let hash = match ExportSource::detect(path).and_then(|source| source.content_hash()) {
+                Ok(hash) => hash,
+                Err(e) => {
+                    warn!("Failed to read \"{}\": {:#}", path.display(), e);
+                    self.failed.insert(path.clone(), *version);
+                    continue;
+                }
+            };
+            if self.state.imported.contains(&hash) {
+                continue;
+            }
+            term.write_line(&format!("Importing \"{}\"...", path.display()))?;
+            let progress = ProgressBar::hidden();
+            match core::healthkit_to_sqlite(&self.output, path, &self.options, &progress).await {
+                Ok(ImportStatus::Complete) => {
+                    term.write_line(&format!(
+                        "Imported \"{}\" into {}",
+                        path.display(),
+                        self.output
+                    ))?;
+                    self.state.imported.insert(hash);
+                    self.state.write(&self.state_path)?;
+                    imported.push(path.clone());
+                }
+                Ok(ImportStatus::Interrupted(reason)) => {
+                    term.write_line(&format!(
+                        "Import {}, saved the rows imported so far and stopped watching",
+                        reason
+                    ))?;
+                    return Ok(None);
+                }
+                Err(e) => {
+                    // Retried once the file changes, e.g. when a corrupt copy is replaced
+                    error!("Failed to import \"{}\": {:#}", path.display(), e);
+                    self.failed.insert(path.clone(), *version);
+                }
+            }
+        }
+        self.seen = versions;
+        Ok(Some(imported))
+    }
+}
+
+/// Returns the version of each zip file directly inside the directory
+fn zip_files(dir: &Path) -> anyhow::Result<HashMap<PathBuf, FileVersion>> {
+    let mut files = HashMap::new();
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        let is_zip = path
+            .extension()
+            .is_some_and(|extension| extension.eq_ignore_ascii_case("zip"));
+        if !is_zip {
+            continue;
+        }
+        let metadata = entry.metadata()?;
+        if metadata.is_file() {
+            files.insert(path, (metadata.len(), metadata.modified().ok()));
+        }
+    }
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conformance::EXPORT_FILES;
+    use crate::testing::{self, TestDir};
+
+    /// Watches the drops directory of the test directory, returning the watcher and the URL of
+    /// the database it imports into
+    fn watch_drops(dir: &TestDir) -> (Watcher, String) {
+        let drops = dir.path().join("drops");
+        std::fs::create_dir_all(&drops).unwrap();
+        let db_url = dir.db_url("health.db");
+        let watcher = Watcher::new(
+            &drops,
+            drops.join(DEFAULT_STATE_FILE_NAME),
+            &ImportOptions::defaults(),
+            Output::Database(db_url.clone()),
+        )
+        .unwrap();
+        (watcher, db_url)
+    }
+
+    /// Writes the conformance export as a zip file with the name in the drops directory
+    fn drop_export(dir: &TestDir, file_name: &str) -> PathBuf {
+        let files: Vec<(String, &[u8])> = EXPORT_FILES
+            .iter()
+            .map(|(path, contents)| (format!("apple_health_export/{}", path), *contents))
+            .collect();
+        let files: Vec<(&str, &[u8])> = files
+            .iter()
+            .map(|(path, contents)| (path.as_str(), *contents))
+            .collect();
+        let path = dir.write_zip(file_name, &files);
+        let dropped = dir.path().join("drops").join(file_name);
+        std::fs::rename(&path, &dropped).unwrap();
+        dropped
+    }
+
+    #[tokio::test]
+    async fn new_zip_files_are_imported_once_they_stop_changing() {
+        let dir = TestDir::new("watch-new");
+        let (mut watcher, db_url) = watch_drops(&dir);
+        let term = Term::stderr();
+        assert_eq!(watcher.scan(&term).await.unwrap(), Some(vec![]));
+        let export = drop_export(&dir, "export.zip");
+        std::fs::write(dir.path().join("drops").join("notes.txt"), "not an export").unwrap();
+        // Seen for the first time, it may still be being copied
+        assert_eq!(watcher.scan(&term).await.unwrap(), Some(vec![]));
+        assert_eq!(watcher.scan(&term).await.unwrap(), Some(vec![export]));
+        let workouts = testing::row_counts(&db_url).await["Workout"];
+        assert_eq!(workouts, 1);
+        assert_eq!(watcher.scan(&term).await.unwrap(), Some(vec![]));
+    }
+
+    #[tokio::test]
+    async fn exports_already_imported_are_skipped() {
+        let dir = TestDir::new("watch-imported");
+        let (mut watcher, db_url) = watch_drops(&dir);
+        let term = Term::stderr();
+        let export = drop_export(&dir, "export.zip");
+        watcher.scan(&term).await.unwrap();
+        assert_eq!(watcher.scan(&term).await.unwrap(), Some(vec![export]));
+        let imported = testing::row_counts(&db_url).await;
+        // The same export under another name, also once the watch is restarted
+        drop_export(&dir, "export copy.zip");
+        let (mut watcher, _) = watch_drops(&dir);
+        watcher.scan(&term).await.unwrap();
+        assert_eq!(watcher.scan(&term).await.unwrap(), Some(vec![]));
+        assert_eq!(testing::row_counts(&db_url).await, imported);
+    }
+
+    #[tokio::test]
+    async fn zip_files_that_fail_are_retried_once_they_change() {
+        let dir = TestDir::new("watch-failed");
+        let (mut watcher, _) = watch_drops(&dir);
+        let term = Term::stderr();
+        let path = dir.path().join("drops").join("export.zip");
+        // A zip file cut short while it was being copied
+        std::fs::write(&path, b"PK\x03\x04 cut short").unwrap();
+        watcher.scan(&term).await.unwrap();
+        assert_eq!(watcher.scan(&term).await.unwrap(), Some(vec![]));
+        assert_eq!(watcher.failed.len(), 1);
+        let export = drop_export(&dir, "export.zip");
+        watcher.scan(&term).await.unwrap();
+        assert_eq!(watcher.scan(&term).await.unwrap(), Some(vec![export]));
+    }
+}
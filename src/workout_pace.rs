@@ -0,0 +1,299 @@
+//! The duration in seconds of each workout, and the average speed and pace of each workout with a
+//! distance, derived from its duration and distance in whichever units the export gives them in
+use crate::core::{DatabaseRow, DatabaseValue, HKTables};
+use crate::units::conversion_factor;
+
+/// Workout column holding the duration in seconds, whatever the unit of duration
+pub const DURATION_SECONDS_COLUMN: &str = "duration_seconds";
+
+/// Workout column holding the average speed in m/s
+pub const AVG_SPEED_COLUMN: &str = "avg_speed";
+
+/// Workout column holding the average pace in seconds per km
+pub const AVG_PACE_COLUMN: &str = "avg_pace_sec_per_km";
+
+/// Prefix of the WorkoutStatistics types holding a distance, e.g.
+/// HKQuantityTypeIdentifierDistanceWalkingRunning and HKQuantityTypeIdentifierDistanceCycling
+const DISTANCE_TYPE_PREFIX: &str = "HKQuantityTypeIdentifierDistance";
+
+/// Adds the duration, speed, and pace columns to the Workout table
+pub fn extend_tables(tables: &mut HKTables, workout_table_name: &str) {
+    if let Some(columns) = tables.get_mut(workout_table_name) {
+        columns.insert(DURATION_SECONDS_COLUMN.to_string(), "REAL");
+        columns.insert(AVG_SPEED_COLUMN.to_string(), "REAL");
+        columns.insert(AVG_PACE_COLUMN.to_string(), "REAL");
+    }
+}
+
+/// Returns the duration column of a workout row with a duration, along with the speed and pace
+/// columns when it has a distance too. The duration is in minutes when the unit is missing. The
+/// distance is the totalDistance attribute of older exports, or else the sum of the distance
+/// WorkoutStatistics of newer ones.
+pub fn derived_columns<'a>(
+    row: &DatabaseRow,
+    statistics: impl Iterator<Item = &'a DatabaseRow>,
+) -> Vec<(String, DatabaseValue)> {
+    let duration = number(row, "duration").zip(text(row, "durationUnit").or(Some("min")));
+    let seconds = match duration.and_then(|(duration, unit)| in_unit(duration, unit, "s")) {
+        Some(seconds) => seconds,
+        None => return Vec::new(),
+    };
+    let mut columns = vec![(
+        DURATION_SECONDS_COLUMN.to_string(),
+        DatabaseValue::Real(seconds),
+    )];
+    if seconds <= 0.0 {
+        return columns;
+    }
+    let total_distance = number(row, "totalDistance")
+        .zip(text(row, "totalDistanceUnit"))
+        .and_then(|(distance, unit)| in_unit(distance, unit, "m"));
+    let meters = match total_distance.or_else(|| statistics_distance(statistics)) {
+        Some(meters) if meters > 0.0 => meters,
+        _ => return columns,
+    };
+    columns.extend([
+        (
+            AVG_SPEED_COLUMN.to_string(),
+            DatabaseValue::Real(meters / seconds),
+        ),
+        (
+            AVG_PACE_COLUMN.to_string(),
+            DatabaseValue::Real(seconds / (meters / 1000.0)),
+        ),
+    ]);
+    columns
+}
+
+/// Sums the distance statistics in meters, counting each distance type once as the statistics
+/// of a workout's activities repeat those of the workout
+fn statistics_distance<'a>(statistics: impl Iterator<Item = &'a DatabaseRow>) -> Option<f64> {
+    let mut types = Vec::new();
+    let mut meters = None;
+    for row in statistics {
+        let distance_type = match text(row, "type") {
+            Some(ty) if ty.starts_with(DISTANCE_TYPE_PREFIX) && !types.contains(&ty) => ty,
+            _ => continue,
+        };
+        let distance = number(row, "sum")
+            .zip(text(row, "unit"))
+            .and_then(|(sum, unit)| in_unit(sum, unit, "m"));
+        if let Some(distance) = distance {
+            types.push(distance_type);
+            meters = Some(meters.unwrap_or(0.0) + distance);
+        }
+    }
+    meters
+}
+
+fn in_unit(value: f64, from: &str, to: &str) -> Option<f64> {
+    conversion_factor(from, to).map(|factor| value * factor)
+}
+
+fn number(row: &DatabaseRow, column_name: &str) -> Option<f64> {
+    row.iter().find_map(|(name, value)| match value {
+        DatabaseValue::Integer(i) if name == column_name => Some(*i as f64),
+        DatabaseValue::Real(r) if name == column_name => Some(*r),
+        _ => None,
+    })
+}
+
+fn text<'a>(row: &'a DatabaseRow, column_name: &str) -> Option<&'a str> {
+    row.iter().find_map(|(name, value)| match value {
+        DatabaseValue::Text(text) if name == column_name => Some(text.as_str()),
+        _ => None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text_value(text: &str) -> DatabaseValue {
+        DatabaseValue::Text(text.to_string())
+    }
+
+    fn workout(columns: &[(&str, DatabaseValue)]) -> DatabaseRow {
+        columns
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.clone()))
+            .collect()
+    }
+
+    fn statistic(ty: &str, sum: f64, unit: &str) -> DatabaseRow {
+        workout(&[
+            ("type", text_value(ty)),
+            ("sum", DatabaseValue::Real(sum)),
+            ("unit", text_value(unit)),
+        ])
+    }
+
+    /// The derived columns as (name, value) pairs, for the REAL columns they all are
+    fn derived(row: &DatabaseRow, statistics: &[DatabaseRow]) -> Vec<(String, f64)> {
+        derived_columns(row, statistics.iter())
+            .into_iter()
+            .map(|(name, value)| match value {
+                DatabaseValue::Real(r) => (name, r),
+                other => panic!("{} is not REAL: {:?}", name, other),
+            })
+            .collect()
+    }
+
+    fn assert_speed_and_pace(columns: &[(String, f64)], speed: f64, pace: f64) {
+        assert_eq!(columns.len(), 3, "{:?}", columns);
+        assert_eq!(columns[1].0, AVG_SPEED_COLUMN);
+        assert!((columns[1].1 - speed).abs() < 1e-9, "{:?}", columns);
+        assert_eq!(columns[2].0, AVG_PACE_COLUMN);
+        assert!((columns[2].1 - pace).abs() < 1e-9, "{:?}", columns);
+    }
+
+    #[test]
+    fn the_total_distance_is_converted_to_meters() {
+        // 5 km in 25 minutes
+        let row = workout(&[
+            ("duration", DatabaseValue::Real(25.0)),
+            ("durationUnit", text_value("min")),
+            ("totalDistance", DatabaseValue::Real(5.0)),
+            ("totalDistanceUnit", text_value("km")),
+        ]);
+        assert_speed_and_pace(&derived(&row, &[]), 5000.0 / 1500.0, 300.0);
+        // 1 mile in 10 minutes
+        let row = workout(&[
+            ("duration", DatabaseValue::Integer(10)),
+            ("durationUnit", text_value("min")),
+            ("totalDistance", DatabaseValue::Integer(1)),
+            ("totalDistanceUnit", text_value("mi")),
+        ]);
+        assert_speed_and_pace(&derived(&row, &[]), 1609.344 / 600.0, 600.0 / 1.609344);
+    }
+
+    #[test]
+    fn the_total_distance_takes_precedence_over_the_statistics() {
+        let row = workout(&[
+            ("duration", DatabaseValue::Real(25.0)),
+            ("durationUnit", text_value("min")),
+            ("totalDistance", DatabaseValue::Real(5.0)),
+            ("totalDistanceUnit", text_value("km")),
+        ]);
+        let statistics = [statistic(
+            "HKQuantityTypeIdentifierDistanceWalkingRunning",
+            10.0,
+            "km",
+        )];
+        assert_speed_and_pace(&derived(&row, &statistics), 5000.0 / 1500.0, 300.0);
+    }
+
+    #[test]
+    fn the_distance_falls_back_to_the_sum_of_the_distance_statistics() {
+        let row = workout(&[
+            ("duration", DatabaseValue::Real(1.0)),
+            ("durationUnit", text_value("hr")),
+        ]);
+        let statistics = [
+            statistic("HKQuantityTypeIdentifierDistanceCycling", 20.0, "km"),
+            statistic("HKQuantityTypeIdentifierActiveEnergyBurned", 500.0, "kcal"),
+            statistic("HKQuantityTypeIdentifierDistanceSwimming", 1000.0, "m"),
+        ];
+        assert_speed_and_pace(&derived(&row, &statistics), 21000.0 / 3600.0, 3600.0 / 21.0);
+    }
+
+    #[test]
+    fn repeated_statistics_types_are_counted_once() {
+        // The statistics of the workout's activity repeat those of the workout
+        let row = workout(&[
+            ("duration", DatabaseValue::Real(30.0)),
+            ("durationUnit", text_value("min")),
+        ]);
+        let statistics = [
+            statistic("HKQuantityTypeIdentifierDistanceWalkingRunning", 6.0, "km"),
+            statistic("HKQuantityTypeIdentifierDistanceWalkingRunning", 6.0, "km"),
+        ];
+        assert_speed_and_pace(&derived(&row, &statistics), 6000.0 / 1800.0, 300.0);
+        // A statistic without a convertible sum doesn't hide a later one of the same type
+        let statistics = [
+            statistic(
+                "HKQuantityTypeIdentifierDistanceWalkingRunning",
+                6.0,
+                "count",
+            ),
+            statistic("HKQuantityTypeIdentifierDistanceWalkingRunning", 6.0, "km"),
+        ];
+        assert_speed_and_pace(&derived(&row, &statistics), 6000.0 / 1800.0, 300.0);
+    }
+
+    #[test]
+    fn zero_durations_and_distances_have_no_speed_or_pace() {
+        let row = workout(&[
+            ("duration", DatabaseValue::Real(0.0)),
+            ("durationUnit", text_value("min")),
+            ("totalDistance", DatabaseValue::Real(5.0)),
+            ("totalDistanceUnit", text_value("km")),
+        ]);
+        assert_eq!(
+            derived(&row, &[]),
+            [(DURATION_SECONDS_COLUMN.to_string(), 0.0)]
+        );
+        let row = workout(&[
+            ("duration", DatabaseValue::Real(25.0)),
+            ("durationUnit", text_value("min")),
+            ("totalDistance", DatabaseValue::Real(0.0)),
+            ("totalDistanceUnit", text_value("km")),
+        ]);
+        assert_eq!(
+            derived(&row, &[]),
+            [(DURATION_SECONDS_COLUMN.to_string(), 1500.0)]
+        );
+        // Nor do workouts without a distance, or with one in a unit that isn't a length
+        let row = workout(&[
+            ("duration", DatabaseValue::Real(25.0)),
+            ("durationUnit", text_value("min")),
+            ("totalDistance", DatabaseValue::Real(5.0)),
+            ("totalDistanceUnit", text_value("kcal")),
+        ]);
+        assert_eq!(
+            derived(&row, &[]),
+            [(DURATION_SECONDS_COLUMN.to_string(), 1500.0)]
+        );
+    }
+
+    #[test]
+    fn the_duration_is_converted_to_seconds_from_its_unit() {
+        for (duration, unit, seconds) in [
+            (DatabaseValue::Real(90.0), "s", 90.0),
+            (DatabaseValue::Real(1.5), "min", 90.0),
+            (DatabaseValue::Integer(2), "hr", 7200.0),
+        ] {
+            let row = workout(&[("duration", duration), ("durationUnit", text_value(unit))]);
+            assert_eq!(
+                derived(&row, &[]),
+                [(DURATION_SECONDS_COLUMN.to_string(), seconds)],
+                "{}",
+                unit
+            );
+        }
+    }
+
+    #[test]
+    fn the_duration_is_in_minutes_without_a_unit() {
+        let row = workout(&[("duration", DatabaseValue::Real(1.5))]);
+        assert_eq!(
+            derived(&row, &[]),
+            [(DURATION_SECONDS_COLUMN.to_string(), 90.0)]
+        );
+    }
+
+    #[test]
+    fn workouts_without_a_convertible_duration_have_no_derived_columns() {
+        let row = workout(&[
+            ("durationUnit", text_value("min")),
+            ("totalDistance", DatabaseValue::Real(5.0)),
+            ("totalDistanceUnit", text_value("km")),
+        ]);
+        assert_eq!(derived(&row, &[]), []);
+        let row = workout(&[
+            ("duration", DatabaseValue::Real(25.0)),
+            ("durationUnit", text_value("km")),
+        ]);
+        assert_eq!(derived(&row, &[]), []);
+    }
+}
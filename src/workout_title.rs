@@ -0,0 +1,130 @@
+//! The readable `title` of each workout, taken from the name an app gave it when there is one
+use crate::core::{DatabaseRow, DatabaseValue, HKTables, METADATA_COLUMN_PREFIX};
+
+/// Workout column holding the title
+pub const TITLE_COLUMN: &str = "title";
+
+/// Metadata keys holding the name of a workout, in order of preference: the custom titles apps
+/// write, then the brand name of guided workouts such as Fitness+ and Peloton classes
+const TITLE_KEYS: &[&str] = &[
+    "HKWorkoutTitle",
+    "HKMetadataKeyWorkoutTitle",
+    "HKWorkoutBrandName",
+    "HKMetadataKeyWorkoutBrandName",
+];
+
+/// Prefix of the workoutActivityType values, stripped from the fallback titles
+const ACTIVITY_TYPE_PREFIX: &str = "HKWorkoutActivityType";
+
+/// Adds the title column to the Workout table
+pub fn extend_tables(tables: &mut HKTables, workout_table_name: &str) {
+    if let Some(columns) = tables.get_mut(workout_table_name) {
+        columns.insert(TITLE_COLUMN.to_string(), "TEXT");
+    }
+}
+
+/// Returns the title of a workout row: the first non-empty title metadata, or else its activity
+/// type in words, e.g. "Traditional Strength Training" for
+/// HKWorkoutActivityTypeTraditionalStrengthTraining
+pub fn title(row: &DatabaseRow) -> Option<String> {
+    let text = |column_name: &str| {
+        row.iter().find_map(|(name, value)| match value {
+            DatabaseValue::Text(text) if name == column_name && !text.trim().is_empty() => {
+                Some(text.trim().to_string())
+            }
+            _ => None,
+        })
+    };
+    TITLE_KEYS
+        .iter()
+        .find_map(|key| text(&format!("{}{}", METADATA_COLUMN_PREFIX, key)))
+        .or_else(|| {
+            text("workoutActivityType").map(|activity_type| activity_type_words(&activity_type))
+        })
+}
+
+/// Splits the camel case activity type into words, e.g. HKWorkoutActivityTypeHighIntensityIntervalTraining
+/// becomes "High Intensity Interval Training"
+fn activity_type_words(activity_type: &str) -> String {
+    let name = activity_type.trim_start_matches(ACTIVITY_TYPE_PREFIX);
+    let mut words = String::with_capacity(name.len() + 4);
+    let mut previous: Option<char> = None;
+    for c in name.chars() {
+        if let Some(previous) = previous {
+            if c.is_uppercase() && previous.is_lowercase() {
+                words.push(' ');
+            }
+        }
+        words.push(c);
+        previous = Some(c);
+    }
+    words
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn workout(columns: &[(&str, &str)]) -> DatabaseRow {
+        columns
+            .iter()
+            .map(|(name, value)| (name.to_string(), DatabaseValue::Text(value.to_string())))
+            .collect()
+    }
+
+    #[test]
+    fn the_title_metadata_takes_precedence_over_the_brand_name() {
+        let row = workout(&[
+            ("workoutActivityType", "HKWorkoutActivityTypeCycling"),
+            ("metadata_HKWorkoutBrandName", "Peloton"),
+            ("metadata_HKWorkoutTitle", " 30 min Climb Ride "),
+        ]);
+        assert_eq!(title(&row).as_deref(), Some("30 min Climb Ride"));
+        let row = workout(&[
+            ("metadata_HKMetadataKeyWorkoutBrandName", "Peloton"),
+            ("metadata_HKMetadataKeyWorkoutTitle", "Tempo Run"),
+        ]);
+        assert_eq!(title(&row).as_deref(), Some("Tempo Run"));
+    }
+
+    #[test]
+    fn the_brand_name_is_the_title_without_a_title_metadata() {
+        let row = workout(&[
+            ("workoutActivityType", "HKWorkoutActivityTypeYoga"),
+            ("metadata_HKWorkoutTitle", "  "),
+            ("metadata_HKWorkoutBrandName", "Fitness+"),
+        ]);
+        assert_eq!(title(&row).as_deref(), Some("Fitness+"));
+    }
+
+    #[test]
+    fn the_activity_type_in_words_is_the_title_without_metadata() {
+        for (activity_type, words) in [
+            (
+                "HKWorkoutActivityTypeTraditionalStrengthTraining",
+                "Traditional Strength Training",
+            ),
+            (
+                "HKWorkoutActivityTypeHighIntensityIntervalTraining",
+                "High Intensity Interval Training",
+            ),
+            ("HKWorkoutActivityTypeRunning", "Running"),
+            // Runs of capitals aren't split
+            ("HKWorkoutActivityTypeMMA", "MMA"),
+            // Types without the prefix are split as they are
+            ("CustomActivity", "Custom Activity"),
+        ] {
+            let row = workout(&[
+                ("workoutActivityType", activity_type),
+                ("metadata_HKWorkoutBrandName", ""),
+            ]);
+            assert_eq!(title(&row).as_deref(), Some(words));
+        }
+    }
+
+    #[test]
+    fn workouts_without_metadata_or_an_activity_type_have_no_title() {
+        assert_eq!(title(&workout(&[])), None);
+        assert_eq!(title(&workout(&[("sourceName", "Watch")])), None);
+    }
+}